@@ -0,0 +1,214 @@
+//! Manual approval of inbound connections (`--approve`).
+
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::{
+    io::{stdin, AsyncBufReadExt, BufReader, Lines, Stdin},
+    sync::oneshot,
+    time::timeout,
+};
+
+/// How long a connection waits for a decision before it is rejected.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the operator decided about a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Reject,
+    /// Allow it and every later one from the same IP.
+    Always,
+}
+
+/// A connection waiting for a decision, for [`Approver::queued`].
+#[derive(Debug, Clone)]
+pub struct Question {
+    pub id: u64,
+    pub peer: SocketAddr,
+    /// The HTTP request line, if any.
+    pub preview: Option<String>,
+}
+
+/// Where the questions go.
+enum Prompt {
+    Terminal(tokio::sync::Mutex<Lines<BufReader<Stdin>>>),
+    Queue(Mutex<Queue>),
+}
+
+#[derive(Default)]
+struct Queue {
+    last_id: u64,
+    /// Oldest first, each with where its decision goes.
+    waiting: VecDeque<(Question, oneshot::Sender<Decision>)>,
+}
+
+/// Asks the operator whether to let a connection through.
+///
+/// Prompts are serialized so concurrent connections don't interleave. Answering
+/// `a` approves the connection and every later one from the same IP. A
+/// connection nobody decided on within the timeout is rejected.
+pub struct Approver {
+    remembered: Mutex<HashSet<IpAddr>>,
+    prompt: Prompt,
+    timeout: Duration,
+}
+
+impl Approver {
+    /// Ask on the terminal.
+    pub fn new() -> Self {
+        let input = BufReader::new(stdin()).lines();
+        Self::with(Prompt::Terminal(tokio::sync::Mutex::new(input)))
+    }
+
+    /// Leave the questions to someone else, e.g. a dashboard, which lists
+    /// them with [`pending`](Self::pending) and decides with
+    /// [`answer`](Self::answer).
+    pub fn queued() -> Self {
+        Self::with(Prompt::Queue(Mutex::default()))
+    }
+
+    fn with(prompt: Prompt) -> Self {
+        Self {
+            remembered: Mutex::new(HashSet::new()),
+            prompt,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Reject connections nobody decided on within `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether connections from `ip` were already approved with `a`.
+    pub fn is_remembered(&self, ip: IpAddr) -> bool {
+        self.remembered.lock().unwrap().contains(&ip)
+    }
+
+    /// Prompt for a decision. `preview` is the HTTP request line, if any.
+    pub async fn ask(&self, peer: SocketAddr, preview: Option<&str>) -> bool {
+        let decision = match &self.prompt {
+            Prompt::Terminal(input) => self.ask_terminal(input, peer, preview).await,
+            Prompt::Queue(queue) => self.ask_queue(queue, peer, preview).await,
+        };
+        match decision {
+            Decision::Allow => true,
+            Decision::Reject => false,
+            Decision::Always => {
+                self.remembered.lock().unwrap().insert(peer.ip());
+                true
+            }
+        }
+    }
+
+    async fn ask_terminal(
+        &self,
+        input: &tokio::sync::Mutex<Lines<BufReader<Stdin>>>,
+        peer: SocketAddr,
+        preview: Option<&str>,
+    ) -> Decision {
+        let answer = timeout(self.timeout, async {
+            let mut input = input.lock().await;
+            // Another prompt may have remembered this IP while we waited.
+            if self.is_remembered(peer.ip()) {
+                return Decision::Allow;
+            }
+            match preview {
+                Some(line) => print!("  ?  Connection from {peer} — {line}\n     Allow? [y]es / [N]o / [a]lways for this IP: "),
+                None => print!("  ?  Connection from {peer}\n     Allow? [y]es / [N]o / [a]lways for this IP: "),
+            }
+            let _ = std::io::stdout().flush();
+            let answer = input.next_line().await.ok().flatten();
+            parse(&answer.unwrap_or_default())
+        });
+        match answer.await {
+            Ok(decision) => decision,
+            Err(_) => {
+                println!("\n     No answer in {:?}, rejected {peer}.", self.timeout);
+                Decision::Reject
+            }
+        }
+    }
+
+    async fn ask_queue(
+        &self,
+        queue: &Mutex<Queue>,
+        peer: SocketAddr,
+        preview: Option<&str>,
+    ) -> Decision {
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut queue = queue.lock().unwrap();
+            queue.last_id += 1;
+            let question = Question {
+                id: queue.last_id,
+                peer,
+                preview: preview.map(str::to_owned),
+            };
+            queue.waiting.push_back((question, tx));
+            queue.last_id
+        };
+        let decision = timeout(self.timeout, rx).await;
+        queue.lock().unwrap().waiting.retain(|(q, _)| q.id != id);
+        match decision {
+            Ok(Ok(decision)) => decision,
+            _ => Decision::Reject,
+        }
+    }
+
+    /// The connections waiting for a decision, oldest first. Always empty
+    /// unless [`queued`](Self::queued).
+    pub fn pending(&self) -> Vec<Question> {
+        match &self.prompt {
+            Prompt::Queue(queue) => {
+                let queue = queue.lock().unwrap();
+                queue.waiting.iter().map(|(q, _)| q.clone()).collect()
+            }
+            Prompt::Terminal(_) => Vec::new(),
+        }
+    }
+
+    /// Decide on the question `id`; an `Always` lets the other questions
+    /// from the same IP through too.
+    pub fn answer(&self, id: u64, decision: Decision) {
+        let Prompt::Queue(queue) = &self.prompt else {
+            return;
+        };
+        let mut queue = queue.lock().unwrap();
+        let waiting = &mut queue.waiting;
+        let Some(i) = waiting.iter().position(|(q, _)| q.id == id) else {
+            return;
+        };
+        let (question, tx) = waiting.remove(i).expect("found above");
+        let _ = tx.send(decision);
+        if decision == Decision::Always {
+            let ip = question.peer.ip();
+            while let Some(i) = waiting.iter().position(|(q, _)| q.peer.ip() == ip) {
+                let (_, tx) = waiting.remove(i).expect("found above");
+                let _ = tx.send(Decision::Allow);
+            }
+        }
+    }
+}
+
+/// An answer typed at the prompt; anything unclear rejects.
+fn parse(answer: &str) -> Decision {
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Decision::Allow,
+        "a" | "always" => Decision::Always,
+        _ => Decision::Reject,
+    }
+}
+
+impl Default for Approver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,188 @@
+//! Spreading a tunnel's visitors across several local ports of one service,
+//! e.g. `--port 3000,3001,3002` for three processes of the same app.
+//!
+//! Each new connection goes to the next port in turn, or with
+//! [`Balance::LeastConns`] to the one with the fewest connections open. The
+//! health checks are passive: a port that refused [`EJECT_AFTER`]
+//! connections in a row is left out for [`EJECT_FOR`] and then tried again.
+//! When every port is left out, they are all tried anyway.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+/// Connect failures in a row after which a port is left out.
+const EJECT_AFTER: u32 = 3;
+
+/// How long a failing port is left out before it gets another chance.
+const EJECT_FOR: Duration = Duration::from_secs(10);
+
+/// How visitors are spread across a tunnel's local ports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    /// Each port in turn.
+    #[default]
+    RoundRobin,
+    /// The port with the fewest open connections, for visitors that stay
+    /// connected for very different times.
+    LeastConns,
+}
+
+impl FromStr for Balance {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-conns" => Ok(Self::LeastConns),
+            _ => bail!("unknown balancing '{s}' (expected round-robin or least-conns)"),
+        }
+    }
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RoundRobin => "round-robin",
+            Self::LeastConns => "least-conns",
+        })
+    }
+}
+
+/// What is known about the local ports of all tunnels.
+pub(crate) struct Balancer {
+    balance: Balance,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    ports: HashMap<u16, Port>,
+    /// Whose turn it is next, for each tunnel's set of ports.
+    turns: HashMap<Vec<u16>, usize>,
+}
+
+#[derive(Default)]
+struct Port {
+    open: usize,
+    /// Connect failures since the last success.
+    failures: u32,
+    /// Left out until then.
+    ejected_until: Option<Instant>,
+}
+
+impl Port {
+    fn ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+}
+
+impl Balancer {
+    pub(crate) fn new(balance: Balance) -> Self {
+        Self {
+            balance,
+            state: Mutex::default(),
+        }
+    }
+
+    /// The port out of `ports` for the next connection, other than those
+    /// already `tried` for it while there are others. It counts as open
+    /// until the [`Pick`] is dropped.
+    pub(crate) fn pick(&self, ports: &[u16], tried: &[u16]) -> Pick<'_> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let untried: Vec<u16> = match ports.iter().any(|p| !tried.contains(p)) {
+            true => ports
+                .iter()
+                .copied()
+                .filter(|p| !tried.contains(p))
+                .collect(),
+            false => ports.to_vec(),
+        };
+        let healthy: Vec<u16> = untried
+            .iter()
+            .copied()
+            .filter(|p| !state.ports.get(p).is_some_and(|port| port.ejected(now)))
+            .collect();
+        let candidates = match healthy.is_empty() {
+            true => untried,
+            false => healthy,
+        };
+        let turn = state.turns.entry(ports.to_vec()).or_default();
+        let start = *turn;
+        *turn = turn.wrapping_add(1);
+        // In turn from the one after the last pick, so that ties go round.
+        let mut in_turn = (0..ports.len())
+            .map(|i| ports[(start + i) % ports.len()])
+            .filter(|p| candidates.contains(p));
+        let port = match self.balance {
+            Balance::RoundRobin => in_turn.next(),
+            Balance::LeastConns => {
+                in_turn.min_by_key(|p| state.ports.get(p).map_or(0, |port| port.open))
+            }
+        };
+        let port = port.unwrap_or(ports[0]);
+        state.ports.entry(port).or_default().open += 1;
+        Pick {
+            port,
+            balancer: self,
+        }
+    }
+}
+
+/// A port picked for a connection.
+pub(crate) struct Pick<'a> {
+    pub(crate) port: u16,
+    balancer: &'a Balancer,
+}
+
+impl Pick<'_> {
+    /// The connection went through: the port is healthy.
+    pub(crate) fn succeeded(&self) {
+        let mut state = self.balancer.state.lock().unwrap();
+        let port = state.ports.entry(self.port).or_default();
+        if port.failures >= EJECT_AFTER {
+            info!(port = self.port, "local port is back");
+        }
+        port.failures = 0;
+        port.ejected_until = None;
+    }
+
+    /// The connection was refused, or failed otherwise.
+    pub(crate) fn failed(&self) {
+        let now = Instant::now();
+        let mut state = self.balancer.state.lock().unwrap();
+        let port = state.ports.entry(self.port).or_default();
+        port.failures += 1;
+        if port.failures < EJECT_AFTER {
+            return;
+        }
+        if !port.ejected(now) {
+            warn!(
+                port = self.port,
+                failures = port.failures,
+                "local port keeps failing, leaving it out for {}s",
+                EJECT_FOR.as_secs()
+            );
+        }
+        port.ejected_until = Some(now + EJECT_FOR);
+    }
+}
+
+impl Drop for Pick<'_> {
+    fn drop(&mut self) {
+        let mut state = self.balancer.state.lock().unwrap();
+        if let Some(port) = state.ports.get_mut(&self.port) {
+            port.open -= 1;
+        }
+    }
+}
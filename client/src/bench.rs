@@ -0,0 +1,184 @@
+//! `sshx bench`: open a temporary tunnel that echoes, and time visitors
+//! going through the server to it and back. Says whether a slow app is the
+//! tunnel's fault or the app's.
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use futures_util::future::try_join_all;
+use sshx_client::{
+    status::{format_bytes, Stats},
+    Proto,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+};
+
+use crate::Cli;
+
+/// Bytes each round trip of the latency test carries.
+const PING_SIZE: usize = 64;
+/// Bytes written at a time in the throughput test.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What to measure.
+pub struct Settings {
+    /// Connections to set up, and round trips to time.
+    pub samples: usize,
+    /// Connections the throughput test runs at once.
+    pub connections: usize,
+    /// How long the throughput test runs.
+    pub duration: Duration,
+}
+
+pub async fn run(cli: &Cli, settings: &Settings) -> Result<()> {
+    let tunnel = crate::builder(cli, Arc::new(Stats::new()))
+        .reconnect(false)
+        .proto(Proto::Tcp)
+        .echo()
+        .connect()
+        .await?;
+    let server = cli.server();
+    let port = tunnel.public_port();
+    let addr = lookup_host((server, port))
+        .await?
+        .next()
+        .with_context(|| format!("{server} does not resolve"))?;
+    println!();
+    println!("  ⏱  Benchmarking through {server}:{port}");
+
+    let result = measure(addr, settings).await;
+    tunnel.shutdown().await?;
+    let report = result?;
+    println!("     Setup     : {}", report.setup);
+    println!("     Latency   : {}", report.latency);
+    println!(
+        "     Throughput: {}/s over {} connections ({} in all)",
+        format_bytes(report.bytes_per_sec as u64),
+        settings.connections,
+        format_bytes(report.bytes),
+    );
+    println!();
+    println!("     If your app answers much slower than this, the time goes to the app.");
+    println!();
+    Ok(())
+}
+
+struct Report {
+    setup: Percentiles,
+    latency: Percentiles,
+    /// Echoed back in the throughput test.
+    bytes: u64,
+    bytes_per_sec: f64,
+}
+
+async fn measure(addr: SocketAddr, settings: &Settings) -> Result<Report> {
+    // Until the first byte comes back, which takes a data connection to
+    // this client.
+    let mut setup = Vec::with_capacity(settings.samples);
+    for _ in 0..settings.samples {
+        let start = Instant::now();
+        let mut visitor = TcpStream::connect(addr).await?;
+        round_trip(&mut visitor, &[0]).await?;
+        setup.push(start.elapsed());
+    }
+
+    let mut visitor = TcpStream::connect(addr).await?;
+    visitor.set_nodelay(true)?;
+    let mut latency = Vec::with_capacity(settings.samples);
+    for _ in 0..settings.samples {
+        let start = Instant::now();
+        round_trip(&mut visitor, &[0; PING_SIZE]).await?;
+        latency.push(start.elapsed());
+    }
+    drop(visitor);
+
+    let start = Instant::now();
+    let deadline = start + settings.duration;
+    let streams = (0..settings.connections).map(|_| stream(addr, deadline));
+    let bytes: u64 = try_join_all(streams).await?.into_iter().sum();
+    Ok(Report {
+        setup: Percentiles::of(setup),
+        latency: Percentiles::of(latency),
+        bytes,
+        bytes_per_sec: bytes as f64 / start.elapsed().as_secs_f64(),
+    })
+}
+
+/// Send `data` and wait for all of it to come back.
+async fn round_trip(visitor: &mut TcpStream, data: &[u8]) -> Result<()> {
+    visitor.write_all(data).await?;
+    let mut echoed = vec![0; data.len()];
+    visitor
+        .read_exact(&mut echoed)
+        .await
+        .context("the tunnel closed the connection")?;
+    Ok(())
+}
+
+/// Write as fast as the tunnel takes it until `deadline`, and count what
+/// comes back.
+async fn stream(addr: SocketAddr, deadline: Instant) -> Result<u64> {
+    let visitor = TcpStream::connect(addr).await?;
+    let (mut read, mut write) = visitor.into_split();
+    let writer = tokio::spawn(async move {
+        let chunk = vec![0; CHUNK_SIZE];
+        while Instant::now() < deadline {
+            write.write_all(&chunk).await?;
+        }
+        write.shutdown().await
+    });
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut echoed = 0;
+    loop {
+        match read.read(&mut buf).await? {
+            0 => break,
+            n => echoed += n as u64,
+        }
+    }
+    writer.await??;
+    Ok(echoed)
+}
+
+/// Summary of a set of timings.
+struct Percentiles {
+    p50: Duration,
+    p90: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+impl Percentiles {
+    fn of(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let at = |p: f64| {
+            let i = ((samples.len().saturating_sub(1)) as f64 * p).round() as usize;
+            samples.get(i).copied().unwrap_or_default()
+        };
+        Self {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+impl std::fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "p50 {:.1}ms  p90 {:.1}ms  p99 {:.1}ms  max {:.1}ms",
+            ms(self.p50),
+            ms(self.p90),
+            ms(self.p99),
+            ms(self.max),
+        )
+    }
+}
@@ -0,0 +1,191 @@
+//! Simulated network conditions (`--chaos`), to see how an app behaves for
+//! visitors far away or on a bad link without leaving the desk.
+//!
+//! Visitor traffic is held back by `latency`, give or take up to `jitter`,
+//! in each direction on its own and without reordering. With `drop`, every
+//! chunk of a TCP or HTTP connection has that chance of cutting the
+//! connection, and every UDP datagram of being lost.
+
+use std::{collections::VecDeque, fmt, future::pending, io, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{sleep_until, Instant},
+};
+
+/// Bytes read from one side at a time.
+const CHUNK: usize = 16 * 1024;
+
+/// Most bytes held back per direction; reading waits beyond that, and
+/// datagrams are lost.
+const MAX_IN_FLIGHT: usize = 1024 * 1024;
+
+/// How bad the simulated network is.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Chaos {
+    pub latency: Duration,
+    pub jitter: Duration,
+    /// Chance, from 0 to 1, of cutting a connection at each chunk, or of
+    /// losing a datagram.
+    pub drop: f64,
+}
+
+/// Parses `latency=200ms,jitter=50ms,drop=1%`; each part is optional.
+impl FromStr for Chaos {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut chaos = Self::default();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let Some((key, value)) = part.split_once('=') else {
+                bail!("expected key=value, got '{part}'");
+            };
+            let duration = || {
+                humantime::parse_duration(value).with_context(|| format!("invalid {key} '{value}'"))
+            };
+            match key {
+                "latency" => chaos.latency = duration()?,
+                "jitter" => chaos.jitter = duration()?,
+                "drop" => {
+                    let drop = match value.strip_suffix('%') {
+                        Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+                        None => value.parse(),
+                    };
+                    chaos.drop = drop
+                        .ok()
+                        .filter(|d| (0.0..=1.0).contains(d))
+                        .with_context(|| format!("invalid drop '{value}' (expected 0-100%)"))?;
+                }
+                _ => bail!("unknown setting '{key}' (expected latency, jitter or drop)"),
+            }
+        }
+        Ok(chaos)
+    }
+}
+
+impl fmt::Display for Chaos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency={},jitter={},drop={}%",
+            humantime::format_duration(self.latency),
+            humantime::format_duration(self.jitter),
+            self.drop * 100.0
+        )
+    }
+}
+
+impl Chaos {
+    /// How long to hold back the next chunk or datagram.
+    pub(crate) fn delay(&self) -> Duration {
+        let jitter = self.jitter.as_secs_f64() * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_secs_f64((self.latency.as_secs_f64() + jitter).max(0.0))
+    }
+
+    /// Whether the next chunk cuts its connection, or the next datagram is
+    /// lost.
+    pub(crate) fn drops(&self) -> bool {
+        self.drop > 0.0 && fastrand::f64() < self.drop
+    }
+
+    /// Copy between `a` and `b` like [`tokio::io::copy_bidirectional`], over
+    /// the simulated network. Returns the bytes copied from `a` to `b`, and
+    /// from `b` to `a`.
+    pub(crate) async fn relay<A, B>(&self, a: &mut A, b: &mut B) -> io::Result<(u64, u64)>
+    where
+        A: AsyncRead + AsyncWrite + Unpin,
+        B: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut a_buf, mut b_buf) = (vec![0; CHUNK], vec![0; CHUNK]);
+        let (mut to_a, mut to_b) = (DelayLine::default(), DelayLine::default());
+        // Whether each side still sends, and was told the other is done.
+        let (mut a_open, mut b_open) = (true, true);
+        let (mut a_shut, mut b_shut) = (false, false);
+        let (mut a_to_b, mut b_to_a) = (0, 0);
+        loop {
+            if !a_open && to_b.is_empty() && !b_shut {
+                b.shutdown().await?;
+                b_shut = true;
+            }
+            if !b_open && to_a.is_empty() && !a_shut {
+                a.shutdown().await?;
+                a_shut = true;
+            }
+            if a_shut && b_shut {
+                return Ok((a_to_b, b_to_a));
+            }
+            tokio::select! {
+                n = a.read(&mut a_buf), if a_open && !to_b.is_full() => match n? {
+                    0 => a_open = false,
+                    _ if self.drops() => return Err(cut()),
+                    n => to_b.push(a_buf[..n].to_vec(), self.delay()),
+                },
+                n = b.read(&mut b_buf), if b_open && !to_a.is_full() => match n? {
+                    0 => b_open = false,
+                    _ if self.drops() => return Err(cut()),
+                    n => to_a.push(b_buf[..n].to_vec(), self.delay()),
+                },
+                chunk = to_b.pop() => {
+                    b.write_all(&chunk).await?;
+                    a_to_b += chunk.len() as u64;
+                }
+                chunk = to_a.pop() => {
+                    a.write_all(&chunk).await?;
+                    b_to_a += chunk.len() as u64;
+                }
+            }
+        }
+    }
+}
+
+fn cut() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "connection cut by --chaos")
+}
+
+/// Chunks or datagrams on their way, each due at its time. Nothing is due
+/// before what came in ahead of it.
+pub(crate) struct DelayLine<T> {
+    queue: VecDeque<(Instant, T)>,
+    bytes: usize,
+}
+
+impl<T> Default for DelayLine<T> {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> DelayLine<T> {
+    /// Send `item` on, due `delay` from now.
+    pub(crate) fn push(&mut self, item: T, delay: Duration) {
+        let mut due = Instant::now() + delay;
+        if let Some((last, _)) = self.queue.back() {
+            due = due.max(*last);
+        }
+        self.bytes += item.as_ref().len();
+        self.queue.push_back((due, item));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.bytes >= MAX_IN_FLIGHT
+    }
+
+    /// The next item once it is due; never returns while there is none.
+    pub(crate) async fn pop(&mut self) -> T {
+        let Some(&(due, _)) = self.queue.front() else {
+            return pending().await;
+        };
+        sleep_until(due).await;
+        let (_, item) = self.queue.pop_front().unwrap();
+        self.bytes -= item.as_ref().len();
+        item
+    }
+}
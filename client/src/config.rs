@@ -0,0 +1,240 @@
+//! The client config file (`~/.config/sshx/config.toml`).
+//!
+//! ```toml
+//! server = "tunnel.example.com"
+//! secret = "hunter2"             # or: token = "c2a7…"
+//! subdomain_prefix = "alice-"   # prepended to every subdomain
+//! allow_cidr = ["10.0.0.0/8"]   # like --allow-cidr
+//! on_connect = ["https://hooks.slack.com/services/…"]
+//!
+//! [profiles.dev]
+//! forward = ["web:3000", "db:5432:tcp"]
+//!
+//! [profiles.staging]
+//! server = ["staging.example.com", "staging-b.example.com"]   # standbys
+//! forward = ["web:3000"]
+//! ```
+//!
+//! Flags win over the profile, which wins over the top-level settings.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use sshx_client::{
+    balance::Balance, hooks::Hook, Forward, HttpProxy, IpNet, ProxyProtocol, Resolver, Transport,
+};
+
+use crate::Cli;
+
+/// Settings that may appear at the top level and in profiles.
+#[derive(Debug, Default, Deserialize)]
+struct Settings {
+    /// One server, or a list whose rest are standbys.
+    #[serde(default, deserialize_with = "one_or_many")]
+    server: Option<Vec<String>>,
+    control_port: Option<u16>,
+    secret: Option<String>,
+    token: Option<String>,
+    host: Option<String>,
+    tls: Option<bool>,
+    tls_ca: Option<PathBuf>,
+    transport: Option<Transport>,
+    proxy: Option<HttpProxy>,
+    resolver: Option<Resolver>,
+    bind_address: Option<IpAddr>,
+    bind_interface: Option<String>,
+    reconnect: Option<bool>,
+    subdomain_prefix: Option<String>,
+    allow_cidr: Option<Vec<IpNet>>,
+    deny_cidr: Option<Vec<IpNet>>,
+    proxy_protocol: Option<ProxyProtocol>,
+    error_page: Option<PathBuf>,
+    e2e_key: Option<PathBuf>,
+    control_socket: Option<PathBuf>,
+    /// Like `--local-retry`, e.g. "5s".
+    #[serde(default, deserialize_with = "duration")]
+    local_retry: Option<Duration>,
+    /// Like `--buffer-size`, e.g. "256Ki".
+    #[serde(default, deserialize_with = "size")]
+    buffer_size: Option<usize>,
+    resumable: Option<bool>,
+    warm_pool: Option<usize>,
+    /// Like `--ttl`, e.g. "30m".
+    #[serde(default, deserialize_with = "duration")]
+    ttl: Option<Duration>,
+    max_uses: Option<u64>,
+    pool: Option<bool>,
+    takeover_key: Option<String>,
+    balance: Option<Balance>,
+    on_connect: Option<Vec<Hook>>,
+    on_disconnect: Option<Vec<Hook>>,
+    on_new_connection: Option<Vec<Hook>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Profile {
+    #[serde(flatten)]
+    settings: Settings,
+    /// Tunnels as `subdomain:localport[:proto][:publicport]`, like `--forward`.
+    #[serde(default)]
+    forward: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    settings: Settings,
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    /// Where the config file lives unless `--config` says otherwise.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+        Some(base.join("sshx").join("config.toml"))
+    }
+
+    /// Read `path`, or the default file if it exists.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match Self::default_path().filter(|p| p.exists()) {
+                Some(path) => path,
+                None => return Ok(Self::default()),
+            },
+        };
+        let text =
+            fs::read_to_string(&path).with_context(|| format!("cannot read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Whether there is a `[profiles.NAME]` for `name`.
+    pub fn has_profile(&self, name: &str) -> bool {
+        self.profiles.contains_key(name)
+    }
+
+    /// Fill in what `cli` left unset, from `profile` first, then the top level.
+    pub fn apply(&self, cli: &mut Cli, profile: Option<&str>) -> Result<()> {
+        let profile = match profile {
+            Some(name) => match self.profiles.get(name) {
+                Some(profile) => Some(profile),
+                None => bail!("no profile '{name}' in the config file"),
+            },
+            None => None,
+        };
+        if let Some(profile) = profile {
+            profile.settings.apply(cli);
+            for forward in &profile.forward {
+                let forward: Forward = forward.parse().context("invalid forward in profile")?;
+                cli.forwards.push(forward);
+            }
+        }
+        self.settings.apply(cli);
+
+        let prefix = profile
+            .and_then(|p| p.settings.subdomain_prefix.as_deref())
+            .or(self.settings.subdomain_prefix.as_deref());
+        if let Some(prefix) = prefix {
+            if let Some(subdomain) = &mut cli.subdomain {
+                subdomain.insert_str(0, prefix);
+            }
+            for forward in &mut cli.forwards {
+                forward.subdomain.insert_str(0, prefix);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Settings {
+    fn apply(&self, cli: &mut Cli) {
+        fill_list(&mut cli.server, &self.server);
+        fill(&mut cli.control_port, &self.control_port);
+        // A credential given as a flag replaces both kinds from the file.
+        if cli.secret.is_none() && cli.token.is_none() {
+            fill(&mut cli.secret, &self.secret);
+            fill(&mut cli.token, &self.token);
+        }
+        fill(&mut cli.host, &self.host);
+        fill(&mut cli.tls_ca, &self.tls_ca);
+        fill(&mut cli.transport, &self.transport);
+        fill(&mut cli.proxy, &self.proxy);
+        fill(&mut cli.resolver, &self.resolver);
+        fill(&mut cli.bind_address, &self.bind_address);
+        fill(&mut cli.bind_interface, &self.bind_interface);
+        fill(&mut cli.reconnect, &self.reconnect);
+        fill(&mut cli.proxy_protocol, &self.proxy_protocol);
+        fill(&mut cli.error_page, &self.error_page);
+        fill(&mut cli.e2e_key, &self.e2e_key);
+        fill(&mut cli.control_socket, &self.control_socket);
+        fill(&mut cli.local_retry, &self.local_retry);
+        fill(&mut cli.buffer_size, &self.buffer_size);
+        fill(&mut cli.balance, &self.balance);
+        fill(&mut cli.takeover_key, &self.takeover_key);
+        fill(&mut cli.warm_pool, &self.warm_pool);
+        fill(&mut cli.ttl, &self.ttl);
+        fill(&mut cli.max_uses, &self.max_uses);
+        fill_list(&mut cli.allow_cidr, &self.allow_cidr);
+        fill_list(&mut cli.deny_cidr, &self.deny_cidr);
+        fill_list(&mut cli.on_connect, &self.on_connect);
+        fill_list(&mut cli.on_disconnect, &self.on_disconnect);
+        fill_list(&mut cli.on_new_connection, &self.on_new_connection);
+        cli.tls |= self.tls.unwrap_or(false);
+        cli.resumable |= self.resumable.unwrap_or(false);
+        cli.pool |= self.pool.unwrap_or(false);
+    }
+}
+
+fn fill<T: Clone>(flag: &mut Option<T>, config: &Option<T>) {
+    if flag.is_none() {
+        flag.clone_from(config);
+    }
+}
+
+fn fill_list<T: Clone>(flag: &mut Vec<T>, config: &Option<Vec<T>>) {
+    if let (true, Some(config)) = (flag.is_empty(), config) {
+        flag.clone_from(config);
+    }
+}
+
+/// A single string, or a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(Some(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    }))
+}
+
+/// A buffer size written like a flag's, e.g. "256Ki".
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    crate::parse_buffer_size(&text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// A duration written like a flag's, e.g. "500ms" or "5s".
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_duration(&text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
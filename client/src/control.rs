@@ -0,0 +1,526 @@
+//! The control socket of a running client, for `sshx status`,
+//! `sshx add-tunnel` and `sshx down` (`sshx remove-tunnel`).
+//!
+//! A running `sshx` listens on a Unix socket only its user may use (a named
+//! pipe on Windows). Each connection carries one request and one response,
+//! each a line of JSON. Added tunnels get a control connection of their own;
+//! removing one of several tunnels that share a connection unregisters it
+//! there, or reconnects the others without it if the server is too old.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sshx_client::{
+    inspect::Inspector,
+    record::Recorder,
+    status::{Stats, TunnelError},
+    ErrorCode, Event, Forward, Proto, ShutdownHandle, Tunnel,
+};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    sync::mpsc,
+    task::JoinHandle,
+    time::{timeout, Duration},
+};
+use tracing::{info, warn};
+
+use crate::{print_event, tunnel_builder, Cli};
+
+/// How long an added tunnel may take to register.
+const ADD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest request or response line.
+const MAX_LINE: usize = 1024 * 1024;
+
+/// What a command asks of the running client.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    /// `subdomain:localport[:proto][:publicport]`, like `--forward`.
+    AddTunnel {
+        forward: String,
+    },
+    RemoveTunnel {
+        subdomain: String,
+    },
+}
+
+/// The answer: the tunnels as they are after the request, or why it failed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub server: String,
+    #[serde(default)]
+    pub tunnels: Vec<TunnelStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    pub subdomain: String,
+    pub proto: Proto,
+    pub local_port: u16,
+    /// Unset until the server first accepted the tunnel.
+    pub public_port: Option<u16>,
+    pub url: Option<String>,
+    /// Whether its control connection is up.
+    pub connected: bool,
+    /// Traffic as the server last reported it.
+    pub conns: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// Where a client listens unless `--control-socket` says otherwise.
+pub fn default_path() -> Option<PathBuf> {
+    if cfg!(windows) {
+        let user = std::env::var("USERNAME").unwrap_or_default();
+        return Some(PathBuf::from(format!(r"\\.\pipe\sshx-{user}")));
+    }
+    match dirs::runtime_dir() {
+        Some(dir) => Some(dir.join("sshx.sock")),
+        None => Some(crate::config::Config::default_path()?.with_file_name("sshx.sock")),
+    }
+}
+
+/// Send `request` to the client listening on `path`.
+pub async fn send(path: &Path, request: &Request) -> Result<Response> {
+    let stream = connect(path).await.with_context(|| {
+        format!(
+            "no sshx running with a control socket at {}",
+            path.display()
+        )
+    })?;
+    let mut stream = BufReader::new(stream);
+    write_line(&mut stream, request).await?;
+    let response: Response = read_line(&mut stream)
+        .await?
+        .context("the running sshx hung up")?;
+    match response.error {
+        Some(error) => Err(anyhow!(error)),
+        None => Ok(response),
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+async fn write_line(stream: &mut (impl AsyncWrite + Unpin), value: &impl Serialize) -> Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_line<T: for<'de> Deserialize<'de>>(
+    stream: &mut (impl AsyncBufRead + Unpin),
+) -> Result<Option<T>> {
+    let mut line = String::new();
+    let mut limited = (&mut *stream).take(MAX_LINE as u64);
+    if limited.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(
+        serde_json::from_str(&line).context("invalid control message")?,
+    ))
+}
+
+#[cfg(unix)]
+async fn connect(path: &Path) -> std::io::Result<Box<dyn Io>> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(windows)]
+async fn connect(path: &Path) -> std::io::Result<Box<dyn Io>> {
+    let pipe = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+    Ok(Box::new(pipe))
+}
+
+/// The listening end of the control socket.
+pub struct Listener {
+    path: PathBuf,
+    #[cfg(unix)]
+    inner: tokio::net::UnixListener,
+    /// The pipe instance the next command connects to.
+    #[cfg(windows)]
+    next: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+
+impl Listener {
+    /// Listen on `path`, or return `None` if another client already does.
+    #[cfg(unix)]
+    pub async fn bind(path: &Path) -> Result<Option<Self>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if connect(path).await.is_ok() {
+            return Ok(None);
+        }
+        // Left behind by a client that didn't get to clean up.
+        let _ = std::fs::remove_file(path);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("cannot create {}", dir.display()))?;
+        }
+        let inner = tokio::net::UnixListener::bind(path)
+            .with_context(|| format!("cannot listen on {}", path.display()))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Some(Self {
+            path: path.to_owned(),
+            inner,
+        }))
+    }
+
+    #[cfg(windows)]
+    pub async fn bind(path: &Path) -> Result<Option<Self>> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let next = match ServerOptions::new()
+            .first_pipe_instance(true)
+            .reject_remote_clients(true)
+            .create(path)
+        {
+            Ok(next) => next,
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("cannot listen on {}", path.display()))
+            }
+        };
+        Ok(Some(Self {
+            path: path.to_owned(),
+            next,
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[cfg(unix)]
+    async fn accept(&mut self) -> Result<Box<dyn Io>> {
+        let (stream, _) = self.inner.accept().await?;
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(windows)]
+    async fn accept(&mut self) -> Result<Box<dyn Io>> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        self.next.connect().await?;
+        let next = ServerOptions::new()
+            .reject_remote_clients(true)
+            .create(&self.path)?;
+        Ok(Box::new(std::mem::replace(&mut self.next, next)))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// ── The tunnels of a running client ───────────────────────────────────────────
+
+/// Tunnels that share a control connection.
+struct Group {
+    forwards: Vec<Forward>,
+    /// Subdomain the server picked for a forward without one.
+    picked: Option<String>,
+    connected: bool,
+    shutdown: ShutdownHandle,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Group {
+    /// The forwards, with the picked subdomain filled in.
+    fn named(&self) -> impl Iterator<Item = Forward> + '_ {
+        self.forwards.iter().cloned().map(|mut forward| {
+            if forward.subdomain.is_empty() {
+                forward.subdomain = self.picked.clone().unwrap_or_default();
+            }
+            forward
+        })
+    }
+}
+
+/// Keeps the tunnels of a run up, and changes them on request.
+pub struct Running<'a> {
+    cli: &'a Cli,
+    stats: Arc<Stats>,
+    inspector: Option<Arc<Inspector>>,
+    pcap: Option<Arc<Recorder>>,
+    groups: HashMap<u64, Group>,
+    next_id: u64,
+    /// What the server said about each tunnel, by subdomain.
+    public: HashMap<String, (u16, Option<String>)>,
+    traffic: HashMap<String, (u64, u64, u64)>,
+    /// Events of every group; `None` once a group stopped.
+    events_tx: mpsc::UnboundedSender<(u64, Option<Event>)>,
+    events: mpsc::UnboundedReceiver<(u64, Option<Event>)>,
+}
+
+impl<'a> Running<'a> {
+    pub fn new(
+        cli: &'a Cli,
+        stats: Arc<Stats>,
+        inspector: Option<Arc<Inspector>>,
+        pcap: Option<Arc<Recorder>>,
+    ) -> Self {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        Self {
+            cli,
+            stats,
+            inspector,
+            pcap,
+            groups: HashMap::new(),
+            next_id: 0,
+            public: HashMap::new(),
+            traffic: HashMap::new(),
+            events_tx,
+            events,
+        }
+    }
+
+    /// Keep `tunnel`, serving `forwards`, up with the others.
+    pub fn insert(&mut self, forwards: Vec<Forward>, tunnel: Tunnel) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let shutdown = tunnel.shutdown_handle();
+        let events = self.events_tx.clone();
+        let task = tokio::spawn(async move {
+            let mut tunnel = tunnel;
+            while let Some(event) = tunnel.next_event().await {
+                let _ = events.send((id, Some(event)));
+            }
+            let _ = events.send((id, None));
+            tunnel.wait().await
+        });
+        let group = Group {
+            forwards,
+            picked: None,
+            connected: true,
+            shutdown,
+            task,
+        };
+        self.groups.insert(id, group);
+    }
+
+    /// Print events and answer `control` until every tunnel stopped on its
+    /// own, or `signal` resolves.
+    pub async fn run(
+        mut self,
+        mut control: Option<Listener>,
+        mut signal: Pin<&mut impl Future<Output = ()>>,
+    ) -> Result<()> {
+        loop {
+            let next_command = async {
+                match &mut control {
+                    Some(listener) => listener.accept().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                Some((id, event)) = self.events.recv() => match event {
+                    Some(event) => self.observe(id, event),
+                    None => {
+                        // Gone already if it was removed on request.
+                        let Some(group) = self.groups.remove(&id) else {
+                            continue;
+                        };
+                        let result = group.task.await?;
+                        if self.groups.is_empty() {
+                            return result;
+                        }
+                        if let Err(e) = result {
+                            warn!(err = format!("{e:#}"), "tunnel stopped");
+                        }
+                    }
+                },
+                stream = next_command => match stream {
+                    Ok(stream) => self.answer(stream).await,
+                    Err(e) => warn!(err = format!("{e:#}"), "control socket failed"),
+                },
+                _ = &mut signal => {
+                    info!("shutting down");
+                    let mut result = Ok(());
+                    for (_, group) in self.groups.drain() {
+                        group.shutdown.shutdown();
+                        let stopped = group.task.await?;
+                        result = result.and(stopped);
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    fn observe(&mut self, id: u64, event: Event) {
+        let Some(group) = self.groups.get_mut(&id) else {
+            return;
+        };
+        match &event {
+            Event::Connected(registration) => {
+                group.connected = true;
+                let known = group
+                    .forwards
+                    .iter()
+                    .any(|f| f.subdomain == registration.subdomain);
+                if !known {
+                    group.picked = Some(registration.subdomain.clone());
+                }
+                self.public.insert(
+                    registration.subdomain.clone(),
+                    (registration.public_port, registration.url.clone()),
+                );
+            }
+            Event::Disconnected { .. } => group.connected = false,
+            Event::Traffic { subdomain, traffic } => {
+                let counts = (traffic.conns, traffic.bytes_in, traffic.bytes_out);
+                self.traffic.insert(subdomain.clone(), counts);
+            }
+            _ => {}
+        }
+        let forwards: Vec<Forward> = self.groups.values().flat_map(|g| g.named()).collect();
+        let _ = print_event(self.cli, &forwards, event);
+    }
+
+    /// Read one request from `stream` and answer it.
+    async fn answer(&mut self, stream: Box<dyn Io>) {
+        let mut stream = BufReader::new(stream);
+        let request = timeout(Duration::from_secs(5), read_line::<Request>(&mut stream)).await;
+        let done = match request {
+            Ok(Ok(Some(request))) => self.handle(request).await,
+            Ok(Ok(None)) => return,
+            Ok(Err(e)) => Err(e),
+            Err(_) => return,
+        };
+        let mut response = self.status();
+        if let Err(e) = done {
+            response.error = Some(format!("{e:#}"));
+        }
+        let _ = write_line(&mut stream, &response).await;
+    }
+
+    async fn handle(&mut self, request: Request) -> Result<()> {
+        match request {
+            Request::Status => Ok(()),
+            Request::AddTunnel { forward } => {
+                let forward: Forward = forward.parse()?;
+                if self.find(&forward.subdomain).is_some() {
+                    bail!("tunnel '{}' is already running", forward.subdomain);
+                }
+                info!(%forward, "adding tunnel");
+                self.add(vec![forward]).await
+            }
+            Request::RemoveTunnel { subdomain } => {
+                let id = self
+                    .find(&subdomain)
+                    .with_context(|| format!("no tunnel '{subdomain}'"))?;
+                info!(subdomain, "removing tunnel");
+                self.public.remove(&subdomain);
+                self.traffic.remove(&subdomain);
+                let group = self.groups.get_mut(&id).expect("found above");
+                if group.forwards.len() > 1 {
+                    let closed = group.shutdown.close(&subdomain).await;
+                    let too_old = closed.as_ref().is_err_and(|e| {
+                        e.downcast_ref::<TunnelError>()
+                            .is_some_and(|e| e.code == Some(ErrorCode::ProtocolMismatch))
+                    });
+                    if !too_old {
+                        let picked = group.picked.clone();
+                        group.forwards.retain(|f| match f.subdomain.is_empty() {
+                            true => picked.as_deref() != Some(subdomain.as_str()),
+                            false => f.subdomain != subdomain,
+                        });
+                        return closed;
+                    }
+                }
+                let group = self.groups.remove(&id).expect("found above");
+                // The others on its connection come back without it.
+                let rest: Vec<Forward> =
+                    group.named().filter(|f| f.subdomain != subdomain).collect();
+                group.shutdown.shutdown();
+                if let Err(e) = group.task.await? {
+                    warn!(err = format!("{e:#}"), "tunnel stopped");
+                }
+                match rest.is_empty() {
+                    true => Ok(()),
+                    false => self.add(rest).await,
+                }
+            }
+        }
+    }
+
+    /// The group serving `subdomain`.
+    fn find(&self, subdomain: &str) -> Option<u64> {
+        self.groups
+            .iter()
+            .find(|(_, group)| group.named().any(|f| f.subdomain == subdomain))
+            .map(|(&id, _)| id)
+    }
+
+    /// Register `forwards` on a control connection of their own.
+    async fn add(&mut self, forwards: Vec<Forward>) -> Result<()> {
+        let builder = tunnel_builder(
+            self.cli,
+            &forwards,
+            Arc::clone(&self.stats),
+            self.inspector.as_ref(),
+            self.pcap.as_ref(),
+        )?;
+        let tunnel = timeout(ADD_TIMEOUT, builder.connect())
+            .await
+            .map_err(|_| anyhow!("the server did not accept the tunnel in {ADD_TIMEOUT:?}"))??;
+        self.insert(forwards, tunnel);
+        Ok(())
+    }
+
+    fn status(&self) -> Response {
+        let mut ids: Vec<_> = self.groups.keys().copied().collect();
+        ids.sort_unstable();
+        let tunnels = ids
+            .iter()
+            .flat_map(|id| {
+                let group = &self.groups[id];
+                group.named().map(|forward| {
+                    let public = self.public.get(&forward.subdomain);
+                    let (conns, bytes_in, bytes_out) = self
+                        .traffic
+                        .get(&forward.subdomain)
+                        .copied()
+                        .unwrap_or_default();
+                    TunnelStatus {
+                        proto: forward.proto,
+                        local_port: forward.local_port,
+                        public_port: public.map(|(port, _)| *port),
+                        url: public.and_then(|(_, url)| url.clone()),
+                        connected: group.connected,
+                        conns,
+                        bytes_in,
+                        bytes_out,
+                        subdomain: forward.subdomain,
+                    }
+                })
+            })
+            .collect();
+        Response {
+            error: None,
+            server: self.cli.server().to_owned(),
+            tunnels,
+        }
+    }
+}
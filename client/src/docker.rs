@@ -0,0 +1,234 @@
+//! `sshx docker`: ask the Docker daemon which port a container exposes and
+//! where it is reached from here, so that the port can be tunneled without
+//! looking it up by hand.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{de::IgnoredAny, Deserialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// Where the daemon listens unless `$DOCKER_HOST` says otherwise.
+const DEFAULT_HOST: &str = "unix:///var/run/docker.sock";
+
+/// How long the daemon has to answer.
+const API_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A container's service, as reached from this machine.
+#[derive(Debug)]
+pub struct Target {
+    /// The container's name, fit for a subdomain.
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub udp: bool,
+}
+
+/// What `GET /containers/{name}/json` says, as far as we need it.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    name: String,
+    state: State,
+    config: Config,
+    network_settings: NetworkSettings,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct State {
+    running: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Config {
+    #[serde(default)]
+    exposed_ports: Option<HashMap<String, IgnoredAny>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    /// Exposed ports, with where they are published on the host if they are.
+    #[serde(default)]
+    ports: Option<HashMap<String, Option<Vec<Binding>>>>,
+    #[serde(default, rename = "IPAddress")]
+    ip_address: String,
+    #[serde(default)]
+    networks: HashMap<String, Network>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Binding {
+    #[serde(default)]
+    host_ip: String,
+    host_port: String,
+}
+
+#[derive(Deserialize)]
+struct Network {
+    #[serde(default, rename = "IPAddress")]
+    ip_address: String,
+}
+
+/// Find the service of `container`. With several exposed ports,
+/// `container_port` says which. A port published on the host is reached
+/// there; otherwise the container's own address is used, which works on a
+/// Linux host or from another container on the same network.
+pub async fn inspect(container: &str, container_port: Option<u16>) -> Result<Target> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_.-".contains(c);
+    if container.is_empty() || !container.chars().all(valid) {
+        bail!("'{container}' is not a container name or id");
+    }
+    let path = format!("/containers/{container}/json");
+    let (status, body) = timeout(API_TIMEOUT, get(&path))
+        .await
+        .context("the Docker daemon did not answer")??;
+    match status {
+        200 => {}
+        404 => bail!("no container named '{container}'"),
+        _ => bail!(
+            "the Docker daemon answered {status}: {}",
+            String::from_utf8_lossy(&body).trim()
+        ),
+    }
+    let info: Container =
+        serde_json::from_slice(&body).context("unexpected answer from the Docker daemon")?;
+    if !info.state.running {
+        bail!("container '{container}' is not running");
+    }
+    target(info, container_port)
+}
+
+fn target(info: Container, container_port: Option<u16>) -> Result<Target> {
+    let name = info.name.trim_start_matches('/');
+    let settings = info.network_settings;
+    let published = settings.ports.unwrap_or_default();
+    let mut exposed: Vec<(u16, bool, &str)> = info
+        .config
+        .exposed_ports
+        .iter()
+        .flatten()
+        .map(|(key, _)| key)
+        .chain(published.keys())
+        .filter_map(|key| {
+            let (port, proto) = key.split_once('/').unwrap_or((key, "tcp"));
+            let udp = match proto {
+                "tcp" => false,
+                "udp" => true,
+                _ => return None,
+            };
+            Some((port.parse().ok()?, udp, key.as_str()))
+        })
+        .collect();
+    exposed.sort();
+    exposed.dedup_by_key(|(port, udp, _)| (*port, *udp));
+    let (port, udp, key) = match (container_port, &exposed[..]) {
+        (Some(wanted), _) => *exposed
+            .iter()
+            .find(|(port, ..)| *port == wanted)
+            .with_context(|| format!("container '{name}' does not expose port {wanted}"))?,
+        (None, [only]) => *only,
+        (None, []) => bail!("container '{name}' exposes no port; pick one with --container-port"),
+        (None, several) => {
+            let list: Vec<_> = several.iter().map(|(.., key)| *key).collect();
+            bail!(
+                "container '{name}' exposes {}; pick one with --container-port",
+                list.join(", ")
+            )
+        }
+    };
+
+    let binding = published
+        .get(key)
+        .and_then(Option::as_ref)
+        .and_then(|bindings| bindings.first());
+    let (host, port) = match binding {
+        Some(binding) => {
+            let host = match binding.host_ip.as_str() {
+                "" | "0.0.0.0" | "::" => "localhost".to_owned(),
+                ip => ip.to_owned(),
+            };
+            let port = binding
+                .host_port
+                .parse()
+                .context("unexpected host port from the Docker daemon")?;
+            (host, port)
+        }
+        None => {
+            let ip = Some(&settings.ip_address)
+                .filter(|ip| !ip.is_empty())
+                .or_else(|| {
+                    let mut networks: Vec<_> = settings.networks.iter().collect();
+                    networks.sort_by_key(|(name, _)| *name);
+                    networks
+                        .into_iter()
+                        .map(|(_, network)| &network.ip_address)
+                        .find(|ip| !ip.is_empty())
+                })
+                .with_context(|| {
+                    format!("port {port} of '{name}' is not published and it has no address")
+                })?;
+            (ip.clone(), port)
+        }
+    };
+    Ok(Target {
+        name: subdomain_of(name),
+        host,
+        port,
+        udp,
+    })
+}
+
+/// Container names may have underscores and dots, which hostnames can't.
+fn subdomain_of(name: &str) -> String {
+    name.to_ascii_lowercase().replace(['_', '.'], "-")
+}
+
+/// Send a GET to the daemon and return the status and body.
+async fn get(path: &str) -> Result<(u16, Vec<u8>)> {
+    let docker_host = std::env::var("DOCKER_HOST").unwrap_or_else(|_| DEFAULT_HOST.into());
+    if let Some(addr) = docker_host.strip_prefix("tcp://") {
+        let stream = TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("cannot reach the Docker daemon at {addr}"))?;
+        return request(stream, path).await;
+    }
+    let Some(socket) = docker_host.strip_prefix("unix://") else {
+        bail!("DOCKER_HOST '{docker_host}' is neither unix:// nor tcp://");
+    };
+    #[cfg(unix)]
+    return match tokio::net::UnixStream::connect(socket).await {
+        Ok(stream) => request(stream, path).await,
+        Err(e) => Err(anyhow!(e).context(format!(
+            "cannot reach the Docker daemon at {socket}; is it running, and may we use it?"
+        ))),
+    };
+    #[cfg(not(unix))]
+    bail!("can't reach the Docker daemon at {socket} here; set DOCKER_HOST to tcp://host:port")
+}
+
+/// One HTTP/1.0 request, so the daemon closes the connection after the
+/// body and no chunked encoding comes back.
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    path: &str,
+) -> Result<(u16, Vec<u8>)> {
+    let head = format!("GET {path} HTTP/1.0\r\nHost: docker\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let httparse::Status::Complete(len) = parsed.parse(&response)? else {
+        bail!("the Docker daemon sent a cut-off answer");
+    };
+    let status = parsed.code.unwrap_or_default();
+    Ok((status, response[len..].to_vec()))
+}
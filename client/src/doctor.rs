@@ -0,0 +1,76 @@
+//! `sshx doctor`: find out why tunnels don't come up, or why HTTP tunnels
+//! don't answer, and say what to fix. Most first-run failures are DNS.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use sshx_client::{
+    status::{Failure, Stats, TunnelError},
+    ErrorCode,
+};
+use sshx_core::doctor::{self, Check, Report};
+
+use crate::Cli;
+
+/// A subdomain looked up only to prove the credentials work.
+const PROBE_SUBDOMAIN: &str = "sshx-doctor";
+
+pub async fn run(cli: &Cli, domain: Option<&str>, http_port: u16) -> Result<()> {
+    let server = cli.server();
+    let mut report = Report::default();
+    let ips = match doctor::resolve(server).await {
+        Ok(ips) if !ips.is_empty() => {
+            let list: Vec<_> = ips.iter().map(ToString::to_string).collect();
+            report.push(Check::pass(format!(
+                "{server} resolves to {}",
+                list.join(", ")
+            )));
+            ips
+        }
+        _ => {
+            report.push(Check::fail(
+                format!("{server} does not resolve"),
+                "check --server, or the server's A and AAAA records",
+            ));
+            print!("{report}");
+            bail!("cannot find the server");
+        }
+    };
+
+    let lookup = crate::builder(cli, Arc::new(Stats::new()))
+        .reconnect(false)
+        .lookup(PROBE_SUBDOMAIN)
+        .await;
+    report.push(match lookup {
+        Ok(_) => Check::pass(format!("{server} accepts our credentials")),
+        Err(e) => match (TunnelError::code_of(&e), Failure::of(&e)) {
+            (Some(ErrorCode::NoSuchTunnel), _) => {
+                Check::pass(format!("{server} accepts our credentials"))
+            }
+            (_, Failure::Auth) => Check::fail(
+                format!("{server} refused our credentials: {e:#}"),
+                "pass the server's --secret, or a --token its operator issued you",
+            ),
+            (_, Failure::Network) => Check::fail(
+                format!("cannot reach the control port of {server}: {e:#}"),
+                "open 12267/tcp (12268 with --tls) on the server's firewall, or use \
+                 --transport ws where only HTTPS gets out",
+            ),
+            _ => Check::fail(
+                format!("{server} answered unexpectedly: {e:#}"),
+                "upgrade the client and the server to the same release",
+            ),
+        },
+    });
+
+    let domain = domain.unwrap_or(server);
+    if let Some(probe) = doctor::check_routing(&mut report, domain, http_port, &ips).await {
+        doctor::check_clock(&mut report, &probe);
+    }
+
+    print!("{report}");
+    if report.failed() {
+        bail!("fix the problems above, then run sshx doctor again");
+    }
+    Ok(())
+}
@@ -0,0 +1,327 @@
+//! Hooks on tunnel lifecycle events (`--on-connect`, `--on-disconnect`,
+//! `--on-new-connection`).
+//!
+//! A hook is either a shell command, run with the event in `SSHX_*`
+//! environment variables, or an `http://` or `https://` URL the event is
+//! POSTed to as JSON. The JSON has a one-line summary in `text`, which is what
+//! Slack's incoming webhooks post. Hooks run in the background; one that fails
+//! or hangs is logged and doesn't hold up the tunnel.
+
+use std::{fmt, net::SocketAddr, str::FromStr, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    process::Command,
+    time::{timeout, Duration},
+};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tracing::warn;
+
+use crate::{tls, Event};
+
+/// How long a hook may run, or a webhook take to answer.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest webhook response head we read.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// What runs when an event fires.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Hook {
+    /// Run through `sh -c` (`cmd /C` on Windows).
+    Command(String),
+    /// POST the event as JSON.
+    Webhook(Webhook),
+}
+
+impl FromStr for Hook {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once("://") {
+            Some(("http" | "https", _)) => Ok(Self::Webhook(s.parse()?)),
+            _ if s.trim().is_empty() => bail!("empty hook"),
+            _ => Ok(Self::Command(s.to_owned())),
+        }
+    }
+}
+
+impl TryFrom<String> for Hook {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// A URL to POST events to, `http[s]://host[:port][/path]`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Webhook {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// Path and query.
+    path: String,
+}
+
+impl FromStr for Webhook {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (tls, rest) = match s.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => bail!("webhook '{s}' must start with http:// or https://"),
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(at) => (&rest[..at], rest[at..].to_owned()),
+            None => (rest, "/".to_owned()),
+        };
+        let path = match path.starts_with('?') {
+            true => format!("/{path}"),
+            false => path,
+        };
+        // `[::1]:8080` keeps its colons inside the brackets.
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .with_context(|| format!("invalid webhook port '{port}'"))?,
+            ),
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('@') {
+            bail!("invalid webhook host in '{s}'");
+        }
+        Ok(Self {
+            tls,
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+}
+
+/// Leaves the path out, which for many services is the secret.
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{scheme}://{}:{}/…", self.host, self.port)
+    }
+}
+
+/// The hooks of each event.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Hooks {
+    /// When the server accepts a tunnel, also after a reconnect.
+    pub(crate) connect: Vec<Hook>,
+    /// When the control connection is lost.
+    pub(crate) disconnect: Vec<Hook>,
+    /// When a visitor connects.
+    pub(crate) new_connection: Vec<Hook>,
+}
+
+/// What hooks are told about an event.
+#[derive(Debug, Serialize)]
+struct Payload {
+    /// `connect`, `disconnect` or `new_connection`.
+    event: &'static str,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subdomain: Option<String>,
+    /// `server:port` visitors connect to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_addr: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reconnecting: Option<bool>,
+}
+
+impl Payload {
+    fn new(event: &'static str, text: String) -> Self {
+        Self {
+            event,
+            text,
+            subdomain: None,
+            public: None,
+            url: None,
+            remote_addr: None,
+            error: None,
+            reconnecting: None,
+        }
+    }
+
+    /// The payload as `SSHX_*` environment variables.
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![("SSHX_EVENT", self.event.to_owned())];
+        let fields = [
+            ("SSHX_SUBDOMAIN", self.subdomain.clone()),
+            ("SSHX_PUBLIC", self.public.clone()),
+            ("SSHX_URL", self.url.clone()),
+            ("SSHX_REMOTE_ADDR", self.remote_addr.map(|a| a.to_string())),
+            ("SSHX_ERROR", self.error.clone()),
+        ];
+        env.extend(fields.into_iter().filter_map(|(k, v)| Some((k, v?))));
+        env
+    }
+}
+
+impl Hooks {
+    /// Start the hooks of `event`, if it has any, for a tunnel on `server`.
+    pub(crate) fn fire(&self, server: &str, event: &Event) {
+        let (hooks, payload) = match event {
+            Event::Connected(registration) if !self.connect.is_empty() => {
+                let public = format!("{server}:{}", registration.public_port);
+                let at = registration.url.as_ref().unwrap_or(&public);
+                let mut payload = Payload::new(
+                    "connect",
+                    format!("Tunnel {} is up at {at}", registration.subdomain),
+                );
+                payload.subdomain = Some(registration.subdomain.clone());
+                payload.public = Some(public);
+                payload.url = registration.url.clone();
+                (&self.connect, payload)
+            }
+            Event::Disconnected {
+                error,
+                reconnecting,
+            } if !self.disconnect.is_empty() => {
+                let mut payload =
+                    Payload::new("disconnect", format!("Tunnel lost {server}: {error}"));
+                payload.error = Some(error.clone());
+                payload.reconnecting = Some(*reconnecting);
+                (&self.disconnect, payload)
+            }
+            Event::Connection {
+                subdomain,
+                peer_addr,
+                public_port,
+                ..
+            } if !self.new_connection.is_empty() => {
+                let mut payload = Payload::new(
+                    "new_connection",
+                    format!("Visitor from {peer_addr} on tunnel {subdomain}"),
+                );
+                payload.subdomain = Some(subdomain.clone());
+                payload.public = public_port.map(|port| format!("{server}:{port}"));
+                payload.remote_addr = Some(*peer_addr);
+                (&self.new_connection, payload)
+            }
+            _ => return,
+        };
+        let payload = Arc::new(payload);
+        for hook in hooks {
+            let hook = hook.clone();
+            let payload = Arc::clone(&payload);
+            tokio::spawn(async move {
+                let ran = match timeout(HOOK_TIMEOUT, hook.run(&payload)).await {
+                    Ok(ran) => ran,
+                    Err(_) => Err(anyhow::anyhow!("timed out")),
+                };
+                if let Err(e) = ran {
+                    warn!(event = payload.event, ?hook, "hook failed: {e:#}");
+                }
+            });
+        }
+    }
+}
+
+impl Hook {
+    async fn run(&self, payload: &Payload) -> Result<()> {
+        match self {
+            Self::Command(command) => {
+                let (shell, flag) = match cfg!(windows) {
+                    true => ("cmd", "/C"),
+                    false => ("sh", "-c"),
+                };
+                let status = Command::new(shell)
+                    .args([flag, command])
+                    .envs(payload.env())
+                    .stdin(std::process::Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await
+                    .with_context(|| format!("cannot run '{command}'"))?;
+                if !status.success() {
+                    bail!("'{command}' exited with {status}");
+                }
+                Ok(())
+            }
+            Self::Webhook(webhook) => webhook.post(payload).await,
+        }
+    }
+}
+
+impl Webhook {
+    async fn post(&self, payload: &Payload) -> Result<()> {
+        let body = serde_json::to_vec(payload)?;
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("cannot reach {}:{}", self.host, self.port))?;
+        if !self.tls {
+            return self.send(stream, &body).await;
+        }
+        let name = ServerName::try_from(self.host.clone())
+            .with_context(|| format!("invalid TLS name '{}'", self.host))?;
+        let stream = tls::connector(None)?
+            .connect(name, stream)
+            .await
+            .with_context(|| format!("TLS with {} failed", self.host))?;
+        self.send(stream, &body).await
+    }
+
+    /// Send the request on `stream` and check the status of the answer.
+    async fn send(
+        &self,
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        body: &[u8],
+    ) -> Result<()> {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        let head = format!(
+            "POST {} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             User-Agent: sshx/{}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.path,
+            env!("CARGO_PKG_VERSION"),
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.windows(4).any(|w| w == b"\r\n\r\n") {
+            if response.len() > MAX_HEAD {
+                bail!("webhook response head too large");
+            }
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        let status = String::from_utf8_lossy(&response);
+        let status = status.lines().next().unwrap_or_default();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => bail!("webhook answered '{status}'"),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Reaching the server through an HTTP proxy with `CONNECT`.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout, Duration},
+};
+
+use crate::status::{Failure, TunnelError};
+
+/// Largest proxy response head we read.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// An HTTP proxy, given as `http://[user:pass@]host[:port]`.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HttpProxy {
+    pub host: String,
+    pub port: u16,
+    /// `user:pass`, sent as basic auth.
+    credentials: Option<String>,
+}
+
+impl FromStr for HttpProxy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = match s.split_once("://") {
+            Some(("http", rest)) => rest,
+            Some((scheme, _)) => bail!("unsupported proxy scheme '{scheme}' (expected http)"),
+            None => s,
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, addr) = match rest.rsplit_once('@') {
+            Some((credentials, addr)) => (Some(credentials.to_owned()), addr),
+            None => (None, rest),
+        };
+        // `[::1]:3128` keeps its colons inside the brackets.
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .with_context(|| format!("invalid proxy port '{port}'"))?,
+            ),
+            _ => (addr, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            bail!("missing proxy host in '{s}'");
+        }
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            credentials,
+        })
+    }
+}
+
+impl TryFrom<String> for HttpProxy {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Leaves the password out.
+impl fmt::Debug for HttpProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}", self.host, self.port)
+    }
+}
+
+impl HttpProxy {
+    /// The proxy named by `HTTPS_PROXY` (or `https_proxy`), unless `server`
+    /// is listed in `NO_PROXY` (or `no_proxy`), as curl reads them.
+    pub fn from_env(server: &str) -> Result<Option<Self>> {
+        let var = |name: &str| {
+            std::env::var(name.to_ascii_uppercase())
+                .or_else(|_| std::env::var(name))
+                .ok()
+                .filter(|v| !v.is_empty())
+        };
+        let Some(proxy) = var("https_proxy") else {
+            return Ok(None);
+        };
+        let server = server.to_ascii_lowercase();
+        let exempt = var("no_proxy").is_some_and(|list| {
+            list.split(',').map(str::trim).any(|entry| {
+                let entry = entry.trim_start_matches('.').to_ascii_lowercase();
+                entry == "*"
+                    || server == entry
+                    || server
+                        .strip_suffix(&entry)
+                        .is_some_and(|rest| rest.ends_with('.'))
+            })
+        });
+        if exempt {
+            return Ok(None);
+        }
+        let proxy = proxy.parse().context("invalid HTTPS_PROXY")?;
+        Ok(Some(proxy))
+    }
+
+    /// Ask the proxy on `stream` for a tunnel to `host:port`, giving it
+    /// `handshake` to answer.
+    pub(crate) async fn connect(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+        handshake: Duration,
+    ) -> Result<()> {
+        let target = if host.contains(':') {
+            format!("[{host}]:{port}")
+        } else {
+            format!("{host}:{port}")
+        };
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some(credentials) = &self.credentials {
+            let token = STANDARD.encode(credentials);
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let head = timeout(handshake, read_head(stream))
+            .await
+            .context("proxy did not answer CONNECT")??;
+        let status_line = head.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        let refused = |kind| {
+            let message = format!("proxy refused CONNECT to {target}: {status_line}");
+            Err(TunnelError::new(kind, message).into())
+        };
+        match status {
+            s if s.starts_with('2') => Ok(()),
+            "407" => refused(Failure::Auth),
+            _ => refused(Failure::Network),
+        }
+    }
+}
+
+/// Read the response head byte by byte, so nothing after it is consumed.
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::with_capacity(256);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            bail!("proxy response head larger than {MAX_HEAD} bytes");
+        }
+        let byte = stream
+            .read_u8()
+            .await
+            .context("proxy closed the connection")?;
+        head.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
@@ -0,0 +1,1105 @@
+//! HTTP request inspection (`--inspect`).
+//!
+//! In HTTP tunnels the bytes to and from the local service are parsed as
+//! HTTP/1.x on the side, while they are relayed unchanged. Every finished
+//! exchange is logged and kept in a ring buffer, which [`Inspector::serve`]
+//! shows on a local port:
+//!
+//! ```text
+//! GET /                     recent requests as a web page
+//! GET /api/requests         recent requests, newest first
+//! GET /api/requests/<id>    one request, with headers and request body
+//! POST /api/requests/<id>/replay
+//!                           send the request to the local service again;
+//!                           JSON or from the inspector's own origin only
+//! GET /api/rpcs             calls and errors per gRPC method
+//! ```
+//!
+//! Requests must name the inspector by `localhost` or an address in `Host`,
+//! so that a web page can't read it through a DNS name rebound to
+//! 127.0.0.1.
+//!
+//! Anything that doesn't parse, and everything after a protocol upgrade such
+//! as WebSocket, is passed through without being looked at.
+//!
+//! gRPC tunnels speak HTTP/2, which is not kept as exchanges: only the header
+//! blocks are read, to count the calls of each method and those that ended
+//! with a `grpc-status` other than 0 or were cancelled.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::json;
+use sshx_core::h2;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    record::{PcapTap, Recorder},
+    tunnel::connect_local,
+};
+
+/// Largest message head parsed; bigger ones turn inspection off for the
+/// connection.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Most headers per message.
+const MAX_HEADERS: usize = 100;
+
+/// Request body bytes kept per exchange.
+const MAX_BODY: usize = 64 * 1024;
+
+/// How long a browser may take to send the request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the local service may take to answer a replayed request.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Request headers a replay sets itself.
+const REPLACED_HEADERS: [&str; 3] = ["connection", "content-length", "transfer-encoding"];
+
+/// One request and its response, as seen on the way to the local service.
+#[derive(Debug, Clone, Serialize)]
+pub struct Exchange {
+    pub id: u64,
+    /// When the request head arrived, as RFC 3339.
+    pub time: String,
+    pub subdomain: String,
+    pub peer_addr: SocketAddr,
+    /// The local service the request went to.
+    pub local_host: String,
+    pub local_port: u16,
+    /// The Unix socket the request went to instead of the host and port.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_socket: Option<PathBuf>,
+    /// The exchange this one replayed.
+    pub replay_of: Option<u64>,
+    pub method: String,
+    pub path: String,
+    pub request_headers: Vec<(String, String)>,
+    /// The first [`MAX_BODY`] bytes of the request body.
+    #[serde(serialize_with = "lossy")]
+    pub request_body: Vec<u8>,
+    pub request_size: u64,
+    /// `None` when the connection closed before a response.
+    pub status: Option<u16>,
+    pub response_headers: Vec<(String, String)>,
+    pub response_size: u64,
+    /// From the request head to the end of the response.
+    pub duration_ms: u64,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Exchange {
+    /// The request as sent again: the captured head and body, with a fresh
+    /// length and the connection closed after the response.
+    fn replay_request(&self) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        for (name, value) in &self.request_headers {
+            if !REPLACED_HEADERS
+                .iter()
+                .any(|h| name.eq_ignore_ascii_case(h))
+            {
+                let _ = write!(head, "{name}: {value}\r\n");
+            }
+        }
+        if !self.request_body.is_empty() {
+            let _ = write!(head, "Content-Length: {}\r\n", self.request_body.len());
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        let mut request = head.into_bytes();
+        request.extend_from_slice(&self.request_body);
+        request
+    }
+}
+
+fn lossy<S: serde::Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&String::from_utf8_lossy(bytes))
+}
+
+/// An [`Exchange`] as listed, without headers and body.
+#[derive(Serialize)]
+struct Summary<'a> {
+    id: u64,
+    time: &'a str,
+    subdomain: &'a str,
+    peer_addr: SocketAddr,
+    replay_of: Option<u64>,
+    method: &'a str,
+    path: &'a str,
+    status: Option<u16>,
+    duration_ms: u64,
+    request_size: u64,
+    response_size: u64,
+}
+
+impl<'a> From<&'a Exchange> for Summary<'a> {
+    fn from(e: &'a Exchange) -> Self {
+        Self {
+            id: e.id,
+            time: &e.time,
+            subdomain: &e.subdomain,
+            peer_addr: e.peer_addr,
+            replay_of: e.replay_of,
+            method: &e.method,
+            path: &e.path,
+            status: e.status,
+            duration_ms: e.duration_ms,
+            request_size: e.request_size,
+            response_size: e.response_size,
+        }
+    }
+}
+
+/// Calls of one gRPC method through one tunnel.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RpcCount {
+    pub tunnel: String,
+    /// The request path, `/package.Service/Method`.
+    pub method: String,
+    pub calls: u64,
+    /// Calls that ended with a `grpc-status` other than 0, or were cancelled.
+    pub errors: u64,
+}
+
+/// Keeps the most recent exchanges of every HTTP tunnel, and counts the calls
+/// of gRPC ones.
+pub struct Inspector {
+    capacity: usize,
+    next_id: AtomicU64,
+    /// Newest first.
+    exchanges: Mutex<VecDeque<Arc<Exchange>>>,
+    /// By tunnel and method.
+    rpcs: Mutex<BTreeMap<(String, String), RpcCount>>,
+    /// Where finished exchanges are saved too.
+    har: Option<Recorder>,
+}
+
+impl Inspector {
+    /// Keep up to `capacity` exchanges.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            next_id: AtomicU64::new(1),
+            exchanges: Mutex::new(VecDeque::with_capacity(capacity)),
+            rpcs: Mutex::default(),
+            har: None,
+        }
+    }
+
+    /// Save every finished exchange to `recorder`, a HAR recording.
+    pub fn har(mut self, recorder: Recorder) -> Self {
+        self.har = Some(recorder);
+        self
+    }
+
+    /// Recent exchanges, newest first.
+    pub fn recent(&self) -> Vec<Arc<Exchange>> {
+        self.exchanges.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The exchange numbered `id`, if it is still kept.
+    pub fn get(&self, id: u64) -> Option<Arc<Exchange>> {
+        let exchanges = self.exchanges.lock().unwrap();
+        exchanges.iter().find(|e| e.id == id).cloned()
+    }
+
+    /// Calls so far of every gRPC method, by tunnel and method.
+    pub fn rpcs(&self) -> Vec<RpcCount> {
+        self.rpcs.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Count a call of `method` through `tunnel`, or its failure.
+    fn count_rpc(&self, tunnel: &str, method: &str, failed: bool) {
+        let mut rpcs = self.rpcs.lock().unwrap();
+        let key = (tunnel.to_owned(), method.to_owned());
+        let count = rpcs.entry(key).or_insert_with(|| RpcCount {
+            tunnel: tunnel.to_owned(),
+            method: method.to_owned(),
+            ..RpcCount::default()
+        });
+        match failed {
+            true => count.errors += 1,
+            false => count.calls += 1,
+        }
+    }
+
+    /// Send the request of exchange `id` to the local service again. The
+    /// replay is recorded like any other exchange, and returned.
+    pub async fn replay(self: &Arc<Self>, id: u64) -> Result<Arc<Exchange>> {
+        let original = self.get(id).with_context(|| format!("no request #{id}"))?;
+        if original.request_size > original.request_body.len() as u64 {
+            bail!("the body of request #{id} was larger than {MAX_BODY} bytes and was not kept");
+        }
+        let (host, port) = (original.local_host.as_str(), original.local_port);
+        let socket = original.local_socket.as_deref();
+        let mut local = connect_local(host, port, socket).await?;
+
+        let request = original.replay_request();
+        let mut tap = HttpTap::new(
+            Arc::clone(self),
+            &original.subdomain,
+            original.peer_addr,
+            host,
+            port,
+            socket,
+        );
+        tap.pairing.replay_of = Some(id);
+        tap.request(&request);
+        let replay_id = tap.pairing.pending.back().map(|e| e.id);
+        local.write_all(&request).await?;
+
+        let mut buf = vec![0; 16 * 1024];
+        let answered = timeout(REPLAY_TIMEOUT, async {
+            while !tap.is_idle() {
+                let n = local.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                tap.response(&buf[..n]);
+            }
+            anyhow::Ok(())
+        })
+        .await;
+        drop(tap);
+        answered.context("the local service did not answer in time")??;
+        replay_id
+            .and_then(|id| self.get(id))
+            .context("the replayed request was not recorded")
+    }
+
+    fn record(&self, mut exchange: Exchange) {
+        exchange.duration_ms = exchange.started.elapsed().as_millis() as u64;
+        match exchange.status {
+            Some(status) => info!(
+                subdomain = %exchange.subdomain,
+                peer = %exchange.peer_addr,
+                status,
+                duration_ms = exchange.duration_ms,
+                size = exchange.response_size,
+                "{} {}",
+                exchange.method,
+                exchange.path,
+            ),
+            None => info!(
+                subdomain = %exchange.subdomain,
+                peer = %exchange.peer_addr,
+                "{} {} (no response)",
+                exchange.method,
+                exchange.path,
+            ),
+        }
+        if let Some(har) = &self.har {
+            har.exchange(&exchange);
+        }
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if exchanges.len() == self.capacity {
+            exchanges.pop_back();
+        }
+        exchanges.push_front(Arc::new(exchange));
+    }
+
+    /// Serve the inspection pages on `listener`, one request per connection.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(err = %e, "inspector accept failed");
+                    continue;
+                }
+            };
+            let inspector = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = inspector.handle(stream).await {
+                    debug!(%addr, err = %e, "inspector request failed");
+                }
+            });
+        }
+    }
+
+    async fn handle(self: &Arc<Self>, mut stream: TcpStream) -> Result<()> {
+        let head = match timeout(HEAD_TIMEOUT, read_head(&mut stream)).await {
+            Ok(head) => head?,
+            Err(_) => return Ok(()),
+        };
+        // Pages served to other sites' names are how DNS rebinding reads them.
+        let port = stream.local_addr()?.port();
+        if !header(&head, "host").is_some_and(|host| local_host(host, port)) {
+            let body = json!({ "error": "the inspector only answers to localhost" });
+            return respond_json(&mut stream, 403, "Forbidden", body).await;
+        }
+        let mut parts = head.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let path = path.split('?').next().unwrap_or("");
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        match (method, segments.as_slice()) {
+            ("GET", []) => {
+                let page = self.page();
+                respond(&mut stream, 200, "OK", "text/html; charset=utf-8", &page).await
+            }
+            ("GET", ["api", "requests"]) => {
+                let recent = self.recent();
+                let list: Vec<Summary> = recent.iter().map(|e| e.as_ref().into()).collect();
+                respond_json(&mut stream, 200, "OK", json!(list)).await
+            }
+            ("GET", ["api", "requests", id]) => match id.parse().ok().and_then(|id| self.get(id)) {
+                Some(exchange) => respond_json(&mut stream, 200, "OK", json!(*exchange)).await,
+                None => {
+                    let body = json!({ "error": format!("no request '{id}'") });
+                    respond_json(&mut stream, 404, "Not Found", body).await
+                }
+            },
+            ("POST", ["api", "requests", id, "replay"]) if !same_origin(&head) => {
+                let body = json!({ "error": "replays are only taken from the inspector itself" });
+                respond_json(&mut stream, 403, "Forbidden", body).await
+            }
+            ("POST", ["api", "requests", id, "replay"]) => {
+                let Some(id) = id.parse().ok().filter(|&id| self.get(id).is_some()) else {
+                    let body = json!({ "error": format!("no request '{id}'") });
+                    return respond_json(&mut stream, 404, "Not Found", body).await;
+                };
+                match self.replay(id).await {
+                    Ok(replay) => respond_json(&mut stream, 200, "OK", json!(*replay)).await,
+                    Err(e) => {
+                        let body = json!({ "error": format!("{e:#}") });
+                        respond_json(&mut stream, 502, "Bad Gateway", body).await
+                    }
+                }
+            }
+            ("GET", ["api", "rpcs"]) => {
+                respond_json(&mut stream, 200, "OK", json!(self.rpcs())).await
+            }
+            _ => {
+                let body = json!({ "error": format!("no route for {method} {path}") });
+                respond_json(&mut stream, 404, "Not Found", body).await
+            }
+        }
+    }
+
+    /// Recent requests as a table linking to their details.
+    fn page(&self) -> String {
+        let mut page = String::from(
+            "<!doctype html><meta charset=utf-8><title>sshx inspector</title>\
+             <style>body{font-family:sans-serif}td,th{padding:2px 8px;text-align:left}\
+             </style><h1>Recent requests</h1><table><tr><th>#<th>Time<th>Tunnel\
+             <th>Visitor<th>Request<th>Status<th>Duration<th>Size<th></tr>",
+        );
+        for e in self.recent() {
+            let status = e.status.map_or("—".to_owned(), |s| s.to_string());
+            let _ = write!(
+                page,
+                "<tr><td><a href=\"/api/requests/{id}\">{id}</a><td>{time}<td>{tunnel}\
+                 <td>{peer}<td>{method} {path}<td>{status}<td>{ms} ms<td>{size} B<td>\
+                 <button onclick=\"replay({id})\">Replay</button></tr>",
+                id = e.id,
+                time = escape(&e.time),
+                tunnel = escape(&e.subdomain),
+                peer = e.peer_addr,
+                method = escape(&e.method),
+                path = escape(&e.path),
+                ms = e.duration_ms,
+                size = e.response_size,
+            );
+        }
+        page.push_str(
+            "</table><script>function replay(id){fetch(`/api/requests/${id}/replay`,\
+             {method:'POST',headers:{'Content-Type':'application/json'}})\
+             .then(()=>location.reload())}</script>",
+        );
+        let rpcs = self.rpcs();
+        if !rpcs.is_empty() {
+            page.push_str(
+                "<h1>gRPC calls</h1><table><tr><th>Tunnel<th>Method<th>Calls<th>Errors</tr>",
+            );
+            for rpc in rpcs {
+                let _ = write!(
+                    page,
+                    "<tr><td>{}<td>{}<td>{}<td>{}</tr>",
+                    escape(&rpc.tunnel),
+                    escape(&rpc.method),
+                    rpc.calls,
+                    rpc.errors,
+                );
+            }
+            page.push_str("</table>");
+        }
+        page
+    }
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The value of the first header called `name` in a request head.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Whether a request comes from the inspector's own page or a program rather
+/// than from another site: its `Origin` is the inspector's, or it is JSON,
+/// which other sites can't send without a CORS preflight that is never
+/// answered.
+fn same_origin(head: &str) -> bool {
+    let json = header(head, "content-type")
+        .is_some_and(|t| t.split(';').next().unwrap_or("").trim() == "application/json");
+    let own = match (header(head, "origin"), header(head, "host")) {
+        (Some(origin), Some(host)) => origin.strip_prefix("http://") == Some(host),
+        _ => false,
+    };
+    json || own
+}
+
+/// Whether `host` names this machine on `port`: `localhost` or an address,
+/// never a DNS name that might point here for now.
+fn local_host(host: &str, port: u16) -> bool {
+    let Some((name, host_port)) = host.rsplit_once(':') else {
+        return false;
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    host_port.parse() == Ok(port)
+        && (name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok())
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::with_capacity(512);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            bail!("request head larger than {MAX_HEAD} bytes");
+        }
+        head.push(stream.read_u8().await?);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+async fn respond_json(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    body: serde_json::Value,
+) -> Result<()> {
+    let body = body.to_string();
+    respond(stream, status, reason, "application/json", &body).await
+}
+
+async fn respond(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// ── Parsing ───────────────────────────────────────────────────────────────────
+
+/// Follows the exchanges of one visitor connection. Request bytes are what
+/// goes to the local service, response bytes what comes back.
+pub(crate) struct HttpTap {
+    requests: Parser,
+    responses: Parser,
+    pairing: Pairing,
+}
+
+/// Requests waiting for their response, in order.
+struct Pairing {
+    inspector: Arc<Inspector>,
+    subdomain: String,
+    peer_addr: SocketAddr,
+    local_host: String,
+    local_port: u16,
+    local_socket: Option<PathBuf>,
+    replay_of: Option<u64>,
+    pending: VecDeque<Exchange>,
+    /// The exchange whose response is being read.
+    responding: Option<Exchange>,
+    /// The request whose body is being read.
+    filling: Option<u64>,
+    /// A `101 Switching Protocols` or answered `CONNECT` ended HTTP.
+    upgraded: bool,
+}
+
+impl HttpTap {
+    pub(crate) fn new(
+        inspector: Arc<Inspector>,
+        subdomain: &str,
+        peer_addr: SocketAddr,
+        local_host: &str,
+        local_port: u16,
+        local_socket: Option<&Path>,
+    ) -> Self {
+        Self {
+            requests: Parser::default(),
+            responses: Parser::default(),
+            pairing: Pairing {
+                inspector,
+                subdomain: subdomain.to_owned(),
+                peer_addr,
+                local_host: local_host.to_owned(),
+                local_port,
+                local_socket: local_socket.map(Path::to_owned),
+                replay_of: None,
+                pending: VecDeque::new(),
+                responding: None,
+                filling: None,
+                upgraded: false,
+            },
+        }
+    }
+
+    pub(crate) fn request(&mut self, data: &[u8]) {
+        self.requests
+            .feed(data, &mut RequestSide(&mut self.pairing));
+    }
+
+    /// Whether every request seen so far got its whole response.
+    fn is_idle(&self) -> bool {
+        self.pairing.pending.is_empty() && self.pairing.responding.is_none()
+    }
+
+    pub(crate) fn response(&mut self, data: &[u8]) {
+        self.responses
+            .feed(data, &mut ResponseSide(&mut self.pairing));
+        if self.pairing.upgraded {
+            self.requests.state = State::Opaque;
+            self.responses.state = State::Opaque;
+        }
+    }
+}
+
+/// A response read until the connection closed ends here, and requests that
+/// never got one are recorded without.
+impl Drop for HttpTap {
+    fn drop(&mut self) {
+        let pairing = &mut self.pairing;
+        let unanswered = pairing.responding.take().into_iter();
+        for exchange in unanswered.chain(pairing.pending.drain(..)) {
+            pairing.inspector.record(exchange);
+        }
+    }
+}
+
+/// What a connection to the local service is followed by when inspecting
+/// or recording.
+pub(crate) enum Tap {
+    Http(HttpTap),
+    Grpc(GrpcTap),
+    Pcap(PcapTap),
+}
+
+impl Tap {
+    pub(crate) fn request(&mut self, data: &[u8]) {
+        match self {
+            Self::Http(tap) => tap.request(data),
+            Self::Grpc(tap) => tap.request(data),
+            Self::Pcap(tap) => tap.request(data),
+        }
+    }
+
+    pub(crate) fn response(&mut self, data: &[u8]) {
+        match self {
+            Self::Http(tap) => tap.response(data),
+            Self::Grpc(tap) => tap.response(data),
+            Self::Pcap(tap) => tap.response(data),
+        }
+    }
+}
+
+/// Counts the calls on one gRPC connection. Once either direction isn't
+/// HTTP/2, the rest of the connection is passed over.
+pub(crate) struct GrpcTap {
+    inspector: Arc<Inspector>,
+    subdomain: String,
+    peer_addr: SocketAddr,
+    requests: Option<h2::Reader>,
+    responses: Option<h2::Reader>,
+    /// The method of every call not yet ended, by stream.
+    open: HashMap<u32, String>,
+    events: Vec<h2::Event>,
+}
+
+impl GrpcTap {
+    pub(crate) fn new(inspector: Arc<Inspector>, subdomain: &str, peer_addr: SocketAddr) -> Self {
+        Self {
+            inspector,
+            subdomain: subdomain.to_owned(),
+            peer_addr,
+            requests: Some(h2::Reader::requests()),
+            responses: Some(h2::Reader::responses()),
+            open: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub(crate) fn request(&mut self, data: &[u8]) {
+        if !self.read(data, true) {
+            return;
+        }
+        for event in std::mem::take(&mut self.events) {
+            match event {
+                h2::Event::Headers { stream, .. } if !self.open.contains_key(&stream) => {
+                    let Some(method) = event.header(":path") else {
+                        continue;
+                    };
+                    self.inspector.count_rpc(&self.subdomain, &method, false);
+                    self.open.insert(stream, method);
+                }
+                h2::Event::Reset { stream } => self.end(stream, Some("cancelled")),
+                _ => {}
+            }
+        }
+    }
+
+    pub(crate) fn response(&mut self, data: &[u8]) {
+        if !self.read(data, false) {
+            return;
+        }
+        for event in std::mem::take(&mut self.events) {
+            match &event {
+                // Trailers, or a response with nothing but them.
+                h2::Event::Headers { stream, .. } => {
+                    if let Some(status) = event.header("grpc-status") {
+                        self.end(*stream, Some(status.as_str()).filter(|s| *s != "0"));
+                    }
+                }
+                h2::Event::Reset { stream } => self.end(*stream, Some("reset")),
+            }
+        }
+    }
+
+    /// Feed one direction, dropping its reader if the bytes aren't HTTP/2.
+    fn read(&mut self, data: &[u8], request: bool) -> bool {
+        let reader = match request {
+            true => &mut self.requests,
+            false => &mut self.responses,
+        };
+        let Some(inner) = reader else {
+            return false;
+        };
+        if let Err(e) = inner.feed(data, &mut self.events) {
+            debug!(peer = %self.peer_addr, err = %e, "no longer counting gRPC calls");
+            *reader = None;
+            self.events.clear();
+            return false;
+        }
+        true
+    }
+
+    /// The call on `stream` ended, with `error` unless it succeeded.
+    fn end(&mut self, stream: u32, error: Option<&str>) {
+        let Some(method) = self.open.remove(&stream) else {
+            return;
+        };
+        match error {
+            Some(error) => {
+                self.inspector.count_rpc(&self.subdomain, &method, true);
+                info!(subdomain = %self.subdomain, peer = %self.peer_addr, error, "{method}");
+            }
+            None => info!(subdomain = %self.subdomain, peer = %self.peer_addr, "{method}"),
+        }
+    }
+}
+
+/// How the body after a head is delimited.
+pub(crate) enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+    UntilClose,
+    /// No longer HTTP.
+    Opaque,
+}
+
+impl Framing {
+    /// The framing a head's headers announce.
+    pub(crate) fn of(headers: &[(String, String)]) -> Self {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .rev()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        if let Some(coding) = header("transfer-encoding") {
+            let last = coding.rsplit(',').next().unwrap_or_default();
+            if last.trim().eq_ignore_ascii_case("chunked") {
+                return Self::Chunked;
+            }
+            return Self::UntilClose;
+        }
+        match header("content-length").map(|v| v.trim().parse()) {
+            Some(Ok(0)) => Self::Empty,
+            Some(Ok(n)) => Self::Length(n),
+            Some(Err(_)) => Self::Opaque,
+            None => Self::UntilClose,
+        }
+    }
+}
+
+/// A parsed message head.
+pub(crate) struct Head {
+    /// Method and path for requests.
+    pub(crate) request: Option<(String, String)>,
+    status: Option<u16>,
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+pub(crate) trait Handler {
+    fn is_request(&self) -> bool;
+    /// A complete head; `raw` is how it was sent.
+    fn head(&mut self, head: Head, raw: &[u8]) -> Framing;
+    fn body(&mut self, data: &[u8]);
+    fn end(&mut self);
+    /// Every byte that isn't part of a head, as sent, including chunk
+    /// framing and whatever doesn't parse.
+    fn raw(&mut self, _data: &[u8]) {}
+}
+
+struct RequestSide<'a>(&'a mut Pairing);
+
+impl Handler for RequestSide<'_> {
+    fn is_request(&self) -> bool {
+        true
+    }
+
+    fn head(&mut self, head: Head, _raw: &[u8]) -> Framing {
+        let (method, path) = head.request.unwrap_or_default();
+        // A request without a length has no body.
+        let framing = match Framing::of(&head.headers) {
+            Framing::UntilClose => Framing::Empty,
+            framing => framing,
+        };
+        let pairing = &mut *self.0;
+        let exchange = Exchange {
+            id: pairing.inspector.next_id.fetch_add(1, Ordering::Relaxed),
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            subdomain: pairing.subdomain.clone(),
+            peer_addr: pairing.peer_addr,
+            local_host: pairing.local_host.clone(),
+            local_port: pairing.local_port,
+            local_socket: pairing.local_socket.clone(),
+            replay_of: pairing.replay_of,
+            method,
+            path,
+            request_headers: head.headers,
+            request_body: Vec::new(),
+            request_size: 0,
+            status: None,
+            response_headers: Vec::new(),
+            response_size: 0,
+            duration_ms: 0,
+            started: Instant::now(),
+        };
+        pairing.filling = Some(exchange.id);
+        pairing.pending.push_back(exchange);
+        framing
+    }
+
+    fn body(&mut self, data: &[u8]) {
+        let pairing = &mut *self.0;
+        let filling = pairing.filling;
+        let exchange = pairing
+            .pending
+            .iter_mut()
+            .chain(pairing.responding.as_mut())
+            .find(|e| Some(e.id) == filling);
+        if let Some(exchange) = exchange {
+            exchange.request_size += data.len() as u64;
+            let room = MAX_BODY.saturating_sub(exchange.request_body.len());
+            exchange
+                .request_body
+                .extend_from_slice(&data[..room.min(data.len())]);
+        }
+    }
+
+    fn end(&mut self) {
+        self.0.filling = None;
+    }
+}
+
+struct ResponseSide<'a>(&'a mut Pairing);
+
+impl Handler for ResponseSide<'_> {
+    fn is_request(&self) -> bool {
+        false
+    }
+
+    fn head(&mut self, head: Head, _raw: &[u8]) -> Framing {
+        let status = head.status.unwrap_or_default();
+        let pairing = &mut *self.0;
+        if status == 101 {
+            pairing.upgraded = true;
+        }
+        // `100 Continue` and friends come before the real response.
+        if (100..200).contains(&status) && status != 101 {
+            return Framing::Empty;
+        }
+        let Some(mut exchange) = pairing.pending.pop_front() else {
+            return Framing::Opaque;
+        };
+        let method = exchange.method.clone();
+        exchange.status = Some(status);
+        let framing = match Framing::of(&head.headers) {
+            _ if status == 101 => Framing::Empty,
+            _ if method == "CONNECT" && (200..300).contains(&status) => {
+                pairing.upgraded = true;
+                Framing::Empty
+            }
+            _ if method == "HEAD" || status == 204 || status == 304 => Framing::Empty,
+            framing => framing,
+        };
+        exchange.response_headers = head.headers;
+        pairing.responding = Some(exchange);
+        framing
+    }
+
+    fn body(&mut self, data: &[u8]) {
+        if let Some(exchange) = &mut self.0.responding {
+            exchange.response_size += data.len() as u64;
+        }
+    }
+
+    fn end(&mut self) {
+        let pairing = &mut *self.0;
+        if let Some(exchange) = pairing.responding.take() {
+            pairing.inspector.record(exchange);
+        }
+    }
+}
+
+/// Where a parser is within a stream of messages.
+enum State {
+    Head(Vec<u8>),
+    Body(u64),
+    /// Within a chunked body: reading a size line.
+    ChunkSize(Vec<u8>),
+    ChunkData(u64),
+    /// The CRLF after a chunk's data; bytes left of it.
+    ChunkEnd(u8),
+    /// Reading trailer lines after the last chunk.
+    Trailer(Vec<u8>),
+    UntilClose,
+    Opaque,
+}
+
+pub(crate) struct Parser {
+    state: State,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self {
+            state: State::Head(Vec::new()),
+        }
+    }
+}
+
+impl Parser {
+    pub(crate) fn feed(&mut self, mut data: &[u8], handler: &mut impl Handler) {
+        while !data.is_empty() {
+            match &mut self.state {
+                State::Head(buf) => {
+                    let old = buf.len();
+                    buf.extend_from_slice(data);
+                    let parsed = parse_head(buf, handler.is_request());
+                    let len = match parsed {
+                        Ok(Some((len, head))) => {
+                            let framing = handler.head(head, &buf[..len]);
+                            self.start_body(framing, handler);
+                            len
+                        }
+                        Ok(None) if buf.len() <= MAX_HEAD => return,
+                        _ => {
+                            debug!("not HTTP, no longer parsing the connection");
+                            handler.raw(buf);
+                            self.state = State::Opaque;
+                            return;
+                        }
+                    };
+                    data = &data[len - old..];
+                }
+                State::Body(left) => {
+                    let n = (*left).min(data.len() as u64) as usize;
+                    handler.body(&data[..n]);
+                    handler.raw(&data[..n]);
+                    *left -= n as u64;
+                    data = &data[n..];
+                    if *left == 0 {
+                        self.end(handler);
+                    }
+                }
+                State::ChunkSize(line) => {
+                    let rest = data;
+                    let taken = take_line(line, &mut data);
+                    handler.raw(&rest[..rest.len() - data.len()]);
+                    let Some(n) = taken else {
+                        continue;
+                    };
+                    let line = String::from_utf8_lossy(&line[..n]);
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    self.state = match u64::from_str_radix(size, 16) {
+                        Ok(0) => State::Trailer(Vec::new()),
+                        Ok(size) => State::ChunkData(size),
+                        Err(_) => State::Opaque,
+                    };
+                }
+                State::ChunkData(left) => {
+                    let n = (*left).min(data.len() as u64) as usize;
+                    handler.body(&data[..n]);
+                    handler.raw(&data[..n]);
+                    *left -= n as u64;
+                    data = &data[n..];
+                    if *left == 0 {
+                        self.state = State::ChunkEnd(2);
+                    }
+                }
+                State::ChunkEnd(left) => {
+                    let n = (*left as usize).min(data.len());
+                    handler.raw(&data[..n]);
+                    *left -= n as u8;
+                    data = &data[n..];
+                    if *left == 0 {
+                        self.state = State::ChunkSize(Vec::new());
+                    }
+                }
+                State::Trailer(line) => {
+                    let rest = data;
+                    let taken = take_line(line, &mut data);
+                    handler.raw(&rest[..rest.len() - data.len()]);
+                    let Some(n) = taken else {
+                        continue;
+                    };
+                    if line[..n].iter().all(|b| b.is_ascii_whitespace()) {
+                        self.end(handler);
+                    } else {
+                        line.clear();
+                    }
+                }
+                State::UntilClose => {
+                    handler.body(data);
+                    handler.raw(data);
+                    return;
+                }
+                State::Opaque => return handler.raw(data),
+            }
+        }
+    }
+
+    /// Hand over the bytes of a head cut short by the end of the stream.
+    pub(crate) fn finish(&mut self, handler: &mut impl Handler) {
+        if let State::Head(buf) = &mut self.state {
+            handler.raw(buf);
+            buf.clear();
+        }
+    }
+
+    fn start_body(&mut self, framing: Framing, handler: &mut impl Handler) {
+        self.state = match framing {
+            Framing::Empty => return self.end(handler),
+            Framing::Length(n) => State::Body(n),
+            Framing::Chunked => State::ChunkSize(Vec::new()),
+            Framing::UntilClose => State::UntilClose,
+            Framing::Opaque => State::Opaque,
+        };
+    }
+
+    fn end(&mut self, handler: &mut impl Handler) {
+        handler.end();
+        self.state = State::Head(Vec::new());
+    }
+}
+
+/// Move bytes up to and including a newline from `data` to `line`. Returns
+/// the line's length without the newline once it is complete; overlong
+/// lines never complete, which leaves the parser waiting harmlessly.
+fn take_line(line: &mut Vec<u8>, data: &mut &[u8]) -> Option<usize> {
+    let (chunk, complete) = match data.iter().position(|&b| b == b'\n') {
+        Some(i) => (&data[..=i], true),
+        None => (*data, false),
+    };
+    if line.len() < MAX_HEAD {
+        line.extend_from_slice(chunk);
+    }
+    *data = &data[chunk.len()..];
+    complete.then(|| line.len() - 1)
+}
+
+/// Parse a complete head at the start of `buf`: its length and contents,
+/// `None` if more bytes are needed.
+fn parse_head(buf: &[u8], request: bool) -> Result<Option<(usize, Head)>, httparse::Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let owned = |headers: &[httparse::Header]| {
+        headers
+            .iter()
+            .map(|h| {
+                let value = String::from_utf8_lossy(h.value).into_owned();
+                (h.name.to_owned(), value)
+            })
+            .collect()
+    };
+    if request {
+        let mut req = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(len) = req.parse(buf)? else {
+            return Ok(None);
+        };
+        let method = req.method.unwrap_or_default().to_owned();
+        let path = req.path.unwrap_or_default().to_owned();
+        let head = Head {
+            request: Some((method, path)),
+            status: None,
+            headers: owned(req.headers),
+        };
+        Ok(Some((len, head)))
+    } else {
+        let mut res = httparse::Response::new(&mut headers);
+        let httparse::Status::Complete(len) = res.parse(buf)? else {
+            return Ok(None);
+        };
+        let head = Head {
+            request: None,
+            status: res.code,
+            headers: owned(res.headers),
+        };
+        Ok(Some((len, head)))
+    }
+}
+
+impl Default for Inspector {
+    fn default() -> Self {
+        Self::new(100)
+    }
+}
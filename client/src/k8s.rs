@@ -0,0 +1,238 @@
+//! `sshx k8s`: keep a tunnel up for every Kubernetes Service annotated with
+//! `sshx.dev/subdomain`, and take it down when the annotation or the Service
+//! goes, so a dev cluster exposes selected services through the sshx server
+//! without a command per service. Built with `--features k8s`.
+//!
+//! Annotations on a Service:
+//!
+//! - `sshx.dev/subdomain`: the subdomain; Services without it are left alone.
+//! - `sshx.dev/port`: the Service port to tunnel, by number or name
+//!   [default: the first].
+//! - `sshx.dev/proto`: `http`, `grpc`, `tcp` or `udp` [default: `udp` for a
+//!   UDP port, `http` otherwise].
+//!
+//! Visitors go to the Service's cluster DNS name, so the client has to run
+//! where that resolves, normally as a Deployment in the cluster.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
+use k8s_openapi::api::core::v1::{Service, ServicePort};
+use kube::{
+    runtime::{
+        watcher::{self, watcher, Event},
+        WatchStreamExt,
+    },
+    Api, Client, ResourceExt,
+};
+use sshx_client::{status::Stats, Proto};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::Cli;
+
+const SUBDOMAIN: &str = "sshx.dev/subdomain";
+const PORT: &str = "sshx.dev/port";
+const PROTO: &str = "sshx.dev/proto";
+
+/// The tunnel an annotated Service asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Spec {
+    subdomain: String,
+    host: String,
+    port: u16,
+    proto: Proto,
+    grpc: bool,
+}
+
+/// A tunnel kept up for a Service, by `namespace/name`.
+struct Exposed {
+    spec: Spec,
+    stop: CancellationToken,
+}
+
+pub async fn run(cli: &Cli, namespace: Option<&str>) -> Result<()> {
+    let client = Client::try_default()
+        .await
+        .context("cannot reach the Kubernetes API; is there a kubeconfig or a service account?")?;
+    let services: Api<Service> = match namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::all(client),
+    };
+    let stats = Arc::new(Stats::new());
+    let mut exposed: HashMap<String, Exposed> = HashMap::new();
+    // Services listed since the watch (re)started, to drop the tunnels of
+    // those that went away meanwhile.
+    let mut listed = Vec::new();
+    let mut events = watcher(services, watcher::Config::default())
+        .default_backoff()
+        .boxed();
+    info!(
+        namespace = namespace.unwrap_or("*"),
+        "watching Services for {SUBDOMAIN}"
+    );
+    loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = crate::shutdown_signal() => break,
+        };
+        let event = match event {
+            Some(Ok(event)) => event,
+            Some(Err(e)) => {
+                warn!(err = %e, "watching Services failed, retrying");
+                continue;
+            }
+            None => bail!("the Kubernetes API ended the watch"),
+        };
+        match event {
+            Event::Init => listed.clear(),
+            Event::InitApply(service) => {
+                listed.push(key(&service));
+                reconcile(cli, &stats, &mut exposed, &service);
+            }
+            Event::InitDone => {
+                exposed.retain(|key, tunnel| {
+                    let keep = listed.contains(key);
+                    if !keep {
+                        let subdomain = &tunnel.spec.subdomain;
+                        info!(service = %key, %subdomain, "service is gone, closing its tunnel");
+                        tunnel.stop.cancel();
+                    }
+                    keep
+                });
+            }
+            Event::Apply(service) => reconcile(cli, &stats, &mut exposed, &service),
+            Event::Delete(service) => {
+                let key = key(&service);
+                if let Some(tunnel) = exposed.remove(&key) {
+                    let subdomain = &tunnel.spec.subdomain;
+                    info!(service = %key, %subdomain, "service deleted, closing its tunnel");
+                    tunnel.stop.cancel();
+                }
+            }
+        }
+    }
+    info!("shutting down");
+    for tunnel in exposed.into_values() {
+        tunnel.stop.cancel();
+    }
+    Ok(())
+}
+
+fn key(service: &Service) -> String {
+    format!(
+        "{}/{}",
+        service.namespace().unwrap_or_default(),
+        service.name_any()
+    )
+}
+
+/// Bring the tunnel of `service` in line with its annotations.
+fn reconcile(
+    cli: &Cli,
+    stats: &Arc<Stats>,
+    exposed: &mut HashMap<String, Exposed>,
+    service: &Service,
+) {
+    let key = key(service);
+    let spec = match spec(service) {
+        Ok(spec) => spec,
+        Err(e) => {
+            warn!(service = %key, "{e:#}");
+            None
+        }
+    };
+    if exposed.get(&key).map(|tunnel| &tunnel.spec) == spec.as_ref() {
+        return;
+    }
+    if let Some(tunnel) = exposed.remove(&key) {
+        let subdomain = &tunnel.spec.subdomain;
+        info!(service = %key, %subdomain, "annotations changed, closing the tunnel");
+        tunnel.stop.cancel();
+    }
+    if let Some(spec) = spec {
+        let stop = expose(cli, stats, &key, &spec);
+        exposed.insert(key, Exposed { spec, stop });
+    }
+}
+
+/// What the annotations of `service` ask for, if anything.
+fn spec(service: &Service) -> Result<Option<Spec>> {
+    let annotations = service.annotations();
+    let Some(subdomain) = annotations.get(SUBDOMAIN) else {
+        return Ok(None);
+    };
+    let ports: &[ServicePort] = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.ports.as_deref())
+        .unwrap_or_default();
+    let port = match annotations.get(PORT) {
+        Some(wanted) => ports
+            .iter()
+            .find(|p| p.name.as_ref() == Some(wanted) || p.port.to_string() == *wanted)
+            .with_context(|| format!("{PORT} names no port of the service: '{wanted}'"))?,
+        None => ports.first().context("the service has no ports")?,
+    };
+    let udp = port.protocol.as_deref() == Some("UDP");
+    let (proto, grpc) = match annotations.get(PROTO).map(String::as_str) {
+        None if udp => (Proto::Udp, false),
+        None | Some("http") => (Proto::Http, false),
+        Some("grpc") => (Proto::Http, true),
+        Some("tcp") => (Proto::Tcp, false),
+        Some("udp") => (Proto::Udp, false),
+        Some(other) => bail!("{PROTO} is '{other}', expected http, grpc, tcp or udp"),
+    };
+    let namespace = service.namespace().unwrap_or_else(|| "default".into());
+    Ok(Some(Spec {
+        subdomain: subdomain.clone(),
+        host: format!("{}.{namespace}.svc", service.name_any()),
+        port: u16::try_from(port.port).context("the service port is out of range")?,
+        proto,
+        grpc,
+    }))
+}
+
+/// Keep a tunnel up for `spec` until the returned token is cancelled.
+fn expose(cli: &Cli, stats: &Arc<Stats>, key: &str, spec: &Spec) -> CancellationToken {
+    let builder = crate::builder(cli, Arc::clone(stats))
+        .subdomain(&spec.subdomain)
+        .local_host(&spec.host)
+        .local_port(spec.port)
+        .proto(spec.proto)
+        .grpc(spec.grpc);
+    let stop = CancellationToken::new();
+    let (stopped, key, subdomain) = (stop.clone(), key.to_owned(), spec.subdomain.clone());
+    tokio::spawn(async move {
+        let tunnel = tokio::select! {
+            tunnel = builder.connect() => tunnel,
+            _ = stopped.cancelled() => return,
+        };
+        let tunnel = match tunnel {
+            Ok(tunnel) => tunnel,
+            Err(e) => {
+                let err = format!("{e:#}");
+                warn!(service = %key, %subdomain, err, "cannot open the tunnel");
+                return;
+            }
+        };
+        let (public_port, url) = (tunnel.public_port(), tunnel.url().map(str::to_owned));
+        info!(service = %key, %subdomain, public_port, url, "tunnel up");
+        let handle = tunnel.shutdown_handle();
+        let wait = tunnel.wait();
+        tokio::pin!(wait);
+        let result = tokio::select! {
+            result = &mut wait => result,
+            _ = stopped.cancelled() => {
+                handle.shutdown();
+                wait.await
+            }
+        };
+        if let Err(e) = result {
+            let err = format!("{e:#}");
+            warn!(service = %key, %subdomain, err, "tunnel closed");
+        }
+    });
+    stop
+}
@@ -0,0 +1,53 @@
+//! sshx client library — open tunnels from your own program.
+//!
+//! ```no_run
+//! use sshx_client::{Event, Tunnel};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let mut tunnel = Tunnel::builder()
+//!     .server("tunnel.example.com")
+//!     .subdomain("myapp")
+//!     .local_port(3000)
+//!     .connect()
+//!     .await?;
+//! println!("public port: {}", tunnel.public_port());
+//!
+//! while let Some(event) = tunnel.next_event().await {
+//!     if let Event::Connection { peer_addr, .. } = event {
+//!         println!("visitor from {peer_addr}");
+//!     }
+//! }
+//! tunnel.wait().await
+//! # }
+//! ```
+
+pub mod approve;
+pub mod balance;
+pub mod chaos;
+pub mod hooks;
+mod http_proxy;
+pub mod inspect;
+pub mod mdns;
+mod proxy;
+mod pull;
+mod quic;
+pub mod record;
+mod resolve;
+mod rewrite;
+pub mod share;
+pub mod socks;
+pub mod status;
+mod tls;
+mod tunnel;
+
+pub use http_proxy::HttpProxy;
+pub use proxy::ProxyProtocol;
+pub use resolve::Resolver;
+pub use sshx_core::{
+    e2e,
+    protocol::{ErrorCode, IpNet, Proto},
+};
+pub use tunnel::{
+    Event, Forward, Registration, ShutdownHandle, Transport, Tunnel, TunnelBuilder, TunnelTraffic,
+    DEFAULT_SERVER,
+};
@@ -4,179 +4,1808 @@
 //!   sshx -s myapp -p 3000              # HTTP tunnel
 //!   sshx -s myssh -p 22 --tcp          # TCP/SSH tunnel
 //!   sshx -s myssh -p 22 --tcp --secret mypassword
+//!   sshx -s alice-web -p 3000 --token c2a7…   # per-user token
+//!   sshx -s game -p 27015 --udp        # UDP tunnel (game servers, DNS, ...)
+//!   sshx -s api -p 50051 --grpc        # gRPC tunnel: HTTP/2 only
+//!   sshx -s docker --tcp --unix-socket /var/run/docker.sock   # a Unix socket
+//!   sshx -s date --tcp --exec date     # run a command per visitor (inetd-style)
+//!   sshx --forward web:3000 --forward db:5432:tcp   # several tunnels
+//!   sshx up dev                        # tunnels of a config file profile
+//!   sshx -s myapp -p 3000 --ui         # live dashboard instead of logs
+//!   sshx -s myapp -p 3000 --inspect    # log requests, browse them on :4040
+//!   sshx -s myapp -p 3000 --record app.har     # save requests for later
+//!   sshx -s myapp -p 3000 --chaos latency=200ms,drop=1%   # a far-away visitor
+//!   sshx -s db -p 5432 --tcp --record-pcap db.pcap   # save connections
+//!   sshx -s myapp -p 3000 --http-auth admin:s3cret   # ask visitors to log in
+//!   sshx -s myapp -p 3000 --host-rewrite myapp.test --add-header 'X-Env: dev'
+//!   sshx replay 12                     # send captured request #12 again
+//!   sshx socks -s dev --allow-dest 10.0.0.0/8   # SOCKS5 proxy into the LAN
+//!   sshx pull --remote db:5432 --local 5432     # a port the server reaches, here
+//!   sshx stdio -s myssh                # one visitor on stdin/stdout, then exit
+//!   sshx echo -s ping                  # visitors get their bytes back
+//!   sshx ssh alice@myssh               # ssh to the server behind a TCP tunnel
+//!   sshx ssh-config alice@myssh >> ~/.ssh/config
+//!   sshx -s myssh -p 22 --tcp --e2e-key e2e.key   # server sees ciphertext only
+//!   sshx service install dev           # keep profile dev up, also after reboots
+//!   sshx status                        # tunnels of the running sshx
+//!   sshx add-tunnel api:8080           # one more, without a restart
+//!   sshx doctor                        # what's wrong with DNS or the server
+//!   sshx bench                         # how fast the way through the server is
 
-mod auth;
-mod shared;
+mod bench;
+mod config;
+mod control;
+mod docker;
+mod doctor;
+#[cfg(feature = "k8s")]
+mod k8s;
+mod ready;
+mod service;
+mod ssh;
+mod ui;
 
-use std::sync::Arc;
+use std::{
+    ffi::OsString,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::pin,
+    process::ExitCode,
+    sync::Arc,
+    time::SystemTime,
+};
 
-use anyhow::{bail, Result};
-use auth::Auth;
-use clap::Parser;
-use shared::{ClientMsg, Framed_, Proto, ServerMsg, CONTROL_PORT};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use config::Config;
+use serde_json::{json, Map, Value};
+use sshx_client::{
+    approve::Approver,
+    balance::Balance,
+    chaos::Chaos,
+    e2e::{self, Keypair, PublicKey},
+    hooks::Hook,
+    inspect::Inspector,
+    mdns,
+    record::{Format, Recorder, Rotation, DEFAULT_MAX_SIZE},
+    share::Share,
+    socks::AllowRule,
+    status::{format_bytes, Failure, Stats},
+    Event, Forward, HttpProxy, IpNet, Proto, ProxyProtocol, Resolver, Transport, Tunnel,
+    TunnelBuilder, DEFAULT_SERVER,
+};
 use tokio::{
-    io::AsyncWriteExt,
-    net::TcpStream,
-    time::{sleep, Duration},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration},
 };
-use tracing::{error, info, warn};
-use uuid::Uuid;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+use ui::Dashboard;
+
+/// Where `--inspect` serves captured requests unless told otherwise.
+const INSPECT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4040);
 
 // ── CLI ───────────────────────────────────────────────────────────────────────
 
 #[derive(Parser, Clone)]
-#[command(name = "sshx", about = "Expose a local port through sshx tunnel")]
+#[command(
+    name = "sshx",
+    about = "Expose a local port through sshx tunnel",
+    subcommand_negates_reqs = true,
+    group(ArgGroup::new("local").args(["port", "unix_socket", "exec"]))
+)]
 struct Cli {
-    /// Subdomain to register (e.g. "myapp" → myapp.yourdomain.com).
-    #[arg(short, long)]
-    subdomain: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Subdomain to register (e.g. "myapp" → myapp.yourdomain.com)
+    /// [default: a random one the server picks, like brave-otter-42].
+    #[arg(short, long, env = "SSHX_SUBDOMAIN", requires = "local")]
+    subdomain: Option<String>,
+
+    /// Local port to expose. Several, e.g. 3000,3001,3002, spread visitors
+    /// across as many processes of the service.
+    #[arg(
+        short,
+        long,
+        env = "SSHX_PORT",
+        value_delimiter = ',',
+        required_unless_present_any = ["forwards", "unix_socket", "exec"]
+    )]
+    port: Vec<u16>,
+
+    /// How visitors are spread across the local ports of a tunnel that has
+    /// several: round-robin, or least-conns for the one with the fewest
+    /// open connections [default: round-robin].
+    #[arg(long, env = "SSHX_BALANCE", global = true)]
+    balance: Option<Balance>,
+
+    /// Unix socket to expose instead of --port, e.g. /var/run/docker.sock.
+    /// Not for UDP, and not with --forward.
+    #[arg(long, conflicts_with_all = ["udp", "forwards"])]
+    unix_socket: Option<PathBuf>,
+
+    /// Run this shell command for each visitor instead of exposing a port,
+    /// inetd-style: the visitor talks to its stdin and stdout. Not for UDP,
+    /// and not with --forward.
+    #[arg(long, conflicts_with_all = ["udp", "forwards"])]
+    exec: Option<String>,
+
+    /// Another tunnel on the same connection, as
+    /// subdomain:localport[:http|tcp|udp][:publicport].
+    /// Repeatable.
+    #[arg(
+        short = 'f',
+        long = "forward",
+        value_name = "SUBDOMAIN:PORT[:PROTO]",
+        global = true
+    )]
+    forwards: Vec<Forward>,
+
+    /// Config file [default: ~/.config/sshx/config.toml].
+    #[arg(long, env = "SSHX_CONFIG", global = true)]
+    config: Option<PathBuf>,
 
-    /// Local port to expose.
-    #[arg(short, long)]
-    port: u16,
+    /// Local host to forward traffic to [default: localhost].
+    #[arg(long, env = "SSHX_HOST", global = true)]
+    host: Option<String>,
 
-    /// Local host to forward traffic to.
-    #[arg(long, default_value = "localhost")]
-    host: String,
+    /// sshx server address, or `auto` for the first one announcing itself
+    /// on the LAN (`sshx-server --mdns`) [default: teamxpirates.qzz.io].
+    /// Several, comma-separated or repeated, are standbys tried in order
+    /// when the one in use stops answering.
+    #[arg(
+        long,
+        short = 'r',
+        env = "SSHX_SERVER",
+        global = true,
+        value_delimiter = ','
+    )]
+    server: Vec<String>,
 
-    /// sshx server address.
-    #[arg(long, short = 'r', env = "SSHX_SERVER", default_value = "teamxpirates.qzz.io")]
-    server: String,
+    /// Control port of the sshx server [default: 12267, or 12268 with --tls
+    /// or --transport quic; 80 or 443 with --transport ws].
+    #[arg(long, env = "SSHX_CONTROL_PORT", global = true)]
+    control_port: Option<u16>,
 
-    /// Use raw TCP mode (for SSH, databases, etc.). Default is HTTP.
-    #[arg(long)]
+    /// Connect to the server's TLS control port.
+    #[arg(long, env = "SSHX_TLS", global = true)]
+    tls: bool,
+
+    /// PEM certificate(s) to trust instead of the public CAs, e.g. the
+    /// server's self-signed certificate. Implies --tls.
+    #[arg(long, env = "SSHX_TLS_CA", global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// How to reach the server: tcp; ws for a WebSocket to its HTTP(S) port
+    /// when only web traffic gets out; or quic, over UDP to a server run with
+    /// --quic, falling back to TLS over TCP [default: tcp].
+    #[arg(long, env = "SSHX_TRANSPORT", global = true)]
+    transport: Option<Transport>,
+
+    /// HTTP proxy to reach the server through, as
+    /// http://[user:pass@]host:port [default: $HTTPS_PROXY unless the server
+    /// is in $NO_PROXY].
+    #[arg(long, global = true)]
+    proxy: Option<HttpProxy>,
+
+    /// Who resolves the server's name: system, or a DNS-over-HTTPS URL such
+    /// as https://1.1.1.1/dns-query [default: system].
+    #[arg(long, env = "SSHX_RESOLVER", global = true)]
+    resolver: Option<Resolver>,
+
+    /// Use raw TCP mode for --subdomain (for SSH, databases, etc.). Default is HTTP.
+    #[arg(long, env = "SSHX_TCP")]
     tcp: bool,
 
+    /// Forward UDP datagrams for --subdomain instead.
+    #[arg(long, env = "SSHX_UDP", conflicts_with = "tcp")]
+    udp: bool,
+
+    /// Make --subdomain an HTTP tunnel for gRPC: visitors must speak HTTP/2,
+    /// and --inspect counts calls per method.
+    #[arg(long, conflicts_with_all = ["tcp", "udp"])]
+    grpc: bool,
+
+    /// Public port to ask the server for, e.g. a stable port for SSH.
+    /// Default is a random port.
+    #[arg(long, env = "SSHX_PUBLIC_PORT", requires = "local")]
+    public_port: Option<u16>,
+
     /// Optional shared secret (must match server's --secret).
-    #[arg(long, env = "SSHX_SECRET", hide_env_values = true)]
+    #[arg(long, env = "SSHX_SECRET", hide_env_values = true, global = true)]
     secret: Option<String>,
 
-    /// Automatically reconnect on disconnect.
-    #[arg(long, default_value_t = true)]
-    reconnect: bool,
+    /// Personal token from the server's tokens file, instead of --secret.
+    #[arg(
+        long,
+        env = "SSHX_TOKEN",
+        hide_env_values = true,
+        global = true,
+        conflicts_with = "secret"
+    )]
+    token: Option<String>,
+
+    /// Local IP address that connections to the server originate from.
+    #[arg(long, env = "SSHX_BIND_ADDRESS", global = true)]
+    bind_address: Option<IpAddr>,
+
+    /// Network interface that connections to the server go out on (Linux only).
+    #[arg(long, env = "SSHX_BIND_INTERFACE", global = true)]
+    bind_interface: Option<String>,
+
+    /// HTML page for HTTP visitors while the local service is down, instead
+    /// of the built-in one. {{subdomain}}, {{local}} and {{error}} are
+    /// filled in.
+    #[arg(long, env = "SSHX_ERROR_PAGE", global = true)]
+    error_page: Option<PathBuf>,
+
+    /// Let only HTTP visitors that log in with these credentials through,
+    /// as user:password (basic auth).
+    #[arg(long, env = "SSHX_HTTP_AUTH", hide_env_values = true, global = true)]
+    http_auth: Option<String>,
+
+    /// Set a header on every request of HTTP tunnels, replacing any the
+    /// visitor sent, as "Name: value" (repeatable).
+    #[arg(long, value_name = "NAME: VALUE", global = true)]
+    add_header: Vec<String>,
+
+    /// Drop a header from every request of HTTP tunnels (repeatable).
+    #[arg(long, value_name = "NAME", global = true)]
+    remove_header: Vec<String>,
+
+    /// Host header to send HTTP requests to the local service with, e.g.
+    /// the name of a local virtual host.
+    #[arg(long, value_name = "HOST", global = true)]
+    host_rewrite: Option<String>,
+
+    /// Don't check that something is listening on --host:--port before registering.
+    #[arg(long, global = true)]
+    skip_local_check: bool,
+
+    /// When a visitor arrives while the local service is down, e.g.
+    /// restarting, keep trying to reach it for this long before giving up,
+    /// e.g. "5s" [default: 0, give up at once].
+    #[arg(long, env = "SSHX_LOCAL_RETRY", value_parser = humantime::parse_duration, global = true)]
+    local_retry: Option<Duration>,
+
+    /// Make visitors' traffic go as over a worse network, for testing, e.g.
+    /// "latency=200ms,jitter=50ms,drop=1%". Each direction is held back by
+    /// the latency give or take the jitter; drop is the chance of cutting a
+    /// connection at each chunk, or of losing a datagram.
+    #[arg(long, global = true)]
+    chaos: Option<Chaos>,
+
+    /// Bytes copied at a time between a visitor and the local service, in
+    /// each direction, e.g. 256Ki (default 8Ki). Bigger speeds up large
+    /// transfers, at that much memory twice per connection.
+    #[arg(long, env = "SSHX_BUFFER_SIZE", value_parser = parse_buffer_size, global = true)]
+    buffer_size: Option<usize>,
+
+    /// Carry each TCP or HTTP visitor over a resumable stream, so a data
+    /// connection that drops is reopened without the visitor noticing.
+    /// Needs a server that supports it.
+    #[arg(long, env = "SSHX_RESUMABLE", global = true)]
+    resumable: bool,
+
+    /// Keep this many data connections open at the server (up to 64), so
+    /// each visitor is bridged at once over a TCP connection of its own
+    /// instead of sharing the multiplexed one. Needs a server that supports
+    /// it; ignored with --resumable.
+    #[arg(long, env = "SSHX_WARM_POOL", global = true)]
+    warm_pool: Option<usize>,
+
+    /// Close the tunnels once they have been up this long, e.g. 30m.
+    #[arg(long, env = "SSHX_TTL", value_parser = humantime::parse_duration, global = true)]
+    ttl: Option<Duration>,
+
+    /// Close the tunnels once this many visitors came and went, e.g. 1 to
+    /// share something once. Later visitors are turned away.
+    #[arg(long, env = "SSHX_MAX_USES", value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    max_uses: Option<u64>,
+
+    /// Share the subdomains with other clients started with --pool under
+    /// the same secret or token, e.g. on other machines: the server spreads
+    /// visitors across them, and drops each when its connection does.
+    #[arg(long, env = "SSHX_POOL", global = true)]
+    pool: bool,
+
+    /// Let a later client started with the same key (and secret or token)
+    /// take the tunnels over, and take over those of an earlier one: new
+    /// visitors go to the new client, and the old one stops once its
+    /// connections in progress finish. For redeploys without downtime.
+    #[arg(long, env = "SSHX_TAKEOVER_KEY", global = true)]
+    takeover_key: Option<String>,
+
+    /// Ask on the terminal (or in the --ui dashboard) before letting each
+    /// inbound connection through.
+    #[arg(long, global = true)]
+    approve: bool,
+
+    /// Reject a connection nobody approved in this long [default: 30s].
+    #[arg(long, value_parser = humantime::parse_duration, global = true, requires = "approve")]
+    approve_timeout: Option<Duration>,
+
+    /// Once registered, connect to each TCP and HTTP tunnel's public port
+    /// like a visitor until one gets through to this client, e.g. in CI
+    /// before the tests start; give up and exit after this long. Visitors
+    /// arriving before then are turned away.
+    #[arg(long, env = "SSHX_READY_TIMEOUT", value_parser = humantime::parse_duration, global = true)]
+    ready_timeout: Option<Duration>,
+
+    /// Write each tunnel's URL (or host:port) to this file once it is
+    /// ready, one per line. Under GitHub Actions the first also becomes
+    /// the step output `url`.
+    #[arg(long, env = "SSHX_PRINT_URL_FILE", global = true)]
+    print_url_file: Option<PathBuf>,
+
+    /// Show a live dashboard of tunnels, connections and traffic instead of
+    /// log output.
+    #[arg(long, global = true)]
+    ui: bool,
+
+    /// Log every HTTP request with its status, duration and size, and keep
+    /// the recent ones for browsing on --inspect-addr.
+    #[arg(long, global = true)]
+    inspect: bool,
+
+    /// Where to serve the captured requests [default: 127.0.0.1:4040].
+    /// Implies --inspect.
+    #[arg(long, global = true)]
+    inspect_addr: Option<SocketAddr>,
+
+    /// Save every exchange of HTTP tunnels to this HAR file. The traffic is
+    /// stored in plaintext, credentials and cookies included.
+    #[arg(long, value_name = "FILE", global = true)]
+    record: Option<PathBuf>,
+
+    /// Save every connection of TCP tunnels to this PCAP file, for Wireshark
+    /// and the like. The traffic is stored in plaintext.
+    #[arg(long, value_name = "FILE", global = true)]
+    record_pcap: Option<PathBuf>,
+
+    /// Start a new recording once a file grows past this size, keeping the
+    /// previous one as NAME.1.EXT, e.g. 10M or 1Gi [default: 100Mi].
+    #[arg(long, value_parser = parse_size, global = true)]
+    record_max_size: Option<u64>,
+
+    /// Start a new recording once a file is this old, e.g. 1h.
+    #[arg(long, value_parser = humantime::parse_duration, global = true)]
+    record_max_age: Option<Duration>,
+
+    /// Only let visitors from this network in, e.g. 10.0.0.0/8 (repeatable).
+    #[arg(long, value_delimiter = ',', global = true)]
+    allow_cidr: Vec<IpNet>,
+
+    /// Turn away visitors from this network unless allowed (repeatable).
+    #[arg(long, value_delimiter = ',', global = true)]
+    deny_cidr: Vec<IpNet>,
+
+    /// Run a shell command, or POST JSON to an http(s):// URL, whenever the
+    /// server accepts a tunnel (repeatable). Commands get the details in
+    /// SSHX_SUBDOMAIN, SSHX_PUBLIC and SSHX_URL.
+    #[arg(long, value_name = "COMMAND|URL", global = true)]
+    on_connect: Vec<Hook>,
+
+    /// Like --on-connect, whenever the connection to the server is lost;
+    /// commands get SSHX_ERROR.
+    #[arg(long, value_name = "COMMAND|URL", global = true)]
+    on_disconnect: Vec<Hook>,
+
+    /// Like --on-connect, for every visitor; commands get SSHX_SUBDOMAIN and
+    /// SSHX_REMOTE_ADDR.
+    #[arg(long, value_name = "COMMAND|URL", global = true)]
+    on_new_connection: Vec<Hook>,
+
+    /// Send the visitor's address to the local service in a PROXY protocol
+    /// header (v1 or v2; TCP and HTTP tunnels).
+    #[arg(long, global = true, num_args = 0..=1, default_missing_value = "v1")]
+    proxy_protocol: Option<ProxyProtocol>,
+
+    /// Encrypt visitor connections end to end with the key pair in this
+    /// file, created if missing, so the server only sees ciphertext.
+    /// Visitors connect with --e2e-peer and its public key. TCP only.
+    #[arg(long, env = "SSHX_E2E_KEY", global = true)]
+    e2e_key: Option<PathBuf>,
+
+    /// Public key of a tunnel encrypted end to end, for `sshx connect`,
+    /// `sshx ssh` and `sshx ssh-config`.
+    #[arg(long, env = "SSHX_E2E_PEER", global = true)]
+    e2e_peer: Option<PublicKey>,
+
+    /// Automatically reconnect on disconnect [default: true].
+    #[arg(long, action = clap::ArgAction::Set, global = true)]
+    reconnect: Option<bool>,
+
+    /// How long each step of reaching the server may take: TLS, auth and
+    /// registering, e.g. "30s" [default: 5s].
+    #[arg(long, env = "SSHX_HANDSHAKE_TIMEOUT", value_parser = humantime::parse_duration, global = true)]
+    handshake_timeout: Option<Duration>,
+
+    /// Ask the server for heartbeats this far apart on slow links, e.g.
+    /// "5s" [default: 500ms]. Never more often than the server's own.
+    #[arg(long, env = "SSHX_HEARTBEAT_INTERVAL", value_parser = humantime::parse_duration, global = true)]
+    heartbeat_interval: Option<Duration>,
+
+    /// Reconnect after hearing nothing from the server for this long, or for
+    /// ten heartbeats if that is longer, e.g. "1m" [default: 5s].
+    #[arg(long, env = "SSHX_HEARTBEAT_TIMEOUT", value_parser = humantime::parse_duration, global = true)]
+    heartbeat_timeout: Option<Duration>,
+
+    /// Where a running sshx takes `sshx status`, `add-tunnel` and
+    /// `down` commands, a Unix socket or a named pipe on Windows
+    /// [default: sshx.sock in $XDG_RUNTIME_DIR or ~/.config/sshx].
+    #[arg(long, env = "SSHX_CONTROL_SOCKET", global = true)]
+    control_socket: Option<PathBuf>,
+
+    /// How log lines are written: `json` emits one object per line with the
+    /// tunnel, connection and byte counts as fields, for Loki or ELK.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, env = "SSHX_LOG_FORMAT", global = true)]
+    log_format: LogFormat,
+
+    /// What goes to stdout: `json` writes each tunnel event, such as the
+    /// public port and URL once registered, as one object per line for
+    /// scripts and CI, and sends the messages meant for people to stderr.
+    #[arg(long, value_enum, default_value_t = Output::Text, env = "SSHX_OUTPUT", global = true)]
+    output: Output,
+
+    /// Log filter, e.g. `debug` or `info,sshx_client=trace`. Falls back to
+    /// `RUST_LOG`, then `info`.
+    #[arg(long, env = "SSHX_LOG_LEVEL", global = true)]
+    log_level: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Start every tunnel of a profile in the config file.
+    Up {
+        /// Name of the profile, as in `[profiles.NAME]`.
+        profile: String,
+    },
+    /// Send a request captured by a running `sshx --inspect` to the local
+    /// service again.
+    Replay {
+        /// Number of the request, as listed by the inspector.
+        id: u64,
+    },
+    /// Expose a SOCKS5 proxy instead of a local port, so visitors can reach
+    /// the hosts this machine reaches.
+    Socks {
+        /// Subdomain to register.
+        #[arg(short, long)]
+        subdomain: String,
+        /// Destinations visitors may reach, as CIDR[:PORTS], e.g.
+        /// 10.0.0.0/8:22,8000-8999 (repeatable). Nothing else is reachable.
+        #[arg(long, value_name = "CIDR[:PORTS]", required = true)]
+        allow_dest: Vec<AllowRule>,
+        /// Public port to ask the server for.
+        #[arg(long)]
+        public_port: Option<u16>,
+    },
+    /// Share the files of a directory over HTTP, without a local web
+    /// server. Guard them with --http-auth user:password.
+    Share {
+        /// Directory to share.
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+        /// Subdomain to register [default: one the server picks].
+        #[arg(short, long)]
+        subdomain: Option<String>,
+        /// Let visitors upload files into it; existing ones are never
+        /// overwritten.
+        #[arg(long)]
+        upload: bool,
+        /// Largest file visitors may upload, e.g. 10M or 1Gi [default:
+        /// 100Mi].
+        #[arg(long, value_parser = parse_size, requires = "upload")]
+        max_upload: Option<u64>,
+        /// Public port to ask the server for.
+        #[arg(long)]
+        public_port: Option<u16>,
+    },
+    /// Make a port the server reaches appear locally, instead of exposing a
+    /// local one.
+    Pull {
+        /// host:port to reach from the server; the server must allow it.
+        #[arg(long)]
+        remote: String,
+        /// Local port to listen on, on --host.
+        #[arg(long)]
+        local: u16,
+    },
+    /// Connect the first visitor of a TCP tunnel to stdin and stdout, e.g.
+    /// as an SSH ProxyCommand, and exit when it leaves.
+    Stdio {
+        /// Subdomain to register.
+        #[arg(short, long)]
+        subdomain: String,
+        /// Public port to ask the server for.
+        #[arg(long)]
+        public_port: Option<u16>,
+    },
+    /// Open a TCP tunnel that sends visitors' bytes straight back, e.g. to
+    /// test the way to this machine with nc.
+    Echo {
+        /// Subdomain to register.
+        #[arg(short, long)]
+        subdomain: String,
+        /// Public port to ask the server for.
+        #[arg(long)]
+        public_port: Option<u16>,
+    },
+    /// Time connection setup, round trips and throughput through a
+    /// temporary echo tunnel, to tell a slow tunnel from a slow app.
+    Bench {
+        /// Connections to set up one by one, and round trips to time.
+        #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+        samples: u64,
+        /// Connections streaming at once in the throughput test.
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+        connections: u64,
+        /// How long the throughput test runs.
+        #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+        duration: Duration,
+    },
+    /// Connect stdin and stdout to the public port of a tunnel, e.g. as an
+    /// SSH ProxyCommand, until the tunnel's side hangs up.
+    Connect {
+        /// Subdomain of the tunnel.
+        subdomain: String,
+    },
+    /// Run ssh against the SSH server behind a TCP tunnel.
+    Ssh {
+        /// [user@]subdomain of the tunnel.
+        target: String,
+        /// More arguments for ssh, after --.
+        #[arg(last = true)]
+        args: Vec<OsString>,
+    },
+    /// Print a ~/.ssh/config entry for the SSH server behind a TCP tunnel.
+    SshConfig {
+        /// [user@]subdomain of the tunnel.
+        target: String,
+    },
+    /// Tunnel the port a Docker container exposes, found through the Docker
+    /// daemon ($DOCKER_HOST, by default /var/run/docker.sock). The
+    /// container's name is the subdomain unless one is given.
+    Docker {
+        /// Name or id of the container.
+        container: String,
+        /// Subdomain to register [default: the container's name].
+        #[arg(short, long)]
+        subdomain: Option<String>,
+        /// Port inside the container, for one that exposes several.
+        #[arg(long)]
+        container_port: Option<u16>,
+        /// Public port to ask the server for.
+        #[arg(long)]
+        public_port: Option<u16>,
+    },
+    /// Keep a tunnel up for every Kubernetes Service annotated with
+    /// sshx.dev/subdomain, until the annotation or the Service goes.
+    #[cfg(feature = "k8s")]
+    K8s {
+        /// Only watch Services in this namespace [default: all].
+        #[arg(short, long)]
+        namespace: Option<String>,
+    },
+    /// Show the tunnels of the running sshx.
+    Status,
+    /// Open one more tunnel in the running sshx, with its settings.
+    AddTunnel {
+        /// subdomain:localport[:http|tcp|udp][:publicport], like --forward.
+        #[arg(value_name = "SUBDOMAIN:PORT[:PROTO]")]
+        tunnel: String,
+    },
+    /// Close one tunnel of the running sshx and keep the others up.
+    #[command(alias = "remove-tunnel")]
+    Down {
+        /// Subdomain of the tunnel.
+        subdomain: String,
+    },
+    /// Check that the server is reachable, takes our credentials and that
+    /// DNS leads HTTP tunnels to it, and print what to fix.
+    Doctor {
+        /// Base domain of tunnel hostnames [default: the server's host].
+        #[arg(long)]
+        domain: Option<String>,
+        /// The server's shared HTTP port.
+        #[arg(long, default_value_t = 80)]
+        http_port: u16,
+    },
+    /// Keep the tunnels of a profile up in the background as a systemd user
+    /// unit, a launchd agent or a Windows service.
+    Service {
+        #[command(subcommand)]
+        action: service::Action,
+    },
+}
+
+impl Cli {
+    fn server(&self) -> &str {
+        self.server.first().map_or(DEFAULT_SERVER, String::as_str)
+    }
+
+    fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or("localhost")
+    }
+
+    fn control_socket(&self) -> Option<PathBuf> {
+        self.control_socket.clone().or_else(control::default_path)
+    }
+
+    /// Destinations of `sshx socks`; `None` when forwarding to local ports.
+    fn socks(&self) -> Option<&[AllowRule]> {
+        match &self.command {
+            Some(Command::Socks { allow_dest, .. }) => Some(allow_dest),
+            _ => None,
+        }
+    }
+
+    /// Directory of `sshx share`; `None` when forwarding to local ports.
+    fn share(&self) -> Option<&Path> {
+        match &self.command {
+            Some(Command::Share { dir, .. }) => Some(dir),
+            _ => None,
+        }
+    }
+
+    /// Whether a visitor gets stdin and stdout, with `sshx stdio`.
+    fn stdio(&self) -> bool {
+        matches!(self.command, Some(Command::Stdio { .. }))
+    }
+
+    /// Whether stdout carries something other than messages: the visitor
+    /// with `sshx stdio`, or JSON lines with `--output json`.
+    fn stdout_taken(&self) -> bool {
+        self.stdio() || self.output == Output::Json
+    }
+
+    /// Where messages for people go.
+    fn messages(&self) -> Box<dyn Write> {
+        match self.stdout_taken() {
+            true => Box::new(io::stderr()),
+            false => Box::new(io::stdout()),
+        }
+    }
+
+    /// Whether visitors get their bytes back, with `sshx echo`.
+    fn echo(&self) -> bool {
+        matches!(self.command, Some(Command::Echo { .. }))
+    }
+
+    /// Every tunnel to open: `--subdomain`/`--port` first, then `--forward`s.
+    fn tunnels(&self) -> Vec<Forward> {
+        if let Some(
+            Command::Socks {
+                subdomain,
+                public_port,
+                ..
+            }
+            | Command::Stdio {
+                subdomain,
+                public_port,
+            }
+            | Command::Echo {
+                subdomain,
+                public_port,
+            },
+        ) = &self.command
+        {
+            return vec![Forward {
+                subdomain: subdomain.clone(),
+                local_port: 0,
+                proto: Proto::Tcp,
+                grpc: false,
+                public_port: *public_port,
+                upstreams: Vec::new(),
+            }];
+        }
+        if let Some(Command::Share {
+            subdomain,
+            public_port,
+            ..
+        }) = &self.command
+        {
+            return vec![Forward {
+                // Left empty, the server picks one.
+                subdomain: subdomain.clone().unwrap_or_default(),
+                local_port: 0,
+                proto: Proto::Http,
+                grpc: false,
+                public_port: *public_port,
+                upstreams: Vec::new(),
+            }];
+        }
+        let first = self
+            .port
+            .first()
+            .copied()
+            .or((self.unix_socket.is_some() || self.exec.is_some()).then_some(0))
+            .map(|local_port| {
+                let proto = match (self.tcp, self.udp) {
+                    (true, _) => Proto::Tcp,
+                    (_, true) => Proto::Udp,
+                    _ => Proto::Http,
+                };
+                Forward {
+                    // Left empty, the server picks one.
+                    subdomain: self.subdomain.clone().unwrap_or_default(),
+                    local_port,
+                    proto,
+                    grpc: self.grpc,
+                    public_port: self.public_port,
+                    upstreams: self.port.iter().skip(1).copied().collect(),
+                }
+            });
+        first.into_iter().chain(self.forwards.clone()).collect()
+    }
+
+    /// When recordings move on to a new file.
+    fn rotation(&self) -> Rotation {
+        Rotation {
+            max_size: self.record_max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            max_age: self.record_max_age,
+        }
+    }
+}
+
+/// Bytes with an optional decimal (K, M, G) or binary (Ki, Mi, Gi) suffix,
+/// e.g. `500K`, `10Mi` or `1G`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim().trim_end_matches(['B', 'b']);
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" => 1,
+        "k" => 1_000,
+        "m" => 1_000_000,
+        "g" => 1_000_000_000,
+        "ki" => 1 << 10,
+        "mi" => 1 << 20,
+        "gi" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "unknown unit '{unit}' (expected K, M, G, Ki, Mi or Gi)"
+            ))
+        }
+    };
+    let n: u64 = digits.parse().map_err(|_| format!("invalid size '{s}'"))?;
+    n.checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{s}' is too large"))
+}
+
+/// A copy buffer size as for [`parse_size`], from 1Ki to 16Mi.
+fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    match parse_size(s)? {
+        size @ 1024..=0x100_0000 => Ok(size as usize),
+        _ => Err(format!("buffer size '{s}' is not between 1Ki and 16Mi")),
+    }
 }
 
 // ── Entry point ───────────────────────────────────────────────────────────────
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let cli = Cli::parse();
-    let proto = if cli.tcp { Proto::Tcp } else { Proto::Http };
-
-    info!(
-        subdomain = %cli.subdomain,
-        port = cli.port,
-        server = %cli.server,
-        "starting sshx"
+fn main() -> ExitCode {
+    // The service manager starts us, and waits to be talked to first.
+    #[cfg(windows)]
+    if let Ok(Cli {
+        command: Some(Command::Service {
+            action: service::Action::Run { .. },
+        }),
+        ..
+    }) = Cli::try_parse()
+    {
+        return service::windows::dispatch();
+    }
+    let runtime = tokio::runtime::Runtime::new().expect("cannot start the async runtime");
+    let code = runtime.block_on(run());
+    // A read of stdin left waiting by `sshx stdio` would hold up the exit.
+    runtime.shutdown_background();
+    code
+}
+
+async fn run() -> ExitCode {
+    let mut cli = Cli::parse();
+    if let Err(e) = init_logging(cli.log_format, cli.log_level.as_deref()) {
+        eprintln!("error: {e:#}");
+        return Failure::Other.exit_code();
+    }
+    let profile = match &cli.command {
+        Some(
+            Command::Up { profile }
+            | Command::Service {
+                action: service::Action::Run { profile },
+            },
+        ) => Some(profile.clone()),
+        Some(Command::Service { action }) => {
+            return match service::manage(action, cli.config.as_deref()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {e:#}");
+                    Failure::Other.exit_code()
+                }
+            };
+        }
+        Some(Command::Replay { id }) => {
+            let addr = cli.inspect_addr.unwrap_or(INSPECT_ADDR);
+            return match replay(addr, *id).await {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {e:#}");
+                    Failure::Other.exit_code()
+                }
+            };
+        }
+        Some(Command::Socks { .. }) if !cli.forwards.is_empty() => {
+            eprintln!("error: --forward can't be combined with socks");
+            return Failure::Other.exit_code();
+        }
+        Some(Command::Stdio { .. }) if !cli.forwards.is_empty() => {
+            eprintln!("error: --forward can't be combined with stdio");
+            return Failure::Other.exit_code();
+        }
+        Some(Command::Share { .. }) if !cli.forwards.is_empty() => {
+            eprintln!("error: --forward can't be combined with share");
+            return Failure::Other.exit_code();
+        }
+        Some(Command::Echo { .. }) if !cli.forwards.is_empty() => {
+            eprintln!("error: --forward can't be combined with echo");
+            return Failure::Other.exit_code();
+        }
+        // These need the terminal or stdout, which the visitor gets instead.
+        Some(Command::Stdio { .. }) if cli.ui || cli.approve || cli.inspect => {
+            eprintln!("error: --ui, --approve and --inspect can't be combined with stdio");
+            return Failure::Other.exit_code();
+        }
+        _ if cli.ui && cli.output == Output::Json => {
+            eprintln!("error: --ui can't be combined with --output json");
+            return Failure::Other.exit_code();
+        }
+        Some(
+            Command::Socks { .. }
+            | Command::Share { .. }
+            | Command::Pull { .. }
+            | Command::Stdio { .. }
+            | Command::Echo { .. }
+            | Command::Bench { .. }
+            | Command::Docker { .. }
+            | Command::Connect { .. }
+            | Command::Ssh { .. }
+            | Command::SshConfig { .. }
+            | Command::Status
+            | Command::AddTunnel { .. }
+            | Command::Down { .. }
+            | Command::Doctor { .. },
+        )
+        | None => None,
+        #[cfg(feature = "k8s")]
+        Some(Command::K8s { .. }) => None,
+    };
+    let config = Config::load(cli.config.as_deref())
+        .and_then(|config| config.apply(&mut cli, profile.as_deref()));
+    if let Err(e) = config {
+        eprintln!("error: {e:#}");
+        return Failure::Other.exit_code();
+    }
+    // A running sshx has already found its server.
+    let controls = matches!(
+        cli.command,
+        Some(Command::Status | Command::AddTunnel { .. } | Command::Down { .. })
     );
+    if cli.server() == "auto" && !controls {
+        if let Err(e) = discover(&mut cli).await {
+            eprintln!("error: {e:#}");
+            return Failure::Network.exit_code();
+        }
+    }
+    if cli.proxy.is_none() {
+        match HttpProxy::from_env(cli.server()) {
+            Ok(proxy) => cli.proxy = proxy,
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                return Failure::Other.exit_code();
+            }
+        }
+    }
+    if cli.bind_interface.is_some() && !cfg!(target_os = "linux") {
+        eprintln!("error: --bind-interface is only supported on Linux");
+        return Failure::Other.exit_code();
+    }
 
-    loop {
-        match run(&cli, proto).await {
-            Ok(_) => {
-                info!("tunnel closed cleanly");
-                break;
+    let result = match &cli.command {
+        Some(Command::Connect { subdomain }) => Some(connect(&cli, subdomain).await),
+        Some(Command::Bench {
+            samples,
+            connections,
+            duration,
+        }) => {
+            let settings = bench::Settings {
+                samples: *samples as usize,
+                connections: *connections as usize,
+                duration: *duration,
+            };
+            Some(bench::run(&cli, &settings).await)
+        }
+        Some(Command::Doctor { domain, http_port }) => {
+            Some(doctor::run(&cli, domain.as_deref(), *http_port).await)
+        }
+        Some(Command::Ssh { target, args }) => {
+            match ssh::run(&cli, &ssh::Target::parse(target), args).await {
+                Ok(code) => return code,
+                Err(e) => Some(Err(e)),
             }
+        }
+        Some(Command::SshConfig { target }) => {
+            let entry = ssh::config(&cli, &ssh::Target::parse(target));
+            Some(entry.map(|entry| print!("{entry}")))
+        }
+        #[cfg(feature = "k8s")]
+        Some(Command::K8s { namespace }) => Some(k8s::run(&cli, namespace.as_deref()).await),
+        Some(Command::Status) => Some(command(&cli, control::Request::Status).await),
+        Some(Command::AddTunnel { tunnel }) => {
+            let request = control::Request::AddTunnel {
+                forward: tunnel.clone(),
+            };
+            Some(command(&cli, request).await)
+        }
+        Some(Command::Down { subdomain }) => {
+            let request = control::Request::RemoveTunnel {
+                subdomain: subdomain.clone(),
+            };
+            Some(command(&cli, request).await)
+        }
+        _ => None,
+    };
+    if let Some(result) = result {
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
             Err(e) => {
-                error!(err = %e, "tunnel error");
-                if !cli.reconnect {
-                    return Err(e);
-                }
-                warn!("reconnecting in 3 seconds…");
-                sleep(Duration::from_secs(3)).await;
+                eprintln!("error: {e:#}");
+                Failure::of(&e).exit_code()
+            }
+        };
+    }
+
+    if let Some(Command::Docker {
+        container,
+        subdomain,
+        container_port,
+        public_port,
+    }) = cli.command.clone()
+    {
+        let target = match docker::inspect(&container, container_port).await {
+            Ok(target) => target,
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                return Failure::Other.exit_code();
+            }
+        };
+        info!(%container, host = %target.host, port = target.port, "found the container's port");
+        cli.subdomain = Some(subdomain.unwrap_or(target.name));
+        cli.port = vec![target.port];
+        cli.host = Some(target.host);
+        cli.public_port = public_port.or(cli.public_port);
+        if target.udp {
+            (cli.tcp, cli.udp, cli.grpc) = (false, true, false);
+        }
+    }
+
+    if let Some(Command::Pull { remote, local }) = &cli.command {
+        let stats = Arc::new(Stats::new());
+        let result = pull(&cli, remote, *local, Arc::clone(&stats)).await;
+        let _ = write!(cli.messages(), "{}", stats.summary());
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                Failure::of(&e).exit_code()
+            }
+        };
+    }
+
+    let tunnels = cli.tunnels();
+    if tunnels.is_empty() {
+        eprintln!(
+            "error: profile '{}' has no tunnels",
+            profile.unwrap_or_default()
+        );
+        return Failure::Other.exit_code();
+    }
+    for tunnel in &tunnels {
+        info!(
+            subdomain = %tunnel.subdomain,
+            port = tunnel.local_port,
+            server = %cli.server(),
+            "starting sshx"
+        );
+    }
+
+    if let Some(path) = cli.unix_socket.as_ref().filter(|_| !cli.skip_local_check) {
+        if !path.exists() {
+            warn!(path = %path.display(), "local service check failed");
+            let mut out = cli.messages();
+            let _ = writeln!(out);
+            let _ = writeln!(out, "  ⚠  {} doesn't exist yet.", path.display());
+            let _ = writeln!(
+                out,
+                "     • Start your service first, or double-check --unix-socket."
+            );
+            let _ = writeln!(out, "     • Pass --skip-local-check to silence this check.");
+        }
+    } else if !cli.skip_local_check
+        && cli.socks().is_none()
+        && cli.share().is_none()
+        && !cli.stdio()
+        && !cli.echo()
+        && cli.exec.is_none()
+    {
+        // UDP has no handshake to probe with.
+        for tunnel in tunnels.iter().filter(|t| t.proto != Proto::Udp) {
+            for port in tunnel.local_ports() {
+                check_local_service(&cli, port).await;
             }
         }
     }
+
+    let stats = Arc::new(Stats::new());
+    let result = serve(&cli, &tunnels, Arc::clone(&stats)).await;
+    let _ = write!(cli.messages(), "{}", stats.summary());
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            Failure::of(&e).exit_code()
+        }
+    }
+}
+
+/// Install the global subscriber, writing through the dashboard's gate. JSON
+/// lines keep the spans, so each carries its tunnel's server and visitor.
+fn init_logging(format: LogFormat, level: Option<&str>) -> Result<()> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level).context("invalid --log-level")?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(ui::log_writer);
+    match format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().flatten_event(true).with_span_list(true).init(),
+    }
     Ok(())
 }
 
-// ── Main tunnel loop ──────────────────────────────────────────────────────────
+/// Replace `--server auto` with the first server found on the LAN.
+async fn discover(cli: &mut Cli) -> Result<()> {
+    let Some(found) = mdns::discover(mdns::DISCOVERY_WAIT).await? else {
+        bail!("no sshx-server announced itself on the LAN; start one with --mdns");
+    };
+    eprintln!("Found sshx-server '{}' at {}", found.name, found.addr);
+    let port = match (cli.tls, found.tls_port) {
+        (true, Some(port)) => port,
+        (true, None) => bail!("'{}' has no TLS control port; leave out --tls", found.name),
+        (false, _) => found.addr.port(),
+    };
+    cli.server = vec![found.addr.ip().to_string()];
+    cli.control_port.get_or_insert(port);
+    Ok(())
+}
 
-async fn run(cli: &Cli, proto: Proto) -> Result<()> {
-    // Open control connection.
-    let stream = connect(&cli.server, CONTROL_PORT).await?;
-    let mut ctrl = Framed_::new(stream);
+/// Run the tunnels until they fail for good or we are asked to stop.
+async fn serve(cli: &Cli, tunnels: &[Forward], stats: Arc<Stats>) -> Result<()> {
+    if let Some(path) = &cli.e2e_key {
+        let notice = format!(
+            "  🔒  End-to-end encrypted; visitors connect with --e2e-peer {}",
+            Keypair::load_or_create(path)?.public()
+        );
+        let _ = writeln!(cli.messages(), "{notice}");
+    }
+    let inspect = cli.inspect || cli.inspect_addr.is_some();
+    let mut inspector = None;
+    if inspect || cli.record.is_some() {
+        let mut captured = Inspector::default();
+        if let Some(path) = &cli.record {
+            captured = captured.har(Recorder::create(path, Format::Har, cli.rotation())?);
+            eprintln!(
+                "  ⚠️   Recording HTTP exchanges to {} in plaintext, credentials included",
+                path.display()
+            );
+        }
+        let captured = Arc::new(captured);
+        if inspect {
+            let addr = cli.inspect_addr.unwrap_or(INSPECT_ADDR);
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow!("cannot serve --inspect on {addr}: {e}"))?;
+            tokio::spawn(Arc::clone(&captured).serve(listener));
+            let _ = writeln!(cli.messages(), "  🔍  Inspect requests at http://{addr}");
+        }
+        inspector = Some(captured);
+    }
+    let mut pcap = None;
+    if let Some(path) = &cli.record_pcap {
+        pcap = Some(Arc::new(Recorder::create(
+            path,
+            Format::Pcap,
+            cli.rotation(),
+        )?));
+        eprintln!(
+            "  ⚠️   Recording TCP connections to {} in plaintext",
+            path.display()
+        );
+    }
+    if let Some(chaos) = &cli.chaos {
+        eprintln!("  🌩️   Simulating a bad network for visitors: {chaos}");
+    }
+    let mut builder = tunnel_builder(
+        cli,
+        tunnels,
+        Arc::clone(&stats),
+        inspector.as_ref(),
+        pcap.as_ref(),
+    )?;
+    let mut approver = None;
+    if cli.approve {
+        let mut prompt = match cli.ui {
+            true => Approver::queued(),
+            false => Approver::new(),
+        };
+        if let Some(timeout) = cli.approve_timeout {
+            prompt = prompt.timeout(timeout);
+        }
+        let prompt = Arc::new(prompt);
+        builder = builder.approver(Arc::clone(&prompt));
+        approver = Some(prompt);
+    }
+    if cli.ready_timeout.is_some() {
+        builder = builder.probe_first(true);
+    }
 
-    // Auth (if secret provided).
-    if let Some(secret) = &cli.secret {
-        Auth::new(secret).handshake(&mut ctrl).await?;
+    let mut signal = pin!(shutdown_signal());
+    let mut tunnel = tokio::select! {
+        tunnel = builder.connect() => tunnel?,
+        _ = &mut signal => {
+            info!("shutting down");
+            return Ok(());
+        }
+    };
+    if cli.ready_timeout.is_some() || cli.print_url_file.is_some() {
+        if let Some(limit) = cli.ready_timeout {
+            let ready = tokio::select! {
+                ready = ready::wait(cli, tunnels, &mut tunnel, limit) => Some(ready),
+                _ = &mut signal => None,
+            };
+            match ready {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    let _ = tunnel.shutdown().await;
+                    return Err(e);
+                }
+                None => {
+                    info!("shutting down");
+                    return tunnel.shutdown().await;
+                }
+            }
+        }
+        let path = cli.print_url_file.as_deref();
+        ready::export(cli, tunnels, tunnel.registrations(), path)?;
+    }
+    if cli.ui {
+        let mut dashboard = Dashboard::new(cli.server(), cli.host(), tunnels, stats);
+        if let Some(path) = &cli.unix_socket {
+            dashboard = dashboard.unix_socket(path);
+        }
+        if let Some(command) = &cli.exec {
+            dashboard = dashboard.exec(command);
+        }
+        if let Some(inspector) = inspector {
+            dashboard = dashboard.inspector(inspector);
+        }
+        if let Some(approver) = approver {
+            dashboard = dashboard.approver(approver);
+        }
+        return dashboard.run(tunnel, signal).await;
     }
 
-    // Register subdomain.
-    ctrl.send(ClientMsg::Hello {
-        subdomain: cli.subdomain.clone(),
-        proto,
-    })
-    .await?;
+    // Tunnels added later get the same settings, which --approve (a prompt
+    // per tunnel), a SOCKS proxy, a shared directory, echoing and
+    // stdin/stdout can't share.
+    let mut control = None;
+    let special = cli.socks().is_some() || cli.share().is_some() || cli.echo() || cli.stdio();
+    if !cli.approve && !special {
+        if let Some(path) = cli.control_socket() {
+            match control::Listener::bind(&path).await {
+                Ok(Some(listener)) => control = Some(listener),
+                Ok(None) => warn!(
+                    path = %path.display(),
+                    "another sshx has the control socket; pass --control-socket to tell them apart"
+                ),
+                Err(e) => warn!(err = format!("{e:#}"), "no control socket"),
+            }
+        }
+    }
+    if let Some(listener) = &control {
+        info!(path = %listener.path().display(), "taking commands");
+    }
+    let mut running = control::Running::new(cli, stats, inspector, pcap);
+    running.insert(tunnels.to_vec(), tunnel);
+    running.run(control, signal).await
+}
 
-    // Read server Hello.
-    let public_port = match ctrl.recv_timeout::<ServerMsg>().await? {
-        Some(ServerMsg::Hello { public_port }) => public_port,
-        Some(ServerMsg::Error(e)) => bail!("server error: {e}"),
-        Some(ServerMsg::Challenge(_)) => bail!("server requires auth but no --secret given"),
-        _ => bail!("unexpected response from server"),
-    };
+/// A builder for `tunnels` with every setting of `cli`, sharing `stats`,
+/// `inspector` and the `pcap` recording. Each run sets up --approve itself.
+fn tunnel_builder(
+    cli: &Cli,
+    tunnels: &[Forward],
+    stats: Arc<Stats>,
+    inspector: Option<&Arc<Inspector>>,
+    pcap: Option<&Arc<Recorder>>,
+) -> Result<TunnelBuilder> {
+    let mut builder = builder(cli, stats);
+    for tunnel in tunnels {
+        builder = builder.forward(tunnel.clone());
+    }
+    if let Some(version) = cli.proxy_protocol {
+        builder = builder.proxy_protocol(version);
+    }
+    if let Some(window) = cli.local_retry {
+        builder = builder.local_retry(window);
+    }
+    if let Some(chaos) = cli.chaos {
+        builder = builder.chaos(chaos);
+    }
+    for &net in &cli.allow_cidr {
+        builder = builder.allow(net);
+    }
+    for &net in &cli.deny_cidr {
+        builder = builder.deny(net);
+    }
+    for hook in &cli.on_connect {
+        builder = builder.on_connect(hook.clone());
+    }
+    for hook in &cli.on_disconnect {
+        builder = builder.on_disconnect(hook.clone());
+    }
+    for hook in &cli.on_new_connection {
+        builder = builder.on_new_connection(hook.clone());
+    }
+    if let Some(allow) = cli.socks() {
+        builder = builder.socks(allow.to_vec());
+    }
+    if let Some(Command::Share {
+        dir,
+        upload,
+        max_upload,
+        ..
+    }) = &cli.command
+    {
+        let mut share = Share::new(dir).uploads(*upload);
+        if let Some(bytes) = max_upload {
+            share = share.max_upload(*bytes);
+        }
+        builder = builder.share(share);
+    }
+    if let Some(path) = &cli.unix_socket {
+        builder = builder.unix_socket(path);
+    }
+    if let Some(command) = &cli.exec {
+        builder = builder.exec(command);
+    }
+    if cli.echo() {
+        builder = builder.echo();
+    }
+    if cli.stdio() {
+        builder = builder.stdio();
+    }
+    if let Some(path) = &cli.e2e_key {
+        builder = builder.e2e(Keypair::load_or_create(path)?);
+    }
+    if let Some(credentials) = &cli.http_auth {
+        builder = builder.http_auth(credentials);
+    }
+    for header in &cli.add_header {
+        let Some((name, value)) = header.split_once(':') else {
+            bail!("--add-header expects \"Name: value\", got '{header}'");
+        };
+        builder = builder.add_header(name.trim(), value.trim());
+    }
+    for name in &cli.remove_header {
+        builder = builder.remove_header(name);
+    }
+    if let Some(host) = &cli.host_rewrite {
+        builder = builder.host_rewrite(host);
+    }
+    if let Some(path) = &cli.error_page {
+        let template = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read --error-page {}", path.display()))?;
+        builder = builder.error_page(template);
+    }
+    if let Some(inspector) = inspector {
+        builder = builder.inspector(Arc::clone(inspector));
+    }
+    if let Some(pcap) = pcap.filter(|_| tunnels.iter().any(|t| t.proto == Proto::Tcp)) {
+        builder = builder.record_pcap(Arc::clone(pcap));
+    }
+    Ok(builder)
+}
+
+/// Send `request` to the running sshx and print its tunnels.
+async fn command(cli: &Cli, request: control::Request) -> Result<()> {
+    let path = cli
+        .control_socket()
+        .context("cannot find the control socket; pass --control-socket")?;
+    let response = control::send(&path, &request).await?;
+    if cli.output == Output::Json {
+        let mut out = io::stdout().lock();
+        for tunnel in &response.tunnels {
+            serde_json::to_writer(&mut out, tunnel)?;
+            writeln!(out)?;
+        }
+        return Ok(());
+    }
+    if response.tunnels.is_empty() {
+        println!("No tunnels on {}", response.server);
+        return Ok(());
+    }
+    println!(
+        "{:<24} {:<5} {:>6} {:<28} {:>6} {:>10} {:>10}",
+        "TUNNEL", "PROTO", "LOCAL", "PUBLIC", "CONNS", "IN", "OUT"
+    );
+    for tunnel in &response.tunnels {
+        let public = match (&tunnel.url, tunnel.public_port) {
+            _ if !tunnel.connected => "(reconnecting)".to_owned(),
+            (Some(url), _) => url.clone(),
+            (None, Some(port)) => format!("{}:{port}", response.server),
+            (None, None) => "-".to_owned(),
+        };
+        println!(
+            "{:<24} {:<5} {:>6} {:<28} {:>6} {:>10} {:>10}",
+            tunnel.subdomain,
+            format!("{:?}", tunnel.proto).to_lowercase(),
+            tunnel.local_port,
+            public,
+            tunnel.conns,
+            format_bytes(tunnel.bytes_in),
+            format_bytes(tunnel.bytes_out),
+        );
+    }
+    Ok(())
+}
+
+/// A builder with the settings for reaching the server.
+fn builder(cli: &Cli, stats: Arc<Stats>) -> TunnelBuilder {
+    let mut builder = Tunnel::builder()
+        .server(cli.server())
+        .local_host(cli.host())
+        .tls(cli.tls)
+        .reconnect(cli.reconnect.unwrap_or(true))
+        .stats(stats);
+    for standby in cli.server.iter().skip(1) {
+        builder = builder.standby(standby);
+    }
+    if let Some(port) = cli.control_port {
+        builder = builder.control_port(port);
+    }
+    if let Some(ca) = &cli.tls_ca {
+        builder = builder.tls_ca(ca);
+    }
+    if let Some(transport) = cli.transport {
+        builder = builder.transport(transport);
+    }
+    if let Some(proxy) = &cli.proxy {
+        builder = builder.http_proxy(proxy.clone());
+    }
+    if let Some(resolver) = &cli.resolver {
+        builder = builder.resolver(resolver.clone());
+    }
+    // Tokens are answered exactly like a secret; only the server tells them apart.
+    if let Some(secret) = cli.token.as_ref().or(cli.secret.as_ref()) {
+        builder = builder.secret(secret);
+    }
+    if let Some(addr) = cli.bind_address {
+        builder = builder.bind_address(addr);
+    }
+    if let Some(iface) = &cli.bind_interface {
+        builder = builder.bind_interface(iface);
+    }
+    if let Some(timeout) = cli.handshake_timeout {
+        builder = builder.handshake_timeout(timeout);
+    }
+    if let Some(size) = cli.buffer_size {
+        builder = builder.buffer_size(size);
+    }
+    if cli.resumable {
+        builder = builder.resumable(true);
+    }
+    if let Some(size) = cli.warm_pool {
+        builder = builder.warm_pool(size);
+    }
+    if let Some(ttl) = cli.ttl {
+        builder = builder.ttl(ttl);
+    }
+    if let Some(uses) = cli.max_uses {
+        builder = builder.max_uses(uses);
+    }
+    if cli.pool {
+        builder = builder.pool(true);
+    }
+    if let Some(key) = &cli.takeover_key {
+        builder = builder.takeover_key(key);
+    }
+    if let Some(balance) = cli.balance {
+        builder = builder.balance(balance);
+    }
+    if let Some(interval) = cli.heartbeat_interval {
+        builder = builder.heartbeat_interval(interval);
+    }
+    if let Some(timeout) = cli.heartbeat_timeout {
+        builder = builder.heartbeat_timeout(timeout);
+    }
+    builder
+}
 
-    println!();
-    println!("  ✓  Tunnel active!");
-    println!("     Subdomain : {}.{}", cli.subdomain, cli.server);
-    println!("     Public    : {}:{}", cli.server, public_port);
-    println!("     Local     : {}:{}", cli.host, cli.port);
-    println!("     Protocol  : {:?}", proto);
-    println!();
+/// Pull `remote` to `local` on --host until we are asked to stop.
+async fn pull(cli: &Cli, remote: &str, local: u16, stats: Arc<Stats>) -> Result<()> {
+    let listener = TcpListener::bind((cli.host(), local))
+        .await
+        .with_context(|| format!("cannot listen on {}:{local}", cli.host()))?;
+    let mut out = cli.messages();
+    writeln!(out)?;
+    writeln!(out, "  ✓  Pulling {remote} through {}", cli.server())?;
+    writeln!(out, "     Local     : {}", listener.local_addr()?)?;
+    writeln!(out)?;
+    tokio::select! {
+        result = builder(cli, stats).pull(remote, listener) => result,
+        _ = shutdown_signal() => {
+            info!("shutting down");
+            Ok(())
+        }
+    }
+}
 
-    // Share CLI config across spawned tasks.
-    let cli = Arc::new(cli.clone());
+/// Connect stdin and stdout to the public port of the tunnel of `subdomain`.
+async fn connect(cli: &Cli, subdomain: &str) -> Result<()> {
+    let stream = builder(cli, Arc::new(Stats::new())).dial(subdomain).await?;
+    match &cli.e2e_peer {
+        Some(peer) => pipe_stdio(e2e::connect(stream, peer).await?).await,
+        None => pipe_stdio(stream).await,
+    }
+}
 
-    // Event loop.
+/// Copy stdin to `stream` and `stream` to stdout until `stream` ends.
+async fn pipe_stdio(stream: impl AsyncRead + AsyncWrite) -> Result<()> {
+    let (mut read, mut write) = tokio::io::split(stream);
+    let (mut stdin, mut stdout) = (tokio::io::stdin(), tokio::io::stdout());
+    let to_stdout = async {
+        tokio::io::copy(&mut read, &mut stdout).await?;
+        stdout.flush().await
+    };
+    let from_stdin = async {
+        tokio::io::copy(&mut stdin, &mut write).await?;
+        write.shutdown().await
+    };
+    let (mut to_stdout, mut from_stdin) = (pin!(to_stdout), pin!(from_stdin));
+    // Stdin may stay open after the tunnel's side hangs up; that side decides.
+    let mut stdin_open = true;
     loop {
-        match ctrl.recv::<ServerMsg>().await? {
-            Some(ServerMsg::Heartbeat) => {}
-            Some(ServerMsg::Connection(id)) => {
-                let cli = Arc::clone(&cli);
-                tokio::spawn(async move {
-                    if let Err(e) = handle_data_connection(id, &cli).await {
-                        warn!(err = %e, "data connection error");
-                    }
-                });
+        tokio::select! {
+            done = &mut to_stdout => return Ok(done?),
+            done = &mut from_stdin, if stdin_open => {
+                done?;
+                stdin_open = false;
             }
-            Some(ServerMsg::Error(e)) => error!("server: {e}"),
-            None => break,
-            _ => {}
         }
     }
+}
+
+fn print_event(cli: &Cli, tunnels: &[Forward], event: Event) -> io::Result<()> {
+    if cli.output == Output::Json {
+        return print_json(cli, tunnels, event);
+    }
+    let mut out = cli.messages();
+    match event {
+        Event::Connected(registration) => {
+            let Some(tunnel) = registered_as(tunnels, &registration.subdomain) else {
+                return Ok(());
+            };
+            writeln!(out)?;
+            writeln!(out, "  ✓  Tunnel active!")?;
+            writeln!(
+                out,
+                "     Subdomain : {}.{}",
+                registration.subdomain,
+                cli.server()
+            )?;
+            writeln!(
+                out,
+                "     Public    : {}:{}",
+                cli.server(),
+                registration.public_port
+            )?;
+            if registration.addrs.len() > 1 {
+                let addrs: Vec<_> = registration.addrs.iter().map(ToString::to_string).collect();
+                writeln!(out, "     Bound on  : {}", addrs.join(", "))?;
+            }
+            match &registration.url {
+                Some(url) => writeln!(out, "     URL       : {url}")?,
+                // Ready to paste into a browser.
+                None if cli.share().is_some() => writeln!(
+                    out,
+                    "     URL       : http://{}:{}/",
+                    cli.server(),
+                    registration.public_port
+                )?,
+                None => {}
+            }
+            match (cli.socks(), cli.share(), &cli.unix_socket, &cli.exec) {
+                (Some(allow), _, _, _) => {
+                    let allow: Vec<_> = allow.iter().map(ToString::to_string).collect();
+                    writeln!(out, "     Local     : SOCKS5 proxy to {}", allow.join(" "))?;
+                }
+                (None, Some(dir), _, _) => {
+                    writeln!(out, "     Local     : files in {}", dir.display())?
+                }
+                _ if cli.stdio() => writeln!(out, "     Local     : stdin/stdout")?,
+                _ if cli.echo() => writeln!(out, "     Local     : echo")?,
+                (None, None, _, Some(command)) => writeln!(out, "     Local     : exec {command}")?,
+                (None, None, Some(path), None) => {
+                    writeln!(out, "     Local     : {}", path.display())?
+                }
+                (None, None, None, None) => {
+                    writeln!(out, "     Local     : {}", tunnel.local_addr(cli.host()))?
+                }
+            }
+            writeln!(out, "     Protocol  : {:?}", tunnel.proto)?;
+            writeln!(out)?;
+        }
+        Event::Traffic { subdomain, traffic } => writeln!(
+            out,
+            "  ⇅  {subdomain}: {} connections, {} in / {} out ({}/s in, {}/s out)",
+            traffic.conns,
+            format_bytes(traffic.bytes_in),
+            format_bytes(traffic.bytes_out),
+            format_bytes(traffic.rate_in as u64),
+            format_bytes(traffic.rate_out as u64),
+        )?,
+        Event::Notice(notice) => writeln!(out, "  ℹ  Server notice: {notice}")?,
+        Event::Expired { message, .. } => {
+            writeln!(out)?;
+            writeln!(out, "  ⌛  The server closed a tunnel: {message}.")?;
+            writeln!(out, "     Start sshx again to reopen it.")?;
+            writeln!(out)?;
+        }
+        Event::HandedOver { subdomain } => {
+            writeln!(out)?;
+            writeln!(out, "  ⇄  Another client took over {subdomain}.")?;
+            writeln!(out, "     Finishing the connections in progress.")?;
+            writeln!(out)?;
+        }
+        Event::Unregistered { subdomain } => writeln!(out, "  ✕  Closed the tunnel {subdomain}.")?,
+        _ => {}
+    }
     Ok(())
 }
 
-// ── Data connection (one per inbound TCP connection) ──────────────────────────
+/// The tunnel the server registered as `subdomain`; one without a subdomain
+/// got the one the server picked.
+fn registered_as<'a>(tunnels: &'a [Forward], subdomain: &str) -> Option<&'a Forward> {
+    tunnels
+        .iter()
+        .find(|t| t.subdomain == subdomain)
+        .or_else(|| tunnels.iter().find(|t| t.subdomain.is_empty()))
+}
 
-async fn handle_data_connection(id: Uuid, cli: &Cli) -> Result<()> {
-    // Open a NEW control-port connection just for this data stream.
-    let stream = connect(&cli.server, CONTROL_PORT).await?;
-    let mut data_conn = Framed_::new(stream);
+/// `event` as one line of JSON on stdout, for `--output json`.
+fn print_json(cli: &Cli, tunnels: &[Forward], event: Event) -> io::Result<()> {
+    let (kind, fields) = match event {
+        Event::Connected(registration) => {
+            let proto = registered_as(tunnels, &registration.subdomain)
+                .map(|t| format!("{:?}", t.proto).to_lowercase());
+            let url = registration.url.or_else(|| {
+                cli.share()
+                    .map(|_| format!("http://{}:{}/", cli.server(), registration.public_port))
+            });
+            let fields = json!({
+                "subdomain": registration.subdomain,
+                "proto": proto,
+                "host": cli.server(),
+                "public_port": registration.public_port,
+                "url": url,
+                "addrs": registration.addrs,
+            });
+            ("connected", fields)
+        }
+        Event::Connection {
+            subdomain,
+            peer_addr,
+            public_port,
+            country,
+            asn,
+        } => {
+            let fields = json!({
+                "subdomain": subdomain,
+                "peer_addr": peer_addr,
+                "public_port": public_port,
+                "country": country,
+                "asn": asn,
+            });
+            ("connection", fields)
+        }
+        Event::Probed {
+            subdomain,
+            peer_addr,
+        } => {
+            let fields = json!({ "subdomain": subdomain, "peer_addr": peer_addr });
+            ("probed", fields)
+        }
+        Event::Request {
+            subdomain,
+            peer_addr,
+            line,
+        } => {
+            let fields = json!({ "subdomain": subdomain, "peer_addr": peer_addr, "line": line });
+            ("request", fields)
+        }
+        Event::ConnectionClosed {
+            subdomain,
+            peer_addr,
+            bytes_in,
+            bytes_out,
+        } => {
+            let fields = json!({
+                "subdomain": subdomain,
+                "peer_addr": peer_addr,
+                "bytes_in": bytes_in,
+                "bytes_out": bytes_out,
+            });
+            ("connection_closed", fields)
+        }
+        Event::Traffic { subdomain, traffic } => {
+            let fields = json!({
+                "subdomain": subdomain,
+                "conns": traffic.conns,
+                "bytes_in": traffic.bytes_in,
+                "bytes_out": traffic.bytes_out,
+                "rate_in": traffic.rate_in,
+                "rate_out": traffic.rate_out,
+            });
+            ("traffic", fields)
+        }
+        Event::Notice(message) => ("notice", json!({ "message": message })),
+        Event::Expired { subdomain, message } => {
+            let fields = json!({ "subdomain": subdomain, "message": message });
+            ("expired", fields)
+        }
+        Event::HandedOver { subdomain } => ("handed_over", json!({ "subdomain": subdomain })),
+        Event::Unregistered { subdomain } => ("unregistered", json!({ "subdomain": subdomain })),
+        Event::Disconnected {
+            error,
+            reconnecting,
+        } => {
+            let fields = json!({ "error": error, "reconnecting": reconnecting });
+            ("disconnected", fields)
+        }
+    };
+    let mut line = Map::new();
+    let time = humantime::format_rfc3339_millis(SystemTime::now());
+    line.insert("time".to_owned(), time.to_string().into());
+    line.insert("event".to_owned(), kind.into());
+    if let Value::Object(fields) = fields {
+        line.extend(fields);
+    }
+    let mut out = io::stdout().lock();
+    serde_json::to_writer(&mut out, &line)?;
+    writeln!(out)?;
+    out.flush()
+}
 
-    // Re-auth if needed.
-    if let Some(secret) = &cli.secret {
-        Auth::new(secret).handshake(&mut data_conn).await?;
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
     }
+    #[cfg(windows)]
+    {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = service::windows::STOP.cancelled() => {}
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
 
-    // Tell server which pending connection we're accepting.
-    data_conn.send(ClientMsg::Accept(id)).await?;
+// ── Replay ────────────────────────────────────────────────────────────────────
 
-    // Connect to local service.
-    let mut local = connect(&cli.host, cli.port).await?;
+/// Ask the inspector on `addr` to replay request `id`, and print the outcome.
+async fn replay(addr: SocketAddr, id: u64) -> Result<()> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("no inspector on {addr}; is sshx running with --inspect?"))?;
+    let request = format!(
+        "POST /api/requests/{id}/replay HTTP/1.1\r\nHost: {addr}\r\n\
+         Content-Type: application/json\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
 
-    // Upgrade: discard the framing codec, use raw TCP from here.
-    let mut parts = data_conn.into_parts();
-    local.write_all(&parts.read_buf).await?;
-    tokio::io::copy_bidirectional(&mut local, &mut parts.io).await?;
+    let response = String::from_utf8_lossy(&response);
+    let (_, body) = response
+        .split_once("\r\n\r\n")
+        .context("the inspector sent a malformed response")?;
+    let body: Value = serde_json::from_str(body).context("the inspector sent invalid JSON")?;
+    if let Some(error) = body["error"].as_str() {
+        bail!("{error}");
+    }
+    let status = body["status"]
+        .as_u64()
+        .map_or("no response".to_owned(), |s| s.to_string());
+    println!(
+        "  ↻  Replayed #{id} as #{}: {} {} → {status} in {} ms, {} B",
+        body["id"],
+        body["method"].as_str().unwrap_or_default(),
+        body["path"].as_str().unwrap_or_default(),
+        body["duration_ms"],
+        body["response_size"],
+    );
     Ok(())
 }
 
-// ── Helper ────────────────────────────────────────────────────────────────────
+// ── Local service check ───────────────────────────────────────────────────────
 
-async fn connect(host: &str, port: u16) -> Result<TcpStream> {
-    TcpStream::connect((host, port))
-        .await
-        .map_err(|e| anyhow::anyhow!("cannot connect to {}:{} — {}", host, port, e))
+/// Warn when nothing is listening on the local target yet — by far the most
+/// common reason a fresh tunnel "doesn't work".
+async fn check_local_service(cli: &Cli, port: u16) {
+    let host = cli.host();
+    let err = match timeout(Duration::from_secs(2), TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => return,
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => "timed out".into(),
+    };
+    warn!(%host, port, %err, "local service check failed");
+    let mut out = cli.messages();
+    let _ = writeln!(out);
+    let _ = writeln!(out, "  ⚠  Nothing is answering on {host}:{port} yet.");
+    let _ = writeln!(
+        out,
+        "     • Start your service first, or double-check --port / --host."
+    );
+    let _ = writeln!(
+        out,
+        "     • If it listens on another interface, try --host 127.0.0.1 or --host ::1."
+    );
+    let _ = writeln!(
+        out,
+        "     • The tunnel will still open; visitors get errors until the service is up."
+    );
+    let _ = writeln!(out, "     • Pass --skip-local-check to silence this check.");
 }
@@ -0,0 +1,61 @@
+//! Finding an sshx server on the local network, for `--server auto`. Servers
+//! started with `--mdns` announce themselves over mDNS (DNS-SD).
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use sshx_core::protocol::MDNS_SERVICE;
+use tokio::time::timeout;
+use tracing::debug;
+
+/// How long `--server auto` listens for announcements.
+pub const DISCOVERY_WAIT: Duration = Duration::from_secs(3);
+
+/// A server that announced itself.
+#[derive(Debug, Clone)]
+pub struct Found {
+    /// The name it announced, usually its host name.
+    pub name: String,
+    /// Where its plain control port is.
+    pub addr: SocketAddr,
+    /// Its TLS control port, if it has one.
+    pub tls_port: Option<u16>,
+}
+
+/// The first server that announces itself within `wait`, if any.
+pub async fn discover(wait: Duration) -> Result<Option<Found>> {
+    let daemon = ServiceDaemon::new().context("cannot start mDNS")?;
+    let events = daemon
+        .browse(MDNS_SERVICE)
+        .context("cannot look for servers over mDNS")?;
+    let browse = async {
+        while let Ok(event) = events.recv_async().await {
+            let ServiceEvent::ServiceResolved(info) = event else {
+                continue;
+            };
+            // IPv4 first: link-local IPv6 addresses need a scope to be dialed.
+            let mut ips: Vec<_> = info.get_addresses().iter().copied().collect();
+            ips.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+            let Some(&ip) = ips.first() else {
+                continue;
+            };
+            let name = info.get_fullname();
+            let name = name
+                .strip_suffix(MDNS_SERVICE)
+                .map_or(name, |n| n.trim_end_matches('.'));
+            debug!(name, %ip, "server found over mDNS");
+            return Some(Found {
+                name: name.to_owned(),
+                addr: SocketAddr::new(ip, info.get_port()),
+                tls_port: info
+                    .get_property_val_str("tls_port")
+                    .and_then(|port| port.parse().ok()),
+            });
+        }
+        None
+    };
+    let found = timeout(wait, browse).await.ok().flatten();
+    let _ = daemon.shutdown();
+    Ok(found)
+}
@@ -0,0 +1,86 @@
+//! PROXY protocol headers, so the local service learns the visitor's address.
+//!
+//! Spec: <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::bail;
+use serde::Deserialize;
+
+/// Version of the PROXY protocol header sent to the local service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// Human-readable text header.
+    V1,
+    /// Binary header.
+    V2,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "v1" | "1" => Ok(Self::V1),
+            "v2" | "2" => Ok(Self::V2),
+            _ => bail!("unknown PROXY protocol version '{s}' (expected v1 or v2)"),
+        }
+    }
+}
+
+impl fmt::Display for ProxyProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::V1 => "v1",
+            Self::V2 => "v2",
+        })
+    }
+}
+
+/// v2 signature, followed by version 2 + the PROXY command.
+const V2_PREFIX: [u8; 13] = *b"\r\n\r\n\0\r\nQUIT\n\x21";
+
+/// Header announcing a TCP connection from `src` (the visitor) to `dst`.
+pub(crate) fn header(version: ProxyProtocol, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    // Both addresses must be of one family; mixed pairs become IPv6.
+    let (src_ip, dst_ip) = match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (s, d) => (IpAddr::V6(to_v6(s)), IpAddr::V6(to_v6(d))),
+    };
+    match version {
+        ProxyProtocol::V1 => {
+            let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {family} {src_ip} {dst_ip} {} {}\r\n",
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let mut out = V2_PREFIX.to_vec();
+            let (family, mut addrs) = match (src_ip, dst_ip) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => (0x11, [s.octets(), d.octets()].concat()),
+                (s, d) => (0x21, [to_v6(s).octets(), to_v6(d).octets()].concat()),
+            };
+            addrs.extend(src.port().to_be_bytes());
+            addrs.extend(dst.port().to_be_bytes());
+            out.push(family);
+            out.extend((addrs.len() as u16).to_be_bytes());
+            out.extend(addrs);
+            out
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
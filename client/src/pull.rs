@@ -0,0 +1,81 @@
+//! Pull mode: a port reachable from the server appears on a local listener.
+//!
+//! Each connection accepted locally opens its own connection to the server,
+//! which starts with `Pull` instead of `Hello`. Once the server answers
+//! `Pulled`, the connection carries the target's bytes.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use sshx_core::{
+    auth::Auth,
+    protocol::{ClientMsg, ServerMsg},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+use crate::{
+    status::TunnelError,
+    tunnel::{connect_control, Shared},
+};
+
+/// Accept local connections and pull `target` for each, until the listener
+/// fails.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    target: String,
+    shared: Arc<Shared>,
+) -> Result<()> {
+    let target = Arc::new(target);
+    loop {
+        let (local, peer_addr) = listener.accept().await?;
+        let (target, shared) = (Arc::clone(&target), Arc::clone(&shared));
+        tokio::spawn(async move {
+            match pull(local, &target, &shared).await {
+                Ok((bytes_in, bytes_out)) => {
+                    info!(%peer_addr, bytes_in, bytes_out, "pull connection closed");
+                    shared.stats.record_connection();
+                }
+                Err(e) => {
+                    warn!(%peer_addr, err = format!("{e:#}"), "pull failed");
+                    shared.stats.record_error(&e);
+                }
+            }
+        });
+    }
+}
+
+/// Connect `local` to `target` through the server. Returns the bytes that
+/// came from the target and went to it.
+async fn pull(mut local: TcpStream, target: &str, shared: &Shared) -> Result<(u64, u64)> {
+    let mut conn = shared.framed(connect_control(shared).await?);
+    if let Some(secret) = &shared.options.secret {
+        Auth::new(secret).handshake(&mut conn).await?;
+    }
+    conn.send(ClientMsg::Pull {
+        target: target.to_owned(),
+    })
+    .await?;
+    let peer_addr = match conn.recv_timeout::<ServerMsg>().await? {
+        Some(ServerMsg::Pulled { peer_addr }) => peer_addr,
+        Some(ServerMsg::Refused { code, message }) => {
+            return Err(TunnelError::with_code(code, message).into())
+        }
+        Some(ServerMsg::Error(message)) => return Err(TunnelError::from_server(message).into()),
+        Some(other) => bail!("unexpected reply to Pull: {other:?}"),
+        None => bail!("the server hung up; it may be too old to pull ports"),
+    };
+    info!(%target, %peer_addr, "pulling");
+
+    let mut parts = conn.into_parts();
+    local.write_all(&parts.read_buf).await?;
+    let size = shared.options.buffer_size;
+    let (to_target, from_target) =
+        tokio::io::copy_bidirectional_with_sizes(&mut local, &mut parts.io, size, size).await?;
+    let from_target = from_target + parts.read_buf.len() as u64;
+    shared.stats.record_transfer(from_target, to_target);
+    Ok((from_target, to_target))
+}
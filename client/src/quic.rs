@@ -0,0 +1,122 @@
+//! The QUIC transport: the control connection and every data connection as
+//! streams of one QUIC connection to the server's TLS control port, over UDP.
+//!
+//! Networks that drop UDP are common, so when the QUIC handshake fails the
+//! client says so and carries on with TLS over TCP to the same port.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Context, Result};
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint};
+use sshx_core::protocol::{QUIC_ALPN, QUIC_STREAM_OPEN};
+use tokio::{sync::Mutex, time::timeout};
+use tokio_rustls::rustls;
+use tracing::{info, warn};
+
+use crate::{
+    resolve,
+    tunnel::{Io, Options},
+};
+
+pub(crate) struct Quic {
+    config: ClientConfig,
+    /// The connection streams are opened on, once it is up.
+    connection: Mutex<Option<(Endpoint, Connection)>>,
+    /// Set once QUIC couldn't connect; TCP is used from then on.
+    fell_back: AtomicBool,
+}
+
+impl Quic {
+    pub(crate) fn new(mut tls: rustls::ClientConfig) -> Result<Self> {
+        tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let crypto = QuicClientConfig::try_from(tls)?;
+        Ok(Self {
+            config: ClientConfig::new(Arc::new(crypto)),
+            connection: Mutex::new(None),
+            fell_back: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether streams go over QUIC, rather than TCP after a fallback.
+    pub(crate) fn in_use(&self) -> bool {
+        !self.fell_back.load(Ordering::Relaxed)
+    }
+
+    /// Open a stream to the control port of `server`, connecting first if
+    /// needed. `None` means QUIC is out and the caller should use TCP.
+    pub(crate) async fn open(
+        &self,
+        options: &Options,
+        server: &str,
+    ) -> Result<Option<Box<dyn Io>>> {
+        if !self.in_use() {
+            return Ok(None);
+        }
+        let mut current = self.connection.lock().await;
+        // A connection that went away is replaced, once.
+        if let Some((_, connection)) = current.as_ref() {
+            if let Ok(stream) = open_stream(connection).await {
+                return Ok(Some(stream));
+            }
+            *current = None;
+        }
+        let (endpoint, connection) = match self.connect(options, server).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                warn!(
+                    err = format!("{e:#}"),
+                    "QUIC is unreachable, falling back to TCP"
+                );
+                self.fell_back.store(true, Ordering::Relaxed);
+                return Ok(None);
+            }
+        };
+        let stream = open_stream(&connection).await?;
+        *current = Some((endpoint, connection));
+        Ok(Some(stream))
+    }
+
+    /// Drop the connection and give QUIC another chance, for a new server.
+    pub(crate) async fn forget(&self) {
+        *self.connection.lock().await = None;
+        self.fell_back.store(false, Ordering::Relaxed);
+    }
+
+    async fn connect(&self, options: &Options, server: &str) -> Result<(Endpoint, Connection)> {
+        let port = options.control_port;
+        let addr = resolve::lookup(server, port, &options.resolver)
+            .await?
+            .into_iter()
+            .find(|addr| {
+                // A source address only works with destinations of the same family.
+                options
+                    .bind_address
+                    .is_none_or(|src| src.is_ipv4() == addr.is_ipv4())
+            })
+            .ok_or_else(|| anyhow!("no usable address for {server}"))?;
+        let local = options.bind_address.unwrap_or(match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+        let endpoint = Endpoint::client(SocketAddr::new(local, 0))?;
+        let connecting = endpoint.connect_with(self.config.clone(), addr, server)?;
+        let connection = timeout(options.timeouts.handshake, connecting)
+            .await
+            .context("QUIC handshake timed out")?
+            .with_context(|| format!("QUIC handshake with {server}:{port} failed"))?;
+        info!(%addr, "connected over QUIC");
+        Ok((endpoint, connection))
+    }
+}
+
+async fn open_stream(connection: &Connection) -> Result<Box<dyn Io>> {
+    let (mut send, recv) = connection.open_bi().await?;
+    send.write_all(&[QUIC_STREAM_OPEN]).await?;
+    Ok(Box::new(tokio::io::join(recv, send)))
+}
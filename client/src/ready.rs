@@ -0,0 +1,141 @@
+//! `--ready-timeout` and `--print-url-file`, for CI jobs that start a
+//! tunnel and then point tests at it: wait until a visitor really gets
+//! through the public port, and say where that is.
+//!
+//! The tunnel is opened with [`probe_first`](sshx_client::TunnelBuilder::probe_first),
+//! so the probes stop at the client: they don't reach the local service, run
+//! hooks or use up `--max-uses`.
+
+use std::{io::Write, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use sshx_client::{
+    status::{Failure, TunnelError},
+    Event, Forward, Proto, Registration, Tunnel,
+};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout, Instant},
+};
+use tracing::{debug, info};
+
+use crate::{print_event, registered_as, Cli};
+
+/// How long one probe waits for the client to hear of it.
+const PROBE_WAIT: Duration = Duration::from_secs(2);
+/// Pause between probes that didn't get through.
+const PROBE_PAUSE: Duration = Duration::from_millis(500);
+
+/// Connect to the public port of every TCP and HTTP tunnel like a visitor
+/// would, until the connection comes through to this client, then let
+/// visitors through. Fails once `limit` passes first. Events that come in
+/// meanwhile are printed.
+pub async fn wait(
+    cli: &Cli,
+    tunnels: &[Forward],
+    tunnel: &mut Tunnel,
+    limit: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + limit;
+    let registrations = tunnel.registrations().to_vec();
+    for registration in &registrations {
+        // UDP has no connection to notice.
+        if registered_as(tunnels, &registration.subdomain).is_some_and(|t| t.proto == Proto::Udp) {
+            continue;
+        }
+        let (host, port) = (cli.server(), registration.public_port);
+        loop {
+            if Instant::now() >= deadline {
+                let message = format!(
+                    "{} was not reachable at {host}:{port} within {}",
+                    registration.subdomain,
+                    humantime::format_duration(limit)
+                );
+                return Err(TunnelError::new(Failure::Network, message).into());
+            }
+            if probe(cli, tunnels, tunnel, registration, deadline).await {
+                info!(subdomain = %registration.subdomain, %host, port, "tunnel is reachable");
+                break;
+            }
+            sleep(PROBE_PAUSE).await;
+        }
+    }
+    tunnel.ready();
+    Ok(())
+}
+
+/// One visitor connection to the public port of `registration`; whether
+/// the client heard of it.
+async fn probe(
+    cli: &Cli,
+    tunnels: &[Forward],
+    tunnel: &mut Tunnel,
+    registration: &Registration,
+    deadline: Instant,
+) -> bool {
+    let addr = (cli.server(), registration.public_port);
+    let _visitor = match tokio::time::timeout_at(deadline, TcpStream::connect(addr)).await {
+        Ok(Ok(visitor)) => visitor,
+        Ok(Err(e)) => {
+            debug!(err = %e, "reachability probe failed to connect");
+            return false;
+        }
+        Err(_) => return false,
+    };
+    let heard = async {
+        while let Some(event) = tunnel.next_event().await {
+            let ours = matches!(
+                &event,
+                Event::Probed { subdomain, .. } if *subdomain == registration.subdomain
+            );
+            let _ = print_event(cli, tunnels, event);
+            if ours {
+                return true;
+            }
+        }
+        false
+    };
+    let wait = PROBE_WAIT.min(deadline.saturating_duration_since(Instant::now()));
+    timeout(wait, heard).await.unwrap_or(false)
+}
+
+/// Where visitors reach `registration`: its URL, or the server's host and
+/// the public port.
+fn url(cli: &Cli, tunnels: &[Forward], registration: &Registration) -> String {
+    if let Some(url) = &registration.url {
+        return url.clone();
+    }
+    let (host, port) = (cli.server(), registration.public_port);
+    match registered_as(tunnels, &registration.subdomain).map(|t| t.proto) {
+        Some(Proto::Http) => format!("http://{host}:{port}/"),
+        _ => format!("{host}:{port}"),
+    }
+}
+
+/// Write the URL of every tunnel to `path`, one per line, in one go so a
+/// job polling for the file never reads half of it. Under GitHub Actions,
+/// the first one also becomes the step output `url`.
+pub fn export(
+    cli: &Cli,
+    tunnels: &[Forward],
+    registrations: &[Registration],
+    path: Option<&Path>,
+) -> Result<()> {
+    let urls: Vec<String> = registrations.iter().map(|r| url(cli, tunnels, r)).collect();
+    if let Some(path) = path {
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, urls.join("\n") + "\n")
+            .with_context(|| format!("cannot write {}", partial.display()))?;
+        std::fs::rename(&partial, path)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+    }
+    if let (Some(output), Some(first)) = (std::env::var_os("GITHUB_OUTPUT"), urls.first()) {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&output)
+            .context("cannot write $GITHUB_OUTPUT")?;
+        writeln!(file, "url={first}").context("cannot write $GITHUB_OUTPUT")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,442 @@
+//! Saving tunneled traffic for offline debugging: the exchanges of HTTP
+//! tunnels as HAR (`--record`), the connections of TCP tunnels as PCAP
+//! (`--record-pcap`).
+//!
+//! Both hold traffic as it went to and from the local service, which is
+//! plaintext whatever the visitor used: passwords, cookies and tokens
+//! included.
+//!
+//! A file is rotated once it grows past [`Rotation::max_size`] or has been
+//! written to for [`Rotation::max_age`]: it is renamed with `.1` before its
+//! extension, replacing the previous one, and a new file is started. Each
+//! file is complete on its own, and so is the current one at any moment.
+
+use std::{
+    fs::{self, File},
+    io::{self, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::inspect::Exchange;
+
+/// Size at which a file is rotated unless told otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 100 << 20;
+
+/// What a HAR file starts and ends with; entries go in between.
+const HAR_HEAD: &str = concat!(
+    r#"{"log":{"version":"1.2","creator":{"name":"sshx","version":""#,
+    env!("CARGO_PKG_VERSION"),
+    r#""},"entries":["#
+);
+const HAR_TAIL: &str = "]}}\n";
+
+/// Link type of packets that start with their IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+
+/// Largest TCP payload per recorded packet.
+const MAX_SEGMENT: usize = 32 * 1024;
+
+// TCP flags.
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// What a recording is saved as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Har,
+    Pcap,
+}
+
+/// When a recording moves on to a new file.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub max_size: u64,
+    /// Never rotated for age when `None`.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+            max_age: None,
+        }
+    }
+}
+
+/// A recording, shared by every connection that goes into it. Writing
+/// stops, with a warning, at the first error.
+pub struct Recorder {
+    path: PathBuf,
+    format: Format,
+    rotation: Rotation,
+    /// `None` once writing failed.
+    file: Mutex<Option<Current>>,
+}
+
+/// The file being written.
+struct Current {
+    file: File,
+    size: u64,
+    opened: Instant,
+    entries: u64,
+}
+
+impl Recorder {
+    /// Start recording to `path`, replacing whatever is there.
+    pub fn create(path: impl Into<PathBuf>, format: Format, rotation: Rotation) -> Result<Self> {
+        let path = path.into();
+        let current =
+            open(&path, format).with_context(|| format!("cannot record to {}", path.display()))?;
+        Ok(Self {
+            path,
+            format,
+            rotation,
+            file: Mutex::new(Some(current)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Add a finished exchange to a HAR recording.
+    pub(crate) fn exchange(&self, exchange: &Exchange) {
+        let entry = har_entry(exchange).to_string();
+        self.write(entry.len() as u64, |current| {
+            let tail = HAR_TAIL.len() as u64;
+            current.file.seek(SeekFrom::Start(current.size - tail))?;
+            let sep = if current.entries > 0 { "," } else { "" };
+            write!(current.file, "{sep}{entry}{HAR_TAIL}")?;
+            current.size += (sep.len() + entry.len()) as u64;
+            Ok(())
+        });
+    }
+
+    /// Add a packet, stamped with the current time, to a PCAP recording.
+    fn packet(&self, packet: &[u8]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(packet);
+        self.write(record.len() as u64, |current| {
+            current.file.write_all(&record)?;
+            current.size += record.len() as u64;
+            Ok(())
+        });
+    }
+
+    /// Run `append` on the current file, rotating first if `len` more bytes
+    /// would take it past the size limit, or it is too old.
+    fn write(&self, len: u64, append: impl FnOnce(&mut Current) -> io::Result<()>) {
+        let mut file = self.file.lock().unwrap();
+        let Some(current) = file.as_mut() else {
+            return;
+        };
+        let written = (|| {
+            let full = current.entries > 0 && current.size + len > self.rotation.max_size;
+            let old = self
+                .rotation
+                .max_age
+                .is_some_and(|age| current.opened.elapsed() >= age);
+            if full || old {
+                fs::rename(&self.path, rotated(&self.path))?;
+                *current = open(&self.path, self.format)?;
+                info!(path = %self.path.display(), "recording rotated");
+            }
+            append(current)?;
+            current.entries += 1;
+            io::Result::Ok(())
+        })();
+        if let Err(e) = written {
+            warn!(path = %self.path.display(), err = %e, "recording stopped");
+            *file = None;
+        }
+    }
+}
+
+/// A new, empty recording at `path`, readable by the owner only since it
+/// holds secrets. Rotated files keep that.
+fn open(path: &Path, format: Format) -> io::Result<Current> {
+    let mut options = File::options();
+    options.read(true).write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    let size = match format {
+        Format::Har => {
+            write!(file, "{HAR_HEAD}{HAR_TAIL}")?;
+            HAR_HEAD.len() + HAR_TAIL.len()
+        }
+        Format::Pcap => {
+            let mut header = Vec::with_capacity(24);
+            header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+            header.extend_from_slice(&2u16.to_le_bytes());
+            header.extend_from_slice(&4u16.to_le_bytes());
+            header.extend_from_slice(&[0; 8]);
+            header.extend_from_slice(&65535u32.to_le_bytes());
+            header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            file.write_all(&header)?;
+            header.len()
+        }
+    };
+    Ok(Current {
+        file,
+        size: size as u64,
+        opened: Instant::now(),
+        entries: 0,
+    })
+}
+
+/// Where the file at `path` goes when rotated: `capture.har` becomes
+/// `capture.1.har`.
+fn rotated(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}.1.{}", ext.to_string_lossy()),
+        None => format!("{stem}.1"),
+    };
+    path.with_file_name(name)
+}
+
+/// An exchange as a HAR entry. Response bodies are not kept, so only their
+/// size is given.
+fn har_entry(e: &Exchange) -> Value {
+    let header = |headers: &[(String, String)], name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    };
+    let pairs = |headers: &[(String, String)]| -> Vec<Value> {
+        headers
+            .iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect()
+    };
+    let host = header(&e.request_headers, "host").unwrap_or_else(|| e.subdomain.clone());
+    let query: Vec<Value> = e
+        .path
+        .split_once('?')
+        .map(|(_, query)| query.split('&').filter(|p| !p.is_empty()))
+        .into_iter()
+        .flatten()
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            json!({ "name": name, "value": value })
+        })
+        .collect();
+    let mut request = json!({
+        "method": e.method,
+        "url": format!("http://{host}{}", e.path),
+        "httpVersion": "HTTP/1.1",
+        "cookies": [],
+        "headers": pairs(&e.request_headers),
+        "queryString": query,
+        "headersSize": -1,
+        "bodySize": e.request_size,
+    });
+    if e.request_size > 0 {
+        request["postData"] = json!({
+            "mimeType": header(&e.request_headers, "content-type").unwrap_or_default(),
+            "text": String::from_utf8_lossy(&e.request_body),
+        });
+    }
+    json!({
+        "startedDateTime": e.time,
+        "time": e.duration_ms,
+        "request": request,
+        "response": {
+            "status": e.status.unwrap_or(0),
+            "statusText": "",
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": pairs(&e.response_headers),
+            "content": {
+                "size": e.response_size,
+                "mimeType": header(&e.response_headers, "content-type").unwrap_or_default(),
+            },
+            "redirectURL": header(&e.response_headers, "location").unwrap_or_default(),
+            "headersSize": -1,
+            "bodySize": e.response_size,
+        },
+        "cache": {},
+        "timings": { "send": 0, "wait": e.duration_ms, "receive": 0 },
+        "serverIPAddress": e.local_host,
+        "comment": format!("tunnel {}, visitor {}", e.subdomain, e.peer_addr),
+    })
+}
+
+/// Records one connection of a TCP tunnel as packets between the visitor
+/// and the local service, as if the visitor had connected to it directly:
+/// a handshake when it opens, a segment for each read and write, and FINs
+/// when it closes. Checksums are filled in, so tools take them as they are.
+pub(crate) struct PcapTap {
+    recorder: Arc<Recorder>,
+    visitor: SocketAddr,
+    local: SocketAddr,
+    /// Next sequence number from the visitor, and from the local service.
+    seq: (u32, u32),
+}
+
+impl PcapTap {
+    pub(crate) fn new(recorder: Arc<Recorder>, visitor: SocketAddr, local: SocketAddr) -> Self {
+        let mut tap = Self {
+            recorder,
+            visitor,
+            local,
+            seq: (0, 0),
+        };
+        tap.segment(true, SYN, &[]);
+        tap.segment(false, SYN | ACK, &[]);
+        tap.segment(true, ACK, &[]);
+        tap
+    }
+
+    /// Bytes that went to the local service.
+    pub(crate) fn request(&mut self, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            self.segment(true, PSH | ACK, chunk);
+        }
+    }
+
+    /// Bytes that came back from it.
+    pub(crate) fn response(&mut self, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            self.segment(false, PSH | ACK, chunk);
+        }
+    }
+
+    /// Record a segment sent by the visitor if `outbound`, or else by the
+    /// local service, and advance its sequence number.
+    fn segment(&mut self, outbound: bool, flags: u8, payload: &[u8]) {
+        let (src, dst, seq, ack) = match outbound {
+            true => (self.visitor, self.local, self.seq.0, self.seq.1),
+            false => (self.local, self.visitor, self.seq.1, self.seq.0),
+        };
+        let ack = if flags & ACK != 0 { ack } else { 0 };
+        let packet = tcp_packet(src, dst, seq, ack, flags, payload);
+        self.recorder.packet(&packet);
+        let advance = payload.len() as u32 + u32::from(flags & (SYN | FIN) != 0);
+        let next = match outbound {
+            true => &mut self.seq.0,
+            false => &mut self.seq.1,
+        };
+        *next = next.wrapping_add(advance);
+    }
+}
+
+impl Drop for PcapTap {
+    fn drop(&mut self) {
+        self.segment(true, FIN | ACK, &[]);
+        self.segment(false, FIN | ACK, &[]);
+        self.segment(true, ACK, &[]);
+    }
+}
+
+/// A TCP segment in an IPv4 packet, or in an IPv6 one if either address is
+/// IPv6.
+fn tcp_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.extend_from_slice(&[5 << 4, flags]);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0; 4]);
+    tcp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + tcp.len());
+    let pseudo = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&(20 + tcp.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let header = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&header.to_be_bytes());
+            [
+                &s.octets()[..],
+                &d.octets()[..],
+                &[0, 6],
+                &(tcp.len() as u16).to_be_bytes(),
+            ]
+            .concat()
+        }
+        (s, d) => {
+            let (s, d) = (v6(s), v6(d));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&s);
+            packet.extend_from_slice(&d);
+            [
+                &s[..],
+                &d[..],
+                &(tcp.len() as u32).to_be_bytes(),
+                &[0, 0, 0, 6],
+            ]
+            .concat()
+        }
+    };
+    let sum = checksum(&[&pseudo, &tcp]);
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn v6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// The Internet checksum of `parts` one after the other; all but the last
+/// must be of even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let word = u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]);
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,397 @@
+//! Name resolution and connection racing for the client's connections.
+//!
+//! Names are resolved by the system or over DNS-over-HTTPS (RFC 8484) and
+//! the answers kept for a short while, so the data connection of every
+//! visitor doesn't wait on DNS again. The addresses are then tried the
+//! happy-eyeballs way (RFC 8305): IPv6 and IPv4 in turn, each attempt given
+//! a head start of [`ATTEMPT_DELAY`] before the next one joins the race,
+//! and the first to connect wins.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+    time::{sleep, timeout},
+};
+use tokio_rustls::rustls::pki_types::ServerName;
+
+/// How long an answer is used before the name is resolved again.
+pub const CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// How long a connection attempt runs alone before the next address is
+/// tried alongside it.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long a DNS-over-HTTPS query may take.
+const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest DNS-over-HTTPS answer we read.
+const MAX_ANSWER: u64 = 64 * 1024;
+
+/// DNS record types.
+const A: u16 = 1;
+const AAAA: u16 = 28;
+
+/// Who resolves the server's name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Resolver {
+    /// The system's resolver.
+    #[default]
+    System,
+    /// A DNS-over-HTTPS server, e.g. `https://1.1.1.1/dns-query`.
+    Doh(DohServer),
+}
+
+/// Where DNS-over-HTTPS queries go.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DohServer {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for Resolver {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "system" {
+            return Ok(Self::System);
+        }
+        let Some(rest) = s.strip_prefix("https://") else {
+            bail!("resolver '{s}' is neither 'system' nor an https:// URL");
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(at) => (&rest[..at], &rest[at..]),
+            None => (rest, "/dns-query"),
+        };
+        // `[2606:4700::1111]:443` keeps its colons inside the brackets.
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .with_context(|| format!("invalid resolver port '{port}'"))?,
+            ),
+            _ => (authority, 443),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('@') || path.contains('?') {
+            bail!("invalid resolver URL '{s}'");
+        }
+        Ok(Self::Doh(DohServer {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        }))
+    }
+}
+
+impl TryFrom<String> for Resolver {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Debug for DohServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "https://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// Answers by name and resolver, until they expire.
+static CACHE: LazyLock<Mutex<HashMap<(String, bool), Cached>>> = LazyLock::new(Mutex::default);
+
+struct Cached {
+    addrs: Vec<IpAddr>,
+    until: Instant,
+}
+
+/// The addresses of `host`, with `port`.
+pub(crate) async fn lookup(host: &str, port: u16, resolver: &Resolver) -> Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let key = (
+        host.to_ascii_lowercase(),
+        matches!(resolver, Resolver::Doh(_)),
+    );
+    let now = Instant::now();
+    if let Some(cached) = CACHE.lock().unwrap().get(&key).filter(|c| c.until > now) {
+        return Ok(with_port(&cached.addrs, port));
+    }
+    let (addrs, ttl) = match resolver {
+        Resolver::System => {
+            let addrs = lookup_host((host, port))
+                .await
+                .with_context(|| format!("cannot resolve {host}"))?;
+            (addrs.map(|addr| addr.ip()).collect::<Vec<_>>(), CACHE_TTL)
+        }
+        Resolver::Doh(server) => server.resolve(host).await?,
+    };
+    if addrs.is_empty() {
+        bail!("{host} has no address");
+    }
+    let until = Instant::now() + ttl.min(CACHE_TTL);
+    let cached = Cached {
+        addrs: addrs.clone(),
+        until,
+    };
+    CACHE.lock().unwrap().insert(key, cached);
+    Ok(with_port(&addrs, port))
+}
+
+/// Resolve `host` afresh next time, e.g. once none of its addresses took
+/// a connection.
+pub(crate) fn forget(host: &str) {
+    let host = host.to_ascii_lowercase();
+    CACHE.lock().unwrap().retain(|(name, _), _| *name != host);
+}
+
+fn with_port(addrs: &[IpAddr], port: u16) -> Vec<SocketAddr> {
+    addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect()
+}
+
+/// Connect to `host:port`, racing its addresses.
+pub(crate) async fn connect(host: &str, port: u16, resolver: &Resolver) -> Result<TcpStream> {
+    let addrs = lookup(host, port, resolver).await?;
+    match happy_eyeballs(&addrs, TcpStream::connect).await {
+        Ok(stream) => Ok(stream),
+        Err(e) => {
+            forget(host);
+            Err(e).with_context(|| format!("cannot connect to {host}:{port}"))
+        }
+    }
+}
+
+/// Race `connect` over `addrs`, IPv6 and IPv4 in turn: each attempt starts
+/// when the one before fails or has run for [`ATTEMPT_DELAY`]. The first
+/// connection wins and the others are dropped; with none, the last error
+/// is returned.
+pub(crate) async fn happy_eyeballs<F, Fut, S>(addrs: &[SocketAddr], connect: F) -> io::Result<S>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let mut next = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    match next.next() {
+        Some(addr) => attempts.push(connect(addr)),
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "no address")),
+    }
+    loop {
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => match next.next() {
+                    Some(addr) => attempts.push(connect(addr)),
+                    None if attempts.is_empty() => return Err(e),
+                    None => {}
+                },
+            },
+            _ = sleep(ATTEMPT_DELAY), if next.len() > 0 => {
+                attempts.extend(next.next().map(&connect));
+            }
+        }
+    }
+}
+
+/// `addrs` with the families taking turns, IPv6 first.
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().copied().partition(|addr| addr.is_ipv6());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut order = Vec::with_capacity(addrs.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return order,
+            (a, b) => order.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+impl DohServer {
+    /// The A and AAAA records of `host`, and how long they may be kept.
+    async fn resolve(&self, host: &str) -> Result<(Vec<IpAddr>, Duration)> {
+        let (v4, v6) = tokio::join!(self.query(host, A), self.query(host, AAAA));
+        let resolved = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => [v6, v4].into_iter().flatten(),
+        };
+        let mut addrs = Vec::new();
+        let mut ttl = CACHE_TTL;
+        for (found, found_ttl) in resolved {
+            addrs.extend(found);
+            ttl = ttl.min(found_ttl);
+        }
+        Ok((addrs, ttl))
+    }
+
+    /// Ask for the `kind` records of `host`.
+    async fn query(&self, host: &str, kind: u16) -> Result<(Vec<IpAddr>, Duration)> {
+        let query = URL_SAFE_NO_PAD.encode(encode_query(host, kind)?);
+        let answer = timeout(DOH_TIMEOUT, self.get(&query))
+            .await
+            .context("timed out")
+            .and_then(|answer| answer)
+            .with_context(|| format!("cannot resolve {host} with {self:?}"))?;
+        decode_answer(&answer, kind).with_context(|| format!("bad answer from {self:?}"))
+    }
+
+    /// GET `?dns=<query>` and return the body.
+    async fn get(&self, query: &str) -> Result<Vec<u8>> {
+        // The system resolves the resolver itself, and without the cache:
+        // going through `lookup` would have it ask itself.
+        let (host, port) = (self.host.as_str(), self.port);
+        let addrs: Vec<_> = lookup_host((host, port))
+            .await
+            .with_context(|| format!("cannot resolve {host}"))?
+            .collect();
+        let stream = happy_eyeballs(&addrs, TcpStream::connect)
+            .await
+            .with_context(|| format!("cannot connect to {host}:{port}"))?;
+        let name = ServerName::try_from(self.host.clone())
+            .with_context(|| format!("invalid TLS name '{}'", self.host))?;
+        let mut stream = crate::tls::connector(None)?.connect(name, stream).await?;
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        let head = format!(
+            "GET {}?dns={query} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             Accept: application/dns-message\r\n\
+             Connection: close\r\n\r\n",
+            self.path
+        );
+        stream.write_all(head.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.take(MAX_ANSWER).read_to_end(&mut response).await?;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let httparse::Status::Complete(len) = parsed.parse(&response)? else {
+            bail!("cut-off answer");
+        };
+        match parsed.code {
+            Some(200) => {}
+            code => bail!("answered {}", code.unwrap_or_default()),
+        }
+        let chunked = parsed.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("transfer-encoding")
+                && String::from_utf8_lossy(h.value).contains("chunked")
+        });
+        let body = response.split_off(len);
+        match chunked {
+            true => dechunk(&body),
+            false => Ok(body),
+        }
+    }
+}
+
+/// A DNS query for the `kind` records of `host`, with id 0 as RFC 8484
+/// asks for caching's sake.
+fn encode_query(host: &str, kind: u16) -> Result<Vec<u8>> {
+    // Id, flags (recursion desired), one question.
+    let mut query = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid host name '{host}'");
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&kind.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+/// The `kind` records in a DNS answer, and the shortest TTL among them.
+fn decode_answer(msg: &[u8], kind: u16) -> Result<(Vec<IpAddr>, Duration)> {
+    let u16_at = |at: usize| -> Result<u16> {
+        let bytes = msg.get(at..at + 2).context("answer too short")?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let rcode = u16_at(2)? & 0xf;
+    match rcode {
+        0 => {}
+        3 => return Ok((Vec::new(), CACHE_TTL)),
+        _ => bail!("DNS error {rcode}"),
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(msg, at)? + 4;
+    }
+    let mut addrs = Vec::new();
+    let mut ttl = CACHE_TTL;
+    for _ in 0..answers {
+        at = skip_name(msg, at)?;
+        let (record, len) = (u16_at(at)?, u16_at(at + 8)? as usize);
+        let secs = u32::from(u16_at(at + 4)?) << 16 | u32::from(u16_at(at + 6)?);
+        let data = msg
+            .get(at + 10..at + 10 + len)
+            .context("answer too short")?;
+        at += 10 + len;
+        let addr = match (record, data.len()) {
+            (A, 4) if kind == A => IpAddr::from(<[u8; 4]>::try_from(data)?),
+            (AAAA, 16) if kind == AAAA => IpAddr::from(<[u8; 16]>::try_from(data)?),
+            // CNAMEs on the way, signatures and the like.
+            _ => continue,
+        };
+        addrs.push(addr);
+        ttl = ttl.min(Duration::from_secs(secs.into()));
+    }
+    Ok((addrs, ttl))
+}
+
+/// The offset right after the name at `at`.
+fn skip_name(msg: &[u8], mut at: usize) -> Result<usize> {
+    loop {
+        let len = *msg.get(at).context("answer too short")?;
+        match len {
+            0 => return Ok(at + 1),
+            // A pointer to a name further up ends this one.
+            _ if len & 0xc0 == 0xc0 => return Ok(at + 2),
+            _ => at += 1 + usize::from(len),
+        }
+    }
+}
+
+/// The body of a chunked response.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .context("cut-off chunk")?;
+        let size = std::str::from_utf8(&body[..line_end])?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).context("invalid chunk size")?;
+        if size == 0 {
+            return Ok(out);
+        }
+        let chunk = body
+            .get(line_end + 2..line_end + 2 + size)
+            .context("cut-off chunk")?;
+        out.extend_from_slice(chunk);
+        body = body.get(line_end + 4 + size..).context("cut-off chunk")?;
+    }
+}
@@ -0,0 +1,312 @@
+//! Rules for the requests of HTTP tunnels: basic auth (`--http-auth`) and
+//! header rewriting (`--add-header`, `--remove-header`, `--host-rewrite`).
+//!
+//! Requests from visitors are parsed on their way to the local service, with
+//! the same parser as [`inspect`](crate::inspect), and each request head is
+//! checked and rewritten; bodies pass unchanged. Responses are not touched.
+//! After a protocol upgrade such as WebSocket or h2c, or anything that
+//! doesn't parse as HTTP/1.x, the bytes are passed through as they are. That
+//! includes HTTP/2 with prior knowledge, whose headers are not rewritten; it
+//! has no head to find credentials in, so with `--http-auth` it is refused.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sshx_core::auth::same_secret;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::inspect::{Framing, Handler, Head, Parser};
+
+/// What requests must carry, and how their heads are changed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RequestRules {
+    /// `user:password` that visitors must send with basic auth. Their
+    /// `Authorization` header doesn't reach the local service.
+    pub(crate) credentials: Option<String>,
+    /// Set on every request, replacing any the visitor sent.
+    pub(crate) add: Vec<(String, String)>,
+    /// Dropped from every request.
+    pub(crate) remove: Vec<String>,
+    /// Replaces the `Host` header.
+    pub(crate) host: Option<String>,
+}
+
+impl RequestRules {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.credentials.is_none()
+            && self.add.is_empty()
+            && self.remove.is_empty()
+            && self.host.is_none()
+    }
+
+    /// Refuse names and values that would break the request apart.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let token = |name: &str| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+        };
+        let mut names = self.add.iter().map(|(name, _)| name).chain(&self.remove);
+        if let Some(name) = names.find(|name| !token(name)) {
+            bail!("invalid header name '{name}'");
+        }
+        let mut values = self.add.iter().map(|(_, value)| value).chain(&self.host);
+        if let Some(value) = values.find(|v| v.contains(['\r', '\n'])) {
+            bail!("header value {value:?} spans several lines");
+        }
+        if self.credentials.as_ref().is_some_and(|c| !c.contains(':')) {
+            bail!("HTTP auth credentials must be user:password");
+        }
+        Ok(())
+    }
+
+    /// Whether a request with `headers` may pass.
+    fn admits(&self, headers: &[(String, String)]) -> bool {
+        let Some(credentials) = &self.credentials else {
+            return true;
+        };
+        headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            .filter_map(|(_, value)| value.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+            .filter_map(|(_, token)| STANDARD.decode(token.trim()).ok())
+            .any(|sent| same_secret(&sent, credentials.as_bytes()))
+    }
+
+    /// Whether a header called `name` is dropped from requests.
+    fn drops(&self, name: &str) -> bool {
+        let name = name.trim();
+        (self.host.is_some() && name.eq_ignore_ascii_case("host"))
+            || (self.credentials.is_some() && name.eq_ignore_ascii_case("authorization"))
+            || self
+                .add
+                .iter()
+                .map(|(n, _)| n)
+                .chain(&self.remove)
+                .any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    /// Append the rewritten form of the request head `raw` to `out`.
+    fn apply(&self, raw: &[u8], out: &mut Vec<u8>) {
+        let mut lines = raw.split_inclusive(|&b| b == b'\n');
+        out.extend_from_slice(lines.next().unwrap_or_default());
+        let mut dropping = false;
+        for line in lines {
+            if line == b"\r\n" || line == b"\n" {
+                break;
+            }
+            // Folded continuation lines belong to the header above.
+            if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+                let name = line.split(|&b| b == b':').next().unwrap_or_default();
+                dropping = self.drops(&String::from_utf8_lossy(name));
+            }
+            if !dropping {
+                out.extend_from_slice(line);
+            }
+        }
+        if let Some(host) = &self.host {
+            out.extend_from_slice(format!("Host: {host}\r\n").as_bytes());
+        }
+        for (name, value) in &self.add {
+            out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Collects the rewritten bytes of what the parser is fed.
+struct Rewrite<'a> {
+    rules: &'a RequestRules,
+    out: &'a mut Vec<u8>,
+    verdict: &'a mut Verdict,
+}
+
+/// Whether the requests so far were let through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// No complete request head yet.
+    Pending,
+    Admitted,
+    /// A request lacked the credentials; nothing after it passes.
+    Denied,
+}
+
+impl Handler for Rewrite<'_> {
+    fn is_request(&self) -> bool {
+        true
+    }
+
+    fn head(&mut self, head: Head, raw: &[u8]) -> Framing {
+        if !self.rules.admits(&head.headers) {
+            *self.verdict = Verdict::Denied;
+            return Framing::Opaque;
+        }
+        *self.verdict = Verdict::Admitted;
+        self.rules.apply(raw, self.out);
+        let upgrade = head
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("upgrade"));
+        let connect = head.request.is_some_and(|(method, _)| method == "CONNECT");
+        match Framing::of(&head.headers) {
+            // What follows may not be HTTP any more.
+            _ if upgrade || connect => Framing::Opaque,
+            // A request without a length has no body.
+            Framing::UntilClose => Framing::Empty,
+            framing => framing,
+        }
+    }
+
+    fn body(&mut self, _data: &[u8]) {}
+
+    fn end(&mut self) {}
+
+    fn raw(&mut self, data: &[u8]) {
+        // Before any head, these are bytes that didn't parse.
+        let pending = *self.verdict == Verdict::Pending && !data.is_empty();
+        if pending && self.rules.credentials.is_some() {
+            *self.verdict = Verdict::Denied;
+        }
+        if *self.verdict != Verdict::Denied {
+            self.out.extend_from_slice(data);
+        }
+    }
+}
+
+/// A visitor connection whose requests are checked and rewritten as they
+/// are read. Without rules it is the connection as it is. Once a request is
+/// denied, reading fails.
+pub(crate) struct Rewriting<'a, S> {
+    io: S,
+    rules: Option<&'a RequestRules>,
+    parser: Parser,
+    verdict: Verdict,
+    /// Rewritten bytes not read yet.
+    pending: Vec<u8>,
+    /// How much of `pending` has been read.
+    read: usize,
+}
+
+impl<'a, S> Rewriting<'a, S> {
+    pub(crate) fn new(io: S, rules: Option<&'a RequestRules>) -> Self {
+        Self {
+            io,
+            rules: rules.filter(|r| !r.is_empty()),
+            parser: Parser::default(),
+            verdict: Verdict::Pending,
+            pending: Vec::new(),
+            read: 0,
+        }
+    }
+
+    /// Rewrite bytes that were read from the connection earlier.
+    pub(crate) fn rewrite(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let Some(rules) = self.rules else {
+            return data;
+        };
+        let mut out = Vec::with_capacity(data.len());
+        let mut rewrite = Rewrite {
+            rules,
+            out: &mut out,
+            verdict: &mut self.verdict,
+        };
+        self.parser.feed(&data, &mut rewrite);
+        out
+    }
+
+    /// Whether visitors must authenticate.
+    pub(crate) fn is_guarded(&self) -> bool {
+        self.rules.is_some_and(|r| r.credentials.is_some())
+    }
+
+    pub(crate) fn verdict(&self) -> Verdict {
+        self.verdict
+    }
+
+    /// The connection, if nothing is rewritten on the way in.
+    pub(crate) fn passthrough(&self) -> Option<&S> {
+        self.rules.is_none().then_some(&self.io)
+    }
+}
+
+/// What a visitor without the credentials gets.
+pub(crate) fn unauthorized(realm: &str) -> Vec<u8> {
+    let realm = realm.replace('"', "");
+    let body = "This tunnel needs a user name and password.\n";
+    format!(
+        "HTTP/1.1 401 Unauthorized\r\n\
+         WWW-Authenticate: Basic realm=\"{realm}\", charset=\"UTF-8\"\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .into_bytes()
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewriting<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(rules) = this.rules else {
+            return Pin::new(&mut this.io).poll_read(cx, buf);
+        };
+        // A head is passed on whole, so it may take several reads.
+        while this.read == this.pending.len() {
+            if this.verdict == Verdict::Denied {
+                let denied = io::Error::new(io::ErrorKind::PermissionDenied, "request denied");
+                return Poll::Ready(Err(denied));
+            }
+            this.pending.clear();
+            this.read = 0;
+            let mut bytes = [0; 8192];
+            let mut chunk = ReadBuf::new(&mut bytes);
+            std::task::ready!(Pin::new(&mut this.io).poll_read(cx, &mut chunk))?;
+            let mut rewrite = Rewrite {
+                rules,
+                out: &mut this.pending,
+                verdict: &mut this.verdict,
+            };
+            if chunk.filled().is_empty() {
+                this.parser.finish(&mut rewrite);
+                if this.pending.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                break;
+            }
+            this.parser.feed(chunk.filled(), &mut rewrite);
+        }
+        let n = buf.remaining().min(this.pending.len() - this.read);
+        buf.put_slice(&this.pending[this.read..this.read + n]);
+        this.read += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewriting<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
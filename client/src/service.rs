@@ -0,0 +1,421 @@
+//! `sshx service`: keep the tunnels of a config file profile up in the
+//! background, so they start without a terminal and outlive it.
+//!
+//! On Linux the profile becomes a systemd user unit, `sshx-<profile>`, which
+//! also runs while logged out once lingering is enabled. On macOS it is a
+//! launchd agent, `sshx.<profile>`, started at login. On Windows it is a
+//! service, `sshx-<profile>`, started at boot; installing it needs an
+//! administrator. The service runs `sshx up <profile>` with the config file
+//! it was installed from, so later edits to that file apply on restart.
+
+use std::{env, path::Path};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::Subcommand;
+
+use crate::config::Config;
+
+#[derive(Subcommand, Clone)]
+pub enum Action {
+    /// Register the tunnels of a profile to start in the background, and
+    /// start them.
+    Install {
+        /// Name of the profile, as in `[profiles.NAME]`.
+        profile: String,
+    },
+    /// Stop the tunnels of a profile and remove the service.
+    Uninstall { profile: String },
+    /// Start an installed service.
+    Start { profile: String },
+    /// Stop an installed service until the next start or boot.
+    Stop { profile: String },
+    /// Run as the Windows service; started by the service manager.
+    #[command(hide = true)]
+    Run { profile: String },
+}
+
+/// Carry out `action`, except `Run`. `config` is the `--config` file, if
+/// one was given.
+pub fn manage(action: &Action, config: Option<&Path>) -> Result<()> {
+    match action {
+        Action::Install { profile } => {
+            check_name(profile)?;
+            let config = match config {
+                Some(path) => path.to_owned(),
+                None => Config::default_path().context("cannot find the config directory")?,
+            };
+            let config = config
+                .canonicalize()
+                .with_context(|| format!("cannot read {}", config.display()))?;
+            // Fail now rather than in the background.
+            if !Config::load(Some(&config))?.has_profile(profile) {
+                bail!("no profile '{profile}' in {}", config.display());
+            }
+            let exe = env::current_exe().context("cannot find the sshx binary")?;
+            install(profile, &exe, &config)?;
+            println!("  ✓  Installed the sshx service for profile '{profile}'");
+            Ok(())
+        }
+        Action::Uninstall { profile } => uninstall(profile),
+        Action::Start { profile } => start(profile),
+        Action::Stop { profile } => stop(profile),
+        Action::Run { .. } => bail!("`sshx service run` is for the Windows service manager"),
+    }
+}
+
+/// Profile names end up in file and service names.
+fn check_name(profile: &str) -> Result<()> {
+    let valid = profile
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if profile.is_empty() || !valid {
+        bail!("profile '{profile}' needs a name of letters, digits, '-' and '_' to be a service");
+    }
+    Ok(())
+}
+
+/// Run `program` with `args`, failing with its output if it fails.
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("cannot run {program}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{program} {} failed: {}", args.join(" "), stderr.trim());
+    }
+    Ok(())
+}
+
+// ── Linux: systemd user units ─────────────────────────────────────────────────
+
+#[cfg(target_os = "linux")]
+fn unit_name(profile: &str) -> String {
+    format!("sshx-{profile}.service")
+}
+
+#[cfg(target_os = "linux")]
+fn unit_path(profile: &str) -> Result<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))
+        .context("cannot find the config directory")?;
+    Ok(base.join("systemd/user").join(unit_name(profile)))
+}
+
+#[cfg(target_os = "linux")]
+fn install(profile: &str, exe: &Path, config: &Path) -> Result<()> {
+    let unit = format!(
+        "[Unit]\n\
+         Description=sshx tunnels of profile {profile}\n\
+         After=network-online.target\n\
+         Wants=network-online.target\n\
+         \n\
+         [Service]\n\
+         ExecStart=\"{}\" --config \"{}\" up {profile}\n\
+         Restart=always\n\
+         RestartSec=5\n\
+         \n\
+         [Install]\n\
+         WantedBy=default.target\n",
+        exe.display(),
+        config.display()
+    );
+    let path = unit_path(profile)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    }
+    fs::write(&path, unit).with_context(|| format!("cannot write {}", path.display()))?;
+    run("systemctl", &["--user", "daemon-reload"])?;
+    run(
+        "systemctl",
+        &["--user", "enable", "--now", &unit_name(profile)],
+    )?;
+    // Without lingering, user units stop at logout and wait for a login.
+    if let Err(e) = run("loginctl", &["enable-linger"]) {
+        println!("  ⚠  The tunnels only run while you are logged in: {e:#}");
+        println!("     Ask an administrator for `loginctl enable-linger $USER`.");
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn uninstall(profile: &str) -> Result<()> {
+    let path = unit_path(profile)?;
+    if !path.exists() {
+        bail!("no sshx service for profile '{profile}'");
+    }
+    run(
+        "systemctl",
+        &["--user", "disable", "--now", &unit_name(profile)],
+    )?;
+    fs::remove_file(&path).with_context(|| format!("cannot remove {}", path.display()))?;
+    run("systemctl", &["--user", "daemon-reload"])
+}
+
+#[cfg(target_os = "linux")]
+fn start(profile: &str) -> Result<()> {
+    run("systemctl", &["--user", "start", &unit_name(profile)])
+}
+
+#[cfg(target_os = "linux")]
+fn stop(profile: &str) -> Result<()> {
+    run("systemctl", &["--user", "stop", &unit_name(profile)])
+}
+
+// ── macOS: launchd agents ─────────────────────────────────────────────────────
+
+#[cfg(target_os = "macos")]
+fn label(profile: &str) -> String {
+    format!("sshx.{profile}")
+}
+
+#[cfg(target_os = "macos")]
+fn plist_path(profile: &str) -> Result<PathBuf> {
+    let home = dirs::home_dir().context("cannot find the home directory")?;
+    Ok(home
+        .join("Library/LaunchAgents")
+        .join(format!("{}.plist", label(profile))))
+}
+
+#[cfg(target_os = "macos")]
+fn install(profile: &str, exe: &Path, config: &Path) -> Result<()> {
+    let escape = |s: &str| {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let home = dirs::home_dir().context("cannot find the home directory")?;
+    let log = home.join(format!("Library/Logs/sshx-{profile}.log"));
+    let args = [
+        exe.display().to_string(),
+        "--config".into(),
+        config.display().to_string(),
+        "up".into(),
+        profile.into(),
+    ];
+    let args: String = args
+        .iter()
+        .map(|arg| format!("        <string>{}</string>\n", escape(arg)))
+        .collect();
+    let plist = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+         \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n{args}\x20   </array>\n\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>KeepAlive</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>StandardOutPath</key>\n\
+         \x20   <string>{log}</string>\n\
+         \x20   <key>StandardErrorPath</key>\n\
+         \x20   <string>{log}</string>\n\
+         </dict>\n\
+         </plist>\n",
+        label(profile),
+        log = escape(&log.display().to_string()),
+    );
+    let path = plist_path(profile)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    }
+    fs::write(&path, plist).with_context(|| format!("cannot write {}", path.display()))?;
+    run("launchctl", &["load", "-w", &path.display().to_string()])
+}
+
+#[cfg(target_os = "macos")]
+fn uninstall(profile: &str) -> Result<()> {
+    let path = plist_path(profile)?;
+    if !path.exists() {
+        bail!("no sshx service for profile '{profile}'");
+    }
+    run("launchctl", &["unload", "-w", &path.display().to_string()])?;
+    fs::remove_file(&path).with_context(|| format!("cannot remove {}", path.display()))
+}
+
+#[cfg(target_os = "macos")]
+fn start(profile: &str) -> Result<()> {
+    run(
+        "launchctl",
+        &["load", &plist_path(profile)?.display().to_string()],
+    )
+}
+
+#[cfg(target_os = "macos")]
+fn stop(profile: &str) -> Result<()> {
+    // Unloaded, KeepAlive can't bring it back; it loads again at login.
+    run(
+        "launchctl",
+        &["unload", &plist_path(profile)?.display().to_string()],
+    )
+}
+
+// ── Windows: services ─────────────────────────────────────────────────────────
+
+#[cfg(windows)]
+fn service_name(profile: &str) -> String {
+    format!("sshx-{profile}")
+}
+
+#[cfg(windows)]
+fn install(profile: &str, exe: &Path, config: &Path) -> Result<()> {
+    let name = service_name(profile);
+    let bin_path = format!(
+        "\"{}\" --config \"{}\" service run {profile}",
+        exe.display(),
+        config.display()
+    );
+    let display_name = format!("sshx ({profile})");
+    run(
+        "sc.exe",
+        &[
+            "create",
+            &name,
+            "binPath=",
+            &bin_path,
+            "start=",
+            "auto",
+            "DisplayName=",
+            &display_name,
+        ],
+    )?;
+    // Restart after a crash, like Restart=always elsewhere.
+    run(
+        "sc.exe",
+        &[
+            "failure",
+            &name,
+            "reset=",
+            "86400",
+            "actions=",
+            "restart/5000",
+        ],
+    )?;
+    run("sc.exe", &["start", &name])
+}
+
+#[cfg(windows)]
+fn uninstall(profile: &str) -> Result<()> {
+    let name = service_name(profile);
+    // Already stopped is fine.
+    let _ = run("sc.exe", &["stop", &name]);
+    run("sc.exe", &["delete", &name])
+}
+
+#[cfg(windows)]
+fn start(profile: &str) -> Result<()> {
+    run("sc.exe", &["start", &service_name(profile)])
+}
+
+#[cfg(windows)]
+fn stop(profile: &str) -> Result<()> {
+    run("sc.exe", &["stop", &service_name(profile)])
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn install(_profile: &str, _exe: &Path, _config: &Path) -> Result<()> {
+    bail!("`sshx service` supports Linux, macOS and Windows")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn uninstall(profile: &str) -> Result<()> {
+    install(profile, Path::new(""), Path::new(""))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn start(profile: &str) -> Result<()> {
+    uninstall(profile)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn stop(profile: &str) -> Result<()> {
+    uninstall(profile)
+}
+
+/// The Windows service side of `sshx service run`: report to the service
+/// manager and turn its stop request into a shutdown.
+#[cfg(windows)]
+pub mod windows {
+    use std::{ffi::OsString, process::ExitCode, sync::LazyLock, time::Duration};
+
+    use tokio_util::sync::CancellationToken;
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher,
+    };
+
+    /// Cancelled when the service manager asks us to stop.
+    pub static STOP: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Hand this process to the service manager, which calls back into
+    /// `service_main` and returns once the service stopped.
+    pub fn dispatch() -> ExitCode {
+        // The name is ignored for a service in a process of its own.
+        match service_dispatcher::start("sshx", ffi_service_main) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: cannot start as a service: {e}");
+                ExitCode::FAILURE
+            }
+        }
+    }
+
+    fn service_main(_args: Vec<OsString>) {
+        let handler = |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                STOP.cancel();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let Ok(status) = service_control_handler::register("sshx", handler) else {
+            return;
+        };
+        let report = |state, exit_code| {
+            let _ = status.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted: match state {
+                    ServiceState::Running => {
+                        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+                    }
+                    _ => ServiceControlAccept::empty(),
+                },
+                exit_code: ServiceExitCode::Win32(exit_code),
+                checkpoint: 0,
+                wait_hint: Duration::from_secs(10),
+                process_id: None,
+            });
+        };
+        report(ServiceState::Running, 0);
+        let code = match tokio::runtime::Runtime::new() {
+            Ok(runtime) => {
+                let code = runtime.block_on(crate::run());
+                runtime.shutdown_background();
+                code
+            }
+            Err(_) => ExitCode::FAILURE,
+        };
+        report(
+            ServiceState::Stopped,
+            if code == ExitCode::SUCCESS { 0 } else { 1 },
+        );
+    }
+}
@@ -0,0 +1,486 @@
+//! Static file sharing (`sshx share`): instead of forwarding to a local
+//! service, the client answers HTTP visitors itself from a directory.
+//!
+//! `GET` and `HEAD` serve files, and list directories without an
+//! `index.html`. With uploads on, `PUT` stores a new file of up to
+//! [`Share::max_upload`] bytes, and the listings get a form that does so. An
+//! upload is written next to its file and renamed once complete. Paths never
+//! leave the directory, through `..` or a symlink. Basic auth is
+//! `--http-auth`, checked before any of this.
+
+use std::{
+    fmt::Write as _,
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::inspect::escape;
+
+const MAX_HEAD: usize = 64 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Largest upload unless told otherwise.
+pub const DEFAULT_MAX_UPLOAD: u64 = 100 << 20;
+
+/// A directory to share.
+#[derive(Debug, Clone)]
+pub struct Share {
+    root: PathBuf,
+    uploads: bool,
+    max_upload: u64,
+}
+
+impl Share {
+    /// Share the files below `root`, which must be a directory.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            uploads: false,
+            max_upload: DEFAULT_MAX_UPLOAD,
+        }
+    }
+
+    /// Let visitors add files with `PUT` [default: false]. Existing files
+    /// are never overwritten.
+    pub fn uploads(mut self, uploads: bool) -> Self {
+        self.uploads = uploads;
+        self
+    }
+
+    /// Refuse uploads bigger than `bytes` [default: [`DEFAULT_MAX_UPLOAD`]],
+    /// so visitors can't fill the disk.
+    pub fn max_upload(mut self, bytes: u64) -> Self {
+        self.max_upload = bytes;
+        self
+    }
+
+    /// Check that the root is a directory, and resolve it for telling
+    /// whether paths stay below it.
+    pub(crate) fn open(self) -> Result<Self> {
+        let root = self
+            .root
+            .canonicalize()
+            .with_context(|| format!("cannot share {}", self.root.display()))?;
+        if !root.is_dir() {
+            bail!("cannot share {}: not a directory", root.display());
+        }
+        Ok(Self { root, ..self })
+    }
+}
+
+/// One request head.
+struct Request {
+    method: String,
+    /// Still percent-encoded, without the query.
+    path: String,
+    content_length: Option<u64>,
+    chunked: bool,
+    keep_alive: bool,
+}
+
+/// Answer the requests on `io` until the visitor closes it or asks to.
+/// `buffered` already came from `io`. Returns the bytes read and written.
+pub(crate) async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    io: &mut S,
+    mut buffered: Vec<u8>,
+    share: &Share,
+) -> Result<(u64, u64)> {
+    let (mut read, mut written) = (buffered.len() as u64, 0);
+    loop {
+        let (len, request) = loop {
+            match parse_head(&buffered) {
+                Ok(Some(head)) => break head,
+                Ok(None) if buffered.len() < MAX_HEAD => {}
+                _ => {
+                    written += respond(io, 400, "text/plain", b"bad request\n", false).await?;
+                    return Ok((read, written));
+                }
+            }
+            let n = io.read_buf(&mut buffered).await?;
+            if n == 0 {
+                return Ok((read, written));
+            }
+            read += n as u64;
+        };
+        buffered.drain(..len);
+
+        let keep_alive = request.keep_alive;
+        written += match request.method.as_str() {
+            "GET" | "HEAD" => get(io, share, &request).await?,
+            // Without a length, the body can't be told from the next request.
+            "PUT" if share.uploads && (request.chunked || request.content_length.is_none()) => {
+                written += respond(io, 411, "text/plain", b"length required\n", false).await?;
+                io.shutdown().await?;
+                return Ok((read, written));
+            }
+            // Not worth reading the body past.
+            "PUT" if share.uploads && request.content_length > Some(share.max_upload) => {
+                written += respond(io, 413, "text/plain", b"file too large\n", false).await?;
+                io.shutdown().await?;
+                return Ok((read, written));
+            }
+            "PUT" if share.uploads => {
+                let (n, body_read) = put(io, share, &request, &mut buffered).await?;
+                read += body_read;
+                n
+            }
+            _ => respond(io, 405, "text/plain", b"method not allowed\n", keep_alive).await?,
+        };
+        if !keep_alive {
+            io.shutdown().await?;
+            return Ok((read, written));
+        }
+    }
+}
+
+fn parse_head(buf: &[u8]) -> Result<Option<(usize, Request)>, httparse::Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let httparse::Status::Complete(len) = req.parse(buf)? else {
+        return Ok(None);
+    };
+    let header = |name: &str| {
+        req.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| String::from_utf8_lossy(h.value).trim().to_ascii_lowercase())
+    };
+    let connection = header("connection");
+    let keep_alive = match req.version {
+        Some(1) => connection.as_deref() != Some("close"),
+        _ => connection.as_deref() == Some("keep-alive"),
+    };
+    let request = Request {
+        method: req.method.unwrap_or_default().to_owned(),
+        path: req
+            .path
+            .unwrap_or("/")
+            .split(['?', '#'])
+            .next()
+            .unwrap_or("/")
+            .to_owned(),
+        content_length: header("content-length").and_then(|v| v.parse().ok()),
+        chunked: header("transfer-encoding").is_some_and(|v| v.contains("chunked")),
+        keep_alive,
+    };
+    Ok(Some((len, request)))
+}
+
+/// Where the request path leads below the root, if it stays there.
+fn resolve(share: &Share, path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(path)?;
+    let mut resolved = share.root.clone();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// Whether `path`, once its symlinks are followed, is still below the root.
+fn contained(share: &Share, path: &Path) -> bool {
+    path.canonicalize()
+        .is_ok_and(|real| real.starts_with(&share.root))
+}
+
+async fn get<S: AsyncWrite + Unpin>(io: &mut S, share: &Share, request: &Request) -> Result<u64> {
+    let head_only = request.method == "HEAD";
+    let keep_alive = request.keep_alive;
+    let Some(mut path) = resolve(share, &request.path).filter(|p| contained(share, p)) else {
+        return respond(io, 404, "text/plain", b"not found\n", keep_alive).await;
+    };
+    if path.is_dir() {
+        // Relative links in a listing need the slash.
+        if !request.path.ends_with('/') {
+            let location = format!("{}/", request.path);
+            return redirect(io, &location, keep_alive).await;
+        }
+        let index = path.join("index.html");
+        if !index.is_file() {
+            let page = listing(share, &path, &request.path).await?;
+            let head = head(
+                200,
+                "text/html; charset=utf-8",
+                page.len() as u64,
+                keep_alive,
+                None,
+            );
+            io.write_all(head.as_bytes()).await?;
+            if !head_only {
+                io.write_all(page.as_bytes()).await?;
+            }
+            io.flush().await?;
+            return Ok((head.len() + if head_only { 0 } else { page.len() }) as u64);
+        }
+        path = index;
+    }
+    let mut file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return respond(io, 404, "text/plain", b"not found\n", keep_alive).await,
+    };
+    let len = file.metadata().await?.len();
+    let head = head(200, content_type(&path), len, keep_alive, None);
+    io.write_all(head.as_bytes()).await?;
+    let mut written = head.len() as u64;
+    if !head_only {
+        written += tokio::io::copy(&mut file, io).await?;
+    }
+    io.flush().await?;
+    Ok(written)
+}
+
+/// Store the request body as a new file. Returns the bytes written and the
+/// body bytes read from `io`, past `buffered`.
+async fn put<S: AsyncRead + AsyncWrite + Unpin>(
+    io: &mut S,
+    share: &Share,
+    request: &Request,
+    buffered: &mut Vec<u8>,
+) -> Result<(u64, u64)> {
+    let keep_alive = request.keep_alive;
+    let len = request.content_length.unwrap_or(0);
+    // The body has to be read past whatever the answer is.
+    let from_buffer = (len as usize).min(buffered.len());
+    let body_start: Vec<u8> = buffered.drain(..from_buffer).collect();
+    let mut body = io::Cursor::new(body_start).chain((&mut *io).take(len - from_buffer as u64));
+
+    let target = resolve(share, &request.path)
+        .filter(|p| p.file_name().is_some())
+        .filter(|p| p.parent().is_some_and(|dir| contained(share, dir)));
+    let created = match &target {
+        Some(path) => {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .await
+        }
+        None => Err(io::ErrorKind::NotFound.into()),
+    };
+    let (status, message): (u16, &[u8]) = match (created, &target) {
+        (Ok(placeholder), Some(path)) => {
+            // The empty file holds the name until the upload replaces it.
+            drop(placeholder);
+            let partial = partial_path(path);
+            if let Err(e) = store(&mut body, len, &partial, path).await {
+                let _ = fs::remove_file(&partial).await;
+                let _ = fs::remove_file(path).await;
+                return Err(e);
+            }
+            (201, b"created\n")
+        }
+        (Err(e), _) if e.kind() == io::ErrorKind::AlreadyExists => {
+            tokio::io::copy(&mut body, &mut tokio::io::sink()).await?;
+            (409, b"a file by that name exists\n")
+        }
+        _ => {
+            tokio::io::copy(&mut body, &mut tokio::io::sink()).await?;
+            (404, b"not found\n")
+        }
+    };
+    let read = len - from_buffer as u64;
+    let written = respond(io, status, "text/plain", message, keep_alive).await?;
+    Ok((written, read))
+}
+
+/// Write the `len` bytes of `body` to `partial`, then move it to `path`
+/// once they all came.
+async fn store<R: AsyncRead + Unpin>(
+    body: &mut R,
+    len: u64,
+    partial: &Path,
+    path: &Path,
+) -> Result<()> {
+    let mut file = File::create(partial).await?;
+    let copied = tokio::io::copy(body, &mut file).await?;
+    if copied < len {
+        bail!("the upload broke off after {copied} of {len} bytes");
+    }
+    file.flush().await?;
+    drop(file);
+    fs::rename(partial, path).await?;
+    Ok(())
+}
+
+/// A hidden name next to `path` for the upload on its way there.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{}.partial", Uuid::new_v4().simple()))
+}
+
+/// An HTML list of the entries in `dir`, reached as `path`.
+async fn listing(share: &Share, dir: &Path, path: &str) -> Result<String> {
+    let mut entries = Vec::new();
+    let mut read = fs::read_dir(dir).await?;
+    while let Some(entry) = read.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_dir = entry.file_type().await.is_ok_and(|t| t.is_dir());
+        entries.push((!is_dir, name));
+    }
+    // Directories first, then by name.
+    entries.sort();
+
+    let title = escape(&percent_decode(path).unwrap_or_else(|| path.to_owned()));
+    let mut page = format!(
+        "<!doctype html><meta charset=utf-8><title>{title}</title>\
+         <style>body{{font-family:sans-serif;margin:2em}}li{{line-height:1.6}}</style>\
+         <h1>{title}</h1><ul>"
+    );
+    if path != "/" {
+        page.push_str("<li><a href=\"../\">../</a>");
+    }
+    for (is_file, name) in entries {
+        let slash = if is_file { "" } else { "/" };
+        let _ = write!(
+            page,
+            "<li><a href=\"{}{slash}\">{}{slash}</a>",
+            percent_encode(&name),
+            escape(&name),
+        );
+    }
+    page.push_str("</ul>");
+    if share.uploads {
+        page.push_str(UPLOAD_FORM);
+    }
+    Ok(page)
+}
+
+/// PUTs the picked files next to the listing, then reloads it.
+const UPLOAD_FORM: &str = "<form><input type=file multiple id=f> <button type=button \
+    onclick=\"Promise.all([...f.files].map(x=>fetch(encodeURIComponent(x.name),\
+    {method:'PUT',body:x}))).then(()=>location.reload())\">Upload</button></form>";
+
+async fn redirect<S: AsyncWrite + Unpin>(
+    io: &mut S,
+    location: &str,
+    keep_alive: bool,
+) -> Result<u64> {
+    let head = head(301, "text/plain", 0, keep_alive, Some(location));
+    io.write_all(head.as_bytes()).await?;
+    io.flush().await?;
+    Ok(head.len() as u64)
+}
+
+async fn respond<S: AsyncWrite + Unpin>(
+    io: &mut S,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+    keep_alive: bool,
+) -> Result<u64> {
+    let head = head(status, content_type, body.len() as u64, keep_alive, None);
+    io.write_all(head.as_bytes()).await?;
+    io.write_all(body).await?;
+    io.flush().await?;
+    Ok((head.len() + body.len()) as u64)
+}
+
+fn head(
+    status: u16,
+    content_type: &str,
+    len: u64,
+    keep_alive: bool,
+    location: Option<&str>,
+) -> String {
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        301 => "Moved Permanently",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        _ => "",
+    };
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let mut head = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {len}\r\nConnection: {connection}\r\n"
+    );
+    if let Some(location) = location {
+        let _ = write!(head, "Location: {location}\r\n");
+    }
+    head.push_str("\r\n");
+    head
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt" | "md" | "log" | "csv") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("woff2") => "font/woff2",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// `%XX` escapes decoded; `None` if that isn't valid UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Escape `name` for a link to it.
+fn percent_encode(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => {
+                let _ = write!(out, "%{byte:02X}");
+            }
+        }
+    }
+    out
+}
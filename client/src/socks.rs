@@ -0,0 +1,224 @@
+//! SOCKS5 gateway: instead of one local service, a visitor names the host
+//! and port it wants to reach, and the client connects it there if an
+//! [`AllowRule`] permits the destination.
+//!
+//! Only CONNECT without authentication is spoken; the tunnel's secret and
+//! ACL already decide who gets this far.
+//!
+//! Spec: <https://www.rfc-editor.org/rfc/rfc1928>
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use sshx_core::protocol::IpNet;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// Reply codes.
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NOT_ALLOWED: u8 = 0x02;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Destinations a visitor may be connected to: a network, and optionally
+/// the ports on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowRule {
+    net: IpNet,
+    /// Every port when empty.
+    ports: Vec<RangeInclusive<u16>>,
+}
+
+impl AllowRule {
+    pub fn permits(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.net.contains(&ip)
+            && (self.ports.is_empty() || self.ports.iter().any(|p| p.contains(&addr.port())))
+    }
+}
+
+/// Parses `CIDR[:PORTS]`, where `PORTS` is a comma-separated list of ports
+/// and ranges, e.g. `10.0.0.0/8:22,8000-8999`. A bare address stands for
+/// itself alone.
+impl FromStr for AllowRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // IPv6 addresses are full of colons; ports follow the prefix length.
+        let (net, ports) = match s.split_once('/') {
+            Some((ip, rest)) => match rest.split_once(':') {
+                Some((len, ports)) => (format!("{ip}/{len}"), Some(ports)),
+                None => (s.to_owned(), None),
+            },
+            None if s.parse::<IpAddr>().is_ok() => (s.to_owned(), None),
+            None => match s.rsplit_once(':') {
+                Some((ip, ports)) => (ip.to_owned(), Some(ports)),
+                None => (s.to_owned(), None),
+            },
+        };
+        let net = net
+            .parse::<IpNet>()
+            .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+            .with_context(|| format!("invalid network '{net}' in '{s}'"))?;
+        let ports = match ports {
+            Some(ports) => ports
+                .split(',')
+                .map(|p| parse_ports(p).with_context(|| format!("invalid ports in '{s}'")))
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        Ok(Self { net, ports })
+    }
+}
+
+impl fmt::Display for AllowRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.net)?;
+        for (i, ports) in self.ports.iter().enumerate() {
+            f.write_str(if i == 0 { ":" } else { "," })?;
+            match ports.start() == ports.end() {
+                true => write!(f, "{}", ports.start())?,
+                false => write!(f, "{}-{}", ports.start(), ports.end())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_ports(s: &str) -> Result<RangeInclusive<u16>> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let (start, end): (u16, u16) = (start.trim().parse()?, end.trim().parse()?);
+    if start > end {
+        bail!("empty port range '{s}'");
+    }
+    Ok(start..=end)
+}
+
+/// Read a visitor's CONNECT request and connect it to the destination,
+/// answering as SOCKS5 does. Returns the connection and where it goes.
+pub(crate) async fn connect<S>(
+    visitor: &mut S,
+    allow: &[AllowRule],
+) -> Result<(TcpStream, SocketAddr)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0; 2];
+    visitor.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        bail!("not a SOCKS5 client (version {})", head[0]);
+    }
+    let mut methods = vec![0; head[1] as usize];
+    visitor.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        visitor.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        bail!("the SOCKS client wants authentication");
+    }
+    visitor.write_all(&[VERSION, NO_AUTH]).await?;
+
+    let mut request = [0; 4];
+    visitor.read_exact(&mut request).await?;
+    let [_, command, _, atyp] = request;
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            visitor.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            visitor.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = visitor.read_u8().await?;
+            let mut name = vec![0; len as usize];
+            visitor.read_exact(&mut name).await?;
+            String::from_utf8(name).context("invalid domain name")?
+        }
+        _ => {
+            reply(visitor, ADDRESS_NOT_SUPPORTED, None).await?;
+            bail!("unknown SOCKS address type {atyp}");
+        }
+    };
+    let port = visitor.read_u16().await?;
+    if command != CONNECT {
+        reply(visitor, COMMAND_NOT_SUPPORTED, None).await?;
+        bail!("unsupported SOCKS command {command}");
+    }
+
+    // Names are resolved here, so every address they stand for is checked.
+    let addrs = match lookup_host((host.as_str(), port)).await {
+        Ok(addrs) => addrs.collect::<Vec<_>>(),
+        Err(e) => {
+            reply(visitor, HOST_UNREACHABLE, None).await?;
+            return Err(e).with_context(|| format!("cannot resolve {host}"));
+        }
+    };
+    let allowed: Vec<_> = addrs
+        .into_iter()
+        .filter(|&addr| allow.iter().any(|rule| rule.permits(addr)))
+        .collect();
+    if allowed.is_empty() {
+        reply(visitor, NOT_ALLOWED, None).await?;
+        bail!("destination {host}:{port} is not allowed");
+    }
+    let mut last_err = None;
+    for addr in allowed {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => {
+                reply(visitor, SUCCEEDED, stream.local_addr().ok()).await?;
+                return Ok((stream, addr));
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    let err = last_err.expect("at least one address was tried");
+    let code = match err.kind() {
+        std::io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        _ => GENERAL_FAILURE,
+    };
+    reply(visitor, code, None).await?;
+    Err(err).with_context(|| format!("cannot connect to {host}:{port}"))
+}
+
+async fn reply<S: AsyncWrite + Unpin>(
+    visitor: &mut S,
+    code: u8,
+    bound: Option<SocketAddr>,
+) -> Result<()> {
+    let bound = bound.unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    let mut out = vec![VERSION, code, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend(ip.octets());
+        }
+    }
+    out.extend(bound.port().to_be_bytes());
+    visitor.write_all(&out).await?;
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! `sshx ssh` and `sshx ssh-config`: reach the SSH server behind a TCP
+//! tunnel with `sshx connect` as the `ProxyCommand`, so the tunnel's public
+//! port never has to be looked up by hand.
+
+use std::{borrow::Cow, env, ffi::OsString, process::ExitCode, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use sshx_client::{status::Stats, Proto};
+use tokio::process::Command;
+
+use crate::Cli;
+
+/// A `[user@]subdomain` destination.
+pub struct Target<'a> {
+    pub user: Option<&'a str>,
+    pub subdomain: &'a str,
+}
+
+impl<'a> Target<'a> {
+    pub fn parse(s: &'a str) -> Self {
+        match s.rsplit_once('@') {
+            Some((user, subdomain)) => Self {
+                user: Some(user),
+                subdomain,
+            },
+            None => Self {
+                user: None,
+                subdomain: s,
+            },
+        }
+    }
+}
+
+/// Run `ssh` through the TCP tunnel of `target`, with `args` passed on
+/// before the destination. Returns ssh's exit code.
+pub async fn run(cli: &Cli, target: &Target<'_>, args: &[OsString]) -> Result<ExitCode> {
+    let (proto, _) = crate::builder(cli, Arc::new(Stats::new()))
+        .lookup(target.subdomain)
+        .await
+        .with_context(|| format!("cannot look up the tunnel '{}'", target.subdomain))?;
+    if proto != Proto::Tcp {
+        bail!("'{}' is not a TCP tunnel", target.subdomain);
+    }
+    let host = host_alias(cli, target.subdomain);
+    let mut ssh = Command::new("ssh");
+    ssh.arg("-o")
+        .arg(format!(
+            "ProxyCommand={}",
+            proxy_command(cli, target.subdomain)?
+        ))
+        .arg("-o")
+        .arg(format!("HostKeyAlias={host}"))
+        .args(args);
+    match target.user {
+        Some(user) => ssh.arg(format!("{user}@{host}")),
+        None => ssh.arg(&host),
+    };
+    // The proxy command inherits the environment: credentials stay off its
+    // command line.
+    if let Some(token) = &cli.token {
+        ssh.env("SSHX_TOKEN", token);
+    } else if let Some(secret) = &cli.secret {
+        ssh.env("SSHX_SECRET", secret);
+    }
+    let status = ssh.status().await.context("cannot run ssh")?;
+    Ok(match status.code() {
+        Some(code) => ExitCode::from(code as u8),
+        None => ExitCode::FAILURE,
+    })
+}
+
+/// A `~/.ssh/config` entry that reaches `target` as `Host <subdomain>`.
+pub fn config(cli: &Cli, target: &Target<'_>) -> Result<String> {
+    let mut entry = format!("Host {}\n", target.subdomain);
+    if let Some(user) = target.user {
+        entry += &format!("    User {user}\n");
+    }
+    entry += &format!("    HostKeyAlias {}\n", host_alias(cli, target.subdomain));
+    entry += &format!(
+        "    ProxyCommand {}\n",
+        proxy_command(cli, target.subdomain)?
+    );
+    Ok(entry)
+}
+
+/// Name the SSH server behind a tunnel goes by, e.g. for its host key.
+fn host_alias(cli: &Cli, subdomain: &str) -> String {
+    format!("{subdomain}.{}", cli.server())
+}
+
+/// This executable, running `sshx connect` with the settings that reach the
+/// server. Secrets are left to the environment and the config file.
+fn proxy_command(cli: &Cli, subdomain: &str) -> Result<String> {
+    let exe = env::current_exe().context("cannot find the sshx executable")?;
+    let mut words = vec![exe.to_string_lossy().into_owned()];
+    if let Some(path) = &cli.config {
+        words.extend(["--config".into(), path.to_string_lossy().into_owned()]);
+    }
+    words.extend(["--server".into(), cli.server().to_owned()]);
+    if let Some(port) = cli.control_port {
+        words.extend(["--control-port".into(), port.to_string()]);
+    }
+    if let Some(ca) = &cli.tls_ca {
+        words.extend(["--tls-ca".into(), ca.to_string_lossy().into_owned()]);
+    } else if cli.tls {
+        words.push("--tls".into());
+    }
+    if let Some(transport) = cli.transport {
+        words.extend(["--transport".into(), transport.to_string()]);
+    }
+    if let Some(peer) = cli.e2e_peer {
+        words.extend(["--e2e-peer".into(), peer.to_string()]);
+    }
+    words.extend(["connect".into(), subdomain.to_owned()]);
+    Ok(words.iter().map(|w| quote(w)).collect::<Vec<_>>().join(" "))
+}
+
+/// Quote `word` for the shell that ssh runs the proxy command with.
+fn quote(word: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@,+".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return Cow::Borrowed(word);
+    }
+    Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+}
@@ -0,0 +1,276 @@
+//! Exit codes, failure classification and the final status summary.
+
+use std::{
+    fmt::{self, Write as _},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sshx_core::protocol::ErrorCode;
+
+/// Failures that wrappers and CI may want to tell apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Missing or wrong secret or token.
+    Auth,
+    /// Someone else holds the requested subdomain.
+    SubdomainTaken,
+    /// The requested public port is taken or outside the server's range.
+    PortUnavailable,
+    /// The server's policy for our identity rules the tunnel out: its
+    /// subdomain, its protocol, or one tunnel too many.
+    Denied,
+    /// The server could not be reached or the connection dropped.
+    Network,
+    /// Anything else.
+    Other,
+}
+
+impl Failure {
+    /// Process exit code; 0 is reserved for a clean shutdown.
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            Self::Other => 1,
+            Self::Auth => 2,
+            Self::SubdomainTaken => 3,
+            Self::Network => 4,
+            Self::PortUnavailable => 5,
+            Self::Denied => 6,
+        })
+    }
+
+    /// Classify an error from the tunnel loop.
+    pub fn of(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(e) = cause.downcast_ref::<TunnelError>() {
+                return e.kind;
+            }
+            if cause.is::<std::io::Error>() || cause.is::<tokio::time::error::Elapsed>() {
+                return Self::Network;
+            }
+        }
+        Self::Other
+    }
+}
+
+impl From<ErrorCode> for Failure {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::AuthRequired | ErrorCode::AuthFailed => Self::Auth,
+            ErrorCode::SubdomainTaken => Self::SubdomainTaken,
+            ErrorCode::PortTaken | ErrorCode::PortOutOfRange | ErrorCode::PortNotPermitted => {
+                Self::PortUnavailable
+            }
+            ErrorCode::SubdomainBanned
+            | ErrorCode::SubdomainNotPermitted
+            | ErrorCode::ProtocolNotPermitted
+            | ErrorCode::QuotaExceeded
+            | ErrorCode::PullNotPermitted => Self::Denied,
+            ErrorCode::NoPorts
+            | ErrorCode::ProtocolMismatch
+            | ErrorCode::NoSuchTunnel
+            | ErrorCode::NoSuchStream
+            | ErrorCode::Unknown => Self::Other,
+        }
+    }
+}
+
+/// An error with a known [`Failure`] kind, and the server's [`ErrorCode`]
+/// when there is one.
+#[derive(Debug)]
+pub struct TunnelError {
+    pub kind: Failure,
+    pub code: Option<ErrorCode>,
+    pub message: String,
+}
+
+impl TunnelError {
+    pub fn new(kind: Failure, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            code: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn with_code(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            kind: code.into(),
+            code: Some(code),
+            message: message.into(),
+        }
+    }
+
+    /// Wrap a `ServerMsg::Error`. Older servers, and every server before
+    /// the client has said it understands codes, only send text, so the
+    /// code is recovered from the server's wording.
+    pub fn from_server(message: String) -> Self {
+        let message_has = |s| message.contains(s);
+        let code = if message_has("already taken") || message_has("is reserved for") {
+            Some(ErrorCode::SubdomainTaken)
+        } else if message_has("is banned") {
+            Some(ErrorCode::SubdomainBanned)
+        } else if message_has("already in use") {
+            Some(ErrorCode::PortTaken)
+        } else if message_has("allowed range of") {
+            Some(ErrorCode::PortNotPermitted)
+        } else if message_has("allowed range") {
+            Some(ErrorCode::PortOutOfRange)
+        } else if message_has("exhausted") {
+            Some(ErrorCode::NoPorts)
+        } else if message_has("not permitted to claim") {
+            Some(ErrorCode::SubdomainNotPermitted)
+        } else if message_has("not permitted to open") {
+            Some(ErrorCode::ProtocolNotPermitted)
+        } else if message_has("limit of open tunnels") {
+            Some(ErrorCode::QuotaExceeded)
+        } else if message_has("secret") || message_has("token") || message_has("Authenticate") {
+            Some(ErrorCode::AuthFailed)
+        } else {
+            None
+        };
+        let message = format!("server error: {message}");
+        match code {
+            Some(code) => Self::with_code(code, message),
+            None => Self::new(Failure::Other, message),
+        }
+    }
+
+    /// Wrap a `ServerMsg::Refused`.
+    pub fn from_code(code: ErrorCode, message: String) -> Self {
+        Self::with_code(code, format!("server refused: {message}"))
+    }
+
+    /// The server's code for `err`, if it is or wraps a [`TunnelError`]
+    /// that has one.
+    pub fn code_of(err: &anyhow::Error) -> Option<ErrorCode> {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<TunnelError>())
+            .and_then(|e| e.code)
+    }
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for TunnelError {}
+
+/// Counters for the final summary.
+pub struct Stats {
+    started: Instant,
+    connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    rtt: Mutex<Option<Duration>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+            rtt: Mutex::new(None),
+        }
+    }
+
+    /// Record a finished data connection.
+    pub fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record bytes as they move. `bytes_in` flowed from the visitor to the
+    /// local service, `bytes_out` the other way.
+    pub fn record_transfer(&self, bytes_in: u64, bytes_out: u64) {
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    /// Bytes moved so far, in and out, counting connections in progress.
+    pub fn transferred(&self) -> (u64, u64) {
+        (
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Record the round trip to the server, as it measured the last
+    /// heartbeat.
+    pub fn record_rtt(&self, rtt: Duration) {
+        *self.rtt.lock().unwrap() = Some(rtt);
+    }
+
+    /// The last round trip to the server; `None` until one was measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().unwrap()
+    }
+
+    pub fn record_error(&self, err: &anyhow::Error) {
+        *self.last_error.lock().unwrap() = Some(format!("{err:#}"));
+    }
+
+    pub fn print_summary(&self) {
+        print!("{}", self.summary());
+    }
+
+    /// What [`print_summary`](Self::print_summary) prints.
+    pub fn summary(&self) -> String {
+        let uptime =
+            humantime::format_duration(Duration::from_secs(self.started.elapsed().as_secs()));
+        let mut out = String::new();
+        let _ = writeln!(out);
+        let _ = writeln!(out, "  ■  sshx stopped");
+        let _ = writeln!(out, "     Uptime      : {uptime}");
+        let _ = writeln!(
+            out,
+            "     Connections : {}",
+            self.connections.load(Ordering::Relaxed)
+        );
+        let (bytes_in, bytes_out) = self.transferred();
+        let _ = writeln!(
+            out,
+            "     Transferred : {} in / {} out",
+            format_bytes(bytes_in),
+            format_bytes(bytes_out)
+        );
+        if let Some(rtt) = self.rtt() {
+            let _ = writeln!(out, "     Latency     : {}ms", rtt.as_millis());
+        }
+        if let Some(err) = &*self.last_error.lock().unwrap() {
+            let _ = writeln!(out, "     Last error  : {err}");
+        }
+        let _ = writeln!(out);
+        out
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{n} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
@@ -0,0 +1,41 @@
+//! TLS for connections to the server's TLS control port.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+/// Trust the public web PKI, or only the certificates in `ca` (PEM), e.g. a
+/// server's self-signed certificate.
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(client_config(ca)?)))
+}
+
+/// The TLS settings behind [`connector`], also used by QUIC.
+pub fn client_config(ca: Option<&Path>) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+                .map_err(|e| anyhow!("cannot read {}: {e}", path.display()))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(anyhow!("no usable certificate in {}", path.display()));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(config)
+}
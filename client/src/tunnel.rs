@@ -0,0 +1,3331 @@
+//! The tunnel: registration, reconnects and inbound data connections.
+
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context as TaskContext, Poll},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{future::join_all, SinkExt, StreamExt};
+use serde::Deserialize;
+use sshx_core::{
+    auth::Auth,
+    e2e::{self, Keypair},
+    protocol::{
+        multiplex, negotiate, tune_socket, Acl, ClientMsg, ClientSettings, ErrorCode, Framed_,
+        IpNet, Proto, ServerMsg, SessionType, StreamHandle, Timeouts, BUFFER_SIZE, CONTROL_PORT,
+        HANDSHAKE_TIMEOUT, HEARTBEAT_INTERVAL, MAX_DATAGRAM, MAX_WARM_POOL, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION, TLS_CONTROL_PORT, UDP_IDLE_TIMEOUT,
+    },
+    resume::{self, Link, RESUME_WINDOW},
+    splice,
+    ws::WsStream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{lookup_host, TcpListener, TcpSocket, TcpStream, UdpSocket},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
+    time::{interval, sleep, timeout, Duration},
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+use tokio_util::{bytes::Bytes, sync::CancellationToken};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    approve::Approver,
+    balance::{Balance, Balancer, Pick},
+    chaos::{Chaos, DelayLine},
+    hooks::{Hook, Hooks},
+    http_proxy::HttpProxy,
+    inspect::{escape, GrpcTap, HttpTap, Inspector, Tap},
+    proxy::{self, ProxyProtocol},
+    pull,
+    quic::Quic,
+    record::{Format, PcapTap, Recorder},
+    resolve::{self, Resolver},
+    rewrite::{self, RequestRules, Rewriting, Verdict},
+    share::{self, Share},
+    socks::{self, AllowRule},
+    status::{Failure, Stats, TunnelError},
+    tls,
+};
+
+/// Server used when none is configured.
+pub const DEFAULT_SERVER: &str = "teamxpirates.qzz.io";
+
+/// Events that are not picked up in time are dropped.
+const EVENT_BUFFER: usize = 64;
+
+/// First and longest pause between attempts to reach a local service that
+/// is down, with [`TunnelBuilder::local_retry`].
+const LOCAL_RETRY_FIRST: Duration = Duration::from_millis(100);
+const LOCAL_RETRY_MAX: Duration = Duration::from_secs(1);
+
+/// First and longest pause before parking a warm connection again after
+/// one failed.
+const WARM_RETRY_FIRST: Duration = Duration::from_millis(100);
+const WARM_RETRY_MAX: Duration = Duration::from_secs(5);
+
+/// Idle time before TCP keepalive probes on the local leg of gRPC calls.
+/// The calls' own HTTP/2 pings pass through the tunnel end to end.
+const GRPC_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// What HTTP visitors see when the local service doesn't answer, unless
+/// [`TunnelBuilder::error_page`] says otherwise.
+const BAD_GATEWAY_PAGE: &str = "<!doctype html><meta charset=utf-8>\
+<title>502 Bad Gateway</title>\
+<h1>502 Bad Gateway</h1>\
+<p>The tunnel <b>{{subdomain}}</b> is up, but nothing answers on {{local}} behind it.\
+<p>If this is your tunnel, start the local service or check the port and host \
+it was given; visitors get through as soon as it listens.\
+<p><small>{{error}}</small>\n";
+
+// ── Public API ────────────────────────────────────────────────────────────────
+
+/// A local port exposed under a subdomain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    pub subdomain: String,
+    pub local_port: u16,
+    pub proto: Proto,
+    /// An HTTP tunnel for gRPC: visitors must speak HTTP/2, and the
+    /// inspector counts calls instead of requests.
+    pub grpc: bool,
+    /// Public port to ask the server for; random when `None`.
+    pub public_port: Option<u16>,
+    /// More ports of the same local service, e.g. other processes of it;
+    /// visitors are spread across them and `local_port`.
+    pub upstreams: Vec<u16>,
+}
+
+impl Forward {
+    /// `local_port` and the `upstreams`.
+    pub fn local_ports(&self) -> Vec<u16> {
+        std::iter::once(self.local_port)
+            .chain(self.upstreams.iter().copied())
+            .collect()
+    }
+
+    /// Where on `host` visitors go, e.g. `localhost:3000,3001`.
+    pub fn local_addr(&self, host: &str) -> String {
+        let ports: Vec<String> = self.local_ports().iter().map(u16::to_string).collect();
+        format!("{host}:{}", ports.join(","))
+    }
+}
+
+/// Parses `subdomain:localport[:proto][:publicport]`, where `proto` is `http`
+/// (default), `grpc`, `tcp` or `udp`. `localport` may be several, separated
+/// by commas, e.g. `3000,3001`.
+impl FromStr for Forward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':');
+        let (Some(subdomain), Some(port)) = (parts.next(), parts.next()) else {
+            bail!("expected subdomain:localport[:proto][:publicport], got '{s}'");
+        };
+        if subdomain.is_empty() {
+            bail!("missing subdomain in '{s}'");
+        }
+        let mut local_ports = port.split(',').map(|port| {
+            port.parse()
+                .with_context(|| format!("invalid local port '{port}'"))
+        });
+        let local_port = local_ports.next().unwrap()?;
+        let upstreams = local_ports.collect::<Result<_>>()?;
+        let mut rest: Vec<&str> = parts.collect();
+        let public_port = match rest
+            .last()
+            .filter(|p| p.starts_with(|c: char| c.is_ascii_digit()))
+        {
+            Some(port) => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("invalid public port '{port}'"))?;
+                rest.pop();
+                Some(port)
+            }
+            None => None,
+        };
+        let (proto, grpc) = match rest.as_slice() {
+            [] | ["http"] => (Proto::Http, false),
+            ["grpc"] => (Proto::Http, true),
+            ["tcp"] => (Proto::Tcp, false),
+            ["udp"] => (Proto::Udp, false),
+            [other] => bail!("unknown protocol '{other}' (expected http, grpc, tcp or udp)"),
+            _ => bail!("expected subdomain:localport[:proto][:publicport], got '{s}'"),
+        };
+        Ok(Self {
+            subdomain: subdomain.to_owned(),
+            local_port,
+            proto,
+            grpc,
+            public_port,
+            upstreams,
+        })
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let proto = match self.proto {
+            Proto::Http if self.grpc => "grpc",
+            Proto::Http => "http",
+            Proto::Tcp => "tcp",
+            Proto::Udp => "udp",
+        };
+        write!(f, "{}:{}", self.subdomain, self.local_port)?;
+        for port in &self.upstreams {
+            write!(f, ",{port}")?;
+        }
+        write!(f, ":{proto}")?;
+        match self.public_port {
+            Some(port) => write!(f, ":{port}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How connections to the server are carried.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Straight to the control port.
+    #[default]
+    Tcp,
+    /// A WebSocket to the server's HTTP port, or its HTTPS port with TLS, for
+    /// networks that only let web traffic out.
+    Ws,
+    /// QUIC to the TLS control port, over UDP, falling back to TLS over TCP
+    /// when UDP doesn't get through.
+    Quic,
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "ws" | "websocket" => Ok(Self::Ws),
+            "quic" => Ok(Self::Quic),
+            _ => bail!("unknown transport '{s}' (expected tcp, ws or quic)"),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Ws => "ws",
+            Self::Quic => "quic",
+        })
+    }
+}
+
+/// A tunnel the server accepted.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub subdomain: String,
+    pub public_port: u16,
+    /// Public URL, when the server routes HTTP tunnels by hostname.
+    pub url: Option<String>,
+    /// Every address the server bound `public_port` on; empty with older
+    /// servers.
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// A tunnel's traffic as the server relayed it, since it registered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TunnelTraffic {
+    pub conns: u64,
+    /// From visitors towards the local service.
+    pub bytes_in: u64,
+    /// From the local service back to visitors.
+    pub bytes_out: u64,
+    /// Bytes/sec in each direction since the server's previous report; zero
+    /// for the first.
+    pub rate_in: f64,
+    pub rate_out: f64,
+}
+
+/// Something that happened to a running tunnel.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The server accepted a tunnel. Sent again after every reconnect.
+    Connected(Registration),
+    /// A visitor connected through the tunnel for `subdomain`, on
+    /// `public_port` of the server. Servers before protocol version 7 don't
+    /// say which port. Servers with a GeoIP database say which `country`
+    /// (ISO code) and autonomous system (`asn`) the visitor is from.
+    Connection {
+        subdomain: String,
+        peer_addr: SocketAddr,
+        public_port: Option<u16>,
+        country: Option<String>,
+        asn: Option<u32>,
+    },
+    /// A visitor connected through the tunnel for `subdomain` before
+    /// [`Tunnel::ready`], with [`TunnelBuilder::probe_first`]. It was closed
+    /// without reaching the local service.
+    Probed {
+        subdomain: String,
+        peer_addr: SocketAddr,
+    },
+    /// A visitor of an HTTP tunnel sent a request; `line` is its request
+    /// line. Only the first request of each connection is reported.
+    Request {
+        subdomain: String,
+        peer_addr: SocketAddr,
+        line: String,
+    },
+    /// A visitor's connection ended, also when it was turned away or failed.
+    /// `bytes_in` flowed from the visitor to the local service, `bytes_out`
+    /// the other way.
+    ConnectionClosed {
+        subdomain: String,
+        peer_addr: SocketAddr,
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+    /// The server reported the traffic of the tunnel for `subdomain`, a few
+    /// seconds after the last report while it changes. Servers before
+    /// protocol version 6 don't.
+    Traffic {
+        subdomain: String,
+        traffic: TunnelTraffic,
+    },
+    /// The server operator sent a message for users.
+    Notice(String),
+    /// The server closed the tunnel for `subdomain` because it sat idle or
+    /// reached its maximum lifetime. `message` says which.
+    Expired { subdomain: String, message: String },
+    /// Another client took over the tunnel for `subdomain` with the same
+    /// takeover key. Once every tunnel was, and the connections in progress
+    /// finished, the tunnel closes.
+    HandedOver { subdomain: String },
+    /// The tunnel for `subdomain` was closed with
+    /// [`ShutdownHandle::close`]; the others stay up.
+    Unregistered { subdomain: String },
+    /// The control connection was lost. `reconnecting` tells whether another
+    /// attempt follows.
+    Disconnected { error: String, reconnecting: bool },
+}
+
+/// Configures and opens a [`Tunnel`].
+pub struct TunnelBuilder {
+    server: String,
+    standby: Vec<String>,
+    control_port: Option<u16>,
+    subdomain: Option<String>,
+    local_host: String,
+    local_port: Option<u16>,
+    upstreams: Vec<u16>,
+    balance: Balance,
+    proto: Proto,
+    grpc: bool,
+    public_port: Option<u16>,
+    secret: Option<String>,
+    bind_address: Option<IpAddr>,
+    bind_interface: Option<String>,
+    tls: bool,
+    tls_ca: Option<PathBuf>,
+    transport: Transport,
+    http_proxy: Option<HttpProxy>,
+    resolver: Resolver,
+    forwards: Vec<Forward>,
+    acl: Acl,
+    proxy_protocol: Option<ProxyProtocol>,
+    approver: Option<Arc<Approver>>,
+    inspector: Option<Arc<Inspector>>,
+    pcap: Option<Arc<Recorder>>,
+    error_page: Option<String>,
+    requests: RequestRules,
+    socks: Option<Vec<AllowRule>>,
+    unix_socket: Option<PathBuf>,
+    exec: Option<String>,
+    share: Option<Share>,
+    echo: bool,
+    stdio: bool,
+    e2e: Option<Keypair>,
+    reconnect: bool,
+    timeouts: Timeouts,
+    local_retry: Duration,
+    chaos: Option<Chaos>,
+    buffer_size: usize,
+    resumable: bool,
+    warm_pool: usize,
+    pool: bool,
+    takeover: Option<String>,
+    ttl: Option<Duration>,
+    max_uses: Option<u64>,
+    probe_first: bool,
+    stats: Option<Arc<Stats>>,
+    hooks: Hooks,
+}
+
+impl TunnelBuilder {
+    fn new() -> Self {
+        Self {
+            server: DEFAULT_SERVER.into(),
+            standby: Vec::new(),
+            control_port: None,
+            subdomain: None,
+            local_host: "localhost".into(),
+            local_port: None,
+            upstreams: Vec::new(),
+            balance: Balance::default(),
+            proto: Proto::Http,
+            grpc: false,
+            public_port: None,
+            secret: None,
+            bind_address: None,
+            bind_interface: None,
+            tls: false,
+            tls_ca: None,
+            transport: Transport::Tcp,
+            http_proxy: None,
+            resolver: Resolver::System,
+            forwards: Vec::new(),
+            acl: Acl::default(),
+            proxy_protocol: None,
+            approver: None,
+            reconnect: true,
+            timeouts: Timeouts::default(),
+            local_retry: Duration::ZERO,
+            chaos: None,
+            buffer_size: BUFFER_SIZE,
+            resumable: false,
+            warm_pool: 0,
+            pool: false,
+            takeover: None,
+            ttl: None,
+            max_uses: None,
+            probe_first: false,
+            inspector: None,
+            pcap: None,
+            error_page: None,
+            requests: RequestRules::default(),
+            socks: None,
+            unix_socket: None,
+            exec: None,
+            share: None,
+            echo: false,
+            stdio: false,
+            e2e: None,
+            stats: None,
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Hostname of the sshx server.
+    pub fn server(mut self, server: impl Into<String>) -> Self {
+        self.server = server.into();
+        self
+    }
+
+    /// Another server to fail over to when the ones before it can't be
+    /// reached or stop answering heartbeats; may be given several times.
+    /// After a failover, each reconnect starts over from the first server,
+    /// where a reserved port is resumed if it is still held. Needs
+    /// [`reconnect`](Self::reconnect), which is on by default.
+    pub fn standby(mut self, server: impl Into<String>) -> Self {
+        self.standby.push(server.into());
+        self
+    }
+
+    /// Control port of the server [default: 12267, or 12268 with TLS; 80 or
+    /// 443 over WebSocket].
+    pub fn control_port(mut self, port: u16) -> Self {
+        self.control_port = Some(port);
+        self
+    }
+
+    /// Subdomain to register. Without it, and without tunnels added with
+    /// [`forward`](Self::forward), the server picks a random one, which
+    /// [`Event::Connected`] names.
+    pub fn subdomain(mut self, subdomain: impl Into<String>) -> Self {
+        self.subdomain = Some(subdomain.into());
+        self
+    }
+
+    /// Host the local service listens on [default: localhost].
+    pub fn local_host(mut self, host: impl Into<String>) -> Self {
+        self.local_host = host.into();
+        self
+    }
+
+    /// Port of the local service. Required unless there is a
+    /// [`unix_socket`](Self::unix_socket) or tunnels are added with
+    /// [`forward`](Self::forward).
+    pub fn local_port(mut self, port: u16) -> Self {
+        self.local_port = Some(port);
+        self
+    }
+
+    /// Another port of the same local service, e.g. a second process of it;
+    /// may be given several times. Visitors are spread across these and the
+    /// [`local_port`](Self::local_port), and a port that keeps refusing
+    /// connections is left out for a while.
+    pub fn upstream(mut self, port: u16) -> Self {
+        self.upstreams.push(port);
+        self
+    }
+
+    /// How visitors are spread across the local ports of a tunnel that has
+    /// several [default: round-robin].
+    pub fn balance(mut self, balance: Balance) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    /// Connect visitors of TCP and HTTP tunnels to this Unix socket, e.g.
+    /// `/var/run/docker.sock`, instead of the local host and port. Unix only.
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    /// Run `command` through the shell for each visitor of a TCP or HTTP
+    /// tunnel, inetd-style, and connect the visitor to its stdin and stdout
+    /// instead of a local service. The process is killed when the visitor
+    /// leaves, if it hasn't exited by then.
+    pub fn exec(mut self, command: impl Into<String>) -> Self {
+        self.exec = Some(command.into());
+        self
+    }
+
+    /// Answer HTTP visitors from the files of `share` instead of forwarding
+    /// to a local service. `http_auth` guards them as it would a service.
+    pub fn share(mut self, share: Share) -> Self {
+        self.share = Some(share);
+        self
+    }
+
+    /// Send every visitor's bytes straight back instead of forwarding to a
+    /// local service, e.g. to test the way through the server. TCP tunnels
+    /// only.
+    pub fn echo(mut self) -> Self {
+        self.echo = true;
+        self
+    }
+
+    /// Connect the first visitor to this process's stdin and stdout instead
+    /// of a local service, and close the tunnel once it leaves. Later
+    /// visitors are turned away. Takes a single TCP tunnel.
+    pub fn stdio(mut self) -> Self {
+        self.stdio = true;
+        self
+    }
+
+    /// Encrypt every visitor connection end to end with `keypair`, so the
+    /// server only relays ciphertext. Visitors connect with `sshx connect
+    /// --e2e-peer` and its public key. TCP tunnels only.
+    pub fn e2e(mut self, keypair: Keypair) -> Self {
+        self.e2e = Some(keypair);
+        self
+    }
+
+    /// Tunnel protocol [default: HTTP].
+    pub fn proto(mut self, proto: Proto) -> Self {
+        self.proto = proto;
+        self
+    }
+
+    /// Make the HTTP tunnel a gRPC one: the server turns away visitors that
+    /// don't speak HTTP/2, and the inspector counts calls per method. Needs
+    /// a server of protocol version 8 or later.
+    pub fn grpc(mut self, grpc: bool) -> Self {
+        self.grpc = grpc;
+        self
+    }
+
+    /// Public port to ask the server for. Registration fails if the server
+    /// cannot bind it [default: a random port].
+    pub fn public_port(mut self, port: u16) -> Self {
+        self.public_port = Some(port);
+        self
+    }
+
+    /// Expose another local port on the same control connection. The local
+    /// host is shared by all tunnels.
+    pub fn forward(mut self, forward: Forward) -> Self {
+        self.forwards.push(forward);
+        self
+    }
+
+    /// Shared secret matching the server's `--secret`.
+    pub fn secret(mut self, secret: impl Into<String>) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    /// Local IP address that connections to the server originate from.
+    pub fn bind_address(mut self, addr: IpAddr) -> Self {
+        self.bind_address = Some(addr);
+        self
+    }
+
+    /// Network interface that connections to the server go out on (Linux only).
+    pub fn bind_interface(mut self, iface: impl Into<String>) -> Self {
+        self.bind_interface = Some(iface.into());
+        self
+    }
+
+    /// Connect to the server's TLS control port.
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// PEM certificate(s) to trust instead of the public CAs. Implies TLS.
+    pub fn tls_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tls = true;
+        self.tls_ca = Some(path.into());
+        self
+    }
+
+    /// Reach the server over `transport` [default: TCP].
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Reach the server through this HTTP proxy, for control and data
+    /// connections alike.
+    pub fn http_proxy(mut self, proxy: HttpProxy) -> Self {
+        self.http_proxy = Some(proxy);
+        self
+    }
+
+    /// Resolve the server's name with `resolver` [default: the system's].
+    /// Names of local services are always left to the system.
+    pub fn resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Let visitors from `net` in. Once a network is allowed, visitors from
+    /// anywhere else are turned away.
+    pub fn allow(mut self, net: IpNet) -> Self {
+        self.acl.allow.push(net);
+        self
+    }
+
+    /// Turn away visitors from `net`, unless they are also allowed.
+    pub fn deny(mut self, net: IpNet) -> Self {
+        self.acl.deny.push(net);
+        self
+    }
+
+    /// Start every TCP and HTTP connection to the local service with a PROXY
+    /// protocol header carrying the visitor's address.
+    pub fn proxy_protocol(mut self, version: ProxyProtocol) -> Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+
+    /// Ask before letting each inbound connection through: on the terminal,
+    /// or wherever a [`queued`](Approver::queued) `approver` is answered.
+    pub fn approver(mut self, approver: impl Into<Arc<Approver>>) -> Self {
+        self.approver = Some(approver.into());
+        self
+    }
+
+    /// Parse the HTTP tunnels' traffic and keep their requests in
+    /// `inspector`.
+    pub fn inspector(mut self, inspector: Arc<Inspector>) -> Self {
+        self.inspector = Some(inspector);
+        self
+    }
+
+    /// Save the connections of TCP tunnels to `recorder`, a PCAP recording,
+    /// as packets between the visitor and the local service.
+    pub fn record_pcap(mut self, recorder: Arc<Recorder>) -> Self {
+        self.pcap = Some(recorder);
+        self
+    }
+
+    /// HTML answered with a 502 to HTTP visitors when the local service
+    /// can't be reached. `{{subdomain}}`, `{{local}}` and `{{error}}` are
+    /// replaced with the tunnel, the local address and what went wrong.
+    pub fn error_page(mut self, template: impl Into<String>) -> Self {
+        self.error_page = Some(template.into());
+        self
+    }
+
+    /// Let only HTTP visitors that send these `user:password` credentials
+    /// with basic auth through.
+    pub fn http_auth(mut self, credentials: impl Into<String>) -> Self {
+        self.requests.credentials = Some(credentials.into());
+        self
+    }
+
+    /// Set header `name` on every request of HTTP tunnels, replacing any the
+    /// visitor sent.
+    pub fn add_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.requests.add.push((name.into(), value.into()));
+        self
+    }
+
+    /// Drop header `name` from every request of HTTP tunnels.
+    pub fn remove_header(mut self, name: impl Into<String>) -> Self {
+        self.requests.remove.push(name.into());
+        self
+    }
+
+    /// Send HTTP requests to the local service with this `Host`, e.g. the
+    /// name of a local virtual host.
+    pub fn host_rewrite(mut self, host: impl Into<String>) -> Self {
+        self.requests.host = Some(host.into());
+        self
+    }
+
+    /// Act as a SOCKS5 proxy instead of forwarding to the local port:
+    /// visitors name a destination, and are connected to it if one of
+    /// `allow` permits it. Applies to every tunnel; use `Proto::Tcp`.
+    pub fn socks(mut self, allow: Vec<AllowRule>) -> Self {
+        self.socks = Some(allow);
+        self
+    }
+
+    /// Reconnect after the connection drops [default: true].
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// How long each step of reaching the server may take: TLS, auth and
+    /// every message up to the first tunnel [default: 5s].
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+
+    /// Ask the server for heartbeats this far apart, for slow links. Servers
+    /// don't send them more often than they would anyway [default: 500ms].
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.timeouts.heartbeat_interval = interval;
+        self
+    }
+
+    /// Reconnect after hearing nothing from the server for this long, or
+    /// for ten heartbeats if that is longer [default: 5s]. Also the idle
+    /// time before TCP keepalive probes start.
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.heartbeat_timeout = timeout;
+        self
+    }
+
+    /// Keep trying to reach the local service for this long when a visitor
+    /// arrives while it is down, e.g. restarting, instead of failing the
+    /// visit at once. The visitor waits meanwhile [default: 0, no retries].
+    pub fn local_retry(mut self, window: Duration) -> Self {
+        self.local_retry = window;
+        self
+    }
+
+    /// Relay visitor traffic as if over a worse network: with latency, and
+    /// connections cut or datagrams lost at random. For testing only.
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Open a data connection per visitor, instead of multiplexing them over
+    /// the control connection, and pick it up again when it drops
+    /// mid-transfer, without the visitor or the local service noticing.
+    /// Needs a server of protocol version 9 or later; UDP tunnels are left
+    /// as they are.
+    pub fn resumable(mut self, resumable: bool) -> Self {
+        self.resumable = resumable;
+        self
+    }
+
+    /// Keep `size` data connections open and authenticated at the server,
+    /// up to 64, instead of multiplexing visitors over the control
+    /// connection: each visitor is bridged to one at once and gets a TCP
+    /// connection of its own, so a large transfer doesn't hold up the
+    /// others. Every connection that takes a visitor is replaced. Needs a
+    /// server of protocol version 13 or later, and doesn't apply to
+    /// resumable data connections.
+    pub fn warm_pool(mut self, size: usize) -> Self {
+        self.warm_pool = size.min(MAX_WARM_POOL);
+        self
+    }
+
+    /// Close the tunnels once they have been up for `ttl`, e.g. for a demo
+    /// that shouldn't outlive the afternoon. Reconnects don't start it over.
+    /// Servers of protocol version 15 or later enforce it too.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Let `max_uses` visitors through in all, then close the tunnels once
+    /// the last of them left, e.g. `1` to share a file once. Later visitors
+    /// are turned away. The forwards of one tunnel share them; servers of
+    /// protocol version 15 or later also enforce the limit, per forward.
+    pub fn max_uses(mut self, max_uses: u64) -> Self {
+        self.max_uses = Some(max_uses);
+        self
+    }
+
+    /// Take visitors for reachability probes until [`Tunnel::ready`] is
+    /// called: they are reported as [`Event::Probed`] and closed, without
+    /// reaching the local service, running hooks or counting against
+    /// `max_uses`. Until then the server leaves `max_uses` to the client.
+    pub fn probe_first(mut self, probe_first: bool) -> Self {
+        self.probe_first = probe_first;
+        self
+    }
+
+    /// Share the subdomains with other clients of the same identity that
+    /// ask for a pool too, e.g. replicas of the local service on other
+    /// machines: the server spreads visitors across all of them, and the
+    /// tunnels stay up for as long as any is connected. Needs a server of
+    /// protocol version 10 or later; UDP tunnels can't be pooled.
+    pub fn pool(mut self, pool: bool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Let a later client started with the same key take the tunnels over,
+    /// and take over the ones an earlier client registered with it, e.g. to
+    /// redeploy the local service on another machine without downtime. The
+    /// server sends new visitors to the new client and lets the old one
+    /// finish its connections in progress; the old one then stops. Needs a
+    /// server of protocol version 11 or later, and the same identity on
+    /// both; UDP tunnels can't be taken over.
+    pub fn takeover_key(mut self, key: impl Into<String>) -> Self {
+        self.takeover = Some(key.into());
+        self
+    }
+
+    /// Copy visitors' bytes this many at a time in each direction; bigger
+    /// speeds up large transfers, at that much memory twice per connection
+    /// [default: 8 KiB].
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size.max(1);
+        self
+    }
+
+    /// Count traffic into `stats`, which outlives the tunnel.
+    pub fn stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Run `hook` whenever the server accepts a tunnel, also after a
+    /// reconnect.
+    pub fn on_connect(mut self, hook: Hook) -> Self {
+        self.hooks.connect.push(hook);
+        self
+    }
+
+    /// Run `hook` whenever the control connection is lost.
+    pub fn on_disconnect(mut self, hook: Hook) -> Self {
+        self.hooks.disconnect.push(hook);
+        self
+    }
+
+    /// Run `hook` for every visitor that connects.
+    pub fn on_new_connection(mut self, hook: Hook) -> Self {
+        self.hooks.new_connection.push(hook);
+        self
+    }
+
+    /// Register the tunnels and keep them up in the background.
+    ///
+    /// Returns once the server has accepted every tunnel. Until then,
+    /// failures that may go away are retried if reconnecting is enabled.
+    pub async fn connect(mut self) -> Result<Tunnel> {
+        let subdomain = self.subdomain.take();
+        if subdomain.is_some() || self.forwards.is_empty() {
+            let local_port = match self.local_port {
+                Some(port) => port,
+                None if self.unix_socket.is_some()
+                    || self.exec.is_some()
+                    || self.share.is_some()
+                    || self.echo =>
+                {
+                    0
+                }
+                None => bail!("a local port is required"),
+            };
+            let first = Forward {
+                // Empty for the server to pick.
+                subdomain: subdomain.unwrap_or_default(),
+                local_port,
+                proto: self.proto,
+                grpc: self.grpc && self.proto == Proto::Http,
+                public_port: self.public_port,
+                upstreams: std::mem::take(&mut self.upstreams),
+            };
+            self.forwards.insert(0, first);
+        }
+        let (shared, events) = self.into_shared()?;
+
+        // In stdio mode, the visitor leaving stops the tunnel too.
+        let shutdown = match &shared.stdio {
+            Some(stdio) => stdio.closed.child_token(),
+            None => CancellationToken::new(),
+        };
+        let finished = CancellationToken::new();
+        let (registered_tx, mut registered) = watch::channel(Vec::new());
+        // Log lines of the tunnel and its connections say which server.
+        let span = info_span!("tunnel", server = %shared.options.server);
+        let task = tokio::spawn({
+            let (shutdown, finished) = (shutdown.clone(), finished.clone());
+            let shared = Arc::clone(&shared);
+            async move {
+                let result = run_forever(&shared, &shutdown, &registered_tx).await;
+                finished.cancel();
+                result
+            }
+            .instrument(span)
+        });
+
+        // Stop the tunnel if the caller gives up before it is registered.
+        let guard = shutdown.clone().drop_guard();
+        let registrations = registered
+            .wait_for(|r| !r.is_empty())
+            .await
+            .map(|r| r.clone());
+        let Ok(registrations) = registrations else {
+            // The tunnel stopped before it got registered.
+            return match task.await? {
+                Ok(()) => Err(anyhow!("tunnel closed before it was registered")),
+                Err(e) => Err(e),
+            };
+        };
+        guard.disarm();
+        if shared.deadline.is_some() || shared.uses_left.is_some() {
+            tokio::spawn(enforce_limits(
+                Arc::clone(&shared),
+                registered.clone(),
+                shutdown.clone(),
+                finished.clone(),
+            ));
+        }
+        Ok(Tunnel {
+            registrations,
+            events,
+            shared,
+            registered,
+            shutdown,
+            finished,
+            task,
+        })
+    }
+
+    /// Make `target`, a `host:port` the server reaches, appear on
+    /// `listener` instead of registering tunnels: every connection accepted
+    /// there gets its own connection through the server. The server must
+    /// allow pulling `target`. Runs until the listener fails.
+    pub async fn pull(self, target: impl Into<String>, listener: TcpListener) -> Result<()> {
+        let (shared, _) = self.into_shared()?;
+        pull::serve(listener, target.into(), shared).await
+    }
+
+    /// Ask the server for the protocol and public port of the tunnel of
+    /// `subdomain`, whoever registered it.
+    pub async fn lookup(self, subdomain: impl Into<String>) -> Result<(Proto, u16)> {
+        let (shared, _) = self.into_shared()?;
+        lookup(&shared, subdomain.into()).await
+    }
+
+    /// Connect to the tunnel of `subdomain` as a visitor would, from the
+    /// bind address and interface like the connections to the server.
+    pub async fn dial(self, subdomain: impl Into<String>) -> Result<TcpStream> {
+        let (shared, _) = self.into_shared()?;
+        let (_, port) = lookup(&shared, subdomain.into()).await?;
+        let options = &shared.options;
+        connect_direct(options, shared.server(), port).await
+    }
+
+    /// What the tunnel's background work shares, built from the settings.
+    fn into_shared(self) -> Result<(Arc<Shared>, mpsc::Receiver<Event>)> {
+        if self.bind_interface.is_some() && !cfg!(target_os = "linux") {
+            bail!("binding to an interface is only supported on Linux");
+        }
+        if self.unix_socket.is_some() {
+            if !cfg!(unix) {
+                bail!("Unix sockets are not supported on this platform");
+            }
+            if self.forwards.iter().any(|f| f.proto == Proto::Udp) {
+                bail!("UDP tunnels can't forward to a Unix socket");
+            }
+        }
+        let upstreams = self.forwards.iter().any(|f| !f.upstreams.is_empty());
+        if upstreams && (self.unix_socket.is_some() || self.exec.is_some()) {
+            bail!("a Unix socket or a command can't be spread over several local ports");
+        }
+        if self.exec.is_some() {
+            if self.unix_socket.is_some() {
+                bail!("a tunnel can't forward to both a command and a Unix socket");
+            }
+            if self.forwards.iter().any(|f| f.proto == Proto::Udp) {
+                bail!("UDP tunnels can't forward to a command");
+            }
+        }
+        if self.share.is_some() {
+            if self.unix_socket.is_some() || self.exec.is_some() || self.socks.is_some() {
+                bail!("a tunnel sharing files can't forward anywhere else");
+            }
+            if self
+                .forwards
+                .iter()
+                .any(|f| f.proto != Proto::Http || f.grpc)
+            {
+                bail!("only HTTP tunnels can share files");
+            }
+        }
+        if self.echo {
+            let elsewhere = self.unix_socket.is_some() || self.exec.is_some();
+            if elsewhere || self.socks.is_some() || self.share.is_some() || self.stdio {
+                bail!("a tunnel echoing visitors can't forward anywhere else");
+            }
+            if self.forwards.iter().any(|f| f.proto != Proto::Tcp) {
+                bail!("only TCP tunnels can echo");
+            }
+        }
+        if self.stdio {
+            if self.forwards.len() > 1 {
+                bail!("stdin and stdout can only serve one tunnel");
+            }
+            if self.forwards.iter().any(|f| f.proto != Proto::Tcp) {
+                bail!("only TCP tunnels can use stdin and stdout");
+            }
+        }
+        if let Some(pcap) = &self.pcap {
+            if pcap.format() != Format::Pcap {
+                bail!("TCP connections can only be recorded as PCAP");
+            }
+            if self.forwards.iter().all(|f| f.proto != Proto::Tcp) {
+                bail!("PCAP recording only applies to TCP tunnels");
+            }
+        }
+        let timeouts = &self.timeouts;
+        if [
+            timeouts.handshake,
+            timeouts.heartbeat_interval,
+            timeouts.heartbeat_timeout,
+        ]
+        .contains(&Duration::ZERO)
+        {
+            bail!("timeouts and the heartbeat interval must be longer than zero");
+        }
+        if self.pool && self.forwards.iter().any(|f| f.proto == Proto::Udp) {
+            bail!("UDP tunnels can't be pooled");
+        }
+        if self.takeover.is_some() && self.forwards.iter().any(|f| f.proto == Proto::Udp) {
+            bail!("UDP tunnels can't be taken over");
+        }
+        if self.e2e.is_some() && self.forwards.iter().any(|f| f.proto != Proto::Tcp) {
+            bail!("only TCP tunnels can be encrypted end to end");
+        }
+        if !self.requests.is_empty() {
+            if self.forwards.iter().all(|f| f.proto != Proto::Http) {
+                bail!("HTTP auth and header rewriting only apply to HTTP tunnels");
+            }
+            if self.forwards.iter().any(|f| f.grpc) {
+                bail!("HTTP auth and header rewriting don't apply to gRPC tunnels");
+            }
+            self.requests.validate()?;
+        }
+        let quic = match self.transport {
+            Transport::Quic => {
+                if self.http_proxy.is_some() {
+                    bail!("QUIC can't go through an HTTP proxy");
+                }
+                if self.bind_interface.is_some() {
+                    bail!("QUIC can't be bound to an interface");
+                }
+                Some(Quic::new(tls::client_config(self.tls_ca.as_deref())?)?)
+            }
+            _ => None,
+        };
+        // QUIC always runs TLS, and so does the TCP it falls back to.
+        let tls = match self.tls || quic.is_some() {
+            true => Some(tls::connector(self.tls_ca.as_deref())?),
+            false => None,
+        };
+        let default_port = match (self.transport, self.tls) {
+            (Transport::Quic, _) => TLS_CONTROL_PORT,
+            (Transport::Tcp, false) => CONTROL_PORT,
+            (Transport::Tcp, true) => TLS_CONTROL_PORT,
+            (Transport::Ws, false) => 80,
+            (Transport::Ws, true) => 443,
+        };
+        let (events_tx, events) = mpsc::channel(EVENT_BUFFER);
+        let shared = Arc::new(Shared {
+            options: Options {
+                server: self.server,
+                standby: self.standby,
+                control_port: self.control_port.unwrap_or(default_port),
+                transport: self.transport,
+                http_proxy: self.http_proxy,
+                resolver: self.resolver,
+                forwards: self.forwards,
+                local_host: self.local_host,
+                secret: self.secret,
+                bind_address: self.bind_address,
+                bind_interface: self.bind_interface,
+                reconnect: self.reconnect,
+                timeouts: self.timeouts,
+                local_retry: self.local_retry,
+                chaos: self.chaos,
+                buffer_size: self.buffer_size,
+                resumable: self.resumable,
+                warm_pool: match self.resumable {
+                    true => 0,
+                    false => self.warm_pool,
+                },
+                pool: self.pool,
+                takeover: self.takeover,
+                acl: self.acl,
+                proxy_protocol: self.proxy_protocol,
+                error_page: self.error_page,
+                requests: self.requests,
+                socks: self.socks,
+                unix_socket: self.unix_socket,
+                exec: self.exec,
+                share: self.share.map(Share::open).transpose()?,
+                echo: self.echo,
+            },
+            approver: self.approver,
+            inspector: self.inspector,
+            pcap: self.pcap,
+            balancer: Balancer::new(self.balance),
+            tls,
+            quic,
+            settings: Mutex::new(ClientSettings::default()),
+            active: AtomicUsize::new(0),
+            current: AtomicUsize::new(0),
+            sessions: Mutex::new(HashMap::new()),
+            assigned: Mutex::new(None),
+            version: AtomicU32::new(PROTOCOL_VERSION),
+            dialing: Mutex::new(HashMap::new()),
+            reports: Mutex::new(HashMap::new()),
+            deadline: self.ttl.map(|ttl| Instant::now() + ttl),
+            uses_left: self.max_uses.map(AtomicU64::new),
+            probing: AtomicBool::new(self.probe_first),
+            used_up: CancellationToken::new(),
+            closed: Mutex::new(HashSet::new()),
+            closing: Notify::new(),
+            stats: self.stats.unwrap_or_default(),
+            stdio: self.stdio.then(Stdio::default),
+            e2e: self.e2e,
+            hooks: self.hooks,
+            events: events_tx,
+        });
+        Ok((shared, events))
+    }
+}
+
+/// A registered tunnel, kept up by a background task.
+///
+/// Dropping the tunnel shuts it down without waiting.
+pub struct Tunnel {
+    registrations: Vec<Registration>,
+    events: mpsc::Receiver<Event>,
+    shared: Arc<Shared>,
+    /// The tunnels as of the current control connection.
+    registered: watch::Receiver<Vec<Registration>>,
+    shutdown: CancellationToken,
+    finished: CancellationToken,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Tunnel {
+    pub fn builder() -> TunnelBuilder {
+        TunnelBuilder::new()
+    }
+
+    /// Port the server exposes the first tunnel on, as of the first
+    /// registration.
+    pub fn public_port(&self) -> u16 {
+        self.registrations[0].public_port
+    }
+
+    /// Public URL of the first tunnel, if the server has one.
+    pub fn url(&self) -> Option<&str> {
+        self.registrations[0].url.as_deref()
+    }
+
+    /// Every tunnel, as of the first registration.
+    pub fn registrations(&self) -> &[Registration] {
+        &self.registrations
+    }
+
+    /// The next event, or `None` once the tunnel has stopped.
+    pub async fn next_event(&mut self) -> Option<Event> {
+        tokio::select! {
+            biased;
+            event = self.events.recv() => event,
+            _ = self.finished.cancelled() => self.events.try_recv().ok(),
+        }
+    }
+
+    /// End the probing started with [`TunnelBuilder::probe_first`]: later
+    /// visitors reach the local service and count against `max_uses`.
+    pub fn ready(&self) {
+        self.shared.probing.store(false, Ordering::Relaxed);
+    }
+
+    /// A handle that shuts the tunnel down from elsewhere.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown: self.shutdown.clone(),
+            shared: Arc::clone(&self.shared),
+            registered: self.registered.clone(),
+        }
+    }
+
+    /// Close the tunnel and wait for the server to be told.
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.cancel();
+        self.wait().await
+    }
+
+    /// Wait until the tunnel stops: shut down, closed by the server, or
+    /// failed for good.
+    pub async fn wait(mut self) -> Result<()> {
+        (&mut self.task).await?
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Shuts a [`Tunnel`] down gracefully, or single tunnels of it.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown: CancellationToken,
+    shared: Arc<Shared>,
+    registered: watch::Receiver<Vec<Registration>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Close the tunnel for `subdomain` and keep the others up: the server
+    /// takes no more visitors for it, the ones in progress finish, and it
+    /// isn't registered again after a reconnect. Closing the last tunnel
+    /// shuts down like [`shutdown`](Self::shutdown). Otherwise the server
+    /// must speak protocol version 14.
+    pub async fn close(&self, subdomain: &str) -> Result<()> {
+        let shared = &self.shared;
+        let live = shared.live();
+        if !live.iter().any(|f| f.subdomain == subdomain) {
+            bail!("no tunnel '{subdomain}'");
+        }
+        if live.len() == 1 {
+            self.shutdown();
+            return Ok(());
+        }
+        let version = shared.version.load(Ordering::Relaxed);
+        if version < 14 {
+            let message = format!(
+                "the server speaks protocol version {version}; closing one tunnel needs 14"
+            );
+            return Err(TunnelError::with_code(ErrorCode::ProtocolMismatch, message).into());
+        }
+        shared.closed.lock().unwrap().insert(subdomain.to_owned());
+        shared.closing.notify_one();
+        // A reconnect in the meantime leaves it out too.
+        let wait = shared.options.timeouts.handshake;
+        let mut registered = self.registered.clone();
+        let gone = registered.wait_for(|r| !r.iter().any(|r| r.subdomain == subdomain));
+        let done = timeout(wait, gone).await;
+        match done {
+            Ok(_) => Ok(()),
+            Err(_) => bail!(
+                "the server did not confirm closing '{subdomain}' in {wait:?}; \
+                 it won't be registered again"
+            ),
+        }
+    }
+}
+
+// ── Shared state ──────────────────────────────────────────────────────────────
+
+/// What the tunnel was built with.
+pub(crate) struct Options {
+    pub(crate) server: String,
+    /// Servers to fail over to, in order.
+    standby: Vec<String>,
+    pub(crate) control_port: u16,
+    transport: Transport,
+    http_proxy: Option<HttpProxy>,
+    pub(crate) resolver: Resolver,
+    /// Never empty when registering; the first one is registered with `Hello`.
+    forwards: Vec<Forward>,
+    local_host: String,
+    pub(crate) secret: Option<String>,
+    pub(crate) bind_address: Option<IpAddr>,
+    bind_interface: Option<String>,
+    reconnect: bool,
+    pub(crate) timeouts: Timeouts,
+    /// How long to keep trying a local service that is down.
+    local_retry: Duration,
+    /// Network conditions simulated for visitors.
+    chaos: Option<Chaos>,
+    /// Bytes copied at a time between a visitor and the local service.
+    pub(crate) buffer_size: usize,
+    /// Data connections are resumable streams, when the server can.
+    resumable: bool,
+    /// Data connections kept parked at the server for visitors.
+    warm_pool: usize,
+    /// Tunnels join the pool serving their subdomain.
+    pool: bool,
+    /// Lets clients with the same key take over each other's tunnels.
+    takeover: Option<String>,
+    /// Sent to the server, and checked again here for servers that ignore it.
+    acl: Acl,
+    proxy_protocol: Option<ProxyProtocol>,
+    /// Template of the page HTTP visitors get when the local service is down.
+    error_page: Option<String>,
+    /// What requests of HTTP tunnels must carry, and how they are rewritten.
+    requests: RequestRules,
+    /// Destinations visitors may reach when acting as a SOCKS5 proxy.
+    socks: Option<Vec<AllowRule>>,
+    /// Stands in for the local host and port of every tunnel.
+    unix_socket: Option<PathBuf>,
+    /// Command run for each visitor instead of connecting to a local
+    /// service.
+    exec: Option<String>,
+    /// Directory served to visitors instead of a local service.
+    share: Option<Share>,
+    /// Visitors get their own bytes back instead of a local service.
+    echo: bool,
+}
+
+/// State that outlives a single control connection.
+pub(crate) struct Shared {
+    pub(crate) options: Options,
+    approver: Option<Arc<Approver>>,
+    inspector: Option<Arc<Inspector>>,
+    /// Where TCP connections are recorded.
+    pcap: Option<Arc<Recorder>>,
+    /// Picks the local port of tunnels that have several.
+    balancer: Balancer,
+    /// Wraps connections to the server when TLS is on.
+    tls: Option<TlsConnector>,
+    /// Carries connections to the server with the QUIC transport.
+    quic: Option<Quic>,
+    /// Settings pushed by the server via `ServerMsg::Reconfigure`.
+    settings: Mutex<ClientSettings>,
+    /// Data connections currently being served.
+    active: AtomicUsize,
+    /// Which server is in use: 0 for `options.server`, then the standbys.
+    current: AtomicUsize,
+    /// Token of the last control connection to each server, to keep our
+    /// ports when reconnecting to it.
+    sessions: Mutex<HashMap<usize, Uuid>>,
+    /// Subdomain the server picked for the first tunnel, when it was left
+    /// out. Asked for again when reconnecting.
+    assigned: Mutex<Option<String>>,
+    /// Protocol version of the last control connection.
+    version: AtomicU32,
+    /// Parked connections being fetched, cancelled when the server gives
+    /// up on them.
+    dialing: Mutex<HashMap<Uuid, CancellationToken>>,
+    /// The server's last traffic report for each tunnel, and when it came.
+    reports: Mutex<HashMap<String, (TunnelTraffic, Instant)>>,
+    /// When the tunnels close, with a `ttl`.
+    deadline: Option<Instant>,
+    /// Visitors the tunnels may still take, with `max_uses`.
+    uses_left: Option<AtomicU64>,
+    /// Visitors are taken for probes, until [`Tunnel::ready`].
+    probing: AtomicBool,
+    /// Cancelled once no visitor is left to take and the last one left.
+    used_up: CancellationToken,
+    /// Tunnels closed on request, which aren't registered again.
+    closed: Mutex<HashSet<String>>,
+    /// Wakes the control connection to unregister them.
+    closing: Notify,
+    pub(crate) stats: Arc<Stats>,
+    /// Set in stdio mode.
+    stdio: Option<Stdio>,
+    /// Key visitors encrypt to, when they connect end to end.
+    e2e: Option<Keypair>,
+    hooks: Hooks,
+    events: mpsc::Sender<Event>,
+}
+
+/// The visitor connected to stdin and stdout, in stdio mode.
+#[derive(Default)]
+struct Stdio {
+    taken: AtomicBool,
+    /// Cancelled once the visitor leaves.
+    closed: CancellationToken,
+}
+
+impl Shared {
+    /// The forward a connection for `subdomain` belongs to, with the name the
+    /// server picked filled in. Older servers don't say, and only carry the
+    /// first tunnel.
+    fn forward(&self, subdomain: Option<&str>) -> Result<Forward> {
+        let Some(subdomain) = subdomain else {
+            return Ok(self.first());
+        };
+        self.named()
+            .find(|f| f.subdomain == subdomain)
+            .with_context(|| format!("connection for unknown tunnel '{subdomain}'"))
+    }
+
+    fn first(&self) -> Forward {
+        let mut first = self.options.forwards[0].clone();
+        if first.subdomain.is_empty() {
+            first.subdomain = self.assigned.lock().unwrap().clone().unwrap_or_default();
+        }
+        first
+    }
+
+    /// Every forward, with the name the server picked filled in.
+    fn named(&self) -> impl Iterator<Item = Forward> + '_ {
+        std::iter::once(self.first()).chain(self.options.forwards[1..].iter().cloned())
+    }
+
+    /// The forwards to register: all but those closed on request.
+    fn live(&self) -> Vec<Forward> {
+        let closed = self.closed.lock().unwrap();
+        self.named()
+            .filter(|f| !closed.contains(&f.subdomain))
+            .collect()
+    }
+
+    fn settings(&self) -> ClientSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// The server in use.
+    pub(crate) fn server(&self) -> &str {
+        match self.current.load(Ordering::Relaxed) {
+            0 => &self.options.server,
+            n => &self.options.standby[n - 1],
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        self.hooks.fire(self.server(), &event);
+        let _ = self.events.try_send(event);
+    }
+
+    /// Apply a `Reconfigure`; fields the server left out keep their value.
+    fn reconfigure(&self, update: ClientSettings) {
+        if let Some(notice) = &update.notice {
+            self.emit(Event::Notice(notice.clone()));
+        }
+        let mut settings = self.settings.lock().unwrap();
+        let ClientSettings {
+            heartbeat_interval_ms,
+            reconnect_delay_secs,
+            max_connections,
+            notice,
+        } = update;
+        settings.heartbeat_interval_ms = heartbeat_interval_ms.or(settings.heartbeat_interval_ms);
+        settings.reconnect_delay_secs = reconnect_delay_secs.or(settings.reconnect_delay_secs);
+        settings.max_connections = max_connections.or(settings.max_connections);
+        settings.notice = notice.or(settings.notice.take());
+        info!(settings = ?*settings, "server pushed new settings");
+    }
+
+    /// How long the control connection may stay silent before we give up on it.
+    fn silence_limit(&self) -> Duration {
+        let timeouts = &self.options.timeouts;
+        let heartbeat = self
+            .settings()
+            .heartbeat_interval_ms
+            .map_or(HEARTBEAT_INTERVAL, Duration::from_millis)
+            .max(timeouts.heartbeat_interval);
+        (heartbeat * 10).max(timeouts.heartbeat_timeout)
+    }
+
+    /// Frame a connection to the server, waiting on it for the handshake
+    /// timeout.
+    pub(crate) fn framed<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Framed_<S> {
+        Framed_::new(stream).with_handshake_timeout(self.options.timeouts.handshake)
+    }
+
+    /// Record the server's traffic report for `subdomain`, with the rates
+    /// since the one before.
+    fn report(&self, subdomain: &str, conns: u64, bytes_in: u64, bytes_out: u64) -> TunnelTraffic {
+        let now = Instant::now();
+        let mut reports = self.reports.lock().unwrap();
+        let rate = |now_bytes: u64, then_bytes: u64, then: Instant| {
+            // Counts start over when the tunnel registers again.
+            let secs = now.duration_since(then).as_secs_f64();
+            match secs > 0.0 {
+                true => now_bytes.saturating_sub(then_bytes) as f64 / secs,
+                false => 0.0,
+            }
+        };
+        let (rate_in, rate_out) = match reports.get(subdomain) {
+            Some((last, then)) => (
+                rate(bytes_in, last.bytes_in, *then),
+                rate(bytes_out, last.bytes_out, *then),
+            ),
+            None => (0.0, 0.0),
+        };
+        let traffic = TunnelTraffic {
+            conns,
+            bytes_in,
+            bytes_out,
+            rate_in,
+            rate_out,
+        };
+        reports.insert(subdomain.to_owned(), (traffic, now));
+        traffic
+    }
+
+    /// Take a slot for a data connection, unless the server's connection
+    /// limit has been reached; then the limit comes back. Taken before the
+    /// connection is spawned, so that a burst can't overshoot it, and given
+    /// back with [`Shared::release_slot`].
+    fn reserve_slot(&self) -> Result<(), usize> {
+        let limit = self.settings().max_connections;
+        self.active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                limit.is_none_or(|limit| n < limit).then_some(n + 1)
+            })
+            .map(drop)
+            .map_err(|_| limit.unwrap_or_default())
+    }
+
+    /// Give a data connection's slot back, and close the tunnels if that was
+    /// the last visitor they could take.
+    fn release_slot(&self) {
+        let active = self.active.fetch_sub(1, Ordering::Relaxed) - 1;
+        let none_left = self
+            .uses_left
+            .as_ref()
+            .is_some_and(|n| n.load(Ordering::Relaxed) == 0);
+        if active == 0 && none_left {
+            self.used_up.cancel();
+        }
+    }
+
+    /// Count a visitor against `max_uses`; false once none is left. Probes
+    /// aren't counted.
+    fn take_use(&self) -> bool {
+        let Some(left) = &self.uses_left else {
+            return true;
+        };
+        if self.probing.load(Ordering::Relaxed) {
+            return true;
+        }
+        left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    }
+}
+
+// ── Main tunnel loop ──────────────────────────────────────────────────────────
+
+/// Close the tunnels once `ttl` is up or `max_uses` are used up. Servers of
+/// protocol version 15 or later do it themselves and get a moment to say so
+/// first.
+async fn enforce_limits(
+    shared: Arc<Shared>,
+    registered: watch::Receiver<Vec<Registration>>,
+    shutdown: CancellationToken,
+    finished: CancellationToken,
+) {
+    let deadline = async {
+        match shared.deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let message = tokio::select! {
+        _ = deadline => "tunnel reached its time limit",
+        _ = shared.used_up.cancelled() => "tunnel closed after its last visitor",
+        _ = finished.cancelled() => return,
+    };
+    if shared.version.load(Ordering::Relaxed) >= 15 {
+        tokio::select! {
+            _ = sleep(Duration::from_secs(2)) => {}
+            _ = finished.cancelled() => return,
+        }
+    }
+    let subdomains: Vec<String> = registered
+        .borrow()
+        .iter()
+        .map(|r| r.subdomain.clone())
+        .collect();
+    for subdomain in subdomains {
+        warn!(%subdomain, "{message}");
+        shared.emit(Event::Expired {
+            subdomain,
+            message: message.to_owned(),
+        });
+    }
+    shutdown.cancel();
+}
+
+/// Keep the tunnel up, reconnecting after failures that may go away.
+async fn run_forever(
+    shared: &Arc<Shared>,
+    shutdown: &CancellationToken,
+    registered: &watch::Sender<Vec<Registration>>,
+) -> Result<()> {
+    loop {
+        let connected_once = !registered.borrow().is_empty();
+        let registrations = registered.subscribe();
+        match run(shared, shutdown, registered).await {
+            Ok(_) => {
+                info!("tunnel closed cleanly");
+                return Ok(());
+            }
+            Err(e) => {
+                error!(err = format!("{e:#}"), "tunnel error");
+                shared.stats.record_error(&e);
+                // Wrong credentials never fix themselves, and a subdomain or
+                // port that is taken (or a quota that is used up) on the first
+                // attempt belongs to someone else. After a drop it is usually
+                // our own stale registration.
+                let fatal = match Failure::of(&e) {
+                    Failure::Auth => true,
+                    Failure::SubdomainTaken | Failure::PortUnavailable | Failure::Denied => {
+                        !connected_once
+                    }
+                    _ => false,
+                };
+                let reconnecting = !fatal && shared.options.reconnect;
+                shared.emit(Event::Disconnected {
+                    error: format!("{e:#}"),
+                    reconnecting,
+                });
+                if !reconnecting {
+                    return Err(e);
+                }
+                // With standbys, a server that can't be reached hands over to
+                // the next one at once. A session that was up, or a round
+                // where every server failed, starts over from the first after
+                // the usual pause.
+                let servers = 1 + shared.options.standby.len();
+                let was_up = registrations.has_changed().unwrap_or(false);
+                let current = shared.current.load(Ordering::Relaxed);
+                let next = match was_up {
+                    true => 0,
+                    false => (current + 1) % servers,
+                };
+                if next != current {
+                    shared.current.store(next, Ordering::Relaxed);
+                    if let Some(quic) = &shared.quic {
+                        quic.forget().await;
+                    }
+                    warn!(server = shared.server(), "failing over");
+                }
+                if !was_up && next != 0 {
+                    continue;
+                }
+                let delay = shared.settings().reconnect_delay_secs.unwrap_or(3);
+                warn!("reconnecting in {delay} seconds…");
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(delay)) => {}
+                    _ = shutdown.cancelled() => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+async fn run(
+    shared: &Arc<Shared>,
+    shutdown: &CancellationToken,
+    registered: &watch::Sender<Vec<Registration>>,
+) -> Result<()> {
+    let (ctrl, first, mux, warm) = tokio::select! {
+        result = register(shared) => result?,
+        _ = shutdown.cancelled() => return Ok(()),
+    };
+
+    // Older servers don't multiplex; they get a data connection per visitor.
+    if !mux {
+        return serve_session(ctrl, None, first, warm, shared, shutdown, registered).await;
+    }
+    let (mut control, mut streams) = multiplex(ctrl, SessionType::Client);
+    let first_stream = timeout(shared.options.timeouts.handshake, streams.recv())
+        .await
+        .ok()
+        .flatten();
+    let Some(first_stream) = first_stream else {
+        return Err(
+            TunnelError::new(Failure::Network, "server did not open a control stream").into(),
+        );
+    };
+    let ctrl = shared.framed(first_stream);
+    let streams = Some(streams);
+    let result = serve_session(ctrl, streams, first, None, shared, shutdown, registered).await;
+    control.close().await;
+    result
+}
+
+/// Register the remaining tunnels on the control connection, then handle
+/// control messages. With `warm`, the key the server gave, data connections
+/// are kept parked at the server meanwhile.
+async fn serve_session<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ctrl: Framed_<S>,
+    streams: Option<mpsc::Receiver<StreamHandle>>,
+    first: Registration,
+    warm: Option<Uuid>,
+    shared: &Arc<Shared>,
+    shutdown: &CancellationToken,
+    registered: &watch::Sender<Vec<Registration>>,
+) -> Result<()> {
+    let rest: Vec<Forward> = shared
+        .live()
+        .into_iter()
+        .filter(|f| f.subdomain != first.subdomain)
+        .collect();
+    let mut registrations = vec![first];
+    for forward in &rest {
+        registrations.push(register_more(&mut ctrl, forward, shared).await?);
+    }
+    for registration in &registrations {
+        shared.emit(Event::Connected(registration.clone()));
+    }
+    registered.send_replace(registrations);
+    let events = event_loop(ctrl, streams, shared, shutdown, registered);
+    match warm {
+        Some(key) => tokio::select! {
+            result = events => result,
+            () = keep_warm(key, shared) => Ok(()),
+        },
+        None => events.await,
+    }
+}
+
+/// Open a control connection and register the first tunnel. Returns the
+/// connection with the registration, whether the server multiplexes, and
+/// the key of the warm pool if it keeps one.
+async fn register(
+    shared: &Shared,
+) -> Result<(Framed_<Box<dyn Io>>, Registration, bool, Option<Uuid>)> {
+    let options = &shared.options;
+    let stream = connect_control(shared).await?;
+    let mut ctrl = shared.framed(stream);
+
+    // Auth (if secret provided).
+    let auth = options.secret.as_deref().map(Auth::new);
+    let challenge = match &auth {
+        Some(auth) => Some(auth.handshake(&mut ctrl).await?),
+        None => None,
+    };
+
+    let live = shared.live();
+    let forward = live.first().context("every tunnel was closed")?;
+    let current = shared.current.load(Ordering::Relaxed);
+    let resume = shared.sessions.lock().unwrap().get(&current).copied();
+    let mut hello = ClientMsg::Hello {
+        // Empty until the server picked one.
+        subdomain: Some(forward.subdomain.clone()).filter(|s| !s.is_empty()),
+        proto: forward.proto,
+        grpc: forward.grpc,
+        pool: options.pool,
+        takeover: options.takeover.clone(),
+        // Each data connection is a QUIC stream already, and resumable and
+        // warm ones must not go down with the control connection.
+        mux: !shared.quic.as_ref().is_some_and(Quic::in_use)
+            && !options.resumable
+            && options.warm_pool == 0,
+        warm: options.warm_pool > 0,
+        // What is left of them, after a reconnect.
+        ttl_secs: shared.deadline.map(|deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .as_secs()
+                .max(1)
+        }),
+        // The server can't tell probes from visitors.
+        max_uses: match shared.probing.load(Ordering::Relaxed) {
+            true => None,
+            false => shared.uses_left.as_ref().map(|n| n.load(Ordering::Relaxed)),
+        },
+        desired_port: forward.public_port,
+        acl: options.acl.clone(),
+        error_codes: true,
+        version: PROTOCOL_VERSION,
+        resume,
+        seal: None,
+        heartbeat_interval_ms: (options.timeouts.heartbeat_interval != HEARTBEAT_INTERVAL)
+            .then_some(options.timeouts.heartbeat_interval.as_millis() as u64),
+    };
+    // Bind the Hello to the handshake, so it can't be swapped on the way.
+    if let (Some(auth), Some(challenge)) = (&auth, &challenge) {
+        auth.seal(challenge, &mut hello);
+    }
+    ctrl.send(hello).await?;
+
+    match ctrl.recv_timeout::<ServerMsg>().await? {
+        Some(ServerMsg::Hello {
+            public_port,
+            url,
+            mux,
+            version,
+            session,
+            subdomain,
+            addrs,
+            warm,
+        }) => {
+            if negotiate(version).is_none() {
+                let message = format!(
+                    "server speaks protocol version {version}, this client needs \
+                     {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}; upgrade the server"
+                );
+                return Err(TunnelError::with_code(ErrorCode::ProtocolMismatch, message).into());
+            }
+            // Older servers let HTTP/1 visitors into a gRPC tunnel.
+            if version < 8 && shared.options.forwards.iter().any(|f| f.grpc) {
+                let message = format!(
+                    "server speaks protocol version {version}, gRPC tunnels need 8; \
+                     upgrade the server"
+                );
+                return Err(TunnelError::with_code(ErrorCode::ProtocolMismatch, message).into());
+            }
+            if version < 9 && options.resumable {
+                warn!(version, "server is too old to resume data connections");
+            }
+            if version < 10 && options.pool {
+                warn!(
+                    version,
+                    "server is too old for pools, the tunnels are not shared"
+                );
+            }
+            if version < 11 && options.takeover.is_some() {
+                warn!(
+                    version,
+                    "server is too old for takeovers, the tunnels can't be taken over"
+                );
+            }
+            if version < 13 && options.warm_pool > 0 {
+                warn!(
+                    version,
+                    "server is too old for warm pools, visitors wait for a data connection"
+                );
+            }
+            if version < 15 && (shared.deadline.is_some() || shared.uses_left.is_some()) {
+                warn!(
+                    version,
+                    "server is too old for tunnel limits, only the client enforces them"
+                );
+            }
+            check_public_port(forward, public_port);
+            let mut sessions = shared.sessions.lock().unwrap();
+            match session {
+                Some(session) => sessions.insert(current, session),
+                None => sessions.remove(&current),
+            };
+            drop(sessions);
+            shared.version.store(version, Ordering::Relaxed);
+            let subdomain = match subdomain {
+                Some(picked) if forward.subdomain.is_empty() => {
+                    *shared.assigned.lock().unwrap() = Some(picked.clone());
+                    picked
+                }
+                _ if forward.subdomain.is_empty() => {
+                    bail!("the server did not pick a subdomain; it may be too old, pass one")
+                }
+                _ => forward.subdomain.clone(),
+            };
+            let registration = Registration {
+                subdomain,
+                public_port,
+                url,
+                addrs,
+            };
+            Ok((ctrl, registration, mux, warm))
+        }
+        Some(ServerMsg::Error(e)) => Err(TunnelError::from_server(e).into()),
+        Some(ServerMsg::Refused { code, message }) => {
+            Err(TunnelError::from_code(code, message).into())
+        }
+        Some(ServerMsg::Challenge(_)) => Err(TunnelError::with_code(
+            ErrorCode::AuthRequired,
+            "server requires auth but no secret was given",
+        )
+        .into()),
+        None => Err(TunnelError::new(Failure::Network, "server closed the connection").into()),
+        Some(other) => Err(TunnelError::with_code(
+            ErrorCode::ProtocolMismatch,
+            format!("unexpected response from server: {other:?}"),
+        )
+        .into()),
+    }
+}
+
+/// Older servers ignore the public port a tunnel asks for.
+fn check_public_port(forward: &Forward, granted: u16) {
+    if let Some(wanted) = forward.public_port.filter(|&p| p != granted) {
+        warn!(
+            subdomain = %forward.subdomain,
+            wanted,
+            granted,
+            "server did not grant the requested public port"
+        );
+    }
+}
+
+/// Register one more tunnel on an established control connection.
+async fn register_more<S: AsyncRead + AsyncWrite + Unpin>(
+    ctrl: &mut Framed_<S>,
+    forward: &Forward,
+    shared: &Arc<Shared>,
+) -> Result<Registration> {
+    ctrl.send(ClientMsg::Register {
+        subdomain: forward.subdomain.clone(),
+        proto: forward.proto,
+        grpc: forward.grpc,
+        pool: shared.options.pool,
+        desired_port: forward.public_port,
+    })
+    .await?;
+    let reply = async {
+        loop {
+            match ctrl.recv::<ServerMsg>().await? {
+                Some(ServerMsg::Registered {
+                    subdomain,
+                    public_port,
+                    url,
+                    addrs,
+                }) if subdomain == forward.subdomain => {
+                    check_public_port(forward, public_port);
+                    return Ok(Registration {
+                        subdomain,
+                        public_port,
+                        url,
+                        addrs,
+                    });
+                }
+                Some(ServerMsg::Error(e)) => return Err(TunnelError::from_server(e).into()),
+                Some(ServerMsg::Refused { code, message }) => {
+                    return Err(TunnelError::from_code(code, message).into())
+                }
+                Some(ServerMsg::Ping { nonce, .. }) => ctrl.send(ClientMsg::Pong(nonce)).await?,
+                Some(msg) => {
+                    if let Some(reply) = dispatch(msg, shared) {
+                        ctrl.send(reply).await?;
+                    }
+                }
+                None => {
+                    return Err(
+                        TunnelError::new(Failure::Network, "server closed the connection").into(),
+                    )
+                }
+            }
+        }
+    };
+    timeout(shared.options.timeouts.handshake, reply)
+        .await
+        .map_err(|_| {
+            anyhow!(
+            "server did not register '{}'; it may be too old for several tunnels per connection",
+            forward.subdomain
+        )
+        })?
+}
+
+/// Handle control messages until the server goes away or the tunnel is shut
+/// down. `streams` carries inbound connections when the session is multiplexed.
+async fn event_loop<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ctrl: Framed_<S>,
+    mut streams: Option<mpsc::Receiver<StreamHandle>>,
+    shared: &Arc<Shared>,
+    shutdown: &CancellationToken,
+    registered: &watch::Sender<Vec<Registration>>,
+) -> Result<()> {
+    // A lost multiplexed connection reads as a clean end; the server only
+    // hangs up on purpose after saying why.
+    let mut told_why = false;
+    let mut handed_over = HashSet::new();
+    // Sent `Unregister` for, and not answered yet.
+    let mut unregistering = HashSet::new();
+    loop {
+        let silence_limit = shared.silence_limit();
+        let next_stream = async {
+            match &mut streams {
+                Some(streams) => streams.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let msg = tokio::select! {
+            msg = timeout(silence_limit, ctrl.recv::<ServerMsg>()) => msg.map_err(|_| {
+                TunnelError::new(
+                    Failure::Network,
+                    format!("no heartbeat from server for {silence_limit:?}"),
+                )
+            })??,
+            Some(stream) = next_stream => {
+                match shared.reserve_slot() {
+                    Err(limit) => warn!(limit, "connection limit reached, dropping connection"),
+                    Ok(()) if !shared.take_use() => {
+                        shared.release_slot();
+                        warn!("tunnel is used up, dropping connection");
+                    }
+                    Ok(()) => spawn_data_connection(DataConn::Stream(stream), shared),
+                }
+                continue;
+            }
+            _ = shared.closing.notified() => {
+                // Those closed while disconnected were left out already.
+                let closing: Vec<String> = {
+                    let closed = shared.closed.lock().unwrap();
+                    let registered = registered.borrow();
+                    registered
+                        .iter()
+                        .filter(|r| closed.contains(&r.subdomain))
+                        .map(|r| r.subdomain.clone())
+                        .collect()
+                };
+                for subdomain in closing {
+                    if unregistering.insert(subdomain.clone()) {
+                        ctrl.send(ClientMsg::Unregister { subdomain }).await?;
+                    }
+                }
+                continue;
+            }
+            _ = shutdown.cancelled() => break,
+        };
+        match msg {
+            Some(ServerMsg::Ping { nonce, rtt_ms }) => {
+                ctrl.send(ClientMsg::Pong(nonce)).await?;
+                if let Some(rtt_ms) = rtt_ms {
+                    debug!(rtt_ms, "server round trip");
+                    shared.stats.record_rtt(Duration::from_millis(rtt_ms));
+                }
+            }
+            Some(ServerMsg::Shutdown) => {
+                warn!("server is shutting down");
+                drain(&mut ctrl, shared, shutdown).await;
+                return Err(TunnelError::new(Failure::Network, "server shut down").into());
+            }
+            Some(ServerMsg::HandedOver { subdomain }) => {
+                warn!(%subdomain, "another client took over the tunnel");
+                shared.emit(Event::HandedOver {
+                    subdomain: subdomain.clone(),
+                });
+                handed_over.insert(subdomain);
+                // Nothing is left to serve: finish up and stay down, rather
+                // than take the tunnels back.
+                if shared
+                    .live()
+                    .iter()
+                    .all(|f| handed_over.contains(&f.subdomain))
+                {
+                    drain(&mut ctrl, shared, shutdown).await;
+                    break;
+                }
+            }
+            Some(ServerMsg::Unregistered { subdomain }) => {
+                info!(%subdomain, "tunnel closed");
+                unregistering.remove(&subdomain);
+                registered.send_modify(|r| r.retain(|r| r.subdomain != subdomain));
+                shared.emit(Event::Unregistered { subdomain });
+            }
+            Some(msg) => {
+                told_why |= matches!(msg, ServerMsg::Error(_) | ServerMsg::Expired { .. });
+                if let Some(reply) = dispatch(msg, shared) {
+                    ctrl.send(reply).await?;
+                }
+            }
+            None if told_why => break,
+            None => {
+                return Err(
+                    TunnelError::new(Failure::Network, "server closed the connection").into(),
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Let the connections in progress finish before hanging up. The server
+/// closes the connection itself when its drain timeout is up.
+async fn drain<S: AsyncRead + AsyncWrite + Unpin>(
+    ctrl: &mut Framed_<S>,
+    shared: &Shared,
+    shutdown: &CancellationToken,
+) {
+    let mut tick = interval(Duration::from_millis(100));
+    while shared.active.load(Ordering::Relaxed) > 0 {
+        tokio::select! {
+            _ = tick.tick() => {}
+            msg = ctrl.recv::<ServerMsg>() => if !matches!(msg, Ok(Some(_))) {
+                return;
+            },
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Act on a message that may arrive at any time on the control connection.
+/// Returns the answer to send back, if it needs one.
+fn dispatch(msg: ServerMsg, shared: &Arc<Shared>) -> Option<ClientMsg> {
+    match msg {
+        ServerMsg::Heartbeat => {}
+        ServerMsg::Connection {
+            id,
+            peer_addr,
+            subdomain,
+            public_port,
+            country,
+            asn,
+        } => {
+            let accepted = match shared.reserve_slot() {
+                Err(limit) => {
+                    warn!(%peer_addr, limit, "connection limit reached, declining connection");
+                    false
+                }
+                Ok(()) if !shared.take_use() => {
+                    shared.release_slot();
+                    warn!(%peer_addr, "tunnel is used up, declining connection");
+                    false
+                }
+                Ok(()) => {
+                    let cancelled = CancellationToken::new();
+                    shared.dialing.lock().unwrap().insert(id, cancelled.clone());
+                    let conn = DataConn::Dial {
+                        visitor: Visitor {
+                            id,
+                            peer_addr,
+                            public_port,
+                            country,
+                            asn,
+                        },
+                        subdomain,
+                        cancelled,
+                    };
+                    spawn_data_connection(conn, shared);
+                    true
+                }
+            };
+            // Older servers wait for the `Accept` either way.
+            if shared.version.load(Ordering::Relaxed) >= 5 {
+                return Some(ClientMsg::ConnectionAck { id, accepted });
+            }
+        }
+        ServerMsg::ConnectionCancelled { id } => {
+            if let Some(cancelled) = shared.dialing.lock().unwrap().remove(&id) {
+                warn!(%id, "server gave up on the connection before it was accepted");
+                cancelled.cancel();
+            }
+        }
+        ServerMsg::Stats {
+            subdomain,
+            conns,
+            bytes_in,
+            bytes_out,
+        } => {
+            let traffic = shared.report(&subdomain, conns, bytes_in, bytes_out);
+            shared.emit(Event::Traffic { subdomain, traffic });
+        }
+        ServerMsg::Reconfigure(update) => shared.reconfigure(update),
+        ServerMsg::Error(e) => error!("server: {e}"),
+        ServerMsg::Refused { code, message } => error!(?code, "server: {message}"),
+        ServerMsg::Expired { subdomain, message } => {
+            warn!(%subdomain, "{message}");
+            shared.emit(Event::Expired { subdomain, message });
+        }
+        _ => {}
+    }
+    None
+}
+
+// ── Data connection (one per inbound TCP connection) ──────────────────────────
+
+/// Who an inbound connection is from, as the server announced it.
+struct Visitor {
+    id: Uuid,
+    peer_addr: SocketAddr,
+    public_port: Option<u16>,
+    country: Option<String>,
+    asn: Option<u32>,
+}
+
+/// Where an inbound connection's bytes come from.
+enum DataConn {
+    /// Dial the control port and `Accept` the parked connection `id`,
+    /// unless the server gives up on it first.
+    Dial {
+        visitor: Visitor,
+        subdomain: Option<String>,
+        cancelled: CancellationToken,
+    },
+    /// A stream the server opened on the multiplexed session.
+    Stream(StreamHandle),
+    /// A warm connection the server just announced a visitor on.
+    Warm {
+        conn: Framed_<Box<dyn Io>>,
+        visitor: Visitor,
+        subdomain: Option<String>,
+    },
+}
+
+/// Serve `conn` in a slot taken with [`Shared::reserve_slot`].
+fn spawn_data_connection(conn: DataConn, shared: &Arc<Shared>) {
+    let shared = Arc::clone(shared);
+    tokio::spawn(
+        async move {
+            if let Err(e) = handle_data_connection(conn, &shared).await {
+                warn!(err = format!("{e:#}"), "data connection error");
+                shared.stats.record_error(&e);
+            }
+            shared.release_slot();
+        }
+        .in_current_span(),
+    );
+}
+
+/// Serve one inbound connection.
+async fn handle_data_connection(conn: DataConn, shared: &Shared) -> Result<()> {
+    match conn {
+        DataConn::Dial {
+            visitor,
+            subdomain,
+            cancelled,
+        } => {
+            let id = visitor.id;
+            let accept = async {
+                let forward = shared.forward(subdomain.as_deref())?;
+                // Open a NEW control-port connection just for this data stream.
+                let stream = connect_control(shared).await?;
+                let mut data_conn = shared.framed(stream);
+
+                // Re-auth if needed.
+                if let Some(secret) = &shared.options.secret {
+                    Auth::new(secret).handshake(&mut data_conn).await?;
+                }
+
+                // Tell server which pending connection we're accepting.
+                let resumable = shared.options.resumable
+                    && forward.proto != Proto::Udp
+                    && shared.version.load(Ordering::Relaxed) >= 9;
+                match resumable {
+                    true => data_conn.send(ClientMsg::AcceptResumable(id)).await?,
+                    false => data_conn.send(ClientMsg::Accept(id)).await?,
+                }
+                anyhow::Ok((data_conn, forward, resumable.then_some(id)))
+            };
+            let accepted = tokio::select! {
+                accepted = accept => accepted,
+                _ = cancelled.cancelled() => return Ok(()),
+            };
+            shared.dialing.lock().unwrap().remove(&id);
+            let (data_conn, forward, resume) = accepted?;
+            serve_visitor(data_conn, visitor, resume, &forward, shared).await
+        }
+        DataConn::Stream(stream) => {
+            // The stream opens with a `Connection` frame describing the visitor.
+            let mut data_conn = shared.framed(stream);
+            let (visitor, subdomain) = announced(data_conn.recv_timeout().await?)?;
+            let forward = &shared.forward(subdomain.as_deref())?;
+            serve_visitor(data_conn, visitor, None, forward, shared).await
+        }
+        DataConn::Warm {
+            conn,
+            visitor,
+            subdomain,
+        } => {
+            let forward = &shared.forward(subdomain.as_deref())?;
+            serve_visitor(conn, visitor, None, forward, shared).await
+        }
+    }
+}
+
+/// The visitor the first frame of a data stream or warm connection
+/// describes, and the tunnel it is for.
+fn announced(first: Option<ServerMsg>) -> Result<(Visitor, Option<String>)> {
+    match first {
+        Some(ServerMsg::Connection {
+            id,
+            peer_addr,
+            subdomain,
+            public_port,
+            country,
+            asn,
+        }) => {
+            let visitor = Visitor {
+                id,
+                peer_addr,
+                public_port,
+                country,
+                asn,
+            };
+            Ok((visitor, subdomain))
+        }
+        other => bail!("unexpected first frame on data stream: {other:?}"),
+    }
+}
+
+/// Keep the warm pool full for the session `key`.
+async fn keep_warm(key: Uuid, shared: &Arc<Shared>) {
+    let slots = (0..shared.options.warm_pool).map(|_| warm_slot(key, shared));
+    join_all(slots).await;
+}
+
+/// Park a data connection at the server, and park another once a visitor
+/// took it or it dropped.
+async fn warm_slot(key: Uuid, shared: &Arc<Shared>) {
+    let mut delay = WARM_RETRY_FIRST;
+    loop {
+        match standby(key, shared).await {
+            Ok(conn) => {
+                delay = WARM_RETRY_FIRST;
+                match shared.reserve_slot() {
+                    Err(limit) => warn!(limit, "connection limit reached, dropping connection"),
+                    Ok(()) if !shared.take_use() => {
+                        shared.release_slot();
+                        warn!("tunnel is used up, dropping connection");
+                    }
+                    Ok(()) => spawn_data_connection(conn, shared),
+                }
+            }
+            Err(e) => {
+                debug!(err = format!("{e:#}"), "cannot park a warm connection");
+                sleep(delay).await;
+                delay = (delay * 2).min(WARM_RETRY_MAX);
+            }
+        }
+    }
+}
+
+/// Park a data connection at the server until a visitor comes down it.
+async fn standby(key: Uuid, shared: &Shared) -> Result<DataConn> {
+    let stream = connect_control(shared).await?;
+    let mut conn = shared.framed(stream);
+    if let Some(secret) = &shared.options.secret {
+        Auth::new(secret).handshake(&mut conn).await?;
+    }
+    conn.send(ClientMsg::Standby(key)).await?;
+    let Some(first) = conn.recv::<ServerMsg>().await? else {
+        bail!("the server closed the warm connection");
+    };
+    let (visitor, subdomain) = announced(Some(first))?;
+    Ok(DataConn::Warm {
+        conn,
+        visitor,
+        subdomain,
+    })
+}
+
+/// Splice a visitor's data connection to the local service. With `resume`,
+/// the id it was accepted with, it is a resumable stream.
+async fn serve_visitor<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    data_conn: Framed_<S>,
+    visitor: Visitor,
+    resume: Option<Uuid>,
+    forward: &Forward,
+    shared: &Shared,
+) -> Result<()> {
+    let span = info_span!(
+        "conn",
+        id = %visitor.id,
+        peer = %visitor.peer_addr,
+        subdomain = %forward.subdomain,
+        public_port = visitor.public_port,
+        country = visitor.country,
+        asn = visitor.asn,
+    );
+    relay_visitor(data_conn, visitor, resume, forward, shared)
+        .instrument(span)
+        .await
+}
+
+async fn relay_visitor<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    data_conn: Framed_<S>,
+    visitor: Visitor,
+    resume: Option<Uuid>,
+    forward: &Forward,
+    shared: &Shared,
+) -> Result<()> {
+    let peer_addr = visitor.peer_addr;
+    // UDP has no connection to probe with.
+    if forward.proto != Proto::Udp && shared.probing.load(Ordering::Relaxed) {
+        info!(%peer_addr, "reachability probe");
+        shared.emit(Event::Probed {
+            subdomain: forward.subdomain.clone(),
+            peer_addr,
+        });
+        return Ok(());
+    }
+    if !shared.options.acl.permits(peer_addr.ip()) {
+        info!(%peer_addr, "connection denied by ACL");
+        return Ok(());
+    }
+    let subdomain = forward.subdomain.clone();
+    shared.emit(Event::Connection {
+        subdomain: subdomain.clone(),
+        peer_addr,
+        public_port: visitor.public_port,
+        country: visitor.country.clone(),
+        asn: visitor.asn,
+    });
+    let relayed = match forward.proto {
+        Proto::Udp => relay_datagrams(data_conn, peer_addr, forward, shared).await,
+        _ => relay_stream(data_conn, peer_addr, resume, forward, shared).await,
+    };
+    let (bytes_in, bytes_out) = match relayed {
+        Ok(Some(counts)) => {
+            shared.stats.record_connection();
+            counts
+        }
+        _ => (0, 0),
+    };
+    info!(bytes_in, bytes_out, "connection closed");
+    shared.emit(Event::ConnectionClosed {
+        subdomain,
+        peer_addr,
+        bytes_in,
+        bytes_out,
+    });
+    relayed.map(drop)
+}
+
+/// Copy a TCP or HTTP visitor's bytes to and from the local service. Returns
+/// the bytes moved in each direction, or `None` if the visitor was rejected.
+async fn relay_stream<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    data_conn: Framed_<S>,
+    peer_addr: SocketAddr,
+    resume: Option<Uuid>,
+    forward: &Forward,
+    shared: &Shared,
+) -> Result<Option<(u64, u64)>> {
+    let Some(id) = resume else {
+        // Upgrade: discard the framing codec, use raw bytes from here.
+        let parts = data_conn.into_parts();
+        let buffered = parts.read_buf.to_vec();
+        return relay_bytes(parts.io, buffered, peer_addr, forward, shared).await;
+    };
+    // The visitor's bytes come out of the stream at the other end of a pipe.
+    let (visitor, far) = tokio::io::duplex(shared.options.buffer_size.max(MAX_DATAGRAM));
+    let link = resume::unframe(data_conn);
+    let resumed = resume::relay(far, Vec::new(), link, |err| relink(id, err, shared));
+    let relayed = relay_bytes(visitor, Vec::new(), peer_addr, forward, shared);
+    let (resumed, relayed) = tokio::join!(resumed, relayed);
+    resumed.context("resumable stream failed")?;
+    relayed
+}
+
+/// Open a new data connection for the resumable stream `id`, whose last one
+/// failed with `err`. Retries for up to [`RESUME_WINDOW`], while the server
+/// waits.
+async fn relink(id: Uuid, err: io::Error, shared: &Shared) -> io::Result<Box<dyn Link>> {
+    warn!(err = %err, "data connection dropped, resuming");
+    let deadline = Instant::now() + RESUME_WINDOW;
+    let mut delay = Duration::from_millis(100);
+    loop {
+        let e = match reopen(id, shared).await {
+            Ok(link) => {
+                info!("data connection resumed");
+                return Ok(link);
+            }
+            // The server gave up on it, or never knew it.
+            Err(e) if TunnelError::code_of(&e).is_some() => e,
+            Err(e) if Instant::now() + delay < deadline => {
+                debug!(err = format!("{e:#}"), "cannot resume yet");
+                sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(2));
+                continue;
+            }
+            Err(e) => e,
+        };
+        return Err(io::Error::other(format!("cannot resume: {e:#}")));
+    }
+}
+
+/// A data connection that carries on the resumable stream `id`.
+async fn reopen(id: Uuid, shared: &Shared) -> Result<Box<dyn Link>> {
+    let stream = connect_control(shared).await?;
+    let mut conn = shared.framed(stream);
+    if let Some(secret) = &shared.options.secret {
+        Auth::new(secret).handshake(&mut conn).await?;
+    }
+    conn.send(ClientMsg::ResumeStream(id)).await?;
+    match conn.recv_timeout::<ServerMsg>().await? {
+        Some(ServerMsg::StreamResumed) => Ok(resume::unframe(conn)),
+        Some(ServerMsg::Refused { code, message }) => {
+            Err(TunnelError::from_code(code, message).into())
+        }
+        Some(ServerMsg::Error(message)) => Err(TunnelError::from_server(message).into()),
+        Some(other) => bail!("unexpected reply to ResumeStream: {other:?}"),
+        None => bail!("the server hung up"),
+    }
+}
+
+/// [`relay_stream`] from the visitor's raw bytes on, the first of which
+/// are `buffered`.
+async fn relay_bytes<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    io: S,
+    buffered: Vec<u8>,
+    peer_addr: SocketAddr,
+    forward: &Forward,
+    shared: &Shared,
+) -> Result<Option<(u64, u64)>> {
+    let Some(keypair) = &shared.e2e else {
+        return relay_plain(io, buffered, peer_addr, forward, shared).await;
+    };
+    // The visitor's handshake may have come in with the framing.
+    let (read, write) = tokio::io::split(io);
+    let visitor = tokio::io::join(io::Cursor::new(buffered).chain(read), write);
+    let visitor = match e2e::accept(visitor, keypair).await {
+        Ok(visitor) => visitor,
+        Err(e) => {
+            warn!(%peer_addr, err = format!("{e:#}"), "end-to-end handshake failed");
+            return Ok(None);
+        }
+    };
+    relay_plain(visitor, Vec::new(), peer_addr, forward, shared).await
+}
+
+/// [`relay_stream`] once the visitor's bytes are plaintext; `buffered`
+/// already came from `io`.
+async fn relay_plain<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    mut io: S,
+    mut buffered: Vec<u8>,
+    peer_addr: SocketAddr,
+    forward: &Forward,
+    shared: &Shared,
+) -> Result<Option<(u64, u64)>> {
+    // gRPC visitors speak HTTP/2, which has no request line.
+    let request_line = match forward.proto {
+        Proto::Http if !forward.grpc => peek_request_line(&mut io, &mut buffered).await,
+        _ => None,
+    };
+
+    // Nothing reaches the local service until the operator approves.
+    let approver = shared.approver.as_ref();
+    if let Some(approver) = approver.filter(|a| !a.is_remembered(peer_addr.ip())) {
+        if !approver.ask(peer_addr, request_line.as_deref()).await {
+            info!(%peer_addr, "connection rejected");
+            return Ok(None);
+        }
+    }
+    if let Some(allow) = &shared.options.socks {
+        return relay_socks(io, buffered, peer_addr, allow, shared).await;
+    }
+    if let Some(stdio) = &shared.stdio {
+        return relay_stdio(io, buffered, peer_addr, stdio, shared).await;
+    }
+    if shared.options.echo {
+        return relay_echo(io, buffered, shared).await.map(Some);
+    }
+    let rules = (forward.proto == Proto::Http && !forward.grpc).then_some(&shared.options.requests);
+    let mut io = Rewriting::new(io, rules);
+    let mut buffered = io.rewrite(buffered);
+    // Visitors without the credentials get no further than their first head.
+    if io.is_guarded() {
+        while io.verdict() == Verdict::Pending {
+            match timeout(HANDSHAKE_TIMEOUT, io.read_buf(&mut buffered)).await {
+                Ok(Ok(n)) if n > 0 => {}
+                _ if io.verdict() == Verdict::Denied => {}
+                _ => return Ok(None),
+            }
+        }
+        if io.verdict() == Verdict::Denied {
+            debug!(%peer_addr, "HTTP request without valid credentials");
+            let response = rewrite::unauthorized(&forward.subdomain);
+            io.write_all(&response).await?;
+            io.shutdown().await?;
+            return Ok(Some((0, response.len() as u64)));
+        }
+    }
+    if let Some(line) = request_line {
+        shared.emit(Event::Request {
+            subdomain: forward.subdomain.clone(),
+            peer_addr,
+            line,
+        });
+    }
+
+    if let Some(share) = &shared.options.share {
+        return share::serve(&mut io, buffered, share).await.map(Some);
+    }
+
+    let tap = match (forward.proto, &shared.inspector) {
+        (Proto::Http, Some(inspector)) if forward.grpc => Some(Tap::Grpc(GrpcTap::new(
+            Arc::clone(inspector),
+            &forward.subdomain,
+            peer_addr,
+        ))),
+        (Proto::Http, Some(inspector)) => Some(Tap::Http(HttpTap::new(
+            Arc::clone(inspector),
+            &forward.subdomain,
+            peer_addr,
+            &shared.options.local_host,
+            forward.local_port,
+            shared.options.unix_socket.as_deref(),
+        ))),
+        _ => None,
+    };
+    // Connect to local service. HTTP visitors are told why it failed.
+    let (host, mut port) = (&shared.options.local_host, forward.local_port);
+    // Holds the port picked for the connection until it ends.
+    let mut _backend = None;
+    let local = match &shared.options.exec {
+        Some(command) => spawn_local(command, peer_addr, &forward.subdomain),
+        None if !forward.upstreams.is_empty() => {
+            connect_balanced(forward, shared)
+                .await
+                .map(|(local, pick)| {
+                    port = pick.port;
+                    _backend = Some(pick);
+                    local
+                })
+        }
+        None => {
+            let unix_socket = shared.options.unix_socket.as_deref();
+            connect_local_within(host, port, unix_socket, shared.options.local_retry).await
+        }
+    };
+    let local = match local {
+        Ok(local) => local,
+        // A gRPC client would not read an HTTP/1 page.
+        Err(e) if forward.proto == Proto::Http && !forward.grpc => {
+            warn!(%peer_addr, err = format!("{e:#}"), "local service unreachable, answering 502");
+            let response = bad_gateway(&shared.options, forward, &e);
+            if let Some(mut tap) = tap {
+                tap.request(&buffered);
+                tap.response(&response);
+            }
+            shared
+                .stats
+                .record_transfer(buffered.len() as u64, response.len() as u64);
+            io.write_all(&response).await?;
+            io.shutdown().await?;
+            return Ok(Some((buffered.len() as u64, response.len() as u64)));
+        }
+        Err(e) => return Err(e),
+    };
+    // Recorded as if the visitor had connected to the local service.
+    let tap = match (&shared.pcap, forward.proto) {
+        (Some(pcap), Proto::Tcp) => {
+            let local_addr = local.peer_addr()?;
+            let local_addr = local_addr.unwrap_or(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+            Some(Tap::Pcap(PcapTap::new(
+                Arc::clone(pcap),
+                peer_addr,
+                local_addr,
+            )))
+        }
+        _ => tap,
+    };
+    // Calls may sit quiet for long between messages: don't let small frames
+    // wait, and notice a local service that went away.
+    if let (true, LocalIo::Tcp(stream)) = (forward.grpc, &local) {
+        if let Err(e) = tune_socket(stream, GRPC_KEEPALIVE) {
+            debug!(err = %e, "cannot tune local socket");
+        }
+    }
+    let mut local = LocalStream {
+        io: local,
+        stats: &shared.stats,
+        tap,
+    };
+    if let Some(version) = shared.options.proxy_protocol {
+        // A Unix socket has no address; say the visitor came to localhost.
+        let local_addr = local.io.peer_addr()?;
+        let local_addr = local_addr.unwrap_or(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        let header = proxy::header(version, peer_addr, local_addr);
+        local.io.write_all(&header).await?;
+    }
+    if let Some(chaos) = shared.options.chaos.filter(|_| !buffered.is_empty()) {
+        sleep(chaos.delay()).await;
+    }
+    local.write_all(&buffered).await?;
+    // Nothing needs to see the bytes between two plain sockets: let the
+    // kernel move them.
+    let zero_copy = match (&local.io, &local.tap, &shared.options.chaos) {
+        (LocalIo::Tcp(local_tcp), None, None) if splice::ZERO_COPY => io
+            .passthrough()
+            .and_then(plain_tcp)
+            .map(|visitor| (local_tcp, visitor)),
+        _ => None,
+    };
+    let (to_visitor, to_local) = match zero_copy {
+        Some((local_tcp, visitor)) => {
+            let stats = &shared.stats;
+            splice::relay(
+                local_tcp,
+                visitor,
+                |n| stats.record_transfer(0, n as u64),
+                |n| stats.record_transfer(n as u64, 0),
+            )
+            .await?
+        }
+        None => match &shared.options.chaos {
+            Some(chaos) => chaos.relay(&mut local, &mut io).await?,
+            None => {
+                let size = shared.options.buffer_size;
+                tokio::io::copy_bidirectional_with_sizes(&mut local, &mut io, size, size).await?
+            }
+        },
+    };
+    Ok(Some((to_local + buffered.len() as u64, to_visitor)))
+}
+
+/// The TCP socket under a visitor's data connection, if it is a plain one.
+fn plain_tcp<S: 'static>(io: &S) -> Option<&TcpStream> {
+    let stream = (io as &dyn Any).downcast_ref::<Box<dyn Io>>()?;
+    // Deref twice: the box is an `Io` itself.
+    (**stream).as_any().downcast_ref::<TcpStream>()
+}
+
+/// Connect a visitor to the destination it asks for as a SOCKS5 client.
+async fn relay_socks<S: AsyncRead + AsyncWrite + Unpin>(
+    io: S,
+    buffered: Vec<u8>,
+    peer_addr: SocketAddr,
+    allow: &[AllowRule],
+    shared: &Shared,
+) -> Result<Option<(u64, u64)>> {
+    let (read, write) = tokio::io::split(io);
+    let mut visitor = tokio::io::join(io::Cursor::new(buffered).chain(read), write);
+    let (local, target) = match socks::connect(&mut visitor, allow).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            warn!(%peer_addr, err = format!("{e:#}"), "SOCKS request refused");
+            return Ok(None);
+        }
+    };
+    info!(%peer_addr, %target, "SOCKS connection");
+    let mut local = LocalStream {
+        io: LocalIo::Tcp(local),
+        stats: &shared.stats,
+        tap: None,
+    };
+    let size = shared.options.buffer_size;
+    let (to_visitor, to_local) =
+        tokio::io::copy_bidirectional_with_sizes(&mut local, &mut visitor, size, size).await?;
+    Ok(Some((to_local, to_visitor)))
+}
+
+/// Send a visitor's bytes back to it until it stops sending.
+async fn relay_echo<S: AsyncRead + AsyncWrite + Unpin>(
+    io: S,
+    buffered: Vec<u8>,
+    shared: &Shared,
+) -> Result<(u64, u64)> {
+    let (read, mut write) = tokio::io::split(io);
+    let mut read = tokio::io::BufReader::with_capacity(shared.options.buffer_size, read);
+    write.write_all(&buffered).await?;
+    let n = buffered.len() as u64 + tokio::io::copy_buf(&mut read, &mut write).await?;
+    write.shutdown().await?;
+    Ok((n, n))
+}
+
+/// Connect the first visitor to stdin and stdout, and close the tunnel when
+/// it leaves.
+async fn relay_stdio<S: AsyncRead + AsyncWrite + Unpin>(
+    io: S,
+    buffered: Vec<u8>,
+    peer_addr: SocketAddr,
+    stdio: &Stdio,
+    shared: &Shared,
+) -> Result<Option<(u64, u64)>> {
+    if stdio.taken.swap(true, Ordering::Relaxed) {
+        info!(%peer_addr, "stdin and stdout are taken, connection rejected");
+        return Ok(None);
+    }
+    info!(%peer_addr, "visitor connected to stdin and stdout");
+    let _closed = stdio.closed.clone().drop_guard();
+    let local = LocalStream {
+        io: LocalIo::Stdio(tokio::io::join(tokio::io::stdin(), tokio::io::stdout())),
+        stats: &shared.stats,
+        tap: None,
+    };
+    let (mut from_stdin, mut to_stdout) = tokio::io::split(local);
+    let (mut read, mut write) = tokio::io::split(io);
+    to_stdout.write_all(&buffered).await?;
+    let to_local = async {
+        let n = tokio::io::copy(&mut read, &mut to_stdout).await?;
+        to_stdout.flush().await?;
+        io::Result::Ok(n)
+    };
+    let to_visitor = async {
+        let n = tokio::io::copy(&mut from_stdin, &mut write).await?;
+        write.shutdown().await?;
+        io::Result::Ok(n)
+    };
+    tokio::pin!(to_local, to_visitor);
+    // Stdin may stay open after the visitor is gone; its side decides.
+    let mut to_visitor_done = None;
+    let to_local = loop {
+        tokio::select! {
+            n = &mut to_local => break n?,
+            n = &mut to_visitor, if to_visitor_done.is_none() => to_visitor_done = Some(n?),
+        }
+    };
+    Ok(Some((
+        to_local + buffered.len() as u64,
+        to_visitor_done.unwrap_or(0),
+    )))
+}
+
+/// The 502 response for a visitor of `forward` whose local service failed
+/// with `err`.
+fn bad_gateway(options: &Options, forward: &Forward, err: &anyhow::Error) -> Vec<u8> {
+    let template = options.error_page.as_deref().unwrap_or(BAD_GATEWAY_PAGE);
+    let local = match (&options.exec, &options.unix_socket) {
+        (Some(command), _) => command.clone(),
+        (None, Some(path)) => path.display().to_string(),
+        (None, None) => forward.local_addr(&options.local_host),
+    };
+    let body = template
+        .replace("{{subdomain}}", &escape(&forward.subdomain))
+        .replace("{{local}}", &escape(&local))
+        .replace("{{error}}", &escape(&format!("{err:#}")));
+    let mut response = format!(
+        "HTTP/1.1 502 Bad Gateway\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body.as_bytes());
+    response
+}
+
+/// Exchange a UDP flow's datagrams with the local service until either side
+/// goes quiet for [`UDP_IDLE_TIMEOUT`].
+async fn relay_datagrams<S: AsyncRead + AsyncWrite + Unpin>(
+    data_conn: Framed_<S>,
+    peer_addr: SocketAddr,
+    forward: &Forward,
+    shared: &Shared,
+) -> Result<Option<(u64, u64)>> {
+    let approver = shared.approver.as_ref();
+    if let Some(approver) = approver.filter(|a| !a.is_remembered(peer_addr.ip())) {
+        if !approver.ask(peer_addr, None).await {
+            info!(%peer_addr, "connection rejected");
+            return Ok(None);
+        }
+    }
+
+    // Datagrams can't be refused, so each flow just takes its turn.
+    let backend = shared.balancer.pick(&forward.local_ports(), &[]);
+    let (host, port) = (&shared.options.local_host, backend.port);
+    let target = lookup_host((host.as_str(), port))
+        .await?
+        .next()
+        .with_context(|| format!("cannot resolve {host}"))?;
+    let unspecified: IpAddr = match target {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let local = UdpSocket::bind((unspecified, 0)).await?;
+    local.connect(target).await?;
+
+    let mut remote = data_conn.into_datagrams();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let (mut bytes_in, mut bytes_out) = (0, 0);
+    // Only used with --chaos.
+    let chaos = shared.options.chaos.as_ref();
+    let (mut to_local, mut to_remote) = (DelayLine::default(), DelayLine::default());
+    loop {
+        tokio::select! {
+            datagram = remote.next() => {
+                let Some(datagram) = datagram.transpose()? else { break };
+                bytes_in += datagram.len() as u64;
+                shared.stats.record_transfer(datagram.len() as u64, 0);
+                match chaos {
+                    Some(chaos) if chaos.drops() || to_local.is_full() => {}
+                    Some(chaos) => to_local.push(datagram, chaos.delay()),
+                    // Lost like on the network if nothing listens locally.
+                    None => {
+                        let _ = local.send(&datagram).await;
+                    }
+                }
+            }
+            datagram = to_local.pop() => {
+                let _ = local.send(&datagram).await;
+            }
+            reply = local.recv(&mut buf) => {
+                // Errors are ICMP reports of earlier sends; keep going.
+                let Ok(n) = reply else { continue };
+                bytes_out += n as u64;
+                shared.stats.record_transfer(0, n as u64);
+                let reply = Bytes::copy_from_slice(&buf[..n]);
+                match chaos {
+                    Some(chaos) if chaos.drops() || to_remote.is_full() => {}
+                    Some(chaos) => to_remote.push(reply, chaos.delay()),
+                    None => remote.send(reply).await?,
+                }
+            }
+            reply = to_remote.pop() => remote.send(reply).await?,
+            _ = sleep(UDP_IDLE_TIMEOUT) => break,
+        }
+    }
+    Ok(Some((bytes_in, bytes_out)))
+}
+
+/// Read until the end of the HTTP request line, keeping the bytes in `buf`.
+async fn peek_request_line(io: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>) -> Option<String> {
+    let read_line = async {
+        while !buf.contains(&b'\n') && buf.len() < 1024 {
+            if io.read_buf(buf).await.ok()? == 0 {
+                return None;
+            }
+        }
+        Some(())
+    };
+    timeout(Duration::from_secs(2), read_line).await.ok()??;
+    let end = buf.iter().position(|&b| b == b'\n').unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..end]).trim_end().to_owned())
+}
+
+// ── Helper ────────────────────────────────────────────────────────────────────
+
+/// The connection to the local service. The bytes that pass are added to
+/// the stats as they go, so rates show while a connection is open, and
+/// parsed as HTTP when inspecting.
+struct LocalStream<'a> {
+    io: LocalIo,
+    stats: &'a Stats,
+    tap: Option<Tap>,
+}
+
+impl AsyncRead for LocalStream<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.io).poll_read(cx, buf);
+        let read = &buf.filled()[before..];
+        self.stats.record_transfer(0, read.len() as u64);
+        if let Some(tap) = &mut self.tap {
+            tap.response(read);
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for LocalStream<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.io).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.stats.record_transfer(n as u64, 0);
+            if let Some(tap) = &mut self.tap {
+                tap.request(&buf[..n]);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// A connection to the local service: TCP, a Unix socket standing in for
+/// its host and port, stdin and stdout, or those of a child process.
+pub(crate) enum LocalIo {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+    /// This process's stdin and stdout, in stdio mode.
+    Stdio(tokio::io::Join<tokio::io::Stdin, tokio::io::Stdout>),
+    /// The stdout and stdin of a process run for one visitor.
+    Exec(ChildIo),
+}
+
+/// A process run for one visitor, killed when dropped.
+pub(crate) struct ChildIo {
+    _child: Child,
+    stdout: ChildStdout,
+    /// Dropped on shutdown: closing the pipe is the only way the process
+    /// sees the end of its input.
+    stdin: Option<ChildStdin>,
+}
+
+impl AsyncRead for ChildIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ChildIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().stdin {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stdin {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(stdin) = &mut this.stdin {
+            ready!(Pin::new(stdin).poll_flush(cx))?;
+        }
+        this.stdin = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl LocalIo {
+    /// Address of the local service; `None` for a Unix socket.
+    fn peer_addr(&self) -> io::Result<Option<SocketAddr>> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().map(Some),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(None),
+            Self::Stdio(_) | Self::Exec(_) => Ok(None),
+        }
+    }
+}
+
+impl AsyncRead for LocalIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Stdio(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Exec(child) => Pin::new(child).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LocalIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Stdio(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Exec(child) => Pin::new(child).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Stdio(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Exec(child) => Pin::new(child).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Stdio(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Exec(child) => Pin::new(child).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connect to the local service at `host:port`, or at `unix_socket` if
+/// there is one.
+pub(crate) async fn connect_local(
+    host: &str,
+    port: u16,
+    unix_socket: Option<&Path>,
+) -> Result<LocalIo> {
+    match unix_socket {
+        #[cfg(unix)]
+        Some(path) => tokio::net::UnixStream::connect(path)
+            .await
+            .map(LocalIo::Unix)
+            .with_context(|| format!("cannot connect to {}", path.display())),
+        #[cfg(not(unix))]
+        Some(_) => bail!("Unix sockets are not supported on this platform"),
+        None => connect(host, port).await.map(LocalIo::Tcp),
+    }
+}
+
+/// [`connect_local`], tried again with backoff for `window` while the local
+/// service is down.
+async fn connect_local_within(
+    host: &str,
+    port: u16,
+    unix_socket: Option<&Path>,
+    window: Duration,
+) -> Result<LocalIo> {
+    let deadline = Instant::now() + window;
+    let mut delay = LOCAL_RETRY_FIRST;
+    loop {
+        let err = match connect_local(host, port, unix_socket).await {
+            Ok(local) => return Ok(local),
+            Err(e) => e,
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(err);
+        }
+        debug!(
+            err = format!("{err:#}"),
+            "local service unreachable, trying again"
+        );
+        sleep(delay.min(left)).await;
+        delay = (delay * 2).min(LOCAL_RETRY_MAX);
+    }
+}
+
+/// Connect to one of the local ports of `forward`, trying the others when it
+/// fails, and all of them again with backoff for the `local_retry` window.
+/// The [`Pick`] counts the connection until it is dropped.
+async fn connect_balanced<'a>(
+    forward: &Forward,
+    shared: &'a Shared,
+) -> Result<(LocalIo, Pick<'a>)> {
+    let (host, ports) = (&shared.options.local_host, forward.local_ports());
+    let deadline = Instant::now() + shared.options.local_retry;
+    let mut delay = LOCAL_RETRY_FIRST;
+    loop {
+        let mut tried = Vec::new();
+        let mut err = None;
+        while tried.len() < ports.len() {
+            let pick = shared.balancer.pick(&ports, &tried);
+            match connect(host, pick.port).await {
+                Ok(stream) => {
+                    pick.succeeded();
+                    return Ok((LocalIo::Tcp(stream), pick));
+                }
+                Err(e) => {
+                    debug!(
+                        port = pick.port,
+                        err = format!("{e:#}"),
+                        "local port unreachable"
+                    );
+                    pick.failed();
+                    tried.push(pick.port);
+                    err = Some(e);
+                }
+            }
+        }
+        let err = err.unwrap().context(format!(
+            "no local port of {} took the connection",
+            forward.subdomain
+        ));
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(err);
+        }
+        sleep(delay.min(left)).await;
+        delay = (delay * 2).min(LOCAL_RETRY_MAX);
+    }
+}
+
+/// Run `command` for the visitor from `peer_addr`, who gets its stdin and
+/// stdout. It learns the visitor and tunnel from `SSHX_REMOTE_ADDR` and
+/// `SSHX_SUBDOMAIN`; its stderr is ours.
+fn spawn_local(command: &str, peer_addr: SocketAddr, subdomain: &str) -> Result<LocalIo> {
+    let (shell, flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let mut child = Command::new(shell)
+        .args([flag, command])
+        .env("SSHX_REMOTE_ADDR", peer_addr.to_string())
+        .env("SSHX_SUBDOMAIN", subdomain)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("cannot run '{command}'"))?;
+    let (Some(stdout), Some(stdin)) = (child.stdout.take(), child.stdin.take()) else {
+        bail!("'{command}' has no stdin or stdout");
+    };
+    Ok(LocalIo::Exec(ChildIo {
+        _child: child,
+        stdout,
+        stdin: Some(stdin),
+    }))
+}
+
+async fn connect(host: &str, port: u16) -> Result<TcpStream> {
+    resolve::connect(host, port, &Resolver::System).await
+}
+
+/// A connection to the server: plain TCP or TLS.
+pub(crate) trait Io: AsyncRead + AsyncWrite + Unpin + Send + Any {
+    /// The stream itself, to look for a plain TCP socket under it.
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Io for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Open a control-port connection, wrapped in TLS if enabled, or a stream of
+/// the QUIC connection.
+pub(crate) async fn connect_control(shared: &Shared) -> Result<Box<dyn Io>> {
+    let server = shared.server();
+    if let Some(quic) = &shared.quic {
+        if let Some(stream) = quic.open(&shared.options, server).await? {
+            return Ok(stream);
+        }
+    }
+    let options = &shared.options;
+    let stream = connect_server(options, server).await?;
+    if let Err(e) = tune_socket(&stream, options.timeouts.heartbeat_timeout) {
+        debug!(err = %e, "cannot tune the connection to the server");
+    }
+    let Some(tls) = &shared.tls else {
+        return upgrade(stream, options, server, false).await;
+    };
+    let name = ServerName::try_from(server.to_owned())
+        .with_context(|| format!("invalid server name {server}"))?;
+    let stream = timeout(options.timeouts.handshake, tls.connect(name, stream))
+        .await
+        .with_context(|| format!("TLS handshake with {server} timed out"))?
+        .with_context(|| format!("TLS handshake with {server} failed"))?;
+    upgrade(stream, options, server, true).await
+}
+
+/// Run the connection over a WebSocket if that is the transport.
+async fn upgrade<S: Io + 'static>(
+    stream: S,
+    options: &Options,
+    server: &str,
+    tls: bool,
+) -> Result<Box<dyn Io>> {
+    if options.transport != Transport::Ws {
+        return Ok(Box::new(stream));
+    }
+    let port = options.control_port;
+    let host = match (tls, port) {
+        (false, 80) | (true, 443) => server.to_owned(),
+        _ => format!("{server}:{port}"),
+    };
+    Ok(Box::new(WsStream::connect(stream, &host, tls).await?))
+}
+
+/// The protocol and public port of the tunnel of `subdomain`.
+async fn lookup(shared: &Shared, subdomain: String) -> Result<(Proto, u16)> {
+    let mut conn = shared.framed(connect_control(shared).await?);
+    if let Some(secret) = &shared.options.secret {
+        Auth::new(secret).handshake(&mut conn).await?;
+    }
+    conn.send(ClientMsg::Lookup { subdomain }).await?;
+    match conn.recv_timeout::<ServerMsg>().await? {
+        Some(ServerMsg::Found { proto, public_port }) => Ok((proto, public_port)),
+        Some(ServerMsg::Refused { code, message }) => {
+            Err(TunnelError::with_code(code, message).into())
+        }
+        Some(ServerMsg::Error(message)) => Err(TunnelError::from_server(message).into()),
+        Some(other) => bail!("unexpected reply to Lookup: {other:?}"),
+        None => bail!("the server hung up; it may be too old to look up tunnels"),
+    }
+}
+
+/// Connect to `server`, through the HTTP proxy if there is one.
+async fn connect_server(options: &Options, server: &str) -> Result<TcpStream> {
+    let port = options.control_port;
+    let Some(proxy) = &options.http_proxy else {
+        return connect_direct(options, server, port).await;
+    };
+    let mut stream = connect_direct(options, &proxy.host, proxy.port)
+        .await
+        .context("cannot reach the proxy")?;
+    proxy
+        .connect(&mut stream, server, port, options.timeouts.handshake)
+        .await?;
+    Ok(stream)
+}
+
+/// Connect to `server`, honouring the resolver, bind address and interface.
+async fn connect_direct(options: &Options, server: &str, port: u16) -> Result<TcpStream> {
+    let addrs: Vec<_> = resolve::lookup(server, port, &options.resolver)
+        .await?
+        .into_iter()
+        // A source address only works with destinations of the same family.
+        .filter(|addr| {
+            options
+                .bind_address
+                .is_none_or(|src| src.is_ipv4() == addr.is_ipv4())
+        })
+        .collect();
+    if addrs.is_empty() {
+        return Err(TunnelError::new(
+            Failure::Network,
+            format!("no address of {server} matches the bind address"),
+        )
+        .into());
+    }
+    match resolve::happy_eyeballs(&addrs, |addr| connect_from(options, addr)).await {
+        Ok(stream) => Ok(stream),
+        Err(e) => {
+            resolve::forget(server);
+            Err(e).with_context(|| format!("cannot connect to {server}:{port}"))
+        }
+    }
+}
+
+async fn connect_from(options: &Options, addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(src) = options.bind_address {
+        socket.bind(SocketAddr::new(src, 0))?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(iface) = &options.bind_interface {
+        socket.bind_device(Some(iface.as_bytes()))?;
+    }
+    socket.connect(addr).await
+}
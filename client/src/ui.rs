@@ -0,0 +1,681 @@
+//! Live dashboard for `--ui`: tunnels, open connections, traffic and recent
+//! HTTP requests, redrawn a few times a second. With `--inspect`, a request
+//! can be picked with the arrow keys and replayed with `r`. With `--approve`,
+//! the oldest connection waiting for approval is shown at the top and
+//! answered with `y`, `n` or `a`.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use ratatui::{
+    crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Paragraph, Row, Table, TableState},
+    DefaultTerminal, Frame,
+};
+use sshx_client::{
+    approve::{Approver, Decision},
+    inspect::Inspector,
+    status::{format_bytes, Stats},
+    Event, Forward, Proto, Tunnel, TunnelTraffic,
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::interval,
+};
+
+/// Requests kept for the requests panel.
+const MAX_REQUESTS: usize = 100;
+
+/// Set while the dashboard owns the terminal; log lines would tear it up.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Where log lines go: stderr, except while the dashboard is up.
+pub fn log_writer() -> Box<dyn Write> {
+    if QUIET.load(Ordering::Relaxed) {
+        Box::new(io::sink())
+    } else {
+        Box::new(io::stderr())
+    }
+}
+
+/// What the dashboard knows, built up from tunnel events.
+pub struct Dashboard {
+    server: String,
+    local_host: String,
+    /// Shown as every tunnel's local end instead of a host and port.
+    local: Option<String>,
+    tunnels: Vec<TunnelRow>,
+    connections: Vec<Connection>,
+    /// Newest first.
+    requests: VecDeque<Request>,
+    link: Link,
+    notice: Option<String>,
+    stats: Arc<Stats>,
+    started: Instant,
+    /// Bytes in and out at the last sample, and when it was taken.
+    sample: ((u64, u64), Instant),
+    /// Bytes per second in and out over the last sample.
+    rates: (u64, u64),
+    /// Captured exchanges, when inspecting; they replace `requests`.
+    inspector: Option<Arc<Inspector>>,
+    /// Connections waiting for approval, with `--approve`.
+    approver: Option<Arc<Approver>>,
+    /// The exchange picked for replay.
+    selected: Option<u64>,
+    /// Outcome of the last replay.
+    replayed: Option<String>,
+    replays: (UnboundedSender<String>, UnboundedReceiver<String>),
+}
+
+struct TunnelRow {
+    forward: Forward,
+    public_port: Option<u16>,
+    url: Option<String>,
+    state: TunnelState,
+    /// Connections since start.
+    total: u64,
+    /// What the server last reported relaying.
+    traffic: Option<TunnelTraffic>,
+}
+
+enum TunnelState {
+    Connecting,
+    Up,
+    Reconnecting,
+    Expired,
+    HandedOver,
+    Down,
+}
+
+/// The control connection, as far as the client can tell.
+enum Link {
+    Online,
+    Reconnecting(String),
+    Down(String),
+}
+
+struct Connection {
+    subdomain: String,
+    peer_addr: SocketAddr,
+    since: Instant,
+}
+
+struct Request {
+    subdomain: String,
+    peer_addr: SocketAddr,
+    line: String,
+    at: Instant,
+}
+
+/// Why the dashboard stopped.
+enum Stop {
+    /// The user quit or a signal came in.
+    Quit,
+    /// The tunnel ended by itself.
+    Finished,
+}
+
+impl Dashboard {
+    pub fn new(server: &str, local_host: &str, tunnels: &[Forward], stats: Arc<Stats>) -> Self {
+        let now = Instant::now();
+        Self {
+            server: server.to_owned(),
+            local_host: local_host.to_owned(),
+            local: None,
+            tunnels: tunnels
+                .iter()
+                .map(|forward| TunnelRow {
+                    forward: forward.clone(),
+                    public_port: None,
+                    url: None,
+                    state: TunnelState::Connecting,
+                    total: 0,
+                    traffic: None,
+                })
+                .collect(),
+            connections: Vec::new(),
+            requests: VecDeque::new(),
+            link: Link::Online,
+            notice: None,
+            sample: (stats.transferred(), now),
+            stats,
+            started: now,
+            rates: (0, 0),
+            inspector: None,
+            approver: None,
+            selected: None,
+            replayed: None,
+            replays: unbounded_channel(),
+        }
+    }
+
+    /// The tunnels forward to the Unix socket at `path`.
+    pub fn unix_socket(mut self, path: &Path) -> Self {
+        self.local = Some(path.display().to_string());
+        self
+    }
+
+    /// The tunnels hand each visitor to a run of `command`.
+    pub fn exec(mut self, command: &str) -> Self {
+        self.local = Some(format!("exec {command}"));
+        self
+    }
+
+    /// List the exchanges `inspector` captures, and let them be replayed.
+    pub fn inspector(mut self, inspector: Arc<Inspector>) -> Self {
+        self.inspector = Some(inspector);
+        self
+    }
+
+    /// Ask about the connections `approver` holds, one at a time.
+    pub fn approver(mut self, approver: Arc<Approver>) -> Self {
+        self.approver = Some(approver);
+        self
+    }
+
+    /// Show the dashboard until the user quits, `signal` resolves or the
+    /// tunnel ends, then hand the terminal back and stop the tunnel.
+    pub async fn run(
+        mut self,
+        mut tunnel: Tunnel,
+        signal: Pin<&mut impl Future<Output = ()>>,
+    ) -> Result<()> {
+        let mut terminal = ratatui::try_init()?;
+        QUIET.store(true, Ordering::Relaxed);
+        let stop = self.drive(&mut terminal, &mut tunnel, signal).await;
+        ratatui::restore();
+        QUIET.store(false, Ordering::Relaxed);
+        match stop? {
+            Stop::Quit => tunnel.shutdown().await,
+            Stop::Finished => tunnel.wait().await,
+        }
+    }
+
+    async fn drive(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        tunnel: &mut Tunnel,
+        mut signal: Pin<&mut impl Future<Output = ()>>,
+    ) -> Result<Stop> {
+        let mut redraw = interval(Duration::from_millis(250));
+        loop {
+            tokio::select! {
+                event = tunnel.next_event() => match event {
+                    Some(event) => self.apply(event),
+                    None => return Ok(Stop::Finished),
+                },
+                Some(replayed) = self.replays.1.recv() => self.replayed = Some(replayed),
+                _ = redraw.tick() => {
+                    if self.handle_keys()? {
+                        return Ok(Stop::Quit);
+                    }
+                    self.sample();
+                    terminal.draw(|frame| self.render(frame))?;
+                }
+                _ = &mut signal => return Ok(Stop::Quit),
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Connected(registration) => {
+                self.link = Link::Online;
+                if let Some(row) = self.row(&registration.subdomain) {
+                    row.public_port = Some(registration.public_port);
+                    row.url = registration.url;
+                    row.state = TunnelState::Up;
+                }
+            }
+            Event::Connection {
+                subdomain,
+                peer_addr,
+                ..
+            } => {
+                if let Some(row) = self.row(&subdomain) {
+                    row.total += 1;
+                }
+                self.connections.push(Connection {
+                    subdomain,
+                    peer_addr,
+                    since: Instant::now(),
+                });
+            }
+            Event::ConnectionClosed {
+                subdomain,
+                peer_addr,
+                ..
+            } => {
+                let closed = self
+                    .connections
+                    .iter()
+                    .position(|c| c.subdomain == subdomain && c.peer_addr == peer_addr);
+                if let Some(i) = closed {
+                    self.connections.remove(i);
+                }
+            }
+            Event::Probed { .. } => {}
+            Event::Request {
+                subdomain,
+                peer_addr,
+                line,
+            } => {
+                self.requests.push_front(Request {
+                    subdomain,
+                    peer_addr,
+                    line,
+                    at: Instant::now(),
+                });
+                self.requests.truncate(MAX_REQUESTS);
+            }
+            Event::Traffic { subdomain, traffic } => {
+                if let Some(row) = self.row(&subdomain) {
+                    row.traffic = Some(traffic);
+                }
+            }
+            Event::Notice(notice) => self.notice = Some(notice),
+            Event::Expired { subdomain, .. } => {
+                if let Some(row) = self.row(&subdomain) {
+                    row.state = TunnelState::Expired;
+                }
+            }
+            Event::HandedOver { subdomain } => {
+                if let Some(row) = self.row(&subdomain) {
+                    row.state = TunnelState::HandedOver;
+                }
+            }
+            Event::Unregistered { subdomain } => {
+                self.tunnels
+                    .retain(|row| row.forward.subdomain != subdomain);
+            }
+            Event::Disconnected {
+                error,
+                reconnecting,
+            } => {
+                for row in &mut self.tunnels {
+                    if !matches!(row.state, TunnelState::Expired | TunnelState::HandedOver) {
+                        row.state = match reconnecting {
+                            true => TunnelState::Reconnecting,
+                            false => TunnelState::Down,
+                        };
+                    }
+                }
+                self.link = match reconnecting {
+                    true => Link::Reconnecting(error),
+                    false => Link::Down(error),
+                };
+            }
+        }
+    }
+
+    /// Act on the keys pressed since the last look; true means quit. The
+    /// terminal is in raw mode, so Ctrl-C arrives as a key rather than a
+    /// signal.
+    fn handle_keys(&mut self) -> io::Result<bool> {
+        while event::poll(Duration::ZERO)? {
+            let TermEvent::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(true)
+                }
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+                KeyCode::Up => self.select(-1),
+                KeyCode::Down => self.select(1),
+                KeyCode::Char('r') => self.replay(),
+                KeyCode::Char('y') => self.decide(Decision::Allow),
+                KeyCode::Char('n') => self.decide(Decision::Reject),
+                KeyCode::Char('a') => self.decide(Decision::Always),
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    /// Answer the oldest connection waiting for approval.
+    fn decide(&self, decision: Decision) {
+        let Some(approver) = &self.approver else {
+            return;
+        };
+        if let Some(question) = approver.pending().first() {
+            approver.answer(question.id, decision);
+        }
+    }
+
+    /// Move the selection `step` rows down the list of exchanges.
+    fn select(&mut self, step: isize) {
+        let Some(inspector) = &self.inspector else {
+            return;
+        };
+        let recent = inspector.recent();
+        let current = self
+            .selected
+            .and_then(|id| recent.iter().position(|e| e.id == id));
+        let next = match current {
+            Some(i) => i
+                .saturating_add_signed(step)
+                .min(recent.len().saturating_sub(1)),
+            None => 0,
+        };
+        self.selected = recent.get(next).map(|e| e.id);
+    }
+
+    /// Replay the selected exchange in the background.
+    fn replay(&mut self) {
+        let (Some(inspector), Some(id)) = (&self.inspector, self.selected) else {
+            return;
+        };
+        let inspector = Arc::clone(inspector);
+        let done = self.replays.0.clone();
+        self.replayed = Some(format!("↻ replaying #{id}…"));
+        tokio::spawn(async move {
+            let outcome = match inspector.replay(id).await {
+                Ok(replay) => {
+                    let status = replay
+                        .status
+                        .map_or("no response".to_owned(), |s| s.to_string());
+                    format!(
+                        "↻ replayed #{id} as #{}: {status} in {} ms",
+                        replay.id, replay.duration_ms
+                    )
+                }
+                Err(e) => format!("✗ replay of #{id} failed: {e:#}"),
+            };
+            let _ = done.send(outcome);
+        });
+    }
+
+    fn row(&mut self, subdomain: &str) -> Option<&mut TunnelRow> {
+        self.tunnels
+            .iter_mut()
+            .find(|row| row.forward.subdomain == subdomain)
+    }
+
+    /// Work out transfer rates about once a second.
+    fn sample(&mut self) {
+        let ((last_in, last_out), at) = self.sample;
+        let elapsed = at.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        let (bytes_in, bytes_out) = self.stats.transferred();
+        let per_sec = |n: u64| (n as f64 / elapsed.as_secs_f64()) as u64;
+        self.rates = (per_sec(bytes_in - last_in), per_sec(bytes_out - last_out));
+        self.sample = ((bytes_in, bytes_out), Instant::now());
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let http = self.tunnels.iter().any(|t| t.forward.proto == Proto::Http);
+        let notice = self.notice.is_some() as u16;
+        let pending = self
+            .approver
+            .as_ref()
+            .map(|a| a.pending())
+            .unwrap_or_default();
+        let question = !pending.is_empty() as u16;
+        let [header, notice_area, question_area, tunnels, traffic, connections, requests, footer] =
+            Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Length(notice),
+                Constraint::Length(question),
+                Constraint::Length(self.tunnels.len() as u16 + 3),
+                Constraint::Length(3),
+                Constraint::Fill(1),
+                Constraint::Fill(http as u16),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+
+        frame.render_widget(self.header(), header);
+        if let Some(notice) = &self.notice {
+            let line = Line::from(format!(" ℹ  Server notice: {notice}")).fg(Color::Cyan);
+            frame.render_widget(line, notice_area);
+        }
+        if let Some(first) = pending.first() {
+            let mut text = format!(" ?  Connection from {}", first.peer);
+            if let Some(line) = &first.preview {
+                text.push_str(&format!(" — {line}"));
+            }
+            text.push_str("   y allow  n reject  a always for this IP");
+            if pending.len() > 1 {
+                text.push_str(&format!("  (+{} waiting)", pending.len() - 1));
+            }
+            frame.render_widget(Line::from(text).fg(Color::Yellow).bold(), question_area);
+        }
+        frame.render_widget(self.tunnel_table(), tunnels);
+        frame.render_widget(self.traffic(), traffic);
+        frame.render_widget(self.connection_table(), connections);
+        if http {
+            match &self.inspector {
+                Some(inspector) => {
+                    let (table, mut state) = self.exchange_table(inspector);
+                    frame.render_stateful_widget(table, requests, &mut state);
+                }
+                None => frame.render_widget(self.request_table(), requests),
+            }
+        }
+        let mut help = vec![Span::from(" q quit").add_modifier(Modifier::DIM)];
+        if self.inspector.is_some() {
+            help.push(Span::from("  ↑↓ select  r replay").add_modifier(Modifier::DIM));
+        }
+        if let Some(replayed) = &self.replayed {
+            help.push(Span::from(format!("   {replayed}")));
+        }
+        frame.render_widget(Line::from(help), footer);
+    }
+
+    fn header(&self) -> Line<'_> {
+        let (status, color) = match &self.link {
+            Link::Online => ("● online".to_owned(), Color::Green),
+            Link::Reconnecting(error) => (format!("↻ reconnecting: {error}"), Color::Yellow),
+            Link::Down(error) => (format!("✗ disconnected: {error}"), Color::Red),
+        };
+        Line::from(vec![
+            Span::from(" sshx ").bold(),
+            Span::from(format!("→ {}  ", self.server)),
+            Span::from(status).fg(color),
+            Span::from(format!("  up {}", format_secs(self.started.elapsed()))).dim(),
+            Span::from(match self.stats.rtt() {
+                Some(rtt) if matches!(self.link, Link::Online) => {
+                    format!("  rtt {}ms", rtt.as_millis())
+                }
+                _ => String::new(),
+            })
+            .dim(),
+        ])
+    }
+
+    fn tunnel_table(&self) -> Table<'_> {
+        let rows = self.tunnels.iter().map(|row| {
+            let forward = &row.forward;
+            let public = match (&row.url, row.public_port) {
+                (Some(url), _) => url.clone(),
+                (None, Some(port)) => format!("{}:{port}", self.server),
+                (None, None) => "—".to_owned(),
+            };
+            let (state, color) = match row.state {
+                TunnelState::Connecting => ("… connecting", Color::Gray),
+                TunnelState::Up => ("● up", Color::Green),
+                TunnelState::Reconnecting => ("↻ reconnecting", Color::Yellow),
+                TunnelState::Expired => ("⌛ expired", Color::DarkGray),
+                TunnelState::HandedOver => ("⇄ handed over", Color::DarkGray),
+                TunnelState::Down => ("✗ down", Color::Red),
+            };
+            let open = self
+                .connections
+                .iter()
+                .filter(|c| c.subdomain == forward.subdomain)
+                .count();
+            let local = match &self.local {
+                Some(local) => local.clone(),
+                None => forward.local_addr(&self.local_host),
+            };
+            let relayed = match &row.traffic {
+                Some(t) => format!(
+                    "↓ {}/s ↑ {}/s",
+                    format_bytes(t.rate_in as u64),
+                    format_bytes(t.rate_out as u64)
+                ),
+                None => "—".to_owned(),
+            };
+            Row::new(vec![
+                Span::from(forward.subdomain.clone()),
+                Span::from(match forward.grpc {
+                    true => "grpc".to_owned(),
+                    false => format!("{:?}", forward.proto).to_lowercase(),
+                }),
+                Span::from(public),
+                Span::from(local),
+                Span::from(state).fg(color),
+                Span::from(format!("{open} / {}", row.total)),
+                Span::from(relayed),
+            ])
+        });
+        let widths = [
+            Constraint::Min(12),
+            Constraint::Length(5),
+            Constraint::Fill(2),
+            Constraint::Fill(1),
+            Constraint::Length(15),
+            Constraint::Length(12),
+            Constraint::Length(24),
+        ];
+        Table::new(rows, widths)
+            .header(header_row([
+                "Subdomain",
+                "Type",
+                "Public",
+                "Local",
+                "Status",
+                "Open / all",
+                "Server rate",
+            ]))
+            .block(Block::bordered().title(" Tunnels "))
+    }
+
+    fn traffic(&self) -> Paragraph<'_> {
+        let (rate_in, rate_out) = self.rates;
+        let (total_in, total_out) = self.stats.transferred();
+        let line = Line::from(vec![
+            Span::from(format!("↓ {}/s in", format_bytes(rate_in))).fg(Color::Cyan),
+            Span::from("   "),
+            Span::from(format!("↑ {}/s out", format_bytes(rate_out))).fg(Color::Magenta),
+            Span::from(format!(
+                "   total {} in / {} out",
+                format_bytes(total_in),
+                format_bytes(total_out)
+            ))
+            .dim(),
+        ]);
+        Paragraph::new(line).block(Block::bordered().title(" Traffic "))
+    }
+
+    fn connection_table(&self) -> Table<'_> {
+        let rows = self.connections.iter().rev().map(|c| {
+            Row::new(vec![
+                c.subdomain.clone(),
+                c.peer_addr.to_string(),
+                format_secs(c.since.elapsed()),
+            ])
+        });
+        let widths = [
+            Constraint::Min(12),
+            Constraint::Fill(1),
+            Constraint::Length(12),
+        ];
+        let title = format!(" Connections ({}) ", self.connections.len());
+        Table::new(rows, widths)
+            .header(header_row(["Tunnel", "Visitor", "Open for"]))
+            .block(Block::bordered().title(title))
+    }
+
+    fn request_table(&self) -> Table<'_> {
+        let rows = self.requests.iter().map(|r| {
+            Row::new(vec![
+                format!("{} ago", format_secs(r.at.elapsed())),
+                r.subdomain.clone(),
+                r.peer_addr.to_string(),
+                r.line.clone(),
+            ])
+        });
+        let widths = [
+            Constraint::Length(12),
+            Constraint::Min(12),
+            Constraint::Length(22),
+            Constraint::Fill(1),
+        ];
+        Table::new(rows, widths)
+            .header(header_row(["When", "Tunnel", "Visitor", "Request"]))
+            .block(Block::bordered().title(" Recent requests "))
+    }
+
+    /// The captured exchanges, with the selected one highlighted.
+    fn exchange_table(&self, inspector: &Inspector) -> (Table<'static>, TableState) {
+        let recent = inspector.recent();
+        let selected = self
+            .selected
+            .and_then(|id| recent.iter().position(|e| e.id == id));
+        let rows = recent.iter().map(|e| {
+            let status = e.status.map_or("—".to_owned(), |s| s.to_string());
+            let color = match e.status {
+                Some(200..=399) => Color::Green,
+                Some(400..=499) => Color::Yellow,
+                _ => Color::Red,
+            };
+            let id = match e.replay_of {
+                Some(of) => format!("{} ↻{of}", e.id),
+                None => e.id.to_string(),
+            };
+            Row::new(vec![
+                Span::from(id),
+                Span::from(e.subdomain.clone()),
+                Span::from(e.peer_addr.to_string()),
+                Span::from(format!("{} {}", e.method, e.path)),
+                Span::from(status).fg(color),
+                Span::from(format!("{} ms", e.duration_ms)),
+            ])
+        });
+        let widths = [
+            Constraint::Length(10),
+            Constraint::Min(12),
+            Constraint::Length(22),
+            Constraint::Fill(1),
+            Constraint::Length(6),
+            Constraint::Length(10),
+        ];
+        let table = Table::new(rows, widths)
+            .header(header_row([
+                "#", "Tunnel", "Visitor", "Request", "Status", "Duration",
+            ]))
+            .row_highlight_style(Style::new().reversed())
+            .block(Block::bordered().title(" Recent requests "));
+        (table, TableState::default().with_selected(selected))
+    }
+}
+
+fn header_row<const N: usize>(titles: [&'static str; N]) -> Row<'static> {
+    Row::new(titles).style(Style::new().bold())
+}
+
+fn format_secs(elapsed: Duration) -> String {
+    humantime::format_duration(Duration::from_secs(elapsed.as_secs())).to_string()
+}
@@ -0,0 +1,151 @@
+//! The `sshx` binary as scripts and CI jobs run it.
+
+use std::{future::Future, net::Ipv4Addr, process::Stdio, time::Duration};
+
+use serde_json::Value;
+use sshx_server::{Config, Server};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{TcpListener, TcpStream},
+    process::{Child, ChildStdout, Command},
+    time::timeout,
+};
+
+const LOCALHOST: Ipv4Addr = Ipv4Addr::LOCALHOST;
+
+async fn within<T>(fut: impl Future<Output = T>) -> T {
+    timeout(Duration::from_secs(10), fut)
+        .await
+        .expect("timed out")
+}
+
+/// Start a server on an ephemeral control port and return that port.
+async fn start_server() -> u16 {
+    let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let config = Config {
+        bind: vec![LOCALHOST.into()],
+        ..Config::default()
+    };
+    tokio::spawn(Server::new(config).serve(listener));
+    port
+}
+
+/// Run `sshx` against the server on `control` with `args` on top, and read
+/// its stdout line by line.
+fn sshx(control: u16, args: &[&str]) -> (Child, Lines<BufReader<ChildStdout>>) {
+    let control = control.to_string();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sshx"))
+        .args(["-r", "127.0.0.1", "--control-port", &control])
+        .args(["--host", "127.0.0.1", "--reconnect", "false"])
+        .arg("--skip-local-check")
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    (child, stdout)
+}
+
+/// The next event of kind `kind` that `sshx --output json` wrote, skipping
+/// others.
+async fn event(lines: &mut Lines<BufReader<ChildStdout>>, kind: &str) -> Value {
+    loop {
+        let line = within(lines.next_line())
+            .await
+            .unwrap()
+            .expect("sshx exited");
+        let event: Value = serde_json::from_str(&line).unwrap();
+        assert!(event["time"].is_string(), "{line}");
+        if event["event"] == kind {
+            return event;
+        }
+    }
+}
+
+#[tokio::test]
+async fn output_json_writes_one_event_per_line() {
+    let control = start_server().await;
+    let local = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let port = local.local_addr().unwrap().port().to_string();
+    let args = ["-p", &port, "-s", "scripted", "--tcp", "--output", "json"];
+    let (_sshx, mut events) = sshx(control, &args);
+
+    let connected = event(&mut events, "connected").await;
+    assert_eq!(connected["subdomain"], "scripted");
+    assert_eq!(connected["proto"], "tcp");
+    assert_eq!(connected["host"], "127.0.0.1");
+    let public_port = connected["public_port"].as_u64().unwrap() as u16;
+
+    let mut visitor = TcpStream::connect((LOCALHOST, public_port)).await.unwrap();
+    let (mut served, _) = within(local.accept()).await.unwrap();
+    let connection = event(&mut events, "connection").await;
+    assert_eq!(connection["subdomain"], "scripted");
+    assert_eq!(connection["public_port"], public_port);
+    assert!(connection["peer_addr"].is_string());
+
+    visitor.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    within(served.read_exact(&mut ping)).await.unwrap();
+    drop((visitor, served));
+    let closed = event(&mut events, "connection_closed").await;
+    assert_eq!(closed["subdomain"], "scripted");
+    assert_eq!(closed["bytes_in"], 4);
+}
+
+#[tokio::test]
+async fn ready_probes_stop_at_the_client() {
+    let control = start_server().await;
+    let local = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let port = local.local_addr().unwrap().port().to_string();
+    let url_file = std::env::temp_dir().join(format!("sshx-url-{}", std::process::id()));
+    let url_arg = url_file.to_str().unwrap();
+    let args = [
+        "-p",
+        &port,
+        "-s",
+        "probed",
+        "--tcp",
+        "--output",
+        "json",
+        "--ready-timeout",
+        "10s",
+        "--print-url-file",
+        url_arg,
+        "--max-uses",
+        "1",
+    ];
+    let (mut sshx, mut events) = sshx(control, &args);
+
+    let connected = event(&mut events, "connected").await;
+    let public_port = connected["public_port"].as_u64().unwrap() as u16;
+    let probed = event(&mut events, "probed").await;
+    assert_eq!(probed["subdomain"], "probed");
+    // The URL file is written once the probe got through, and the probe
+    // never reached the local service.
+    within(async {
+        while !url_file.exists() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    let url = std::fs::read_to_string(&url_file).unwrap();
+    assert_eq!(url, format!("127.0.0.1:{public_port}\n"));
+    std::fs::remove_file(&url_file).unwrap();
+    let reached = timeout(Duration::from_millis(300), local.accept()).await;
+    assert!(reached.is_err(), "the probe reached the local service");
+
+    // Nor did it take the one use: the first visitor gets through, and the
+    // tunnel closes once it leaves.
+    let mut visitor = TcpStream::connect((LOCALHOST, public_port)).await.unwrap();
+    let (mut served, _) = within(local.accept()).await.unwrap();
+    event(&mut events, "connection").await;
+    visitor.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    within(served.read_exact(&mut ping)).await.unwrap();
+    drop((visitor, served));
+    let status = within(sshx.wait()).await.unwrap();
+    assert!(status.success(), "{status}");
+}
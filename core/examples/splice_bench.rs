@@ -0,0 +1,106 @@
+//! Compare relaying over loopback with `copy_bidirectional` and with
+//! `splice(2)`.
+//!
+//! ```sh
+//! cargo run --release -p sshx-core --features splice --example splice_bench -- [GiB]
+//! ```
+//!
+//! A sender pushes the bytes through a relay to a sink. CPU time is that of
+//! the whole process, sender and sink included; they do the same work either
+//! way, so the difference is the relay's.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use sshx_core::splice;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const CHUNK: usize = 256 * 1024;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Copy,
+    Splice,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let gib: u64 = match std::env::args().nth(1) {
+        Some(arg) => arg.parse()?,
+        None => 4,
+    };
+    let total = gib << 30;
+    println!("relaying {gib} GiB over loopback");
+    for (name, mode) in [("copy_bidirectional", Mode::Copy), ("splice", Mode::Splice)] {
+        let cpu = cpu_time();
+        let start = Instant::now();
+        run(mode, total).await?;
+        let (wall, cpu) = (start.elapsed(), cpu_time() - cpu);
+        let gbps = (total * 8) as f64 / wall.as_secs_f64() / 1e9;
+        println!(
+            "{name:>18}: {gbps:6.2} Gbit/s, {:.2}s wall, {:.2}s CPU",
+            wall.as_secs_f64(),
+            cpu.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// Send `total` bytes through a relay in `mode` and wait for the sink.
+async fn run(mode: Mode, total: u64) -> Result<()> {
+    let sink = TcpListener::bind("127.0.0.1:0").await?;
+    let sink_addr = sink.local_addr()?;
+    let relay = TcpListener::bind("127.0.0.1:0").await?;
+    let relay_addr = relay.local_addr()?;
+
+    let sink = tokio::spawn(async move {
+        let (mut stream, _) = sink.accept().await?;
+        let mut buf = vec![0; CHUNK];
+        let mut received = 0;
+        loop {
+            match stream.read(&mut buf).await? {
+                0 => return anyhow::Ok(received),
+                n => received += n as u64,
+            }
+        }
+    });
+    let relay = tokio::spawn(async move {
+        let (mut a, _) = relay.accept().await?;
+        let mut b = TcpStream::connect(sink_addr).await?;
+        match mode {
+            Mode::Copy => tokio::io::copy_bidirectional(&mut a, &mut b).await?,
+            Mode::Splice => splice::relay(&a, &b, |_| {}, |_| {}).await?,
+        };
+        anyhow::Ok(())
+    });
+
+    let mut sender = TcpStream::connect(relay_addr).await?;
+    let chunk = vec![0x5a; CHUNK];
+    let mut sent = 0;
+    while sent < total {
+        let n = chunk.len().min((total - sent) as usize);
+        sender.write_all(&chunk[..n]).await?;
+        sent += n as u64;
+    }
+    sender.shutdown().await?;
+    sender.read_to_end(&mut Vec::new()).await?;
+
+    relay.await??;
+    anyhow::ensure!(sink.await?? == total, "sink got short bytes");
+    Ok(())
+}
+
+/// CPU time used by this process so far, user and system.
+fn cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills in `usage`, which has the room.
+    let usage = unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr());
+        usage.assume_init()
+    };
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
@@ -0,0 +1,102 @@
+//! Shared-secret authentication of control connections.
+//!
+//! The server sends `Challenge(uuid)` and the client answers with
+//! `Authenticate(hex)`, an HMAC-SHA256 tag of the challenge keyed with the
+//! SHA-256 of the secret.
+//!
+//! The answer alone says nothing about the `Hello` after it, which a
+//! man in the middle could swap when the connection isn't TLS. So the
+//! client also seals its `Hello`: a tag, keyed the same way, of the
+//! challenge and the `Hello`'s [`binding`](ClientMsg::binding). Both tags
+//! are 64 hex digits whatever the secret or the `Hello`.
+
+use anyhow::{bail, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
+
+use crate::protocol::{ClientMsg, Framed_, ServerMsg};
+
+/// Sets seals apart from answers, which cover the challenge alone.
+const HELLO_DOMAIN: &[u8] = b"sshx-hello";
+
+/// Whether `a` and `b` are equal, in time that doesn't tell how much of
+/// them is. For secrets compared as they are rather than by HMAC.
+pub fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// HMAC-SHA256 over the challenge with a shared secret.
+pub struct Auth(Hmac<Sha256>);
+
+impl Auth {
+    pub fn new(secret: &str) -> Self {
+        let key = Sha256::new().chain_update(secret).finalize();
+        Self(Hmac::new_from_slice(&key).expect("hmac accepts any key size"))
+    }
+
+    /// The seal of a `Hello` whose binding is `binding` for `challenge`.
+    fn seal_tag(&self, challenge: &Uuid, binding: &[u8]) -> Hmac<Sha256> {
+        let mut mac = self.0.clone();
+        mac.update(challenge.as_bytes());
+        mac.update(HELLO_DOMAIN);
+        mac.update(binding);
+        mac
+    }
+
+    /// Seal `msg` to `challenge`, if it is a `Hello`.
+    pub fn seal(&self, challenge: &Uuid, msg: &mut ClientMsg) {
+        let Some(binding) = msg.binding() else {
+            return;
+        };
+        let tag = hex::encode(self.seal_tag(challenge, &binding).finalize().into_bytes());
+        if let ClientMsg::Hello { seal, .. } = msg {
+            *seal = Some(tag);
+        }
+    }
+
+    /// Whether `seal` is the right seal of a `Hello` with `binding` for
+    /// `challenge`, in constant time.
+    pub fn verify_seal(&self, challenge: &Uuid, binding: &[u8], seal: &str) -> bool {
+        hex::decode(seal)
+            .map(|t| self.seal_tag(challenge, binding).verify_slice(&t).is_ok())
+            .unwrap_or(false)
+    }
+
+    /// The client's answer to `challenge`.
+    pub fn answer(&self, challenge: &Uuid) -> String {
+        let mut mac = self.0.clone();
+        mac.update(challenge.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Whether `tag` is the right answer to `challenge`, in constant time.
+    pub fn verify(&self, challenge: &Uuid, tag: &str) -> bool {
+        hex::decode(tag)
+            .map(|t| {
+                let mut mac = self.0.clone();
+                mac.update(challenge.as_bytes());
+                mac.verify_slice(&t).is_ok()
+            })
+            .unwrap_or(false)
+    }
+
+    /// Client side: answer the server's challenge. Returns the challenge,
+    /// to [`seal`](Self::seal) the `Hello` with.
+    pub async fn handshake<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Framed_<T>,
+    ) -> Result<Uuid> {
+        match stream.recv_timeout::<ServerMsg>().await? {
+            Some(ServerMsg::Challenge(c)) => {
+                stream
+                    .send(ClientMsg::Authenticate(self.answer(&c)))
+                    .await?;
+                Ok(c)
+            }
+            _ => bail!("expected Challenge from server"),
+        }
+    }
+}
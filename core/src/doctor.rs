@@ -0,0 +1,320 @@
+//! Setup checks shared by `sshx doctor` and `sshx-server check-dns`.
+//!
+//! Most first-run failures are DNS, not code: the wildcard record is missing
+//! or points elsewhere, or the server's `--domain` doesn't match it. Pages the
+//! server sends for hosts without a tunnel carry [`ROUTE_HEADER`], the
+//! subdomain the host was routed to, and [`TIME_HEADER`], the server's clock.
+//! So a request for a made-up subdomain shows whether DNS leads to an sshx
+//! server, whether that server routes the host as expected, and how far apart
+//! the clocks are.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+    time::timeout,
+};
+use uuid::Uuid;
+
+/// Response header naming the subdomain a host without a tunnel routed to.
+pub const ROUTE_HEADER: &str = "X-Sshx-Route";
+
+/// Response header with the server's clock, in seconds since the Unix epoch.
+pub const TIME_HEADER: &str = "X-Sshx-Time";
+
+/// Clocks further apart than this get a warning: certificates start to look
+/// expired or not yet valid.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// How long each lookup or connection may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest response head a probe reads.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// How a check went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// One finding, with what to do about it unless it passed.
+#[derive(Debug)]
+pub struct Check {
+    pub status: Status,
+    pub what: String,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    pub fn pass(what: impl Into<String>) -> Self {
+        Self {
+            status: Status::Pass,
+            what: what.into(),
+            fix: None,
+        }
+    }
+
+    pub fn warn(what: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            status: Status::Warn,
+            what: what.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    pub fn fail(what: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            status: Status::Fail,
+            what: what.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Findings in the order they were made.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn push(&mut self, check: Check) {
+        self.checks.push(check);
+    }
+
+    /// Whether any check failed; warnings don't count.
+    pub fn failed(&self) -> bool {
+        self.checks.iter().any(|c| c.status == Status::Fail)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let mark = match check.status {
+                Status::Pass => "ok  ",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "{mark}  {}", check.what)?;
+            if let Some(fix) = &check.fix {
+                writeln!(f, "      fix: {fix}")?;
+            }
+        }
+        let problems = self
+            .checks
+            .iter()
+            .filter(|c| c.status != Status::Pass)
+            .count();
+        match problems {
+            0 => writeln!(f, "\nEverything looks right."),
+            1 => writeln!(f, "\n1 problem found."),
+            n => writeln!(f, "\n{n} problems found."),
+        }
+    }
+}
+
+/// What an sshx server answered for a host without a tunnel.
+#[derive(Debug)]
+pub struct Probe {
+    pub status: u16,
+    /// The subdomain the host was routed to, if it is one of the server's.
+    pub route: Option<String>,
+    /// Unset if something other than an sshx server answered.
+    pub server_time: Option<SystemTime>,
+}
+
+/// Addresses `host` resolves to, sorted.
+pub async fn resolve(host: &str) -> Result<Vec<IpAddr>> {
+    let addrs = timeout(CHECK_TIMEOUT, lookup_host((host, 0)))
+        .await
+        .context("lookup timed out")??;
+    let mut ips: Vec<_> = addrs.map(|a| a.ip()).collect();
+    ips.sort();
+    ips.dedup();
+    Ok(ips)
+}
+
+/// Whether a TCP connection to `addr` opens.
+pub async fn reachable(addr: SocketAddr) -> Result<()> {
+    timeout(CHECK_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .context("timed out")??;
+    Ok(())
+}
+
+/// Ask the HTTP port at `addr` for `/` on `host`.
+pub async fn probe(addr: SocketAddr, host: &str) -> Result<Probe> {
+    timeout(CHECK_TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: {host}\r\nUser-Agent: sshx-doctor\r\n\
+             Connection: close\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut head = Vec::new();
+        let mut buf = [0; 4096];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || head.len() > MAX_HEAD {
+                break;
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        parse_head(&String::from_utf8_lossy(&head))
+    })
+    .await
+    .context("timed out")?
+}
+
+fn parse_head(head: &str) -> Result<Probe> {
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok());
+    let Some(status) = status else {
+        bail!("the answer is not HTTP");
+    };
+    let mut probe = Probe {
+        status,
+        route: None,
+        server_time: None,
+    };
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case(ROUTE_HEADER) {
+            probe.route = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case(TIME_HEADER) {
+            probe.server_time = value
+                .parse()
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        }
+    }
+    Ok(probe)
+}
+
+/// Check that `*.domain` resolves to one of `expected`, the server's
+/// addresses if known, and that the sshx server on `http_port` there routes a
+/// made-up subdomain as its own. Returns what it answered.
+pub async fn check_routing(
+    report: &mut Report,
+    domain: &str,
+    http_port: u16,
+    expected: &[IpAddr],
+) -> Option<Probe> {
+    let label = format!("sshx-check-{}", &Uuid::new_v4().simple().to_string()[..8]);
+    let host = format!("{label}.{domain}");
+    let ips = match resolve(&host).await {
+        Ok(ips) if !ips.is_empty() => ips,
+        _ => {
+            report.push(Check::fail(
+                format!("*.{domain} does not resolve (tried {host})"),
+                format!(
+                    "add wildcard DNS records `*.{domain}` of type A (and AAAA for IPv6) \
+                     with the server's public address"
+                ),
+            ));
+            return None;
+        }
+    };
+    if !expected.is_empty() && !ips.iter().any(|ip| expected.contains(ip)) {
+        report.push(Check::fail(
+            format!(
+                "*.{domain} resolves to {}, not to the server at {}",
+                list(&ips),
+                list(expected)
+            ),
+            format!(
+                "point the `*.{domain}` records at {}; changes can take the record's TTL \
+                 to show",
+                list(expected)
+            ),
+        ));
+        return None;
+    }
+    report.push(Check::pass(format!(
+        "*.{domain} resolves to {}",
+        list(&ips)
+    )));
+
+    let addr = SocketAddr::new(ips[0], http_port);
+    let probe = match probe(addr, &host).await {
+        Ok(probe) => probe,
+        Err(e) => {
+            report.push(Check::fail(
+                format!("nothing answers HTTP on {addr}: {e:#}"),
+                format!(
+                    "start sshx-server with --http-port {http_port} and open {http_port}/tcp \
+                     in the firewall"
+                ),
+            ));
+            return None;
+        }
+    };
+    let check = match (&probe.server_time, &probe.route) {
+        (None, _) => Check::fail(
+            format!(
+                "{addr} answered {} without sshx's headers; something else holds the port",
+                probe.status
+            ),
+            format!(
+                "let sshx-server have port {http_port}, or have the proxy in front of it pass \
+                 every host under {domain} through"
+            ),
+        ),
+        (Some(_), None) => Check::fail(
+            format!("the sshx server at {addr} does not count {host} as one of its hosts"),
+            format!("start sshx-server with --domain {domain}"),
+        ),
+        (Some(_), Some(route)) if *route != label => Check::fail(
+            format!("{host} was routed to '{route}' instead of '{label}'"),
+            format!("start sshx-server with --domain {domain}"),
+        ),
+        (Some(_), Some(_)) => Check::pass(format!(
+            "http://{host}:{http_port} reaches sshx-server, which routes it to '{label}'"
+        )),
+    };
+    report.push(check);
+    Some(probe)
+}
+
+/// Compare our clock with the one in `probe`.
+pub fn check_clock(report: &mut Report, probe: &Probe) {
+    let Some(server_time) = probe.server_time else {
+        return;
+    };
+    let now = SystemTime::now();
+    let (skew, direction) = match now.duration_since(server_time) {
+        Ok(ahead) => (ahead, "ahead of"),
+        Err(e) => (e.duration(), "behind"),
+    };
+    // The header has whole seconds.
+    if skew <= MAX_CLOCK_SKEW + Duration::from_secs(1) {
+        report.push(Check::pass("the clock agrees with the server's"));
+        return;
+    }
+    report.push(Check::warn(
+        format!("the clock is {}s {direction} the server's", skew.as_secs()),
+        "sync the clock with NTP, e.g. `sudo timedatectl set-ntp true`; certificates look \
+         invalid to a clock that is off",
+    ));
+}
+
+fn list(ips: &[IpAddr]) -> String {
+    let ips: Vec<_> = ips.iter().map(IpAddr::to_string).collect();
+    ips.join(", ")
+}
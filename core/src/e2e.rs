@@ -0,0 +1,371 @@
+//! End-to-end encryption of visitor connections, so the relay only ever
+//! carries ciphertext.
+//!
+//! The client exposing a port holds a [`Keypair`]; visitors connect with
+//! `sshx connect` and the client's [`PublicKey`]. The two run a Noise NK
+//! handshake over the tunnel: only the holder of the private key can
+//! answer it, and the server in between sees neither key nor plaintext.
+//! After that, bytes travel in frames of a 2-byte big-endian length and
+//! a ChaCha20-Poly1305 ciphertext, but a [`SecureStream`] reads and writes
+//! like any byte stream.
+//!
+//! Shutting a [`SecureStream`] down sends a frame without plaintext, and
+//! only that frame ends the stream for the other side: a connection that
+//! ends before it is an [`UnexpectedEof`](io::ErrorKind::UnexpectedEof), so
+//! the relay can't cut the tail off unnoticed, not even between frames.
+
+use std::{
+    fmt, fs, io,
+    path::Path,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Context as _, Result};
+use futures_util::ready;
+use snow::{params::NoiseParams, Builder, HandshakeState, TransportState};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time::timeout,
+};
+
+use crate::protocol::HANDSHAKE_TIMEOUT;
+
+const PATTERN: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+
+/// Mixed into the handshake, so it can't be taken for another protocol's.
+const PROLOGUE: &[u8] = b"sshx-e2e 1";
+
+/// Largest Noise message, length prefix excluded.
+const MAX_FRAME: usize = u16::MAX as usize;
+
+/// Poly1305 tag at the end of every frame.
+const TAG_LEN: usize = 16;
+
+/// Most plaintext one frame carries.
+const MAX_PLAIN: usize = MAX_FRAME - TAG_LEN;
+
+fn params() -> NoiseParams {
+    PATTERN.parse().expect("valid Noise pattern")
+}
+
+/// The Curve25519 key visitors encrypt to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey([u8; 32]);
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({self})")
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.trim()).context("the public key is not hex")?;
+        match <[u8; 32]>::try_from(bytes) {
+            Ok(key) => Ok(Self(key)),
+            Err(_) => bail!("the public key must be 64 hex digits"),
+        }
+    }
+}
+
+/// The key pair of a client whose visitors connect end to end.
+pub struct Keypair {
+    private: Vec<u8>,
+    public: PublicKey,
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Keypair {
+    pub fn generate() -> Self {
+        let keypair = Builder::new(params())
+            .generate_keypair()
+            .expect("Curve25519 keys can be generated");
+        let public = <[u8; 32]>::try_from(keypair.public).expect("Curve25519 keys are 32 bytes");
+        Self {
+            private: keypair.private,
+            public: PublicKey(public),
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+
+    /// Load the key pair in `path`: the private key and then the public key,
+    /// in hex, one per line. A missing file gets a new key pair, readable
+    /// only by its owner.
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                Self::parse(&text).with_context(|| format!("invalid key file {}", path.display()))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let keypair = Self::generate();
+                let text = format!("{}\n{}\n", hex::encode(&keypair.private), keypair.public);
+                write_private(path, &text)
+                    .with_context(|| format!("cannot write {}", path.display()))?;
+                Ok(keypair)
+            }
+            Err(e) => Err(e).with_context(|| format!("cannot read {}", path.display())),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut lines = text.lines();
+        let (Some(private), Some(public)) = (lines.next(), lines.next()) else {
+            bail!("expected a private and a public key");
+        };
+        let private = hex::decode(private.trim()).context("the private key is not hex")?;
+        if private.len() != 32 {
+            bail!("the private key must be 64 hex digits");
+        }
+        Ok(Self {
+            private,
+            public: public.parse()?,
+        })
+    }
+}
+
+fn write_private(path: &Path, text: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, text.as_bytes())
+}
+
+/// Tunnel side: answer a visitor's handshake with `keypair`.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    io: S,
+    keypair: &Keypair,
+) -> Result<SecureStream<S>> {
+    let noise = Builder::new(params())
+        .prologue(PROLOGUE)
+        .local_private_key(&keypair.private)
+        .build_responder()?;
+    handshake(io, noise, false).await
+}
+
+/// Visitor side: start a handshake with the holder of `peer`'s private key.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    io: S,
+    peer: &PublicKey,
+) -> Result<SecureStream<S>> {
+    let noise = Builder::new(params())
+        .prologue(PROLOGUE)
+        .remote_public_key(&peer.0)
+        .build_initiator()?;
+    handshake(io, noise, true).await
+}
+
+/// Run the two messages of NK, the initiator writing first.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    mut io: S,
+    mut noise: HandshakeState,
+    initiator: bool,
+) -> Result<SecureStream<S>> {
+    let exchange = async {
+        let mut buf = vec![0; MAX_FRAME];
+        for writing in [initiator, !initiator] {
+            if writing {
+                let n = noise.write_message(&[], &mut buf)?;
+                write_frame(&mut io, &buf[..n]).await?;
+            } else {
+                let frame = read_frame(&mut io).await?;
+                noise
+                    .read_message(&frame, &mut buf)
+                    .context("end-to-end handshake failed; is it the right key?")?;
+            }
+        }
+        anyhow::Ok(())
+    };
+    timeout(HANDSHAKE_TIMEOUT, exchange)
+        .await
+        .context("end-to-end handshake timed out")??;
+    Ok(SecureStream::new(io, noise.into_transport_mode()?))
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(io: &mut S) -> io::Result<Vec<u8>> {
+    let len = io.read_u16().await?;
+    let mut frame = vec![0; len as usize];
+    io.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(io: &mut S, frame: &[u8]) -> io::Result<()> {
+    io.write_u16(frame.len() as u16).await?;
+    io.write_all(frame).await?;
+    io.flush().await
+}
+
+/// A connection encrypted end to end, read and written as a byte stream.
+pub struct SecureStream<S> {
+    io: S,
+    noise: TransportState,
+    /// Ciphertext read but not decrypted yet: a length prefix and as much
+    /// of its frame as arrived.
+    read_raw: Vec<u8>,
+    /// Rest of the last frame's plaintext, not read yet.
+    plain: Vec<u8>,
+    plain_pos: usize,
+    /// Frames sealed but not written out yet.
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    /// The other side's end frame was read.
+    read_end: bool,
+    /// Ours was sealed.
+    sent_end: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SecureStream<S> {
+    fn new(io: S, noise: TransportState) -> Self {
+        Self {
+            io,
+            noise,
+            read_raw: Vec::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            read_end: false,
+            sent_end: false,
+        }
+    }
+
+    /// Decrypt the first frame of `read_raw`, if it arrived whole.
+    fn open_frame(&mut self) -> io::Result<bool> {
+        let Some(prefix) = self.read_raw.get(..2) else {
+            return Ok(false);
+        };
+        let len = u16::from_be_bytes([prefix[0], prefix[1]]) as usize;
+        let Some(frame) = self.read_raw.get(2..2 + len) else {
+            return Ok(false);
+        };
+        self.plain.resize(len, 0);
+        let n = self
+            .noise
+            .read_message(frame, &mut self.plain)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.plain.truncate(n);
+        self.plain_pos = 0;
+        self.read_raw.drain(..2 + len);
+        // Writes never seal an empty frame, so this is the end.
+        self.read_end = n == 0;
+        Ok(true)
+    }
+
+    /// Append `plain` to `write_buf` as one frame; empty for the end.
+    fn seal(&mut self, plain: &[u8]) -> io::Result<()> {
+        let start = self.write_buf.len();
+        self.write_buf.resize(start + 2 + plain.len() + TAG_LEN, 0);
+        let n = self
+            .noise
+            .write_message(plain, &mut self.write_buf[start + 2..])
+            .map_err(io::Error::other)?;
+        self.write_buf[start..start + 2].copy_from_slice(&(n as u16).to_be_bytes());
+        self.write_buf.truncate(start + 2 + n);
+        Ok(())
+    }
+
+    /// Write out the sealed frames.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n =
+                ready!(Pin::new(&mut self.io).poll_write(cx, &self.write_buf[self.write_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for SecureStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = out.remaining().min(this.plain.len() - this.plain_pos);
+                out.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_end {
+                return Poll::Ready(Ok(()));
+            }
+            if this.open_frame()? {
+                continue;
+            }
+            let mut chunk = [0; 16 * 1024];
+            let mut buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                // Only the end frame ends the stream.
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.read_raw.extend_from_slice(buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for SecureStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_PLAIN);
+        this.seal(&buf[..n])?;
+        // Start writing now; what doesn't fit goes with the next write or
+        // flush.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+        if !this.sent_end {
+            this.seal(&[])?;
+            this.sent_end = true;
+            ready!(this.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,246 @@
+//! Just enough HTTP/2 to route h2c visitors and count gRPC calls: frame
+//! headers and header blocks. Everything else goes by as bytes.
+//!
+//! A [`Reader`] follows one direction of a connection. Header blocks have to
+//! be decoded in order, since HPACK compresses each against the ones before,
+//! so a reader sees every byte of its direction; payloads of other frames
+//! are skipped without being buffered.
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// What clients send first: HTTP/2 with prior knowledge, or after TLS
+/// negotiated `h2`.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// ALPN protocol name of HTTP/2 over TLS.
+pub const ALPN: &[u8] = b"h2";
+
+const FRAME_HEADER: usize = 9;
+
+// Frame types.
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags.
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+/// Largest header block decoded.
+const MAX_BLOCK: usize = 64 * 1024;
+
+/// What a [`Reader`] found.
+#[derive(Debug)]
+pub enum Event {
+    /// A complete header block: a request or response head, or trailers.
+    Headers {
+        stream: u32,
+        headers: Vec<(Vec<u8>, Vec<u8>)>,
+        /// Nothing follows on the stream in this direction.
+        end_stream: bool,
+    },
+    /// The stream was cancelled.
+    Reset { stream: u32 },
+}
+
+impl Event {
+    /// Value of the header called `name` (pseudo-headers included) in a
+    /// header block.
+    pub fn header(&self, name: &str) -> Option<String> {
+        let Event::Headers { headers, .. } = self else {
+            return None;
+        };
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+    }
+}
+
+/// Follows the frames of one direction of an HTTP/2 connection.
+pub struct Reader {
+    /// Bytes of the preface still to come.
+    preface: usize,
+    state: State,
+    decoder: hpack::Decoder<'static>,
+    /// The header block being collected: its stream, whether the stream
+    /// ends with it, and the fragments so far.
+    block: Option<(u32, bool, Vec<u8>)>,
+}
+
+enum State {
+    /// Reading a frame header.
+    Header(Vec<u8>),
+    /// Reading the payload of a frame we look at.
+    Payload {
+        kind: u8,
+        flags: u8,
+        stream: u32,
+        len: usize,
+        buf: Vec<u8>,
+    },
+    /// Passing over the payload of a frame we don't look at.
+    Skip(usize),
+}
+
+impl Reader {
+    /// A reader of what a client sends, starting with the [`PREFACE`].
+    pub fn requests() -> Self {
+        Self::new(PREFACE.len())
+    }
+
+    /// A reader of what a server sends.
+    pub fn responses() -> Self {
+        Self::new(0)
+    }
+
+    fn new(preface: usize) -> Self {
+        Self {
+            preface,
+            state: State::Header(Vec::with_capacity(FRAME_HEADER)),
+            decoder: hpack::Decoder::new(),
+            block: None,
+        }
+    }
+
+    /// Take in the next bytes, adding what they complete to `events`. Fails
+    /// once the bytes aren't HTTP/2; the reader is of no use after that.
+    pub fn feed(&mut self, mut data: &[u8], events: &mut Vec<Event>) -> Result<()> {
+        if self.preface > 0 {
+            let skipped = &PREFACE[PREFACE.len() - self.preface..];
+            let n = self.preface.min(data.len());
+            if data[..n] != skipped[..n] {
+                bail!("no HTTP/2 preface");
+            }
+            self.preface -= n;
+            data = &data[n..];
+        }
+        while !data.is_empty() {
+            match &mut self.state {
+                State::Header(buf) => {
+                    let n = (FRAME_HEADER - buf.len()).min(data.len());
+                    buf.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    if buf.len() < FRAME_HEADER {
+                        return Ok(());
+                    }
+                    let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+                    let (kind, flags) = (buf[3], buf[4]);
+                    let stream = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & !(1 << 31);
+                    if self.block.is_some() && kind != CONTINUATION {
+                        bail!("header block interrupted");
+                    }
+                    self.state = match kind {
+                        HEADERS | CONTINUATION | RST_STREAM if len > MAX_BLOCK => {
+                            bail!("header block larger than {MAX_BLOCK} bytes")
+                        }
+                        HEADERS | CONTINUATION | RST_STREAM => State::Payload {
+                            kind,
+                            flags,
+                            stream,
+                            len,
+                            buf: Vec::with_capacity(len),
+                        },
+                        _ => State::Skip(len),
+                    };
+                    self.complete(events)?;
+                }
+                State::Payload { len, buf, .. } => {
+                    let n = (*len - buf.len()).min(data.len());
+                    buf.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    self.complete(events)?;
+                }
+                State::Skip(left) => {
+                    let n = (*left).min(data.len());
+                    *left -= n;
+                    data = &data[n..];
+                    self.complete(events)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the frame in `state` if all of it is there.
+    fn complete(&mut self, events: &mut Vec<Event>) -> Result<()> {
+        let next = State::Header(Vec::with_capacity(FRAME_HEADER));
+        match &self.state {
+            State::Skip(0) => self.state = next,
+            State::Payload { len, buf, .. } if buf.len() == *len => {
+                let State::Payload {
+                    kind,
+                    flags,
+                    stream,
+                    buf,
+                    ..
+                } = std::mem::replace(&mut self.state, next)
+                else {
+                    unreachable!()
+                };
+                self.frame(kind, flags, stream, &buf, events)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream: u32,
+        payload: &[u8],
+        events: &mut Vec<Event>,
+    ) -> Result<()> {
+        match (kind, &mut self.block) {
+            (RST_STREAM, _) => {
+                events.push(Event::Reset { stream });
+                return Ok(());
+            }
+            (HEADERS, None) => {
+                let fragment = fragment(payload, flags).context("malformed HEADERS")?;
+                self.block = Some((stream, flags & END_STREAM != 0, fragment.to_vec()));
+            }
+            (CONTINUATION, Some((open, _, block))) if *open == stream => {
+                if block.len() + payload.len() > MAX_BLOCK {
+                    bail!("header block larger than {MAX_BLOCK} bytes");
+                }
+                block.extend_from_slice(payload);
+            }
+            _ => bail!("header block out of order"),
+        }
+        if flags & END_HEADERS == 0 {
+            return Ok(());
+        }
+        let (stream, end_stream, block) = self.block.take().unwrap_or_default();
+        let headers = self
+            .decoder
+            .decode(&block)
+            .map_err(|e| anyhow!("malformed header block: {e:?}"))?;
+        events.push(Event::Headers {
+            stream,
+            headers,
+            end_stream,
+        });
+        Ok(())
+    }
+}
+
+/// The header block fragment of a HEADERS frame, without padding and
+/// priority.
+fn fragment(payload: &[u8], flags: u8) -> Option<&[u8]> {
+    let (pad, mut fragment) = match flags & PADDED {
+        0 => (0, payload),
+        _ => {
+            let (&pad, rest) = payload.split_first()?;
+            (pad as usize, rest)
+        }
+    };
+    if flags & PRIORITY != 0 {
+        fragment = fragment.get(5..)?;
+    }
+    fragment.get(..fragment.len().checked_sub(pad)?)
+}
@@ -0,0 +1,14 @@
+//! sshx-core — the wire protocol shared by the sshx client and server.
+//!
+//! Both binaries build on this crate, so they always agree on ports, message
+//! shapes and framing. Changes to [`protocol`] must stay readable by peers
+//! built from older releases; `tests/compat.rs` pins the wire format.
+
+pub mod auth;
+pub mod doctor;
+pub mod e2e;
+pub mod h2;
+pub mod protocol;
+pub mod resume;
+pub mod splice;
+pub mod ws;
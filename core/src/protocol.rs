@@ -0,0 +1,809 @@
+//! Protocol definitions for sshx tunnels.
+//!
+//! Control plane: null-delimited JSON on port 12267, at most [`MAX_FRAME`]
+//! bytes a frame. JSON never contains a raw NUL, so the delimiter can't
+//! appear inside a message.
+//! Data plane:   raw TCP copy_bidirectional; UDP flows carry length-prefixed
+//!               datagrams (see [`datagram_codec`]).
+//!
+//! When both sides set `mux` in their `Hello`, the control connection turns
+//! into a yamux session right after the handshake. The server opens every
+//! stream: first the control stream, then one stream per inbound connection,
+//! which starts with a `Connection` frame followed by the raw bytes. Peers
+//! that leave `mux` out get the classic one-connection-per-`Accept` plane.
+//!
+//! Once the first tunnel is up, a client may `Register` more tunnels on the
+//! same control connection; each `Connection` names the tunnel it is for.
+//!
+//! A connection may start with `Pull` instead of `Hello`: the client wants a
+//! port reachable from the server. The server connects to it and answers
+//! `Pulled`, and the connection carries that port's bytes from then on.
+//! Servers from before pulling hang up instead.
+//!
+//! Likewise, `Lookup` asks which public port a tunnel has; the server answers
+//! `Found` and closes the connection.
+//!
+//! Versions: the client puts the newest [`PROTOCOL_VERSION`] it speaks in its
+//! `Hello`, and the server answers with the version both will use, the lower
+//! of the two (see [`negotiate`]). A `Hello` without a version is version 1,
+//! which is what every peer from before versioning speaks, so old and new
+//! peers pair up at 1. A server refuses a client older than its
+//! [`MIN_PROTOCOL_VERSION`] with `protocol_mismatch`, and a client hangs up on
+//! a server that answers with a version it no longer speaks. New messages
+//! are only sent to peers at the version that introduced them:
+//!
+//! - 1: everything up to `Reconfigure`, plus the `mux`, `desired_port`, `acl`,
+//!   `error_codes`, `resume`, `seal`, `heartbeat_interval_ms`, `session`,
+//!   `addrs` and server `subdomain` fields that older peers ignore. A client's
+//!   `Hello` without a `subdomain` only works with servers that pick one.
+//! - 2: `Refused` for every refusal, authentication included, and `Shutdown`.
+//! - 3: `Expired`.
+//! - 4: `Ping` instead of `Heartbeat`, answered with `Pong`.
+//! - 5: `ConnectionAck` for every `Connection` that doesn't come as a stream,
+//!   and `ConnectionCancelled` when a parked visitor was never accepted.
+//! - 6: `Stats` for every tunnel whose traffic changed, by default every
+//!   [`STATS_INTERVAL`].
+//! - 7: the `public_port` a visitor came in on, in `Connection`.
+//! - 8: `grpc` in `Hello` and `Register`. Older servers take such a tunnel
+//!   for a plain HTTP one, so clients check the version they got.
+//! - 9: `AcceptResumable`, `ResumeStream` and `StreamResumed`, for data
+//!   connections that can drop and be picked up again (see [`resume`]).
+//! - 10: `pool` in `Hello` and `Register`. Older servers refuse a second
+//!   client on the subdomain as usual, so clients check the version they got.
+//! - 11: `takeover` in `Hello`, and `HandedOver`, which only clients that
+//!   sent a takeover key get.
+//! - 12: the visitor's `country` and `asn` in `Connection`, when the server
+//!   has a GeoIP database that knows them.
+//! - 13: `warm` in both `Hello`s, and `Standby`: a client that doesn't
+//!   multiplex parks idle data connections at the server, each of which
+//!   gets the next visitor's `Connection` frame followed by its raw bytes,
+//!   with no `Accept` round trip.
+//! - 14: `Unregister`, answered with `Unregistered`: a client closes one of
+//!   its tunnels and keeps the others on the connection up.
+//! - 15: `ttl_secs` and `max_uses` in `Hello`; the server closes such tunnels
+//!   with `Expired`. Clients stop older servers' tunnels themselves.
+//!
+//! [`resume`]: crate::resume
+
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+pub use ipnet::IpNet;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts, LengthDelimitedCodec};
+pub use tokio_yamux::{session::SessionType, Control, StreamHandle};
+use tokio_yamux::{Config as MuxConfig, Session};
+
+/// Control port — clients connect here first.
+pub const CONTROL_PORT: u16 = 12267;
+
+/// TLS control port, used with `--tls`.
+pub const TLS_CONTROL_PORT: u16 = 12268;
+
+/// DNS-SD service type servers started with `--mdns` announce on the LAN.
+/// Its TXT record has the protocol `version` and, with a TLS control port,
+/// `tls_port`.
+pub const MDNS_SERVICE: &str = "_sshx._tcp.local.";
+
+/// ALPN protocol of the QUIC transport, served on UDP at the TLS control
+/// port.
+pub const QUIC_ALPN: &[u8] = b"sshx";
+
+/// First byte the client writes on every QUIC stream it opens. The server
+/// only learns of a stream once it carries data, and would otherwise never
+/// send its `Challenge`.
+pub const QUIC_STREAM_OPEN: u8 = b'S';
+
+/// Max JSON frame size (bytes), leaving room for long ACLs and reports.
+/// Peers from before it was raised read at most 4096.
+pub const MAX_FRAME: usize = 64 * 1024;
+
+/// Default interval between server heartbeats.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default silence on a control connection after which a peer gives up on
+/// the other.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Heartbeats in a row a client may leave unanswered before the server
+/// drops it.
+pub const MAX_MISSED_HEARTBEATS: u32 = 10;
+
+/// How often the server reports the traffic of tunnels that had any.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most data connections a client may keep parked with `Standby`.
+pub const MAX_WARM_POOL: usize = 64;
+
+/// Largest datagram a UDP tunnel carries.
+pub const MAX_DATAGRAM: usize = 65_535;
+
+/// Default size of the buffer a visitor's bytes are copied through, in each
+/// direction: tokio's own. Large transfers go faster with a bigger one.
+pub const BUFFER_SIZE: usize = 8 * 1024;
+
+/// UDP flows with no traffic in either direction for this long are closed.
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default timeout for initial handshake messages.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long peers wait on each other. Satellite and mobile links need all of
+/// them much larger than the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// For each step of a handshake: connecting, TLS, and every message up
+    /// to the first tunnel.
+    pub handshake: Duration,
+    /// Between heartbeats on a control connection.
+    pub heartbeat_interval: Duration,
+    /// Silence on a control connection after which the peer is given up on.
+    pub heartbeat_timeout: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: HANDSHAKE_TIMEOUT,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            heartbeat_timeout: HEARTBEAT_TIMEOUT,
+        }
+    }
+}
+
+/// Turn off Nagle's algorithm on a control or data socket, whose small
+/// frames shouldn't wait, and have the OS probe it once it sat idle for
+/// `keepalive`, so a peer that vanished behind a NAT is noticed.
+pub fn tune_socket(stream: &TcpStream, keepalive: Duration) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))
+}
+
+/// Newest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u32 = 15;
+
+/// Oldest protocol version this build still talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The version to use with a peer that speaks up to `peer`: the newest both
+/// speak, or `None` if the peer is too old for this build.
+pub fn negotiate(peer: u32) -> Option<u32> {
+    (peer >= MIN_PROTOCOL_VERSION).then(|| peer.min(PROTOCOL_VERSION))
+}
+
+/// Version of peers that don't say.
+fn version_1() -> u32 {
+    1
+}
+
+fn is_version_1(version: &u32) -> bool {
+    *version == 1
+}
+
+// ── Messages: Client → Server ────────────────────────────────────────────────
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientMsg {
+    /// Step 1 after optional auth: register a subdomain + protocol.
+    Hello {
+        /// `None` asks the server to pick one, which it names in its
+        /// `Hello`. Servers from before that refuse a `Hello` without it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subdomain: Option<String>,
+        proto: Proto,
+        /// An HTTP tunnel for gRPC: only HTTP/2 visitors get in.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        grpc: bool,
+        /// Share the subdomain with other clients that ask for a pool too:
+        /// the server spreads visitors across them. Version 10.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pool: bool,
+        /// Key that lets a later client with the same one take this
+        /// connection's tunnels over, and this one take over tunnels that
+        /// were registered with it. Version 11.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        takeover: Option<String>,
+        /// Heartbeats further apart than the server's, for slow links.
+        /// Servers don't send them more often than they would anyway.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        heartbeat_interval_ms: Option<u64>,
+        /// The client can multiplex data streams over this connection.
+        #[serde(default)]
+        mux: bool,
+        /// The client will park data connections with `Standby` rather than
+        /// multiplex. Version 13.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        warm: bool,
+        /// Close this connection's tunnels after this many seconds. Version
+        /// 15; older servers leave it to the client.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_secs: Option<u64>,
+        /// Close each of this connection's tunnels once this many visitors
+        /// came through and the last one left; later ones are turned away.
+        /// Version 15.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_uses: Option<u64>,
+        /// Public port to bind instead of a random one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        desired_port: Option<u16>,
+        /// Who may reach this connection's tunnels.
+        #[serde(default, skip_serializing_if = "Acl::is_empty")]
+        acl: Acl,
+        /// The client understands `Refused`; without it the server only
+        /// sends `Error`. Implied by version 2.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        error_codes: bool,
+        /// Newest protocol version the client speaks.
+        #[serde(default = "version_1", skip_serializing_if = "is_version_1")]
+        version: u32,
+        /// `session` of the previous connection, to get the public ports of
+        /// its tunnels back.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume: Option<uuid::Uuid>,
+        /// Ties this `Hello` to the auth answer before it: an HMAC, with the
+        /// secret, of the challenge and the [`binding`](ClientMsg::binding).
+        /// Set by clients that authenticated.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seal: Option<String>,
+    },
+    /// Register one more tunnel on an established control connection.
+    Register {
+        subdomain: String,
+        proto: Proto,
+        /// As in `Hello`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        grpc: bool,
+        /// As in `Hello`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pool: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        desired_port: Option<u16>,
+    },
+    /// Close the tunnel for `subdomain`, one of this connection's, without
+    /// holding its port; visitors in progress finish. Version 14.
+    Unregister { subdomain: String },
+    /// Auth challenge response.
+    Authenticate(String),
+    /// Accept a pending proxied connection.
+    Accept(uuid::Uuid),
+    /// Accept it as a resumable stream: resume frames follow instead of raw
+    /// bytes, both ways. Version 9.
+    AcceptResumable(uuid::Uuid),
+    /// Answer to `Ping`, with its nonce. Version 4.
+    Pong(u64),
+    /// Whether the client will `Accept` the parked connection `id`. The
+    /// server holds a visitor that was promised a while longer, and turns
+    /// one that was declined away at once. Version 5.
+    ConnectionAck { id: uuid::Uuid, accepted: bool },
+    /// Instead of `Hello`: connect this connection to `target`, a
+    /// `host:port` the server reaches.
+    Pull { target: String },
+    /// Instead of `Hello`: ask for the public port of the tunnel of
+    /// `subdomain`.
+    Lookup { subdomain: String },
+    /// Instead of `Hello`: carry on the resumable stream of the connection
+    /// `id`, whose data connection dropped, over this one. Version 9.
+    ResumeStream(uuid::Uuid),
+    /// Instead of `Hello`: wait at the server for a visitor of the session
+    /// whose `Hello` answered with this `warm` key. The visitor's
+    /// `Connection` frame comes down when there is one, then raw bytes.
+    /// Version 13.
+    Standby(uuid::Uuid),
+}
+
+impl ClientMsg {
+    /// What the `seal` of a `Hello` covers: everything it asks for, in a
+    /// fixed encoding. Fields newer than the seal are appended only when one
+    /// is set, so a `Hello` that leaves them out binds as it always did and
+    /// older clients' seals still verify. `None` for other messages.
+    pub fn binding(&self) -> Option<Vec<u8>> {
+        let ClientMsg::Hello {
+            subdomain,
+            proto,
+            grpc,
+            pool,
+            takeover,
+            heartbeat_interval_ms,
+            warm,
+            ttl_secs,
+            max_uses,
+            desired_port,
+            acl,
+            resume,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        let fields = (subdomain, proto, desired_port, acl, resume);
+        let mut binding = serde_json::to_vec(&fields).expect("Hello fields serialize");
+        let newer = (
+            heartbeat_interval_ms,
+            grpc,
+            pool,
+            takeover,
+            warm,
+            ttl_secs,
+            max_uses,
+        );
+        if newer != (&None, &false, &false, &None, &false, &None, &None) {
+            serde_json::to_writer(&mut binding, &newer).expect("Hello fields serialize");
+        }
+        Some(binding)
+    }
+}
+
+// ── Messages: Server → Client ────────────────────────────────────────────────
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServerMsg {
+    /// Auth challenge (only sent when server has a secret).
+    Challenge(uuid::Uuid),
+    /// Subdomain registered OK. `public_port` is the exposed port on the server.
+    Hello {
+        public_port: u16,
+        /// Public URL when the server routes HTTP tunnels by hostname.
+        #[serde(default)]
+        url: Option<String>,
+        /// The connection switches to a multiplexed session after this message.
+        #[serde(default)]
+        mux: bool,
+        /// Protocol version the connection uses from here on.
+        #[serde(default = "version_1", skip_serializing_if = "is_version_1")]
+        version: u32,
+        /// Present as `resume` when reconnecting; the server holds the
+        /// connection's subdomains and ports for it a while. `None` when
+        /// the server doesn't.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<uuid::Uuid>,
+        /// The subdomain the server picked, for a client that left it out.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subdomain: Option<String>,
+        /// Every address `public_port` is bound on, e.g. an IPv4 and an
+        /// IPv6 one on a dual-stack server.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        addrs: Vec<SocketAddr>,
+        /// Key the client's `Standby` connections present, when it asked
+        /// for `warm` and doesn't multiplex. Version 13.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        warm: Option<uuid::Uuid>,
+    },
+    /// Reply to `Register`. Failures are reported with `Error`.
+    Registered {
+        subdomain: String,
+        public_port: u16,
+        #[serde(default)]
+        url: Option<String>,
+        /// As in `Hello`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        addrs: Vec<SocketAddr>,
+    },
+    /// Reply to `Unregister`: the tunnel takes no more visitors. An unknown
+    /// subdomain is refused with `no_such_tunnel`. Version 14.
+    Unregistered { subdomain: String },
+    /// Keepalive — sent every ~500 ms on idle control connections.
+    Heartbeat,
+    /// Keepalive the client answers with `Pong(nonce)`, so the server knows
+    /// it is still there. Sent instead of `Heartbeat` from version 4.
+    Ping {
+        nonce: u64,
+        /// Round trip of the last answered ping, as the server measured it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rtt_ms: Option<u64>,
+    },
+    /// A new inbound connection arrived; client should open a data connection.
+    Connection {
+        id: uuid::Uuid,
+        /// Address of the remote peer that connected to the public port.
+        peer_addr: SocketAddr,
+        /// Tunnel the connection is for; older servers only carry one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subdomain: Option<String>,
+        /// Server port the visitor connected to. Version 7.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_port: Option<u16>,
+        /// ISO 3166-1 code of the visitor's country, e.g. `DE`. Version 12.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        country: Option<String>,
+        /// Autonomous system the visitor's address belongs to. Version 12.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        asn: Option<u32>,
+    },
+    /// The visitor parked as `id` waited too long for the client's `Accept`
+    /// and was turned away; an `Accept` for it finds nothing. Version 5.
+    ConnectionCancelled { id: uuid::Uuid },
+    /// Traffic of the tunnel for `subdomain` since it registered, as the
+    /// server relayed it: visitor connections, bytes from visitors and bytes
+    /// back to them. Version 6.
+    Stats {
+        subdomain: String,
+        conns: u64,
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+    /// Operator-pushed settings; sent after `Hello` and whenever they change.
+    Reconfigure(ClientSettings),
+    /// The server is going away: it takes no more visitors and closes the
+    /// connection once in-flight ones finish. Reconnect later. Version 2.
+    Shutdown,
+    /// The server closed a tunnel that sat idle or outlived its maximum
+    /// lifetime. Version 3; older clients get an `Error`.
+    Expired { subdomain: String, message: String },
+    /// Another client took over the tunnel for `subdomain` with its
+    /// takeover key: new visitors go there now. The server closes the
+    /// connection once in-flight ones finish and no tunnel is left; don't
+    /// reconnect. Version 11.
+    HandedOver { subdomain: String },
+    /// Reply to `Pull`: the server is connected to the target, at
+    /// `peer_addr`, and raw bytes follow.
+    Pulled { peer_addr: SocketAddr },
+    /// Reply to `Lookup`: the tunnel is up, with this protocol and port.
+    Found { proto: Proto, public_port: u16 },
+    /// Reply to `ResumeStream`: resume frames follow, both ways. Version 9.
+    StreamResumed,
+    /// Something went wrong.
+    Error(String),
+    /// A request was turned down for a reason the client can act on. Sent
+    /// instead of `Error` to clients that set `error_codes` or speak
+    /// version 2.
+    Refused { code: ErrorCode, message: String },
+}
+
+/// Why a request failed, so clients can decide what to do without reading
+/// the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The server wants a secret or token and none was given.
+    AuthRequired,
+    /// The secret or token is wrong.
+    AuthFailed,
+    /// Another tunnel holds the subdomain.
+    SubdomainTaken,
+    /// The operator banned the subdomain.
+    SubdomainBanned,
+    /// The requested public port is bound by something else.
+    PortTaken,
+    /// The requested public port is outside the server's range.
+    PortOutOfRange,
+    /// Every port in the server's range is in use.
+    NoPorts,
+    /// The peer sent something this side doesn't understand.
+    ProtocolMismatch,
+    /// The client's identity may not claim this subdomain.
+    SubdomainNotPermitted,
+    /// The client's identity may not bind this public port.
+    PortNotPermitted,
+    /// The client's identity may not open tunnels of this protocol.
+    ProtocolNotPermitted,
+    /// The client's identity has as many tunnels open as it may.
+    QuotaExceeded,
+    /// The server doesn't let clients pull the requested target.
+    PullNotPermitted,
+    /// No tunnel has the subdomain that was looked up.
+    NoSuchTunnel,
+    /// No stream to resume has the id, or it stopped waiting.
+    NoSuchStream,
+    /// A code this version doesn't know yet.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Operational parameters the server can push to connected clients.
+///
+/// Every field is optional; `None` leaves the client's current value alone.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientSettings {
+    /// Interval between server heartbeats, in milliseconds.
+    pub heartbeat_interval_ms: Option<u64>,
+    /// Seconds to wait before reconnecting after a disconnect.
+    pub reconnect_delay_secs: Option<u64>,
+    /// Max data connections the client should serve at once.
+    pub max_connections: Option<usize>,
+    /// Message for users, e.g. a maintenance announcement.
+    pub notice: Option<String>,
+}
+
+/// Which visitor addresses may reach a tunnel.
+///
+/// An address matching an `allow` network gets in; otherwise one matching a
+/// `deny` network is refused. When there are `allow` networks, everything
+/// else is refused too.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<IpNet>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<IpNet>,
+}
+
+impl Acl {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // IPv4 visitors of a dual-stack listener show up as ::ffff:a.b.c.d.
+        let ip = ip.to_canonical();
+        if self.allow.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+        self.allow.is_empty() && !self.deny.iter().any(|net| net.contains(&ip))
+    }
+}
+
+// ── Protocol type ─────────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Proto {
+    Tcp,
+    Http,
+    /// Datagrams; every visitor address is a flow of its own.
+    Udp,
+}
+
+// ── Framed transport ──────────────────────────────────────────────────────────
+
+/// Why a frame could not be sent or received. Comes wrapped in the
+/// `anyhow::Error` of [`Framed_`]; downcast to tell what the peer did wrong.
+#[derive(Debug)]
+pub enum FrameError {
+    /// Longer than the limit, or no delimiter within it.
+    TooLarge { limit: usize },
+    /// Not a message of this protocol: broken JSON, an unknown message or
+    /// the wrong fields.
+    Malformed(serde_json::Error),
+    /// The connection failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { limit } => write!(f, "frame longer than {limit} bytes"),
+            Self::Malformed(e) => write!(f, "malformed frame: {e}"),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::TooLarge { .. } => None,
+            Self::Malformed(e) => Some(e),
+            Self::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Splits a byte stream into null-delimited frames of at most a limit,
+/// without the delimiter.
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    limit: usize,
+    /// Where to continue looking for the delimiter.
+    searched: usize,
+}
+
+impl FrameCodec {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, searched: 0 }
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(MAX_FRAME)
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = FrameError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, FrameError> {
+        // Never look further than a frame may reach.
+        let end = buf.len().min(self.limit + 1);
+        let start = self.searched.min(end);
+        match buf[start..end].iter().position(|&b| b == 0) {
+            Some(at) => {
+                let frame = buf.split_to(start + at);
+                buf.advance(1);
+                self.searched = 0;
+                Ok(Some(frame))
+            }
+            None if buf.len() > self.limit => Err(FrameError::TooLarge { limit: self.limit }),
+            None => {
+                self.searched = buf.len();
+                Ok(None)
+            }
+        }
+    }
+
+    /// A last frame without a delimiter still counts, as it always has.
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, FrameError> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => {
+                self.searched = 0;
+                Ok(Some(buf.split()))
+            }
+        }
+    }
+}
+
+impl Encoder<String> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, frame: String, buf: &mut BytesMut) -> Result<(), FrameError> {
+        if frame.len() > self.limit {
+            return Err(FrameError::TooLarge { limit: self.limit });
+        }
+        buf.reserve(frame.len() + 1);
+        buf.put_slice(frame.as_bytes());
+        buf.put_u8(0);
+        Ok(())
+    }
+}
+
+/// Null-delimited JSON transport.
+pub struct Framed_<U> {
+    inner: Framed<U, FrameCodec>,
+    /// How long [`recv_timeout`](Self::recv_timeout) waits.
+    handshake_timeout: Duration,
+}
+
+impl<U: AsyncRead + AsyncWrite + Unpin> Framed_<U> {
+    pub fn new(stream: U) -> Self {
+        Self {
+            inner: Framed::new(stream, FrameCodec::default()),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Wait this long for handshake messages instead of
+    /// [`HANDSHAKE_TIMEOUT`].
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        match self.inner.next().await {
+            Some(Ok(bytes)) => Ok(serde_json::from_slice(&bytes).map_err(FrameError::Malformed)?),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn recv_timeout<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        timeout(self.handshake_timeout, self.recv())
+            .await
+            .context("handshake timed out")?
+    }
+
+    /// Bytes read but not yet part of a complete frame.
+    pub fn unread(&self) -> &[u8] {
+        self.inner.read_buffer()
+    }
+
+    pub async fn send<T: Serialize>(&mut self, msg: T) -> Result<()> {
+        self.inner.send(serde_json::to_string(&msg)?).await?;
+        Ok(())
+    }
+
+    /// Flush and close the sending side; the peer reads everything sent so
+    /// far, then the end of the stream.
+    pub async fn close(&mut self) -> Result<()> {
+        SinkExt::<String>::close(&mut self.inner).await?;
+        Ok(())
+    }
+
+    pub fn into_parts(self) -> FramedParts<U, FrameCodec> {
+        self.inner.into_parts()
+    }
+
+    /// Switch to datagram framing, keeping bytes the JSON codec read ahead.
+    pub fn into_datagrams(self) -> Framed<U, LengthDelimitedCodec> {
+        let old = self.inner.into_parts();
+        let mut parts = FramedParts::new::<Bytes>(old.io, datagram_codec());
+        parts.read_buf = old.read_buf;
+        Framed::from_parts(parts)
+    }
+}
+
+/// Framing of UDP flows on a byte stream: every datagram is preceded by its
+/// length as a big-endian `u16`.
+pub fn datagram_codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .length_field_length(2)
+        .max_frame_length(MAX_DATAGRAM)
+        .new_codec()
+}
+
+// ── Multiplexing ──────────────────────────────────────────────────────────────
+
+/// Switch a control connection to a yamux session running in the background.
+///
+/// Streams opened by the peer arrive on the returned receiver; streams nobody
+/// receives are dropped. Close the session through the returned [`Control`].
+pub fn multiplex<T>(ctrl: Framed_<T>, side: SessionType) -> (Control, mpsc::Receiver<StreamHandle>)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The peer may already have sent yamux frames that the codec read ahead.
+    let parts = ctrl.into_parts();
+    let io = Rewind {
+        buf: parts.read_buf,
+        io: parts.io,
+    };
+    let mut session = Session::new(io, MuxConfig::default(), side);
+    let control = session.control();
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        while let Some(Ok(stream)) = session.next().await {
+            let _ = tx.send(stream).await;
+        }
+    });
+    (control, rx)
+}
+
+/// A stream that yields `buf` before reading from `io` again.
+struct Rewind<T> {
+    buf: BytesMut,
+    io: T,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.buf.is_empty() {
+            return Pin::new(&mut self.io).poll_read(cx, out);
+        }
+        let n = self.buf.len().min(out.remaining());
+        out.put_slice(&self.buf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
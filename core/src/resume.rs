@@ -0,0 +1,325 @@
+//! Resumable data streams: a visitor's bytes get through a data connection
+//! between client and server that drops mid-transfer, as long as the client
+//! opens a new one within [`RESUME_WINDOW`].
+//!
+//! On a resumable stream, each side sends frames instead of raw bytes:
+//!
+//! - `D`, a big-endian u32 length and that many bytes of the stream;
+//! - `A` and a big-endian u64: how much of the stream it received so far;
+//! - `F`: the stream ends here. It counts as one more unit received.
+//!
+//! What a side sends stays in its replay buffer, at most [`REPLAY_BUFFER`]
+//! bytes, until the other acknowledges it; reading from the near end waits
+//! while the buffer is full. A connection that fails, or goes
+//! [`LINK_TIMEOUT`] without a frame, is given up on. The client then opens a
+//! new one with `ResumeStream` and the server hands it to the stream; on it,
+//! both sides send an `A` first and then everything after the other's.
+//! Frames come at least every [`KEEPALIVE`], an `A` on an idle stream.
+
+use std::{
+    future::Future,
+    io::{self, Cursor},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    time::{interval_at, timeout, Instant},
+};
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+
+use crate::protocol::Framed_;
+
+/// How long a stream whose data connection dropped waits for the client to
+/// resume it.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(30);
+
+/// Most bytes sent and not acknowledged yet, per stream and direction.
+pub const REPLAY_BUFFER: usize = 4 * 1024 * 1024;
+
+/// Longest a side stays silent on a connection.
+pub const KEEPALIVE: Duration = Duration::from_secs(5);
+
+/// Silence after which a connection is given up on.
+pub const LINK_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Most bytes in one `D` frame.
+const MAX_DATA: usize = 64 * 1024;
+
+/// How long a finished stream waits for the peer to close the connection.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// A data connection a stream runs over, once the control frames before it
+/// were read.
+pub trait Link: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Link for T {}
+
+/// The connection under `framed`, starting with the bytes it read ahead.
+pub fn unframe<S>(framed: Framed_<S>) -> Box<dyn Link>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let parts = framed.into_parts();
+    let (read, write) = tokio::io::split(parts.io);
+    Box::new(tokio::io::join(
+        AsyncReadExt::chain(Cursor::new(parts.read_buf), read),
+        write,
+    ))
+}
+
+/// Relay `near`, whose first bytes `read_ahead` were read from it already,
+/// over the resumable stream on `link`, like
+/// [`tokio::io::copy_bidirectional`]. When a connection fails, `relink` is
+/// asked for the next one; the stream fails with it if it can't get one.
+/// Returns the bytes that went each way, from `near` first.
+pub async fn relay<A, F, Fut>(
+    near: A,
+    read_ahead: Vec<u8>,
+    link: Box<dyn Link>,
+    mut relink: F,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite,
+    F: FnMut(io::Error) -> Fut,
+    Fut: Future<Output = io::Result<Box<dyn Link>>>,
+{
+    let (mut near_read, mut near_write) = tokio::io::split(near);
+    let mut stream = Stream::new(read_ahead);
+    let mut link = link;
+    loop {
+        match stream.run(&mut near_read, &mut near_write, link).await {
+            Ok(()) => return Ok((stream.read, stream.received - 1)),
+            Err(Failure::Near(e)) => return Err(e),
+            Err(Failure::Link(e)) => {
+                stream.resyncing = true;
+                link = relink(e).await?;
+            }
+        }
+    }
+}
+
+/// What ended a connection of a stream.
+enum Failure {
+    /// The near end failed: the stream is over.
+    Near(io::Error),
+    /// The connection did: the stream may go on over another.
+    Link(io::Error),
+}
+
+fn invalid(message: &str) -> Failure {
+    Failure::Link(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// One side of a stream; outlives the connections it runs over. Positions
+/// count bytes from the near end, then one for its end.
+struct Stream {
+    /// Bytes read from the near end.
+    read: u64,
+    /// The last of them, from where the peer acknowledged on.
+    replay: BytesMut,
+    /// How much the peer acknowledged.
+    acked: u64,
+    /// Where sending goes on from.
+    next: u64,
+    /// The near end has no more bytes.
+    near_done: bool,
+    /// What was received, the end included.
+    received: u64,
+    /// The far end has no more bytes.
+    far_done: bool,
+    /// What the last `A` said was received.
+    told: u64,
+    /// Received and not written to the near end yet.
+    to_near: BytesMut,
+    /// The near end was told there is no more.
+    near_shut: bool,
+    /// On a new connection, until the peer's first `A` says where to go on.
+    resyncing: bool,
+}
+
+impl Stream {
+    fn new(read_ahead: Vec<u8>) -> Self {
+        Self {
+            read: read_ahead.len() as u64,
+            replay: BytesMut::from(&read_ahead[..]),
+            acked: 0,
+            next: 0,
+            near_done: false,
+            received: 0,
+            far_done: false,
+            told: 0,
+            to_near: BytesMut::new(),
+            near_shut: false,
+            resyncing: false,
+        }
+    }
+
+    /// Where the stream ends once the near end is done.
+    fn end(&self) -> u64 {
+        self.read + u64::from(self.near_done)
+    }
+
+    /// Both ends are done, and both sides know everything arrived.
+    fn finished(&self) -> bool {
+        self.near_done && self.acked == self.end() && self.near_shut && self.told == self.received
+    }
+
+    /// Take the peer's word that it received `n`.
+    fn ack(&mut self, n: u64) -> Result<(), Failure> {
+        if n > self.end() {
+            return Err(invalid("acknowledged more than was sent"));
+        }
+        if self.resyncing && n < self.acked {
+            return Err(invalid("acknowledged less than before"));
+        }
+        if self.resyncing {
+            self.next = n;
+            self.resyncing = false;
+        }
+        self.acked = self.acked.max(n);
+        let keep = self.read - self.acked.min(self.read);
+        self.replay.advance(self.replay.len() - keep as usize);
+        Ok(())
+    }
+
+    /// Frame what can be sent now into `out`.
+    fn send(&mut self, out: &mut BytesMut) {
+        if self.resyncing {
+            return;
+        }
+        while out.len() < MAX_DATA && self.next < self.read {
+            let start = self.replay.len() - (self.read - self.next) as usize;
+            let len = (self.replay.len() - start).min(MAX_DATA);
+            out.put_u8(b'D');
+            out.put_u32(len as u32);
+            out.put_slice(&self.replay[start..start + len]);
+            self.next += len as u64;
+        }
+        if self.near_done && self.next == self.read {
+            out.put_u8(b'F');
+            self.next += 1;
+        }
+    }
+
+    fn tell(&mut self, out: &mut BytesMut) {
+        out.put_u8(b'A');
+        out.put_u64(self.received);
+        self.told = self.received;
+    }
+
+    /// Take in the frames at the start of `buf`.
+    fn receive(&mut self, buf: &mut BytesMut) -> Result<(), Failure> {
+        while let Some(&kind) = buf.first() {
+            match kind {
+                b'D' if buf.len() >= 5 => {
+                    let len = u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize;
+                    if len > MAX_DATA {
+                        return Err(invalid("oversized data frame"));
+                    }
+                    if buf.len() < 5 + len {
+                        return Ok(());
+                    }
+                    if self.resyncing || self.far_done {
+                        return Err(invalid("data out of place"));
+                    }
+                    buf.advance(5);
+                    self.to_near.extend_from_slice(&buf.split_to(len));
+                    self.received += len as u64;
+                }
+                b'A' if buf.len() >= 9 => {
+                    buf.advance(1);
+                    let n = buf.get_u64();
+                    self.ack(n)?;
+                }
+                b'F' => {
+                    if self.resyncing || self.far_done {
+                        return Err(invalid("end out of place"));
+                    }
+                    buf.advance(1);
+                    self.far_done = true;
+                    self.received += 1;
+                }
+                b'D' | b'A' => return Ok(()),
+                _ => return Err(invalid("unknown frame")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the stream over `link` until it is finished or `link` fails.
+    async fn run<R, W>(
+        &mut self,
+        near_read: &mut R,
+        near_write: &mut W,
+        link: Box<dyn Link>,
+    ) -> Result<(), Failure>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let (mut link_read, mut link_write) = tokio::io::split(link);
+        let (mut out, mut inbox) = (BytesMut::new(), BytesMut::new());
+        let mut buf = vec![0; MAX_DATA];
+        let mut keepalive = interval_at(Instant::now() + KEEPALIVE, KEEPALIVE);
+        let mut heard = Instant::now();
+        if self.resyncing {
+            self.tell(&mut out);
+        }
+        loop {
+            self.send(&mut out);
+            if self.far_done && self.to_near.is_empty() && !self.near_shut {
+                near_write.shutdown().await.map_err(Failure::Near)?;
+                self.near_shut = true;
+            }
+            if self.finished() && out.is_empty() {
+                linger(link_read, link_write).await;
+                return Ok(());
+            }
+            let reading_near = !self.near_done && self.replay.len() < REPLAY_BUFFER;
+            let reading_link = self.to_near.len() < REPLAY_BUFFER;
+            tokio::select! {
+                n = near_read.read(&mut buf), if reading_near => match n.map_err(Failure::Near)? {
+                    0 => self.near_done = true,
+                    n => {
+                        self.replay.extend_from_slice(&buf[..n]);
+                        self.read += n as u64;
+                    }
+                },
+                n = near_write.write(&self.to_near), if !self.to_near.is_empty() => {
+                    let n = n.map_err(Failure::Near)?;
+                    self.to_near.advance(n);
+                }
+                n = link_write.write(&out), if !out.is_empty() => match n.map_err(Failure::Link)? {
+                    0 => return Err(Failure::Link(io::ErrorKind::WriteZero.into())),
+                    n => out.advance(n),
+                },
+                n = link_read.read_buf(&mut inbox), if reading_link => {
+                    if n.map_err(Failure::Link)? == 0 {
+                        return Err(Failure::Link(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    heard = Instant::now();
+                    self.receive(&mut inbox)?;
+                    if self.received > self.told {
+                        self.tell(&mut out);
+                    }
+                }
+                _ = keepalive.tick() => {
+                    if heard.elapsed() > LINK_TIMEOUT {
+                        return Err(Failure::Link(io::ErrorKind::TimedOut.into()));
+                    }
+                    self.tell(&mut out);
+                }
+            }
+        }
+    }
+}
+
+/// Close a finished stream's connection once the peer has read the last
+/// `A`, so it is not reset under it.
+async fn linger(mut read: ReadHalf<Box<dyn Link>>, mut write: WriteHalf<Box<dyn Link>>) {
+    let _ = write.shutdown().await;
+    let mut sink = [0; 64];
+    let drain = async { while matches!(read.read(&mut sink).await, Ok(n) if n > 0) {} };
+    let _ = timeout(LINGER, drain).await;
+}
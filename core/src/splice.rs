@@ -0,0 +1,142 @@
+//! Zero-copy relaying between two TCP sockets with `splice(2)`, on Linux
+//! with the `splice` feature.
+//!
+//! Bytes move from one socket into a pipe and on to the other without ever
+//! being copied to userspace, which matters once a tunnel carries gigabits.
+//! Callers fall back to [`tokio::io::copy_bidirectional`] when [`ZERO_COPY`]
+//! is off, or when anything between the sockets (TLS, framing, a bandwidth
+//! limit) has to see the bytes.
+
+use std::io;
+
+use tokio::net::TcpStream;
+
+/// Whether [`relay`] is available in this build.
+pub const ZERO_COPY: bool = cfg!(all(target_os = "linux", feature = "splice"));
+
+/// Relay between `a` and `b` until both directions are done, like
+/// [`tokio::io::copy_bidirectional`]: each side's write half is shut down
+/// once the other side is read to the end. `a_to_b` and `b_to_a` are told
+/// the bytes moved each way as they go. Returns the totals.
+///
+/// Fails with [`io::ErrorKind::Unsupported`] unless [`ZERO_COPY`].
+pub async fn relay(
+    a: &TcpStream,
+    b: &TcpStream,
+    a_to_b: impl Fn(usize),
+    b_to_a: impl Fn(usize),
+) -> io::Result<(u64, u64)> {
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    {
+        let (up, down) = (linux::Pipe::new()?, linux::Pipe::new()?);
+        tokio::try_join!(
+            linux::pump(a, b, &up, a_to_b),
+            linux::pump(b, a, &down, b_to_a)
+        )
+    }
+    #[cfg(not(all(target_os = "linux", feature = "splice")))]
+    {
+        let _ = (a, b, a_to_b, b_to_a);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "built without zero-copy relaying",
+        ))
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "splice"))]
+mod linux {
+    use std::{
+        io,
+        net::Shutdown,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    };
+
+    use socket2::SockRef;
+    use tokio::{io::Interest, net::TcpStream};
+
+    /// Pipe size asked for; the kernel may grant less.
+    const PIPE_SIZE: libc::c_int = 1024 * 1024;
+
+    /// The kernel buffer bytes pass through on their way between sockets.
+    pub(super) struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+        /// Bytes it holds.
+        size: usize,
+    }
+
+    impl Pipe {
+        pub(super) fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            // SAFETY: `fds` has room for the two descriptors pipe2 fills in.
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: pipe2 succeeded, so both are open and owned by nobody
+            // else.
+            let (read, write) =
+                unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            // SAFETY: F_SETPIPE_SZ takes an int and touches no memory.
+            let size = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, PIPE_SIZE) };
+            let size = match size {
+                // Over /proc/sys/fs/pipe-max-size: keep the default.
+                ..=0 => 64 * 1024,
+                size => size as usize,
+            };
+            Ok(Self { read, write, size })
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        // SAFETY: null offsets splice at the descriptors' own positions.
+        let n = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                flags,
+            )
+        };
+        match n {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
+        }
+    }
+
+    /// Move everything `from` sends to `to` through `pipe`, then shut down
+    /// `to`'s write half.
+    pub(super) async fn pump(
+        from: &TcpStream,
+        to: &TcpStream,
+        pipe: &Pipe,
+        moved: impl Fn(usize),
+    ) -> io::Result<u64> {
+        let mut total = 0;
+        loop {
+            // The pipe is empty here, so only the socket can hold things up.
+            let n = from
+                .async_io(Interest::READABLE, || {
+                    splice(from.as_raw_fd(), pipe.write.as_raw_fd(), pipe.size)
+                })
+                .await?;
+            if n == 0 {
+                SockRef::from(to).shutdown(Shutdown::Write)?;
+                return Ok(total);
+            }
+            let mut left = n;
+            while left > 0 {
+                left -= to
+                    .async_io(Interest::WRITABLE, || {
+                        splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)
+                    })
+                    .await?;
+            }
+            total += n as u64;
+            moved(n);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! WebSocket transport for networks that only let HTTP(S) out.
+//!
+//! The client upgrades a request for [`CONTROL_PATH`] on the server's HTTP or
+//! HTTPS port, and from then on both sides speak the usual protocol inside
+//! binary messages. Message boundaries mean nothing: a [`WsStream`] is a byte
+//! stream like a TCP connection, so framing, mux and data connections work
+//! unchanged on top of it.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use futures_util::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+/// Request path the server upgrades to a control connection.
+pub const CONTROL_PATH: &str = "/_sshx/control";
+
+/// `Sec-WebSocket-Accept` value answering a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    derive_accept_key(key.trim().as_bytes())
+}
+
+/// A WebSocket connection read and written as a byte stream.
+pub struct WsStream<S> {
+    ws: WebSocketStream<S>,
+    /// Rest of the last message, not read yet.
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+    /// Upgrade `stream` with a request for [`CONTROL_PATH`]. `host` is what
+    /// goes in the `Host` header, with the port unless it is the default.
+    pub async fn connect(stream: S, host: &str, tls: bool) -> Result<Self> {
+        let scheme = if tls { "wss" } else { "ws" };
+        let url = format!("{scheme}://{host}{CONTROL_PATH}");
+        let (ws, _) = tokio_tungstenite::client_async(url, stream)
+            .await
+            .with_context(|| format!("WebSocket upgrade at {host} failed"))?;
+        Ok(Self::new(ws))
+    }
+
+    /// Wrap a server-side `stream` whose upgrade was already answered.
+    pub async fn accepted(stream: S) -> Self {
+        Self::new(WebSocketStream::from_raw_socket(stream, Role::Server, None).await)
+    }
+
+    fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            read_buf: Vec::new(),
+            read_pos: 0,
+        }
+    }
+}
+
+fn to_io(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.read_pos == this.read_buf.len() {
+            // Pings are answered by the WebSocket layer itself.
+            match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.read_buf = data,
+                Some(Ok(Message::Text(text))) => this.read_buf = text.into_bytes(),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(to_io(e))),
+            }
+            this.read_pos = 0;
+        }
+        let n = (this.read_buf.len() - this.read_pos).min(out.remaining());
+        out.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut ws = Pin::new(&mut self.ws);
+        ready!(ws.as_mut().poll_ready(cx)).map_err(to_io)?;
+        ws.start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(to_io)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws).poll_close(cx).map_err(to_io)
+    }
+}
@@ -0,0 +1,1228 @@
+//! Wire compatibility: clients and servers from different releases must keep
+//! understanding each other. The JSON below is what goes over the wire; if a
+//! change breaks one of these, older peers break with it.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{from_str, json, to_value};
+use sshx_core::{
+    auth::Auth,
+    protocol::{
+        negotiate, Acl, ClientMsg, ClientSettings, ErrorCode, FrameCodec, FrameError, Framed_,
+        Proto, ServerMsg, CONTROL_PORT, MAX_FRAME, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        TLS_CONTROL_PORT,
+    },
+};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Decoder, Encoder},
+};
+use uuid::Uuid;
+
+#[test]
+fn well_known_ports() {
+    assert_eq!(CONTROL_PORT, 12267);
+    assert_eq!(TLS_CONTROL_PORT, 12268);
+}
+
+#[test]
+fn client_messages_encode_as_before() {
+    let id = Uuid::nil();
+    let hello = ClientMsg::Hello {
+        subdomain: Some("myapp".into()),
+        proto: Proto::Http,
+        grpc: false,
+        pool: false,
+        takeover: None,
+        mux: true,
+        warm: false,
+        ttl_secs: None,
+        max_uses: None,
+        desired_port: None,
+        acl: Acl::default(),
+        error_codes: false,
+        version: 1,
+        resume: None,
+        seal: None,
+        heartbeat_interval_ms: None,
+    };
+    assert_eq!(
+        to_value(hello).unwrap(),
+        json!({"Hello": {"subdomain": "myapp", "proto": "Http", "mux": true}})
+    );
+    assert_eq!(
+        to_value(ClientMsg::Authenticate("ab".into())).unwrap(),
+        json!({"Authenticate": "ab"})
+    );
+    assert_eq!(
+        to_value(ClientMsg::Accept(id)).unwrap(),
+        json!({"Accept": "00000000-0000-0000-0000-000000000000"})
+    );
+}
+
+#[test]
+fn server_messages_encode_as_before() {
+    let id = Uuid::nil();
+    assert_eq!(to_value(ServerMsg::Heartbeat).unwrap(), json!("Heartbeat"));
+    assert_eq!(
+        to_value(ServerMsg::Challenge(id)).unwrap(),
+        json!({"Challenge": "00000000-0000-0000-0000-000000000000"})
+    );
+    assert_eq!(
+        to_value(ServerMsg::Connection {
+            id,
+            peer_addr: "203.0.113.7:5000".parse().unwrap(),
+            subdomain: None,
+            public_port: None,
+            country: None,
+            asn: None,
+        })
+        .unwrap(),
+        json!({"Connection": {
+            "id": "00000000-0000-0000-0000-000000000000",
+            "peer_addr": "203.0.113.7:5000",
+        }})
+    );
+    assert_eq!(
+        to_value(ServerMsg::Error("nope".into())).unwrap(),
+        json!({"Error": "nope"})
+    );
+    assert_eq!(to_value(ServerMsg::Shutdown).unwrap(), json!("Shutdown"));
+    assert_eq!(
+        to_value(ServerMsg::Expired {
+            subdomain: "myapp".into(),
+            message: "tunnel 'myapp' expired after 30m without visitors".into(),
+        })
+        .unwrap(),
+        json!({"Expired": {
+            "subdomain": "myapp",
+            "message": "tunnel 'myapp' expired after 30m without visitors",
+        }})
+    );
+}
+
+#[test]
+fn extra_tunnel_messages() {
+    assert_eq!(
+        to_value(ClientMsg::Register {
+            subdomain: "db".into(),
+            proto: Proto::Tcp,
+            grpc: false,
+            pool: false,
+            desired_port: None,
+        })
+        .unwrap(),
+        json!({"Register": {"subdomain": "db", "proto": "Tcp"}})
+    );
+    assert_eq!(
+        to_value(ServerMsg::Registered {
+            subdomain: "db".into(),
+            public_port: 4522,
+            url: None,
+            addrs: Vec::new(),
+        })
+        .unwrap(),
+        json!({"Registered": {"subdomain": "db", "public_port": 4522, "url": null}})
+    );
+    assert_eq!(
+        to_value(ServerMsg::Connection {
+            id: Uuid::nil(),
+            peer_addr: "203.0.113.7:5000".parse().unwrap(),
+            subdomain: Some("db".into()),
+            public_port: None,
+            country: None,
+            asn: None,
+        })
+        .unwrap(),
+        json!({"Connection": {
+            "id": "00000000-0000-0000-0000-000000000000",
+            "peer_addr": "203.0.113.7:5000",
+            "subdomain": "db",
+        }})
+    );
+}
+
+#[test]
+fn connections_name_the_public_port() {
+    let msg = ServerMsg::Connection {
+        id: Uuid::nil(),
+        peer_addr: "203.0.113.7:5000".parse().unwrap(),
+        subdomain: Some("db".into()),
+        public_port: Some(4522),
+        country: None,
+        asn: None,
+    };
+    let value = to_value(&msg).unwrap();
+    assert_eq!(value["Connection"]["public_port"], json!(4522));
+    let back: ServerMsg = serde_json::from_value(value).unwrap();
+    assert!(matches!(
+        back,
+        ServerMsg::Connection {
+            public_port: Some(4522),
+            ..
+        }
+    ));
+
+    // Servers before version 7 leave it out.
+    let old = json!({"Connection": {
+        "id": "00000000-0000-0000-0000-000000000000",
+        "peer_addr": "203.0.113.7:5000",
+    }});
+    let old: ServerMsg = serde_json::from_value(old).unwrap();
+    let ServerMsg::Connection { public_port, .. } = old else {
+        panic!("not a connection");
+    };
+    assert_eq!(public_port, None);
+}
+
+#[test]
+fn connections_say_where_the_visitor_is_when_known() {
+    let msg = ServerMsg::Connection {
+        id: Uuid::nil(),
+        peer_addr: "203.0.113.7:5000".parse().unwrap(),
+        subdomain: Some("db".into()),
+        public_port: Some(4522),
+        country: Some("NL".into()),
+        asn: Some(64496),
+    };
+    let value = to_value(&msg).unwrap();
+    assert_eq!(value["Connection"]["country"], json!("NL"));
+    assert_eq!(value["Connection"]["asn"], json!(64496));
+
+    // Servers without a GeoIP database, and before version 12, leave them
+    // out.
+    let old = json!({"Connection": {
+        "id": "00000000-0000-0000-0000-000000000000",
+        "peer_addr": "203.0.113.7:5000",
+        "public_port": 4522,
+    }});
+    let old: ServerMsg = serde_json::from_value(old).unwrap();
+    let ServerMsg::Connection { country, asn, .. } = old else {
+        panic!("not a connection");
+    };
+    assert_eq!((country, asn), (None, None));
+}
+
+#[test]
+fn grpc_tunnels_are_http_to_older_servers() {
+    let hello = ClientMsg::Hello {
+        subdomain: Some("api".into()),
+        proto: Proto::Http,
+        grpc: true,
+        pool: false,
+        takeover: None,
+        mux: true,
+        warm: false,
+        ttl_secs: None,
+        max_uses: None,
+        desired_port: None,
+        acl: Acl::default(),
+        error_codes: false,
+        version: PROTOCOL_VERSION,
+        resume: None,
+        heartbeat_interval_ms: None,
+        seal: None,
+    };
+    let json = serde_json::to_string(&hello).unwrap();
+    assert!(json.contains(r#""grpc":true"#), "{json}");
+    let v1::ClientMsg::Hello { subdomain, .. } = serde_json::from_str(&json).unwrap();
+    assert_eq!(subdomain, "api");
+
+    // Left out unless set.
+    let msg: ClientMsg = from_str(r#"{"Register":{"subdomain":"web","proto":"Http"}}"#).unwrap();
+    assert!(matches!(msg, ClientMsg::Register { grpc: false, .. }));
+    let json = serde_json::to_string(&msg).unwrap();
+    assert!(!json.contains("grpc"), "{json}");
+}
+
+#[test]
+fn pool_is_left_out_unless_asked_for() {
+    let msg = ClientMsg::Register {
+        subdomain: "web".into(),
+        proto: Proto::Http,
+        grpc: false,
+        pool: true,
+        desired_port: None,
+    };
+    assert_eq!(
+        to_value(&msg).unwrap(),
+        json!({"Register": {"subdomain": "web", "proto": "Http", "pool": true}})
+    );
+    let msg: ClientMsg = from_str(r#"{"Register":{"subdomain":"web","proto":"Http"}}"#).unwrap();
+    assert!(matches!(msg, ClientMsg::Register { pool: false, .. }));
+}
+
+#[test]
+fn takeover_key_is_left_out_unless_set() {
+    let msg: ClientMsg = from_str(r#"{"Hello":{"subdomain":"web","proto":"Http"}}"#).unwrap();
+    assert!(matches!(msg, ClientMsg::Hello { takeover: None, .. }));
+    let json = serde_json::to_string(&msg).unwrap();
+    assert!(!json.contains("takeover"), "{json}");
+
+    let msg = ServerMsg::HandedOver {
+        subdomain: "web".into(),
+    };
+    assert_eq!(
+        to_value(&msg).unwrap(),
+        json!({"HandedOver": {"subdomain": "web"}})
+    );
+}
+
+#[test]
+fn desired_port_is_optional() {
+    let hello = ClientMsg::Hello {
+        subdomain: Some("ssh".into()),
+        proto: Proto::Tcp,
+        grpc: false,
+        pool: false,
+        takeover: None,
+        mux: true,
+        warm: false,
+        ttl_secs: None,
+        max_uses: None,
+        desired_port: Some(2222),
+        acl: Acl::default(),
+        error_codes: false,
+        version: 1,
+        resume: None,
+        seal: None,
+        heartbeat_interval_ms: None,
+    };
+    assert_eq!(
+        to_value(hello).unwrap(),
+        json!({"Hello": {"subdomain": "ssh", "proto": "Tcp", "mux": true, "desired_port": 2222}})
+    );
+    let msg: ClientMsg = from_str(r#"{"Register":{"subdomain":"db","proto":"Tcp"}}"#).unwrap();
+    assert!(matches!(
+        msg,
+        ClientMsg::Register {
+            desired_port: None,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn acl_encoding() {
+    let acl = Acl {
+        allow: vec!["10.0.0.0/8".parse().unwrap()],
+        deny: vec![],
+    };
+    assert_eq!(to_value(&acl).unwrap(), json!({"allow": ["10.0.0.0/8"]}));
+    let acl: Acl = from_str(r#"{"deny":["0.0.0.0/0","::/0"]}"#).unwrap();
+    assert_eq!(acl.deny.len(), 2);
+    assert!(acl.allow.is_empty());
+}
+
+/// Client and server both enforce the ACL, so they must agree on it.
+#[test]
+fn acl_allow_wins_over_deny() {
+    let acl = Acl {
+        allow: vec!["10.0.0.0/8".parse().unwrap()],
+        deny: vec!["0.0.0.0/0".parse().unwrap()],
+    };
+    assert!(acl.permits("10.1.2.3".parse().unwrap()));
+    assert!(acl.permits("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!acl.permits("203.0.113.7".parse().unwrap()));
+
+    let only_allow = Acl {
+        allow: acl.allow.clone(),
+        deny: vec![],
+    };
+    assert!(!only_allow.permits("2001:db8::1".parse().unwrap()));
+
+    let only_deny = Acl {
+        allow: vec![],
+        deny: vec!["203.0.113.0/24".parse().unwrap()],
+    };
+    assert!(!only_deny.permits("203.0.113.7".parse().unwrap()));
+    assert!(only_deny.permits("198.51.100.1".parse().unwrap()));
+    assert!(Acl::default().permits("198.51.100.1".parse().unwrap()));
+}
+
+#[test]
+fn refusals_carry_a_code() {
+    let refused = ServerMsg::Refused {
+        code: ErrorCode::QuotaExceeded,
+        message: "too many".into(),
+    };
+    assert_eq!(
+        to_value(refused).unwrap(),
+        json!({"Refused": {"code": "quota_exceeded", "message": "too many"}})
+    );
+    // Codes added later must not break this client.
+    let msg: ServerMsg = from_str(r#"{"Refused":{"code":"brand_new","message":"?"}}"#).unwrap();
+    assert!(matches!(
+        msg,
+        ServerMsg::Refused {
+            code: ErrorCode::Unknown,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn error_codes_are_stable() {
+    for (code, name) in [
+        (ErrorCode::AuthRequired, "auth_required"),
+        (ErrorCode::AuthFailed, "auth_failed"),
+        (ErrorCode::SubdomainTaken, "subdomain_taken"),
+        (ErrorCode::SubdomainBanned, "subdomain_banned"),
+        (ErrorCode::PortTaken, "port_taken"),
+        (ErrorCode::PortOutOfRange, "port_out_of_range"),
+        (ErrorCode::NoPorts, "no_ports"),
+        (ErrorCode::ProtocolMismatch, "protocol_mismatch"),
+        (ErrorCode::SubdomainNotPermitted, "subdomain_not_permitted"),
+        (ErrorCode::PortNotPermitted, "port_not_permitted"),
+        (ErrorCode::ProtocolNotPermitted, "protocol_not_permitted"),
+        (ErrorCode::QuotaExceeded, "quota_exceeded"),
+        (ErrorCode::PullNotPermitted, "pull_not_permitted"),
+        (ErrorCode::NoSuchTunnel, "no_such_tunnel"),
+        (ErrorCode::NoSuchStream, "no_such_stream"),
+    ] {
+        assert_eq!(to_value(code).unwrap(), json!(name));
+    }
+}
+
+#[test]
+fn udp_proto_encoding() {
+    assert_eq!(to_value(Proto::Udp).unwrap(), json!("Udp"));
+}
+
+#[test]
+fn hello_from_older_client_decodes() {
+    let msg: ClientMsg = from_str(r#"{"Hello":{"subdomain":"old","proto":"Tcp"}}"#).unwrap();
+    let ClientMsg::Hello {
+        subdomain,
+        proto,
+        grpc,
+        mux,
+        desired_port,
+        acl,
+        error_codes,
+        version,
+        resume,
+        seal,
+        heartbeat_interval_ms,
+        pool,
+        takeover,
+        warm,
+        ttl_secs,
+        max_uses,
+    } = msg
+    else {
+        panic!("expected Hello, got {msg:?}");
+    };
+    assert_eq!(subdomain.as_deref(), Some("old"));
+    assert!(matches!(proto, Proto::Tcp));
+    assert!(!grpc);
+    assert!(!mux);
+    assert_eq!(desired_port, None);
+    assert!(acl.is_empty());
+    assert!(!error_codes);
+    assert_eq!(version, 1);
+    assert_eq!(resume, None);
+    assert_eq!(seal, None);
+    assert_eq!(heartbeat_interval_ms, None);
+    assert!(!pool);
+    assert_eq!(takeover, None);
+    assert!(!warm);
+    assert_eq!(ttl_secs, None);
+    assert_eq!(max_uses, None);
+}
+
+#[test]
+fn hello_from_older_server_decodes() {
+    let msg: ServerMsg = from_str(r#"{"Hello":{"public_port":4521}}"#).unwrap();
+    let ServerMsg::Hello {
+        public_port,
+        url,
+        mux,
+        version,
+        session,
+        subdomain,
+        addrs,
+        warm,
+    } = msg
+    else {
+        panic!("expected Hello, got {msg:?}");
+    };
+    assert_eq!(public_port, 4521);
+    assert_eq!(url, None);
+    assert!(!mux);
+    assert_eq!(version, 1);
+    assert_eq!(session, None);
+    assert_eq!(subdomain, None);
+    assert!(addrs.is_empty());
+    assert_eq!(warm, None);
+}
+
+#[test]
+fn server_names_the_subdomain_it_picked() {
+    let hello = ClientMsg::Hello {
+        subdomain: None,
+        proto: Proto::Http,
+        grpc: false,
+        pool: false,
+        takeover: None,
+        mux: true,
+        warm: false,
+        ttl_secs: None,
+        max_uses: None,
+        desired_port: None,
+        acl: Acl::default(),
+        error_codes: true,
+        version: 1,
+        resume: None,
+        seal: None,
+        heartbeat_interval_ms: None,
+    };
+    assert_eq!(
+        to_value(hello).unwrap(),
+        json!({"Hello": {"proto": "Http", "mux": true, "error_codes": true}})
+    );
+    assert_eq!(
+        to_value(ServerMsg::Hello {
+            public_port: 4521,
+            url: None,
+            mux: true,
+            version: 1,
+            session: None,
+            subdomain: Some("brave-otter-42".into()),
+            addrs: Vec::new(),
+            warm: None,
+        })
+        .unwrap(),
+        json!({"Hello": {
+            "public_port": 4521,
+            "url": null,
+            "mux": true,
+            "subdomain": "brave-otter-42",
+        }})
+    );
+}
+
+#[test]
+fn dual_stack_servers_list_every_address_of_the_port() {
+    let registered = ServerMsg::Registered {
+        subdomain: "db".into(),
+        public_port: 4522,
+        url: None,
+        addrs: vec![
+            "0.0.0.0:4522".parse().unwrap(),
+            "[::]:4522".parse().unwrap(),
+        ],
+    };
+    assert_eq!(
+        to_value(registered).unwrap(),
+        json!({"Registered": {
+            "subdomain": "db",
+            "public_port": 4522,
+            "url": null,
+            "addrs": ["0.0.0.0:4522", "[::]:4522"],
+        }})
+    );
+}
+
+#[test]
+fn sessions_resume_with_the_token_from_hello() {
+    let token = Uuid::nil();
+    assert_eq!(
+        to_value(ServerMsg::Hello {
+            public_port: 4521,
+            url: None,
+            mux: true,
+            version: 1,
+            session: Some(token),
+            subdomain: None,
+            addrs: Vec::new(),
+            warm: None,
+        })
+        .unwrap(),
+        json!({"Hello": {
+            "public_port": 4521,
+            "url": null,
+            "mux": true,
+            "session": "00000000-0000-0000-0000-000000000000",
+        }})
+    );
+    let hello = ClientMsg::Hello {
+        subdomain: Some("myapp".into()),
+        proto: Proto::Http,
+        grpc: false,
+        pool: false,
+        takeover: None,
+        mux: true,
+        warm: false,
+        ttl_secs: None,
+        max_uses: None,
+        desired_port: None,
+        acl: Acl::default(),
+        error_codes: false,
+        version: 1,
+        resume: Some(token),
+        seal: None,
+        heartbeat_interval_ms: None,
+    };
+    assert_eq!(
+        to_value(hello).unwrap(),
+        json!({"Hello": {
+            "subdomain": "myapp",
+            "proto": "Http",
+            "mux": true,
+            "resume": "00000000-0000-0000-0000-000000000000",
+        }})
+    );
+}
+
+/// Message shapes of peers from before versioning, reduced to what they
+/// insist on.
+mod v1 {
+    use serde::Deserialize;
+    use sshx_core::protocol::Proto;
+
+    #[derive(Debug, Deserialize)]
+    pub enum ClientMsg {
+        Hello {
+            subdomain: String,
+            #[allow(dead_code)]
+            proto: Proto,
+            #[serde(default)]
+            mux: bool,
+        },
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub enum ServerMsg {
+        Hello {
+            public_port: u16,
+            #[serde(default)]
+            mux: bool,
+        },
+    }
+}
+
+#[test]
+fn versions_negotiate_down_to_the_older_peer() {
+    assert_eq!(negotiate(1), Some(1));
+    assert_eq!(negotiate(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+    assert_eq!(negotiate(PROTOCOL_VERSION + 1), Some(PROTOCOL_VERSION));
+    assert_eq!(negotiate(MIN_PROTOCOL_VERSION - 1), None);
+}
+
+#[test]
+fn current_client_pairs_with_v1_server() {
+    // The v1 server reads our Hello, ignoring what it doesn't know...
+    let hello = ClientMsg::Hello {
+        subdomain: Some("myapp".into()),
+        proto: Proto::Http,
+        grpc: false,
+        pool: false,
+        takeover: None,
+        mux: true,
+        warm: false,
+        ttl_secs: None,
+        max_uses: None,
+        desired_port: None,
+        acl: Acl::default(),
+        error_codes: true,
+        version: PROTOCOL_VERSION,
+        resume: None,
+        seal: None,
+        heartbeat_interval_ms: None,
+    };
+    let json = serde_json::to_string(&hello).unwrap();
+    let v1::ClientMsg::Hello { subdomain, mux, .. } = serde_json::from_str(&json).unwrap();
+    assert_eq!((subdomain.as_str(), mux), ("myapp", true));
+
+    // ...and its answer leaves the version out, which we read as 1.
+    let reply: ServerMsg = from_str(r#"{"Hello":{"public_port":4521,"mux":true}}"#).unwrap();
+    let ServerMsg::Hello { version, .. } = reply else {
+        panic!("expected Hello, got {reply:?}");
+    };
+    assert_eq!(negotiate(version), Some(1));
+}
+
+#[test]
+fn current_server_pairs_with_v1_client() {
+    let hello: ClientMsg =
+        from_str(r#"{"Hello":{"subdomain":"old","proto":"Tcp","mux":true}}"#).unwrap();
+    let ClientMsg::Hello { version, .. } = hello else {
+        panic!("expected Hello, got {hello:?}");
+    };
+    let version = negotiate(version).unwrap();
+    assert_eq!(version, 1);
+
+    // At version 1 the answer is exactly what a v1 client expects.
+    let reply = ServerMsg::Hello {
+        public_port: 4521,
+        url: None,
+        mux: true,
+        version,
+        session: None,
+        subdomain: None,
+        addrs: Vec::new(),
+        warm: None,
+    };
+    assert_eq!(
+        to_value(&reply).unwrap(),
+        json!({"Hello": {"public_port": 4521, "url": null, "mux": true}})
+    );
+    let json = serde_json::to_string(&reply).unwrap();
+    let v1::ServerMsg::Hello { public_port, mux } = serde_json::from_str(&json).unwrap();
+    assert_eq!((public_port, mux), (4521, true));
+}
+
+#[test]
+fn current_peers_agree_on_the_current_version() {
+    let hello = to_value(ClientMsg::Hello {
+        subdomain: Some("new".into()),
+        proto: Proto::Tcp,
+        grpc: false,
+        pool: false,
+        takeover: None,
+        mux: true,
+        warm: false,
+        ttl_secs: None,
+        max_uses: None,
+        desired_port: None,
+        acl: Acl::default(),
+        error_codes: true,
+        version: PROTOCOL_VERSION,
+        resume: None,
+        seal: None,
+        heartbeat_interval_ms: None,
+    })
+    .unwrap();
+    assert_eq!(hello["Hello"]["version"], json!(PROTOCOL_VERSION));
+    let msg: ClientMsg = serde_json::from_value(hello).unwrap();
+    let ClientMsg::Hello { version, .. } = msg else {
+        panic!("expected Hello, got {msg:?}");
+    };
+    assert_eq!(negotiate(version), Some(PROTOCOL_VERSION));
+}
+
+#[test]
+fn pings_carry_a_nonce_and_the_last_round_trip() {
+    assert_eq!(
+        to_value(ServerMsg::Ping {
+            nonce: 7,
+            rtt_ms: None
+        })
+        .unwrap(),
+        json!({"Ping": {"nonce": 7}})
+    );
+    let ping: ServerMsg = from_str(r#"{"Ping":{"nonce":8,"rtt_ms":42}}"#).unwrap();
+    let ServerMsg::Ping { nonce, rtt_ms } = ping else {
+        panic!("expected Ping, got {ping:?}");
+    };
+    assert_eq!((nonce, rtt_ms), (8, Some(42)));
+    assert_eq!(to_value(ClientMsg::Pong(8)).unwrap(), json!({"Pong": 8}));
+}
+
+#[test]
+fn parked_connections_are_acknowledged_and_cancelled() {
+    let id = Uuid::from_u128(1);
+    assert_eq!(
+        to_value(ClientMsg::ConnectionAck {
+            id,
+            accepted: false
+        })
+        .unwrap(),
+        json!({"ConnectionAck": {"id": id, "accepted": false}})
+    );
+    let cancelled: ServerMsg =
+        from_str(&format!(r#"{{"ConnectionCancelled":{{"id":"{id}"}}}}"#)).unwrap();
+    assert!(matches!(cancelled, ServerMsg::ConnectionCancelled { id: c } if c == id));
+}
+
+#[test]
+fn stats_report_tunnel_traffic() {
+    assert_eq!(
+        to_value(ServerMsg::Stats {
+            subdomain: "myapp".into(),
+            conns: 3,
+            bytes_in: 1024,
+            bytes_out: 4096,
+        })
+        .unwrap(),
+        json!({"Stats": {"subdomain": "myapp", "conns": 3, "bytes_in": 1024, "bytes_out": 4096}})
+    );
+}
+
+#[test]
+fn pull_messages() {
+    assert_eq!(
+        to_value(ClientMsg::Pull {
+            target: "db.internal:5432".into()
+        })
+        .unwrap(),
+        json!({"Pull": {"target": "db.internal:5432"}})
+    );
+    assert_eq!(
+        to_value(ServerMsg::Pulled {
+            peer_addr: "10.0.0.5:5432".parse().unwrap()
+        })
+        .unwrap(),
+        json!({"Pulled": {"peer_addr": "10.0.0.5:5432"}})
+    );
+}
+
+#[test]
+fn lookup_messages() {
+    assert_eq!(
+        to_value(ClientMsg::Lookup {
+            subdomain: "myssh".into()
+        })
+        .unwrap(),
+        json!({"Lookup": {"subdomain": "myssh"}})
+    );
+    let found: ServerMsg = from_str(r#"{"Found":{"proto":"Tcp","public_port":2222}}"#).unwrap();
+    let ServerMsg::Found { proto, public_port } = found else {
+        panic!("expected Found, got {found:?}");
+    };
+    assert_eq!((proto, public_port), (Proto::Tcp, 2222));
+}
+
+#[test]
+fn resumable_stream_messages() {
+    let id = Uuid::from_u128(1);
+    assert_eq!(
+        to_value(ClientMsg::AcceptResumable(id)).unwrap(),
+        json!({ "AcceptResumable": id })
+    );
+    assert_eq!(
+        to_value(ClientMsg::ResumeStream(id)).unwrap(),
+        json!({ "ResumeStream": id })
+    );
+    let resumed: ServerMsg = from_str(r#""StreamResumed""#).unwrap();
+    assert!(matches!(resumed, ServerMsg::StreamResumed));
+}
+
+#[test]
+fn warm_pools_are_left_out_unless_asked_for() {
+    let key = Uuid::from_u128(7);
+    assert_eq!(
+        to_value(ClientMsg::Standby(key)).unwrap(),
+        json!({ "Standby": key })
+    );
+    let msg: ClientMsg = from_str(r#"{"Hello":{"subdomain":"web","proto":"Http"}}"#).unwrap();
+    assert!(matches!(msg, ClientMsg::Hello { warm: false, .. }));
+    let json = serde_json::to_string(&msg).unwrap();
+    assert!(!json.contains("warm"), "{json}");
+
+    let reply: ServerMsg = from_str(&format!(
+        r#"{{"Hello":{{"public_port":4521,"version":13,"warm":"{key}"}}}}"#
+    ))
+    .unwrap();
+    assert!(matches!(reply, ServerMsg::Hello { warm: Some(k), .. } if k == key));
+    let old: ServerMsg = from_str(r#"{"Hello":{"public_port":4521,"version":12}}"#).unwrap();
+    assert!(matches!(old, ServerMsg::Hello { warm: None, .. }));
+}
+
+#[test]
+fn tunnel_limits_are_left_out_unless_set() {
+    let msg: ClientMsg = from_str(r#"{"Hello":{"subdomain":"demo","proto":"Http"}}"#).unwrap();
+    assert!(matches!(
+        msg,
+        ClientMsg::Hello {
+            ttl_secs: None,
+            max_uses: None,
+            ..
+        }
+    ));
+    let json = serde_json::to_string(&msg).unwrap();
+    assert!(
+        !json.contains("ttl_secs") && !json.contains("max_uses"),
+        "{json}"
+    );
+
+    let msg: ClientMsg =
+        from_str(r#"{"Hello":{"subdomain":"demo","proto":"Http","ttl_secs":1800,"max_uses":1}}"#)
+            .unwrap();
+    assert!(matches!(
+        msg,
+        ClientMsg::Hello {
+            ttl_secs: Some(1800),
+            max_uses: Some(1),
+            ..
+        }
+    ));
+}
+
+#[test]
+fn single_tunnels_are_unregistered() {
+    assert_eq!(
+        to_value(ClientMsg::Unregister {
+            subdomain: "db".into()
+        })
+        .unwrap(),
+        json!({"Unregister": {"subdomain": "db"}})
+    );
+    let reply: ServerMsg = from_str(r#"{"Unregistered":{"subdomain":"db"}}"#).unwrap();
+    assert!(matches!(reply, ServerMsg::Unregistered { subdomain } if subdomain == "db"));
+}
+
+#[test]
+fn partial_reconfigure_decodes() {
+    let msg: ServerMsg = from_str(r#"{"Reconfigure":{"notice":"maintenance at 5"}}"#).unwrap();
+    let ServerMsg::Reconfigure(settings) = msg else {
+        panic!("expected Reconfigure, got {msg:?}");
+    };
+    assert_eq!(
+        settings,
+        ClientSettings {
+            notice: Some("maintenance at 5".into()),
+            ..Default::default()
+        }
+    );
+}
+
+#[tokio::test]
+async fn frames_are_null_delimited_json() {
+    let (a, mut b) = duplex(1024);
+    let mut framed = Framed_::new(a);
+    framed.send(ServerMsg::Heartbeat).await.unwrap();
+    let mut buf = [0; 12];
+    b.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"\"Heartbeat\"\0");
+
+    b.write_all(b"{\"Authenticate\":\"ab\"}\0").await.unwrap();
+    let msg = framed.recv::<ClientMsg>().await.unwrap();
+    assert!(matches!(msg, Some(ClientMsg::Authenticate(tag)) if tag == "ab"));
+
+    drop(b);
+    assert!(framed.recv::<ClientMsg>().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn datagrams_follow_the_connection_frame() {
+    let (a, mut b) = duplex(1024);
+    let mut framed = Framed_::new(a);
+    // One write: bytes the JSON codec reads ahead must not be lost.
+    b.write_all(b"\"Heartbeat\"\0\x00\x02hi\x00\x00")
+        .await
+        .unwrap();
+    assert!(matches!(
+        framed.recv::<ServerMsg>().await.unwrap(),
+        Some(ServerMsg::Heartbeat)
+    ));
+
+    let mut datagrams = framed.into_datagrams();
+    assert_eq!(&datagrams.next().await.unwrap().unwrap()[..], b"hi");
+    assert!(datagrams.next().await.unwrap().unwrap().is_empty());
+
+    datagrams.send(Bytes::from_static(b"pong")).await.unwrap();
+    let mut buf = [0; 6];
+    b.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"\x00\x04pong");
+}
+
+#[tokio::test]
+async fn oversized_frame_is_rejected() {
+    let (a, mut b) = duplex(2 * MAX_FRAME);
+    let mut framed = Framed_::new(a);
+    let junk = vec![b'x'; MAX_FRAME + 1];
+    b.write_all(&junk).await.unwrap();
+    let err = framed.recv::<ClientMsg>().await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(FrameError::TooLarge { limit: MAX_FRAME })
+    ));
+}
+
+#[tokio::test]
+async fn frames_up_to_the_limit_pass() {
+    // Older peers read at most 4096 bytes a frame.
+    const { assert!(MAX_FRAME >= 4096) };
+    let (a, b) = duplex(2 * MAX_FRAME);
+    let (mut sender, mut receiver) = (Framed_::new(a), Framed_::new(b));
+    // `{"Error":"…"}` fills the frame to the byte.
+    let message = "x".repeat(MAX_FRAME - r#"{"Error":""}"#.len());
+    sender
+        .send(ServerMsg::Error(message.clone()))
+        .await
+        .unwrap();
+    let msg = receiver.recv::<ServerMsg>().await.unwrap();
+    assert!(matches!(msg, Some(ServerMsg::Error(m)) if m == message));
+
+    let err = sender
+        .send(ServerMsg::Error(format!("{message}x")))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(FrameError::TooLarge { .. })
+    ));
+}
+
+#[tokio::test]
+async fn malformed_frames_are_told_apart() {
+    let (a, mut b) = duplex(1024);
+    let mut framed = Framed_::new(a);
+    for junk in [
+        &b"{\"Hello\":"[..],
+        b"\"NoSuchMessage\"",
+        b"{\"Accept\":42}",
+        b"\xff\xfe",
+    ] {
+        b.write_all(junk).await.unwrap();
+        b.write_all(b"\0").await.unwrap();
+        let err = framed.recv::<ClientMsg>().await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(FrameError::Malformed(_))),
+            "{err:#}"
+        );
+    }
+    // The connection stays usable after a malformed frame.
+    b.write_all(b"{\"Pong\":7}\0").await.unwrap();
+    let msg = framed.recv::<ClientMsg>().await.unwrap();
+    assert!(matches!(msg, Some(ClientMsg::Pong(7))));
+}
+
+/// Deterministic pseudo-random bytes for the decoder fuzz tests (xorshift).
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Random bytes, with extra delimiters and JSON punctuation mixed in.
+    fn byte(&mut self) -> u8 {
+        match self.below(8) {
+            0 => 0,
+            1 => {
+                let punctuation = b"{}[]\":,";
+                punctuation[self.below(punctuation.len())]
+            }
+            _ => self.next() as u8,
+        }
+    }
+}
+
+/// Feed `input` to a codec in chunks of random size, as reads would.
+fn decode_in_chunks(
+    input: &[u8],
+    limit: usize,
+    rng: &mut Rng,
+) -> Result<Vec<BytesMut>, FrameError> {
+    let mut codec = FrameCodec::new(limit);
+    let mut buf = BytesMut::new();
+    let mut frames = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let n = 1 + rng.below(rest.len().min(64));
+        buf.extend_from_slice(&rest[..n]);
+        rest = &rest[n..];
+        while let Some(frame) = codec.decode(&mut buf)? {
+            frames.push(frame);
+        }
+    }
+    while let Some(frame) = codec.decode_eof(&mut buf)? {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+#[test]
+fn decoder_fuzz_random_bytes() {
+    let mut rng = Rng(0x5eed_1234_abcd_0001);
+    for _ in 0..2000 {
+        let len = rng.below(600);
+        let input: Vec<u8> = (0..len).map(|_| rng.byte()).collect();
+        let limit = 1 + rng.below(256);
+        match decode_in_chunks(&input, limit, &mut rng) {
+            Ok(frames) => {
+                // Every byte but the delimiters comes out, in order.
+                let joined: Vec<u8> = frames.iter().flat_map(|f| f.iter().copied()).collect();
+                let expected: Vec<u8> = input.iter().copied().filter(|&b| b != 0).collect();
+                assert_eq!(joined, expected);
+                for frame in &frames {
+                    assert!(frame.len() <= limit && !frame.contains(&0));
+                    // Whatever the frame holds, parsing fails cleanly.
+                    let _ = serde_json::from_slice::<ClientMsg>(frame);
+                    let _ = serde_json::from_slice::<ServerMsg>(frame);
+                }
+            }
+            Err(FrameError::TooLarge { limit: l }) => {
+                assert_eq!(l, limit);
+                assert!(input.split(|&b| b == 0).any(|run| run.len() > limit));
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+}
+
+#[test]
+fn decoder_fuzz_mutated_messages() {
+    let messages = [
+        serde_json::to_string(&ClientMsg::Register {
+            subdomain: "web".into(),
+            proto: Proto::Http,
+            grpc: false,
+            pool: false,
+            desired_port: Some(8080),
+        })
+        .unwrap(),
+        serde_json::to_string(&ServerMsg::Stats {
+            subdomain: "web".into(),
+            conns: 3,
+            bytes_in: 1024,
+            bytes_out: 4096,
+        })
+        .unwrap(),
+        serde_json::to_string(&ClientMsg::Pong(u64::MAX)).unwrap(),
+    ];
+    let mut rng = Rng(0x0ddb_a11c_afe0_0002);
+    for _ in 0..2000 {
+        let mut frame = messages[rng.below(messages.len())].clone().into_bytes();
+        for _ in 0..1 + rng.below(4) {
+            let at = rng.below(frame.len());
+            match rng.below(3) {
+                0 => frame[at] = rng.byte(),
+                1 => frame.insert(at, rng.byte()),
+                _ => {
+                    frame.remove(at);
+                }
+            }
+        }
+        frame.push(0);
+        let frames = decode_in_chunks(&frame, MAX_FRAME, &mut rng).unwrap();
+        for frame in &frames {
+            let _ = serde_json::from_slice::<ClientMsg>(frame);
+            let _ = serde_json::from_slice::<ServerMsg>(frame);
+        }
+    }
+}
+
+#[test]
+fn encoder_appends_the_delimiter() {
+    let mut codec = FrameCodec::new(8);
+    let mut buf = BytesMut::new();
+    codec.encode("\"Ping\"".to_owned(), &mut buf).unwrap();
+    assert_eq!(&buf[..], b"\"Ping\"\0");
+    assert!(matches!(
+        codec.encode("123456789".to_owned(), &mut buf),
+        Err(FrameError::TooLarge { limit: 8 })
+    ));
+}
+
+#[test]
+fn auth_answer_is_stable() {
+    let auth = Auth::new("hunter2");
+    let challenge = Uuid::nil();
+    let answer = auth.answer(&challenge);
+    assert_eq!(answer.len(), 64);
+    assert!(auth.verify(&challenge, &answer));
+    assert!(!Auth::new("hunter3").verify(&challenge, &answer));
+    assert!(!auth.verify(&Uuid::from_u128(1), &answer));
+    assert!(!auth.verify(&challenge, "not hex"));
+}
+
+#[tokio::test]
+async fn auth_handshake_answers_challenge() {
+    let (a, b) = duplex(1024);
+    let (mut client, mut server) = (Framed_::new(a), Framed_::new(b));
+    let challenge = Uuid::new_v4();
+    server.send(ServerMsg::Challenge(challenge)).await.unwrap();
+
+    let answered = Auth::new("hunter2").handshake(&mut client).await.unwrap();
+    assert_eq!(answered, challenge);
+    let Some(ClientMsg::Authenticate(tag)) = server.recv().await.unwrap() else {
+        panic!("expected Authenticate");
+    };
+    assert!(Auth::new("hunter2").verify(&challenge, &tag));
+}
+
+#[test]
+fn hello_seal_binds_the_registration() {
+    let auth = Auth::new("hunter2");
+    let challenge = Uuid::new_v4();
+    let mut hello = ClientMsg::Hello {
+        subdomain: Some("myapp".into()),
+        proto: Proto::Http,
+        grpc: false,
+        pool: false,
+        takeover: None,
+        mux: true,
+        warm: false,
+        ttl_secs: None,
+        max_uses: None,
+        desired_port: None,
+        acl: Acl::default(),
+        error_codes: true,
+        version: PROTOCOL_VERSION,
+        resume: None,
+        seal: None,
+        heartbeat_interval_ms: None,
+    };
+    auth.seal(&challenge, &mut hello);
+    let ClientMsg::Hello {
+        seal: Some(seal), ..
+    } = &hello
+    else {
+        panic!("expected a sealed Hello, got {hello:?}");
+    };
+    assert_eq!(seal.len(), 64);
+    assert_ne!(*seal, auth.answer(&challenge));
+
+    // The seal survives the wire and covers what the Hello asks for...
+    let decoded: ClientMsg = from_str(&serde_json::to_string(&hello).unwrap()).unwrap();
+    let binding = decoded.binding().unwrap();
+    assert!(auth.verify_seal(&challenge, &binding, seal));
+    assert!(!auth.verify_seal(&Uuid::new_v4(), &binding, seal));
+    assert!(!Auth::new("hunter3").verify_seal(&challenge, &binding, seal));
+
+    // ...so changing any of it breaks it.
+    let breaks = |field: &str, value| {
+        let mut swapped = to_value(&decoded).unwrap();
+        swapped["Hello"][field] = value;
+        let swapped: ClientMsg = serde_json::from_value(swapped).unwrap();
+        !auth.verify_seal(&challenge, &swapped.binding().unwrap(), seal)
+    };
+    assert!(breaks("subdomain", json!("victim")));
+    assert!(breaks("proto", json!("Tcp")));
+    assert!(breaks("heartbeat_interval_ms", json!(600_000)));
+    assert!(breaks("grpc", json!(true)));
+    assert!(breaks("pool", json!(true)));
+    assert!(breaks("takeover", json!("attacker")));
+    assert!(breaks("warm", json!(true)));
+    assert!(breaks("ttl_secs", json!(86_400)));
+    assert!(breaks("max_uses", json!(1_000)));
+
+    // Dropping a limit the client asked for breaks it as well.
+    let mut limited = to_value(&decoded).unwrap();
+    limited["Hello"]["max_uses"] = json!(1);
+    let mut limited: ClientMsg = serde_json::from_value(limited).unwrap();
+    auth.seal(&challenge, &mut limited);
+    let mut stripped = to_value(&limited).unwrap();
+    let limited_seal = stripped["Hello"]["seal"].as_str().unwrap().to_owned();
+    stripped["Hello"]
+        .as_object_mut()
+        .unwrap()
+        .remove("max_uses");
+    let stripped: ClientMsg = serde_json::from_value(stripped).unwrap();
+    assert!(auth.verify_seal(&challenge, &limited.binding().unwrap(), &limited_seal));
+    assert!(!auth.verify_seal(&challenge, &stripped.binding().unwrap(), &limited_seal));
+
+    // A Hello without the newer fields binds as it did before them, or the
+    // seals of older clients would stop verifying.
+    let before = (
+        Some("myapp"),
+        Proto::Http,
+        None::<u16>,
+        Acl::default(),
+        None::<Uuid>,
+    );
+    assert_eq!(binding, serde_json::to_vec(&before).unwrap());
+
+    assert_eq!(ClientMsg::Accept(challenge).binding(), None);
+}
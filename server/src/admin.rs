@@ -0,0 +1,229 @@
+//! Admin HTTP API for operators (`--admin-bind`).
+//!
+//! ```text
+//! GET    /tunnels              every active tunnel
+//! GET    /tunnels/<subdomain>  one tunnel
+//! DELETE /tunnels/<subdomain>  close a tunnel
+//! POST   /reload               reload the settings, like SIGHUP
+//! GET    /auth                 auth attempts, failures, throttling, bans
+//! GET    /tarpit               scanners reset, dripped and dropped
+//! GET    /geoip                visitors and those turned away, by country
+//! ```
+//!
+//! Responses are JSON. There is no authentication: bind it to loopback or a
+//! private network. Closing tunnels and reloading go to the audit log.
+//!
+//! Requests must name the API in `Host` by `localhost`, a loopback address
+//! or the address they came in on, and carry no `Origin`, so that a web page
+//! can't reach it through a DNS name rebound to it or a cross-site request.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use sshx_core::protocol::Proto;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    budget::Backoff,
+    http::{header, read_head},
+    server::{State, Tunnel},
+    traffic::TrafficSnapshot,
+    webhooks::Notification,
+};
+
+/// How long a caller may take to send the request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A tunnel as listed by the API.
+#[derive(Serialize)]
+struct TunnelInfo {
+    subdomain: String,
+    proto: Proto,
+    public_port: u16,
+    url: Option<String>,
+    client_addr: SocketAddr,
+    identity: String,
+    uptime_secs: u64,
+    #[serde(flatten)]
+    traffic: TrafficSnapshot,
+    /// Bytes/sec per direction; `null` when unlimited.
+    max_bandwidth: Option<u64>,
+    /// Round trip to the client; `null` until it answers a heartbeat.
+    rtt_ms: Option<u64>,
+    /// Clients serving it: more than one for a pool.
+    clients: usize,
+}
+
+impl TunnelInfo {
+    fn new(subdomain: &str, tunnel: &Tunnel, state: &State) -> Self {
+        Self {
+            subdomain: subdomain.to_owned(),
+            proto: tunnel.proto,
+            public_port: tunnel.public_port,
+            url: match tunnel.proto {
+                Proto::Http => state.http_url(subdomain),
+                _ => None,
+            },
+            client_addr: tunnel.client_addr,
+            identity: tunnel.identity.clone(),
+            uptime_secs: tunnel.since.elapsed().as_secs(),
+            traffic: tunnel.traffic.snapshot(),
+            max_bandwidth: tunnel.traffic.max_bandwidth(),
+            rtt_ms: tunnel.rtt.get().map(|rtt| rtt.as_millis() as u64),
+            clients: tunnel.pool.len(),
+        }
+    }
+}
+
+/// Serve admin requests, one per connection.
+pub(crate) async fn serve(listener: TcpListener, state: Arc<State>) {
+    let mut backoff = Backoff::default();
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(err = %e, "admin accept failed");
+                backoff.failed().await;
+                continue;
+            }
+        };
+        backoff.succeeded();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, addr, &state).await {
+                debug!(%addr, err = %e, "admin request failed");
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, addr: SocketAddr, state: &State) -> Result<()> {
+    let head = match timeout(HEAD_TIMEOUT, read_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Ok(()),
+    };
+    // Browsers send `Origin` with every cross-site POST and DELETE.
+    let local = stream.local_addr()?;
+    let named = header(&head, "host").is_some_and(|host| own_host(host, local));
+    if !named || header(&head, "origin").is_some() {
+        let body = json!({ "error": "the admin API only answers to its own address" });
+        return respond(&mut stream, 403, "Forbidden", body).await;
+    }
+    let line = String::from_utf8_lossy(&head);
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = path.split('?').next().unwrap_or("");
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["tunnels"]) => {
+            let mut tunnels: Vec<TunnelInfo> = state
+                .registry
+                .tunnels()
+                .iter()
+                .map(|(subdomain, t)| TunnelInfo::new(subdomain, t, state))
+                .collect();
+            tunnels.sort_by(|a, b| a.subdomain.cmp(&b.subdomain));
+            respond(&mut stream, 200, "OK", json!(tunnels)).await
+        }
+        ("GET", ["tunnels", subdomain]) => {
+            let info = state
+                .registry
+                .tunnel(subdomain)
+                .map(|t| TunnelInfo::new(subdomain, &t, state));
+            match info {
+                Some(info) => respond(&mut stream, 200, "OK", json!(info)).await,
+                None => not_found(&mut stream, subdomain).await,
+            }
+        }
+        ("DELETE", ["tunnels", subdomain]) => {
+            if !state.close_tunnel(subdomain).await {
+                return not_found(&mut stream, subdomain).await;
+            }
+            info!(%subdomain, admin = %addr, "tunnel closed by operator");
+            state.audit(Notification::admin_tunnel_closed(addr.ip(), subdomain));
+            respond(&mut stream, 200, "OK", json!({ "closed": subdomain })).await
+        }
+        ("GET", ["auth"]) => {
+            let stats = state.throttle.stats();
+            respond(&mut stream, 200, "OK", json!(stats)).await
+        }
+        ("GET", ["tarpit"]) => {
+            let stats = state.offenders.stats();
+            respond(&mut stream, 200, "OK", json!(stats)).await
+        }
+        ("GET", ["geoip"]) => {
+            let stats = state.country_stats.snapshot();
+            respond(&mut stream, 200, "OK", json!(stats)).await
+        }
+        ("POST", ["reload"]) => {
+            let reloaded = state.reload().map_err(|e| format!("{e:#}"));
+            let error = reloaded.as_ref().err().cloned();
+            state.audit(Notification::admin_reload(addr.ip(), error));
+            match reloaded {
+                Ok(()) => respond(&mut stream, 200, "OK", json!({ "reloaded": true })).await,
+                Err(e) => {
+                    let body = json!({ "error": e });
+                    respond(&mut stream, 500, "Internal Server Error", body).await
+                }
+            }
+        }
+        (_, ["tunnels", ..] | ["reload"] | ["auth"] | ["tarpit"] | ["geoip"]) => {
+            let body = json!({ "error": format!("method {method} not allowed") });
+            respond(&mut stream, 405, "Method Not Allowed", body).await
+        }
+        _ => {
+            let body = json!({ "error": format!("no route for {path}") });
+            respond(&mut stream, 404, "Not Found", body).await
+        }
+    }
+}
+
+/// Whether `host` names the API at `local`: `localhost`, a loopback
+/// address or `local`'s own, never a DNS name that might point here for now.
+fn own_host(host: &str, local: SocketAddr) -> bool {
+    let Some((name, port)) = host.rsplit_once(':') else {
+        return false;
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    let ours = match name.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip == local.ip(),
+        Err(_) => name.eq_ignore_ascii_case("localhost"),
+    };
+    ours && port.parse() == Ok(local.port())
+}
+
+async fn not_found(stream: &mut (impl AsyncWrite + Unpin), subdomain: &str) -> Result<()> {
+    let body = json!({ "error": format!("no tunnel '{subdomain}'") });
+    respond(stream, 404, "Not Found", body).await
+}
+
+async fn respond(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    body: serde_json::Value,
+) -> Result<()> {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
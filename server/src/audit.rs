@@ -0,0 +1,332 @@
+//! Tamper-evident audit log (`--audit-log`).
+//!
+//! Tunnels registered and closed, authentication attempts, exceeded quotas
+//! and admin API actions are appended to a file as JSON lines. Each record
+//! carries the SHA-256 of the record before it (`prev`) and of itself
+//! (`hash`), so editing, inserting or removing a record breaks the chain
+//! from there on; `sshx-server verify-audit-log` checks it. The chain runs on
+//! across restarts and rotated files, which verify as one log.
+//!
+//! Cutting records off the end leaves a valid chain, so keep the newest hash
+//! elsewhere to catch that: it is logged whenever the file is rotated.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::webhooks::Notification;
+
+/// `prev` of the first record of a log.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// How far back from the end of a log its last record is looked for.
+const MAX_RECORD: u64 = 64 * 1024;
+
+/// When the log is moved aside for a fresh file.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    /// Start a new file rather than grow one past this many bytes; never
+    /// when `None`.
+    pub max_size: Option<u64>,
+    /// Rotated files kept, `<path>.1` being the newest; older ones are
+    /// deleted.
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            keep: 5,
+        }
+    }
+}
+
+/// An open audit log. Records are written in the order they come by a
+/// thread of its own, so slow disks don't hold up clients, and none are
+/// dropped.
+pub struct AuditLog {
+    queue: Option<mpsc::Sender<Notification>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// A record as written, before its hash is appended.
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Counts up from 1 over the life of the log.
+    seq: u64,
+    #[serde(flatten)]
+    notification: &'a Notification,
+    prev: &'a str,
+}
+
+/// The chain fields of a written record.
+#[derive(Deserialize)]
+struct Link {
+    seq: u64,
+    prev: String,
+    hash: String,
+}
+
+/// Where a chain ends.
+struct Head {
+    seq: u64,
+    hash: String,
+}
+
+impl AuditLog {
+    /// Append to the log at `path`, carrying on the chain of the records
+    /// already there or, after a rotation, in `<path>.1`.
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> Result<Self> {
+        let path = path.into();
+        let head = match last_head(&path)? {
+            Some(head) => head,
+            None => last_head(&rotated(&path, 1))?.unwrap_or(Head {
+                seq: 0,
+                hash: GENESIS.to_owned(),
+            }),
+        };
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        let writer = Writer {
+            path,
+            rotation,
+            file,
+            size,
+            head,
+        };
+        let (queue, records) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || writer.run(records))?;
+        Ok(Self {
+            queue: Some(queue),
+            writer: Some(writer),
+        })
+    }
+
+    /// Queue `notification` to be written.
+    pub(crate) fn record(&self, notification: Notification) {
+        if let Some(queue) = &self.queue {
+            let _ = queue.send(notification);
+        }
+    }
+}
+
+/// Waits for the records queued so far to be written.
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        drop(self.queue.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Owns the file, on the writer thread.
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    head: Head,
+}
+
+impl Writer {
+    fn run(mut self, records: mpsc::Receiver<Notification>) {
+        for notification in records {
+            if let Err(e) = self.write(&notification) {
+                let path = self.path.display();
+                warn!(%path, "cannot write to the audit log: {e:#}");
+            }
+        }
+    }
+
+    fn write(&mut self, notification: &Notification) -> Result<()> {
+        let seq = self.head.seq + 1;
+        let record = Record {
+            seq,
+            notification,
+            prev: &self.head.hash,
+        };
+        let body = serde_json::to_string(&record)?;
+        let hash = seal(&body);
+        let line = format!("{},\"hash\":\"{hash}\"}}\n", &body[..body.len() - 1]);
+        let len = line.len() as u64;
+        if matches!(self.rotation.max_size, Some(max) if self.size > 0 && self.size + len > max) {
+            self.rotate()?;
+        }
+        let written = self
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|()| self.file.sync_data());
+        if let Err(e) = written {
+            // Cut off whatever part made it, or the next record lands on
+            // the same line and the log never verifies again.
+            let _ = self.file.set_len(self.size);
+            return Err(e.into());
+        }
+        self.size += len;
+        // A record that didn't make it to the file isn't chained to.
+        self.head = Head { seq, hash };
+        Ok(())
+    }
+
+    /// Shift the rotated files up by one, dropping the oldest, and start
+    /// the log afresh.
+    fn rotate(&mut self) -> Result<()> {
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(&self.path, keep));
+            for n in (1..keep).rev() {
+                let from = rotated(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        let (path, seq, hash) = (self.path.display(), self.head.seq, &self.head.hash);
+        info!(%path, seq, hash, "audit log rotated");
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("cannot open the audit log {}", path.display()))
+}
+
+/// `<path>.<n>`.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// Hex SHA-256 of a record without its hash.
+fn seal(body: &str) -> String {
+    format!("{:x}", Sha256::digest(body.as_bytes()))
+}
+
+/// The chain fields of `line`, once its hash is found to match.
+fn check(line: &str) -> Result<Link> {
+    let link: Link = serde_json::from_str(line).context("not a record")?;
+    let tail = format!(",\"hash\":\"{}\"}}", link.hash);
+    let Some(body) = line.strip_suffix(&tail) else {
+        bail!("record {} does not end with its hash", link.seq);
+    };
+    if seal(&format!("{body}}}")) != link.hash {
+        bail!("record {} does not match its hash", link.seq);
+    }
+    Ok(link)
+}
+
+/// The end of the chain in the log at `path`, if it has a record.
+fn last_head(path: &Path) -> Result<Option<Head>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("cannot read the audit log {}", path.display()))
+        }
+    };
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(MAX_RECORD)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)
+        .with_context(|| format!("cannot read the audit log {}", path.display()))?;
+    // The seek may have landed inside a character of an earlier record.
+    let tail = String::from_utf8_lossy(&tail);
+    let Some(line) = tail.lines().last() else {
+        return Ok(None);
+    };
+    let link = check(line).with_context(|| {
+        format!(
+            "the last record of {} is damaged; check it with `sshx-server verify-audit-log` \
+             and move it aside to start a new log",
+            path.display()
+        )
+    })?;
+    Ok(Some(Head {
+        seq: link.seq,
+        hash: link.hash,
+    }))
+}
+
+/// What [`verify`] went through.
+#[derive(Debug)]
+pub struct Verified {
+    pub files: usize,
+    pub records: u64,
+    /// Sequence number and hash of the newest record.
+    pub last: Option<(u64, String)>,
+}
+
+/// Check the chain of the log at `path` and its rotated files, oldest first.
+/// The first record's `prev` is taken on trust, as the files before it may
+/// have been rotated away.
+pub fn verify(path: &Path) -> Result<Verified> {
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|n| rotated(path, n))
+        .take_while(|file| file.exists())
+        .collect();
+    files.reverse();
+    if path.exists() {
+        files.push(path.to_owned());
+    }
+    if files.is_empty() {
+        bail!("no audit log at {}", path.display());
+    }
+    let mut records = 0;
+    let mut head: Option<Head> = None;
+    for file in &files {
+        let name = file.display();
+        let reader = BufReader::new(
+            File::open(file).with_context(|| format!("cannot read the audit log {name}"))?,
+        );
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.with_context(|| format!("cannot read the audit log {name}"))?;
+            let at = || format!("{name}:{}", i + 1);
+            let link = check(&line).with_context(at)?;
+            if let Some(head) = &head {
+                if link.seq != head.seq + 1 {
+                    bail!("{}: record {} follows record {}", at(), link.seq, head.seq);
+                }
+                if link.prev != head.hash {
+                    bail!(
+                        "{}: record {} does not chain to the one before",
+                        at(),
+                        link.seq
+                    );
+                }
+            }
+            records += 1;
+            head = Some(Head {
+                seq: link.seq,
+                hash: link.hash,
+            });
+        }
+    }
+    Ok(Verified {
+        files: files.len(),
+        records,
+        last: head.map(|head| (head.seq, head.hash)),
+    })
+}
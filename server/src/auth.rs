@@ -1,63 +1,278 @@
-//! HMAC-SHA256 challenge-response auth.
+//! Pluggable authentication for control connections.
+//!
+//! The wire format is always the same challenge-response exchange: the server
+//! sends `Challenge(uuid)` and the client answers with `Authenticate(String)`.
+//! How that answer is judged is up to an [`AuthProvider`]; [`Auth`] is the
+//! default HMAC-SHA256 shared-secret implementation, and
+//! [`Tokens`](crate::tokens::Tokens) gives every user a token of their own.
+//!
+//! Clients seal the `Hello` they send right behind their answer to the same
+//! challenge, so a man in the middle can't swap the registration after a
+//! good handshake. The seal rides along in [`ChallengeResponse::hello`];
+//! providers built on secrets check it with [`ChallengeResponse::verified_by`].
+
+use std::{net::SocketAddr, ops::RangeInclusive};
 
 use anyhow::{bail, ensure, Result};
-use hmac::{Hmac, Mac};
-use sha2::{Digest, Sha256};
+use futures_util::future::{self, BoxFuture};
 use tokio::io::{AsyncRead, AsyncWrite};
 use uuid::Uuid;
 
-use crate::shared::{ClientMsg, Framed_, ServerMsg};
+use sshx_core::protocol::{ClientMsg, Framed_, Proto, ServerMsg};
+
+/// Default provider: HMAC-SHA256 over the challenge with a shared secret.
+pub use sshx_core::auth::Auth;
 
-pub struct Auth(Hmac<Sha256>);
+/// Who a control connection authenticated as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    /// Bytes/sec per tunnel and direction, overriding the server default.
+    pub max_bandwidth: Option<u64>,
+    /// Subdomains it may claim, where `*` matches any run of characters.
+    /// Empty means any subdomain.
+    pub subdomains: Vec<String>,
+    /// Public ports it may bind. Empty means the server's whole range.
+    pub ports: Vec<RangeInclusive<u16>>,
+    /// Kinds of tunnel it may open. Empty means all.
+    pub protos: Vec<Proto>,
+    /// Tunnels it may hold at once, across all its connections.
+    pub max_tunnels: Option<usize>,
+}
 
-impl Auth {
-    pub fn new(secret: &str) -> Self {
-        let key = Sha256::new().chain_update(secret).finalize();
-        Self(Hmac::new_from_slice(&key).expect("hmac accepts any key size"))
+impl Identity {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            max_bandwidth: None,
+            subdomains: Vec::new(),
+            ports: Vec::new(),
+            protos: Vec::new(),
+            max_tunnels: None,
+        }
     }
 
-    fn answer(&self, challenge: &Uuid) -> String {
-        let mut mac = self.0.clone();
-        mac.update(challenge.as_bytes());
-        hex::encode(mac.finalize().into_bytes())
+    /// Identity used when the server runs without an auth provider.
+    pub fn anonymous() -> Self {
+        Self::new("anonymous")
     }
 
-    fn validate(&self, challenge: &Uuid, tag: &str) -> bool {
-        hex::decode(tag)
-            .map(|t| {
-                let mut mac = self.0.clone();
-                mac.update(challenge.as_bytes());
-                mac.verify_slice(&t).is_ok()
-            })
-            .unwrap_or(false)
+    pub fn may_claim(&self, subdomain: &str) -> bool {
+        self.subdomains.is_empty() || self.subdomains.iter().any(|p| glob(p, subdomain))
     }
 
-    /// Server side: send challenge, verify response.
-    pub async fn handshake_server<T: AsyncRead + AsyncWrite + Unpin>(
-        &self,
-        stream: &mut Framed_<T>,
-    ) -> Result<()> {
-        let challenge = Uuid::new_v4();
-        stream.send(ServerMsg::Challenge(challenge)).await?;
-        match stream.recv_timeout::<ClientMsg>().await? {
-            Some(ClientMsg::Authenticate(tag)) => {
-                ensure!(self.validate(&challenge, &tag), "invalid secret");
-                Ok(())
-            }
-            _ => bail!("expected Authenticate message"),
+    pub fn may_bind(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.iter().any(|r| r.contains(&port))
+    }
+
+    pub fn may_open(&self, proto: Proto) -> bool {
+        self.protos.is_empty() || self.protos.contains(&proto)
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters (including none).
+fn glob(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((head, tail)) => {
+            let Some(rest) = name.strip_prefix(head) else {
+                return false;
+            };
+            (0..=rest.len())
+                .filter(|&i| rest.is_char_boundary(i))
+                .any(|i| glob(tail, &rest[i..]))
         }
     }
+}
 
-    /// Client side: receive challenge, send response.
-    pub async fn handshake_client<T: AsyncRead + AsyncWrite + Unpin>(
+/// The client's answer to a challenge.
+#[derive(Debug, Clone)]
+pub struct ChallengeResponse {
+    pub challenge: Uuid,
+    pub response: String,
+    /// The sealed `Hello` that came right behind the answer, if the client
+    /// sealed one.
+    pub hello: Option<SealedHello>,
+}
+
+/// A `Hello` bound to the challenge by its seal.
+#[derive(Debug, Clone)]
+pub struct SealedHello {
+    /// What the seal covers; see [`ClientMsg::binding`].
+    pub binding: Vec<u8>,
+    pub seal: String,
+}
+
+impl ChallengeResponse {
+    /// Whether the answer, and the seal of the `Hello` if there is one,
+    /// come from `auth`'s secret.
+    pub fn verified_by(&self, auth: &Auth) -> bool {
+        auth.verify(&self.challenge, &self.response)
+            && self
+                .hello
+                .as_ref()
+                .is_none_or(|hello| auth.verify_seal(&self.challenge, &hello.binding, &hello.seal))
+    }
+}
+
+/// Connection details available to an [`AuthProvider`].
+#[derive(Debug, Clone)]
+pub struct AuthMetadata {
+    pub peer_addr: SocketAddr,
+}
+
+/// Decides whether a challenge response is acceptable.
+///
+/// Implement this to back sshx-server with LDAP, a database, or a custom
+/// token system. Returning an error rejects the connection; the error text is
+/// sent to the client.
+pub trait AuthProvider: Send + Sync + 'static {
+    fn authenticate(
         &self,
-        stream: &mut Framed_<T>,
-    ) -> Result<()> {
-        match stream.recv_timeout::<ServerMsg>().await? {
-            Some(ServerMsg::Challenge(c)) => {
-                stream.send(ClientMsg::Authenticate(self.answer(&c))).await
-            }
-            _ => bail!("expected Challenge from server"),
-        }
+        response: ChallengeResponse,
+        meta: AuthMetadata,
+    ) -> BoxFuture<'_, Result<Identity>>;
+}
+
+impl AuthProvider for Auth {
+    fn authenticate(
+        &self,
+        response: ChallengeResponse,
+        _meta: AuthMetadata,
+    ) -> BoxFuture<'_, Result<Identity>> {
+        let result = if response.verified_by(self) {
+            Ok(Identity::new("secret"))
+        } else {
+            Err(anyhow::anyhow!("invalid secret"))
+        };
+        Box::pin(future::ready(result))
+    }
+}
+
+impl AuthProvider for Box<dyn AuthProvider> {
+    fn authenticate(
+        &self,
+        response: ChallengeResponse,
+        meta: AuthMetadata,
+    ) -> BoxFuture<'_, Result<Identity>> {
+        (**self).authenticate(response, meta)
+    }
+}
+
+/// Several shared secrets, each standing for its own [`Identity`]. The first
+/// secret that matches wins.
+#[derive(Default)]
+pub struct Secrets {
+    entries: Vec<(Auth, Identity)>,
+}
+
+impl Secrets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept `secret` as `identity`.
+    pub fn with(mut self, secret: &str, identity: Identity) -> Self {
+        self.entries.push((Auth::new(secret), identity));
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
+
+    /// The identity whose secret produced `response`, if any.
+    pub(crate) fn find(&self, response: &ChallengeResponse) -> Option<Identity> {
+        self.entries
+            .iter()
+            .find(|(auth, _)| response.verified_by(auth))
+            .map(|(_, identity)| identity.clone())
+    }
+}
+
+impl AuthProvider for Secrets {
+    fn authenticate(
+        &self,
+        response: ChallengeResponse,
+        _meta: AuthMetadata,
+    ) -> BoxFuture<'_, Result<Identity>> {
+        let result = self
+            .find(&response)
+            .ok_or_else(|| anyhow::anyhow!("invalid secret"));
+        Box::pin(future::ready(result))
+    }
+}
+
+/// Server side: send challenge, have `provider` verify the response.
+pub async fn handshake_server<T: AsyncRead + AsyncWrite + Unpin>(
+    provider: &dyn AuthProvider,
+    stream: &mut Framed_<T>,
+    meta: AuthMetadata,
+) -> Result<Identity> {
+    let challenge = Uuid::new_v4();
+    stream.send(ServerMsg::Challenge(challenge)).await?;
+    let response = answer(stream, challenge).await?;
+    judge(provider, response, meta).await
+}
+
+/// Server side of a control connection: like [`handshake_server`], but also
+/// reads the message the client sends right behind its answer, so that the
+/// seal of a `Hello` is judged along with the answer. That message comes
+/// back whether or not the client passed, if it arrived.
+pub(crate) async fn handshake_control<T: AsyncRead + AsyncWrite + Unpin>(
+    provider: &dyn AuthProvider,
+    stream: &mut Framed_<T>,
+    meta: AuthMetadata,
+) -> (Result<Identity>, Option<ClientMsg>) {
+    let challenge = Uuid::new_v4();
+    if let Err(e) = stream.send(ServerMsg::Challenge(challenge)).await {
+        return (Err(e), None);
+    }
+    let mut response = match answer(stream, challenge).await {
+        Ok(response) => response,
+        Err(e) => return (Err(e), None),
+    };
+    let first = stream.recv_timeout::<ClientMsg>().await.ok().flatten();
+    if let Some(ClientMsg::Hello {
+        seal: Some(seal), ..
+    }) = &first
+    {
+        response.hello = Some(SealedHello {
+            binding: first
+                .as_ref()
+                .and_then(ClientMsg::binding)
+                .unwrap_or_default(),
+            seal: seal.clone(),
+        });
+    }
+    (judge(provider, response, meta).await, first)
+}
+
+/// Read the client's answer to `challenge`.
+async fn answer<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut Framed_<T>,
+    challenge: Uuid,
+) -> Result<ChallengeResponse> {
+    match stream.recv_timeout::<ClientMsg>().await? {
+        Some(ClientMsg::Authenticate(response)) => Ok(ChallengeResponse {
+            challenge,
+            response,
+            hello: None,
+        }),
+        _ => bail!("expected Authenticate message"),
+    }
+}
+
+async fn judge(
+    provider: &dyn AuthProvider,
+    response: ChallengeResponse,
+    meta: AuthMetadata,
+) -> Result<Identity> {
+    let identity = provider.authenticate(response, meta).await?;
+    ensure!(
+        !identity.name.is_empty(),
+        "auth provider returned an empty identity"
+    );
+    Ok(identity)
 }
@@ -0,0 +1,245 @@
+//! Persistent ban list for IPs and subdomains.
+//!
+//! Bans live in a JSON file so they survive restarts and can be audited. The
+//! running server re-reads the file when it changes, so `sshx-server bans`
+//! can edit it from the command line. Every change is made to what the file
+//! holds at that moment, so neither side undoes the other's.
+
+use std::{
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// What a ban applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum BanTarget {
+    Ip(IpAddr),
+    Subdomain(String),
+}
+
+impl FromStr for BanTarget {
+    type Err = std::convert::Infallible;
+
+    /// Anything that parses as an IP address is an IP ban; the rest are subdomains.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::Subdomain(s.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "ip {ip}"),
+            Self::Subdomain(s) => write!(f, "subdomain {s}"),
+        }
+    }
+}
+
+/// One ban entry. Times are unix seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    pub created_at: u64,
+    /// `None` means the ban never expires.
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|t| t > now)
+    }
+}
+
+/// One page of [`BanList::list`].
+#[derive(Debug, Clone, Serialize)]
+pub struct BanPage {
+    pub bans: Vec<Ban>,
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+}
+
+/// Ban store, optionally backed by a file.
+pub struct BanList {
+    path: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    bans: Vec<Ban>,
+    /// Modification time of the file when we last read or wrote it.
+    loaded_mtime: Option<SystemTime>,
+}
+
+impl BanList {
+    /// A ban list that is never persisted.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Load bans from `path`. A missing file is an empty list.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let list = Self {
+            path: Some(path.into()),
+            inner: Mutex::new(Inner::default()),
+        };
+        list.refresh()?;
+        Ok(list)
+    }
+
+    /// Ban `target` for `ttl` (forever if `None`), replacing any existing ban.
+    pub fn ban(&self, target: BanTarget, reason: &str, ttl: Option<Duration>) -> Result<()> {
+        let now = unix_now();
+        self.update(|bans| {
+            bans.retain(|b| b.target != target);
+            bans.push(Ban {
+                target,
+                reason: reason.to_owned(),
+                created_at: now,
+                expires_at: ttl.map(|t| now + t.as_secs()),
+            });
+            true
+        })
+    }
+
+    /// Lift the ban on `target`. Returns whether there was one.
+    pub fn unban(&self, target: &BanTarget) -> Result<bool> {
+        let mut found = false;
+        self.update(|bans| {
+            let before = bans.len();
+            bans.retain(|b| &b.target != target);
+            found = bans.len() < before;
+            found
+        })?;
+        Ok(found)
+    }
+
+    /// The active ban on `target`, if any.
+    pub fn check(&self, target: &BanTarget) -> Option<Ban> {
+        let now = unix_now();
+        let inner = self.inner.lock().unwrap();
+        inner
+            .bans
+            .iter()
+            .find(|b| &b.target == target && b.is_active(now))
+            .cloned()
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.check(&BanTarget::Ip(ip)).is_some()
+    }
+
+    /// Active bans, oldest first, `per_page` at a time. Pages start at 1.
+    pub fn list(&self, page: usize, per_page: usize) -> BanPage {
+        let now = unix_now();
+        let inner = self.inner.lock().unwrap();
+        let active: Vec<&Ban> = inner.bans.iter().filter(|b| b.is_active(now)).collect();
+        let per_page = per_page.max(1);
+        let page = page.max(1);
+        BanPage {
+            total: active.len(),
+            bans: active
+                .into_iter()
+                .skip((page - 1) * per_page)
+                .take(per_page)
+                .cloned()
+                .collect(),
+            page,
+            per_page,
+        }
+    }
+
+    /// Drop expired bans. Returns how many were removed.
+    pub fn prune(&self) -> Result<usize> {
+        let now = unix_now();
+        let mut removed = 0;
+        self.update(|bans| {
+            let before = bans.len();
+            bans.retain(|b| b.is_active(now));
+            removed = before - bans.len();
+            removed > 0
+        })?;
+        Ok(removed)
+    }
+
+    /// Re-read the backing file if it changed since we last touched it.
+    pub fn refresh(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mtime = match fs::metadata(path) {
+            Ok(meta) => meta.modified().ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("cannot stat {}", path.display())),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        if mtime.is_some() && mtime == inner.loaded_mtime {
+            return Ok(());
+        }
+        inner.bans = read_file(path)?;
+        inner.loaded_mtime = mtime;
+        Ok(())
+    }
+
+    /// Apply `change` to the bans as the file has them now, and write them
+    /// back if it says it changed anything.
+    fn update(&self, change: impl FnOnce(&mut Vec<Ban>) -> bool) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(path) = &self.path {
+            let mtime = fs::metadata(path).and_then(|m| m.modified()).ok();
+            match read_file(path) {
+                Ok(bans) => {
+                    inner.bans = bans;
+                    inner.loaded_mtime = mtime;
+                }
+                Err(_) if !path.exists() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !change(&mut inner.bans) {
+            return Ok(());
+        }
+        self.save(&mut inner)
+    }
+
+    fn save(&self, inner: &mut Inner) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // Write-then-rename so a crash never leaves a half-written file.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&inner.bans)?)
+            .with_context(|| format!("cannot write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("cannot replace {}", path.display()))?;
+        inner.loaded_mtime = fs::metadata(path).and_then(|m| m.modified()).ok();
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<Ban>> {
+    let bytes = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    serde_json::from_slice(&bytes).with_context(|| format!("invalid ban file {}", path.display()))
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
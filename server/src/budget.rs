@@ -0,0 +1,182 @@
+//! Connection budget, so a flood can't run the server out of file
+//! descriptors.
+//!
+//! Every connection accepted on a public listener holds a [`Slot`] for as
+//! long as it is open. With [`ConnLimits`], reaching the high-water mark
+//! pauses all accept loops until enough connections closed to get under the
+//! low-water mark; new visitors wait in the kernel's backlog meanwhile.
+//! Whatever the limits, a failing `accept()` is retried with a [`Backoff`]
+//! rather than at once, since it keeps failing while fds are short.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+    time::sleep,
+};
+use tracing::{info, warn};
+
+/// First pause after a failed accept; it doubles with each one in a row.
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Longest pause between failed accepts.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// When the accept loops stop and start again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnLimits {
+    /// Open connections at which accepting pauses.
+    pub high_water: usize,
+    /// Open connections at or under which it resumes.
+    pub low_water: usize,
+}
+
+impl ConnLimits {
+    /// Pause at `high_water` and resume at 90% of it.
+    pub fn new(high_water: usize) -> Self {
+        Self {
+            high_water,
+            low_water: high_water - high_water.div_ceil(10),
+        }
+    }
+}
+
+/// Open connections across the server's listeners.
+pub(crate) struct Budget {
+    limits: Option<ConnLimits>,
+    open: AtomicUsize,
+    /// Whether the accept loops are paused.
+    paused: watch::Sender<bool>,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: Option<ConnLimits>) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            open: AtomicUsize::new(0),
+            paused: watch::Sender::new(false),
+        })
+    }
+
+    /// Wait until accepting isn't paused.
+    pub(crate) async fn ready(&self) {
+        let mut paused = self.paused.subscribe();
+        let _ = paused.wait_for(|paused| !paused).await;
+    }
+
+    /// Count a connection just accepted, pausing the accept loops if it
+    /// reaches the high-water mark.
+    pub(crate) fn take(self: &Arc<Self>) -> Slot {
+        let open = self.open.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(limits) = self.limits {
+            if open >= limits.high_water && !self.paused.send_replace(true) {
+                warn!(
+                    open,
+                    high_water = limits.high_water,
+                    low_water = limits.low_water,
+                    "too many open connections, pausing accepts"
+                );
+            }
+        }
+        Slot(Arc::clone(self))
+    }
+}
+
+/// One open connection's share of the [`Budget`], given back on drop.
+pub(crate) struct Slot(Arc<Budget>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let budget = &self.0;
+        let open = budget.open.fetch_sub(1, Ordering::Relaxed) - 1;
+        let Some(limits) = budget.limits else {
+            return;
+        };
+        if open <= limits.low_water && *budget.paused.borrow() && budget.paused.send_replace(false)
+        {
+            info!(open, low_water = limits.low_water, "resuming accepts");
+        }
+    }
+}
+
+/// A stream that holds its [`Slot`] until it is dropped.
+pub(crate) struct Counted<S> {
+    inner: S,
+    _slot: Slot,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(inner: S, slot: Slot) -> Self {
+        Self { inner, _slot: slot }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Pauses between failed accepts of one loop.
+#[derive(Default)]
+pub(crate) struct Backoff(Duration);
+
+impl Backoff {
+    /// Wait before the next accept, longer for each failure in a row.
+    pub(crate) async fn failed(&mut self) {
+        self.0 = (self.0 * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+        sleep(self.0).await;
+    }
+
+    pub(crate) fn succeeded(&mut self) {
+        self.0 = Duration::ZERO;
+    }
+}
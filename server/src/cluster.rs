@@ -0,0 +1,493 @@
+//! Clustering: several servers behind one load balancer.
+//!
+//! Nodes share, through a [`Store`], which node holds each subdomain and on
+//! which node each parked connection waits for its client's `Accept`. A
+//! node that gets an HTTP visitor or an `Accept` it can't serve itself hands
+//! it to the right node over that node's peer port. Peer connections open
+//! with the usual challenge when the cluster has a secret, which it must
+//! unless the peer port is on loopback, then a single [`PeerMsg`], then the
+//! raw bytes.
+//!
+//! Claims expire unless renewed, so the subdomains of a node that died come
+//! free after [`CLAIM_TTL`].
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use futures_util::future::{self, BoxFuture};
+use serde::{Deserialize, Serialize};
+use sshx_core::{
+    auth::Auth,
+    protocol::{Framed_, HANDSHAKE_TIMEOUT},
+};
+use tokio::{
+    io::{copy_bidirectional_with_sizes, AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::{self, AuthMetadata},
+    budget::Backoff,
+    server::{splice, Inbound, State},
+};
+
+/// Default peer port nodes listen on for each other.
+pub const PEER_PORT: u16 = 12269;
+
+/// How long a claim lasts unless its node renews it.
+pub const CLAIM_TTL: Duration = Duration::from_secs(30);
+
+/// How long a parked connection can be found from other nodes.
+const PARK_TTL: Duration = Duration::from_secs(10);
+
+/// Where nodes keep what they share.
+///
+/// Implement this to back a cluster with something other than Redis; every
+/// node of a cluster must use the same store.
+pub trait Store: Send + Sync + 'static {
+    /// Claim `subdomain` for `node` for `ttl`, unless another node holds
+    /// it. Renews the claim if `node` holds it already. Returns whether
+    /// `node` holds it now.
+    fn claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        node: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<bool>>;
+
+    /// Give up `subdomain` if `node` holds it.
+    fn release<'a>(&'a self, subdomain: &'a str, node: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// The node holding `subdomain`, if any.
+    fn owner<'a>(&'a self, subdomain: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+
+    /// Record that connection `id` waits on `node`, for `ttl`.
+    fn park<'a>(&'a self, id: Uuid, node: &'a str, ttl: Duration) -> BoxFuture<'a, Result<()>>;
+
+    /// The node connection `id` waits on, if any, forgetting it.
+    fn take(&self, id: Uuid) -> BoxFuture<'_, Result<Option<String>>>;
+}
+
+/// A [`Store`] in this process: for a single node, or for several nodes
+/// embedded in one process.
+#[derive(Default)]
+pub struct MemoryStore {
+    claims: DashMap<String, (String, Instant)>,
+    parked: DashMap<Uuid, (String, Instant)>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        node: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let now = Instant::now();
+        let mut claim = self
+            .claims
+            .entry(subdomain.to_owned())
+            .or_insert_with(|| (node.to_owned(), now));
+        let claimed = claim.0 == node || claim.1 <= now;
+        if claimed {
+            *claim = (node.to_owned(), now + ttl);
+        }
+        Box::pin(future::ready(Ok(claimed)))
+    }
+
+    fn release<'a>(&'a self, subdomain: &'a str, node: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.claims
+            .remove_if(subdomain, |_, (owner, _)| owner == node);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn owner<'a>(&'a self, subdomain: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        let owner = self
+            .claims
+            .get(subdomain)
+            .filter(|claim| claim.1 > Instant::now())
+            .map(|claim| claim.0.clone());
+        Box::pin(future::ready(Ok(owner)))
+    }
+
+    fn park<'a>(&'a self, id: Uuid, node: &'a str, ttl: Duration) -> BoxFuture<'a, Result<()>> {
+        let now = Instant::now();
+        self.parked.retain(|_, (_, until)| *until > now);
+        self.parked.insert(id, (node.to_owned(), now + ttl));
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn take(&self, id: Uuid) -> BoxFuture<'_, Result<Option<String>>> {
+        let node = self
+            .parked
+            .remove(&id)
+            .filter(|(_, (_, until))| *until > Instant::now())
+            .map(|(_, (node, _))| node);
+        Box::pin(future::ready(Ok(node)))
+    }
+}
+
+/// A [`Store`] in Redis, for nodes on several hosts.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    conn: redis::aio::ConnectionManager,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    /// Connect to the Redis server at `url`, e.g. `redis://10.0.0.9/0`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url).context("invalid Redis URL")?;
+        let conn = client
+            .get_connection_manager()
+            .await
+            .with_context(|| format!("cannot connect to Redis at {url}"))?;
+        Ok(Self { conn })
+    }
+
+    fn claim_key(subdomain: &str) -> String {
+        format!("sshx:claim:{subdomain}")
+    }
+
+    fn park_key(id: Uuid) -> String {
+        format!("sshx:parked:{id}")
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Store for RedisStore {
+    fn claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        node: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<bool>> {
+        const CLAIM: &str = r"
+            local owner = redis.call('GET', KEYS[1])
+            if owner and owner ~= ARGV[1] then return 0 end
+            redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+            return 1";
+        let mut conn = self.conn.clone();
+        Box::pin(async move {
+            let claimed: i32 = redis::Script::new(CLAIM)
+                .key(Self::claim_key(subdomain))
+                .arg(node)
+                .arg(ttl.as_millis() as u64)
+                .invoke_async(&mut conn)
+                .await?;
+            Ok(claimed == 1)
+        })
+    }
+
+    fn release<'a>(&'a self, subdomain: &'a str, node: &'a str) -> BoxFuture<'a, Result<()>> {
+        const RELEASE: &str = r"
+            if redis.call('GET', KEYS[1]) == ARGV[1] then redis.call('DEL', KEYS[1]) end
+            return 0";
+        let mut conn = self.conn.clone();
+        Box::pin(async move {
+            let _: i32 = redis::Script::new(RELEASE)
+                .key(Self::claim_key(subdomain))
+                .arg(node)
+                .invoke_async(&mut conn)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn owner<'a>(&'a self, subdomain: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        let mut conn = self.conn.clone();
+        Box::pin(async move {
+            let owner = redis::cmd("GET")
+                .arg(Self::claim_key(subdomain))
+                .query_async(&mut conn)
+                .await?;
+            Ok(owner)
+        })
+    }
+
+    fn park<'a>(&'a self, id: Uuid, node: &'a str, ttl: Duration) -> BoxFuture<'a, Result<()>> {
+        let mut conn = self.conn.clone();
+        Box::pin(async move {
+            let () = redis::cmd("SET")
+                .arg(Self::park_key(id))
+                .arg(node)
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .query_async(&mut conn)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn take(&self, id: Uuid) -> BoxFuture<'_, Result<Option<String>>> {
+        // GETDEL needs Redis 6.2.
+        const TAKE: &str = r"
+            local node = redis.call('GET', KEYS[1])
+            redis.call('DEL', KEYS[1])
+            return node";
+        let mut conn = self.conn.clone();
+        Box::pin(async move {
+            let node = redis::Script::new(TAKE)
+                .key(Self::park_key(id))
+                .invoke_async(&mut conn)
+                .await?;
+            Ok(node)
+        })
+    }
+}
+
+/// How a server takes part in a cluster.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Address other nodes reach this one's peer port at, e.g.
+    /// `10.0.0.2:12269`. It names the node in the store, so it must be
+    /// unique and stable.
+    pub node_addr: String,
+    /// Where to listen for other nodes.
+    pub bind: SocketAddr,
+    /// Peers prove they know it, like clients do a server secret. Every node
+    /// must have the same one. Required unless `bind` is a loopback address.
+    pub secret: Option<String>,
+}
+
+/// The first frame on a peer connection.
+#[derive(Debug, Serialize, Deserialize)]
+enum PeerMsg {
+    /// A visitor of the tunnel of `subdomain`; its bytes follow.
+    Visitor {
+        subdomain: String,
+        peer_addr: SocketAddr,
+    },
+    /// A client's data connection for the parked connection `id`.
+    Accept(Uuid),
+}
+
+/// This node's part in the cluster.
+pub(crate) struct Cluster {
+    pub(crate) config: ClusterConfig,
+    store: Arc<dyn Store>,
+}
+
+impl Cluster {
+    pub(crate) fn new(config: ClusterConfig, store: Arc<dyn Store>) -> Self {
+        Self { config, store }
+    }
+
+    fn node(&self) -> &str {
+        &self.config.node_addr
+    }
+
+    /// Claim `subdomain` for this node. Returns whether another node holds
+    /// it. Errors count as free, so a store outage doesn't stop the node.
+    pub(crate) async fn held_elsewhere(&self, subdomain: &str) -> bool {
+        match self.store.claim(subdomain, self.node(), CLAIM_TTL).await {
+            Ok(claimed) => !claimed,
+            Err(e) => {
+                warn!(%subdomain, err = format!("{e:#}"), "cannot claim subdomain in the cluster");
+                false
+            }
+        }
+    }
+
+    /// Give up `subdomain` in the background.
+    pub(crate) fn release(self: &Arc<Self>, subdomain: String) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let cluster = Arc::clone(self);
+        runtime.spawn(async move {
+            if let Err(e) = cluster.store.release(&subdomain, cluster.node()).await {
+                warn!(%subdomain, err = format!("{e:#}"), "cannot release subdomain in the cluster");
+            }
+        });
+    }
+
+    /// The other node holding `subdomain`, if one does.
+    pub(crate) async fn owner(&self, subdomain: &str) -> Option<String> {
+        match self.store.owner(subdomain).await {
+            Ok(owner) => owner.filter(|node| node != self.node()),
+            Err(e) => {
+                warn!(%subdomain, err = format!("{e:#}"), "cannot look up subdomain in the cluster");
+                None
+            }
+        }
+    }
+
+    /// Let other nodes find the connection `id` parked here.
+    pub(crate) async fn park(&self, id: Uuid) {
+        if let Err(e) = self.store.park(id, self.node(), PARK_TTL).await {
+            warn!(%id, err = format!("{e:#}"), "cannot park connection in the cluster");
+        }
+    }
+
+    /// The other node the connection `id` is parked on, if any.
+    pub(crate) async fn parked_on(&self, id: Uuid) -> Option<String> {
+        match self.store.take(id).await {
+            Ok(node) => node.filter(|node| node != self.node()),
+            Err(e) => {
+                warn!(%id, err = format!("{e:#}"), "cannot look up connection in the cluster");
+                None
+            }
+        }
+    }
+
+    /// Open a connection to the peer port of `node` and send `msg`.
+    async fn dial(&self, node: &str, msg: PeerMsg) -> Result<Framed_<TcpStream>> {
+        let stream = timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(node))
+            .await
+            .with_context(|| format!("connecting to node {node} timed out"))?
+            .with_context(|| format!("cannot connect to node {node}"))?;
+        let mut peer = Framed_::new(stream);
+        if let Some(secret) = &self.config.secret {
+            Auth::new(secret).handshake(&mut peer).await?;
+        }
+        peer.send(msg).await?;
+        Ok(peer)
+    }
+
+    /// Hand a visitor of `subdomain` to `node`, which holds the tunnel.
+    pub(crate) async fn forward_visitor(
+        &self,
+        node: &str,
+        subdomain: &str,
+        inbound: Inbound,
+        buffer_size: usize,
+    ) -> Result<()> {
+        let msg = PeerMsg::Visitor {
+            subdomain: subdomain.to_owned(),
+            peer_addr: inbound.addr,
+        };
+        let peer = self.dial(node, msg).await?;
+        debug!(addr = %inbound.addr, %subdomain, %node, "visitor handed to another node");
+        splice(inbound, peer, buffer_size).await
+    }
+
+    /// A connection to `node` for the parked connection `id`, which relays
+    /// the visitor's bytes as they are.
+    pub(crate) async fn accept_on(&self, node: &str, id: Uuid) -> Result<Framed_<TcpStream>> {
+        self.dial(node, PeerMsg::Accept(id)).await
+    }
+
+    /// Hand a client's data connection for the parked connection `id` to
+    /// `node`, where the visitor waits.
+    pub(crate) async fn forward_accept<S>(
+        &self,
+        node: &str,
+        id: Uuid,
+        data: Framed_<S>,
+        buffer_size: usize,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let peer = self.dial(node, PeerMsg::Accept(id)).await?;
+        debug!(%id, %node, "Accept handed to another node");
+        let (mut data, mut peer) = (data.into_parts(), peer.into_parts());
+        peer.io.write_all(&data.read_buf).await?;
+        data.io.write_all(&peer.read_buf).await?;
+        copy_bidirectional_with_sizes(&mut data.io, &mut peer.io, buffer_size, buffer_size).await?;
+        Ok(())
+    }
+}
+
+/// Accept other nodes on the peer port.
+pub(crate) async fn serve(listener: TcpListener, state: Arc<State>) {
+    let mut backoff = Backoff::default();
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(err = %e, "peer accept failed");
+                backoff.failed().await;
+                continue;
+            }
+        };
+        backoff.succeeded();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = serve_peer(stream, addr, &state).await {
+                warn!(%addr, err = format!("{e:#}"), "peer connection failed");
+            }
+        });
+    }
+}
+
+async fn serve_peer(stream: TcpStream, addr: SocketAddr, state: &Arc<State>) -> Result<()> {
+    let Some(cluster) = &state.cluster else {
+        bail!("not in a cluster");
+    };
+    let mut peer = Framed_::new(stream);
+    if let Some(secret) = &cluster.config.secret {
+        let meta = AuthMetadata { peer_addr: addr };
+        auth::handshake_server(&Auth::new(secret), &mut peer, meta)
+            .await
+            .context("peer failed to authenticate")?;
+    }
+    match peer.recv_timeout::<PeerMsg>().await? {
+        Some(PeerMsg::Visitor {
+            subdomain,
+            peer_addr,
+        }) => {
+            let tunnel = state
+                .registry
+                .tunnel(&subdomain)
+                .map(|t| (t.inbound.clone(), t.acl.permits(peer_addr.ip())));
+            let Some((sender, permitted)) = tunnel else {
+                bail!("no tunnel for '{subdomain}' on this node");
+            };
+            if !permitted {
+                debug!(%peer_addr, %subdomain, "forwarded visitor denied by ACL");
+                return Ok(());
+            }
+            let parts = peer.into_parts();
+            let inbound = Inbound {
+                stream: Box::new(parts.io),
+                addr: peer_addr,
+                prefix: parts.read_buf.to_vec(),
+            };
+            info!(%peer_addr, %subdomain, node = %addr, "visitor from another node");
+            if sender.send(inbound).await.is_err() {
+                bail!("the tunnel for '{subdomain}' went away");
+            }
+            Ok(())
+        }
+        Some(PeerMsg::Accept(id)) => {
+            let Some(inbound) = state.take_pending(id) else {
+                bail!("Accept for unknown connection {id}");
+            };
+            let _in_flight = state.in_flight();
+            let buffer_size = state.config().buffer_size;
+            splice(inbound, peer, buffer_size).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Keep this node's claims alive while its tunnels are up.
+pub(crate) async fn renew_claims(state: Arc<State>) {
+    let Some(cluster) = &state.cluster else {
+        return;
+    };
+    loop {
+        sleep(CLAIM_TTL / 3).await;
+        for (subdomain, _) in state.registry.tunnels() {
+            if cluster.held_elsewhere(&subdomain).await {
+                warn!(%subdomain, "another node claimed a subdomain held here");
+            }
+        }
+    }
+}
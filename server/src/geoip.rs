@@ -0,0 +1,156 @@
+//! GeoIP tagging and country rules for visitors (`--geoip-db`).
+//!
+//! With MaxMind databases (GeoLite2 or GeoIP2 Country or City, and ASN),
+//! each visitor is tagged with its country and autonomous system in the
+//! server's log, in the `Connection` its client gets and in per-country
+//! counters on the admin API. [`CountryRules`] turn visitors away on accept,
+//! before anything is forwarded. Reading the databases takes the `geoip`
+//! feature.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+#[cfg(feature = "geoip")]
+use anyhow::{bail, Context};
+use serde::Serialize;
+
+/// Where a visitor comes from, as far as the databases know.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code, e.g. `DE`.
+    pub country: Option<String>,
+    /// Autonomous system number.
+    pub asn: Option<u32>,
+}
+
+/// Open MaxMind databases: one that knows countries, one that knows
+/// networks, or both.
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    countries: Option<maxminddb::Reader<Vec<u8>>>,
+    #[cfg(feature = "geoip")]
+    networks: Option<maxminddb::Reader<Vec<u8>>>,
+    paths: Vec<PathBuf>,
+}
+
+impl GeoIp {
+    /// Read the databases at `paths`, telling them apart by their type:
+    /// ASN and ISP databases give networks, the others countries.
+    #[cfg(feature = "geoip")]
+    pub fn open(paths: &[impl AsRef<Path>]) -> Result<Self> {
+        let mut geoip = Self {
+            countries: None,
+            networks: None,
+            paths: Vec::new(),
+        };
+        for path in paths {
+            let path = path.as_ref();
+            let reader = maxminddb::Reader::open_readfile(path)
+                .with_context(|| format!("cannot read the GeoIP database {}", path.display()))?;
+            let kind = &reader.metadata.database_type;
+            let slot = match kind.contains("ASN") || kind.contains("ISP") {
+                true => &mut geoip.networks,
+                false => &mut geoip.countries,
+            };
+            if slot.is_some() {
+                bail!("{} is a second {kind} database", path.display());
+            }
+            *slot = Some(reader);
+            geoip.paths.push(path.to_owned());
+        }
+        Ok(geoip)
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(_paths: &[impl AsRef<Path>]) -> Result<Self> {
+        anyhow::bail!("this sshx-server was built without GeoIP support (feature \"geoip\")")
+    }
+
+    /// Look `ip` up. Private and unknown addresses have no location.
+    pub fn locate(&self, ip: IpAddr) -> Location {
+        #[cfg(feature = "geoip")]
+        {
+            use maxminddb::geoip2;
+            let country = self
+                .countries
+                .as_ref()
+                .and_then(|db| db.lookup::<geoip2::Country>(ip).ok())
+                .and_then(|found| found.country?.iso_code)
+                .map(str::to_owned);
+            let asn = self
+                .networks
+                .as_ref()
+                .and_then(|db| db.lookup::<geoip2::Asn>(ip).ok())
+                .and_then(|found| found.autonomous_system_number);
+            Location { country, asn }
+        }
+        #[cfg(not(feature = "geoip"))]
+        {
+            let _ = ip;
+            Location::default()
+        }
+    }
+}
+
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIp").field("paths", &self.paths).finish()
+    }
+}
+
+/// Countries visitors may come from, by ISO 3166-1 alpha-2 code.
+#[derive(Debug, Clone, Default)]
+pub struct CountryRules {
+    /// Turned away.
+    pub block: Vec<String>,
+    /// The only ones let in, unless empty.
+    pub allow: Vec<String>,
+}
+
+impl CountryRules {
+    pub fn is_empty(&self) -> bool {
+        self.block.is_empty() && self.allow.is_empty()
+    }
+
+    /// Whether a visitor from `country` gets in. An address without a
+    /// country, such as a private one, passes a block list but not an allow
+    /// list.
+    pub fn permit(&self, country: Option<&str>) -> bool {
+        let listed = |list: &[String]| {
+            country.is_some_and(|country| list.iter().any(|c| c.eq_ignore_ascii_case(country)))
+        };
+        !listed(&self.block) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// Visitors seen and turned away since the start, by country; `??` for
+/// addresses without one.
+#[derive(Default)]
+pub(crate) struct CountryStats(Mutex<BTreeMap<String, Counts>>);
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct Counts {
+    visitors: u64,
+    blocked: u64,
+}
+
+impl CountryStats {
+    pub(crate) fn count(&self, country: Option<&str>, blocked: bool) {
+        let mut countries = self.0.lock().unwrap();
+        let counts = countries
+            .entry(country.unwrap_or("??").to_owned())
+            .or_default();
+        counts.visitors += 1;
+        counts.blocked += u64::from(blocked);
+    }
+
+    pub(crate) fn snapshot(&self) -> BTreeMap<String, Counts> {
+        self.0.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,477 @@
+//! Host-header routing: one public HTTP port shared by every HTTP tunnel.
+//!
+//! Only the request head of the first request is used for routing. After
+//! that the visitor's connection is handed to the tunnel and spliced like any
+//! other inbound connection, so keep-alive requests stay on the same tunnel;
+//! their heads are [relabeled](crate::relabel) on the way like the first.
+//!
+//! A host without a tunnel of its own goes to the closest wildcard tunnel
+//! above it: `a.b.preview` to `*.b.preview`, else to `*.preview`. The part the
+//! wildcard stood for (`a.b`) is passed on in [`SUBDOMAIN_HEADER`], in every
+//! request, and visitors can't set it themselves.
+//!
+//! A WebSocket upgrade of [`ws::CONTROL_PATH`] is not routed: it becomes a
+//! control connection, whatever the `Host`. Other upgrades (WebSocket, h2c)
+//! are routed like any request, and the bytes after them pass untouched.
+//!
+//! HTTP/2 with prior knowledge (h2c) starts with a preface instead of a
+//! request head, and its `Host` is the `:authority` of the first request's
+//! header block, read from the frames that follow. [`SUBDOMAIN_HEADER`] can't
+//! be added to it. gRPC tunnels take nothing else: HTTP/1.x visitors get a
+//! 505.
+//!
+//! Visitors that can't be routed get one of the
+//! [`ErrorPages`](crate::ErrorPages): no tunnel for the host, a tunnel whose
+//! client is away, or one with too many visitors waiting. In a cluster, a
+//! host whose tunnel is on another node is handed to that node instead.
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use sshx_core::{
+    doctor::{ROUTE_HEADER, TIME_HEADER},
+    h2,
+    protocol::Proto,
+    ws::{self, WsStream},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc::error::TrySendError,
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    budget::{Backoff, Counted},
+    pages::ErrorPage,
+    relabel::{Relabel, Relabeled},
+    server::{self, Inbound, Io, State},
+};
+
+/// Largest request head we buffer before giving up.
+pub(crate) const MAX_HEAD: usize = 16 * 1024;
+
+/// How long a visitor may take to send the request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request header naming the labels a wildcard tunnel matched.
+pub(crate) const SUBDOMAIN_HEADER: &str = "X-Sshx-Subdomain";
+
+/// Accept visitors on the shared HTTP port and route them to tunnels.
+pub(crate) async fn serve(listener: TcpListener, state: Arc<State>) {
+    let mut backoff = Backoff::default();
+    loop {
+        state.budget.ready().await;
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(err = %e, "HTTP accept failed");
+                backoff.failed().await;
+                continue;
+            }
+        };
+        backoff.succeeded();
+        if state.bans.is_ip_banned(addr.ip()) {
+            debug!(%addr, "dropping HTTP connection from banned IP");
+            continue;
+        }
+        if let Err(location) = state.admit_visitor(addr.ip()) {
+            let country = location.country;
+            debug!(%addr, ?country, "dropping HTTP connection by country");
+            continue;
+        }
+        let stream = Counted::new(stream, state.budget.take());
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = route(stream, addr, &state).await {
+                debug!(%addr, err = %e, "HTTP routing failed");
+            }
+        });
+    }
+}
+
+/// Read the request head and hand the visitor to the tunnel named by `Host`.
+pub(crate) async fn route<S>(mut stream: S, addr: SocketAddr, state: &Arc<State>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    let mut head = match timeout(HEAD_TIMEOUT, read_head(&mut stream)).await {
+        Ok(Ok(head)) => head,
+        Ok(Err(e)) => {
+            respond(&mut stream, 400, "Bad Request", "Malformed request.").await?;
+            return Err(e);
+        }
+        Err(_) => {
+            return respond(&mut stream, 408, "Request Timeout", "Request timed out.").await;
+        }
+    };
+
+    if is_control_upgrade(&head) {
+        return upgrade_control(stream, addr, head, state).await;
+    }
+    let http2 = head.starts_with(h2::PREFACE);
+    let host = match http2 {
+        true => match timeout(HEAD_TIMEOUT, h2_authority(&mut stream, &mut head)).await {
+            Ok(host) => host?,
+            Err(_) => bail!("timed out waiting for HTTP/2 request headers"),
+        },
+        false => match header(&head, "host") {
+            Some(host) => host.to_owned(),
+            None => {
+                return respond(&mut stream, 400, "Bad Request", "Missing Host header.").await;
+            }
+        },
+    };
+    let host = host.as_str();
+    let Some(label) = state.subdomain_for_host(host) else {
+        let message = format!("No tunnel is served at {host}.");
+        let page = ErrorPage::NotFound;
+        return refuse(&mut stream, state, http2, page, host, None, &message).await;
+    };
+    let (subdomain, matched) = resolve(state, &label).await;
+    let relabel = match http2 {
+        true => None,
+        false => {
+            let mut relabel = Relabel::new(matched);
+            let mut relabeled = Vec::with_capacity(head.len() + 64);
+            if let Err(e) = relabel.feed(&head, &mut relabeled) {
+                respond(&mut stream, 400, "Bad Request", "Malformed request.").await?;
+                return Err(e.into());
+            }
+            head = relabeled;
+            Some(relabel)
+        }
+    };
+    let tunnel = state
+        .registry
+        .tunnel(&subdomain)
+        .filter(|t| matches!(t.proto, Proto::Http))
+        .map(|t| (t.inbound.clone(), t.acl.permits(addr.ip()), t.grpc));
+    let Some((sender, permitted, grpc)) = tunnel else {
+        if let Some(cluster) = &state.cluster {
+            if let Some(node) = cluster.owner(&subdomain).await {
+                let inbound = Inbound {
+                    stream: relabeled(stream, relabel),
+                    addr,
+                    prefix: head,
+                };
+                let buffer_size = state.config().buffer_size;
+                return cluster
+                    .forward_visitor(&node, &subdomain, inbound, buffer_size)
+                    .await;
+            }
+        }
+        let (page, message) = match state.is_held(&subdomain) {
+            true => (
+                ErrorPage::Offline,
+                format!("The tunnel for '{subdomain}' is offline; its client is reconnecting."),
+            ),
+            false => (
+                ErrorPage::NotFound,
+                format!("No HTTP tunnel is registered for '{subdomain}'."),
+            ),
+        };
+        return refuse(
+            &mut stream,
+            state,
+            http2,
+            page,
+            host,
+            Some(&subdomain),
+            &message,
+        )
+        .await;
+    };
+    if !permitted {
+        debug!(%addr, %subdomain, "HTTP request denied by ACL");
+        if http2 {
+            return goaway(&mut stream).await;
+        }
+        let body = format!("Your address may not reach '{subdomain}'.");
+        return respond(&mut stream, 403, "Forbidden", &body).await;
+    }
+    // gRPC over HTTP/1.1 doesn't work; better to say so than to try.
+    if grpc && !http2 {
+        let body = format!("'{subdomain}' serves gRPC, which needs HTTP/2.");
+        return respond(&mut stream, 505, "HTTP Version Not Supported", &body).await;
+    }
+
+    let host = host.to_owned();
+    let inbound = Inbound {
+        stream: relabeled(stream, relabel),
+        addr,
+        prefix: head,
+    };
+    let (page, message, mut inbound) = match sender.try_send(inbound) {
+        Ok(()) => return Ok(()),
+        Err(TrySendError::Full(inbound)) => {
+            debug!(%addr, %subdomain, "too many connections waiting for the tunnel");
+            let message = format!("The tunnel for '{subdomain}' is busy; try again shortly.");
+            (ErrorPage::TooManyConnections, message, inbound)
+        }
+        Err(TrySendError::Closed(inbound)) => {
+            let message = format!("The tunnel for '{subdomain}' just went offline.");
+            (ErrorPage::Offline, message, inbound)
+        }
+    };
+    let stream = &mut inbound.stream;
+    refuse(
+        stream,
+        state,
+        http2,
+        page,
+        &host,
+        Some(&subdomain),
+        &message,
+    )
+    .await
+}
+
+/// The tunnel `label` belongs to: its own if there is one, here or on
+/// another node, else the closest wildcard one, with the labels the wildcard
+/// stands for.
+async fn resolve<'a>(state: &State, label: &'a str) -> (String, Option<&'a str>) {
+    let candidates: Vec<_> = std::iter::once((label.to_owned(), None))
+        .chain(
+            label
+                .match_indices('.')
+                .map(|(i, _)| (format!("*{}", &label[i..]), Some(&label[..i]))),
+        )
+        .collect();
+    let local = candidates
+        .iter()
+        .find(|(name, _)| state.registry.tunnel(name).is_some() || state.is_held(name));
+    if let Some(found) = local {
+        return found.clone();
+    }
+    if let Some(cluster) = &state.cluster {
+        for candidate in &candidates {
+            if cluster.owner(&candidate.0).await.is_some() {
+                return candidate.clone();
+            }
+        }
+    }
+    (label.to_owned(), None)
+}
+
+/// The visitor's stream as the tunnel reads it: with its request heads
+/// relabeled, unless it speaks HTTP/2.
+fn relabeled<S: Io>(stream: S, relabel: Option<Relabel>) -> Box<dyn Io> {
+    match relabel {
+        Some(relabel) => Box::new(Relabeled::new(stream, relabel)),
+        None => Box::new(stream),
+    }
+}
+
+/// Turn away a visitor of a tunnel at a connection limit. Routed visitors,
+/// whose request head has been read, get the too-many-connections page;
+/// others are disconnected.
+pub(crate) async fn turn_away(mut inbound: Inbound, state: &State, subdomain: &str) {
+    if inbound.prefix.is_empty() {
+        return;
+    }
+    if inbound.prefix.starts_with(h2::PREFACE) {
+        if let Err(e) = goaway(&mut inbound.stream).await {
+            debug!(addr = %inbound.addr, err = %e, "GOAWAY failed");
+        }
+        return;
+    }
+    let host = header(&inbound.prefix, "host")
+        .unwrap_or(subdomain)
+        .to_owned();
+    let message = format!("The tunnel for '{subdomain}' is busy; try again shortly.");
+    let page = ErrorPage::TooManyConnections;
+    if let Err(e) = respond_page(
+        &mut inbound.stream,
+        state,
+        page,
+        &host,
+        Some(subdomain),
+        &message,
+    )
+    .await
+    {
+        debug!(addr = %inbound.addr, err = %e, "busy page failed");
+    }
+}
+
+/// Whether the request asks for a control connection over WebSocket.
+fn is_control_upgrade(head: &[u8]) -> bool {
+    let target = head.split(|&b| b == b' ').nth(1).unwrap_or_default();
+    let path = target.split(|&b| b == b'?').next().unwrap_or_default();
+    path == ws::CONTROL_PATH.as_bytes()
+        && header(head, "upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Complete the WebSocket upgrade and serve a control connection on it.
+async fn upgrade_control<S>(
+    mut stream: S,
+    addr: SocketAddr,
+    head: Vec<u8>,
+    state: &Arc<State>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    let Some(key) = header(&head, "sec-websocket-key") else {
+        let body = "Missing Sec-WebSocket-Key header.";
+        return respond(&mut stream, 400, "Bad Request", body).await;
+    };
+    // Clients wait for our answer before they send anything else.
+    let end = head.windows(4).position(|w| w == b"\r\n\r\n").unwrap_or(0) + 4;
+    if head.len() > end {
+        bail!("data sent before the WebSocket upgrade completed");
+    }
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        ws::accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    debug!(%addr, "control connection over WebSocket");
+    let stream = WsStream::accepted(stream).await;
+    if let Err(e) = server::handle_control(stream, addr, Arc::clone(state)).await {
+        warn!(%addr, err = %e, "connection error");
+    }
+    Ok(())
+}
+
+/// Read until the end of the request head. Returns everything read so far,
+/// which may include the start of the body.
+pub(crate) async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(buf);
+        }
+        if buf.len() >= MAX_HEAD {
+            bail!("request head larger than {MAX_HEAD} bytes");
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            bail!("connection closed before end of request head");
+        }
+    }
+}
+
+/// Read on from the HTTP/2 preface at the start of `buf` until the header
+/// block of the first request is complete, and return its `:authority`.
+/// Everything read is kept in `buf`, to be passed on as it came.
+async fn h2_authority(stream: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>) -> Result<String> {
+    let mut reader = h2::Reader::requests();
+    let mut events = Vec::new();
+    let mut fed = 0;
+    loop {
+        reader.feed(&buf[fed..], &mut events)?;
+        fed = buf.len();
+        if let Some(first) = events.first() {
+            return first
+                .header(":authority")
+                .or_else(|| first.header("host"))
+                .context("HTTP/2 request without :authority");
+        }
+        if buf.len() >= MAX_HEAD {
+            bail!("HTTP/2 request headers larger than {MAX_HEAD} bytes");
+        }
+        if stream.read_buf(buf).await? == 0 {
+            bail!("connection closed before the HTTP/2 request headers");
+        }
+    }
+}
+
+/// Value of the first header called `name` (case-insensitive).
+pub(crate) fn header<'a>(head: &'a [u8], name: &str) -> Option<&'a str> {
+    let head = std::str::from_utf8(head).ok()?;
+    head.split("\r\n")
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+/// Answer with `page`, as the operator may have customised it. The headers
+/// name the `subdomain` the host routed to, if any, and our clock, for
+/// `sshx doctor` and `sshx-server check-dns`.
+async fn respond_page(
+    stream: &mut (impl AsyncWrite + Unpin),
+    state: &State,
+    page: ErrorPage,
+    host: &str,
+    subdomain: Option<&str>,
+    message: &str,
+) -> Result<()> {
+    let (status, reason) = page.status();
+    let body = state.config().error_pages.render(page, host, message);
+    let route = subdomain.map_or(String::new(), |s| format!("{ROUTE_HEADER}: {s}\r\n"));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         {route}{TIME_HEADER}: {now}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// [`respond_page`], or for HTTP/2 visitors, who can't read it, [`goaway`].
+async fn refuse(
+    stream: &mut (impl AsyncWrite + Unpin),
+    state: &State,
+    http2: bool,
+    page: ErrorPage,
+    host: &str,
+    subdomain: Option<&str>,
+    message: &str,
+) -> Result<()> {
+    if http2 {
+        debug!(host, "{message}");
+        return goaway(stream).await;
+    }
+    respond_page(stream, state, page, host, subdomain, message).await
+}
+
+/// Turn an HTTP/2 visitor away: empty settings as our preface, then GOAWAY
+/// with REFUSED_STREAM, which tells the client nothing was processed.
+async fn goaway(stream: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+    const FRAMES: [u8; 26] = [
+        0, 0, 0, 0x4, 0, 0, 0, 0, 0, // SETTINGS
+        0, 0, 8, 0x7, 0, 0, 0, 0, 0, // GOAWAY
+        0, 0, 0, 0, 0, 0, 0, 0x7, // last stream 0, REFUSED_STREAM
+    ];
+    stream.write_all(&FRAMES).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn respond(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}\n",
+        body.len() + 1
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
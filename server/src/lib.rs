@@ -0,0 +1,36 @@
+//! sshx-server library — the tunnel relay, embeddable in other applications.
+//!
+//! The `sshx-server` binary is a thin CLI over [`Server`]. Embedders can swap
+//! the shared-secret check for their own [`auth::AuthProvider`].
+
+mod admin;
+pub mod audit;
+pub mod auth;
+pub mod bans;
+mod budget;
+pub mod cluster;
+pub mod geoip;
+mod http;
+pub mod mdns;
+mod names;
+mod pages;
+mod quic;
+mod registry;
+mod relabel;
+mod server;
+pub mod systemd;
+mod tarpit;
+mod throttle;
+mod tls;
+pub mod tokens;
+mod traffic;
+mod warm;
+mod webhooks;
+
+pub use budget::ConnLimits;
+pub use pages::ErrorPages;
+pub use server::{Config, Reload, Server, MAX_PENDING_PER_TUNNEL};
+pub use tarpit::{Tarpit, TarpitMode};
+pub use throttle::AuthLimits;
+pub use tls::{ControlTlsConfig, TlsConfig};
+pub use webhooks::Webhook;
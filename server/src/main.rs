@@ -1,203 +1,1081 @@
 //! sshx-server — accepts client registrations and proxies inbound connections.
 
-mod auth;
-mod shared;
-
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    collections::BTreeMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
     sync::Arc,
-    time::Duration,
+    time::{Duration, UNIX_EPOCH},
 };
 
-use anyhow::Result;
-use auth::Auth;
-use clap::Parser;
-use dashmap::DashMap;
-use shared::{ClientMsg, Framed_, Proto, ServerMsg, CONTROL_PORT};
-use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpListener, TcpStream},
-    time::{sleep, timeout},
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use sshx_core::{
+    doctor::{self, Check, Report},
+    protocol::{
+        Timeouts, BUFFER_SIZE, CONTROL_PORT, MAX_MISSED_HEARTBEATS, STATS_INTERVAL,
+        TLS_CONTROL_PORT,
+    },
+};
+use sshx_server::{
+    audit::{self, AuditLog, Rotation},
+    auth::{Auth, AuthProvider, Identity, Secrets},
+    bans::{BanList, BanTarget},
+    cluster::{ClusterConfig, Store, PEER_PORT},
+    geoip::{CountryRules, GeoIp},
+    mdns,
+    systemd::{self, Notify},
+    tokens::Tokens,
+    AuthLimits, Config, ConnLimits, ControlTlsConfig, ErrorPages, Reload, Server, Tarpit,
+    TarpitMode, TlsConfig, Webhook, MAX_PENDING_PER_TUNNEL,
 };
-use tracing::{info, warn};
-use uuid::Uuid;
+use tracing_subscriber::EnvFilter;
 
 // ── CLI ───────────────────────────────────────────────────────────────────────
 
 #[derive(Parser)]
-#[command(name = "sshx-server", about = "sshx tunnel server")]
+#[command(
+    name = "sshx-server",
+    about = "sshx tunnel server",
+    args_conflicts_with_subcommands = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file of settings that can change without a restart: it is read
+    /// again on SIGHUP or `POST /reload` to the admin API. Flags win over it.
+    #[arg(long, env = "SSHX_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// How log lines are written: `json` emits one object per line with the
+    /// tunnel, connection and byte counts as fields, for Loki or ELK.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, env = "SSHX_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Log filter, e.g. `debug` or `info,sshx_server=trace`. Falls back to
+    /// `RUST_LOG`, then `info`.
+    #[arg(long, env = "SSHX_LOG_LEVEL")]
+    log_level: Option<String>,
+
     /// Secret clients must know (optional).
     #[arg(long, short, env = "SSHX_SECRET")]
     secret: Option<String>,
 
-    /// Minimum port for tunnels.
-    #[arg(long, default_value_t = 2000, env = "SSHX_MIN_PORT")]
-    min_port: u16,
+    /// Minimum port for tunnels [default: 2000].
+    #[arg(long, env = "SSHX_MIN_PORT")]
+    min_port: Option<u16>,
 
-    /// Maximum port for tunnels.
-    #[arg(long, default_value_t = 65000, env = "SSHX_MAX_PORT")]
-    max_port: u16,
+    /// Maximum port for tunnels [default: 65000].
+    #[arg(long, env = "SSHX_MAX_PORT")]
+    max_port: Option<u16>,
 
-    /// Bind address.
-    #[arg(long, default_value = "0.0.0.0", env = "SSHX_BIND")]
-    bind: IpAddr,
-}
+    /// Address to bind every listener on (repeatable). IPv6 addresses take
+    /// IPv6 only; pass `--bind 0.0.0.0 --bind ::` for dual stack.
+    #[arg(
+        long,
+        default_value = "0.0.0.0",
+        value_delimiter = ',',
+        env = "SSHX_BIND"
+    )]
+    bind: Vec<IpAddr>,
+
+    /// Shared port for HTTP tunnels, routed by Host header (e.g. 80).
+    #[arg(long, env = "SSHX_HTTP_PORT")]
+    http_port: Option<u16>,
+
+    /// Base domain of tunnel hostnames (e.g. tunnel.example.com).
+    #[arg(long, env = "SSHX_DOMAIN")]
+    domain: Option<String>,
+
+    /// Also accept clients over TLS on --tls-control-port.
+    #[arg(long, env = "SSHX_TLS")]
+    tls: bool,
+
+    /// Port of the TLS control listener.
+    #[arg(long, default_value_t = TLS_CONTROL_PORT, env = "SSHX_TLS_CONTROL_PORT")]
+    tls_control_port: u16,
+
+    /// Also accept the QUIC transport (`sshx --transport quic`) on UDP at
+    /// --tls-control-port. Needs --tls.
+    #[arg(long, env = "SSHX_QUIC", requires = "tls")]
+    quic: bool,
+
+    /// PEM certificate chain for the TLS control port (default: self-signed).
+    #[arg(long, env = "SSHX_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching --tls-cert.
+    #[arg(long, env = "SSHX_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Contact email for the ACME account; enables HTTPS for HTTP tunnels.
+    #[arg(long, env = "SSHX_TLS_EMAIL")]
+    tls_email: Option<String>,
+
+    /// Extra hostname to certify at startup (repeatable); enables HTTPS.
+    #[arg(long, env = "SSHX_TLS_DOMAIN", value_delimiter = ',')]
+    tls_domain: Vec<String>,
+
+    /// HTTPS port. ACME validation needs it reachable as 443.
+    #[arg(long, default_value_t = 443, env = "SSHX_TLS_PORT")]
+    tls_port: u16,
+
+    /// Directory where ACME and self-signed certificates are stored.
+    #[arg(long, default_value = "sshx-certs", env = "SSHX_TLS_CERT_DIR")]
+    tls_cert_dir: PathBuf,
+
+    /// Use the Let's Encrypt staging directory (untrusted test certificates).
+    #[arg(long, env = "SSHX_TLS_STAGING")]
+    tls_staging: bool,
+
+    /// Tunnel hostnames to order certificates for per week; later tunnels
+    /// are served over HTTP only.
+    #[arg(long, default_value_t = 40, env = "SSHX_TLS_MAX_ORDERS")]
+    tls_max_orders: usize,
+
+    /// JSON file that persists IP and subdomain bans.
+    #[arg(long, env = "SSHX_BAN_FILE")]
+    ban_file: Option<PathBuf>,
+
+    /// JSON file of settings pushed to every client (re-read when it changes).
+    #[arg(long, env = "SSHX_CLIENT_SETTINGS")]
+    client_settings: Option<PathBuf>,
+
+    /// Serve the admin HTTP API here (e.g. 127.0.0.1:7836). Unauthenticated.
+    #[arg(long, env = "SSHX_ADMIN_BIND")]
+    admin_bind: Option<SocketAddr>,
+
+    /// Bytes/sec each tunnel may carry in each direction, e.g. 500K or 10M.
+    #[arg(long, env = "SSHX_MAX_BANDWIDTH", value_parser = parse_rate)]
+    max_bandwidth: Option<u64>,
+
+    /// Another accepted secret with its own bandwidth, as SECRET=RATE
+    /// (repeatable). RATE 0 means unlimited.
+    #[arg(
+        long,
+        env = "SSHX_SECRET_BANDWIDTH",
+        value_delimiter = ',',
+        value_parser = parse_secret_rate,
+        hide_env_values = true
+    )]
+    secret_bandwidth: Vec<(String, u64)>,
+
+    /// Bytes copied at a time between a visitor and its client, in each
+    /// direction, e.g. 256Ki (default 8Ki). Bigger speeds up large
+    /// transfers, at that much memory twice per connection.
+    #[arg(long, env = "SSHX_BUFFER_SIZE", value_parser = parse_buffer_size)]
+    buffer_size: Option<usize>,
+
+    /// TOML file of per-user tokens, with the subdomains and ports each may
+    /// use (re-read when it changes). --secret keeps working alongside.
+    #[arg(long, env = "SSHX_TOKENS_FILE")]
+    tokens_file: Option<PathBuf>,
+
+    /// Seconds to let visitor connections finish on SIGTERM or Ctrl-C.
+    #[arg(long, default_value_t = 30, env = "SSHX_DRAIN_TIMEOUT")]
+    drain_timeout: u64,
+
+    /// Close tunnels that had no visitor connection for this long, e.g. "30m".
+    #[arg(long, env = "SSHX_IDLE_TIMEOUT", value_parser = humantime::parse_duration)]
+    idle_timeout: Option<Duration>,
+
+    /// Close tunnels this long after they registered, e.g. "24h".
+    #[arg(long, env = "SSHX_MAX_TUNNEL_LIFETIME", value_parser = humantime::parse_duration)]
+    max_tunnel_lifetime: Option<Duration>,
+
+    /// Hold the subdomain and port of a client that lost its connection for
+    /// this long, so it gets them back when it reconnects, e.g. "5m".
+    #[arg(long, env = "SSHX_RESERVATION_GRACE", value_parser = humantime::parse_duration)]
+    reservation_grace: Option<Duration>,
+
+    /// Directory of HTML pages for HTTP visitors that can't reach a tunnel:
+    /// not-found.html, offline.html and too-many-connections.html. Pages
+    /// without a file keep the built-in version.
+    #[arg(long, env = "SSHX_ERROR_PAGES_DIR")]
+    error_pages_dir: Option<PathBuf>,
+
+    /// Visitor connections one tunnel may have open at once; more are turned
+    /// away.
+    #[arg(long, env = "SSHX_MAX_CONNS_PER_TUNNEL")]
+    max_conns_per_tunnel: Option<usize>,
+
+    /// Open connections, across the control, tunnel and HTTP(S) ports, at
+    /// which the server stops accepting more until some close, so a flood
+    /// can't run it out of file descriptors. Keep it under `ulimit -n`.
+    #[arg(long, env = "SSHX_MAX_OPEN_CONNS")]
+    max_open_conns: Option<NonZeroUsize>,
+
+    /// Open connections at or under which accepting resumes after
+    /// --max-open-conns was reached [default: 90% of it].
+    #[arg(long, env = "SSHX_RESUME_OPEN_CONNS", requires = "max_open_conns")]
+    resume_open_conns: Option<usize>,
+
+    /// Visitor connections, across all tunnels, that may wait for their
+    /// client to pick them up; more are turned away.
+    #[arg(long, env = "SSHX_MAX_PENDING")]
+    max_pending: Option<usize>,
+
+    /// Visitor connections one tunnel may have waiting for its client to
+    /// pick them up (default 64); more are turned away.
+    #[arg(long, env = "SSHX_MAX_PENDING_PER_TUNNEL")]
+    max_pending_per_tunnel: Option<NonZeroUsize>,
 
-// ── State ─────────────────────────────────────────────────────────────────────
+    /// Drop clients that leave this many heartbeats in a row unanswered
+    /// (default 10) over at least --heartbeat-timeout. Clients from before
+    /// protocol version 4 don't answer and are never dropped this way.
+    #[arg(long, env = "SSHX_MAX_MISSED_HEARTBEATS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_missed_heartbeats: Option<u32>,
 
-struct State {
-    /// subdomain → port mapping (so names are unique).
-    subdomains: DashMap<String, u16>,
-    /// pending inbound connections waiting for client Accept.
-    pending: DashMap<Uuid, TcpStream>,
-    auth: Option<Auth>,
-    min_port: u16,
-    max_port: u16,
-    bind: IpAddr,
+    /// How often clients get a heartbeat, e.g. "5s" (default 500ms). Clients
+    /// may ask for fewer; a --client-settings file overrides it.
+    #[arg(long, env = "SSHX_HEARTBEAT_INTERVAL", value_parser = humantime::parse_duration)]
+    heartbeat_interval: Option<Duration>,
+
+    /// How long a client may leave heartbeats unanswered before it is
+    /// dropped, e.g. "1m" (default 5s). Also the idle time before TCP
+    /// keepalive probes start on control and data connections.
+    #[arg(long, env = "SSHX_HEARTBEAT_TIMEOUT", value_parser = humantime::parse_duration)]
+    heartbeat_timeout: Option<Duration>,
+
+    /// How long clients may take over each step of their handshake: TLS,
+    /// auth and registering, e.g. "30s" (default 5s).
+    #[arg(long, env = "SSHX_HANDSHAKE_TIMEOUT", value_parser = humantime::parse_duration)]
+    handshake_timeout: Option<Duration>,
+
+    /// How often clients are told the traffic of their tunnels, e.g. "1s"
+    /// (default 5s).
+    #[arg(long, env = "SSHX_STATS_INTERVAL", value_parser = humantime::parse_duration)]
+    stats_interval: Option<Duration>,
+
+    /// A host:port that clients may pull with `sshx pull`, connected to from
+    /// this server (repeatable). Nothing can be pulled by default.
+    #[arg(long, value_delimiter = ',', env = "SSHX_ALLOW_PULL")]
+    allow_pull: Vec<String>,
+
+    /// Also accept the visitors of TCP and HTTP tunnels on a Unix socket in
+    /// this directory, named `<subdomain>.sock`, e.g. for nginx on this host.
+    #[arg(long, env = "SSHX_UNIX_SOCKET_DIR")]
+    unix_socket_dir: Option<PathBuf>,
+
+    /// Auth handshakes one IP may start per minute; more are dropped.
+    #[arg(long, env = "SSHX_AUTH_ATTEMPTS_PER_MINUTE")]
+    auth_attempts_per_minute: Option<u32>,
+
+    /// Failed auth attempts in a row after which an IP is banned (0: never).
+    #[arg(long, env = "SSHX_AUTH_MAX_FAILURES")]
+    auth_max_failures: Option<u32>,
+
+    /// How long an IP banned for failed auth attempts stays banned, e.g. "1h".
+    #[arg(long, env = "SSHX_AUTH_BAN_DURATION", value_parser = humantime::parse_duration)]
+    auth_ban_duration: Option<Duration>,
+
+    /// Refuse clients that don't seal their Hello to the auth handshake, so
+    /// a man in the middle can't swap a registration. Clients older than
+    /// seals can't register then.
+    #[arg(long, env = "SSHX_REQUIRE_SEALED_HELLO")]
+    require_sealed_hello: bool,
+
+    /// POST a JSON event to this http(s):// URL whenever a tunnel is
+    /// registered or closed, a client fails to authenticate or a token
+    /// exceeds its quota, e.g. for an audit log.
+    #[arg(long, env = "SSHX_WEBHOOK")]
+    webhook: Option<Webhook>,
+
+    /// MaxMind database to look visitors up in (repeatable): GeoLite2 or
+    /// GeoIP2 Country or City for their country, ASN for their network.
+    /// Needs the `geoip` feature.
+    #[arg(long, env = "SSHX_GEOIP_DB", value_delimiter = ',')]
+    geoip_db: Vec<PathBuf>,
+
+    /// Turn away visitors from these countries, by ISO code, e.g. `KP,RU`.
+    /// Needs --geoip-db.
+    #[arg(long, env = "SSHX_BLOCK_COUNTRY", value_delimiter = ',')]
+    block_country: Vec<String>,
+
+    /// Only let visitors in from these countries; addresses of no country,
+    /// like private ones, are turned away too. Needs --geoip-db.
+    #[arg(long, env = "SSHX_ALLOW_COUNTRY", value_delimiter = ',')]
+    allow_country: Vec<String>,
+
+    /// Append tunnels registered and closed, auth attempts, exceeded quotas
+    /// and admin API actions to this file as JSON lines, each chained to the
+    /// one before by its SHA-256. Check it with `verify-audit-log`.
+    #[arg(long, env = "SSHX_AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+
+    /// Move the audit log to `<file>.1` before it grows past this size,
+    /// e.g. 100M (default: never).
+    #[arg(long, env = "SSHX_AUDIT_LOG_MAX_SIZE", value_parser = parse_rate, requires = "audit_log")]
+    audit_log_max_size: Option<u64>,
+
+    /// Rotated audit logs kept, oldest deleted first (default 5).
+    #[arg(long, env = "SSHX_AUDIT_LOG_KEEP", requires = "audit_log")]
+    audit_log_keep: Option<usize>,
+
+    /// Answer connections to the control ports that send garbage, or fail
+    /// auth again, with a `reset` or a slow `drip` of bytes instead of
+    /// logging them; IPs that keep at it are dropped on accept.
+    #[arg(long, env = "SSHX_TARPIT")]
+    tarpit: Option<TarpitMode>,
+
+    /// Offenses after which the tarpit drops an IP's connections on accept
+    /// (default 3).
+    #[arg(long, env = "SSHX_TARPIT_DROP_AFTER", value_parser = clap::value_parser!(u32).range(1..))]
+    tarpit_drop_after: Option<u32>,
+
+    /// Offending IPs the tarpit remembers, least recent forgotten first
+    /// (default 1024).
+    #[arg(long, env = "SSHX_TARPIT_TRACKED")]
+    tarpit_tracked: Option<NonZeroUsize>,
+
+    /// JSON file of registered tunnels. After a restart, their clients get
+    /// their subdomains and ports back for --reservation-grace (default 5m).
+    #[arg(long, env = "SSHX_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Tell systemd (Type=notify) when the server is ready and stopping, and
+    /// pet its watchdog if WatchdogSec= is set. The control port may also
+    /// come from a systemd socket unit, with or without this.
+    #[arg(long, env = "SSHX_SYSTEMD_NOTIFY")]
+    systemd_notify: bool,
+
+    /// Announce the server on the local network over mDNS, for clients
+    /// started with `--server auto`.
+    #[arg(long, env = "SSHX_MDNS")]
+    mdns: bool,
+
+    /// Name to announce over mDNS [default: the host name].
+    #[arg(long, env = "SSHX_MDNS_NAME", requires = "mdns")]
+    mdns_name: Option<String>,
+
+    /// Run as a node of a cluster: the address other nodes reach this one's
+    /// peer port at, e.g. 10.0.0.2:12269. Needs --redis-url.
+    #[arg(long, env = "SSHX_CLUSTER_NODE", requires = "redis_url")]
+    cluster_node: Option<String>,
+
+    /// Where to listen for other nodes of the cluster.
+    #[arg(
+        long,
+        default_value_t = SocketAddr::from(([0, 0, 0, 0], PEER_PORT)),
+        env = "SSHX_CLUSTER_BIND"
+    )]
+    cluster_bind: SocketAddr,
+
+    /// Secret nodes prove to each other (default: --secret). Required unless
+    /// --cluster-bind is a loopback address.
+    #[arg(long, env = "SSHX_CLUSTER_SECRET", hide_env_values = true)]
+    cluster_secret: Option<String>,
+
+    /// Redis server the nodes of the cluster share, e.g. redis://10.0.0.9/0.
+    #[arg(long, env = "SSHX_REDIS_URL", requires = "cluster_node")]
+    redis_url: Option<String>,
 }
 
-impl State {
-    fn new(min_port: u16, max_port: u16, bind: IpAddr, secret: Option<&str>) -> Arc<Self> {
-        Arc::new(Self {
-            subdomains: DashMap::new(),
-            pending: DashMap::new(),
-            auth: secret.map(Auth::new),
-            min_port,
-            max_port,
-            bind,
-        })
-    }
+/// Settings of the `--config` file, named like the flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    secret: Option<String>,
+    min_port: Option<u16>,
+    max_port: Option<u16>,
+    /// e.g. "10M".
+    max_bandwidth: Option<String>,
+    /// Other accepted secrets and their bandwidth.
+    #[serde(default)]
+    secret_bandwidth: BTreeMap<String, String>,
+    /// e.g. "256Ki".
+    buffer_size: Option<String>,
+    tokens_file: Option<PathBuf>,
+    /// e.g. "30m".
+    idle_timeout: Option<String>,
+    max_tunnel_lifetime: Option<String>,
+    reservation_grace: Option<String>,
+    error_pages_dir: Option<PathBuf>,
+    max_conns_per_tunnel: Option<usize>,
+    max_pending: Option<usize>,
+    max_pending_per_tunnel: Option<usize>,
+    max_missed_heartbeats: Option<u32>,
+    /// e.g. "5s".
+    heartbeat_interval: Option<String>,
+    heartbeat_timeout: Option<String>,
+    handshake_timeout: Option<String>,
+    stats_interval: Option<String>,
+    #[serde(default)]
+    allow_pull: Vec<String>,
+    unix_socket_dir: Option<PathBuf>,
+    auth_attempts_per_minute: Option<u32>,
+    auth_max_failures: Option<u32>,
+    /// e.g. "1h".
+    auth_ban_duration: Option<String>,
+    #[serde(default)]
+    require_sealed_hello: bool,
+    webhook: Option<Webhook>,
+    #[serde(default)]
+    geoip_db: Vec<PathBuf>,
+    /// e.g. ["KP", "RU"].
+    #[serde(default)]
+    block_country: Vec<String>,
+    #[serde(default)]
+    allow_country: Vec<String>,
+    /// "reset" or "drip".
+    tarpit: Option<TarpitMode>,
+    tarpit_drop_after: Option<u32>,
+    tarpit_tracked: Option<usize>,
+}
 
-    /// Try to bind a listener for the given subdomain.
-    async fn claim_port(&self, subdomain: &str, _proto: Proto) -> Result<TcpListener, String> {
-        if self.subdomains.contains_key(subdomain) {
-            return Err(format!("subdomain '{}' is already taken", subdomain));
-        }
-        // Try 150 random ports (same probabilistic argument as bore).
-        for _ in 0..150 {
-            let port = fastrand::u16(self.min_port..=self.max_port);
-            match TcpListener::bind((self.bind, port)).await {
-                Ok(l) => {
-                    self.subdomains.insert(subdomain.to_owned(), port);
-                    return Ok(l);
-                }
-                Err(_) => continue,
-            }
-        }
-        Err("no free ports available".into())
+impl FileConfig {
+    fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let text =
+            fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
     }
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect or edit the persistent ban list.
+    Bans {
+        /// Ban file to operate on.
+        #[arg(long, env = "SSHX_BAN_FILE")]
+        ban_file: PathBuf,
+
+        #[command(subcommand)]
+        action: BanAction,
+    },
+    /// Check that DNS, the control port and HTTP routing are set up for
+    /// --domain, and print what to fix. Run it with the server up.
+    CheckDns {
+        /// Base domain of tunnel hostnames, as given to the server.
+        #[arg(long, env = "SSHX_DOMAIN")]
+        domain: String,
+
+        /// Shared HTTP port the server routes by Host header.
+        #[arg(long, default_value_t = 80, env = "SSHX_HTTP_PORT")]
+        http_port: u16,
+
+        /// The server's public address, if DNS should point somewhere other
+        /// than where --domain resolves now (repeatable).
+        #[arg(long, value_delimiter = ',')]
+        public_ip: Vec<IpAddr>,
+    },
+    /// Check that no record of an audit log and its rotated files was
+    /// changed, added or removed, short of the newest ones.
+    VerifyAuditLog {
+        /// The --audit-log file.
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum BanAction {
+    /// List active bans.
+    List {
+        #[arg(long, default_value_t = 1)]
+        page: usize,
+        #[arg(long, default_value_t = 20)]
+        per_page: usize,
+    },
+    /// Ban an IP address or subdomain.
+    Add {
+        /// IP address or subdomain.
+        target: BanTarget,
+        #[arg(long, default_value = "")]
+        reason: String,
+        /// How long the ban lasts, e.g. "30m" or "7d" (default: forever).
+        #[arg(long, value_parser = humantime::parse_duration)]
+        ttl: Option<Duration>,
+    },
+    /// Lift a ban.
+    Remove {
+        /// IP address or subdomain.
+        target: BanTarget,
+    },
+}
+
 // ── Entry point ───────────────────────────────────────────────────────────────
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
     let cli = Cli::parse();
+    init_logging(cli.log_format, cli.log_level.as_deref())?;
 
-    let state = State::new(cli.min_port, cli.max_port, cli.bind, cli.secret.as_deref());
-    let listener = TcpListener::bind((cli.bind, CONTROL_PORT)).await?;
-    info!(addr = %cli.bind, port = CONTROL_PORT, "sshx-server listening");
+    match cli.command {
+        Some(Command::Bans { ban_file, action }) => {
+            return manage_bans(BanList::load(ban_file)?, action);
+        }
+        Some(Command::CheckDns {
+            domain,
+            http_port,
+            public_ip,
+        }) => return check_dns(&domain, http_port, &public_ip).await,
+        Some(Command::VerifyAuditLog { path }) => return verify_audit_log(&path),
+        None => {}
+    }
 
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        let state = Arc::clone(&state);
-        tokio::spawn(async move {
-            if let Err(e) = handle_control(stream, state).await {
-                warn!(%addr, err = %e, "connection error");
-            }
-        });
+    let activated = systemd::listeners()?;
+    let Reload { config, auth } = settings(&cli)?;
+    if config.tls.is_some() && config.domain.is_none() {
+        tracing::warn!("HTTPS without --domain only serves the --tls-domain hostnames");
+    }
+    let notify = match cli.systemd_notify {
+        true => match Notify::from_env() {
+            Some(notify) => Some(notify),
+            None => bail!("--systemd-notify needs NOTIFY_SOCKET; run it as a Type=notify service"),
+        },
+        false => None,
+    };
+    let stopping = notify.clone();
+    let signal = async move {
+        shutdown_signal().await;
+        if let Some(notify) = stopping {
+            notify.tell("STOPPING=1");
+        }
+    };
+    // Withdrawn when main returns.
+    let _announcement = match cli.mdns {
+        true => Some(mdns::announce(&config, cli.mdns_name.as_deref())?),
+        false => None,
+    };
+    let mut server = Server::new(config).with_shutdown(signal);
+    if let Some(notify) = notify {
+        tokio::spawn(notify.clone().watchdog());
+        server = server.with_ready(move || notify.tell("READY=1"));
+    }
+    if let Some(auth) = auth {
+        server = server.with_auth(auth);
+    }
+    if let Some(path) = cli.ban_file.clone() {
+        server = server.with_bans(BanList::load(path)?);
+    }
+    if let Some(path) = cli.client_settings.clone() {
+        server = server.with_client_settings(path);
+    }
+    if let Some(path) = cli.state_file.clone() {
+        server = server.with_state_file(path);
+    }
+    if let Some(path) = cli.audit_log.clone() {
+        let defaults = Rotation::default();
+        let rotation = Rotation {
+            max_size: cli.audit_log_max_size,
+            keep: cli.audit_log_keep.unwrap_or(defaults.keep),
+        };
+        server = server.with_audit_log(AuditLog::open(path, rotation)?);
+    }
+    if let (Some(node_addr), Some(url)) = (cli.cluster_node.clone(), cli.redis_url.as_deref()) {
+        let config = ClusterConfig {
+            node_addr,
+            bind: cli.cluster_bind,
+            secret: cli.cluster_secret.clone().or_else(|| cli.secret.clone()),
+        };
+        server = server.with_cluster(config, redis_store(url).await?);
     }
+    let server = server.with_reload(move || settings(&cli));
+    if activated.is_empty() {
+        return server.listen().await;
+    }
+    let addrs: Vec<_> = activated
+        .iter()
+        .filter_map(|l| l.local_addr().ok())
+        .collect();
+    tracing::info!(?addrs, "sshx-server listening on sockets from systemd");
+    server.serve_all(activated).await
 }
 
-// ── Control connection handler ────────────────────────────────────────────────
+/// The cluster store at `--redis-url`.
+#[cfg(feature = "redis")]
+async fn redis_store(url: &str) -> Result<Arc<dyn Store>> {
+    Ok(Arc::new(
+        sshx_server::cluster::RedisStore::connect(url).await?,
+    ))
+}
 
-async fn handle_control(stream: TcpStream, state: Arc<State>) -> Result<()> {
-    let mut ctrl = Framed_::new(stream);
+#[cfg(not(feature = "redis"))]
+async fn redis_store(_url: &str) -> Result<Arc<dyn Store>> {
+    bail!("this sshx-server was built without Redis support (feature \"redis\")")
+}
 
-    // Auth (optional).
-    if let Some(auth) = &state.auth {
-        if let Err(e) = auth.handshake_server(&mut ctrl).await {
-            ctrl.send(ServerMsg::Error(e.to_string())).await?;
-            return Ok(());
+/// The server's settings: flags first, then the `--config` file.
+fn settings(cli: &Cli) -> Result<Reload> {
+    let file = FileConfig::load(cli.config.as_deref())?;
+    let duration = |flag: Option<Duration>, key: &str, value: &Option<String>| match flag {
+        Some(flag) => Ok(Some(flag)),
+        None => value
+            .as_deref()
+            .map(|v| humantime::parse_duration(v).with_context(|| format!("invalid {key}")))
+            .transpose(),
+    };
+    let rate =
+        |key: &str, value: &str| parse_rate(value).map_err(|e| anyhow!("invalid {key}: {e}"));
+
+    let domain = cli
+        .domain
+        .as_deref()
+        .map(|d| d.trim_matches('.').to_ascii_lowercase());
+    let control_tls = cli.tls.then(|| ControlTlsConfig {
+        port: cli.tls_control_port,
+        cert: cli.tls_cert.clone().zip(cli.tls_key.clone()),
+        cert_dir: cli.tls_cert_dir.clone(),
+        names: domain
+            .iter()
+            .cloned()
+            .chain(["localhost".into(), "127.0.0.1".into()])
+            .collect(),
+        quic: cli.quic,
+    });
+    let https = cli.tls_email.is_some() || !cli.tls_domain.is_empty();
+    let tls = https.then(|| TlsConfig {
+        port: cli.tls_port,
+        domains: cli.tls_domain.clone(),
+        email: cli.tls_email.clone(),
+        cert_dir: cli.tls_cert_dir.clone(),
+        production: !cli.tls_staging,
+        max_orders: cli.tls_max_orders,
+    });
+    let error_pages = match cli
+        .error_pages_dir
+        .as_ref()
+        .or(file.error_pages_dir.as_ref())
+    {
+        Some(dir) => ErrorPages::load(dir)?,
+        None => ErrorPages::default(),
+    };
+    let max_bandwidth = match (cli.max_bandwidth, &file.max_bandwidth) {
+        (Some(flag), _) => Some(flag),
+        (None, value) => value
+            .as_deref()
+            .map(|v| rate("max_bandwidth", v))
+            .transpose()?,
+    };
+    let buffer_size = match (cli.buffer_size, &file.buffer_size) {
+        (Some(flag), _) => flag,
+        (None, Some(value)) => {
+            parse_buffer_size(value).map_err(|e| anyhow!("invalid buffer_size: {e}"))?
+        }
+        (None, None) => BUFFER_SIZE,
+    };
+    let defaults = AuthLimits::default();
+    let auth_limits = AuthLimits {
+        attempts_per_minute: cli
+            .auth_attempts_per_minute
+            .or(file.auth_attempts_per_minute)
+            .unwrap_or(defaults.attempts_per_minute),
+        max_failures: cli
+            .auth_max_failures
+            .or(file.auth_max_failures)
+            .unwrap_or(defaults.max_failures),
+        ban_duration: duration(
+            cli.auth_ban_duration,
+            "auth_ban_duration",
+            &file.auth_ban_duration,
+        )?
+        .unwrap_or(defaults.ban_duration),
+    };
+    let defaults = Timeouts::default();
+    let timeouts = Timeouts {
+        handshake: duration(
+            cli.handshake_timeout,
+            "handshake_timeout",
+            &file.handshake_timeout,
+        )?
+        .unwrap_or(defaults.handshake),
+        heartbeat_interval: duration(
+            cli.heartbeat_interval,
+            "heartbeat_interval",
+            &file.heartbeat_interval,
+        )?
+        .unwrap_or(defaults.heartbeat_interval),
+        heartbeat_timeout: duration(
+            cli.heartbeat_timeout,
+            "heartbeat_timeout",
+            &file.heartbeat_timeout,
+        )?
+        .unwrap_or(defaults.heartbeat_timeout),
+    };
+    if [
+        timeouts.handshake,
+        timeouts.heartbeat_interval,
+        timeouts.heartbeat_timeout,
+    ]
+    .contains(&Duration::ZERO)
+    {
+        bail!("timeouts and the heartbeat interval must be longer than zero");
+    }
+    let stats_interval = duration(cli.stats_interval, "stats_interval", &file.stats_interval)?
+        .unwrap_or(STATS_INTERVAL);
+    if stats_interval.is_zero() {
+        bail!("the stats interval must be longer than zero");
+    }
+    let defaults = Tarpit::default();
+    let tarpit = cli.tarpit.or(file.tarpit).map(|mode| Tarpit {
+        mode,
+        drop_after: cli
+            .tarpit_drop_after
+            .or(file.tarpit_drop_after)
+            .unwrap_or(defaults.drop_after),
+        tracked: cli
+            .tarpit_tracked
+            .map(NonZeroUsize::get)
+            .or(file.tarpit_tracked)
+            .unwrap_or(defaults.tracked),
+    });
+    if tarpit.is_some_and(|t| t.drop_after == 0 || t.tracked == 0) {
+        bail!("tarpit_drop_after and tarpit_tracked must be at least 1");
+    }
+    let conn_limits = cli
+        .max_open_conns
+        .map(NonZeroUsize::get)
+        .map(|high_water| ConnLimits {
+            low_water: cli
+                .resume_open_conns
+                .unwrap_or(ConnLimits::new(high_water).low_water),
+            high_water,
+        });
+    if conn_limits.is_some_and(|l| l.low_water >= l.high_water) {
+        bail!("--resume-open-conns must be under --max-open-conns");
+    }
+    let pick = |flag: &Vec<String>, file: &Vec<String>| match flag.is_empty() {
+        true => file.clone(),
+        false => flag.clone(),
+    };
+    let country_rules = CountryRules {
+        block: country_codes(pick(&cli.block_country, &file.block_country))?,
+        allow: country_codes(pick(&cli.allow_country, &file.allow_country))?,
+    };
+    let geoip_db = match cli.geoip_db.is_empty() {
+        true => &file.geoip_db,
+        false => &cli.geoip_db,
+    };
+    let geoip = match geoip_db.is_empty() {
+        true if !country_rules.is_empty() => {
+            bail!("--block-country and --allow-country need --geoip-db")
         }
+        true => None,
+        false => Some(Arc::new(GeoIp::open(geoip_db)?)),
+    };
+    let config = Config {
+        min_port: cli.min_port.or(file.min_port).unwrap_or(2000),
+        max_port: cli.max_port.or(file.max_port).unwrap_or(65000),
+        bind: cli.bind.clone(),
+        http_port: cli.http_port,
+        domain,
+        tls,
+        control_tls,
+        admin_bind: cli.admin_bind,
+        max_bandwidth,
+        drain_timeout: Duration::from_secs(cli.drain_timeout),
+        idle_timeout: duration(cli.idle_timeout, "idle_timeout", &file.idle_timeout)?,
+        max_tunnel_lifetime: duration(
+            cli.max_tunnel_lifetime,
+            "max_tunnel_lifetime",
+            &file.max_tunnel_lifetime,
+        )?,
+        reservation_grace: duration(
+            cli.reservation_grace,
+            "reservation_grace",
+            &file.reservation_grace,
+        )?,
+        error_pages,
+        max_conns_per_tunnel: cli.max_conns_per_tunnel.or(file.max_conns_per_tunnel),
+        max_pending: cli.max_pending.or(file.max_pending),
+        max_pending_per_tunnel: cli
+            .max_pending_per_tunnel
+            .map(NonZeroUsize::get)
+            .or(file.max_pending_per_tunnel)
+            .unwrap_or(MAX_PENDING_PER_TUNNEL),
+        max_missed_heartbeats: cli
+            .max_missed_heartbeats
+            .or(file.max_missed_heartbeats)
+            .unwrap_or(MAX_MISSED_HEARTBEATS),
+        timeouts,
+        stats_interval,
+        pull_targets: match cli.allow_pull.is_empty() {
+            true => file.allow_pull.clone(),
+            false => cli.allow_pull.clone(),
+        },
+        unix_socket_dir: cli.unix_socket_dir.clone().or(file.unix_socket_dir.clone()),
+        auth_limits,
+        require_sealed_hello: cli.require_sealed_hello || file.require_sealed_hello,
+        webhook: cli.webhook.clone().or(file.webhook.clone()),
+        geoip,
+        country_rules,
+        tarpit,
+        conn_limits,
+        buffer_size,
+    };
+    if config.max_pending_per_tunnel == 0 {
+        bail!("max_pending_per_tunnel must be at least 1");
+    }
+    if let Some(dir) = config.unix_socket_dir.as_ref().filter(|d| !d.is_dir()) {
+        bail!("{} is not a directory", dir.display());
     }
 
-    // First real message from client.
-    match ctrl.recv_timeout::<ClientMsg>().await? {
-        // ── Register a tunnel ──────────────────────────────────────────────
-        Some(ClientMsg::Hello { subdomain, proto }) => {
-            let listener = match state.claim_port(&subdomain, proto).await {
-                Ok(l) => l,
-                Err(e) => {
-                    ctrl.send(ServerMsg::Error(e)).await?;
-                    return Ok(());
-                }
-            };
-            let public_port = listener.local_addr()?.port();
-            ctrl.send(ServerMsg::Hello { public_port }).await?;
-            info!(subdomain, public_port, "tunnel registered");
-
-            // Drive the tunnel: heartbeat + accept inbound connections.
-            let result = drive_tunnel(ctrl, listener, &state, &subdomain).await;
-            state.subdomains.remove(&subdomain);
-            info!(subdomain, "tunnel closed");
-            result
+    let secret = cli.secret.as_deref().or(file.secret.as_deref());
+    let tokens_file = cli.tokens_file.as_ref().or(file.tokens_file.as_ref());
+    let mut secret_bandwidth = cli.secret_bandwidth.clone();
+    if secret_bandwidth.is_empty() {
+        for (secret, value) in &file.secret_bandwidth {
+            secret_bandwidth.push((secret.clone(), rate("secret_bandwidth", value)?));
         }
+    }
+    let auth: Option<Box<dyn AuthProvider>> =
+        if tokens_file.is_some() || !secret_bandwidth.is_empty() {
+            // Overrides come first so they win when a secret is listed twice.
+            let mut secrets = Secrets::new();
+            for (i, (secret, rate)) in secret_bandwidth.iter().enumerate() {
+                let mut identity = Identity::new(format!("secret#{}", i + 1));
+                identity.max_bandwidth = Some(*rate);
+                secrets = secrets.with(secret, identity);
+            }
+            if let Some(secret) = secret {
+                secrets = secrets.with(secret, Identity::new("secret"));
+            }
+            match tokens_file {
+                Some(path) => Some(Box::new(Tokens::load(path)?.with_secrets(secrets))),
+                None => Some(Box::new(secrets)),
+            }
+        } else {
+            secret.map(|secret| Box::new(Auth::new(secret)) as Box<dyn AuthProvider>)
+        };
+    Ok(Reload { config, auth })
+}
 
-        // ── Client is accepting a pending inbound connection ───────────────
-        Some(ClientMsg::Accept(id)) => {
-            match state.pending.remove(&id) {
-                Some((_, mut inbound)) => {
-                    let mut parts = ctrl.into_parts();
-                    // Flush any buffered bytes first.
-                    inbound.write_all(&parts.read_buf).await?;
-                    tokio::io::copy_bidirectional(&mut inbound, &mut parts.io).await?;
+/// Install the global subscriber. Spans are kept in JSON lines so each one
+/// carries the fields of the control connection and visitor it belongs to.
+fn init_logging(format: LogFormat, level: Option<&str>) -> Result<()> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level).context("invalid --log-level")?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().flatten_event(true).with_span_list(true).init(),
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
                 }
-                None => warn!(%id, "Accept for unknown connection"),
             }
-            Ok(())
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// ── Ban administration ────────────────────────────────────────────────────────
+
+fn manage_bans(bans: BanList, action: BanAction) -> Result<()> {
+    match action {
+        BanAction::List { page, per_page } => {
+            let page = bans.list(page, per_page);
+            let pages = page.total.div_ceil(page.per_page).max(1);
+            println!(
+                "{:<32} {:<22} {:<22} REASON",
+                "TARGET", "CREATED", "EXPIRES"
+            );
+            for ban in &page.bans {
+                let expires = ban.expires_at.map_or("never".into(), timestamp);
+                println!(
+                    "{:<32} {:<22} {:<22} {}",
+                    ban.target.to_string(),
+                    timestamp(ban.created_at),
+                    expires,
+                    ban.reason
+                );
+            }
+            println!("page {}/{} ({} bans)", page.page, pages, page.total);
+        }
+        BanAction::Add {
+            target,
+            reason,
+            ttl,
+        } => {
+            bans.ban(target.clone(), &reason, ttl)?;
+            println!("banned {target}");
         }
+        BanAction::Remove { target } => {
+            if bans.unban(&target)? {
+                println!("unbanned {target}");
+            } else {
+                println!("{target} was not banned");
+            }
+        }
+    }
+    Ok(())
+}
+
+// ── Audit log ─────────────────────────────────────────────────────────────────
 
-        _ => Ok(()),
+fn verify_audit_log(path: &Path) -> Result<()> {
+    let verified = audit::verify(path)?;
+    match verified.last {
+        Some((seq, hash)) => println!(
+            "{} records in {} files are intact; the newest is #{seq} with hash {hash}",
+            verified.records, verified.files
+        ),
+        None => println!("{} is empty", path.display()),
     }
+    Ok(())
 }
 
-// ── Tunnel driver: heartbeat + forward inbound connections ────────────────────
+// ── DNS check ─────────────────────────────────────────────────────────────────
 
-async fn drive_tunnel(
-    mut ctrl: Framed_<TcpStream>,
-    listener: TcpListener,
-    state: &Arc<State>,
-    subdomain: &str,
-) -> Result<()> {
-    loop {
-        // Send heartbeat; if client is gone, exit.
-        if ctrl.send(ServerMsg::Heartbeat).await.is_err() {
-            return Ok(());
+async fn check_dns(domain: &str, http_port: u16, public_ip: &[IpAddr]) -> Result<()> {
+    let mut report = Report::default();
+    let apex = match doctor::resolve(domain).await {
+        Ok(ips) if !ips.is_empty() => {
+            report.push(Check::pass(format!(
+                "{domain} resolves to {}",
+                ips.iter()
+                    .map(IpAddr::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+            ips
         }
+        _ => {
+            report.push(Check::fail(
+                format!("{domain} does not resolve"),
+                format!(
+                    "add DNS records `{domain}` of type A (and AAAA for IPv6) with this \
+                     server's public address; clients use it as --server"
+                ),
+            ));
+            Vec::new()
+        }
+    };
+    let expected = match public_ip.is_empty() {
+        true => &apex[..],
+        false => public_ip,
+    };
+    if let Some(&ip) = expected.first() {
+        let addr = SocketAddr::new(ip, CONTROL_PORT);
+        report.push(match doctor::reachable(addr).await {
+            Ok(()) => Check::pass(format!("the control port {addr} is open")),
+            Err(e) => Check::fail(
+                format!("cannot connect to the control port {addr}: {e:#}"),
+                format!(
+                    "start sshx-server and open {CONTROL_PORT}/tcp in the firewall; some \
+                     routers can't reach their own public address, so try from outside too"
+                ),
+            ),
+        });
+    }
+    doctor::check_routing(&mut report, domain, http_port, expected).await;
+    check_ntp(&mut report);
 
-        // Wait up to 500 ms for a new inbound connection.
-        match timeout(Duration::from_millis(500), listener.accept()).await {
-            Ok(Ok((stream, addr))) => {
-                let id = Uuid::new_v4();
-                info!(%addr, %subdomain, "inbound connection");
-
-                // Store it; clean up after 10 s if client never accepts.
-                state.pending.insert(id, stream);
-                let pending = Arc::clone(state);
-                tokio::spawn(async move {
-                    sleep(Duration::from_secs(10)).await;
-                    if pending.pending.remove(&id).is_some() {
-                        warn!(%id, "stale pending connection removed");
-                    }
-                });
-
-                ctrl.send(ServerMsg::Connection(id)).await?;
-            }
-            Ok(Err(e)) => return Err(e.into()),
-            Err(_) => {} // timeout — just loop and heartbeat again
+    print!("{report}");
+    if report.failed() {
+        bail!("DNS or routing is not set up for {domain} yet");
+    }
+    Ok(())
+}
+
+/// Warn if systemd says the clock isn't synchronised.
+fn check_ntp(report: &mut Report) {
+    let Ok(output) = std::process::Command::new("timedatectl")
+        .args(["show", "--property=NTPSynchronized", "--value"])
+        .output()
+    else {
+        return;
+    };
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "yes" => report.push(Check::pass("the clock is synchronised with NTP")),
+        "no" => report.push(Check::warn(
+            "the clock is not synchronised with NTP",
+            "run `sudo timedatectl set-ntp true`; clients and certificate checks need the \
+             right time",
+        )),
+        _ => {}
+    }
+}
+
+// ── Argument parsing ──────────────────────────────────────────────────────────
+
+/// Bytes/sec with an optional decimal (K, M, G) or binary (Ki, Mi, Gi)
+/// suffix, e.g. `500K`, `10Mi` or `1G`.
+fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim().trim_end_matches(['B', 'b']);
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" => 1,
+        "k" => 1_000,
+        "m" => 1_000_000,
+        "g" => 1_000_000_000,
+        "ki" => 1 << 10,
+        "mi" => 1 << 20,
+        "gi" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "unknown unit '{unit}' (expected K, M, G, Ki, Mi or Gi)"
+            ))
         }
+    };
+    let n: u64 = digits.parse().map_err(|_| format!("invalid rate '{s}'"))?;
+    n.checked_mul(multiplier)
+        .ok_or_else(|| format!("rate '{s}' is too large"))
+}
+
+/// A copy buffer size as for [`parse_rate`], from 1Ki to 16Mi.
+fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    match parse_rate(s)? {
+        size @ 1024..=0x100_0000 => Ok(size as usize),
+        _ => Err(format!("buffer size '{s}' is not between 1Ki and 16Mi")),
     }
 }
+
+/// ISO 3166-1 alpha-2 codes, upper-cased.
+fn country_codes(codes: Vec<String>) -> Result<Vec<String>> {
+    codes
+        .into_iter()
+        .map(
+            |code| match code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+                true => Ok(code.to_ascii_uppercase()),
+                false => bail!("'{code}' is not a two-letter country code"),
+            },
+        )
+        .collect()
+}
+
+fn parse_secret_rate(s: &str) -> Result<(String, u64), String> {
+    let (secret, rate) = s
+        .rsplit_once('=')
+        .ok_or_else(|| "expected SECRET=RATE".to_owned())?;
+    if secret.is_empty() {
+        return Err("missing secret".into());
+    }
+    Ok((secret.to_owned(), parse_rate(rate)?))
+}
+
+fn timestamp(unix_secs: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(unix_secs)).to_string()
+}
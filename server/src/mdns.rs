@@ -0,0 +1,79 @@
+//! Announcing the server on the local network over mDNS (DNS-SD), so that
+//! `sshx --server auto` finds it without any setup: demos, classrooms, a LAN
+//! without DNS. Off unless `--mdns` is given.
+
+use std::{collections::HashMap, fs, net::IpAddr};
+
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use sshx_core::protocol::{CONTROL_PORT, MDNS_SERVICE, PROTOCOL_VERSION};
+use tracing::{debug, info};
+
+use crate::Config;
+
+/// The server's record on the LAN. Dropping it withdraws the record.
+pub struct Announcement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+/// Announce the server `config` describes as `name`, or as the machine's
+/// host name. Addresses are those of `config.bind`, or of every interface
+/// when it binds them all.
+pub fn announce(config: &Config, name: Option<&str>) -> Result<Announcement> {
+    let host = host_name();
+    let instance = name.unwrap_or(&host);
+    let mut txt = HashMap::from([("version".to_owned(), PROTOCOL_VERSION.to_string())]);
+    if let Some(tls) = &config.control_tls {
+        txt.insert("tls_port".to_owned(), tls.port.to_string());
+    }
+    let ips: Vec<IpAddr> = config
+        .bind
+        .iter()
+        .copied()
+        .filter(|ip| !ip.is_unspecified())
+        .collect();
+    let info = ServiceInfo::new(
+        MDNS_SERVICE,
+        instance,
+        &format!("{host}.local."),
+        &ips[..],
+        CONTROL_PORT,
+        txt,
+    )
+    .context("invalid mDNS record")?;
+    let info = match ips.is_empty() {
+        true => info.enable_addr_auto(),
+        false => info,
+    };
+    let fullname = info.get_fullname().to_owned();
+    let daemon = ServiceDaemon::new().context("cannot start mDNS")?;
+    daemon
+        .register(info)
+        .context("cannot announce the server over mDNS")?;
+    info!(name = instance, "announced over mDNS");
+    Ok(Announcement { daemon, fullname })
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            debug!(err = %e, "cannot withdraw the mDNS record");
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// This machine's name, without a domain.
+fn host_name() -> String {
+    let name = fs::read_to_string("/etc/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .unwrap_or_default();
+    let name = name.trim().split('.').next().unwrap_or_default();
+    match name.is_empty() {
+        true => "sshx-server".to_owned(),
+        false => name.to_owned(),
+    }
+}
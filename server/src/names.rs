@@ -0,0 +1,24 @@
+//! Random, readable subdomains for clients that don't ask for one, like
+//! `brave-otter-42`.
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "dapper", "eager",
+    "fancy", "gentle", "glad", "golden", "happy", "jolly", "keen", "lively", "lucky", "mellow",
+    "merry", "mighty", "nimble", "noble", "plucky", "proud", "quick", "quiet", "rapid", "rosy",
+    "shiny", "silent", "snappy", "sunny", "swift", "tidy", "vivid", "warm", "witty", "zesty",
+];
+
+const ANIMALS: &[&str] = &[
+    "badger", "beaver", "bison", "camel", "cobra", "coyote", "crane", "dingo", "dolphin", "eagle",
+    "falcon", "ferret", "gecko", "heron", "ibis", "jackal", "koala", "lemur", "lynx", "marmot",
+    "moose", "narwhal", "ocelot", "otter", "panda", "parrot", "puffin", "quokka", "raven",
+    "salmon", "seal", "sloth", "tapir", "tiger", "toucan", "walrus", "weasel", "wombat", "yak",
+    "zebra",
+];
+
+/// A fresh random name. About 160,000 of them, so callers check it is free.
+pub(crate) fn random() -> String {
+    let adjective = ADJECTIVES[fastrand::usize(..ADJECTIVES.len())];
+    let animal = ANIMALS[fastrand::usize(..ANIMALS.len())];
+    format!("{adjective}-{animal}-{}", fastrand::u8(..100))
+}
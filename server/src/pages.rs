@@ -0,0 +1,117 @@
+//! Pages for HTTP visitors whose request can't reach a tunnel.
+//!
+//! Each page has a built-in version. An operator can replace any of them
+//! with an HTML template in a directory:
+//!
+//! ```text
+//! not-found.html              no tunnel is registered for the host (404)
+//! offline.html                the tunnel's client is away (502)
+//! too-many-connections.html   the tunnel has more visitors waiting than it
+//!                             can take (503)
+//! ```
+//!
+//! `{{host}}`, `{{status}}` and `{{message}}` in a template are replaced with
+//! the requested host, the status code and a sentence on what went wrong.
+
+use std::{fs, io, path::Path};
+
+use anyhow::{Context, Result};
+
+/// Built-in template shared by every page.
+const BUILT_IN: &str = "<!doctype html><meta charset=utf-8>\
+<title>{{status}} {{title}}</title>\
+<style>body{font-family:sans-serif;max-width:40em;margin:4em auto;color:#333}</style>\
+<h1>{{title}}</h1><p>{{message}}<hr><p><small>sshx</small>\n";
+
+/// A page a visitor can be shown instead of their tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorPage {
+    NotFound,
+    Offline,
+    TooManyConnections,
+}
+
+impl ErrorPage {
+    const ALL: [Self; 3] = [Self::NotFound, Self::Offline, Self::TooManyConnections];
+
+    pub(crate) fn status(self) -> (u16, &'static str) {
+        match self {
+            Self::NotFound => (404, "Not Found"),
+            Self::Offline => (502, "Bad Gateway"),
+            Self::TooManyConnections => (503, "Service Unavailable"),
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::NotFound => "Tunnel not found",
+            Self::Offline => "Tunnel offline",
+            Self::TooManyConnections => "Too many connections",
+        }
+    }
+
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::NotFound => "not-found.html",
+            Self::Offline => "offline.html",
+            Self::TooManyConnections => "too-many-connections.html",
+        }
+    }
+}
+
+/// The templates of the pages, built-in unless replaced.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    not_found: Option<String>,
+    offline: Option<String>,
+    too_many_connections: Option<String>,
+}
+
+impl ErrorPages {
+    /// Read the templates in `dir`. Pages without a file keep the built-in
+    /// version.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut pages = Self::default();
+        for page in ErrorPage::ALL {
+            let path = dir.join(page.file_name());
+            let template = match fs::read_to_string(&path) {
+                Ok(template) => template,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+            };
+            *pages.template_mut(page) = Some(template);
+        }
+        Ok(pages)
+    }
+
+    fn template_mut(&mut self, page: ErrorPage) -> &mut Option<String> {
+        match page {
+            ErrorPage::NotFound => &mut self.not_found,
+            ErrorPage::Offline => &mut self.offline,
+            ErrorPage::TooManyConnections => &mut self.too_many_connections,
+        }
+    }
+
+    /// The HTML of `page` for a visitor of `host`.
+    pub(crate) fn render(&self, page: ErrorPage, host: &str, message: &str) -> String {
+        let template = match page {
+            ErrorPage::NotFound => &self.not_found,
+            ErrorPage::Offline => &self.offline,
+            ErrorPage::TooManyConnections => &self.too_many_connections,
+        };
+        template
+            .as_deref()
+            .unwrap_or(BUILT_IN)
+            .replace("{{title}}", page.title())
+            .replace("{{status}}", &page.status().0.to_string())
+            .replace("{{host}}", &escape(host))
+            .replace("{{message}}", &escape(message))
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
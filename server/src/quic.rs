@@ -0,0 +1,93 @@
+//! QUIC transport: the control connection and every data connection of a
+//! client as streams of one QUIC connection, on UDP at the TLS control port.
+//!
+//! Each bidirectional stream is handled exactly like a connection to the
+//! control port, after the [`QUIC_STREAM_OPEN`] byte the client starts it
+//! with. A lossy link then stalls only the stream that lost a packet, and
+//! a changing client address doesn't break the connection.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use quinn::{
+    crypto::rustls::QuicServerConfig, Endpoint, EndpointConfig, RecvStream, SendStream,
+    TokioRuntime,
+};
+use socket2::Type;
+use sshx_core::protocol::{QUIC_ALPN, QUIC_STREAM_OPEN};
+use tokio::{io::AsyncReadExt, time::timeout};
+use tracing::{debug, info, warn};
+
+use crate::{
+    server::{bind_socket, handle_control, State},
+    tls::{self, ControlTlsConfig},
+};
+
+/// Bind the QUIC endpoint on UDP at the TLS control port of `bind`.
+pub(crate) fn endpoint(config: &ControlTlsConfig, bind: IpAddr) -> Result<Endpoint> {
+    let mut crypto = tls::control_server_config(config)?;
+    crypto.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    let quic = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    let socket = bind_socket(SocketAddr::new(bind, config.port), Type::DGRAM)?;
+    let runtime = Arc::new(TokioRuntime);
+    let endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        Some(quic),
+        socket.into(),
+        runtime,
+    )?;
+    info!(addr = %bind, port = config.port, "QUIC control port listening");
+    Ok(endpoint)
+}
+
+pub(crate) async fn serve(endpoint: Endpoint, state: Arc<State>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let addr = incoming.remote_address();
+        if state.bans.is_ip_banned(addr.ip()) {
+            debug!(%addr, "refusing QUIC connection from banned IP");
+            incoming.refuse();
+            continue;
+        }
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(incoming, addr, state).await {
+                debug!(%addr, err = %e, "QUIC connection closed");
+            }
+        });
+    }
+}
+
+/// Handle every stream the client opens until the connection closes.
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    addr: SocketAddr,
+    state: Arc<State>,
+) -> Result<()> {
+    let connection = incoming.await?;
+    loop {
+        let (send, recv) = connection.accept_bi().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = serve_stream(send, recv, addr, state).await {
+                warn!(%addr, err = %e, "connection error");
+            }
+        });
+    }
+}
+
+async fn serve_stream(
+    send: SendStream,
+    mut recv: RecvStream,
+    addr: SocketAddr,
+    state: Arc<State>,
+) -> Result<()> {
+    let handshake = state.config().timeouts.handshake;
+    let first = timeout(handshake, recv.read_u8()).await??;
+    if first != QUIC_STREAM_OPEN {
+        bail!("QUIC stream opened with {first:#04x}");
+    }
+    handle_control(tokio::io::join(recv, send), addr, state).await
+}
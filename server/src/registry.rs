@@ -0,0 +1,360 @@
+//! What the relay keeps about its tunnels: the tunnel holding each
+//! subdomain, visitor connections parked until their client accepts them,
+//! and subdomains held for clients that went away.
+//!
+//! [`State`](crate::server::State) goes through a [`Registry`] for all of
+//! it, so another backend (one that outlives restarts, say) only has to
+//! implement the trait. [`MemoryRegistry`] keeps everything in this process;
+//! [`FileRegistry`] also writes tunnels and reservations to a JSON file, so
+//! that after a restart their clients get their subdomains and ports back.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use dashmap::{DashMap, Entry};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    bans::unix_now,
+    server::{Inbound, Tunnel},
+};
+
+/// How long tunnels up when the server stopped are held after a restart,
+/// unless a reservation grace is configured.
+pub(crate) const RESTART_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// A subdomain and port held for the client that had them.
+#[derive(Debug, Clone)]
+pub(crate) struct Reservation {
+    pub(crate) port: u16,
+    /// Session token the client presents to get them back.
+    pub(crate) token: Uuid,
+    pub(crate) until: Instant,
+}
+
+impl Reservation {
+    /// How long the reservation still lasts, if it hasn't run out.
+    pub(crate) fn left(&self) -> Option<Duration> {
+        self.until
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+    }
+}
+
+/// Storage for the tunnel registry, parked connections and reservations.
+///
+/// Implementations must make [`claim`](Registry::claim) atomic: of two
+/// tunnels claiming the same subdomain at once, exactly one gets it.
+/// Reservations that ran out must look like they aren't there.
+pub(crate) trait Registry: Send + Sync + 'static {
+    /// Record `tunnel` under `subdomain` unless a tunnel holds it already,
+    /// in which case `tunnel` is handed back.
+    #[allow(clippy::result_large_err)]
+    fn claim(&self, subdomain: &str, tunnel: Tunnel) -> Result<(), Tunnel>;
+
+    /// Forget the tunnel of `subdomain`.
+    fn release(&self, subdomain: &str) -> Option<Tunnel>;
+
+    /// The tunnel holding `subdomain`.
+    fn tunnel(&self, subdomain: &str) -> Option<Tunnel>;
+
+    /// Every registered tunnel, by subdomain.
+    fn tunnels(&self) -> Vec<(String, Tunnel)>;
+
+    /// How many tunnels are registered.
+    fn tunnel_count(&self) -> usize;
+
+    /// Park a visitor connection until its client accepts connection `id`.
+    fn park(&self, id: Uuid, inbound: Inbound);
+
+    /// Take the visitor connection parked as `id`.
+    fn unpark(&self, id: Uuid) -> Option<Inbound>;
+
+    /// Whether a visitor connection is still parked as `id`.
+    fn is_parked(&self, id: Uuid) -> bool;
+
+    /// How many visitor connections are parked.
+    fn parked(&self) -> usize;
+
+    /// Hold `subdomain` for a client that went away.
+    fn reserve(&self, subdomain: &str, reservation: Reservation);
+
+    /// The reservation of `subdomain`, if it hasn't run out.
+    fn reservation(&self, subdomain: &str) -> Option<Reservation>;
+
+    /// Remove the reservation of `subdomain` if the session `token` holds it.
+    fn take_reservation(&self, subdomain: &str, token: Uuid) -> Option<Reservation>;
+
+    /// Whether a reservation that hasn't run out holds `port`.
+    fn is_port_reserved(&self, port: u16) -> bool;
+
+    /// Whether tunnels and reservations outlive a restart, so clients
+    /// should be able to get theirs back even without a reservation grace.
+    fn outlives_restarts(&self) -> bool {
+        false
+    }
+}
+
+/// A [`Registry`] in this process, lost on restart.
+#[derive(Default)]
+pub(crate) struct MemoryRegistry {
+    tunnels: DashMap<String, Tunnel>,
+    pending: DashMap<Uuid, Inbound>,
+    reservations: DashMap<String, Reservation>,
+}
+
+impl MemoryRegistry {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn forget_expired(&self) {
+        let now = Instant::now();
+        self.reservations.retain(|_, r| r.until > now);
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn claim(&self, subdomain: &str, tunnel: Tunnel) -> Result<(), Tunnel> {
+        match self.tunnels.entry(subdomain.to_owned()) {
+            Entry::Occupied(_) => Err(tunnel),
+            Entry::Vacant(entry) => {
+                entry.insert(tunnel);
+                Ok(())
+            }
+        }
+    }
+
+    fn release(&self, subdomain: &str) -> Option<Tunnel> {
+        self.tunnels.remove(subdomain).map(|(_, tunnel)| tunnel)
+    }
+
+    fn tunnel(&self, subdomain: &str) -> Option<Tunnel> {
+        self.tunnels.get(subdomain).map(|t| t.clone())
+    }
+
+    fn tunnels(&self) -> Vec<(String, Tunnel)> {
+        self.tunnels
+            .iter()
+            .map(|t| (t.key().clone(), t.value().clone()))
+            .collect()
+    }
+
+    fn tunnel_count(&self) -> usize {
+        self.tunnels.len()
+    }
+
+    fn park(&self, id: Uuid, inbound: Inbound) {
+        self.pending.insert(id, inbound);
+    }
+
+    fn unpark(&self, id: Uuid) -> Option<Inbound> {
+        self.pending.remove(&id).map(|(_, inbound)| inbound)
+    }
+
+    fn is_parked(&self, id: Uuid) -> bool {
+        self.pending.contains_key(&id)
+    }
+
+    fn parked(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn reserve(&self, subdomain: &str, reservation: Reservation) {
+        self.forget_expired();
+        self.reservations.insert(subdomain.to_owned(), reservation);
+    }
+
+    fn reservation(&self, subdomain: &str) -> Option<Reservation> {
+        self.reservations
+            .get(subdomain)
+            .filter(|r| r.left().is_some())
+            .map(|r| r.clone())
+    }
+
+    fn take_reservation(&self, subdomain: &str, token: Uuid) -> Option<Reservation> {
+        self.forget_expired();
+        self.reservations
+            .remove_if(subdomain, |_, r| r.token == token)
+            .map(|(_, reservation)| reservation)
+    }
+
+    fn is_port_reserved(&self, port: u16) -> bool {
+        self.reservations
+            .iter()
+            .any(|r| r.port == port && r.left().is_some())
+    }
+}
+
+/// A tunnel or reservation as written to the state file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Held {
+    subdomain: String,
+    port: u16,
+    token: Uuid,
+    /// Unix seconds when a reservation runs out; `None` for a tunnel that
+    /// was up.
+    expires_at: Option<u64>,
+}
+
+/// A [`MemoryRegistry`] that keeps its tunnels and reservations in a JSON
+/// file. Tunnels up when the server stopped come back as reservations.
+pub(crate) struct FileRegistry {
+    memory: MemoryRegistry,
+    path: PathBuf,
+    /// Serializes writes of the file.
+    saving: Mutex<()>,
+}
+
+impl FileRegistry {
+    /// Load what a previous run left in `path`, holding the subdomains of
+    /// its tunnels for `grace`. A missing file is an empty registry.
+    pub(crate) fn load(path: impl Into<PathBuf>, grace: Duration) -> Result<Self> {
+        let registry = Self {
+            memory: MemoryRegistry::new(),
+            path: path.into(),
+            saving: Mutex::new(()),
+        };
+        let held = match fs::read(&registry.path) {
+            Ok(bytes) => serde_json::from_slice::<Vec<Held>>(&bytes)
+                .with_context(|| format!("invalid state file {}", registry.path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("cannot read {}", registry.path.display()));
+            }
+        };
+        let (now, unix) = (Instant::now(), unix_now());
+        for held in held {
+            let left = match held.expires_at {
+                Some(at) => Duration::from_secs(at.saturating_sub(unix)),
+                None => grace,
+            };
+            if left.is_zero() {
+                continue;
+            }
+            let reservation = Reservation {
+                port: held.port,
+                token: held.token,
+                until: now + left,
+            };
+            registry
+                .memory
+                .reservations
+                .insert(held.subdomain, reservation);
+        }
+        let restored = registry.memory.reservations.len();
+        if restored > 0 {
+            info!(restored, path = %registry.path.display(), "holding subdomains from the last run");
+        }
+        Ok(registry)
+    }
+
+    /// Write the tunnels and reservations out, logging failures: the
+    /// server carries on without the file.
+    fn save(&self) {
+        let _saving = self.saving.lock().unwrap();
+        let unix = unix_now();
+        let tunnels = self.memory.tunnels.iter().map(|t| Held {
+            subdomain: t.key().clone(),
+            port: t.public_port,
+            token: t.token,
+            expires_at: None,
+        });
+        let reservations = self.memory.reservations.iter().filter_map(|r| {
+            Some(Held {
+                subdomain: r.key().clone(),
+                port: r.port,
+                token: r.token,
+                expires_at: Some(unix + r.left()?.as_secs().max(1)),
+            })
+        });
+        let held: Vec<Held> = tunnels.chain(reservations).collect();
+        if let Err(e) = write_file(&self.path, &held) {
+            warn!(err = format!("{e:#}"), "cannot save the state file");
+        }
+    }
+}
+
+impl Registry for FileRegistry {
+    fn claim(&self, subdomain: &str, tunnel: Tunnel) -> Result<(), Tunnel> {
+        self.memory.claim(subdomain, tunnel)?;
+        self.save();
+        Ok(())
+    }
+
+    fn release(&self, subdomain: &str) -> Option<Tunnel> {
+        let tunnel = self.memory.release(subdomain);
+        if tunnel.is_some() {
+            self.save();
+        }
+        tunnel
+    }
+
+    fn tunnel(&self, subdomain: &str) -> Option<Tunnel> {
+        self.memory.tunnel(subdomain)
+    }
+
+    fn tunnels(&self) -> Vec<(String, Tunnel)> {
+        self.memory.tunnels()
+    }
+
+    fn tunnel_count(&self) -> usize {
+        self.memory.tunnel_count()
+    }
+
+    fn park(&self, id: Uuid, inbound: Inbound) {
+        self.memory.park(id, inbound);
+    }
+
+    fn unpark(&self, id: Uuid) -> Option<Inbound> {
+        self.memory.unpark(id)
+    }
+
+    fn is_parked(&self, id: Uuid) -> bool {
+        self.memory.is_parked(id)
+    }
+
+    fn parked(&self) -> usize {
+        self.memory.parked()
+    }
+
+    fn reserve(&self, subdomain: &str, reservation: Reservation) {
+        self.memory.reserve(subdomain, reservation);
+        self.save();
+    }
+
+    fn reservation(&self, subdomain: &str) -> Option<Reservation> {
+        self.memory.reservation(subdomain)
+    }
+
+    fn take_reservation(&self, subdomain: &str, token: Uuid) -> Option<Reservation> {
+        let reservation = self.memory.take_reservation(subdomain, token);
+        if reservation.is_some() {
+            self.save();
+        }
+        reservation
+    }
+
+    fn is_port_reserved(&self, port: u16) -> bool {
+        self.memory.is_port_reserved(port)
+    }
+
+    fn outlives_restarts(&self) -> bool {
+        true
+    }
+}
+
+fn write_file(path: &Path, held: &[Held]) -> Result<()> {
+    // Write-then-rename so a crash never leaves a half-written file.
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(held)?)
+        .with_context(|| format!("cannot write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("cannot replace {}", path.display()))
+}
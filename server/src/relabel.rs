@@ -0,0 +1,347 @@
+//! The request heads of HTTP/1.x visitors, every one of them.
+//!
+//! [`route`](crate::http::route) reads a visitor's first request head, but a
+//! kept-alive connection carries more. Each head on its way to the tunnel
+//! loses any [`SUBDOMAIN_HEADER`] the visitor sent and gets ours when a
+//! wildcard tunnel matched; bodies pass unchanged, delimited by their
+//! `Content-Length` or chunks.
+//!
+//! After a request to upgrade the connection, say to WebSocket, nothing more
+//! is passed on until the response starts: a `101` means the bytes that
+//! follow aren't HTTP any more, anything else that they are. `CONNECT` is
+//! just a request. A request that doesn't parse ends the connection rather
+//! than get through untouched.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll, Waker},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::http::{MAX_HEAD, SUBDOMAIN_HEADER};
+
+/// Most headers a request may have.
+const MAX_HEADERS: usize = 100;
+
+/// Where the requests of a visitor are at.
+enum State {
+    Head(Vec<u8>),
+    Body(u64),
+    /// Within a chunked body: reading a size line.
+    ChunkSize(Vec<u8>),
+    ChunkData(u64),
+    /// The CRLF after a chunk's data; bytes left of it.
+    ChunkEnd(u8),
+    /// Reading trailer lines after the last chunk.
+    Trailer(Vec<u8>),
+    /// Holding what followed a request to upgrade, and the start of the
+    /// response to it, until that says whether it did.
+    Upgrading {
+        held: Vec<u8>,
+        status: Vec<u8>,
+    },
+    /// Upgraded: no longer HTTP.
+    Opaque,
+}
+
+/// Rewrites request heads as the visitor's bytes are fed to it.
+pub(crate) struct Relabel {
+    /// Our [`SUBDOMAIN_HEADER`], if a wildcard tunnel matched.
+    matched: Option<String>,
+    state: State,
+    /// The request being read asked to upgrade.
+    upgrade: bool,
+}
+
+impl Relabel {
+    pub(crate) fn new(matched: Option<&str>) -> Self {
+        Self {
+            matched: matched.map(str::to_owned),
+            state: State::Head(Vec::new()),
+            upgrade: false,
+        }
+    }
+
+    /// Append what the tunnel gets of the visitor's `data` to `out`.
+    pub(crate) fn feed(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        while !data.is_empty() {
+            match &mut self.state {
+                State::Head(buf) => {
+                    let old = buf.len();
+                    buf.extend_from_slice(data);
+                    let Some((len, framing)) = self.head(out)? else {
+                        return Ok(());
+                    };
+                    data = &data[len - old..];
+                    self.state = match framing {
+                        Some(State::Body(0)) | None => self.end(),
+                        Some(state) => state,
+                    };
+                }
+                State::Body(left) | State::ChunkData(left) => {
+                    let n = (*left).min(data.len() as u64) as usize;
+                    out.extend_from_slice(&data[..n]);
+                    *left -= n as u64;
+                    data = &data[n..];
+                    if *left > 0 {
+                        continue;
+                    }
+                    self.state = match self.state {
+                        State::Body(_) => self.end(),
+                        _ => State::ChunkEnd(2),
+                    };
+                }
+                State::ChunkSize(line) => {
+                    let Some(n) = take_line(line, &mut data, out)? else {
+                        continue;
+                    };
+                    let line = String::from_utf8_lossy(&line[..n]);
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    self.state = match u64::from_str_radix(size, 16) {
+                        Ok(0) => State::Trailer(Vec::new()),
+                        Ok(size) => State::ChunkData(size),
+                        Err(_) => return Err(invalid("bad chunk size")),
+                    };
+                }
+                State::ChunkEnd(left) => {
+                    let n = (*left as usize).min(data.len());
+                    out.extend_from_slice(&data[..n]);
+                    *left -= n as u8;
+                    data = &data[n..];
+                    if *left == 0 {
+                        self.state = State::ChunkSize(Vec::new());
+                    }
+                }
+                State::Trailer(line) => {
+                    let Some(n) = take_line(line, &mut data, out)? else {
+                        continue;
+                    };
+                    if line[..n].iter().all(|b| b.is_ascii_whitespace()) {
+                        self.state = self.end();
+                    } else {
+                        line.clear();
+                    }
+                }
+                State::Upgrading { held, .. } => {
+                    held.extend_from_slice(data);
+                    return Ok(());
+                }
+                State::Opaque => {
+                    out.extend_from_slice(data);
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the head being read. Once it is complete, append it rewritten to
+    /// `out`, and return its length and how its body is read: `None` for a
+    /// request without one.
+    fn head(&mut self, out: &mut Vec<u8>) -> io::Result<Option<(usize, Option<State>)>> {
+        let State::Head(buf) = &self.state else {
+            unreachable!("not reading a head");
+        };
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        let len = match req.parse(buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) if buf.len() <= MAX_HEAD => return Ok(None),
+            Ok(httparse::Status::Partial) => return Err(invalid("request head too large")),
+            Err(e) => return Err(invalid(e)),
+        };
+        let header = |name: &str| {
+            req.headers
+                .iter()
+                .rev()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .map(|h| String::from_utf8_lossy(h.value))
+        };
+        let body = match (header("transfer-encoding"), header("content-length")) {
+            (Some(coding), _) => {
+                let last = coding.rsplit(',').next().unwrap_or_default();
+                if !last.trim().eq_ignore_ascii_case("chunked") {
+                    return Err(invalid("request body without a length"));
+                }
+                Some(State::ChunkSize(Vec::new()))
+            }
+            (None, Some(length)) => match length.trim().parse() {
+                Ok(length) => Some(State::Body(length)),
+                Err(_) => return Err(invalid("bad Content-Length")),
+            },
+            (None, None) => None,
+        };
+        self.upgrade = header("upgrade").is_some();
+        self.rewrite(&buf[..len], out);
+        Ok(Some((len, body)))
+    }
+
+    /// Append the request head `raw` to `out` without the visitor's
+    /// [`SUBDOMAIN_HEADER`], and with ours when a wildcard tunnel matched.
+    fn rewrite(&self, raw: &[u8], out: &mut Vec<u8>) {
+        let mut lines = raw.split_inclusive(|&b| b == b'\n');
+        out.extend_from_slice(lines.next().unwrap_or_default());
+        if let Some(matched) = &self.matched {
+            out.extend_from_slice(format!("{SUBDOMAIN_HEADER}: {matched}\r\n").as_bytes());
+        }
+        for line in lines {
+            let name = line.split(|&b| b == b':').next().unwrap_or_default();
+            if !name
+                .trim_ascii()
+                .eq_ignore_ascii_case(SUBDOMAIN_HEADER.as_bytes())
+            {
+                out.extend_from_slice(line);
+            }
+        }
+    }
+
+    /// The state after a request: the next head, or waiting to see whether
+    /// the connection was upgraded.
+    fn end(&self) -> State {
+        match self.upgrade {
+            true => State::Upgrading {
+                held: Vec::new(),
+                status: Vec::new(),
+            },
+            false => State::Head(Vec::new()),
+        }
+    }
+
+    /// Whether the visitor's bytes are held until the response starts.
+    fn is_upgrading(&self) -> bool {
+        matches!(self.state, State::Upgrading { .. })
+    }
+
+    /// Look at `data` written back to the visitor. Once the status line of
+    /// the response to an upgrade is in, append what was held to `out`, as
+    /// it is after a `101` and as requests otherwise.
+    fn response(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let State::Upgrading { held, status } = &mut self.state else {
+            return Ok(());
+        };
+        // `HTTP/1.1 101`
+        status.extend_from_slice(&data[..data.len().min(12 - status.len())]);
+        if status.len() < 12 {
+            return Ok(());
+        }
+        let held = std::mem::take(held);
+        if status.ends_with(b" 101") {
+            self.state = State::Opaque;
+            out.extend_from_slice(&held);
+            return Ok(());
+        }
+        self.upgrade = false;
+        self.state = State::Head(Vec::new());
+        self.feed(&held, out)
+    }
+}
+
+/// Move bytes up to and including a newline from `data` to `line`, and pass
+/// them on to `out`. Returns the line's length without the newline once it
+/// is complete.
+fn take_line(line: &mut Vec<u8>, data: &mut &[u8], out: &mut Vec<u8>) -> io::Result<Option<usize>> {
+    let (chunk, complete) = match data.iter().position(|&b| b == b'\n') {
+        Some(i) => (&data[..=i], true),
+        None => (*data, false),
+    };
+    line.extend_from_slice(chunk);
+    out.extend_from_slice(chunk);
+    *data = &data[chunk.len()..];
+    if line.len() > MAX_HEAD {
+        return Err(invalid("chunk line too long"));
+    }
+    Ok(complete.then(|| line.len() - 1))
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// A visitor connection whose requests go through a [`Relabel`] as they are
+/// read.
+pub(crate) struct Relabeled<S> {
+    io: S,
+    relabel: Relabel,
+    /// Rewritten bytes not read yet.
+    pending: Vec<u8>,
+    /// How much of `pending` has been read.
+    read: usize,
+    /// The reader waiting for the response to an upgrade.
+    waiting: Option<Waker>,
+}
+
+impl<S> Relabeled<S> {
+    pub(crate) fn new(io: S, relabel: Relabel) -> Self {
+        Self {
+            io,
+            relabel,
+            pending: Vec::new(),
+            read: 0,
+            waiting: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Relabeled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        // A head is passed on whole, so it may take several reads.
+        while this.read == this.pending.len() {
+            if this.relabel.is_upgrading() {
+                this.waiting = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            this.pending.clear();
+            this.read = 0;
+            let mut bytes = [0; 8192];
+            let mut chunk = ReadBuf::new(&mut bytes);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.relabel.feed(chunk.filled(), &mut this.pending)?;
+        }
+        let n = buf.remaining().min(this.pending.len() - this.read);
+        buf.put_slice(&this.pending[this.read..this.read + n]);
+        this.read += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Relabeled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let n = ready!(Pin::new(&mut this.io).poll_write(cx, buf))?;
+        if this.relabel.is_upgrading() {
+            if this.read == this.pending.len() {
+                this.pending.clear();
+                this.read = 0;
+            }
+            this.relabel.response(&buf[..n], &mut this.pending)?;
+            if !this.relabel.is_upgrading() {
+                if let Some(reader) = this.waiting.take() {
+                    reader.wake();
+                }
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
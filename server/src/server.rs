@@ -0,0 +1,3260 @@
+//! Relay core: tunnel registry, control connections and inbound forwarding.
+
+use std::{
+    any::Any,
+    collections::{HashMap, VecDeque},
+    fmt, fs,
+    future::{pending, Future},
+    io,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{
+    future::{join_all, select_all},
+    SinkExt, StreamExt,
+};
+use humantime::format_duration;
+use socket2::{Domain, SockRef, Socket, Type};
+use sshx_core::{
+    auth::same_secret,
+    protocol::{
+        datagram_codec, multiplex, negotiate, tune_socket, Acl, ClientMsg, ClientSettings, Control,
+        ErrorCode, Framed_, Proto, ServerMsg, SessionType, Timeouts, BUFFER_SIZE, CONTROL_PORT,
+        HANDSHAKE_TIMEOUT, MAX_DATAGRAM, MAX_MISSED_HEARTBEATS, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION, STATS_INTERVAL, UDP_IDLE_TIMEOUT,
+    },
+    resume::{self, Link, RESUME_WINDOW},
+    // `splice` is taken by the relay below.
+    splice as zero_copy,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, watch},
+    task::{AbortHandle, JoinHandle},
+    time::{interval, sleep, sleep_until, timeout, Interval, MissedTickBehavior},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{bytes::Bytes, codec::Framed};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+use uuid::Uuid;
+
+use crate::{
+    admin,
+    audit::AuditLog,
+    auth::{self, AuthMetadata, AuthProvider, Identity},
+    bans::{BanList, BanTarget},
+    budget::{Backoff, Budget, ConnLimits, Counted},
+    cluster::{self, Cluster, ClusterConfig, Store},
+    geoip::{CountryRules, CountryStats, GeoIp, Location},
+    http, names,
+    pages::ErrorPages,
+    quic,
+    registry::{FileRegistry, MemoryRegistry, Registry, Reservation, RESTART_GRACE},
+    tarpit::{self, Offenders, Offense, Tarpit},
+    throttle::{AuthLimits, AuthThrottle},
+    tls::{self, ControlTlsConfig, Tls, TlsConfig},
+    traffic::{Metered, Traffic},
+    warm::{Handoff, WarmPool, WarmPools},
+    webhooks::{Notification, Notifier, Webhook},
+};
+
+// ── Config ────────────────────────────────────────────────────────────────────
+
+/// Visitor connections a tunnel may have parked at once, unless configured.
+pub const MAX_PENDING_PER_TUNNEL: usize = 64;
+
+/// How long a parked visitor waits for the `Accept` its client promised, or,
+/// for clients before version 5, for the `Accept` since it was announced.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Relay settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Minimum port for tunnels.
+    pub min_port: u16,
+    /// Maximum port for tunnels.
+    pub max_port: u16,
+    /// Addresses the control port, tunnel ports and the other public
+    /// listeners are bound on; each listener is bound on all of them. IPv6
+    /// addresses take IPv6 only, so a dual-stack server lists `::` and
+    /// `0.0.0.0`.
+    pub bind: Vec<IpAddr>,
+    /// Port of the shared HTTP listener that routes by `Host` header.
+    pub http_port: Option<u16>,
+    /// Base domain of tunnel hostnames, e.g. `tunnel.example.com`.
+    pub domain: Option<String>,
+    /// Terminate HTTPS for HTTP tunnels.
+    pub tls: Option<TlsConfig>,
+    /// Also accept clients on a TLS control port.
+    pub control_tls: Option<ControlTlsConfig>,
+    /// Address of the admin HTTP API; off when `None`.
+    pub admin_bind: Option<SocketAddr>,
+    /// Bytes/sec each tunnel may carry in each direction, unless the
+    /// client's [`Identity`] says otherwise.
+    pub max_bandwidth: Option<u64>,
+    /// How long a shutdown waits for visitor connections to finish.
+    pub drain_timeout: Duration,
+    /// Close tunnels that had no visitor connection for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Close tunnels this long after they registered.
+    pub max_tunnel_lifetime: Option<Duration>,
+    /// Hold the subdomain and port of a client that went away for this long,
+    /// so it gets them back when it reconnects.
+    pub reservation_grace: Option<Duration>,
+    /// Pages for HTTP visitors whose request can't reach a tunnel.
+    pub error_pages: ErrorPages,
+    /// Visitor connections a tunnel may have open at once; more are turned
+    /// away.
+    pub max_conns_per_tunnel: Option<usize>,
+    /// Visitor connections, across all tunnels, that may wait for their
+    /// client to pick them up; more are turned away.
+    pub max_pending: Option<usize>,
+    /// Visitor connections one tunnel may have waiting for its client to
+    /// pick them up; more are turned away.
+    pub max_pending_per_tunnel: usize,
+    /// Drop clients at protocol version 4 or later that leave this many
+    /// heartbeats in a row unanswered, over at least
+    /// [`Timeouts::heartbeat_timeout`].
+    pub max_missed_heartbeats: u32,
+    /// Handshake and heartbeat timing of control connections.
+    pub timeouts: Timeouts,
+    /// How often clients at protocol version 6 or later are sent the
+    /// traffic of their tunnels.
+    pub stats_interval: Duration,
+    /// `host:port` targets clients may pull; none when empty.
+    pub pull_targets: Vec<String>,
+    /// Also accept the visitors of TCP and HTTP tunnels on
+    /// `<dir>/<subdomain>.sock`, for a reverse proxy on the same host.
+    pub unix_socket_dir: Option<PathBuf>,
+    /// How clients that fail to authenticate are held back.
+    pub auth_limits: AuthLimits,
+    /// Refuse a `Hello` after auth unless it is sealed to the handshake.
+    /// Clients from before seals can't register then.
+    pub require_sealed_hello: bool,
+    /// Where tunnels registered and closed, failed authentication and
+    /// exceeded quotas are POSTed.
+    pub webhook: Option<Webhook>,
+    /// Databases visitors are looked up in, for their country and network.
+    pub geoip: Option<Arc<GeoIp>>,
+    /// Countries visitors may come from; they only apply with `geoip`.
+    pub country_rules: CountryRules,
+    /// Reset or drip on connections to the control ports that send garbage
+    /// or keep failing auth, and drop repeat offenders; off when `None`.
+    pub tarpit: Option<Tarpit>,
+    /// Stop accepting connections while this many are open across the
+    /// public listeners; no limit when `None`. Takes a restart to change.
+    pub conn_limits: Option<ConnLimits>,
+    /// Bytes copied at a time between a visitor and its client, in each
+    /// direction. Each connection holds two buffers this big.
+    pub buffer_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            min_port: 2000,
+            max_port: 65000,
+            bind: vec![IpAddr::from([0, 0, 0, 0])],
+            http_port: None,
+            domain: None,
+            tls: None,
+            control_tls: None,
+            admin_bind: None,
+            max_bandwidth: None,
+            drain_timeout: Duration::from_secs(30),
+            idle_timeout: None,
+            max_tunnel_lifetime: None,
+            reservation_grace: None,
+            error_pages: ErrorPages::default(),
+            max_conns_per_tunnel: None,
+            max_pending: None,
+            max_pending_per_tunnel: MAX_PENDING_PER_TUNNEL,
+            max_missed_heartbeats: MAX_MISSED_HEARTBEATS,
+            timeouts: Timeouts::default(),
+            stats_interval: STATS_INTERVAL,
+            pull_targets: Vec::new(),
+            unix_socket_dir: None,
+            auth_limits: AuthLimits::default(),
+            require_sealed_hello: false,
+            webhook: None,
+            geoip: None,
+            country_rules: CountryRules::default(),
+            tarpit: None,
+            conn_limits: None,
+            buffer_size: BUFFER_SIZE,
+        }
+    }
+}
+
+/// Settings a running server switches to; see [`Server::with_reload`].
+pub struct Reload {
+    /// Listeners, the domain and TLS stay as they were: changing them takes
+    /// a restart.
+    pub config: Config,
+    pub auth: Option<Box<dyn AuthProvider>>,
+}
+
+type ReloadFn = dyn Fn() -> Result<Reload> + Send + Sync;
+
+// ── Server ────────────────────────────────────────────────────────────────────
+
+/// An sshx relay that can be embedded in another application.
+pub struct Server {
+    config: Config,
+    auth: Option<Box<dyn AuthProvider>>,
+    bans: BanList,
+    settings_file: Option<PathBuf>,
+    shutdown: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    reload: Option<Box<ReloadFn>>,
+    cluster: Option<Cluster>,
+    state_file: Option<PathBuf>,
+    audit: Option<AuditLog>,
+    ready: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            auth: None,
+            bans: BanList::in_memory(),
+            settings_file: None,
+            shutdown: None,
+            reload: None,
+            cluster: None,
+            state_file: None,
+            audit: None,
+            ready: None,
+        }
+    }
+
+    /// Require clients to authenticate against `provider`.
+    pub fn with_auth(mut self, provider: impl AuthProvider) -> Self {
+        self.auth = Some(Box::new(provider));
+        self
+    }
+
+    /// Enforce (and keep refreshing) the given ban list.
+    pub fn with_bans(mut self, bans: BanList) -> Self {
+        self.bans = bans;
+        self
+    }
+
+    /// Push the [`ClientSettings`] in this JSON file to every client, and
+    /// push again whenever the file changes.
+    pub fn with_client_settings(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings_file = Some(path.into());
+        self
+    }
+
+    /// Keep tunnels and reservations in this JSON file. After a restart,
+    /// the subdomains and ports of the tunnels that were up are held for
+    /// their clients for [`Config::reservation_grace`], or five minutes
+    /// without one.
+    pub fn with_state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Append registrations, auth attempts and admin actions to `log`.
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Shut down gracefully once `signal` resolves: stop taking clients and
+    /// visitors, send clients [`ServerMsg::Shutdown`], and wait up to
+    /// [`Config::drain_timeout`] for connections in progress before returning.
+    pub fn with_shutdown(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(Box::pin(signal));
+        self
+    }
+
+    /// Call `ready` once every listener is bound and clients are taken, e.g.
+    /// to tell a service manager.
+    pub fn with_ready(mut self, ready: impl FnOnce() + Send + 'static) -> Self {
+        self.ready = Some(Box::new(ready));
+        self
+    }
+
+    /// Build fresh settings with `load` and switch to them, without dropping
+    /// a tunnel, on SIGHUP (on Unix) and on `POST /reload` to the admin API.
+    /// If `load` fails, the server keeps its settings.
+    pub fn with_reload(
+        mut self,
+        load: impl Fn() -> Result<Reload> + Send + Sync + 'static,
+    ) -> Self {
+        self.reload = Some(Box::new(load));
+        self
+    }
+
+    /// Run as a node of a cluster whose nodes share `store`: subdomains are
+    /// unique across the cluster, and HTTP visitors and `Accept`s reaching
+    /// this node for a tunnel held by another are handed to that node.
+    pub fn with_cluster(mut self, config: ClusterConfig, store: Arc<dyn Store>) -> Self {
+        self.cluster = Some(Cluster::new(config, store));
+        self
+    }
+
+    /// Bind the control port and serve until shut down.
+    pub async fn listen(self) -> Result<()> {
+        let binds = &self.config.bind;
+        let listeners = bind_all(binds, CONTROL_PORT)?;
+        info!(addrs = ?binds, port = CONTROL_PORT, "sshx-server listening");
+        self.serve_all(listeners).await
+    }
+
+    /// Serve control connections arriving on an already-bound listener.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        self.serve_all(vec![listener]).await
+    }
+
+    /// Serve control connections arriving on any of several already-bound
+    /// listeners, e.g. an IPv4 and an IPv6 one.
+    pub async fn serve_all(self, listeners: Vec<TcpListener>) -> Result<()> {
+        if listeners.is_empty() || self.config.bind.is_empty() {
+            bail!("nothing to listen on");
+        }
+        let mut signal = self.shutdown.unwrap_or_else(|| Box::pin(pending()));
+        let binds = &self.config.bind;
+        let http_listeners = match self.config.http_port {
+            Some(port) => {
+                let l = bind_all(binds, port)?;
+                info!(addrs = ?binds, port, "HTTP routing listening");
+                l
+            }
+            None => Vec::new(),
+        };
+        let https = match self.config.tls.clone() {
+            Some(config) => {
+                let l = bind_all(binds, config.port)?;
+                info!(addrs = ?binds, port = config.port, "HTTPS listening");
+                Some((l, Arc::new(Tls::new(config)?)))
+            }
+            None => None,
+        };
+        let control_tls = match &self.config.control_tls {
+            Some(config) => {
+                let acceptor = tls::control_acceptor(config)?;
+                let l = bind_all(binds, config.port)?;
+                info!(addrs = ?binds, port = config.port, "TLS control port listening");
+                Some((l, acceptor))
+            }
+            None => None,
+        };
+        let quic_endpoints = match &self.config.control_tls {
+            Some(config) if config.quic => binds
+                .iter()
+                .map(|&bind| quic::endpoint(config, bind))
+                .collect::<Result<_>>()?,
+            _ => Vec::new(),
+        };
+        let admin_listener = match self.config.admin_bind {
+            Some(addr) => {
+                let l = TcpListener::bind(addr).await?;
+                if !addr.ip().is_loopback() {
+                    warn!(%addr, "admin API is reachable from the network and has no auth");
+                }
+                info!(%addr, "admin API listening");
+                Some(l)
+            }
+            None => None,
+        };
+        let peer_listener = match &self.cluster {
+            Some(cluster) => {
+                let addr = cluster.config.bind;
+                // Whoever reaches the peer port can hand this node visitors
+                // and claim its parked connections.
+                if cluster.config.secret.is_none() && !addr.ip().is_loopback() {
+                    bail!("the cluster peer port listens on {addr}, so the cluster needs a secret");
+                }
+                let l = TcpListener::bind(addr).await?;
+                info!(%addr, node = %cluster.config.node_addr, "cluster peer port listening");
+                Some(l)
+            }
+            None => None,
+        };
+        let registry: Box<dyn Registry> = match self.state_file {
+            Some(path) => {
+                let grace = self.config.reservation_grace.unwrap_or(RESTART_GRACE);
+                Box::new(FileRegistry::load(path, grace)?)
+            }
+            None => Box::new(MemoryRegistry::new()),
+        };
+        let state = State::new(
+            self.config,
+            registry,
+            https.as_ref().map(|(_, tls)| Arc::clone(tls)),
+            self.auth,
+            self.bans,
+            self.reload,
+            self.cluster.map(Arc::new),
+            self.audit,
+        );
+        // Stopped when shutting down, which also releases their ports.
+        let mut tasks: Vec<JoinHandle<()>> = Vec::new();
+        for l in http_listeners {
+            tasks.push(tokio::spawn(http::serve(l, Arc::clone(&state))));
+        }
+        if let Some(l) = admin_listener {
+            tasks.push(tokio::spawn(admin::serve(l, Arc::clone(&state))));
+        }
+        if let Some((listeners, tls)) = https {
+            for l in listeners {
+                tasks.push(tokio::spawn(tls::serve(
+                    l,
+                    Arc::clone(&tls),
+                    Arc::clone(&state),
+                )));
+            }
+        }
+        tasks.push(tokio::spawn(maintain_bans(Arc::clone(&state))));
+        if let Some(l) = peer_listener {
+            tasks.push(tokio::spawn(cluster::serve(l, Arc::clone(&state))));
+            tasks.push(tokio::spawn(cluster::renew_claims(Arc::clone(&state))));
+        }
+        #[cfg(unix)]
+        if state.reload.is_some() {
+            tasks.push(tokio::spawn(reload_on_hangup(Arc::clone(&state))));
+        }
+        if let Some(path) = self.settings_file {
+            tasks.push(tokio::spawn(watch_client_settings(
+                Arc::clone(&state),
+                path,
+            )));
+        }
+        if let Some((listeners, acceptor)) = control_tls {
+            for l in listeners {
+                let accept = accept_control_tls(l, acceptor.clone(), Arc::clone(&state));
+                tasks.push(tokio::spawn(accept));
+            }
+        }
+        for endpoint in quic_endpoints {
+            tasks.push(tokio::spawn(quic::serve(endpoint, Arc::clone(&state))));
+        }
+        if let Some(ready) = self.ready {
+            ready();
+        }
+        let mut backoff = Backoff::default();
+        loop {
+            let accepted = tokio::select! {
+                accepted = async {
+                    state.budget.ready().await;
+                    accept_any(&listeners).await
+                } => accepted,
+                _ = &mut signal => break,
+            };
+            let (stream, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(err = %e, "control accept failed");
+                    backoff.failed().await;
+                    continue;
+                }
+            };
+            backoff.succeeded();
+            if state.bans.is_ip_banned(addr.ip()) {
+                debug!(%addr, "dropping control connection from banned IP");
+                continue;
+            }
+            let Some(held) = admit_control(&stream, addr, &state) else {
+                continue;
+            };
+            tune_control_socket(&stream, &state);
+            let stream = Counted::new(stream, state.budget.take());
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let serve = handle_control(stream, addr, Arc::clone(&state));
+                let (result, held) = tarpit::holding(held, serve).await;
+                control_ended(&state, addr, result, held).await;
+            });
+        }
+        drop(listeners);
+        for task in &tasks {
+            task.abort();
+        }
+        state.shut_down().await;
+        Ok(())
+    }
+}
+
+// ── State ─────────────────────────────────────────────────────────────────────
+
+pub(crate) struct State {
+    /// Registered tunnels, visitor connections waiting for a client's
+    /// `Accept`, and reservations.
+    pub(crate) registry: Box<dyn Registry>,
+    /// Swapped out by a reload; connections clone it before using it.
+    auth: RwLock<Option<Arc<dyn AuthProvider>>>,
+    pub(crate) bans: BanList,
+    /// Handshakes and failed auth attempts by IP.
+    pub(crate) throttle: AuthThrottle,
+    /// IPs the tarpit caught on the control ports.
+    pub(crate) offenders: Offenders,
+    /// Visitors by country, with a GeoIP database.
+    pub(crate) country_stats: CountryStats,
+    /// Connections open on the public listeners.
+    pub(crate) budget: Arc<Budget>,
+    /// Replaced by a reload, except for what only changes on restart.
+    config: RwLock<Config>,
+    reload: Option<Box<ReloadFn>>,
+    tls: Option<Arc<Tls>>,
+    /// Number of registrations rejected because the port range was full.
+    exhaustions: AtomicU64,
+    /// Multiplexed visitor connections whose stream to the client is still
+    /// being opened; they count towards `max_pending` with `pending`.
+    opening: AtomicUsize,
+    /// Settings pushed to clients; `None` until an operator provides some.
+    settings: watch::Sender<Option<ClientSettings>>,
+    /// Set once the server starts shutting down.
+    draining: watch::Sender<bool>,
+    /// Every visitor connection being relayed holds a receiver, so shutdown
+    /// can wait for the last one to drop.
+    in_flight: watch::Sender<()>,
+    /// Set when this server is a node of a cluster.
+    pub(crate) cluster: Option<Arc<Cluster>>,
+    /// Resumable streams by connection id, each taking the connections it
+    /// is resumed on.
+    resumable: Mutex<HashMap<Uuid, mpsc::Sender<Box<dyn Link>>>>,
+    /// Sessions' parked `Standby` connections.
+    warm: WarmPools,
+    /// Delivers to [`Config::webhook`].
+    webhooks: Notifier,
+    audit: Option<AuditLog>,
+}
+
+/// A registered tunnel, as seen by the rest of the server.
+#[derive(Clone)]
+pub(crate) struct Tunnel {
+    pub(crate) proto: Proto,
+    /// An HTTP tunnel that only takes HTTP/2 visitors.
+    pub(crate) grpc: bool,
+    /// Hands connections accepted elsewhere (e.g. HTTP routing) to the tunnel.
+    pub(crate) inbound: mpsc::Sender<Inbound>,
+    pub(crate) public_port: u16,
+    /// Peer address of the control connection that owns the tunnel.
+    pub(crate) client_addr: SocketAddr,
+    pub(crate) identity: String,
+    pub(crate) since: Instant,
+    pub(crate) traffic: Arc<Traffic>,
+    /// Visitors the client wants to let in.
+    pub(crate) acl: Arc<Acl>,
+    /// Round trip to the client, shared by the tunnels of its connection.
+    pub(crate) rtt: Arc<Rtt>,
+    /// The clients serving the tunnel.
+    pub(crate) pool: Arc<Pool>,
+    /// Session token of the control connection that registered it.
+    pub(crate) token: Uuid,
+}
+
+/// The control connections serving a tunnel: the one that registered it,
+/// and for a pool, the others that joined since. Visitors go to each in
+/// turn, and the tunnel goes away with the last.
+pub(crate) struct Pool {
+    /// Other clients may join.
+    open: bool,
+    /// Key of the client that registered the tunnel; a client with the same
+    /// one may take it over.
+    takeover: Option<String>,
+    members: Mutex<Vec<Member>>,
+    turn: AtomicUsize,
+    /// What the last member to leave takes down.
+    held: Mutex<Option<Held>>,
+}
+
+struct Member {
+    token: Uuid,
+    /// Its session's inbound connections.
+    inbound: mpsc::Sender<(String, Inbound)>,
+    /// Asks it to let go of the tunnel.
+    close: mpsc::Sender<(String, Closing)>,
+}
+
+/// Why a session is asked to let go of a tunnel.
+#[derive(Debug, Clone, Copy)]
+enum Closing {
+    /// The operator closed the tunnel.
+    Operator,
+    /// Another client took the tunnel over.
+    HandedOver,
+}
+
+/// The tasks and certificate of a tunnel, for as long as it has members.
+struct Held {
+    pump: AbortHandle,
+    /// Accepts visitors on the tunnel's Unix socket, if it has one.
+    unix_pump: Option<AbortHandle>,
+    /// Hostname whose certificate is kept fresh while the tunnel is up.
+    tls_host: Option<String>,
+}
+
+impl Pool {
+    fn new(open: bool, takeover: Option<String>, first: Member) -> Arc<Self> {
+        Arc::new(Self {
+            open,
+            takeover,
+            members: Mutex::new(vec![first]),
+            turn: AtomicUsize::new(0),
+            held: Mutex::new(None),
+        })
+    }
+
+    /// Whether `key` is the tunnel's takeover key, compared in constant
+    /// time since it is a secret.
+    fn taken_over_by(&self, key: Option<&str>) -> bool {
+        match (&self.takeover, key) {
+            (Some(ours), Some(key)) => same_secret(ours.as_bytes(), key.as_bytes()),
+            _ => false,
+        }
+    }
+
+    /// How many clients serve the tunnel.
+    pub(crate) fn len(&self) -> usize {
+        self.members.lock().unwrap().len()
+    }
+
+    /// Add a member, returning how many there are now, or `None` if the
+    /// last one left and the tunnel is going away.
+    fn join(&self, member: Member) -> Option<usize> {
+        let mut members = self.members.lock().unwrap();
+        if members.is_empty() {
+            return None;
+        }
+        members.push(member);
+        Some(members.len())
+    }
+
+    /// Make `member` the only one, returning those it replaced, or `None`
+    /// if the last one left and the tunnel is going away.
+    fn take_over(&self, member: Member) -> Option<Vec<Member>> {
+        let mut members = self.members.lock().unwrap();
+        if members.is_empty() {
+            return None;
+        }
+        Some(std::mem::replace(&mut *members, vec![member]))
+    }
+
+    /// Remove the member of session `token`, returning how many are left.
+    fn leave(&self, token: Uuid) -> usize {
+        let mut members = self.members.lock().unwrap();
+        members.retain(|m| m.token != token);
+        members.len()
+    }
+
+    /// Where the next visitor goes: the members in turn, skipping those
+    /// whose connection is going away.
+    fn next(&self) -> Option<mpsc::Sender<(String, Inbound)>> {
+        let members = self.members.lock().unwrap();
+        let start = self.turn.fetch_add(1, Ordering::Relaxed);
+        (0..members.len())
+            .map(|i| &members[(start + i) % members.len()])
+            .find(|m| !m.inbound.is_closed())
+            .map(|m| m.inbound.clone())
+    }
+
+    /// Ask every member to close the tunnel of `subdomain`.
+    async fn close(&self, subdomain: &str) -> bool {
+        let closes: Vec<_> = {
+            let members = self.members.lock().unwrap();
+            members.iter().map(|m| m.close.clone()).collect()
+        };
+        let mut closed = false;
+        for close in closes {
+            let closing = (subdomain.to_owned(), Closing::Operator);
+            closed |= close.send(closing).await.is_ok();
+        }
+        closed
+    }
+}
+
+/// Round trip of the last heartbeat a client answered.
+#[derive(Default)]
+pub(crate) struct Rtt(Mutex<Option<Duration>>);
+
+impl Rtt {
+    pub(crate) fn get(&self) -> Option<Duration> {
+        *self.0.lock().unwrap()
+    }
+
+    fn set(&self, rtt: Duration) {
+        *self.0.lock().unwrap() = Some(rtt);
+    }
+}
+
+/// A visitor's byte stream: plain TCP or terminated TLS.
+pub(crate) trait Io: AsyncRead + AsyncWrite + Unpin + Send + Sync + Any {
+    /// The stream itself, to look for a plain TCP socket under it.
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static> Io for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// An inbound connection on its way to a client.
+pub(crate) struct Inbound {
+    pub(crate) stream: Box<dyn Io>,
+    pub(crate) addr: SocketAddr,
+    /// Bytes already read from `stream`, e.g. a request head used for routing.
+    pub(crate) prefix: Vec<u8>,
+}
+
+/// A tunnel held by a control connection; dropping it unregisters it.
+struct Registration {
+    state: Arc<State>,
+    subdomain: String,
+    public_port: u16,
+    /// Peer address of the control connection.
+    client_addr: SocketAddr,
+    identity: String,
+    /// Every address the port is bound on.
+    addrs: Vec<SocketAddr>,
+    url: Option<String>,
+    /// The clients serving the tunnel, this one included.
+    pool: Arc<Pool>,
+    since: Instant,
+    /// How long the client wants it up.
+    ttl: Option<Duration>,
+    traffic: Arc<Traffic>,
+    /// Session token of the control connection that registered it.
+    token: Uuid,
+    /// Hold the port for the client when the tunnel goes away.
+    reserve: bool,
+}
+
+impl Registration {
+    /// Unregister without holding the port, e.g. because the server closed
+    /// the tunnel on purpose.
+    fn close(mut self) {
+        self.reserve = false;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let members = self.pool.leave(self.token);
+        if members > 0 {
+            info!(subdomain = %self.subdomain, client = %self.client_addr, members, "client left the pool");
+            return;
+        }
+        let held = self.pool.held.lock().unwrap().take();
+        if let Some(held) = &held {
+            held.pump.abort();
+            if let Some(pump) = &held.unix_pump {
+                pump.abort();
+            }
+        }
+        self.state.registry.release(&self.subdomain);
+        if let Some(cluster) = &self.state.cluster {
+            cluster.release(self.subdomain.clone());
+        }
+        let mut grace = self.state.config().reservation_grace;
+        // Tunnels going down with the server are held across the restart.
+        if *self.state.draining.borrow() && self.state.registry.outlives_restarts() {
+            grace = Some(grace.unwrap_or(RESTART_GRACE));
+        }
+        if let (true, Some(grace)) = (self.reserve, grace) {
+            let reservation = Reservation {
+                port: self.public_port,
+                token: self.token,
+                until: Instant::now() + grace,
+            };
+            self.state.registry.reserve(&self.subdomain, reservation);
+        }
+        let tls_host = held.and_then(|held| held.tls_host);
+        if let (Some(tls), Some(host)) = (&self.state.tls, tls_host) {
+            tls.release(&host);
+        }
+        info!(subdomain = %self.subdomain, "tunnel closed");
+        let traffic = self.traffic.snapshot();
+        self.state.notify(Notification::closed(
+            self.client_addr.ip(),
+            &self.identity,
+            &self.subdomain,
+            self.public_port,
+            (traffic.bytes_in, traffic.bytes_out),
+        ));
+    }
+}
+
+/// The public sockets of a tunnel, one per bind address, on the same port.
+enum Listener {
+    Tcp(Vec<TcpListener>),
+    Udp(Vec<UdpSocket>),
+}
+
+impl Listener {
+    fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            Self::Tcp(listeners) => listeners.iter().map(|l| l.local_addr()).collect(),
+            Self::Udp(sockets) => sockets.iter().map(|s| s.local_addr()).collect(),
+        }
+    }
+}
+
+/// Why a tunnel port could not be claimed.
+#[derive(Debug)]
+enum ClaimError {
+    SubdomainTaken(String),
+    /// Held for the client that had it until it reconnects.
+    SubdomainReserved {
+        subdomain: String,
+        left: Duration,
+    },
+    PortOutsideRange {
+        port: u16,
+        min_port: u16,
+        max_port: u16,
+    },
+    PortTaken(u16),
+    /// The client's identity may not bind this port.
+    PortNotPermitted {
+        port: u16,
+        identity: String,
+    },
+    RangeExhausted {
+        min_port: u16,
+        max_port: u16,
+        in_use: usize,
+        capacity: usize,
+    },
+}
+
+impl fmt::Display for ClaimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SubdomainTaken(s) => write!(f, "subdomain '{s}' is already taken"),
+            Self::SubdomainReserved { subdomain, left } => write!(
+                f,
+                "subdomain '{subdomain}' is reserved for its previous client for another {}s",
+                left.as_secs().max(1)
+            ),
+            Self::PortOutsideRange {
+                port,
+                min_port,
+                max_port,
+            } => write!(
+                f,
+                "port {port} is outside the allowed range {min_port}-{max_port}"
+            ),
+            Self::PortTaken(port) => write!(f, "port {port} is already in use"),
+            Self::PortNotPermitted { port, identity } => write!(
+                f,
+                "port {port} is outside the allowed range of '{identity}'"
+            ),
+            Self::RangeExhausted {
+                min_port,
+                max_port,
+                in_use,
+                capacity,
+            } => write!(
+                f,
+                "port range {min_port}-{max_port} exhausted ({in_use}/{capacity} ports held by tunnels)"
+            ),
+        }
+    }
+}
+
+/// Why a registration was turned down, as told to the client. Failures
+/// that aren't the client's doing have no code.
+struct Refusal {
+    code: Option<ErrorCode>,
+    message: String,
+}
+
+impl Refusal {
+    fn new(code: ErrorCode, message: String) -> Self {
+        Self {
+            code: Some(code),
+            message,
+        }
+    }
+
+    /// `Refused` for clients that understand it (`codes`), `Error` otherwise.
+    fn into_msg(self, codes: bool) -> ServerMsg {
+        match self.code {
+            Some(code) if codes => ServerMsg::Refused {
+                code,
+                message: self.message,
+            },
+            _ => ServerMsg::Error(self.message),
+        }
+    }
+}
+
+impl From<String> for Refusal {
+    fn from(message: String) -> Self {
+        Self {
+            code: None,
+            message,
+        }
+    }
+}
+
+impl From<ClaimError> for Refusal {
+    fn from(e: ClaimError) -> Self {
+        let code = match e {
+            ClaimError::SubdomainTaken(_) | ClaimError::SubdomainReserved { .. } => {
+                ErrorCode::SubdomainTaken
+            }
+            ClaimError::PortOutsideRange { .. } => ErrorCode::PortOutOfRange,
+            ClaimError::PortTaken(_) => ErrorCode::PortTaken,
+            ClaimError::PortNotPermitted { .. } => ErrorCode::PortNotPermitted,
+            ClaimError::RangeExhausted { .. } => ErrorCode::NoPorts,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+impl State {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        config: Config,
+        registry: Box<dyn Registry>,
+        tls: Option<Arc<Tls>>,
+        auth: Option<Box<dyn AuthProvider>>,
+        bans: BanList,
+        reload: Option<Box<ReloadFn>>,
+        cluster: Option<Arc<Cluster>>,
+        audit: Option<AuditLog>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            budget: Budget::new(config.conn_limits),
+            registry,
+            auth: RwLock::new(auth.map(Arc::from)),
+            bans,
+            throttle: AuthThrottle::default(),
+            offenders: Offenders::default(),
+            country_stats: CountryStats::default(),
+            config: RwLock::new(config),
+            reload,
+            tls,
+            exhaustions: AtomicU64::new(0),
+            opening: AtomicUsize::new(0),
+            settings: watch::Sender::new(None),
+            draining: watch::Sender::new(false),
+            in_flight: watch::Sender::new(()),
+            cluster,
+            resumable: Mutex::default(),
+            warm: WarmPools::default(),
+            webhooks: Notifier::start(),
+            audit,
+        })
+    }
+
+    /// The settings in force right now. Don't hold on to them across an
+    /// `.await`.
+    pub(crate) fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap()
+    }
+
+    /// `err`, which ended the handshake of a control connection from
+    /// `addr`, as an [`Offense`] if the peer sent `garbage` and the tarpit
+    /// is on.
+    fn offense(&self, addr: SocketAddr, err: anyhow::Error, garbage: bool) -> anyhow::Error {
+        let tarpit = self.config().tarpit;
+        match tarpit {
+            Some(tarpit) if garbage => {
+                let count = self.offenders.record(addr.ip(), &tarpit);
+                let reason = err.to_string();
+                Offense { count, reason }.into()
+            }
+            _ => err,
+        }
+    }
+
+    /// Tell the operator's webhook and audit log, if there are any.
+    fn notify(&self, notification: Notification) {
+        let webhook = self.config().webhook.clone();
+        if let Some(webhook) = webhook {
+            self.audit(notification.clone());
+            self.webhooks.send(webhook, notification);
+        } else {
+            self.audit(notification);
+        }
+    }
+
+    /// Append to the audit log, if there is one.
+    pub(crate) fn audit(&self, notification: Notification) {
+        if let Some(audit) = &self.audit {
+            audit.record(notification);
+        }
+    }
+
+    /// Where a visitor from `ip` comes from; nowhere without a GeoIP
+    /// database.
+    pub(crate) fn locate(&self, ip: IpAddr) -> Location {
+        let config = self.config();
+        match &config.geoip {
+            Some(geoip) => geoip.locate(ip),
+            None => Location::default(),
+        }
+    }
+
+    /// Look up a visitor from `ip` that was just accepted and hold it to
+    /// the country rules: `Err` with where it comes from if it is turned
+    /// away.
+    pub(crate) fn admit_visitor(&self, ip: IpAddr) -> Result<Location, Location> {
+        let config = self.config();
+        let Some(geoip) = &config.geoip else {
+            return Ok(Location::default());
+        };
+        let location = geoip.locate(ip);
+        let permitted = config.country_rules.permit(location.country.as_deref());
+        self.country_stats
+            .count(location.country.as_deref(), !permitted);
+        match permitted {
+            true => Ok(location),
+            false => Err(location),
+        }
+    }
+
+    /// Range tunnel ports are drawn from.
+    fn port_range(&self) -> RangeInclusive<u16> {
+        let config = self.config();
+        config.min_port..=config.max_port
+    }
+
+    /// Switch to the settings the reload function builds. Tunnels already
+    /// registered keep their port and bandwidth limit; clients that connect
+    /// from now on get the new ones.
+    pub(crate) fn reload(&self) -> Result<()> {
+        let Some(load) = &self.reload else {
+            bail!("this server has no way to reload its settings");
+        };
+        let Reload { config, auth } = load()?;
+        {
+            let mut current = self.config.write().unwrap();
+            let restart = current.bind != config.bind
+                || current.http_port != config.http_port
+                || current.domain != config.domain
+                || current.admin_bind != config.admin_bind
+                || current.tls.is_some() != config.tls.is_some()
+                || current.control_tls.is_some() != config.control_tls.is_some();
+            if restart {
+                warn!("listeners, domain and TLS only change on restart; keeping the old ones");
+            }
+            *current = Config {
+                bind: std::mem::take(&mut current.bind),
+                http_port: current.http_port,
+                domain: current.domain.take(),
+                tls: current.tls.take(),
+                control_tls: current.control_tls.take(),
+                admin_bind: current.admin_bind,
+                ..config
+            };
+        }
+        *self.auth.write().unwrap() = auth.map(Arc::from);
+        if let Err(e) = self.bans.refresh() {
+            warn!(err = %e, "cannot reload the ban list");
+        }
+        let range = self.port_range();
+        info!(
+            min_port = range.start(),
+            max_port = range.end(),
+            "settings reloaded"
+        );
+        Ok(())
+    }
+
+    /// Tell control connections to wind down, then wait for visitor
+    /// connections in progress, up to the drain timeout.
+    async fn shut_down(&self) {
+        self.draining.send_replace(true);
+        let active = self.in_flight.receiver_count();
+        let drain_timeout = self.config().drain_timeout;
+        info!(active, timeout = ?drain_timeout, "shutting down, draining connections");
+        match timeout(drain_timeout, self.in_flight.closed()).await {
+            Ok(()) => info!("all connections drained"),
+            Err(_) => warn!(
+                remaining = self.in_flight.receiver_count(),
+                "drain timeout reached, dropping connections"
+            ),
+        }
+    }
+
+    /// Resolves once the server starts shutting down.
+    async fn draining(&self) {
+        let _ = self.draining.subscribe().wait_for(|&d| d).await;
+    }
+
+    /// Visitor connections handed to a client that it hasn't picked up yet.
+    fn waiting(&self) -> usize {
+        self.registry.parked() + self.opening.load(Ordering::Relaxed)
+    }
+
+    /// Take the visitor connection `id` parked for a client's `Accept`.
+    pub(crate) fn take_pending(&self, id: Uuid) -> Option<Inbound> {
+        self.registry.unpark(id)
+    }
+
+    /// Held while relaying a visitor connection, so shutdown waits for it.
+    pub(crate) fn in_flight(&self) -> watch::Receiver<()> {
+        self.in_flight.subscribe()
+    }
+
+    /// Ports held by tunnels vs. total ports in the configured range.
+    fn utilization(&self) -> (usize, usize) {
+        (self.registry.tunnel_count(), self.port_range().len())
+    }
+
+    /// Close the tunnel of `subdomain`, wherever it is held. Returns whether
+    /// there was one.
+    pub(crate) async fn close_tunnel(&self, subdomain: &str) -> bool {
+        let Some(pool) = self.registry.tunnel(subdomain).map(|t| t.pool) else {
+            return false;
+        };
+        pool.close(subdomain).await
+    }
+
+    /// The subdomain a request for `host` is meant for, if any.
+    pub(crate) fn subdomain_for_host(&self, host: &str) -> Option<String> {
+        // Strip the port; IPv6 literals never name a tunnel.
+        if host.starts_with('[') {
+            return None;
+        }
+        let host = host.split(':').next()?.trim_end_matches('.');
+        let host = host.to_ascii_lowercase();
+        let config = self.config();
+        let label = match &config.domain {
+            Some(domain) => host.strip_suffix(domain.as_str())?.strip_suffix('.')?,
+            None => host.split_once('.')?.0,
+        };
+        (!label.is_empty()).then(|| label.to_owned())
+    }
+
+    /// Hostname of an HTTP tunnel, when a base domain is configured.
+    fn hostname(&self, subdomain: &str) -> Option<String> {
+        Some(format!("{subdomain}.{}", self.config().domain.as_ref()?))
+    }
+
+    /// Public URL of an HTTP tunnel, preferring HTTPS when its hostname has
+    /// a certificate.
+    pub(crate) fn http_url(&self, subdomain: &str) -> Option<String> {
+        let host = self.hostname(subdomain)?;
+        let (scheme, port, default) = match (&self.tls, self.config().http_port) {
+            (Some(tls), _) if tls.serves(&host) => ("https", tls.port(), 443),
+            (_, Some(port)) => ("http", port, 80),
+            _ => return None,
+        };
+        Some(if port == default {
+            format!("{scheme}://{host}")
+        } else {
+            format!("{scheme}://{host}:{port}")
+        })
+    }
+
+    /// Register a tunnel under a random subdomain, for a client that left
+    /// the choice to us. Names that turn out to be taken are skipped: the
+    /// client never joins or takes over a tunnel it happened to draw, and
+    /// doesn't start a pool others could join by guessing the name.
+    async fn register_random(
+        self: &Arc<Self>,
+        proto: Proto,
+        grpc: bool,
+        desired_port: Option<u16>,
+        session: &Session,
+    ) -> Result<(String, Registration), Refusal> {
+        let mut attempts = 0;
+        loop {
+            let subdomain = names::random();
+            let registered = match self.registry.tunnel(&subdomain) {
+                Some(_) => Err(Refusal::new(
+                    ErrorCode::SubdomainTaken,
+                    format!("subdomain '{subdomain}' is taken"),
+                )),
+                None => {
+                    self.register(&subdomain, proto, grpc, false, desired_port, session)
+                        .await
+                }
+            };
+            match registered {
+                Ok(registration) => return Ok((subdomain, registration)),
+                Err(refusal)
+                    if refusal.code == Some(ErrorCode::SubdomainTaken) && attempts < 10 =>
+                {
+                    attempts += 1;
+                }
+                Err(refusal) => return Err(refusal),
+            }
+        }
+    }
+
+    /// Register a tunnel for a control connection. Its inbound connections
+    /// are sent to the session, tagged with the subdomain. `grpc` only
+    /// matters for HTTP tunnels. With `pool`, the session joins the pool
+    /// already serving the subdomain, or starts one other clients can join.
+    /// A session with the takeover key the tunnel was registered with takes
+    /// it over instead.
+    async fn register(
+        self: &Arc<Self>,
+        subdomain: &str,
+        proto: Proto,
+        grpc: bool,
+        pool: bool,
+        desired_port: Option<u16>,
+        session: &Session,
+    ) -> Result<Registration, Refusal> {
+        if let Some(ban) = self.bans.check(&BanTarget::Subdomain(subdomain.to_owned())) {
+            return Err(Refusal::new(
+                ErrorCode::SubdomainBanned,
+                format!("subdomain '{subdomain}' is banned: {}", ban.reason),
+            ));
+        }
+        check_wildcard(subdomain, proto)?;
+        if pool && proto == Proto::Udp {
+            return Err(Refusal::new(
+                ErrorCode::ProtocolNotPermitted,
+                "UDP tunnels can't be pooled".into(),
+            ));
+        }
+        // Without auth every client is the same anonymous identity, so any
+        // of them could join the pool and take its visitors.
+        if pool && self.auth.read().unwrap().is_none() {
+            return Err(Refusal::new(
+                ErrorCode::ProtocolNotPermitted,
+                "pools need auth on this server".into(),
+            ));
+        }
+        // Joining or taking over doesn't open another tunnel, so it isn't
+        // held to the identity's limit.
+        if let Some(tunnel) = self.registry.tunnel(subdomain) {
+            let held_here = session
+                .registrations
+                .iter()
+                .any(|r| r.subdomain == subdomain);
+            let takeover = tunnel.pool.taken_over_by(session.takeover.as_deref())
+                && tunnel.proto != Proto::Udp
+                && !held_here;
+            if pool || takeover {
+                return self
+                    .join_pool(subdomain, tunnel, proto, grpc, takeover, session)
+                    .await;
+            }
+        }
+        if let Err(refusal) = self.check_policy(subdomain, proto, &session.identity) {
+            if refusal.code == Some(ErrorCode::QuotaExceeded) {
+                self.notify(Notification::quota_exceeded(
+                    session.addr.ip(),
+                    &session.identity.name,
+                    subdomain,
+                    refusal.message.clone(),
+                ));
+            }
+            return Err(refusal);
+        }
+        if let Some(cluster) = &self.cluster {
+            if cluster.held_elsewhere(subdomain).await {
+                return Err(Refusal::new(
+                    ErrorCode::SubdomainTaken,
+                    format!("subdomain '{subdomain}' is taken on another node"),
+                ));
+            }
+        }
+        let identity = &session.identity;
+        let (routed_tx, routed) = mpsc::channel(64);
+        let limit = session
+            .identity
+            .max_bandwidth
+            .or(self.config().max_bandwidth);
+        let traffic = Arc::new(Traffic::new(limit, session.max_uses));
+        let since = Instant::now();
+        let unix_routed = routed_tx.clone();
+        let tunnel = Tunnel {
+            proto,
+            grpc: grpc && proto == Proto::Http,
+            inbound: routed_tx,
+            public_port: 0,
+            client_addr: session.addr,
+            identity: session.identity.name.clone(),
+            since,
+            traffic: Arc::clone(&traffic),
+            acl: Arc::clone(&session.acl),
+            rtt: Arc::clone(&session.rtt),
+            pool: Pool::new(
+                pool,
+                session.takeover.clone(),
+                Member {
+                    token: session.token,
+                    inbound: session.inbound_tx.clone(),
+                    close: session.close_tx.clone(),
+                },
+            ),
+            token: session.token,
+        };
+        let members = Arc::clone(&tunnel.pool);
+        let listener = self
+            .claim_port(subdomain, tunnel, desired_port, session.resume, identity)
+            .await?;
+        let addrs = match listener.local_addrs() {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                self.registry.release(subdomain);
+                return Err(e.to_string().into());
+            }
+        };
+        let public_port = addrs[0].port();
+        let unix_dir = self.config().unix_socket_dir.clone();
+        let unix_pump = match (unix_dir, proto) {
+            (Some(dir), Proto::Tcp | Proto::Http) if !is_wildcard(subdomain) => {
+                match bind_unix(&dir, subdomain, unix_routed) {
+                    Ok(pump) => Some(pump),
+                    Err(e) => {
+                        self.registry.release(subdomain);
+                        return Err(format!("{e:#}").into());
+                    }
+                }
+            }
+            _ => None,
+        };
+        let (tx, state, name) = (
+            session.inbound_tx.clone(),
+            Arc::clone(self),
+            subdomain.to_owned(),
+        );
+        let acl = Arc::clone(&session.acl);
+        let pump = match listener {
+            Listener::Tcp(listeners) => {
+                let (members, traffic) = (Arc::clone(&members), Arc::clone(&traffic));
+                tokio::spawn(pump(listeners, routed, members, state, name, traffic, acl))
+            }
+            Listener::Udp(sockets) => {
+                let pumps: Vec<_> = sockets
+                    .into_iter()
+                    .map(|socket| {
+                        let (tx, state, name) = (tx.clone(), Arc::clone(&state), name.clone());
+                        pump_udp(
+                            socket,
+                            tx,
+                            state,
+                            name,
+                            Arc::clone(&traffic),
+                            Arc::clone(&acl),
+                        )
+                    })
+                    .collect();
+                tokio::spawn(async move {
+                    join_all(pumps).await;
+                })
+            }
+        };
+        // TLS-ALPN-01 can't validate wildcard certificates. Anyone may open
+        // a tunnel on an open server, so only clients that authenticated
+        // get certificates ordered for them.
+        let may_order = self.auth.read().unwrap().is_some();
+        let tls_host = match (proto, &self.tls) {
+            (Proto::Http, Some(tls)) if !is_wildcard(subdomain) => self
+                .hostname(subdomain)
+                .filter(|h| tls.ensure(h, grpc, may_order)),
+            _ => None,
+        };
+        let url = match proto {
+            Proto::Http => self.http_url(subdomain),
+            _ => None,
+        };
+        *members.held.lock().unwrap() = Some(Held {
+            pump: pump.abort_handle(),
+            unix_pump,
+            tls_host,
+        });
+        let (in_use, capacity) = self.utilization();
+        info!(
+            subdomain,
+            public_port,
+            identity = %session.identity.name,
+            pool,
+            in_use,
+            capacity,
+            "tunnel registered"
+        );
+        self.notify(Notification::registered(
+            session.addr.ip(),
+            &session.identity.name,
+            subdomain,
+            proto,
+            public_port,
+        ));
+        Ok(Registration {
+            state: Arc::clone(self),
+            subdomain: subdomain.to_owned(),
+            public_port,
+            client_addr: session.addr,
+            identity: session.identity.name.clone(),
+            addrs,
+            url,
+            pool: members,
+            since,
+            ttl: session.ttl,
+            traffic,
+            token: session.token,
+            reserve: true,
+        })
+    }
+
+    /// Add a session to the pool serving `subdomain` through `tunnel`, or
+    /// with `takeover`, make it the only one: the others are told to let go
+    /// and keep only the connections they have. Only the identity that
+    /// started it may join, with the same kind of tunnel.
+    async fn join_pool(
+        self: &Arc<Self>,
+        subdomain: &str,
+        tunnel: Tunnel,
+        proto: Proto,
+        grpc: bool,
+        takeover: bool,
+        session: &Session,
+    ) -> Result<Registration, Refusal> {
+        let same_kind = tunnel.proto == proto && tunnel.grpc == (grpc && proto == Proto::Http);
+        let may_join = tunnel.pool.open || takeover;
+        if !may_join || !same_kind || tunnel.identity != session.identity.name {
+            return Err(ClaimError::SubdomainTaken(subdomain.to_owned()).into());
+        }
+        let member = Member {
+            token: session.token,
+            inbound: session.inbound_tx.clone(),
+            close: session.close_tx.clone(),
+        };
+        let taken = match takeover {
+            true => tunnel.pool.take_over(member),
+            false => tunnel.pool.join(member).map(|_| Vec::new()),
+        };
+        let Some(replaced) = taken else {
+            return Err(ClaimError::SubdomainTaken(subdomain.to_owned()).into());
+        };
+        let client = session.addr;
+        match takeover {
+            true => {
+                let replaced = replaced.len();
+                info!(subdomain, %client, replaced, "client took over the tunnel");
+            }
+            false => {
+                let members = tunnel.pool.len();
+                info!(subdomain, %client, members, "client joined the pool");
+            }
+        }
+        for member in replaced {
+            let closing = (subdomain.to_owned(), Closing::HandedOver);
+            let _ = member.close.send(closing).await;
+        }
+        let addrs = self
+            .config()
+            .bind
+            .iter()
+            .map(|&ip| SocketAddr::new(ip, tunnel.public_port))
+            .collect();
+        Ok(Registration {
+            state: Arc::clone(self),
+            subdomain: subdomain.to_owned(),
+            public_port: tunnel.public_port,
+            client_addr: session.addr,
+            identity: session.identity.name.clone(),
+            addrs,
+            url: match proto {
+                Proto::Http => self.http_url(subdomain),
+                _ => None,
+            },
+            pool: tunnel.pool,
+            since: Instant::now(),
+            ttl: session.ttl,
+            traffic: tunnel.traffic,
+            token: session.token,
+            reserve: true,
+        })
+    }
+
+    /// Why `registration` is due to be closed, if it is.
+    fn expiry(&self, registration: &Registration) -> Option<String> {
+        let name = &registration.subdomain;
+        let (lifetime, idle_timeout) = {
+            let config = self.config();
+            (config.max_tunnel_lifetime, config.idle_timeout)
+        };
+        if let Some(max) = lifetime {
+            if registration.since.elapsed() >= max {
+                return Some(format!(
+                    "tunnel '{name}' reached the server's maximum lifetime of {}",
+                    format_duration(max)
+                ));
+            }
+        }
+        if let Some(ttl) = registration.ttl {
+            if registration.since.elapsed() >= ttl {
+                return Some(format!(
+                    "tunnel '{name}' reached its time limit of {}",
+                    format_duration(ttl)
+                ));
+            }
+        }
+        if let Some(uses) = registration.traffic.used_up() {
+            let visitors = match uses {
+                1 => "its one visitor".to_owned(),
+                n => format!("all {n} of its visitors"),
+            };
+            return Some(format!("tunnel '{name}' closed after {visitors}"));
+        }
+        let limit = idle_timeout?;
+        let idle = registration.traffic.idle_for()?;
+        (idle >= limit).then(|| {
+            format!(
+                "tunnel '{name}' expired after {} without visitors",
+                format_duration(limit)
+            )
+        })
+    }
+
+    /// Whether `identity` may open a `proto` tunnel for `subdomain`.
+    fn check_policy(
+        &self,
+        subdomain: &str,
+        proto: Proto,
+        identity: &Identity,
+    ) -> Result<(), Refusal> {
+        let name = &identity.name;
+        if !identity.may_claim(subdomain) {
+            return Err(Refusal::new(
+                ErrorCode::SubdomainNotPermitted,
+                format!("'{name}' is not permitted to claim subdomain '{subdomain}'"),
+            ));
+        }
+        if !identity.may_open(proto) {
+            return Err(Refusal::new(
+                ErrorCode::ProtocolNotPermitted,
+                format!(
+                    "'{name}' is not permitted to open {} tunnels",
+                    format!("{proto:?}").to_lowercase()
+                ),
+            ));
+        }
+        if let Some(max) = identity.max_tunnels {
+            let tunnels = self.registry.tunnels();
+            let open = tunnels.iter().filter(|(_, t)| &t.identity == name).count();
+            if open >= max {
+                return Err(Refusal::new(
+                    ErrorCode::QuotaExceeded,
+                    format!("'{name}' has reached its limit of open tunnels ({max})"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Try to bind a listener for the given subdomain, and record `tunnel`
+    /// under it once one is bound. A `desired` port is the only one tried;
+    /// otherwise the port reserved for a `resume`d session comes first, then
+    /// ports drawn from those `identity` may bind.
+    async fn claim_port(
+        &self,
+        subdomain: &str,
+        mut tunnel: Tunnel,
+        desired: Option<u16>,
+        resume: Option<Uuid>,
+        identity: &Identity,
+    ) -> Result<Listener, ClaimError> {
+        if self.registry.tunnel(subdomain).is_some() {
+            return Err(ClaimError::SubdomainTaken(subdomain.to_owned()));
+        }
+        let reserved = self.reclaim(subdomain, resume)?;
+        if let Some(port) = desired {
+            if reserved != Some(port) && self.is_reserved(port) {
+                return Err(ClaimError::PortTaken(port));
+            }
+            let range = self.port_range();
+            if !range.contains(&port) {
+                return Err(ClaimError::PortOutsideRange {
+                    port,
+                    min_port: *range.start(),
+                    max_port: *range.end(),
+                });
+            }
+            if !identity.may_bind(port) {
+                return Err(ClaimError::PortNotPermitted {
+                    port,
+                    identity: identity.name.clone(),
+                });
+            }
+            let l = self
+                .bind_port(tunnel.proto, port)
+                .map_err(|_| ClaimError::PortTaken(port))?;
+            tunnel.public_port = port;
+            return self.record(subdomain, tunnel, l);
+        }
+        let reserved = reserved.filter(|&port| identity.may_bind(port));
+        if let Some(port) = reserved {
+            if let Ok(l) = self.bind_port(tunnel.proto, port) {
+                tunnel.public_port = port;
+                return self.record(subdomain, tunnel, l);
+            }
+        }
+        // Try 150 random ports (same probabilistic argument as bore).
+        for _ in 0..150 {
+            let Some(port) = self.random_port(&identity.ports) else {
+                break;
+            };
+            if self.is_reserved(port) {
+                continue;
+            }
+            match self.bind_port(tunnel.proto, port) {
+                Ok(l) => {
+                    tunnel.public_port = port;
+                    return self.record(subdomain, tunnel, l);
+                }
+                Err(_) => continue,
+            }
+        }
+        let (in_use, capacity) = self.utilization();
+        let total = self.exhaustions.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(in_use, capacity, total, "port range exhausted");
+        let range = self.port_range();
+        Err(ClaimError::RangeExhausted {
+            min_port: *range.start(),
+            max_port: *range.end(),
+            in_use,
+            capacity,
+        })
+    }
+
+    /// Record `tunnel`, which got `listener`, unless another tunnel took
+    /// `subdomain` while the port was being bound.
+    fn record(
+        &self,
+        subdomain: &str,
+        tunnel: Tunnel,
+        listener: Listener,
+    ) -> Result<Listener, ClaimError> {
+        match self.registry.claim(subdomain, tunnel) {
+            Ok(()) => Ok(listener),
+            Err(_) => Err(ClaimError::SubdomainTaken(subdomain.to_owned())),
+        }
+    }
+
+    /// Take back the reservation of `subdomain` for the session that held
+    /// it, returning its port. Someone else's reservation keeps the name.
+    fn reclaim(&self, subdomain: &str, resume: Option<Uuid>) -> Result<Option<u16>, ClaimError> {
+        let mine = resume.and_then(|token| self.registry.take_reservation(subdomain, token));
+        if let Some(reservation) = mine {
+            return Ok(Some(reservation.port));
+        }
+        match self.registry.reservation(subdomain).and_then(|r| r.left()) {
+            Some(left) => Err(ClaimError::SubdomainReserved {
+                subdomain: subdomain.to_owned(),
+                left,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether `subdomain` is held for a client that went away.
+    pub(crate) fn is_held(&self, subdomain: &str) -> bool {
+        self.registry.reservation(subdomain).is_some()
+    }
+
+    /// Whether `port` is held for a client that went away.
+    fn is_reserved(&self, port: u16) -> bool {
+        self.registry.is_port_reserved(port)
+    }
+
+    /// A random port in the configured range and in one of `allowed`, if
+    /// there are any. `None` when the two don't overlap.
+    fn random_port(&self, allowed: &[RangeInclusive<u16>]) -> Option<u16> {
+        let range = self.port_range();
+        if allowed.is_empty() {
+            return Some(fastrand::u16(range));
+        }
+        let ranges: Vec<RangeInclusive<u16>> = allowed
+            .iter()
+            .map(|r| *r.start().max(range.start())..=*r.end().min(range.end()))
+            .filter(|r| !r.is_empty())
+            .collect();
+        let total: usize = ranges.iter().map(|r| r.len()).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut n = fastrand::usize(..total);
+        for range in ranges {
+            if n < range.len() {
+                return Some(range.start() + n as u16);
+            }
+            n -= range.len();
+        }
+        None
+    }
+
+    /// Bind `port` on every bind address, or on none.
+    fn bind_port(&self, proto: Proto, port: u16) -> io::Result<Listener> {
+        let binds = self.config().bind.clone();
+        match proto {
+            Proto::Udp => binds
+                .iter()
+                .map(|&ip| bind_udp(SocketAddr::new(ip, port)))
+                .collect::<io::Result<_>>()
+                .map(Listener::Udp),
+            _ => bind_all(&binds, port).map(Listener::Tcp),
+        }
+    }
+}
+
+/// A socket bound on `addr`. IPv6 sockets take IPv6 only, whatever the
+/// platform's default, so that `::` and `0.0.0.0` can both be bound.
+pub(crate) fn bind_socket(addr: SocketAddr, ty: Type) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // As `TcpListener::bind` does, so a restart needn't wait out TIME_WAIT.
+    #[cfg(unix)]
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = bind_socket(addr, Type::STREAM)?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    UdpSocket::from_std(bind_socket(addr, Type::DGRAM)?.into())
+}
+
+/// Bind `port` on each of `binds`.
+fn bind_all(binds: &[IpAddr], port: u16) -> io::Result<Vec<TcpListener>> {
+    binds
+        .iter()
+        .map(|&ip| bind_tcp(SocketAddr::new(ip, port)))
+        .collect()
+}
+
+/// Accept a connection on whichever of `listeners` has one first.
+async fn accept_any(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    let (accepted, _, _) = select_all(listeners.iter().map(|l| Box::pin(l.accept()))).await;
+    accepted
+}
+
+// ── Ban maintenance ───────────────────────────────────────────────────────────
+
+/// Ban `addr`'s IP for failing to authenticate too often.
+fn ban_failing_ip(state: &State, addr: SocketAddr, limits: &AuthLimits) {
+    let ttl = limits.ban_duration;
+    warn!(
+        %addr,
+        failures = limits.max_failures,
+        ban = %format_duration(ttl),
+        "banning IP after failed auth attempts"
+    );
+    let reason = "too many failed auth attempts";
+    if let Err(e) = state.bans.ban(BanTarget::Ip(addr.ip()), reason, Some(ttl)) {
+        warn!(err = %e, "cannot save ban");
+    }
+}
+
+/// Pick up edits to the ban file, drop expired entries and forget IPs
+/// without recent auth attempts or offenses.
+async fn maintain_bans(state: Arc<State>) {
+    loop {
+        sleep(Duration::from_secs(5)).await;
+        state.throttle.prune();
+        state.offenders.prune();
+        if let Err(e) = state.bans.refresh() {
+            warn!(err = %e, "cannot refresh ban list");
+        }
+        match state.bans.prune() {
+            Ok(0) => {}
+            Ok(n) => info!(expired = n, "expired bans removed"),
+            Err(e) => warn!(err = %e, "cannot prune ban list"),
+        }
+    }
+}
+
+// ── Reload ────────────────────────────────────────────────────────────────────
+
+/// Reload the settings every time the process gets SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(state: Arc<State>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!(err = %e, "cannot listen for SIGHUP");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading settings");
+        if let Err(e) = state.reload() {
+            warn!(
+                err = format!("{e:#}"),
+                "reload failed, keeping the current settings"
+            );
+        }
+    }
+}
+
+// ── Client settings push ──────────────────────────────────────────────────────
+
+/// Poll the client settings file and broadcast its contents when it changes.
+async fn watch_client_settings(state: Arc<State>, path: PathBuf) {
+    let mut last_mtime = None;
+    loop {
+        let mtime = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if mtime.is_some() && mtime != last_mtime {
+            last_mtime = mtime;
+            match fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| {
+                    serde_json::from_slice::<ClientSettings>(&bytes).map_err(Into::into)
+                }) {
+                Ok(settings) => {
+                    info!(?settings, "client settings loaded");
+                    state.settings.send_replace(Some(settings));
+                }
+                Err(e) => warn!(path = %path.display(), err = %e, "cannot load client settings"),
+            }
+        }
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+// ── Control connection handler ────────────────────────────────────────────────
+
+/// Accept clients on the TLS control port.
+async fn accept_control_tls(listener: TcpListener, acceptor: TlsAcceptor, state: Arc<State>) {
+    let mut backoff = Backoff::default();
+    loop {
+        state.budget.ready().await;
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(err = %e, "TLS control accept failed");
+                backoff.failed().await;
+                continue;
+            }
+        };
+        backoff.succeeded();
+        if state.bans.is_ip_banned(addr.ip()) {
+            debug!(%addr, "dropping control connection from banned IP");
+            continue;
+        }
+        let Some(held) = admit_control(&stream, addr, &state) else {
+            continue;
+        };
+        tune_control_socket(&stream, &state);
+        let stream = Counted::new(stream, state.budget.take());
+        let (acceptor, state) = (acceptor.clone(), Arc::clone(&state));
+        tokio::spawn(async move {
+            let handshake = state.config().timeouts.handshake;
+            let serve = async {
+                match timeout(handshake, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => handle_control(stream, addr, Arc::clone(&state)).await,
+                    // Not TLS at all, or not a ClientHello.
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                        Err(state.offense(addr, e.into(), true))
+                    }
+                    Ok(Err(e)) => Err(e.into()),
+                    Err(_) => Err(anyhow!("TLS handshake timed out")),
+                }
+            };
+            let (result, held) = tarpit::holding(held, serve).await;
+            control_ended(&state, addr, result, held).await;
+        });
+    }
+}
+
+/// Check a new connection to a control port against the tarpit. Returns
+/// `None` if it comes from a repeat offender and was dropped, else a second
+/// handle on its socket when the tarpit is on.
+fn admit_control(stream: &TcpStream, addr: SocketAddr, state: &State) -> Option<Option<Socket>> {
+    let Some(tarpit) = state.config().tarpit else {
+        return Some(None);
+    };
+    if state.offenders.drops(addr.ip(), &tarpit) {
+        tarpit::reset(&SockRef::from(stream));
+        return None;
+    }
+    Some(tarpit::hold(stream))
+}
+
+/// Log how a control connection ended, and tarpit it through `held` if it
+/// offended.
+async fn control_ended(state: &State, addr: SocketAddr, result: Result<()>, held: Option<Socket>) {
+    let Err(e) = result else {
+        return;
+    };
+    if e.downcast_ref::<Offense>().is_none() {
+        warn!(%addr, err = %e, "connection error");
+        return;
+    }
+    let mode = state.config().tarpit.unwrap_or_default().mode;
+    debug!(%addr, err = %e, %mode, "tarpitting control connection");
+    if let Some(socket) = held {
+        state.offenders.punish(socket, mode).await;
+    }
+}
+
+/// Set `TCP_NODELAY` and keepalive on a socket of the control port, which
+/// carries control and data connections alike.
+pub(crate) fn tune_control_socket(stream: &TcpStream, state: &State) {
+    let keepalive = state.config().timeouts.heartbeat_timeout;
+    if let Err(e) = tune_socket(stream, keepalive) {
+        debug!(err = %e, "cannot tune control socket");
+    }
+}
+
+/// Serve one connection to the control port. Everything logged while it lasts
+/// carries the client's address and, once registered, its first subdomain.
+pub(crate) async fn handle_control<S>(stream: S, addr: SocketAddr, state: Arc<State>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let span = info_span!(
+        "control",
+        client = %addr,
+        identity = field::Empty,
+        subdomain = field::Empty
+    );
+    serve_control(stream, addr, state).instrument(span).await
+}
+
+async fn serve_control<S>(stream: S, addr: SocketAddr, state: Arc<State>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut ctrl = Framed_::new(stream).with_handshake_timeout(state.config().timeouts.handshake);
+
+    // Auth (optional). Clients send their first message right behind the
+    // answer, so it is read along with it.
+    let auth = state.auth.read().unwrap().clone();
+    let (identity, first) = match &auth {
+        Some(provider) => {
+            let limits = state.config().auth_limits.clone();
+            if let Err(wait) = state.throttle.admit(addr.ip(), &limits) {
+                debug!(%addr, wait_secs = wait.as_secs(), "auth attempt throttled");
+                return Ok(());
+            }
+            let meta = AuthMetadata { peer_addr: addr };
+            let (identity, first) =
+                auth::handshake_control(provider.as_ref(), &mut ctrl, meta).await;
+            // A Hello says whether the client understands a code.
+            let codes = match &first {
+                Some(ClientMsg::Hello {
+                    error_codes,
+                    version,
+                    ..
+                }) => *error_codes || *version >= 2,
+                _ => false,
+            };
+            let unsealed = matches!(&first, Some(ClientMsg::Hello { seal: None, .. }));
+            let identity = match identity {
+                Ok(_) if unsealed && state.config().require_sealed_hello => {
+                    let message = "this server needs a sealed Hello; upgrade the client".to_owned();
+                    let refusal = Refusal::new(ErrorCode::AuthFailed, message);
+                    ctrl.send(refusal.into_msg(codes)).await?;
+                    return Ok(());
+                }
+                Ok(identity) => identity,
+                Err(e) => {
+                    state.notify(Notification::auth_failed(addr.ip(), e.to_string()));
+                    if state.throttle.failed(addr.ip(), &limits) {
+                        ban_failing_ip(&state, addr, &limits);
+                    }
+                    // With the tarpit on, only an IP's first failure is
+                    // answered, and garbage never is.
+                    let tarpit = state.config().tarpit;
+                    if let Some(tarpit) = tarpit {
+                        let count = state.offenders.record(addr.ip(), &tarpit);
+                        if count > 1 || tarpit::is_garbage(&e, ctrl.unread()) {
+                            let reason = e.to_string();
+                            return Err(Offense { count, reason }.into());
+                        }
+                    }
+                    let refusal = Refusal::new(ErrorCode::AuthFailed, e.to_string());
+                    ctrl.send(refusal.into_msg(codes)).await?;
+                    return Ok(());
+                }
+            };
+            state.audit(Notification::auth_succeeded(addr.ip(), &identity.name));
+            state.throttle.succeeded(addr.ip());
+            state.offenders.forgive(addr.ip());
+            tarpit::release();
+            (identity, first)
+        }
+        None => {
+            let first = match ctrl.recv_timeout::<ClientMsg>().await {
+                Ok(first) => first,
+                Err(e) => {
+                    let garbage = tarpit::is_garbage(&e, ctrl.unread());
+                    return Err(state.offense(addr, e, garbage));
+                }
+            };
+            tarpit::release();
+            (Identity::anonymous(), first)
+        }
+    };
+
+    // First real message from client.
+    match first {
+        // ── Register a tunnel ──────────────────────────────────────────────
+        Some(ClientMsg::Hello {
+            subdomain,
+            proto,
+            grpc,
+            pool,
+            mux,
+            warm,
+            ttl_secs,
+            max_uses,
+            desired_port,
+            acl,
+            error_codes,
+            version,
+            resume,
+            takeover,
+            heartbeat_interval_ms,
+            ..
+        }) => {
+            let Some(version) = negotiate(version) else {
+                let message = format!(
+                    "client speaks protocol version {version}, this server needs \
+                     {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}; upgrade the client"
+                );
+                let refusal = Refusal::new(ErrorCode::ProtocolMismatch, message);
+                ctrl.send(refusal.into_msg(error_codes)).await?;
+                return Ok(());
+            };
+            let (inbound_tx, inbound) = mpsc::channel(64);
+            let (close_tx, closes) = mpsc::channel(8);
+            let mut session = Session {
+                identity,
+                addr,
+                acl: Arc::new(acl),
+                inbound_tx,
+                close_tx,
+                version,
+                error_codes: error_codes || version >= 2,
+                rtt: Arc::default(),
+                token: Uuid::new_v4(),
+                resume,
+                takeover,
+                heartbeat: heartbeat_interval_ms.map(Duration::from_millis),
+                warm: None,
+                ttl: ttl_secs.map(Duration::from_secs),
+                max_uses,
+                registrations: Vec::new(),
+            };
+            let registered = match &subdomain {
+                Some(subdomain) => state
+                    .register(subdomain, proto, grpc, pool, desired_port, &session)
+                    .await
+                    .map(|registration| (None, registration)),
+                None => state
+                    .register_random(proto, grpc, desired_port, &session)
+                    .await
+                    .map(|(picked, registration)| (Some(picked), registration)),
+            };
+            let (picked, first) = match registered {
+                Ok(registered) => registered,
+                Err(refusal) => {
+                    ctrl.send(refusal.into_msg(session.error_codes)).await?;
+                    return Ok(());
+                }
+            };
+            let resumable =
+                state.config().reservation_grace.is_some() || state.registry.outlives_restarts();
+            // Visitors come down the multiplexed session instead.
+            let warm_key = match warm && !mux && version >= 13 {
+                true => {
+                    let (key, pool) = state.warm.open(&session.identity.name);
+                    session.warm = Some(pool);
+                    Some(key)
+                }
+                false => None,
+            };
+            ctrl.send(ServerMsg::Hello {
+                public_port: first.public_port,
+                url: first.url.clone(),
+                mux,
+                version,
+                session: resumable.then_some(session.token),
+                subdomain: picked,
+                addrs: first.addrs.clone(),
+                warm: warm_key,
+            })
+            .await?;
+            Span::current()
+                .record("identity", session.identity.name.as_str())
+                .record("subdomain", first.subdomain.as_str());
+            session.registrations.push(first);
+            let receivers = (inbound, closes);
+
+            // Drive the tunnels: heartbeat + accept inbound connections.
+            if mux {
+                let (mut control, _) = multiplex(ctrl, SessionType::Server);
+                let result = match control.open_stream().await {
+                    Ok(stream) => {
+                        let ctrl = Framed_::new(stream);
+                        let mux = Some(control.clone());
+                        drive_tunnel(ctrl, mux, receivers, session, &state).await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                control.close().await;
+                result
+            } else {
+                drive_tunnel(ctrl, None, receivers, session, &state).await
+            }
+        }
+
+        // ── Client is accepting a pending inbound connection ───────────────
+        Some(ClientMsg::Accept(id)) => match state.take_pending(id) {
+            Some(inbound) => {
+                let _in_flight = state.in_flight();
+                let span = info_span!("conn", %id, peer = %inbound.addr);
+                let buffer_size = state.config().buffer_size;
+                splice(inbound, ctrl, buffer_size).instrument(span).await
+            }
+            None => {
+                // Behind a load balancer, the data connection may reach a
+                // node other than the one holding the visitor.
+                let node = match &state.cluster {
+                    Some(cluster) => cluster.parked_on(id).await,
+                    None => None,
+                };
+                match (&state.cluster, node) {
+                    (Some(cluster), Some(node)) => {
+                        let _in_flight = state.in_flight();
+                        let buffer_size = state.config().buffer_size;
+                        cluster.forward_accept(&node, id, ctrl, buffer_size).await
+                    }
+                    _ => {
+                        warn!(%id, "Accept for unknown connection");
+                        Ok(())
+                    }
+                }
+            }
+        },
+
+        // ── Client is accepting one as a resumable stream ──────────────────
+        Some(ClientMsg::AcceptResumable(id)) => {
+            let _in_flight = state.in_flight();
+            let link = resume::unframe(ctrl);
+            if let Some(inbound) = state.take_pending(id) {
+                let span = info_span!("conn", %id, peer = %inbound.addr);
+                let Inbound { stream, prefix, .. } = inbound;
+                return relay_resumable(stream, prefix, link, id, &state)
+                    .instrument(span)
+                    .await;
+            }
+            let node = match &state.cluster {
+                Some(cluster) => cluster.parked_on(id).await,
+                None => None,
+            };
+            match (&state.cluster, node) {
+                // The node holding the visitor relays its bytes as they are.
+                (Some(cluster), Some(node)) => {
+                    let peer = resume::unframe(cluster.accept_on(&node, id).await?);
+                    relay_resumable(peer, Vec::new(), link, id, &state).await
+                }
+                _ => {
+                    warn!(%id, "Accept for unknown connection");
+                    Ok(())
+                }
+            }
+        }
+
+        // ── Client picks a resumable stream up again ───────────────────────
+        Some(ClientMsg::ResumeStream(id)) => {
+            let relinks = state.resumable.lock().unwrap().get(&id).cloned();
+            let Some(relinks) = relinks else {
+                debug!(%addr, %id, "resume of an unknown stream");
+                let message = format!("no stream {id} is waiting to be resumed");
+                let refusal = Refusal::new(ErrorCode::NoSuchStream, message);
+                return ctrl.send(refusal.into_msg(true)).await;
+            };
+            ctrl.send(ServerMsg::StreamResumed).await?;
+            // Dropped if the stream stopped waiting in the meantime.
+            let _ = relinks.send(resume::unframe(ctrl)).await;
+            Ok(())
+        }
+
+        // ── Client parks a warm data connection ────────────────────────────
+        Some(ClientMsg::Standby(key)) => {
+            let pool = state.warm.get(key);
+            let Some(pool) = pool.filter(|pool| pool.identity == identity.name) else {
+                debug!(%addr, "standby for an unknown session");
+                return Ok(());
+            };
+            let Some(parked) = pool.park() else {
+                debug!(%addr, "warm pool is full");
+                return Ok(());
+            };
+            drop(pool);
+            let handoff = tokio::select! {
+                handoff = parked => handoff,
+                // Clients send nothing until they get a visitor: this is a
+                // hang-up.
+                _ = ctrl.recv::<ClientMsg>() => return Ok(()),
+                _ = state.draining() => return Ok(()),
+            };
+            // The session is gone.
+            let Ok(Handoff {
+                subdomain,
+                announce,
+                inbound,
+                span,
+            }) = handoff
+            else {
+                return Ok(());
+            };
+            let _in_flight = state.in_flight();
+            if let Err(e) = ctrl.send(announce).await {
+                // Give the visitor back to its tunnel, for another client
+                // connection to take.
+                if let Some(tunnel) = state.registry.tunnel(&subdomain) {
+                    let _ = tunnel.inbound.try_send(inbound);
+                }
+                return Err(e);
+            }
+            let buffer_size = state.config().buffer_size;
+            splice(inbound, ctrl, buffer_size).instrument(span).await
+        }
+
+        // ── Client is pulling a port reachable from here ───────────────────
+        Some(ClientMsg::Pull { target }) => pull(ctrl, addr, target, &identity, &state).await,
+
+        // ── Client wants to know where a tunnel listens ────────────────────
+        Some(ClientMsg::Lookup { subdomain }) => {
+            let found = state
+                .registry
+                .tunnel(&subdomain)
+                .map(|t| (t.proto, t.public_port));
+            let reply = match found {
+                Some((proto, public_port)) => ServerMsg::Found { proto, public_port },
+                None => {
+                    let message = format!("no tunnel is registered for '{subdomain}'");
+                    Refusal::new(ErrorCode::NoSuchTunnel, message).into_msg(true)
+                }
+            };
+            debug!(%addr, identity = %identity.name, %subdomain, "tunnel lookup");
+            ctrl.send(reply).await
+        }
+
+        _ => Ok(()),
+    }
+}
+
+/// Connect a client to `target`, if the operator lets clients pull it.
+async fn pull<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    mut ctrl: Framed_<S>,
+    addr: SocketAddr,
+    target: String,
+    identity: &Identity,
+    state: &State,
+) -> Result<()> {
+    let permitted = state.config().pull_targets.contains(&target);
+    if !permitted {
+        warn!(%addr, identity = %identity.name, %target, "pull refused");
+        let message = format!("this server doesn't let clients pull {target}");
+        let refusal = Refusal::new(ErrorCode::PullNotPermitted, message);
+        return ctrl.send(refusal.into_msg(true)).await;
+    }
+    let upstream = match timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(&target)).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            let message = format!("cannot reach {target} from the server: {e}");
+            return ctrl.send(ServerMsg::Error(message)).await;
+        }
+        Err(_) => {
+            let message = format!("connecting to {target} from the server timed out");
+            return ctrl.send(ServerMsg::Error(message)).await;
+        }
+    };
+    let peer_addr = upstream.peer_addr()?;
+    info!(%addr, identity = %identity.name, %target, "pull connection");
+    ctrl.send(ServerMsg::Pulled { peer_addr }).await?;
+    let inbound = Inbound {
+        stream: Box::new(upstream),
+        addr: peer_addr,
+        prefix: Vec::new(),
+    };
+    let _in_flight = state.in_flight.subscribe();
+    let buffer_size = state.config().buffer_size;
+    splice(inbound, ctrl, buffer_size).await
+}
+
+// ── Tunnel driver: heartbeat + forward inbound connections ────────────────────
+
+/// What a control connection holds: the tunnels it registered.
+struct Session {
+    identity: Identity,
+    addr: SocketAddr,
+    /// Applies to every tunnel of the connection.
+    acl: Arc<Acl>,
+    /// Clone for every tunnel; inbound connections of all tunnels arrive here.
+    inbound_tx: mpsc::Sender<(String, Inbound)>,
+    /// Subdomains the session is to let go of, and why.
+    close_tx: mpsc::Sender<(String, Closing)>,
+    /// Negotiated protocol version.
+    version: u32,
+    /// The client understands `ServerMsg::Refused`.
+    error_codes: bool,
+    rtt: Arc<Rtt>,
+    /// Lets the client back into its tunnels' ports after a reconnect.
+    token: Uuid,
+    /// Token of the earlier connection the client is reconnecting from.
+    resume: Option<Uuid>,
+    /// Key that lets the client take over tunnels registered with it, and
+    /// another client take over this one's.
+    takeover: Option<String>,
+    /// Interval between heartbeats the client asked for.
+    heartbeat: Option<Duration>,
+    /// Data connections the client parked for its visitors.
+    warm: Option<Arc<WarmPool>>,
+    /// How long each tunnel stays up, and how many visitors it takes, as
+    /// the client asked.
+    ttl: Option<Duration>,
+    max_uses: Option<u64>,
+    registrations: Vec<Registration>,
+}
+
+/// Inbound connections and close requests for a session's tunnels.
+type Receivers = (
+    mpsc::Receiver<(String, Inbound)>,
+    mpsc::Receiver<(String, Closing)>,
+);
+
+/// Heartbeats sent to a client at version 4 or later, which answers each,
+/// so that one gone quiet is told apart from one that is only idle.
+#[derive(Default)]
+struct Pings {
+    last_nonce: u64,
+    /// Unanswered pings, oldest first.
+    sent: VecDeque<(u64, Instant)>,
+}
+
+impl Pings {
+    /// The next ping, or `None` once `max_missed` in a row went unanswered
+    /// and the oldest of them has waited `timeout`.
+    fn ping(&mut self, max_missed: u32, timeout: Duration, rtt: &Rtt) -> Option<ServerMsg> {
+        let waited = self.sent.front().map(|(_, sent)| sent.elapsed());
+        if self.sent.len() >= max_missed.max(1) as usize && waited >= Some(timeout) {
+            return None;
+        }
+        self.last_nonce += 1;
+        self.sent.push_back((self.last_nonce, Instant::now()));
+        Some(ServerMsg::Ping {
+            nonce: self.last_nonce,
+            rtt_ms: rtt.get().map(|rtt| rtt.as_millis() as u64),
+        })
+    }
+
+    /// Round trip of the ping `nonce` answers. Pings sent before it no
+    /// longer count as missed.
+    fn pong(&mut self, nonce: u64) -> Option<Duration> {
+        let i = self.sent.iter().position(|&(n, _)| n == nonce)?;
+        let (_, sent) = self.sent.drain(..=i).next_back()?;
+        Some(sent.elapsed())
+    }
+}
+
+/// `mux` is set when inbound connections travel as streams of the control
+/// connection's session rather than on connections the client opens.
+async fn drive_tunnel<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ctrl: Framed_<S>,
+    mux: Option<Control>,
+    (mut inbound, mut closes): Receivers,
+    mut session: Session,
+    state: &Arc<State>,
+) -> Result<()> {
+    let mut settings = state.settings.subscribe();
+    let initial = settings.borrow_and_update().clone();
+    let mut heartbeat = heartbeat_timer(initial.as_ref(), &session, state);
+    let mut pings = Pings::default();
+    let mut parked = Parked::new(Arc::clone(state));
+    let stats_interval = state.config().stats_interval;
+    let mut stats = interval(stats_interval);
+    stats.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // What each tunnel's last `Stats` said.
+    let mut reported: HashMap<String, (u64, u64, u64)> = HashMap::new();
+    if let Some(initial) = initial {
+        ctrl.send(ServerMsg::Reconfigure(initial)).await?;
+    }
+
+    loop {
+        let deadline = parked.next_deadline();
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                let beat = match session.version >= 4 {
+                    true => {
+                        let (max_missed, timeout) = {
+                            let config = state.config();
+                            (config.max_missed_heartbeats, config.timeouts.heartbeat_timeout)
+                        };
+                        let Some(ping) = pings.ping(max_missed, timeout, &session.rtt) else {
+                            warn!(addr = %session.addr, max_missed, "client stopped answering heartbeats");
+                            return Ok(());
+                        };
+                        ping
+                    }
+                    false => ServerMsg::Heartbeat,
+                };
+                // Send heartbeat; if client is gone, exit.
+                if ctrl.send(beat).await.is_err() {
+                    return Ok(());
+                }
+                if !expire(&mut ctrl, &mut session, state).await? {
+                    return hang_up(ctrl).await;
+                }
+            }
+
+            _ = stats.tick(), if session.version >= 6 => {
+                for registration in &session.registrations {
+                    let traffic = registration.traffic.snapshot();
+                    let counts = (traffic.connections, traffic.bytes_in, traffic.bytes_out);
+                    let subdomain = &registration.subdomain;
+                    if reported.get(subdomain).copied().unwrap_or_default() == counts {
+                        continue;
+                    }
+                    reported.insert(subdomain.clone(), counts);
+                    ctrl.send(ServerMsg::Stats {
+                        subdomain: subdomain.clone(),
+                        conns: counts.0,
+                        bytes_in: counts.1,
+                        bytes_out: counts.2,
+                    })
+                    .await?;
+                }
+            }
+
+            Ok(()) = settings.changed() => {
+                let Some(current) = settings.borrow_and_update().clone() else {
+                    continue;
+                };
+                heartbeat = heartbeat_timer(Some(&current), &session, state);
+                ctrl.send(ServerMsg::Reconfigure(current)).await?;
+            }
+
+            msg = ctrl.recv::<ClientMsg>() => match msg? {
+                Some(ClientMsg::Register {
+                    subdomain,
+                    proto,
+                    grpc,
+                    pool,
+                    desired_port,
+                }) => {
+                    let registered = state
+                        .register(&subdomain, proto, grpc, pool, desired_port, &session)
+                        .await;
+                    match registered {
+                        Ok(registration) => {
+                            ctrl.send(ServerMsg::Registered {
+                                subdomain,
+                                public_port: registration.public_port,
+                                url: registration.url.clone(),
+                                addrs: registration.addrs.clone(),
+                            })
+                            .await?;
+                            session.registrations.push(registration);
+                        }
+                        Err(refusal) => ctrl.send(refusal.into_msg(session.error_codes)).await?,
+                    }
+                }
+                Some(ClientMsg::Unregister { subdomain }) => {
+                    let registrations = &mut session.registrations;
+                    let Some(i) = registrations.iter().position(|r| r.subdomain == subdomain) else {
+                        let message = format!("no tunnel '{subdomain}' on this connection");
+                        let refusal = Refusal::new(ErrorCode::NoSuchTunnel, message);
+                        ctrl.send(refusal.into_msg(session.error_codes)).await?;
+                        continue;
+                    };
+                    // Visitors in progress, parked ones included, still
+                    // finish; new ones find the tunnel gone.
+                    info!(addr = %session.addr, %subdomain, "client unregistered tunnel");
+                    registrations.remove(i).close();
+                    reported.remove(&subdomain);
+                    ctrl.send(ServerMsg::Unregistered { subdomain }).await?;
+                    if session.registrations.is_empty() {
+                        wait_for_hang_up(&mut ctrl, &mut heartbeat, state).await;
+                        return Ok(());
+                    }
+                }
+                Some(ClientMsg::Pong(nonce)) => {
+                    if let Some(rtt) = pings.pong(nonce) {
+                        debug!(addr = %session.addr, ?rtt, "heartbeat answered");
+                        session.rtt.set(rtt);
+                    }
+                }
+                Some(ClientMsg::ConnectionAck { id, accepted: true }) => {
+                    parked.promise(id, Instant::now() + PENDING_TIMEOUT);
+                }
+                Some(ClientMsg::ConnectionAck { id, accepted: false }) => {
+                    if let Some((subdomain, inbound)) = parked.take(id) {
+                        debug!(%id, %subdomain, "client declined connection");
+                        dismiss(inbound, state, subdomain);
+                    }
+                }
+                Some(other) => debug!(?other, "unexpected message on control connection"),
+                None => return Ok(()),
+            },
+
+            Some((subdomain, inbound)) = inbound.recv() => {
+                let mux = mux.as_ref();
+                offer(&mut ctrl, mux, inbound, state, subdomain, &mut parked, &session).await?;
+            }
+
+            _ = until(deadline) => {
+                for (id, subdomain, inbound) in parked.expired() {
+                    warn!(%id, %subdomain, "pending connection was never accepted");
+                    dismiss(inbound, state, subdomain);
+                    if session.version >= 5 {
+                        ctrl.send(ServerMsg::ConnectionCancelled { id }).await?;
+                    }
+                }
+            }
+
+            _ = state.draining() => {
+                // Take no more visitors, and give the client until the drain
+                // timeout to finish its connections and hang up. Clients
+                // before version 2 aren't told; heartbeats keep them from
+                // dropping connections in progress in the meantime.
+                session.registrations.clear();
+                if session.version >= 2 {
+                    ctrl.send(ServerMsg::Shutdown).await?;
+                }
+                wait_for_hang_up(&mut ctrl, &mut heartbeat, state).await;
+                return Ok(());
+            }
+
+            Some((subdomain, closing)) = closes.recv() => {
+                let registrations = &mut session.registrations;
+                if let Some(i) = registrations.iter().position(|r| r.subdomain == subdomain) {
+                    registrations.remove(i).close();
+                }
+                match closing {
+                    Closing::Operator => {
+                        let notice =
+                            format!("tunnel '{subdomain}' was closed by the server operator");
+                        ctrl.send(ServerMsg::Error(notice)).await?;
+                        if session.registrations.is_empty() {
+                            return hang_up(ctrl).await;
+                        }
+                    }
+                    // Only clients that sent a takeover key get here, and
+                    // those speak version 11. New visitors go to the other
+                    // client already; the ones in progress finish here.
+                    Closing::HandedOver => {
+                        ctrl.send(ServerMsg::HandedOver { subdomain }).await?;
+                        if session.registrations.is_empty() {
+                            wait_for_hang_up(&mut ctrl, &mut heartbeat, state).await;
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Keep a control connection that takes no more visitors open until the
+/// client hangs up or the drain timeout is up, so that its connections in
+/// progress can finish. Heartbeats keep it from giving up on us meanwhile.
+async fn wait_for_hang_up<S: AsyncRead + AsyncWrite + Unpin>(
+    ctrl: &mut Framed_<S>,
+    heartbeat: &mut Interval,
+    state: &State,
+) {
+    let hang_up = async {
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    if ctrl.send(ServerMsg::Heartbeat).await.is_err() {
+                        return;
+                    }
+                }
+                msg = ctrl.recv::<ClientMsg>() => {
+                    if !matches!(msg, Ok(Some(_))) {
+                        return;
+                    }
+                }
+            }
+        }
+    };
+    let drain_timeout = state.config().drain_timeout;
+    let _ = timeout(drain_timeout, hang_up).await;
+}
+
+/// End a control connection whose last tunnel is gone, giving the client a
+/// moment to read the messages that say why before the connection closes.
+async fn hang_up<S: AsyncRead + AsyncWrite + Unpin>(mut ctrl: Framed_<S>) -> Result<()> {
+    ctrl.close().await?;
+    let _ = timeout(HANDSHAKE_TIMEOUT, async {
+        while let Ok(Some(_)) = ctrl.recv::<ClientMsg>().await {}
+    })
+    .await;
+    Ok(())
+}
+
+/// Close the session's tunnels that sat idle or outlived their lifetime, and
+/// tell the client why. Returns whether any tunnel is left.
+async fn expire<S: AsyncRead + AsyncWrite + Unpin>(
+    ctrl: &mut Framed_<S>,
+    session: &mut Session,
+    state: &State,
+) -> Result<bool> {
+    let mut left = Vec::with_capacity(session.registrations.len());
+    for registration in std::mem::take(&mut session.registrations) {
+        let Some(message) = state.expiry(&registration) else {
+            left.push(registration);
+            continue;
+        };
+        let subdomain = registration.subdomain.clone();
+        registration.close();
+        info!(%subdomain, "{message}");
+        let msg = if session.version >= 3 {
+            ServerMsg::Expired { subdomain, message }
+        } else {
+            ServerMsg::Error(message)
+        };
+        ctrl.send(msg).await?;
+    }
+    session.registrations = left;
+    Ok(!session.registrations.is_empty())
+}
+
+/// Forward a tunnel's inbound connections, accepted on its own port or
+/// routed by hostname, to the control connections in its [`Pool`].
+async fn pump(
+    listeners: Vec<TcpListener>,
+    mut routed: mpsc::Receiver<Inbound>,
+    pool: Arc<Pool>,
+    state: Arc<State>,
+    subdomain: String,
+    traffic: Arc<Traffic>,
+    acl: Arc<Acl>,
+) {
+    let mut backoff = Backoff::default();
+    loop {
+        let mut inbound = tokio::select! {
+            accepted = async {
+                state.budget.ready().await;
+                accept_any(&listeners).await
+            } => match accepted {
+                Ok((stream, addr)) => {
+                    backoff.succeeded();
+                    if state.bans.is_ip_banned(addr.ip()) {
+                        debug!(%addr, %subdomain, "dropping inbound connection from banned IP");
+                        continue;
+                    }
+                    if !acl.permits(addr.ip()) {
+                        debug!(%addr, %subdomain, "dropping inbound connection denied by ACL");
+                        continue;
+                    }
+                    if let Err(location) = state.admit_visitor(addr.ip()) {
+                        let country = location.country;
+                        debug!(%addr, %subdomain, ?country, "dropping inbound connection by country");
+                        continue;
+                    }
+                    let stream = Counted::new(stream, state.budget.take());
+                    Inbound { stream: Box::new(stream), addr, prefix: Vec::new() }
+                }
+                Err(e) => {
+                    warn!(%subdomain, err = %e, "tunnel accept failed");
+                    backoff.failed().await;
+                    continue;
+                }
+            },
+            Some(inbound) = routed.recv() => inbound,
+        };
+        let max_conns = state.config().max_conns_per_tunnel;
+        if max_conns.is_some_and(|max| traffic.open() >= max) {
+            let rejected = traffic.reject();
+            warn!(addr = %inbound.addr, %subdomain, rejected, "tunnel connection limit reached");
+            let (state, subdomain) = (Arc::clone(&state), subdomain.clone());
+            tokio::spawn(async move { http::turn_away(inbound, &state, &subdomain).await });
+            continue;
+        }
+        if !traffic.connection() {
+            debug!(addr = %inbound.addr, %subdomain, "tunnel is used up, dropping inbound connection");
+            continue;
+        }
+        traffic.add_in(inbound.prefix.len());
+        inbound.stream = Box::new(Metered::new(inbound.stream, Arc::clone(&traffic)));
+        // A member going away hands the visitor back for the next one.
+        let mut visitor = (subdomain.clone(), inbound);
+        loop {
+            let Some(tx) = pool.next() else {
+                return;
+            };
+            match tx.send(visitor).await {
+                Ok(()) => break,
+                Err(mpsc::error::SendError(back)) => visitor = back,
+            }
+        }
+    }
+}
+
+/// Whether `subdomain` is a wildcard, like `*.preview`, standing for any
+/// labels in front of the rest.
+fn is_wildcard(subdomain: &str) -> bool {
+    subdomain.starts_with("*.")
+}
+
+/// Only HTTP tunnels, routed by hostname, can be wildcards, and `*` only
+/// stands for whole leading labels.
+fn check_wildcard(subdomain: &str, proto: Proto) -> Result<(), Refusal> {
+    if !subdomain.contains('*') {
+        return Ok(());
+    }
+    let rest = subdomain.strip_prefix("*.").unwrap_or_default();
+    if rest.is_empty() || rest.contains('*') {
+        return Err(Refusal::new(
+            ErrorCode::SubdomainNotPermitted,
+            format!("'{subdomain}' is not a valid wildcard; use '*.name'"),
+        ));
+    }
+    if proto != Proto::Http {
+        return Err(Refusal::new(
+            ErrorCode::SubdomainNotPermitted,
+            format!("wildcard subdomain '{subdomain}' needs an HTTP tunnel"),
+        ));
+    }
+    Ok(())
+}
+
+/// Listen on `<dir>/<subdomain>.sock` and route the visitors accepted there
+/// to the tunnel's pump through `routed`.
+#[cfg(unix)]
+fn bind_unix(dir: &Path, subdomain: &str, routed: mpsc::Sender<Inbound>) -> Result<AbortHandle> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+    if subdomain.is_empty() || subdomain.starts_with('.') || !subdomain.chars().all(safe) {
+        bail!("subdomain '{subdomain}' can't name a Unix socket");
+    }
+    let path = dir.join(format!("{subdomain}.sock"));
+    // A server that didn't shut down cleanly leaves the file behind.
+    let _ = fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path)
+        .with_context(|| format!("cannot listen on {}", path.display()))?;
+    let socket = UnixSocket { listener, path };
+    Ok(tokio::spawn(pump_unix(socket, routed, subdomain.to_owned())).abort_handle())
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &Path, _: &str, _: mpsc::Sender<Inbound>) -> Result<AbortHandle> {
+    bail!("Unix sockets are not supported on this platform")
+}
+
+/// A tunnel's Unix socket; the file goes away with it.
+#[cfg(unix)]
+struct UnixSocket {
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Hand the visitors of a tunnel's Unix socket to its [`pump`], like those
+/// routed by hostname. They come from this host and have no address, so
+/// they are given the loopback address and skip bans and the ACL.
+#[cfg(unix)]
+async fn pump_unix(socket: UnixSocket, routed: mpsc::Sender<Inbound>, subdomain: String) {
+    let mut backoff = Backoff::default();
+    loop {
+        let stream = match socket.listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!(%subdomain, err = %e, "Unix socket accept failed");
+                backoff.failed().await;
+                continue;
+            }
+        };
+        backoff.succeeded();
+        let inbound = Inbound {
+            stream: Box::new(stream),
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            prefix: Vec::new(),
+        };
+        if routed.send(inbound).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Split a UDP tunnel's traffic into flows, one per visitor address. Each
+/// flow reaches the client like a TCP visitor would, as a byte stream of
+/// length-prefixed datagrams.
+async fn pump_udp(
+    socket: UdpSocket,
+    tx: mpsc::Sender<(String, Inbound)>,
+    state: Arc<State>,
+    subdomain: String,
+    traffic: Arc<Traffic>,
+    acl: Arc<Acl>,
+) {
+    let socket = Arc::new(socket);
+    let mut flows: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // ICMP errors from earlier replies surface here; not fatal.
+                debug!(%subdomain, err = %e, "tunnel recv failed");
+                continue;
+            }
+        };
+        let datagram = Bytes::copy_from_slice(&buf[..n]);
+        flows.retain(|_, flow| !flow.is_closed());
+        if let Some(flow) = flows.get(&addr) {
+            // A full queue means the client is behind; drop like the network would.
+            let _ = flow.try_send(datagram);
+            continue;
+        }
+        if state.bans.is_ip_banned(addr.ip()) {
+            debug!(%addr, %subdomain, "dropping datagram from banned IP");
+            continue;
+        }
+        if !acl.permits(addr.ip()) {
+            debug!(%addr, %subdomain, "dropping datagram denied by ACL");
+            continue;
+        }
+        if let Err(location) = state.admit_visitor(addr.ip()) {
+            let country = location.country;
+            debug!(%addr, %subdomain, ?country, "dropping datagram by country");
+            continue;
+        }
+        let max_conns = state.config().max_conns_per_tunnel;
+        if max_conns.is_some_and(|max| traffic.open() >= max) {
+            let rejected = traffic.reject();
+            warn!(%addr, %subdomain, rejected, "tunnel connection limit reached");
+            continue;
+        }
+        if !traffic.connection() {
+            debug!(%addr, %subdomain, "tunnel is used up, dropping datagram");
+            continue;
+        }
+        let (visitor, pipe) = tokio::io::duplex(4 * MAX_DATAGRAM);
+        let (flow, queue) = mpsc::channel(64);
+        let _ = flow.try_send(datagram);
+        flows.insert(addr, flow);
+        let flow = udp_flow(pipe, queue, Arc::clone(&socket), addr, Arc::clone(&traffic));
+        tokio::spawn(flow);
+        let inbound = Inbound {
+            stream: Box::new(visitor),
+            addr,
+            prefix: Vec::new(),
+        };
+        if tx.send((subdomain.clone(), inbound)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Carry one visitor's datagrams over `pipe` and send the replies back to
+/// it, until the flow has been idle for [`UDP_IDLE_TIMEOUT`].
+async fn udp_flow(
+    pipe: DuplexStream,
+    mut queue: mpsc::Receiver<Bytes>,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    traffic: Arc<Traffic>,
+) {
+    let _in_use = traffic.in_use();
+    let mut pipe = Framed::new(pipe, datagram_codec());
+    loop {
+        tokio::select! {
+            datagram = queue.recv() => match datagram {
+                Some(datagram) => {
+                    if !traffic.admit_in(datagram.len()) {
+                        continue;
+                    }
+                    if pipe.send(datagram).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            reply = pipe.next() => match reply {
+                Some(Ok(reply)) => {
+                    if !traffic.admit_out(reply.len()) {
+                        continue;
+                    }
+                    if let Err(e) = socket.send_to(&reply, peer).await {
+                        debug!(%peer, err = %e, "udp reply failed");
+                    }
+                }
+                _ => break,
+            },
+            _ = sleep(UDP_IDLE_TIMEOUT) => break,
+        }
+    }
+    debug!(%peer, "udp flow closed");
+}
+
+/// Hand an inbound connection to the client: on a new stream of the session
+/// when multiplexing, or on a warm connection the client parked, otherwise
+/// park it until the client comes to fetch it.
+async fn offer<S: AsyncRead + AsyncWrite + Unpin>(
+    ctrl: &mut Framed_<S>,
+    mux: Option<&Control>,
+    inbound: Inbound,
+    state: &Arc<State>,
+    subdomain: String,
+    parked: &mut Parked,
+    session: &Session,
+) -> Result<()> {
+    let version = session.version;
+    let id = Uuid::new_v4();
+    let peer_addr = inbound.addr;
+    let (max_pending, per_tunnel, handshake) = {
+        let config = state.config();
+        (
+            config.max_pending,
+            config.max_pending_per_tunnel,
+            config.timeouts.handshake,
+        )
+    };
+    let full = mux.is_none() && parked.len(&subdomain) >= per_tunnel;
+    if full || max_pending.is_some_and(|max| state.waiting() >= max) {
+        let rejected = state
+            .registry
+            .tunnel(&subdomain)
+            .map(|t| t.traffic.reject());
+        warn!(%peer_addr, %subdomain, ?rejected, "too many pending connections");
+        dismiss(inbound, state, subdomain);
+        return Ok(());
+    }
+    let Location { country, asn } = state.locate(peer_addr.ip());
+    info!(%id, %peer_addr, %subdomain, country, asn, "inbound connection");
+    let span = info_span!("conn", %id, peer = %peer_addr, %subdomain, country, asn);
+    let public_port = match version >= 7 {
+        true => state.registry.tunnel(&subdomain).map(|t| t.public_port),
+        false => None,
+    };
+    let (country, asn) = match version >= 12 {
+        true => (country, asn),
+        false => (None, None),
+    };
+    let announce = ServerMsg::Connection {
+        id,
+        peer_addr,
+        subdomain: Some(subdomain.clone()),
+        public_port,
+        country,
+        asn,
+    };
+
+    if let Some(control) = mux {
+        let mut control = control.clone();
+        let in_flight = state.in_flight.subscribe();
+        let opening = Opening::new(Arc::clone(state));
+        let buffer_size = state.config().buffer_size;
+        tokio::spawn(
+            async move {
+                let _in_flight = in_flight;
+                let forward = async {
+                    let mut data = Framed_::new(control.open_stream().await?);
+                    data.send(announce).await?;
+                    drop(opening);
+                    splice(inbound, data, buffer_size).await
+                };
+                if let Err(e) = forward.await {
+                    debug!(err = %e, "multiplexed connection failed");
+                }
+            }
+            .instrument(span),
+        );
+        return Ok(());
+    }
+
+    // A warm connection takes the visitor at once; it is parked only when
+    // none is left.
+    let handoff = Handoff {
+        subdomain,
+        announce,
+        inbound,
+        span,
+    };
+    let Handoff {
+        subdomain,
+        announce,
+        inbound,
+        ..
+    } = match &session.warm {
+        Some(warm) => match warm.hand(handoff) {
+            Ok(()) => return Ok(()),
+            Err(handoff) => handoff,
+        },
+        None => handoff,
+    };
+
+    // Clients from version 5 say at once whether they will come for it.
+    let wait = match version >= 5 {
+        true => handshake,
+        false => PENDING_TIMEOUT,
+    };
+    parked.push(id, subdomain, inbound, Instant::now() + wait);
+    if let Some(cluster) = &state.cluster {
+        cluster.park(id).await;
+    }
+
+    ctrl.send(announce).await
+}
+
+/// Visitor connections a session parked for its client's `Accept`, by
+/// tunnel, oldest first. Whatever is still parked when it drops is turned
+/// away.
+struct Parked {
+    state: Arc<State>,
+    tunnels: HashMap<String, VecDeque<Slot>>,
+}
+
+struct Slot {
+    id: Uuid,
+    /// When the visitor is turned away if the client hasn't taken it.
+    deadline: Instant,
+}
+
+impl Parked {
+    fn new(state: Arc<State>) -> Self {
+        Self {
+            state,
+            tunnels: HashMap::new(),
+        }
+    }
+
+    /// Visitors of `subdomain` still waiting, forgetting those accepted.
+    fn len(&mut self, subdomain: &str) -> usize {
+        let Some(slots) = self.tunnels.get_mut(subdomain) else {
+            return 0;
+        };
+        slots.retain(|slot| self.state.registry.is_parked(slot.id));
+        slots.len()
+    }
+
+    fn push(&mut self, id: Uuid, subdomain: String, inbound: Inbound, deadline: Instant) {
+        self.state.registry.park(id, inbound);
+        let slots = self.tunnels.entry(subdomain).or_default();
+        slots.push_back(Slot { id, deadline });
+    }
+
+    /// Hold `id` until `deadline`, for a client that will accept it.
+    fn promise(&mut self, id: Uuid, deadline: Instant) {
+        let slot = self.tunnels.values_mut().flatten().find(|s| s.id == id);
+        if let Some(slot) = slot {
+            slot.deadline = deadline;
+        }
+    }
+
+    /// Take the visitor `id` out, with its subdomain, if nobody has.
+    fn take(&mut self, id: Uuid) -> Option<(String, Inbound)> {
+        for (subdomain, slots) in &mut self.tunnels {
+            if let Some(i) = slots.iter().position(|s| s.id == id) {
+                slots.remove(i);
+                let inbound = self.state.registry.unpark(id)?;
+                return Some((subdomain.clone(), inbound));
+            }
+        }
+        None
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.tunnels.values().flatten().map(|s| s.deadline).min()
+    }
+
+    /// Take out the visitors whose deadline passed before their client
+    /// accepted them.
+    fn expired(&mut self) -> Vec<(Uuid, String, Inbound)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (subdomain, slots) in &mut self.tunnels {
+            slots.retain(|slot| {
+                if slot.deadline > now {
+                    return true;
+                }
+                if let Some(inbound) = self.state.registry.unpark(slot.id) {
+                    expired.push((slot.id, subdomain.clone(), inbound));
+                }
+                false
+            });
+        }
+        self.tunnels.retain(|_, slots| !slots.is_empty());
+        expired
+    }
+}
+
+impl Drop for Parked {
+    fn drop(&mut self) {
+        for (subdomain, slots) in self.tunnels.drain() {
+            for slot in slots {
+                let Some(inbound) = self.state.registry.unpark(slot.id) else {
+                    continue;
+                };
+                dismiss(inbound, &self.state, subdomain.clone());
+            }
+        }
+    }
+}
+
+/// Turn a visitor away without holding up the control connection.
+fn dismiss(inbound: Inbound, state: &Arc<State>, subdomain: String) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move { http::turn_away(inbound, &state, &subdomain).await });
+}
+
+/// Wait until `deadline`, or forever without one.
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline.into()).await,
+        None => pending().await,
+    }
+}
+
+/// Counts a multiplexed visitor connection as pending until its stream to
+/// the client is open.
+struct Opening(Arc<State>);
+
+impl Opening {
+    fn new(state: Arc<State>) -> Self {
+        state.opening.fetch_add(1, Ordering::Relaxed);
+        Self(state)
+    }
+}
+
+impl Drop for Opening {
+    fn drop(&mut self) {
+        self.0.opening.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Join a visitor with the client's end of its data connection, flushing
+/// bytes already buffered on either side first, and log what went through.
+/// Bytes are copied `buffer_size` at a time, unless the kernel moves them.
+pub(crate) async fn splice<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    inbound: Inbound,
+    data: Framed_<S>,
+    buffer_size: usize,
+) -> Result<()> {
+    let Inbound {
+        stream: mut visitor,
+        prefix,
+        ..
+    } = inbound;
+    let mut parts = data.into_parts();
+    parts.io.write_all(&prefix).await?;
+    visitor.write_all(&parts.read_buf).await?;
+    let zero_copy = match zero_copy::ZERO_COPY {
+        true => unmetered_tcp(&*visitor).zip(plain_tcp(&parts.io)),
+        false => None,
+    };
+    let (up, down) = match zero_copy {
+        Some(((visitor, traffic), data)) => {
+            let up = |n| traffic.add_in(n);
+            let down = |n| traffic.add_out(n);
+            zero_copy::relay(visitor, data, up, down).await?
+        }
+        None => {
+            let (visitor, data) = (&mut visitor, &mut parts.io);
+            tokio::io::copy_bidirectional_with_sizes(visitor, data, buffer_size, buffer_size)
+                .await?
+        }
+    };
+    info!(
+        bytes_in = prefix.len() as u64 + up,
+        bytes_out = parts.read_buf.len() as u64 + down,
+        "connection closed"
+    );
+    Ok(())
+}
+
+/// Relay `near`, a visitor or the node that holds one, over the client's
+/// resumable stream for the parked connection `id`, taking the connections
+/// the client resumes it on until it is done.
+async fn relay_resumable<A: AsyncRead + AsyncWrite>(
+    near: A,
+    read_ahead: Vec<u8>,
+    link: Box<dyn Link>,
+    id: Uuid,
+    state: &State,
+) -> Result<()> {
+    let (relinks, relinked) = mpsc::channel(1);
+    state.resumable.lock().unwrap().insert(id, relinks);
+    let relinked = tokio::sync::Mutex::new(relinked);
+    let relink = |err: io::Error| {
+        let relinked = &relinked;
+        async move {
+            info!(err = %err, "data connection dropped, waiting for the client to resume");
+            match timeout(RESUME_WINDOW, relinked.lock().await.recv()).await {
+                Ok(Some(link)) => {
+                    info!("data connection resumed");
+                    Ok(link)
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the client did not resume the connection",
+                )),
+            }
+        }
+    };
+    let relayed = resume::relay(near, read_ahead, link, relink).await;
+    state.resumable.lock().unwrap().remove(&id);
+    let (up, down) = relayed?;
+    info!(bytes_in = up, bytes_out = down, "connection closed");
+    Ok(())
+}
+
+/// The TCP socket of a visitor who came straight to a tunnel port, and the
+/// traffic it counts towards, unless a bandwidth limit has to see its bytes.
+fn unmetered_tcp(visitor: &dyn Io) -> Option<(&TcpStream, &Traffic)> {
+    let metered = visitor.as_any().downcast_ref::<Metered<Box<dyn Io>>>()?;
+    let (inner, traffic) = metered.unmetered()?;
+    // Deref twice: the box is an `Io` itself.
+    Some((plain_tcp((**inner).as_any())?, traffic))
+}
+
+/// The socket of a connection that is plain TCP, without TLS or another
+/// transport on top.
+fn plain_tcp(io: &dyn Any) -> Option<&TcpStream> {
+    io.downcast_ref::<TcpStream>().or_else(|| {
+        io.downcast_ref::<Counted<TcpStream>>()
+            .map(Counted::get_ref)
+    })
+}
+
+/// Heartbeats at the pushed `settings`' interval, or the configured one,
+/// unless the session asked for them further apart.
+fn heartbeat_timer(
+    settings: Option<&ClientSettings>,
+    session: &Session,
+    state: &State,
+) -> Interval {
+    let period = settings
+        .and_then(|s| s.heartbeat_interval_ms)
+        .map_or(
+            state.config().timeouts.heartbeat_interval,
+            Duration::from_millis,
+        )
+        .max(session.heartbeat.unwrap_or_default())
+        .max(Duration::from_millis(50));
+    let mut timer = interval(period);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    timer
+}
@@ -0,0 +1,134 @@
+//! Running under systemd: socket activation and `sd_notify`.
+//!
+//! With a `.socket` unit, systemd binds the control port and hands it over
+//! (`LISTEN_FDS`), so the port stays open while the server restarts and the
+//! service itself can run without the right to bind. With `Type=notify`
+//! (`--systemd-notify`), the server says when it is ready and stopping, and
+//! pets the watchdog if `WatchdogSec=` is set. Both speak the plain protocols
+//! from `sd_listen_fds(3)` and `sd_notify(3)`, without libsystemd.
+
+use std::{io, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+use tokio::net::TcpListener;
+use tracing::warn;
+
+/// Control listeners systemd passed in, if it started us through a socket
+/// unit. The variables are cleared either way, so they don't leak into
+/// anything we start.
+#[cfg(unix)]
+pub fn listeners() -> Result<Vec<TcpListener>> {
+    use std::os::fd::{FromRawFd, RawFd};
+
+    use anyhow::Context;
+
+    /// The first passed descriptor, after stdin, stdout and stderr.
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(Vec::new());
+    };
+    // Meant for a process we forked off before exec'ing, not for us.
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = fds.parse().context("invalid LISTEN_FDS")?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passed us this descriptor to own, and nothing
+            // else in this process refers to it.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener
+                .local_addr()
+                .with_context(|| format!("descriptor {fd} from systemd is not a TCP socket"))?;
+            listener.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(listener)?)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// The socket systemd listens on for state changes of the service.
+#[derive(Debug, Clone)]
+pub struct Notify {
+    /// A path, or an abstract name after `@`.
+    socket: PathBuf,
+}
+
+impl Notify {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    /// The socket in `NOTIFY_SOCKET`, set for services of `Type=notify`.
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os("NOTIFY_SOCKET").map(Self::new)
+    }
+
+    /// Send `state`, e.g. `READY=1`.
+    #[cfg(unix)]
+    pub fn send(&self, state: &str) -> io::Result<()> {
+        use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+        let socket = UnixDatagram::unbound()?;
+        match self.socket.as_os_str().as_bytes() {
+            #[cfg(target_os = "linux")]
+            [b'@', name @ ..] => {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                let addr = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            _ => {
+                socket.send_to(state.as_bytes(), &self.socket)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn send(&self, _state: &str) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Send `state`, logging rather than failing.
+    pub fn tell(&self, state: &str) {
+        if let Err(e) = self.send(state) {
+            warn!(socket = %self.socket.display(), err = %e, state, "cannot notify systemd");
+        }
+    }
+
+    /// Pet the watchdog at half the interval systemd expects, while the
+    /// runtime is alive to do so. Returns at once without `WatchdogSec=`.
+    pub async fn watchdog(self) {
+        let Some(interval) = watchdog_interval() else {
+            return;
+        };
+        let mut ticks = tokio::time::interval(interval / 2);
+        loop {
+            ticks.tick().await;
+            self.tell("WATCHDOG=1");
+        }
+    }
+}
+
+/// The watchdog interval systemd set for this process, if any.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
@@ -0,0 +1,303 @@
+//! Tarpitting of scanners on the control ports (`--tarpit`).
+//!
+//! A connection that sends something other than sshx frames, or fails auth
+//! from an IP that already did, gets no answer: it is reset, or kept busy
+//! with a byte every few seconds. A small LRU remembers the IPs that
+//! offended, and once one has done so often enough, its connections are
+//! dropped as soon as they are accepted, without a log line.
+//!
+//! A first failed auth is still answered, so a mistyped secret says why.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, Socket};
+use sshx_core::protocol::FrameError;
+use tokio::{io::AsyncWriteExt, net::TcpStream, sync::Semaphore, time::sleep};
+use tracing::debug;
+
+/// Time between two bytes of a drip.
+const DRIP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long one connection is dripped before it is let go.
+const DRIP_FOR: Duration = Duration::from_secs(10 * 60);
+
+/// Connections dripped at once; offenders beyond that are reset.
+const MAX_DRIPS: usize = 256;
+
+/// How long an IP without new offenses stays on record.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// What offending connections get.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TarpitMode {
+    /// Reset at once, leaving nothing to wait for.
+    #[default]
+    Reset,
+    /// Kept open and sent a random byte every few seconds, for ten minutes.
+    Drip,
+}
+
+impl FromStr for TarpitMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reset" => Ok(Self::Reset),
+            "drip" => Ok(Self::Drip),
+            _ => bail!("unknown tarpit mode '{s}' (expected reset or drip)"),
+        }
+    }
+}
+
+impl TryFrom<String> for TarpitMode {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for TarpitMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Reset => "reset",
+            Self::Drip => "drip",
+        })
+    }
+}
+
+/// How scanners on the control ports are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tarpit {
+    pub mode: TarpitMode,
+    /// Offenses after which an IP's connections are dropped on accept.
+    pub drop_after: u32,
+    /// Offending IPs remembered; the least recent is forgotten first.
+    pub tracked: usize,
+}
+
+impl Default for Tarpit {
+    fn default() -> Self {
+        Self {
+            mode: TarpitMode::Reset,
+            drop_after: 3,
+            tracked: 1024,
+        }
+    }
+}
+
+/// Why a control connection is tarpitted. Comes back from
+/// [`handle_control`](crate::server::handle_control) as its error.
+#[derive(Debug)]
+pub(crate) struct Offense {
+    /// Times the IP offended, this one included.
+    pub(crate) count: u32,
+    pub(crate) reason: String,
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offense #{}: {}", self.count, self.reason)
+    }
+}
+
+impl std::error::Error for Offense {}
+
+/// Whether `err`, which ended a handshake with `unread` bytes short of a
+/// frame, means the peer doesn't speak the protocol at all, rather than
+/// that the connection failed or was slow. Every message is a JSON object,
+/// so a scanner's request line gives it away before any delimiter.
+pub(crate) fn is_garbage(err: &anyhow::Error, unread: &[u8]) -> bool {
+    let malformed = matches!(
+        err.downcast_ref::<FrameError>(),
+        Some(FrameError::TooLarge { .. } | FrameError::Malformed(_))
+    );
+    malformed || unread.first().is_some_and(|&b| b != b'{')
+}
+
+/// What the tarpit did so far, for the admin API.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct TarpitStats {
+    pub(crate) offenses: u64,
+    pub(crate) resets: u64,
+    pub(crate) drips: u64,
+    /// Connections from repeat offenders dropped on accept.
+    pub(crate) dropped: u64,
+    pub(crate) tracked_ips: usize,
+}
+
+struct Record {
+    offenses: u32,
+    last: Instant,
+}
+
+/// The IPs that offended, at most [`Tarpit::tracked`] of them.
+pub(crate) struct Offenders {
+    records: Mutex<HashMap<IpAddr, Record>>,
+    drips: Semaphore,
+    offenses: AtomicU64,
+    resets: AtomicU64,
+    dripped: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Default for Offenders {
+    fn default() -> Self {
+        Self {
+            records: Mutex::default(),
+            drips: Semaphore::new(MAX_DRIPS),
+            offenses: AtomicU64::default(),
+            resets: AtomicU64::default(),
+            dripped: AtomicU64::default(),
+            dropped: AtomicU64::default(),
+        }
+    }
+}
+
+impl Offenders {
+    /// Count an offense of `ip`. Returns how many it has on record now.
+    pub(crate) fn record(&self, ip: IpAddr, tarpit: &Tarpit) -> u32 {
+        self.offenses.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        if !records.contains_key(&ip) && records.len() >= tarpit.tracked.max(1) {
+            // Small enough that a scan beats keeping the order around.
+            let oldest = records
+                .iter()
+                .min_by_key(|(_, r)| r.last)
+                .map(|(&ip, _)| ip);
+            if let Some(oldest) = oldest {
+                records.remove(&oldest);
+            }
+        }
+        let record = records.entry(ip).or_insert(Record {
+            offenses: 0,
+            last: now,
+        });
+        record.offenses = record.offenses.saturating_add(1);
+        record.last = now;
+        record.offenses
+    }
+
+    /// Whether `ip` offended often enough that its connections are dropped
+    /// on accept. Counts the drop if so.
+    pub(crate) fn drops(&self, ip: IpAddr, tarpit: &Tarpit) -> bool {
+        let records = self.records.lock().unwrap();
+        let drop = records
+            .get(&ip)
+            .is_some_and(|r| r.offenses >= tarpit.drop_after.max(1));
+        if drop {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        drop
+    }
+
+    /// Forget the offenses of `ip`, which just authenticated.
+    pub(crate) fn forgive(&self, ip: IpAddr) {
+        self.records.lock().unwrap().remove(&ip);
+    }
+
+    /// Forget IPs that have been quiet for a while.
+    pub(crate) fn prune(&self) {
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, r| now.duration_since(r.last) < FORGET_AFTER);
+    }
+
+    /// Answer an offending connection through `socket`, a second handle on
+    /// it from [`hold`], after its stream was dropped.
+    pub(crate) async fn punish(&self, socket: Socket, mode: TarpitMode) {
+        let permit = match mode {
+            TarpitMode::Drip => self.drips.try_acquire().ok(),
+            TarpitMode::Reset => None,
+        };
+        let Some(_permit) = permit else {
+            self.resets.fetch_add(1, Ordering::Relaxed);
+            reset(&socket);
+            return;
+        };
+        self.dripped.fetch_add(1, Ordering::Relaxed);
+        let mut stream = match TcpStream::from_std(socket.into()) {
+            Ok(stream) => stream,
+            Err(e) => return debug!(err = %e, "cannot drip"),
+        };
+        let until = Instant::now() + DRIP_FOR;
+        while Instant::now() < until {
+            sleep(DRIP_INTERVAL).await;
+            if stream.write_all(&[fastrand::u8(..)]).await.is_err() {
+                return;
+            }
+        }
+        reset(&SockRef::from(&stream));
+    }
+
+    pub(crate) fn stats(&self) -> TarpitStats {
+        TarpitStats {
+            offenses: self.offenses.load(Ordering::Relaxed),
+            resets: self.resets.load(Ordering::Relaxed),
+            drips: self.dripped.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            tracked_ips: self.records.lock().unwrap().len(),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// The second handle on the socket of the control connection being
+    /// served, until its handshake is done.
+    static HELD: Arc<Mutex<Option<Socket>>>;
+}
+
+/// Run `serve` with `held` at hand for the tarpit. Returns its output and
+/// the handle, unless the connection let go of it with [`release`].
+pub(crate) async fn holding<F: Future>(
+    held: Option<Socket>,
+    serve: F,
+) -> (F::Output, Option<Socket>) {
+    let held = Arc::new(Mutex::new(held));
+    let output = HELD.scope(Arc::clone(&held), serve).await;
+    let socket = held.lock().unwrap().take();
+    (output, socket)
+}
+
+/// Close the second handle on the current control connection's socket: it
+/// passed its handshake, so there is nothing left to tarpit.
+pub(crate) fn release() {
+    let _ = HELD.try_with(|held| held.lock().unwrap().take());
+}
+
+/// A second handle on an accepted socket, so it can still be answered once
+/// the connection's stream is gone. The socket stays open while it is held.
+pub(crate) fn hold(stream: &TcpStream) -> Option<Socket> {
+    #[cfg(unix)]
+    let owned = std::os::fd::AsFd::as_fd(stream).try_clone_to_owned();
+    #[cfg(windows)]
+    let owned = std::os::windows::io::AsSocket::as_socket(stream).try_clone_to_owned();
+    match owned {
+        Ok(owned) => Some(owned.into()),
+        Err(e) => {
+            debug!(err = %e, "cannot hold socket for the tarpit");
+            None
+        }
+    }
+}
+
+/// Make the socket reset its connection when it is closed, instead of
+/// ending it cleanly.
+pub(crate) fn reset(socket: &Socket) {
+    let _ = socket.set_linger(Some(Duration::ZERO));
+}
@@ -0,0 +1,167 @@
+//! Throttling of auth handshakes, so a secret can't be guessed at line rate.
+//!
+//! Each IP may start a few handshakes a minute. From the second failure in
+//! a row on, each makes it wait twice as long as the last before it may try
+//! again, and enough
+//! failures in a row get it banned for a while through the
+//! [`BanList`](crate::bans::BanList). A successful handshake wipes the slate.
+
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+/// Window over which [`AuthLimits::attempts_per_minute`] is counted.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Wait after the second failure; it doubles with each one after.
+const FIRST_PENALTY: Duration = Duration::from_secs(1);
+
+/// Longest wait a failure can earn.
+const MAX_PENALTY: Duration = Duration::from_secs(5 * 60);
+
+/// How hard clients that fail to authenticate are held back.
+#[derive(Debug, Clone)]
+pub struct AuthLimits {
+    /// Handshakes one IP may start per minute; more are dropped.
+    pub attempts_per_minute: u32,
+    /// Failed handshakes in a row after which the IP is banned; 0 never
+    /// bans.
+    pub max_failures: u32,
+    /// How long such a ban lasts.
+    pub ban_duration: Duration,
+}
+
+impl Default for AuthLimits {
+    fn default() -> Self {
+        Self {
+            attempts_per_minute: 30,
+            max_failures: 10,
+            ban_duration: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// What the throttle did so far, for the admin API.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct AuthStats {
+    pub(crate) attempts: u64,
+    pub(crate) failures: u64,
+    /// Handshakes dropped before they started.
+    pub(crate) throttled: u64,
+    pub(crate) bans: u64,
+    /// IPs with attempts or failures on record.
+    pub(crate) tracked_ips: usize,
+}
+
+#[derive(Debug)]
+struct Record {
+    window_start: Instant,
+    attempts: u32,
+    /// Failures since the last success.
+    failures: u32,
+    /// No handshake until then.
+    blocked_until: Option<Instant>,
+}
+
+impl Record {
+    /// Nothing to remember: the window is over, and any block ended long
+    /// enough ago that its failures may be forgotten.
+    fn is_stale(&self, now: Instant) -> bool {
+        now.duration_since(self.window_start) >= WINDOW
+            && self
+                .blocked_until
+                .is_none_or(|until| until + MAX_PENALTY <= now)
+    }
+}
+
+/// Per-IP handshake bookkeeping.
+#[derive(Default)]
+pub(crate) struct AuthThrottle {
+    records: DashMap<IpAddr, Record>,
+    attempts: AtomicU64,
+    failures: AtomicU64,
+    throttled: AtomicU64,
+    bans: AtomicU64,
+}
+
+impl AuthThrottle {
+    /// Count a handshake from `ip`. Returns how long it has to wait when it
+    /// may not start one now.
+    pub(crate) fn admit(&self, ip: IpAddr, limits: &AuthLimits) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut record = self.records.entry(ip).or_insert_with(|| Record {
+            window_start: now,
+            attempts: 0,
+            failures: 0,
+            blocked_until: None,
+        });
+        if let Some(until) = record.blocked_until.filter(|&until| until > now) {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return Err(until - now);
+        }
+        if now.duration_since(record.window_start) >= WINDOW {
+            record.window_start = now;
+            record.attempts = 0;
+        }
+        if record.attempts >= limits.attempts_per_minute {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return Err(WINDOW - now.duration_since(record.window_start));
+        }
+        record.attempts += 1;
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Record a failed handshake from `ip` and hold it back for a while.
+    /// Returns whether it has now failed often enough to be banned.
+    pub(crate) fn failed(&self, ip: IpAddr, limits: &AuthLimits) -> bool {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let Some(mut record) = self.records.get_mut(&ip) else {
+            return false;
+        };
+        record.failures += 1;
+        // A mistyped secret once is no reason to make anyone wait.
+        if record.failures > 1 {
+            let penalty = FIRST_PENALTY
+                .saturating_mul(1 << (record.failures - 2).min(16))
+                .min(MAX_PENALTY);
+            record.blocked_until = Some(now + penalty);
+        }
+        let ban = limits.max_failures > 0 && record.failures >= limits.max_failures;
+        if ban {
+            record.failures = 0;
+            self.bans.fetch_add(1, Ordering::Relaxed);
+        }
+        ban
+    }
+
+    /// Forget the failures of `ip`, which just authenticated.
+    pub(crate) fn succeeded(&self, ip: IpAddr) {
+        if let Some(mut record) = self.records.get_mut(&ip) {
+            record.failures = 0;
+            record.blocked_until = None;
+        }
+    }
+
+    /// Forget IPs with nothing left on record.
+    pub(crate) fn prune(&self) {
+        let now = Instant::now();
+        self.records.retain(|_, record| !record.is_stale(now));
+    }
+
+    pub(crate) fn stats(&self) -> AuthStats {
+        AuthStats {
+            attempts: self.attempts.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            bans: self.bans.load(Ordering::Relaxed),
+            tracked_ips: self.records.len(),
+        }
+    }
+}
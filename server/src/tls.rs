@@ -0,0 +1,375 @@
+//! TLS: HTTPS termination for HTTP tunnels and the encrypted control port.
+//!
+//! Every HTTP tunnel hostname gets its own certificate. It is ordered from the
+//! ACME directory (Let's Encrypt) when the tunnel registers and renewed in the
+//! background while the tunnel stays up. Orders are placed only for clients
+//! that authenticated, and at most [`TlsConfig::max_orders`] a week; other
+//! tunnels are served over plain HTTP. Validation uses TLS-ALPN-01 on the
+//! HTTPS listener itself, so that listener must be reachable on port 443 from
+//! the internet. TLS-ALPN-01 cannot issue wildcard certificates.
+//!
+//! Visitors are offered HTTP/1.1, which is what Host routing reads, except
+//! at the hostnames of gRPC tunnels, which offer HTTP/2 as well.
+//!
+//! The TLS control port carries the same protocol as the plain one, wrapped
+//! in TLS. It serves an operator-provided certificate or, failing that, a
+//! self-signed one that clients pin with `--tls-ca`. The QUIC transport
+//! (see [`quic`](crate::quic)) uses the same certificate.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use futures_util::StreamExt;
+use rustls_acme::{acme::ACME_TLS_ALPN_NAME, caches::DirCache, AcmeConfig, ResolvesServerCertAcme};
+use sshx_core::h2;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    task::AbortHandle,
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{Acceptor, ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    LazyConfigAcceptor, TlsAcceptor,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    budget::{Backoff, Counted},
+    http,
+    server::State,
+};
+
+/// How long a visitor may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Window of [`TlsConfig::max_orders`].
+const ORDER_WINDOW: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// HTTPS settings.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Port of the HTTPS listener.
+    pub port: u16,
+    /// Hostnames certified at startup, on top of tunnel hostnames.
+    pub domains: Vec<String>,
+    /// Contact address of the ACME account.
+    pub email: Option<String>,
+    /// Directory that keeps the ACME account and certificates across restarts.
+    pub cert_dir: PathBuf,
+    /// Use the Let's Encrypt production directory instead of staging.
+    pub production: bool,
+    /// Tunnel hostnames certificates are ordered for in any week. Further
+    /// tunnels are served over plain HTTP only, so that Let's Encrypt's
+    /// limit of 50 certificates per domain and week is never reached.
+    pub max_orders: usize,
+}
+
+/// Certificates by hostname, looked up by SNI during the handshake.
+#[derive(Debug, Default)]
+struct Certs {
+    hosts: DashMap<String, Host>,
+}
+
+#[derive(Debug)]
+struct Host {
+    resolver: Arc<ResolvesServerCertAcme>,
+    /// The host's tunnel carries gRPC, so HTTP/2 is offered.
+    grpc: bool,
+    /// Task that orders and renews the certificate.
+    driver: AbortHandle,
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+impl ResolvesServerCert for Certs {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name()?.to_ascii_lowercase();
+        let resolver = Arc::clone(&self.hosts.get(&name)?.resolver);
+        resolver.resolve(client_hello)
+    }
+}
+
+/// The HTTPS terminator shared by the listener and tunnel registration.
+pub(crate) struct Tls {
+    config: TlsConfig,
+    certs: Arc<Certs>,
+    /// Settings offering HTTP/1.1, and HTTP/2 as well for gRPC hosts.
+    http1: Arc<ServerConfig>,
+    http2: Arc<ServerConfig>,
+    /// Tunnel hostnames certificates were ordered for within the last
+    /// [`ORDER_WINDOW`], oldest first.
+    orders: Mutex<VecDeque<(Instant, String)>>,
+}
+
+impl Tls {
+    pub(crate) fn new(config: TlsConfig) -> Result<Self> {
+        let certs = Arc::new(Certs::default());
+        let mut server = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(certs.clone());
+        // ACME validators offer only `acme-tls/1`; browsers get HTTP/1.1,
+        // which is what Host routing understands.
+        server.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec(), b"http/1.1".to_vec()];
+        let mut http2 = server.clone();
+        http2.alpn_protocols.insert(1, h2::ALPN.to_vec());
+        let tls = Self {
+            http1: Arc::new(server),
+            http2: Arc::new(http2),
+            certs,
+            config,
+            orders: Mutex::default(),
+        };
+        for domain in &tls.config.domains {
+            tls.certify(domain.to_ascii_lowercase(), false);
+        }
+        Ok(tls)
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.config.port
+    }
+
+    /// Whether HTTPS is served for `host`: it is configured or its tunnel
+    /// got a certificate ordered.
+    pub(crate) fn serves(&self, host: &str) -> bool {
+        self.certs.hosts.contains_key(&host.to_ascii_lowercase())
+    }
+
+    /// Serve HTTPS for the tunnel hostname `host`, whose tunnel carries gRPC
+    /// if `grpc` is set. A host that has no certificate yet gets one ordered
+    /// if `may_order` is set and the week's orders aren't used up. Returns
+    /// whether HTTPS is served.
+    pub(crate) fn ensure(&self, host: &str, grpc: bool, may_order: bool) -> bool {
+        let host = host.to_ascii_lowercase();
+        if let Some(mut known) = self.certs.hosts.get_mut(&host) {
+            known.grpc = grpc;
+            return true;
+        }
+        if !may_order {
+            return false;
+        }
+        if !self.take_order(&host) {
+            warn!(%host, limit = self.config.max_orders, "certificate orders used up for the week");
+            return false;
+        }
+        self.certify(host, grpc);
+        true
+    }
+
+    /// Count an order for `host` against [`TlsConfig::max_orders`]. A host
+    /// that returns within the window isn't counted again.
+    fn take_order(&self, host: &str) -> bool {
+        let now = Instant::now();
+        let mut orders = self.orders.lock().unwrap();
+        while orders
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= ORDER_WINDOW)
+        {
+            orders.pop_front();
+        }
+        if orders.iter().any(|(_, ordered)| ordered == host) {
+            return true;
+        }
+        if orders.len() >= self.config.max_orders {
+            return false;
+        }
+        orders.push_back((now, host.to_owned()));
+        true
+    }
+
+    /// Start ordering (and renewing) a certificate for `host`.
+    fn certify(&self, host: String, grpc: bool) {
+        let entry = match self.certs.hosts.entry(host.clone()) {
+            dashmap::Entry::Occupied(mut entry) => {
+                entry.get_mut().grpc = grpc;
+                return;
+            }
+            dashmap::Entry::Vacant(entry) => entry,
+        };
+        let mut acme = AcmeConfig::new([&host])
+            .contact(self.config.email.iter().map(|e| format!("mailto:{e}")))
+            .cache(DirCache::new(self.config.cert_dir.clone()))
+            .directory_lets_encrypt(self.config.production)
+            .state();
+        let resolver = acme.resolver();
+        let driver = tokio::spawn(async move {
+            while let Some(event) = acme.next().await {
+                match event {
+                    Ok(event) => info!(%host, ?event, "ACME"),
+                    Err(err) => warn!(%host, ?err, "ACME order failed"),
+                }
+            }
+        });
+        entry.insert(Host {
+            resolver,
+            grpc,
+            driver: driver.abort_handle(),
+        });
+    }
+
+    /// Stop renewing the certificate of a tunnel that went away. Cached
+    /// certificates stay on disk, so a returning tunnel is served right away.
+    pub(crate) fn release(&self, host: &str) {
+        let host = host.to_ascii_lowercase();
+        if !self.config.domains.contains(&host) {
+            self.certs.hosts.remove(&host);
+        }
+    }
+}
+
+/// Accept visitors on the HTTPS port, terminate TLS and route them to tunnels.
+pub(crate) async fn serve(listener: TcpListener, tls: Arc<Tls>, state: Arc<State>) {
+    let mut backoff = Backoff::default();
+    loop {
+        state.budget.ready().await;
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(err = %e, "HTTPS accept failed");
+                backoff.failed().await;
+                continue;
+            }
+        };
+        backoff.succeeded();
+        if state.bans.is_ip_banned(addr.ip()) {
+            debug!(%addr, "dropping HTTPS connection from banned IP");
+            continue;
+        }
+        if let Err(location) = state.admit_visitor(addr.ip()) {
+            let country = location.country;
+            debug!(%addr, ?country, "dropping HTTPS connection by country");
+            continue;
+        }
+        let stream = Counted::new(stream, state.budget.take());
+        let (tls, state) = (Arc::clone(&tls), Arc::clone(&state));
+        tokio::spawn(async move {
+            if let Err(e) = terminate(stream, addr, &tls, &state).await {
+                debug!(%addr, err = %e, "HTTPS connection failed");
+            }
+        });
+    }
+}
+
+async fn terminate(
+    stream: Counted<TcpStream>,
+    addr: SocketAddr,
+    tls: &Tls,
+    state: &Arc<State>,
+) -> Result<()> {
+    let handshake = async {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        let name = start
+            .client_hello()
+            .server_name()
+            .map(str::to_ascii_lowercase);
+        let grpc = name
+            .and_then(|name| tls.certs.hosts.get(&name).map(|host| host.grpc))
+            .unwrap_or(false);
+        let config = match grpc {
+            true => Arc::clone(&tls.http2),
+            false => Arc::clone(&tls.http1),
+        };
+        start.into_stream(config).await
+    };
+    let mut stream = timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .context("TLS handshake timed out")??;
+    if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN_NAME) {
+        // The handshake itself answered the TLS-ALPN-01 challenge.
+        debug!(%addr, "ACME validation connection");
+        return Ok(stream.shutdown().await?);
+    }
+    http::route(stream, addr, state).await
+}
+
+// ── Control port ──────────────────────────────────────────────────────────────
+
+/// Settings of the TLS control port.
+#[derive(Debug, Clone)]
+pub struct ControlTlsConfig {
+    /// Port of the TLS control listener.
+    pub port: u16,
+    /// PEM certificate chain and private key to serve.
+    pub cert: Option<(PathBuf, PathBuf)>,
+    /// Where the self-signed certificate is kept when `cert` is not set.
+    pub cert_dir: PathBuf,
+    /// Names the self-signed certificate is valid for.
+    pub names: Vec<String>,
+    /// Also accept the QUIC transport on UDP at `port`.
+    pub quic: bool,
+}
+
+/// Build the acceptor for the TLS control port.
+pub(crate) fn control_acceptor(config: &ControlTlsConfig) -> Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(control_server_config(config)?)))
+}
+
+/// TLS settings of the control port, shared by TCP and QUIC.
+pub(crate) fn control_server_config(config: &ControlTlsConfig) -> Result<ServerConfig> {
+    let (cert_path, key_path) = match &config.cert {
+        Some(paths) => paths.clone(),
+        None => self_signed(config)?,
+    };
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| anyhow!("cannot read {}: {e}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .map_err(|e| anyhow!("cannot read {}: {e}", key_path.display()))?;
+    let server = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(server)
+}
+
+/// Paths of the self-signed control certificate, generating it on first use.
+fn self_signed(config: &ControlTlsConfig) -> Result<(PathBuf, PathBuf)> {
+    let cert_path = config.cert_dir.join("control-cert.pem");
+    let key_path = config.cert_dir.join("control-key.pem");
+    if !cert_path.exists() || !key_path.exists() {
+        let generated = rcgen::generate_simple_self_signed(config.names.clone())?;
+        fs::create_dir_all(&config.cert_dir)
+            .with_context(|| format!("cannot create {}", config.cert_dir.display()))?;
+        fs::write(&cert_path, generated.cert.pem())?;
+        // A key without its certificate is useless, and would stop
+        // `create_new`.
+        let _ = fs::remove_file(&key_path);
+        write_private(&key_path, &generated.key_pair.serialize_pem())
+            .with_context(|| format!("cannot write {}", key_path.display()))?;
+        info!(names = ?config.names, "generated self-signed control certificate");
+    }
+    info!(
+        cert = %cert_path.display(),
+        "clients must pass this certificate as --tls-ca"
+    );
+    Ok((cert_path, key_path))
+}
+
+/// Create `path` readable by the owner only, whatever the umask.
+fn write_private(path: &Path, text: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, text.as_bytes())
+}
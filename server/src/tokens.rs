@@ -0,0 +1,190 @@
+//! Per-user tokens, read from a TOML file that can be edited while the
+//! server runs.
+//!
+//! ```toml
+//! [alice]
+//! token = "c2a7…"
+//! subdomains = ["alice", "alice-*"]   # optional, default any
+//! ports = ["3000-3999", "8080"]       # optional, default the whole range
+//! protocols = ["http", "tcp"]         # optional, default all
+//! max_tunnels = 5                     # optional, default unlimited
+//!
+//! [ci]
+//! token = "9f1e…"
+//! ```
+//!
+//! Each table names an [`Identity`]. Clients answer the usual challenge with
+//! their token in place of the shared secret, so tokens never cross the wire
+//! either. Revoking a user is deleting their table.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::BoxFuture;
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tracing::{info, warn};
+
+use sshx_core::protocol::Proto;
+
+use crate::auth::{AuthMetadata, AuthProvider, ChallengeResponse, Identity, Secrets};
+
+/// Token provider backed by a tokens file.
+pub struct Tokens {
+    path: PathBuf,
+    inner: Arc<Mutex<Inner>>,
+    /// Shared secrets accepted as well, e.g. `--secret`.
+    secrets: Secrets,
+}
+
+struct Inner {
+    tokens: Secrets,
+    /// Modification time of the file when we last read it.
+    loaded_mtime: Option<SystemTime>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    token: String,
+    #[serde(default)]
+    subdomains: Vec<String>,
+    #[serde(default)]
+    ports: Vec<String>,
+    #[serde(default)]
+    protocols: Vec<String>,
+    max_tunnels: Option<usize>,
+}
+
+impl Tokens {
+    /// Load tokens from `path`, which must exist.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let tokens = Self {
+            inner: Arc::new(Mutex::new(Inner {
+                tokens: read_file(&path)?,
+                loaded_mtime: mtime(&path),
+            })),
+            path,
+            secrets: Secrets::new(),
+        };
+        Ok(tokens)
+    }
+
+    /// Also accept these shared secrets.
+    pub fn with_secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = secrets;
+        self
+    }
+
+    /// Re-read the file if it changed since we last read it. A broken edit
+    /// keeps the previous tokens in force.
+    pub fn refresh(&self) -> Result<()> {
+        refresh(&self.path, &self.inner)
+    }
+}
+
+impl AuthProvider for Tokens {
+    fn authenticate(
+        &self,
+        response: ChallengeResponse,
+        _meta: AuthMetadata,
+    ) -> BoxFuture<'_, Result<Identity>> {
+        Box::pin(async move {
+            // Looking at the file blocks, so it is done off the runtime.
+            let (path, inner) = (self.path.clone(), Arc::clone(&self.inner));
+            let refreshed = spawn_blocking(move || refresh(&path, &inner))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|refreshed| refreshed);
+            if let Err(e) = refreshed {
+                warn!(
+                    err = format!("{e:#}"),
+                    "cannot reload tokens, keeping the old ones"
+                );
+            }
+            let found = self.inner.lock().unwrap().tokens.find(&response);
+            let what = if self.secrets.is_empty() {
+                "token"
+            } else {
+                "secret or token"
+            };
+            found
+                .or_else(|| self.secrets.find(&response))
+                .ok_or_else(|| anyhow!("invalid {what}"))
+        })
+    }
+}
+
+/// Re-read the tokens at `path` into `inner` if the file changed.
+fn refresh(path: &Path, inner: &Mutex<Inner>) -> Result<()> {
+    let mtime = mtime(path);
+    let mut inner = inner.lock().unwrap();
+    if mtime.is_none() || mtime == inner.loaded_mtime {
+        return Ok(());
+    }
+    inner.loaded_mtime = mtime;
+    inner.tokens = read_file(path)?;
+    info!(path = %path.display(), "tokens reloaded");
+    Ok(())
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_file(path: &Path) -> Result<Secrets> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let entries: BTreeMap<String, Entry> =
+        toml::from_str(&text).with_context(|| format!("invalid tokens file {}", path.display()))?;
+    let mut tokens = Secrets::new();
+    for (name, entry) in entries {
+        if entry.token.is_empty() {
+            bail!("token of '{name}' is empty");
+        }
+        let mut identity = Identity::new(&name);
+        identity.subdomains = entry.subdomains;
+        identity.ports = entry
+            .ports
+            .iter()
+            .map(|p| parse_ports(p))
+            .collect::<Result<_>>()
+            .with_context(|| format!("invalid ports of '{name}'"))?;
+        identity.protos = entry
+            .protocols
+            .iter()
+            .map(|p| parse_proto(p))
+            .collect::<Result<_>>()
+            .with_context(|| format!("invalid protocols of '{name}'"))?;
+        identity.max_tunnels = entry.max_tunnels;
+        tokens = tokens.with(&entry.token, identity);
+    }
+    Ok(tokens)
+}
+
+/// `"3000-3999"` or a single `"8080"`.
+fn parse_ports(s: &str) -> Result<RangeInclusive<u16>> {
+    let (lo, hi) = s.split_once('-').unwrap_or((s, s));
+    let (lo, hi): (u16, u16) = (lo.trim().parse()?, hi.trim().parse()?);
+    if lo > hi {
+        bail!("empty port range {s}");
+    }
+    Ok(lo..=hi)
+}
+
+fn parse_proto(s: &str) -> Result<Proto> {
+    match s.to_ascii_lowercase().as_str() {
+        "tcp" => Ok(Proto::Tcp),
+        "http" => Ok(Proto::Http),
+        "udp" => Ok(Proto::Udp),
+        _ => bail!("unknown protocol '{s}' (expected tcp, http or udp)"),
+    }
+}
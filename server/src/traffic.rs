@@ -0,0 +1,311 @@
+//! Per-tunnel traffic accounting and bandwidth limits.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+/// Counters of one tunnel, updated as bytes move.
+#[derive(Debug)]
+pub(crate) struct Traffic {
+    connections: AtomicU64,
+    /// Visitor connections open right now.
+    open: AtomicU64,
+    /// Visitor connections turned away by a connection limit.
+    rejected: AtomicU64,
+    /// When a connection last opened or closed, or the tunnel registered.
+    last_active: Mutex<Instant>,
+    /// From visitors towards the client.
+    bytes_in: AtomicU64,
+    /// From the client back to visitors.
+    bytes_out: AtomicU64,
+    /// Bytes/sec allowed in each direction, shared by all connections.
+    limit: Option<(Bucket, Bucket)>,
+    /// Visitor connections the tunnel takes in all, if the client limited
+    /// them.
+    max_uses: Option<u64>,
+}
+
+/// A token bucket that may go into debt: a transfer is charged after the
+/// fact, and the next one waits until the debt is paid off.
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    /// Available bytes and when they were last topped up.
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// How long until the bucket is out of debt; `None` when it is.
+    fn wait(&self) -> Option<Duration> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(tokens.1).as_secs_f64() * self.rate as f64;
+        *tokens = ((tokens.0 + refill).min(self.rate as f64), now);
+        (tokens.0 < 0.0).then(|| Duration::from_secs_f64(-tokens.0 / self.rate as f64))
+    }
+
+    fn charge(&self, n: usize) {
+        self.tokens.lock().unwrap().0 -= n as f64;
+    }
+}
+
+/// A point-in-time copy of [`Traffic`].
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct TrafficSnapshot {
+    pub(crate) connections: u64,
+    pub(crate) open: u64,
+    pub(crate) rejected: u64,
+    pub(crate) bytes_in: u64,
+    pub(crate) bytes_out: u64,
+}
+
+impl Traffic {
+    /// Counters for a tunnel limited to `max_bandwidth` bytes/sec per
+    /// direction and `max_uses` visitor connections, or unlimited.
+    pub(crate) fn new(max_bandwidth: Option<u64>, max_uses: Option<u64>) -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            open: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            last_active: Mutex::new(Instant::now()),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            limit: max_bandwidth
+                .filter(|&rate| rate > 0)
+                .map(|rate| (Bucket::new(rate), Bucket::new(rate))),
+            max_uses,
+        }
+    }
+
+    /// Count a visitor connection as open until the guard is dropped.
+    pub(crate) fn in_use(self: &Arc<Self>) -> InUse {
+        self.open.fetch_add(1, Ordering::Relaxed);
+        *self.last_active.lock().unwrap() = Instant::now();
+        InUse(Arc::clone(self))
+    }
+
+    /// How long the tunnel has been without visitor connections; `None`
+    /// while one is open.
+    pub(crate) fn idle_for(&self) -> Option<Duration> {
+        if self.open.load(Ordering::Relaxed) > 0 {
+            return None;
+        }
+        Some(self.last_active.lock().unwrap().elapsed())
+    }
+
+    pub(crate) fn max_bandwidth(&self) -> Option<u64> {
+        self.limit.as_ref().map(|(inbound, _)| inbound.rate)
+    }
+
+    /// Count an inbound datagram unless the tunnel is over its limit; UDP
+    /// is policed by dropping rather than delayed.
+    pub(crate) fn admit_in(&self, n: usize) -> bool {
+        self.admit(n, |(inbound, _)| inbound, Self::add_in)
+    }
+
+    /// Like [`admit_in`](Self::admit_in), for replies.
+    pub(crate) fn admit_out(&self, n: usize) -> bool {
+        self.admit(n, |(_, outbound)| outbound, Self::add_out)
+    }
+
+    fn admit(
+        &self,
+        n: usize,
+        bucket: fn(&(Bucket, Bucket)) -> &Bucket,
+        count: fn(&Self, usize),
+    ) -> bool {
+        if let Some(bucket) = self.limit.as_ref().map(bucket) {
+            if bucket.wait().is_some() {
+                return false;
+            }
+            bucket.charge(n);
+        }
+        count(self, n);
+        true
+    }
+
+    /// Count a visitor connection, unless the tunnel took as many as it
+    /// may already; returns whether it gets through.
+    pub(crate) fn connection(&self) -> bool {
+        let Some(max) = self.max_uses else {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+            return true;
+        };
+        let next = |n: u64| (n < max).then_some(n + 1);
+        self.connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, next)
+            .is_ok()
+    }
+
+    /// The visitor connections the tunnel took, once they are as many as it
+    /// may take and the last one is closed.
+    pub(crate) fn used_up(&self) -> Option<u64> {
+        let max = self.max_uses?;
+        let done = self.connections.load(Ordering::Relaxed) >= max;
+        (done && self.open.load(Ordering::Relaxed) == 0).then_some(max)
+    }
+
+    /// Visitor connections open right now.
+    pub(crate) fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed) as usize
+    }
+
+    /// Count a visitor connection turned away by a limit; returns how many
+    /// have been so far.
+    pub(crate) fn reject(&self) -> u64 {
+        self.rejected.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub(crate) fn add_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            open: self.open.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keeps a tunnel from counting as idle.
+pub(crate) struct InUse(Arc<Traffic>);
+
+impl Drop for InUse {
+    fn drop(&mut self) {
+        *self.0.last_active.lock().unwrap() = Instant::now();
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A visitor stream that counts what is read from and written to it, and
+/// holds either direction back while the tunnel is over its limit.
+pub(crate) struct Metered<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+    _in_use: InUse,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Metered<S> {
+    pub(crate) fn new(inner: S, traffic: Arc<Traffic>) -> Self {
+        Self {
+            inner,
+            _in_use: traffic.in_use(),
+            traffic,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// The stream and the traffic it counts towards, for relaying around
+    /// the meter; `None` while a bandwidth limit needs the meter.
+    pub(crate) fn unmetered(&self) -> Option<(&S, &Traffic)> {
+        self.traffic
+            .limit
+            .is_none()
+            .then_some((&self.inner, &*self.traffic))
+    }
+}
+
+/// Ready once `bucket` is out of debt, sleeping in `delay` until then.
+fn poll_bucket(
+    bucket: Option<&Bucket>,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    let Some(bucket) = bucket else {
+        return Poll::Ready(());
+    };
+    loop {
+        if let Some(sleeping) = delay {
+            if sleeping.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *delay = None;
+        }
+        match bucket.wait() {
+            Some(wait) => *delay = Some(Box::pin(sleep(wait))),
+            None => return Poll::Ready(()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let bucket = this.traffic.limit.as_ref().map(|(inbound, _)| inbound);
+        if poll_bucket(bucket, &mut this.read_delay, cx).is_pending() {
+            return Poll::Pending;
+        }
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        this.traffic.add_in(n);
+        if let Some(bucket) = bucket {
+            bucket.charge(n);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let bucket = this.traffic.limit.as_ref().map(|(_, outbound)| outbound);
+        if poll_bucket(bucket, &mut this.write_delay, cx).is_pending() {
+            return Poll::Pending;
+        }
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.traffic.add_out(n);
+            if let Some(bucket) = bucket {
+                bucket.charge(n);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,92 @@
+//! Warm pools: idle data connections a client parks at the server with
+//! `Standby`, so that a visitor is bridged at once instead of waiting for
+//! the client to dial in and `Accept` it.
+//!
+//! A session whose client asked for `warm` gets a [`WarmPool`] and hands
+//! out its key in `Hello`. Each `Standby` connection presenting the key
+//! waits in the pool for a [`Handoff`]; the session gives the next visitor
+//! to the connection that waited longest, and parks it for an `Accept` as
+//! usual when none is left.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, Weak},
+};
+
+use sshx_core::protocol::{ServerMsg, MAX_WARM_POOL};
+use tokio::sync::oneshot;
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::server::Inbound;
+
+/// A visitor on its way to a warm connection.
+pub(crate) struct Handoff {
+    pub(crate) subdomain: String,
+    /// The `Connection` frame the client gets first.
+    pub(crate) announce: ServerMsg,
+    pub(crate) inbound: Inbound,
+    pub(crate) span: Span,
+}
+
+/// The warm connections of one session.
+pub(crate) struct WarmPool {
+    /// Identity the session authenticated as; only connections with the
+    /// same one may join.
+    pub(crate) identity: String,
+    waiting: Mutex<VecDeque<oneshot::Sender<Handoff>>>,
+}
+
+impl WarmPool {
+    /// Wait in the pool, unless it is full. The receiver fails once the
+    /// session is gone.
+    pub(crate) fn park(&self) -> Option<oneshot::Receiver<Handoff>> {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.retain(|tx| !tx.is_closed());
+        if waiting.len() >= MAX_WARM_POOL {
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        waiting.push_back(tx);
+        Some(rx)
+    }
+
+    /// Give `handoff` to the connection that waited longest, or back if
+    /// none is left.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn hand(&self, mut handoff: Handoff) -> Result<(), Handoff> {
+        let mut waiting = self.waiting.lock().unwrap();
+        while let Some(tx) = waiting.pop_front() {
+            match tx.send(handoff) {
+                Ok(()) => return Ok(()),
+                // That connection hung up meanwhile.
+                Err(back) => handoff = back,
+            }
+        }
+        Err(handoff)
+    }
+}
+
+/// Every session's pool, by key.
+#[derive(Default)]
+pub(crate) struct WarmPools(Mutex<HashMap<Uuid, Weak<WarmPool>>>);
+
+impl WarmPools {
+    /// A new pool for a session of `identity`, and its key. It goes away
+    /// with the session, which holds the only strong reference.
+    pub(crate) fn open(&self, identity: &str) -> (Uuid, Arc<WarmPool>) {
+        let key = Uuid::new_v4();
+        let pool = Arc::new(WarmPool {
+            identity: identity.to_owned(),
+            waiting: Mutex::default(),
+        });
+        let mut pools = self.0.lock().unwrap();
+        pools.retain(|_, pool| pool.strong_count() > 0);
+        pools.insert(key, Arc::downgrade(&pool));
+        (key, pool)
+    }
+
+    pub(crate) fn get(&self, key: Uuid) -> Option<Arc<WarmPool>> {
+        self.0.lock().unwrap().get(&key)?.upgrade()
+    }
+}
@@ -0,0 +1,358 @@
+//! Webhook notifications for operators (`--webhook`).
+//!
+//! Tunnels registered and closed, failed authentication and exceeded quotas
+//! are POSTed as JSON to the configured URL, one request per event and in the
+//! order they happened, e.g. for the audit log of a server a team shares.
+//! Delivery is best effort: while the webhook is down or slow, events queue
+//! up to a limit and are then dropped rather than holding up clients.
+
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sshx_core::protocol::Proto;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use tracing::warn;
+
+/// Events waiting for the webhook; more are dropped.
+const QUEUE: usize = 1024;
+
+/// How long one delivery may take.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest webhook response head we read.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// A URL to POST events to, `http[s]://host[:port][/path]`.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Webhook {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// Path and query.
+    path: String,
+}
+
+impl FromStr for Webhook {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (tls, rest) = match s.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => bail!("webhook '{s}' must start with http:// or https://"),
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(at) => (&rest[..at], &rest[at..]),
+            None => (rest, "/"),
+        };
+        let path = match path.starts_with('?') {
+            true => format!("/{path}"),
+            false => path.to_owned(),
+        };
+        // `[::1]:8080` keeps its colons inside the brackets.
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .with_context(|| format!("invalid webhook port '{port}'"))?,
+            ),
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('@') {
+            bail!("invalid webhook host in '{s}'");
+        }
+        Ok(Self {
+            tls,
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+}
+
+impl TryFrom<String> for Webhook {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Leaves the path out, which for many services is the secret.
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{scheme}://{}:{}/…", self.host, self.port)
+    }
+}
+
+/// Something an operator is told about.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Notification {
+    /// `tunnel_registered`, `tunnel_closed`, `auth_failed` or
+    /// `quota_exceeded`; the audit log also gets `auth_succeeded`,
+    /// `admin_tunnel_closed` and `admin_reload`.
+    event: &'static str,
+    /// Seconds since the Unix epoch.
+    time: u64,
+    /// Address of the client's control connection, or of the admin API
+    /// caller.
+    client_ip: IpAddr,
+    /// Name of the token or secret the client authenticated with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subdomain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proto: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_port: Option<u16>,
+    /// Bytes relayed each way over the tunnel's life.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_in: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_out: Option<u64>,
+    /// Why the client was turned away, or the settings weren't reloaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl Notification {
+    fn new(event: &'static str, client_ip: IpAddr) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self {
+            event,
+            time,
+            client_ip,
+            identity: None,
+            subdomain: None,
+            proto: None,
+            public_port: None,
+            bytes_in: None,
+            bytes_out: None,
+            reason: None,
+        }
+    }
+
+    pub(crate) fn registered(
+        client_ip: IpAddr,
+        identity: &str,
+        subdomain: &str,
+        proto: Proto,
+        public_port: u16,
+    ) -> Self {
+        Self {
+            identity: Some(identity.to_owned()),
+            subdomain: Some(subdomain.to_owned()),
+            proto: Some(format!("{proto:?}").to_lowercase()),
+            public_port: Some(public_port),
+            ..Self::new("tunnel_registered", client_ip)
+        }
+    }
+
+    pub(crate) fn closed(
+        client_ip: IpAddr,
+        identity: &str,
+        subdomain: &str,
+        public_port: u16,
+        (bytes_in, bytes_out): (u64, u64),
+    ) -> Self {
+        Self {
+            identity: Some(identity.to_owned()),
+            subdomain: Some(subdomain.to_owned()),
+            public_port: Some(public_port),
+            bytes_in: Some(bytes_in),
+            bytes_out: Some(bytes_out),
+            ..Self::new("tunnel_closed", client_ip)
+        }
+    }
+
+    pub(crate) fn auth_failed(client_ip: IpAddr, reason: String) -> Self {
+        Self {
+            reason: Some(reason),
+            ..Self::new("auth_failed", client_ip)
+        }
+    }
+
+    pub(crate) fn auth_succeeded(client_ip: IpAddr, identity: &str) -> Self {
+        Self {
+            identity: Some(identity.to_owned()),
+            ..Self::new("auth_succeeded", client_ip)
+        }
+    }
+
+    pub(crate) fn admin_tunnel_closed(admin_ip: IpAddr, subdomain: &str) -> Self {
+        Self {
+            subdomain: Some(subdomain.to_owned()),
+            ..Self::new("admin_tunnel_closed", admin_ip)
+        }
+    }
+
+    /// `error` says why the settings were kept, if they were.
+    pub(crate) fn admin_reload(admin_ip: IpAddr, error: Option<String>) -> Self {
+        Self {
+            reason: error,
+            ..Self::new("admin_reload", admin_ip)
+        }
+    }
+
+    pub(crate) fn quota_exceeded(
+        client_ip: IpAddr,
+        identity: &str,
+        subdomain: &str,
+        reason: String,
+    ) -> Self {
+        Self {
+            identity: Some(identity.to_owned()),
+            subdomain: Some(subdomain.to_owned()),
+            reason: Some(reason),
+            ..Self::new("quota_exceeded", client_ip)
+        }
+    }
+}
+
+/// Hands notifications to the task that delivers them.
+pub(crate) struct Notifier {
+    queue: mpsc::Sender<(Webhook, Notification)>,
+}
+
+impl Notifier {
+    /// Start delivering in the background, until the notifier is dropped.
+    pub(crate) fn start() -> Self {
+        let (queue, pending) = mpsc::channel(QUEUE);
+        tokio::spawn(deliver(pending));
+        Self { queue }
+    }
+
+    /// Queue `notification` for `webhook`.
+    pub(crate) fn send(&self, webhook: Webhook, notification: Notification) {
+        if self.queue.try_send((webhook, notification)).is_err() {
+            warn!("webhook is falling behind; dropping an event");
+        }
+    }
+}
+
+/// POST each queued notification in turn.
+async fn deliver(mut pending: mpsc::Receiver<(Webhook, Notification)>) {
+    let mut connector = None;
+    while let Some((webhook, notification)) = pending.recv().await {
+        let posted = timeout(
+            DELIVERY_TIMEOUT,
+            webhook.post(&notification, &mut connector),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+        if let Err(e) = posted {
+            warn!(
+                ?webhook,
+                event = notification.event,
+                "webhook failed: {e:#}"
+            );
+        }
+    }
+}
+
+impl Webhook {
+    /// POST `notification`, building `connector` on the first HTTPS request.
+    async fn post(
+        &self,
+        notification: &Notification,
+        connector: &mut Option<TlsConnector>,
+    ) -> Result<()> {
+        let body = serde_json::to_vec(notification)?;
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("cannot reach {}:{}", self.host, self.port))?;
+        if !self.tls {
+            return self.send(stream, &body).await;
+        }
+        let connector = match connector {
+            Some(connector) => connector,
+            None => connector.insert(tls_connector()?),
+        };
+        let name = ServerName::try_from(self.host.clone())
+            .with_context(|| format!("invalid TLS name '{}'", self.host))?;
+        let stream = connector
+            .connect(name, stream)
+            .await
+            .with_context(|| format!("TLS with {} failed", self.host))?;
+        self.send(stream, &body).await
+    }
+
+    /// Send the request on `stream` and check the status of the answer.
+    async fn send(
+        &self,
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        body: &[u8],
+    ) -> Result<()> {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        let head = format!(
+            "POST {} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             User-Agent: sshx-server/{}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.path,
+            env!("CARGO_PKG_VERSION"),
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.windows(4).any(|w| w == b"\r\n\r\n") {
+            if response.len() > MAX_HEAD {
+                bail!("webhook response head too large");
+            }
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        let status = String::from_utf8_lossy(&response);
+        let status = status.lines().next().unwrap_or_default();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => bail!("webhook answered '{status}'"),
+        }
+    }
+}
+
+/// Trusts the public web PKI.
+fn tls_connector() -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
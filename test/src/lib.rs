@@ -0,0 +1,337 @@
+//! sshx-test — a scriptable in-memory relay for testing sshx clients.
+//!
+//! [`MockRelay`] speaks the server side of the control protocol on an
+//! ephemeral localhost port, without claiming public ports. Tests drive it
+//! directly: inject visitor connections, push arbitrary server messages, add
+//! delays or reject registrations.
+//!
+//! ```no_run
+//! # async fn demo() -> anyhow::Result<()> {
+//! use sshx_test::MockRelay;
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! let relay = MockRelay::builder().secret("hunter2").start().await?;
+//! // … start the client against 127.0.0.1 with --control-port relay.port() …
+//! let registration = relay.registered().await?;
+//! assert_eq!(registration.subdomain, "myapp");
+//!
+//! let mut visitor = relay.inject_connection().await?;
+//! visitor.write_all(b"ping").await?;
+//! let mut buf = [0; 4];
+//! visitor.read_exact(&mut buf).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use sshx_core::protocol::{
+    ClientMsg, Framed_, Proto, ServerMsg, HEARTBEAT_INTERVAL, PROTOCOL_VERSION,
+};
+use sshx_server::auth::{self, Auth, AuthMetadata};
+use tokio::{
+    io::{duplex, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::{interval, sleep, timeout},
+};
+use uuid::Uuid;
+
+/// How long [`MockRelay`] waits for the client to react before failing.
+const REACT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Buffer size of the in-memory visitor pipe.
+const PIPE_SIZE: usize = 64 * 1024;
+
+// ── Builder ───────────────────────────────────────────────────────────────────
+
+/// Scripted behaviour for a [`MockRelay`].
+#[derive(Debug, Clone)]
+pub struct MockRelayBuilder {
+    secret: Option<String>,
+    hello_delay: Duration,
+    reject: Option<String>,
+    public_port: u16,
+    heartbeats: bool,
+    version: u32,
+}
+
+impl MockRelayBuilder {
+    /// Require the HMAC shared-secret handshake.
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.to_owned());
+        self
+    }
+
+    /// Wait this long before answering `Hello`.
+    pub fn hello_delay(mut self, delay: Duration) -> Self {
+        self.hello_delay = delay;
+        self
+    }
+
+    /// Answer every `Hello` with `ServerMsg::Error(message)`.
+    pub fn reject(mut self, message: &str) -> Self {
+        self.reject = Some(message.to_owned());
+        self
+    }
+
+    /// Port reported back in `ServerMsg::Hello` (nothing is bound on it).
+    pub fn public_port(mut self, port: u16) -> Self {
+        self.public_port = port;
+        self
+    }
+
+    /// Speak up to this protocol version, e.g. to stand in for an older
+    /// server. Version 1 leaves the version out of `Hello` like servers from
+    /// before versioning did.
+    pub fn protocol_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Stop sending heartbeats, e.g. to exercise client liveness checks.
+    pub fn without_heartbeats(mut self) -> Self {
+        self.heartbeats = false;
+        self
+    }
+
+    /// Bind 127.0.0.1 on an ephemeral port and start serving.
+    pub async fn start(self) -> Result<MockRelay> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let inner = Arc::new(Inner {
+            behavior: self,
+            registration: watch::Sender::new(None),
+            session: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        });
+        let task = tokio::spawn(accept_loop(listener, Arc::clone(&inner)));
+        Ok(MockRelay { addr, inner, task })
+    }
+}
+
+impl Default for MockRelayBuilder {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            hello_delay: Duration::ZERO,
+            reject: None,
+            public_port: 40000,
+            heartbeats: true,
+            version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+// ── Relay ─────────────────────────────────────────────────────────────────────
+
+/// What the client asked for in its `Hello`.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub subdomain: String,
+    pub proto: Proto,
+}
+
+/// A fake sshx-server. Dropping it stops the relay.
+pub struct MockRelay {
+    addr: SocketAddr,
+    inner: Arc<Inner>,
+    task: JoinHandle<()>,
+}
+
+struct Inner {
+    behavior: MockRelayBuilder,
+    registration: watch::Sender<Option<Registration>>,
+    /// Outbox of the currently registered control connection.
+    session: Mutex<Option<mpsc::UnboundedSender<ServerMsg>>>,
+    /// Injected connections waiting for the client's `Accept`.
+    pending: Mutex<HashMap<Uuid, oneshot::Sender<Framed_<TcpStream>>>>,
+}
+
+impl MockRelay {
+    pub fn builder() -> MockRelayBuilder {
+        MockRelayBuilder::default()
+    }
+
+    /// Start a relay with default behaviour.
+    pub async fn start() -> Result<Self> {
+        Self::builder().start().await
+    }
+
+    /// Address of the control port.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The control port, for the client's `--control-port`.
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// Wait until a client has registered a tunnel.
+    pub async fn registered(&self) -> Result<Registration> {
+        let mut rx = self.inner.registration.subscribe();
+        let reg = timeout(REACT_TIMEOUT, rx.wait_for(Option::is_some))
+            .await
+            .context("no client registered")??;
+        Ok(reg.clone().expect("waited for Some"))
+    }
+
+    /// Push a raw message to the registered client.
+    pub fn send(&self, msg: ServerMsg) -> Result<()> {
+        let session = self.inner.session.lock().unwrap();
+        let outbox = session
+            .as_ref()
+            .ok_or_else(|| anyhow!("no client registered"))?;
+        outbox
+            .send(msg)
+            .map_err(|_| anyhow!("control connection closed"))
+    }
+
+    /// Simulate a visitor from `127.0.0.1:1` connecting to the public port.
+    pub async fn inject_connection(&self) -> Result<DuplexStream> {
+        self.inject_connection_from(SocketAddr::from(([127, 0, 0, 1], 1)))
+            .await
+    }
+
+    /// Simulate a visitor from `peer_addr`. Returns the visitor's end of an
+    /// in-memory pipe once the client has accepted the connection.
+    pub async fn inject_connection_from(&self, peer_addr: SocketAddr) -> Result<DuplexStream> {
+        let id = Uuid::new_v4();
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending.lock().unwrap().insert(id, tx);
+        // Like a single-tunnel server, leave the tunnel implicit.
+        self.send(ServerMsg::Connection {
+            id,
+            peer_addr,
+            subdomain: None,
+            public_port: None,
+            country: None,
+            asn: None,
+        })?;
+
+        let data = match timeout(REACT_TIMEOUT, rx).await {
+            Ok(Ok(data)) => data,
+            _ => {
+                self.inner.pending.lock().unwrap().remove(&id);
+                bail!("client did not accept connection {id}");
+            }
+        };
+
+        let (visitor, mut relay_end) = duplex(PIPE_SIZE);
+        tokio::spawn(async move {
+            let mut parts = data.into_parts();
+            if relay_end.write_all(&parts.read_buf).await.is_ok() {
+                let _ = tokio::io::copy_bidirectional(&mut relay_end, &mut parts.io).await;
+            }
+        });
+        Ok(visitor)
+    }
+
+    /// Drop the control connection, as if the relay went away.
+    pub fn disconnect(&self) {
+        self.inner.session.lock().unwrap().take();
+        self.inner.registration.send_replace(None);
+    }
+}
+
+impl Drop for MockRelay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// ── Protocol handling ─────────────────────────────────────────────────────────
+
+async fn accept_loop(listener: TcpListener, inner: Arc<Inner>) {
+    while let Ok((stream, addr)) = listener.accept().await {
+        let inner = Arc::clone(&inner);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, addr, inner).await {
+                tracing::debug!(%addr, err = %e, "mock relay connection ended");
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, addr: SocketAddr, inner: Arc<Inner>) -> Result<()> {
+    let mut ctrl = Framed_::new(stream);
+    let behavior = &inner.behavior;
+
+    if let Some(secret) = &behavior.secret {
+        let meta = AuthMetadata { peer_addr: addr };
+        if let Err(e) = auth::handshake_server(&Auth::new(secret), &mut ctrl, meta).await {
+            ctrl.send(ServerMsg::Error(e.to_string())).await?;
+            return Ok(());
+        }
+    }
+
+    match ctrl.recv_timeout::<ClientMsg>().await? {
+        Some(ClientMsg::Hello {
+            subdomain,
+            proto,
+            version,
+            ..
+        }) => {
+            sleep(behavior.hello_delay).await;
+            if let Some(message) = &behavior.reject {
+                ctrl.send(ServerMsg::Error(message.clone())).await?;
+                return Ok(());
+            }
+            ctrl.send(ServerMsg::Hello {
+                public_port: behavior.public_port,
+                url: None,
+                mux: false,
+                version: version.min(behavior.version),
+                session: None,
+                subdomain: None,
+                addrs: Vec::new(),
+                warm: None,
+            })
+            .await?;
+
+            let (outbox, rx) = mpsc::unbounded_channel();
+            *inner.session.lock().unwrap() = Some(outbox);
+            inner.registration.send_replace(Some(Registration {
+                subdomain: subdomain.unwrap_or_default(),
+                proto,
+            }));
+            drive(ctrl, rx, behavior.heartbeats).await
+        }
+        Some(ClientMsg::Accept(id)) => {
+            match inner.pending.lock().unwrap().remove(&id) {
+                Some(waiter) => {
+                    let _ = waiter.send(ctrl);
+                }
+                None => tracing::debug!(%id, "mock relay: Accept for unknown connection"),
+            }
+            Ok(())
+        }
+        other => bail!("unexpected first message: {other:?}"),
+    }
+}
+
+/// Forward scripted messages and heartbeats until the session is dropped.
+async fn drive(
+    mut ctrl: Framed_<TcpStream>,
+    mut outbox: mpsc::UnboundedReceiver<ServerMsg>,
+    heartbeats: bool,
+) -> Result<()> {
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick(), if heartbeats => ctrl.send(ServerMsg::Heartbeat).await?,
+            msg = outbox.recv() => match msg {
+                Some(msg) => ctrl.send(msg).await?,
+                None => return Ok(()),
+            },
+        }
+    }
+}